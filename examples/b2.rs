@@ -0,0 +1,19 @@
+use firetrap::storage::b2::B2StorageBackend;
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let env = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let b2 = B2StorageBackend::new(
+        env("B2_BUCKET"),
+        env("B2_APPLICATION_KEY_ID"),
+        env("B2_APPLICATION_KEY"),
+    );
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || b2.clone()));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
@@ -0,0 +1,89 @@
+//! Serves the temp directory to the users listed in a file, one `username:password` per line:
+//!
+//! ```sh
+//! echo 'alice:secret' > users.txt
+//! cargo run --example file_auth -- users.txt
+//! ```
+//!
+//! Users whose name starts with `guest` can only read.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use async_trait::async_trait;
+use firetrap::auth::authorization::{Authorizer, Operation};
+use firetrap::auth::Authenticator;
+use log::*;
+
+struct FileAuthenticator {
+    passwords: HashMap<String, String>,
+}
+
+impl FileAuthenticator {
+    fn load(path: &str) -> std::io::Result<Self> {
+        let passwords = std::fs::read_to_string(path)?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(2, ':');
+                match (fields.next(), fields.next()) {
+                    (Some(username), Some(password)) if !username.is_empty() => {
+                        Some((username.to_string(), password.to_string()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(FileAuthenticator { passwords })
+    }
+}
+
+#[async_trait]
+impl Authenticator for FileAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()> {
+        Ok(self.passwords.get(username).map(String::as_str) == Some(password))
+    }
+}
+
+struct GuestsReadOnly;
+
+#[async_trait]
+impl Authorizer for GuestsReadOnly {
+    async fn authorize(
+        &self,
+        username: &str,
+        _path: &Path,
+        operation: Operation,
+    ) -> Result<bool, ()> {
+        let read_only = matches!(operation, Operation::Read | Operation::List);
+        Ok(read_only || !username.starts_with("guest"))
+    }
+}
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let users = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "users.txt".to_string());
+    let authenticator = match FileAuthenticator::load(&users) {
+        Ok(authenticator) => authenticator,
+        Err(e) => {
+            error!("Failed to read the users from {}: {}", users, e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Loaded {} users from {}",
+        authenticator.passwords.len(),
+        users
+    );
+
+    let addr = "127.0.0.1:8282";
+    let server = firetrap::Server::with_root(std::env::temp_dir())
+        // The server needs them for as long as it runs, i.e. forever.
+        .authenticator(Box::leak(Box::new(authenticator)))
+        .authorizer(&GuestsReadOnly);
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
@@ -0,0 +1,21 @@
+use firetrap::storage::gcs::{GcsStorageBackend, ServiceAccountKey};
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let env = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let key = ServiceAccountKey::from_file(env("GOOGLE_APPLICATION_CREDENTIALS"))
+        .expect("Failed to read the service account key");
+    let mut gcs = GcsStorageBackend::new(env("GCS_BUCKET"), key);
+    // E.g. `http://localhost:4443`, for an emulator
+    if let Ok(endpoint) = std::env::var("GCS_ENDPOINT") {
+        gcs = gcs.endpoint(endpoint);
+    }
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || gcs.clone()));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
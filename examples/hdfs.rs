@@ -0,0 +1,19 @@
+use firetrap::storage::hdfs::HdfsStorageBackend;
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    // E.g. `http://namenode:9870/user/ftp`
+    let url = std::env::var("WEBHDFS_URL").expect("WEBHDFS_URL is not set");
+    let mut hdfs = HdfsStorageBackend::new(url);
+    if let Ok(user) = std::env::var("HADOOP_USER_NAME") {
+        hdfs = hdfs.user(user);
+    }
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || hdfs.clone()));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
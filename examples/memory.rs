@@ -0,0 +1,23 @@
+//! Serves an in-memory file system to anyone, with any password. Everything is gone when the
+//! server stops, which makes it handy for trying out clients:
+//!
+//! ```sh
+//! cargo run --example memory
+//! ```
+
+use firetrap::auth::AnonymousAuthenticator;
+use firetrap::storage::Memory;
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    // The sessions share the files of the clones.
+    let storage = Memory::new().capacity(64 << 20);
+    let addr = "127.0.0.1:2122";
+    let server = firetrap::Server::new(Box::new(move || storage.clone()))
+        .authenticator(&AnonymousAuthenticator);
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
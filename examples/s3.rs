@@ -0,0 +1,21 @@
+use firetrap::storage::s3::{Credentials, S3StorageBackend};
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let env = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let mut credentials = Credentials::new(env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"));
+    credentials.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+    let mut s3 = S3StorageBackend::new(env("AWS_REGION"), env("S3_BUCKET"), credentials);
+    // E.g. `http://localhost:9000`, for MinIO
+    if let Ok(endpoint) = std::env::var("S3_ENDPOINT") {
+        s3 = s3.endpoint(endpoint).path_style(true);
+    }
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || s3.clone()));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
@@ -0,0 +1,82 @@
+//! Serves an S3 bucket to the users that log in with a JSON Web Token, signed with HS256, as
+//! their password. The `sub` claim of the token has to be the username, and its `exp` claim in
+//! the future:
+//!
+//! ```sh
+//! export AWS_REGION=eu-west-1 S3_BUCKET=my-bucket AWS_ACCESS_KEY_ID=... AWS_SECRET_ACCESS_KEY=...
+//! export JWT_SECRET=...
+//! cargo run --example s3_jwt --features s3
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use firetrap::auth::Authenticator;
+use firetrap::storage::s3::{Credentials, S3StorageBackend};
+use hmac::{Hmac, Mac};
+use log::*;
+
+struct JwtAuthenticator {
+    secret: Vec<u8>,
+}
+
+impl JwtAuthenticator {
+    // Returns the claims of the token, if it's signed with the secret.
+    fn verify(&self, token: &str) -> Option<serde_json::Value> {
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => return None,
+        };
+        let fields: serde_json::Value = serde_json::from_slice(&decode(header)?).ok()?;
+        if fields["alg"] != "HS256" {
+            return None;
+        }
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&self.secret).ok()?;
+        mac.update(format!("{}.{}", header, claims).as_bytes());
+        mac.verify_slice(&decode(signature)?).ok()?;
+        serde_json::from_slice(&decode(claims)?).ok()
+    }
+}
+
+fn decode(part: &str) -> Option<Vec<u8>> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()> {
+        let claims = match self.verify(password) {
+            Some(claims) => claims,
+            None => return Ok(false),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| ())?
+            .as_secs();
+        let unexpired = claims["exp"].as_u64().is_some_and(|exp| exp > now);
+        Ok(unexpired && claims["sub"] == username)
+    }
+}
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let env = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    let mut credentials = Credentials::new(env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY"));
+    credentials.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+    let s3 = S3StorageBackend::new(env("AWS_REGION"), env("S3_BUCKET"), credentials);
+    let authenticator = JwtAuthenticator {
+        secret: env("JWT_SECRET").into_bytes(),
+    };
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || s3.clone()))
+        // The server needs it for as long as it runs, i.e. forever.
+        .authenticator(Box::leak(Box::new(authenticator)));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
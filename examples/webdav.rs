@@ -0,0 +1,19 @@
+use firetrap::storage::webdav::{Credentials, WebDavStorageBackend};
+use log::*;
+
+pub fn main() {
+    pretty_env_logger::init();
+
+    let env = |name| std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name));
+    // E.g. `https://cloud.example.com/remote.php/dav/files/alice`
+    let mut dav = WebDavStorageBackend::new(env("WEBDAV_URL"));
+    if let Ok(username) = std::env::var("WEBDAV_USERNAME") {
+        dav = dav.credentials(Credentials::basic(username, env("WEBDAV_PASSWORD")));
+    }
+
+    let addr = "127.0.0.1:2121";
+    let server = firetrap::Server::new(Box::new(move || dav.clone()));
+
+    info!("Starting ftp server on {}", addr);
+    server.listen(addr);
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate firetrap;
+
+fuzz_target!(|data: &[u8]| {
+    firetrap::fuzzing::control_channel(data);
+});
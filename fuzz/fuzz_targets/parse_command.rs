@@ -5,5 +5,5 @@ extern crate libfuzzer_sys;
 extern crate firetrap;
 
 fuzz_target!(|data: &[u8]| {
-    let _ = firetrap::commands::Command::parse(data);
+    firetrap::fuzzing::parse_command(data);
 });
@@ -0,0 +1,406 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use log::{info, warn};
+
+/// Resolves the hostnames of an [`Allowlist`] to the addresses they currently have. Implement
+/// it to e.g. query a specific DNS server, or to leave DNS out of tests.
+///
+/// [`Allowlist`]: struct.Allowlist.html
+pub trait Resolver {
+    /// Returns the addresses of the given hostname.
+    fn resolve(
+        &self,
+        hostname: &str,
+    ) -> Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send>;
+}
+
+/// [`Resolver`] that uses the resolver of the operating system (`getaddrinfo`, so it honors
+/// `/etc/hosts`), on a thread of its own so it doesn't hold up the server. This is the default.
+///
+/// [`Resolver`]: trait.Resolver.html
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(
+        &self,
+        hostname: &str,
+    ) -> Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send> {
+        let (tx, rx) = futures::sync::oneshot::channel();
+        let hostname = hostname.to_string();
+        let spawned = std::thread::Builder::new()
+            .name("firetrap-resolver".to_string())
+            .spawn(move || {
+                let addrs = (hostname.as_str(), 0)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect());
+                let _ = tx.send(addrs);
+            });
+        if let Err(e) = spawned {
+            return Box::new(future::err(e));
+        }
+        Box::new(
+            rx.map_err(|_| io::Error::other("The resolver went away"))
+                .and_then(future::result),
+        )
+    }
+}
+
+/// A range of IP addresses in CIDR notation, like `192.0.2.0/24` or `2001:db8::/32`. A single
+/// address without a prefix length is a network of its own.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::allowlist::Network;
+///
+/// let network: Network = "192.0.2.0/24".parse().unwrap();
+/// assert!(network.contains("192.0.2.42".parse().unwrap()));
+/// assert!(!network.contains("198.51.100.1".parse().unwrap()));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Returns the network of the given address and prefix length, or `None` if the prefix is
+    /// longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        if prefix_len > max_prefix_len(addr) {
+            return None;
+        }
+        Some(Network { addr, prefix_len })
+    }
+
+    /// Tells whether the given address is in this network. IPv4 addresses mapped to IPv6 (like
+    /// `::ffff:192.0.2.1`) match the IPv4 networks they map to.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                prefix_matches(network.into(), addr.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+// Tells whether the first `prefix_len` of the `bits` bits of both addresses are the same.
+fn prefix_matches(network: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = bits - prefix_len;
+    shift == bits || network >> shift == addr >> shift
+}
+
+impl FromStr for Network {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| ())?,
+            None => max_prefix_len(addr),
+        };
+        Network::new(addr, prefix_len).ok_or(())
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// What an [`Allowlist`] does with connections that could only be allowed by a hostname that
+/// couldn't be resolved (yet).
+///
+/// [`Allowlist`]: struct.Allowlist.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResolutionFailure {
+    /// Refuse them, so nobody gets in who shouldn't. This is the default.
+    FailClosed,
+    /// Allow them, so partners aren't locked out while DNS is down. This of course lets in
+    /// everyone else as well.
+    FailOpen,
+}
+
+// What a hostname resolved to the last time.
+struct Host {
+    name: String,
+    // `None` until it was resolved for the first time, and an empty list after a failure.
+    addrs: Mutex<Option<Vec<IpAddr>>>,
+}
+
+/// The clients that may connect to the [`Server`], by their network or their hostname. The
+/// connections of all others are closed right after they're accepted, with a `421` reply. If
+/// the server reads [PROXY protocol] headers, the source address given by the proxy is checked.
+///
+/// Hostnames are for partners on dynamic IP addresses, with stable hostnames. They're resolved
+/// (forward, so the partner's DNS doesn't get to claim any address with a reverse record) when
+/// the server starts and again at the refresh interval, and connections are checked against the
+/// addresses they had then. What happens while a hostname can't be resolved is up to the
+/// [`ResolutionFailure`] policy.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::allowlist::{Allowlist, ResolutionFailure};
+/// use std::time::Duration;
+///
+/// let allowlist = Allowlist::new()
+///     .network("10.0.0.0/8".parse().unwrap())
+///     .hostname("ftp.partner.example")
+///     .refresh_interval(Duration::from_secs(60))
+///     .on_resolution_failure(ResolutionFailure::FailOpen);
+/// let server = Server::with_root("/srv/ftp").allowlist(allowlist);
+/// ```
+///
+/// [`Server`]: ../server/struct.Server.html
+/// [PROXY protocol]: ../server/enum.ProxyProtocol.html
+/// [`ResolutionFailure`]: enum.ResolutionFailure.html
+pub struct Allowlist {
+    networks: Vec<Network>,
+    hosts: Vec<Arc<Host>>,
+    refresh_interval: Duration,
+    resolution_failure: ResolutionFailure,
+    resolver: &'static (dyn Resolver + Send + Sync),
+}
+
+// How often hostnames are resolved again, unless configured otherwise.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+impl Allowlist {
+    /// Create a new `Allowlist` that allows nobody, until networks or hostnames are added.
+    pub fn new() -> Self {
+        Allowlist {
+            networks: vec![],
+            hosts: vec![],
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            resolution_failure: ResolutionFailure::FailClosed,
+            resolver: &SystemResolver,
+        }
+    }
+
+    /// Allow the clients in the given network.
+    pub fn network(mut self, network: Network) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Allow the clients with one of the addresses the given hostname resolves to.
+    pub fn hostname<H: Into<String>>(mut self, hostname: H) -> Self {
+        self.hosts.push(Arc::new(Host {
+            name: hostname.into(),
+            addrs: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Set how often the hostnames are resolved again. Every 5 minutes by default.
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// Set what happens to connections while hostnames can't be resolved.
+    pub fn on_resolution_failure(mut self, policy: ResolutionFailure) -> Self {
+        self.resolution_failure = policy;
+        self
+    }
+
+    /// Set the [`Resolver`] for the hostnames, instead of the [`SystemResolver`].
+    ///
+    /// [`Resolver`]: trait.Resolver.html
+    /// [`SystemResolver`]: struct.SystemResolver.html
+    pub fn resolver<R: Resolver + Send + Sync>(mut self, resolver: &'static R) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Tells whether a client with the given address may connect.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        if self.networks.iter().any(|network| network.contains(addr)) {
+            return true;
+        }
+        let mut unresolved = false;
+        for host in &self.hosts {
+            let addrs = host
+                .addrs
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            match &*addrs {
+                Some(addrs) if addrs.contains(&addr) => return true,
+                Some(addrs) if !addrs.is_empty() => {}
+                _ => unresolved = true,
+            }
+        }
+        unresolved && self.resolution_failure == ResolutionFailure::FailOpen
+    }
+
+    /// Resolves all hostnames again, and resolves once it's done. A hostname that can't be
+    /// resolved anymore doesn't keep the addresses it had.
+    pub fn refresh(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let resolutions: Vec<_> = self
+            .hosts
+            .iter()
+            .map(|host| {
+                let host = Arc::clone(host);
+                self.resolver.resolve(&host.name).then(move |res| {
+                    let addrs = match res {
+                        Ok(addrs) => addrs,
+                        Err(e) => {
+                            warn!("Failed to resolve allowed host {}: {}", host.name, e);
+                            vec![]
+                        }
+                    };
+                    let mut current = host
+                        .addrs
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner);
+                    if current.as_ref() != Some(&addrs) && !addrs.is_empty() {
+                        info!("Allowed host {} resolves to {:?}", host.name, addrs);
+                    }
+                    *current = Some(addrs);
+                    Ok(())
+                })
+            })
+            .collect();
+        Box::new(future::join_all(resolutions).map(|_| ()))
+    }
+
+    /// Returns the task that resolves the hostnames right away, and again at every refresh
+    /// interval.
+    pub(crate) fn refresher(self: Arc<Self>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if self.hosts.is_empty() {
+            return Box::new(future::ok(()));
+        }
+        Box::new(
+            tokio::timer::Interval::new(Instant::now(), self.refresh_interval)
+                .map_err(|e| warn!("Failed to refresh the allowlist: {}", e))
+                .for_each(move |_| self.refresh()),
+        )
+    }
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Allowlist::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_networks() {
+        let network: Network = "192.0.2.0/24".parse().unwrap();
+        assert_eq!(network.to_string(), "192.0.2.0/24");
+        assert!(network.contains("192.0.2.255".parse().unwrap()));
+        assert!(network.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!network.contains("192.0.3.0".parse().unwrap()));
+        assert!(!network.contains("2001:db8::1".parse().unwrap()));
+
+        let single: Network = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.to_string(), "2001:db8::1/128");
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        let everything: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.9".parse().unwrap()));
+
+        assert_eq!("192.0.2.0/33".parse::<Network>(), Err(()));
+        assert_eq!("192.0.2/24".parse::<Network>(), Err(()));
+        assert_eq!("partner.example".parse::<Network>(), Err(()));
+    }
+
+    struct FakeDns(Mutex<HashMap<&'static str, Vec<IpAddr>>>);
+
+    impl Resolver for FakeDns {
+        fn resolve(
+            &self,
+            hostname: &str,
+        ) -> Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send> {
+            let addrs = self.0.lock().unwrap().get(hostname).cloned();
+            Box::new(future::result(
+                addrs.ok_or_else(|| io::Error::other("NXDOMAIN")),
+            ))
+        }
+    }
+
+    #[test]
+    fn resolves_hostnames() {
+        lazy_static::lazy_static! {
+            static ref DNS: FakeDns = FakeDns(Mutex::new(HashMap::new()));
+        }
+        let partner: IpAddr = "198.51.100.7".parse().unwrap();
+        let moved: IpAddr = "198.51.100.8".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.1".parse().unwrap();
+
+        for policy in &[ResolutionFailure::FailClosed, ResolutionFailure::FailOpen] {
+            DNS.0.lock().unwrap().clear();
+            let allowlist = Allowlist::new()
+                .network("10.0.0.0/8".parse().unwrap())
+                .hostname("partner.example")
+                .resolver(&*DNS)
+                .on_resolution_failure(*policy);
+            let open = *policy == ResolutionFailure::FailOpen;
+
+            // Not resolved yet, or not resolvable.
+            assert!(allowlist.allows("10.1.2.3".parse().unwrap()));
+            assert_eq!(allowlist.allows(partner), open);
+            allowlist.refresh().wait().unwrap();
+            assert_eq!(allowlist.allows(stranger), open);
+
+            DNS.0
+                .lock()
+                .unwrap()
+                .insert("partner.example", vec![partner]);
+            allowlist.refresh().wait().unwrap();
+            assert!(allowlist.allows(partner));
+            assert!(!allowlist.allows(stranger));
+
+            // The partner moved, and only the new address is allowed after the refresh.
+            DNS.0.lock().unwrap().insert("partner.example", vec![moved]);
+            assert!(!allowlist.allows(moved));
+            allowlist.refresh().wait().unwrap();
+            assert!(allowlist.allows(moved));
+            assert!(!allowlist.allows(partner));
+        }
+    }
+
+    #[test]
+    fn system_resolver() {
+        let addrs = SystemResolver.resolve("localhost").wait().unwrap();
+        assert!(addrs.iter().any(|addr| addr.is_loopback()));
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::Read;
+
+use tokio_io::AsyncRead;
+
+// Which way `Ascii` converts line endings.
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    // LF to CRLF, for sending to the client
+    ToNetwork,
+    // CRLF to LF, for storing what the client sent
+    FromNetwork,
+}
+
+// Converts line endings of the data read from `inner`, for `TYPE A` transfers. The conversion
+// works on one chunk at a time, so files of any size can be converted.
+struct Ascii<R> {
+    inner: R,
+    direction: Direction,
+    // The converted bytes that weren't read yet
+    converted: Vec<u8>,
+    pos: usize,
+    // Whether the last byte we've seen was a CR
+    after_cr: bool,
+}
+
+impl<R: Read> Ascii<R> {
+    fn new(inner: R, direction: Direction) -> Self {
+        Ascii {
+            inner,
+            direction,
+            converted: Vec::new(),
+            pos: 0,
+            after_cr: false,
+        }
+    }
+
+    fn convert(&mut self, chunk: &[u8]) {
+        for &b in chunk {
+            match self.direction {
+                Direction::ToNetwork => {
+                    // Lines that already end in CRLF stay as they are.
+                    if b == b'\n' && !self.after_cr {
+                        self.converted.push(b'\r');
+                    }
+                    self.converted.push(b);
+                }
+                Direction::FromNetwork => {
+                    // Hold on to a CR until we know whether a LF follows.
+                    if self.after_cr && b != b'\n' {
+                        self.converted.push(b'\r');
+                    }
+                    if b != b'\r' {
+                        self.converted.push(b);
+                    }
+                }
+            }
+            self.after_cr = b == b'\r';
+        }
+    }
+}
+
+impl<R: Read> Read for Ascii<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if self.pos < self.converted.len() || buf.is_empty() {
+                let n = std::cmp::min(buf.len(), self.converted.len() - self.pos);
+                buf[..n].copy_from_slice(&self.converted[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+
+            self.converted.clear();
+            self.pos = 0;
+            let mut chunk = [0u8; 4096];
+            // A `WouldBlock` error is passed on before we touched any state, so this is a valid
+            // `AsyncRead` as well.
+            let n = self.inner.read(&mut chunk)?;
+            if n == 0 {
+                if self.direction == Direction::FromNetwork && self.after_cr {
+                    // The data ended in a lone CR
+                    self.after_cr = false;
+                    self.converted.push(b'\r');
+                    continue;
+                }
+                return Ok(0);
+            }
+            self.convert(&chunk[..n]);
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Ascii<R> {}
+
+/// Converts LF line endings of `reader` to CRLF when `ascii` is set (i.e. in `TYPE A`).
+pub(crate) fn to_network<R>(reader: R, ascii: bool) -> Box<dyn AsyncRead + Send>
+where
+    R: AsyncRead + Send + 'static,
+{
+    if ascii {
+        Box::new(Ascii::new(reader, Direction::ToNetwork))
+    } else {
+        Box::new(reader)
+    }
+}
+
+/// Converts CRLF line endings of `reader` to LF when `ascii` is set (i.e. in `TYPE A`).
+pub(crate) fn from_network<R>(reader: R, ascii: bool) -> Box<dyn AsyncRead + Send>
+where
+    R: AsyncRead + Send + 'static,
+{
+    if ascii {
+        Box::new(Ascii::new(reader, Direction::FromNetwork))
+    } else {
+        Box::new(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // Reads everything, one byte at a time, to exercise line endings split over reads.
+    fn read_all(mut reader: Box<dyn AsyncRead + Send>) -> Vec<u8> {
+        let mut out = vec![];
+        let mut byte = [0u8; 1];
+        while reader.read(&mut byte).unwrap() == 1 {
+            out.push(byte[0]);
+        }
+        out
+    }
+
+    #[test]
+    fn converts_to_crlf() {
+        let input = b"one\ntwo\r\nthree\n\n".to_vec();
+        assert_eq!(
+            read_all(to_network(std::io::Cursor::new(input), true)),
+            b"one\r\ntwo\r\nthree\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn converts_from_crlf() {
+        let input = b"one\r\ntwo\nthree\rfour\r".to_vec();
+        assert_eq!(
+            read_all(from_network(std::io::Cursor::new(input), true)),
+            b"one\ntwo\nthree\rfour\r".to_vec()
+        );
+    }
+
+    #[test]
+    fn binary_stays_untouched() {
+        let input = b"one\r\ntwo\n".to_vec();
+        assert_eq!(
+            read_all(from_network(std::io::Cursor::new(input.clone()), false)),
+            input
+        );
+    }
+}
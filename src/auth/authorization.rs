@@ -0,0 +1,368 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+/// The kinds of operations an [`Authorizer`] is asked about.
+///
+/// [`Authorizer`]: trait.Authorizer.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// Retrieving the contents of a file.
+    Read,
+    /// Creating, overwriting or appending to a file, or changing its modification time.
+    Write,
+    /// Listing the contents of a directory.
+    List,
+    /// Deleting a file.
+    Delete,
+    /// Creating a directory.
+    CreateDirectory,
+    /// Removing an (empty) directory.
+    RemoveDirectory,
+    /// Removing a directory together with everything in it, when the `Server` allows it. Asked
+    /// in addition to `RemoveDirectory`.
+    RemoveDirectoryRecursively,
+    /// Renaming a file (asked for both the source and the target).
+    Rename,
+    /// Changing the permissions of a file, with `SITE CHMOD`.
+    ChangePermissions,
+    /// Handing out a presigned URL of a file, with `SITE PRESIGN`. Anyone who gets hold of the
+    /// URL can download the file until it expires, so this is typically only for administrators.
+    Presign,
+    /// Getting the support bundle of the server, with `SITE DEBUG`. It tells a lot about the
+    /// configuration of the server, so this is typically only for administrators. Asked about the
+    /// working directory.
+    Debug,
+}
+
+/// Defines the common interface for deciding whether an authenticated user may perform an
+/// [`Operation`] on a path. It is used by [`Server`] before it hands an operation to the storage
+/// backend.
+///
+/// Being async, an implementation can consult an external policy source (e.g. over HTTP or LDAP)
+/// without holding up the other sessions. Such authorizers should still be wrapped in a
+/// [`CachingAuthorizer`], so not every command waits for a round trip.
+///
+/// ```rust
+/// use async_trait::async_trait;
+/// use firetrap::auth::authorization::{Authorizer, Operation};
+/// use std::path::Path;
+///
+/// struct NoDeletes;
+///
+/// #[async_trait]
+/// impl Authorizer for NoDeletes {
+///     async fn authorize(&self, _username: &str, _path: &Path, operation: Operation) -> Result<bool, ()> {
+///         Ok(operation != Operation::Delete)
+///     }
+/// }
+/// ```
+///
+/// [`Operation`]: enum.Operation.html
+/// [`Server`]: ../../server/struct.Server.html
+/// [`CachingAuthorizer`]: struct.CachingAuthorizer.html
+#[async_trait]
+pub trait Authorizer {
+    /// Returns whether the given user may perform the given operation on the given path.
+    #[allow(clippy::result_unit_err)]
+    async fn authorize(
+        &self,
+        username: &str,
+        path: &Path,
+        operation: Operation,
+    ) -> Result<bool, ()>;
+}
+
+/// [`Authorizer`] implementation that allows every operation. This is the default.
+///
+/// [`Authorizer`]: trait.Authorizer.html
+pub struct AllowAll;
+
+#[async_trait]
+impl Authorizer for AllowAll {
+    async fn authorize(
+        &self,
+        _username: &str,
+        _path: &Path,
+        _operation: Operation,
+    ) -> Result<bool, ()> {
+        Ok(true)
+    }
+}
+
+// The number of independently locked parts of the cache. Decisions are spread over the shards by
+// username, so concurrent sessions of different users rarely contend for the same lock.
+const SHARDS: usize = 32;
+
+type Shard = Mutex<HashMap<CacheKey, (bool, Instant)>>;
+
+#[derive(Eq, Hash, PartialEq)]
+struct CacheKey {
+    username: String,
+    prefix: PathBuf,
+    operation: Operation,
+}
+
+/// An [`Authorizer`] that remembers the decisions of an inner authorizer for a limited time.
+///
+/// Decisions are cached per user, operation and path prefix. By default the prefix is the full
+/// path, but with [`prefix_components`] a decision can be shared by everything below a
+/// directory, which saves a round trip per entry when the policy is defined per directory.
+///
+/// The cache is split into shards by username, so it can be shared by many concurrent sessions.
+/// No lock is held while the inner authorizer is asked, and its errors are never cached. Expired
+/// decisions are dropped whenever a new one is cached in the same shard. When the policy source
+/// changes, call [`invalidate_all`] or [`invalidate_user`] so new decisions take effect before
+/// the TTL passes.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::auth::authorization::{AllowAll, CachingAuthorizer};
+/// use std::time::Duration;
+///
+/// let authorizer = CachingAuthorizer::new(AllowAll, Duration::from_secs(60)).prefix_components(2);
+/// // ... and later, when the policy changed:
+/// authorizer.invalidate_all();
+/// ```
+///
+/// [`Authorizer`]: trait.Authorizer.html
+/// [`prefix_components`]: #method.prefix_components
+/// [`invalidate_all`]: #method.invalidate_all
+/// [`invalidate_user`]: #method.invalidate_user
+pub struct CachingAuthorizer<A>
+where
+    A: Authorizer,
+{
+    inner: A,
+    ttl: Duration,
+    prefix_components: Option<usize>,
+    shards: Vec<Shard>,
+}
+
+impl<A> CachingAuthorizer<A>
+where
+    A: Authorizer,
+{
+    /// Create a new `CachingAuthorizer` that caches the decisions of `inner` for `ttl`.
+    pub fn new(inner: A, ttl: Duration) -> Self {
+        CachingAuthorizer {
+            inner,
+            ttl,
+            prefix_components: None,
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Share cached decisions between all paths that have the same first `n` components, e.g.
+    /// with `n = 2` a decision about `/pub/a.txt` is reused for `/pub/b.txt`.
+    pub fn prefix_components(mut self, n: usize) -> Self {
+        self.prefix_components = Some(n);
+        self
+    }
+
+    /// Forget all cached decisions.
+    pub fn invalidate_all(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    /// Forget all cached decisions for the given user.
+    pub fn invalidate_user(&self, username: &str) {
+        self.shard(username)
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.username != username);
+    }
+
+    fn shard(&self, username: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        username.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    fn prefix(&self, path: &Path) -> PathBuf {
+        match self.prefix_components {
+            Some(n) => path.components().take(n).collect(),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl<A> Authorizer for CachingAuthorizer<A>
+where
+    A: Authorizer + Send + Sync,
+{
+    async fn authorize(
+        &self,
+        username: &str,
+        path: &Path,
+        operation: Operation,
+    ) -> Result<bool, ()> {
+        let key = CacheKey {
+            username: username.to_string(),
+            prefix: self.prefix(path),
+            operation,
+        };
+
+        let shard = self.shard(username);
+        if let Some((allowed, at)) = shard.lock().unwrap().get(&key) {
+            if at.elapsed() < self.ttl {
+                return Ok(*allowed);
+            }
+        }
+
+        // Don't hold the lock while waiting for the (possibly slow) inner authorizer.
+        let allowed = self.inner.authorize(username, path, operation).await?;
+        let mut shard = shard.lock().unwrap();
+        // A miss already costs a round trip, so this is a good time to make room.
+        let ttl = self.ttl;
+        shard.retain(|_, (_, at)| at.elapsed() < ttl);
+        shard.insert(key, (allowed, Instant::now()));
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures03::executor::block_on;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingAuthorizer {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Authorizer for CountingAuthorizer {
+        async fn authorize(
+            &self,
+            username: &str,
+            _path: &Path,
+            _operation: Operation,
+        ) -> Result<bool, ()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(username == "alice")
+        }
+    }
+
+    fn counting() -> CountingAuthorizer {
+        CountingAuthorizer {
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn caches_decisions() {
+        let authorizer = CachingAuthorizer::new(counting(), Duration::from_secs(60));
+        let path = Path::new("/pub/file.txt");
+
+        assert_eq!(
+            block_on(authorizer.authorize("alice", path, Operation::Read)),
+            Ok(true)
+        );
+        assert_eq!(
+            block_on(authorizer.authorize("alice", path, Operation::Read)),
+            Ok(true)
+        );
+        assert_eq!(
+            block_on(authorizer.authorize("bob", path, Operation::Read)),
+            Ok(false)
+        );
+        assert_eq!(
+            block_on(authorizer.authorize("bob", path, Operation::Read)),
+            Ok(false)
+        );
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 2);
+
+        // Other operations are decided separately
+        assert_eq!(
+            block_on(authorizer.authorize("alice", path, Operation::Delete)),
+            Ok(true)
+        );
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn shares_decisions_by_prefix() {
+        let authorizer =
+            CachingAuthorizer::new(counting(), Duration::from_secs(60)).prefix_components(2);
+
+        for name in &["/pub/a.txt", "/pub/b.txt", "/pub/c.txt"] {
+            assert_eq!(
+                block_on(authorizer.authorize("alice", Path::new(name), Operation::List)),
+                Ok(true)
+            );
+        }
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 1);
+
+        block_on(authorizer.authorize("alice", Path::new("/private/a.txt"), Operation::List))
+            .unwrap();
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn expires_decisions() {
+        let authorizer = CachingAuthorizer::new(counting(), Duration::from_millis(10));
+        let path = Path::new("/file.txt");
+
+        block_on(authorizer.authorize("alice", path, Operation::Read)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        block_on(authorizer.authorize("alice", path, Operation::Read)).unwrap();
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 2);
+
+        // Expired decisions don't pile up.
+        for i in 0..100 {
+            let path = format!("/file{}.txt", i);
+            block_on(authorizer.authorize("alice", Path::new(&path), Operation::Read)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        block_on(authorizer.authorize("alice", path, Operation::Delete)).unwrap();
+        assert_eq!(authorizer.shard("alice").lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn many_users() {
+        let authorizer = CachingAuthorizer::new(counting(), Duration::from_secs(60));
+        let path = Path::new("/file.txt");
+        let users: Vec<String> = (0..1000).map(|i| format!("user{}", i)).collect();
+
+        for _ in 0..2 {
+            for user in &users {
+                block_on(authorizer.authorize(user, path, Operation::Read)).unwrap();
+            }
+        }
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 1000);
+
+        authorizer.invalidate_user("user500");
+        for user in &users {
+            block_on(authorizer.authorize(user, path, Operation::Read)).unwrap();
+        }
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 1001);
+    }
+
+    #[test]
+    fn invalidates_decisions() {
+        let authorizer = CachingAuthorizer::new(counting(), Duration::from_secs(60));
+        let path = Path::new("/file.txt");
+
+        block_on(authorizer.authorize("alice", path, Operation::Read)).unwrap();
+        block_on(authorizer.authorize("bob", path, Operation::Read)).unwrap();
+
+        authorizer.invalidate_user("bob");
+        block_on(authorizer.authorize("alice", path, Operation::Read)).unwrap();
+        block_on(authorizer.authorize("bob", path, Operation::Read)).unwrap();
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 3);
+
+        authorizer.invalidate_all();
+        block_on(authorizer.authorize("alice", path, Operation::Read)).unwrap();
+        assert_eq!(authorizer.inner.calls.load(Ordering::SeqCst), 4);
+    }
+}
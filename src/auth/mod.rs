@@ -1,4 +1,9 @@
 #![deny(missing_docs)]
+use async_trait::async_trait;
+
+use crate::bandwidth::TransferPriority;
+use crate::storage::quota::QuotaLimits;
+
 /// Defines the common interface that can be implemented for a multitude of authentication
 /// backends, e.g. *LDAP* or *PAM*. It is used by [`Server`] to authenticate users.
 ///
@@ -6,21 +11,172 @@
 /// mechanism you need. For example, to define an `Authenticator` that will randomly decide:
 ///
 /// ```rust
+/// use async_trait::async_trait;
 /// use rand::prelude::*;
 /// use firetrap::auth::Authenticator;
 ///
 /// struct RandomAuthenticator;
 ///
+/// #[async_trait]
 /// impl Authenticator for RandomAuthenticator {
-///     fn authenticate(&self, _username: &str, _password: &str) -> Result<bool, ()> {
+///     async fn authenticate(&self, _username: &str, _password: &str) -> Result<bool, ()> {
 ///         Ok(rand::random())
 ///     }
 /// }
 /// ```
+///
+/// Being async, an implementation can wait for a round trip to a directory server without holding
+/// up the other sessions.
 /// [`Server`]: ../server/struct.Server.html
+#[async_trait]
 pub trait Authenticator {
     /// Authenticate the given user with the given password.
-    fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()>;
+    #[allow(clippy::result_unit_err)]
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()>;
+
+    /// Returns the [`User`] details of the given user, after it was successfully authenticated.
+    /// The default implementation returns a [`User`] without any special settings.
+    ///
+    /// [`User`]: struct.User.html
+    fn user(&self, username: &str) -> User {
+        User::new(username)
+    }
+}
+
+/// The details of an authenticated user, as provided by the [`Authenticator`]. They are handed
+/// to the storage backend after login, so it can apply per-user settings.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::auth::User;
+///
+/// let user = User::new("partner").umask(0o027).dir_mode(0o770);
+/// assert_eq!(user.effective_file_mode(), Some(0o640));
+/// assert_eq!(user.effective_dir_mode(), Some(0o750));
+/// ```
+///
+/// [`Authenticator`]: trait.Authenticator.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    /// The name the user logged in with.
+    pub username: String,
+    /// The permission bits that are cleared from new files and directories.
+    pub umask: Option<u32>,
+    /// The mode of new files, before the `umask` is applied. Defaults to `0o666`.
+    pub file_mode: Option<u32>,
+    /// The mode of new directories, before the `umask` is applied. Defaults to `0o777`.
+    pub dir_mode: Option<u32>,
+    /// The priority of the user's transfers when the bandwidth is limited.
+    pub priority: TransferPriority,
+    /// The number of files and directories the user may create per session, with `MKD` and
+    /// uploads to paths that didn't exist yet. `None` (the default) means there's no limit.
+    pub max_new_entries: Option<u64>,
+    /// What the user may store, for the [`Quota`] wrapper. `None` (the default) means the limits
+    /// of the wrapper apply.
+    ///
+    /// [`Quota`]: ../storage/quota/struct.Quota.html
+    pub quota: Option<QuotaLimits>,
+}
+
+impl User {
+    /// Create a new `User` with the given name and no special settings.
+    pub fn new<S: Into<String>>(username: S) -> Self {
+        User {
+            username: username.into(),
+            umask: None,
+            file_mode: None,
+            dir_mode: None,
+            priority: TransferPriority::default(),
+            max_new_entries: None,
+            quota: None,
+        }
+    }
+
+    /// Set the `umask` for the user's new files and directories.
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.umask = Some(umask);
+        self
+    }
+
+    /// Set the mode of the user's new files.
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = Some(mode);
+        self
+    }
+
+    /// Set the mode of the user's new directories.
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    /// Set the priority of the user's transfers, see [`TransferPriority`].
+    ///
+    /// [`TransferPriority`]: ../bandwidth/enum.TransferPriority.html
+    pub fn priority(mut self, priority: TransferPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Limit the number of files and directories the user may create per session, to blunt
+    /// "directory bombs" from anonymous or partner accounts. Once the limit is reached, `MKD` and
+    /// uploads of new files get a `550` reply, and the [`SessionListener`] is told. Overwriting
+    /// or appending to existing files is still allowed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::auth::User;
+    ///
+    /// let user = User::new("anonymous").max_new_entries(100);
+    /// assert_eq!(user.max_new_entries, Some(100));
+    /// ```
+    ///
+    /// [`SessionListener`]: ../events/trait.SessionListener.html
+    pub fn max_new_entries(mut self, max: u64) -> Self {
+        self.max_new_entries = Some(max);
+        self
+    }
+
+    /// Set what the user may store, when the storage backend is wrapped in a [`Quota`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::auth::User;
+    /// use firetrap::storage::quota::QuotaLimits;
+    ///
+    /// let user = User::new("customer").quota(QuotaLimits {
+    ///     max_bytes: Some(10 << 30),
+    ///     max_files: None,
+    /// });
+    /// ```
+    ///
+    /// [`Quota`]: ../storage/quota/struct.Quota.html
+    pub fn quota(mut self, limits: QuotaLimits) -> Self {
+        self.quota = Some(limits);
+        self
+    }
+
+    /// Returns the mode new files should get, or `None` if the user has no special settings and
+    /// the backend's defaults apply.
+    pub fn effective_file_mode(&self) -> Option<u32> {
+        self.effective_mode(self.file_mode, 0o666)
+    }
+
+    /// Returns the mode new directories should get, or `None` if the user has no special settings
+    /// and the backend's defaults apply.
+    pub fn effective_dir_mode(&self) -> Option<u32> {
+        self.effective_mode(self.dir_mode, 0o777)
+    }
+
+    fn effective_mode(&self, mode: Option<u32>, default: u32) -> Option<u32> {
+        if mode.is_none() && self.umask.is_none() {
+            return None;
+        }
+        Some(mode.unwrap_or(default) & !self.umask.unwrap_or(0))
+    }
 }
 
 /// [`Authenticator`] implementation that authenticates against [`PAM`].
@@ -30,20 +186,29 @@ pub trait Authenticator {
 #[cfg(feature = "pam")]
 pub mod pam;
 
+/// Contains the [`Authorizer`] trait that decides which operations a user may perform, as well as
+/// the [`CachingAuthorizer`] decorator.
+///
+/// [`Authorizer`]: authorization/trait.Authorizer.html
+/// [`CachingAuthorizer`]: authorization/struct.CachingAuthorizer.html
+pub mod authorization;
+
 /// Authenticator implementation that simply allows everyone.
 ///
 /// # Example
 ///
 /// ```rust
 /// use firetrap::auth::{Authenticator, AnonymousAuthenticator};
+/// use futures03::executor::block_on;
 ///
 /// let my_auth = AnonymousAuthenticator{};
-/// assert_eq!(my_auth.authenticate("Finn", "I ❤️ PB").unwrap(), true);
+/// assert_eq!(block_on(my_auth.authenticate("Finn", "I ❤️ PB")).unwrap(), true);
 /// ```
 pub struct AnonymousAuthenticator;
 
+#[async_trait]
 impl Authenticator for AnonymousAuthenticator {
-    fn authenticate(&self, _username: &str, _password: &str) -> Result<bool, ()> {
+    async fn authenticate(&self, _username: &str, _password: &str) -> Result<bool, ()> {
         Ok(true)
     }
 }
@@ -1,3 +1,5 @@
+use async_trait::async_trait;
+
 use crate::auth::Authenticator;
 
 /// [`Authenticator`] implementation that authenticates against [`PAM`].
@@ -15,8 +17,9 @@ impl PAMAuthenticator {
     }
 }
 
+#[async_trait]
 impl Authenticator for PAMAuthenticator {
-    fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()> {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<bool, ()> {
         let mut auth = match pam_auth::Authenticator::new(&self.service) {
             Some(auth) => auth,
             None => return Err(()),
@@ -0,0 +1,220 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future};
+use tokio::timer::Delay;
+use tokio_io::AsyncRead;
+
+/// The priority class of a user's transfers. When the [`BandwidthLimiter`] is saturated, every
+/// running transfer gets a share of the bandwidth proportional to the weight of its class, so
+/// interactive users aren't starved by batch jobs.
+///
+/// [`BandwidthLimiter`]: struct.BandwidthLimiter.html
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TransferPriority {
+    /// For people waiting for their transfers to finish. Weighs four times as much as `Batch`.
+    Interactive,
+    /// The default. Weighs twice as much as `Batch`.
+    #[default]
+    Normal,
+    /// For unattended bulk transfers.
+    Batch,
+}
+
+impl TransferPriority {
+    fn weight(self) -> u64 {
+        match self {
+            TransferPriority::Interactive => 4,
+            TransferPriority::Normal => 2,
+            TransferPriority::Batch => 1,
+        }
+    }
+}
+
+/// Limits the combined bandwidth of all data transfers of a [`Server`], shared fairly between the
+/// running transfers according to their [`TransferPriority`].
+///
+/// [`Server`]: ../server/struct.Server.html
+/// [`TransferPriority`]: enum.TransferPriority.html
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_second: u64,
+    // The sum of the weights of all running transfers.
+    total_weight: AtomicU64,
+}
+
+impl BandwidthLimiter {
+    /// Create a new `BandwidthLimiter` that allows the given number of bytes per second in total.
+    pub fn new(bytes_per_second: u64) -> Self {
+        BandwidthLimiter {
+            bytes_per_second: std::cmp::max(bytes_per_second, 1),
+            total_weight: AtomicU64::new(0),
+        }
+    }
+
+    // The number of bytes per second that all transfers share.
+    pub(crate) fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Registers a new transfer with the given priority. It counts towards the sharing of the
+    /// bandwidth until the returned `Share` is dropped.
+    pub(crate) fn share(limiter: &Arc<Self>, priority: TransferPriority) -> Share {
+        let weight = priority.weight();
+        limiter.total_weight.fetch_add(weight, Ordering::SeqCst);
+        Share {
+            limiter: Arc::clone(limiter),
+            weight,
+        }
+    }
+}
+
+/// Limits the reading speed of `reader` to its share of the `limiter`, if there is one.
+pub(crate) fn throttle<R>(
+    reader: R,
+    limiter: Option<&Arc<BandwidthLimiter>>,
+    priority: TransferPriority,
+) -> Box<dyn AsyncRead + Send>
+where
+    R: AsyncRead + Send + 'static,
+{
+    match limiter {
+        Some(limiter) => Box::new(Throttled::new(
+            reader,
+            BandwidthLimiter::share(limiter, priority),
+        )),
+        None => Box::new(reader),
+    }
+}
+
+/// The claim of a single transfer on the bandwidth of a [`BandwidthLimiter`].
+///
+/// [`BandwidthLimiter`]: struct.BandwidthLimiter.html
+#[derive(Debug)]
+pub(crate) struct Share {
+    limiter: Arc<BandwidthLimiter>,
+    weight: u64,
+}
+
+impl Share {
+    /// The number of bytes per second this transfer may currently use.
+    fn bytes_per_second(&self) -> f64 {
+        let total = std::cmp::max(
+            self.limiter.total_weight.load(Ordering::SeqCst),
+            self.weight,
+        );
+        self.limiter.bytes_per_second as f64 * self.weight as f64 / total as f64
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.limiter
+            .total_weight
+            .fetch_sub(self.weight, Ordering::SeqCst);
+    }
+}
+
+/// An `AsyncRead` that reads no faster than its [`Share`] allows. It is a token bucket that holds
+/// at most a quarter of a second worth of bytes.
+///
+/// [`Share`]: struct.Share.html
+pub(crate) struct Throttled<R> {
+    inner: R,
+    share: Share,
+    tokens: f64,
+    refilled: Instant,
+    delay: Option<Delay>,
+}
+
+impl<R> Throttled<R> {
+    pub(crate) fn new(inner: R, share: Share) -> Self {
+        Throttled {
+            inner,
+            share,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            delay: None,
+        }
+    }
+
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate / 4.0);
+        self.refilled = now;
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                match delay.poll() {
+                    Ok(Async::NotReady) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                    Ok(Async::Ready(())) => self.delay = None,
+                    Err(e) => return Err(std::io::Error::other(e)),
+                }
+            }
+
+            let rate = self.share.bytes_per_second();
+            self.refill(rate);
+            if self.tokens >= 1.0 || buf.is_empty() {
+                break;
+            }
+            // Wait until at least one byte is allowed. Polling the delay in the next iteration
+            // makes sure we get woken up when it has passed.
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / rate);
+            self.delay = Some(Delay::new(Instant::now() + wait));
+        }
+
+        let allowed = std::cmp::min(buf.len(), self.tokens as usize);
+        let n = self.inner.read(&mut buf[..allowed])?;
+        self.tokens -= n as f64;
+        Ok(n)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Throttled<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_by_weight() {
+        let limiter = Arc::new(BandwidthLimiter::new(7000));
+
+        let interactive = BandwidthLimiter::share(&limiter, TransferPriority::Interactive);
+        assert_eq!(interactive.bytes_per_second(), 7000.0);
+        {
+            let normal = BandwidthLimiter::share(&limiter, TransferPriority::Normal);
+            let batch = BandwidthLimiter::share(&limiter, TransferPriority::Batch);
+            assert_eq!(interactive.bytes_per_second(), 4000.0);
+            assert_eq!(normal.bytes_per_second(), 2000.0);
+            assert_eq!(batch.bytes_per_second(), 1000.0);
+        }
+        // The other transfers finished
+        assert_eq!(interactive.bytes_per_second(), 7000.0);
+    }
+
+    #[test]
+    fn throttles_reads() {
+        let limiter = Arc::new(BandwidthLimiter::new(100_000));
+        let share = BandwidthLimiter::share(&limiter, TransferPriority::Normal);
+        let reader = Throttled::new(std::io::Cursor::new(vec![0u8; 50_000]), share);
+
+        let start = Instant::now();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (_, data) = rt
+            .block_on(tokio_io::io::read_to_end(reader, vec![]))
+            .unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(data.len(), 50_000);
+        assert!(elapsed >= Duration::from_millis(400), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+    }
+}
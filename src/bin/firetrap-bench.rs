@@ -0,0 +1,532 @@
+//! A load-test client for FTP servers: opens a number of concurrent sessions against a server
+//! and measures the login rate, the latency of `LIST` and the throughput of `STOR` and `RETR`.
+//! It only needs the standard library, so it runs against any server, not just firetrap.
+//!
+//! ```text
+//! cargo run --release --features bench --bin firetrap-bench -- --sessions 20 --size 64K --size 8M localhost:2121
+//! ```
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: firetrap-bench [OPTIONS] <HOST:PORT>
+
+Options:
+    --sessions <N>     The number of concurrent sessions [default: 10]
+    --iterations <N>   How many times every session lists and transfers [default: 10]
+    --user <NAME>      The user to log in as [default: anonymous]
+    --pass <PASSWORD>  The password to log in with [default: firetrap-bench]
+    --dir <PATH>       The directory to list and to upload the test files to
+    --size <SIZE>      The size of the test files, like 512, 64K or 8M. Can be given more
+                       than once [default: 1M]
+    --help             Print this message";
+
+#[derive(Clone, Debug, PartialEq)]
+struct Options {
+    addr: String,
+    sessions: usize,
+    iterations: usize,
+    user: String,
+    pass: String,
+    dir: Option<String>,
+    sizes: Vec<usize>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            addr: String::new(),
+            sessions: 10,
+            iterations: 10,
+            user: "anonymous".to_string(),
+            pass: "firetrap-bench".to_string(),
+            dir: None,
+            sizes: vec![],
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--sessions" => options.sessions = number(&value()?)?,
+                "--iterations" => options.iterations = number(&value()?)?,
+                "--user" => options.user = value()?,
+                "--pass" => options.pass = value()?,
+                "--dir" => options.dir = Some(value()?),
+                "--size" => options.sizes.push(size(&value()?)?),
+                "--help" => return Err(String::new()),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ if options.addr.is_empty() => options.addr = arg,
+                _ => return Err(format!("Unexpected argument {}", arg)),
+            }
+        }
+        if options.addr.is_empty() {
+            return Err("The address of the server is missing".to_string());
+        }
+        if options.sizes.is_empty() {
+            options.sizes.push(1 << 20);
+        }
+        Ok(options)
+    }
+}
+
+fn number(text: &str) -> Result<usize, String> {
+    match text.parse() {
+        Ok(0) | Err(_) => Err(format!("Not a positive number: {}", text)),
+        Ok(n) => Ok(n),
+    }
+}
+
+// Parses sizes like `512`, `64K`, `8M` or `1G`, in powers of 1024.
+fn size(text: &str) -> Result<usize, String> {
+    let upper = text.to_ascii_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (digits, shift) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 10),
+        Some('M') => (&digits[..digits.len() - 1], 20),
+        Some('G') => (&digits[..digits.len() - 1], 30),
+        _ => (digits, 0),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or(format!("Not a size: {}", text))
+}
+
+fn human_size(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 && b % (1 << 30) == 0 => format!("{}GiB", b >> 30),
+        b if b >= 1 << 20 && b % (1 << 20) == 0 => format!("{}MiB", b >> 20),
+        b if b >= 1 << 10 && b % (1 << 10) == 0 => format!("{}KiB", b >> 10),
+        b => format!("{}B", b),
+    }
+}
+
+// A reply on the control channel.
+#[derive(Debug, PartialEq)]
+struct Reply {
+    code: u32,
+    text: String,
+}
+
+// The control channel of a session.
+struct Control {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Control {
+    fn connect(addr: SocketAddr) -> io::Result<Control> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut control = Control {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        control.expect(2)?;
+        Ok(control)
+    }
+
+    fn reply(&mut self) -> io::Result<Reply> {
+        let mut line = String::new();
+        let mut text = String::new();
+        self.reader.read_line(&mut line)?;
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| invalid(format!("Invalid reply: {:?}", line)))?;
+        text.push_str(line.trim_end());
+        // Multiline replies end with the code followed by a space.
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{} ", code);
+            loop {
+                line.clear();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                text.push('\n');
+                text.push_str(line.trim_end());
+                if line.starts_with(&end) {
+                    break;
+                }
+            }
+        }
+        Ok(Reply { code, text })
+    }
+
+    // Reads a reply, failing unless it's in the given class, like `2` for success.
+    fn expect(&mut self, class: u32) -> io::Result<Reply> {
+        let reply = self.reply()?;
+        if reply.code / 100 != class {
+            return Err(invalid(reply.text));
+        }
+        Ok(reply)
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<Reply> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        self.reply()
+    }
+
+    // Sends a command, failing unless the reply is in the given class.
+    fn command(&mut self, command: &str, class: u32) -> io::Result<Reply> {
+        let reply = self.send(command)?;
+        if reply.code / 100 != class {
+            // Just the verb, to keep passwords out of the output
+            let verb = command.split(' ').next().unwrap_or(command);
+            return Err(invalid(format!("{}: {}", verb, reply.text)));
+        }
+        Ok(reply)
+    }
+
+    // Opens a passive data connection.
+    fn data(&mut self) -> io::Result<TcpStream> {
+        let reply = self.command("PASV", 2)?;
+        let addr = pasv_addr(&reply.text)
+            .ok_or_else(|| invalid(format!("Invalid PASV reply: {}", reply.text)))?;
+        TcpStream::connect(addr)
+    }
+
+    // Runs a command that transfers over a new data connection, with the given function.
+    fn transfer<T, F>(&mut self, command: &str, with: F) -> io::Result<T>
+    where
+        F: FnOnce(TcpStream) -> io::Result<T>,
+    {
+        let data = self.data()?;
+        self.command(command, 1)?;
+        let result = with(data)?;
+        self.expect(2)?;
+        Ok(result)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The address in a reply like `227 Entering Passive Mode (127,0,0,1,4,210)`.
+fn pasv_addr(reply: &str) -> Option<SocketAddr> {
+    let start = reply.find('(')? + 1;
+    let end = start + reply[start..].find(')')?;
+    let numbers: Vec<u8> = reply[start..end]
+        .split(',')
+        .map(|n| n.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers.as_slice() {
+        [a, b, c, d, hi, lo] => Some(SocketAddr::from((
+            [*a, *b, *c, *d],
+            u16::from(*hi) << 8 | u16::from(*lo),
+        ))),
+        _ => None,
+    }
+}
+
+// What one session measured.
+#[derive(Default)]
+struct Measurements {
+    // When the session was logged in, after the start.
+    logged_in: Option<Duration>,
+    logins: Vec<Duration>,
+    lists: Vec<Duration>,
+    // Per file size
+    stors: Vec<Vec<Duration>>,
+    retrs: Vec<Vec<Duration>>,
+    error: Option<String>,
+}
+
+fn session(
+    id: usize,
+    addr: SocketAddr,
+    options: &Options,
+    files: &[Vec<u8>],
+    start: Instant,
+) -> Measurements {
+    let mut measurements = Measurements {
+        stors: vec![vec![]; files.len()],
+        retrs: vec![vec![]; files.len()],
+        ..Measurements::default()
+    };
+    if let Err(e) = run(id, addr, options, files, start, &mut measurements) {
+        measurements.error = Some(format!("Session {}: {}", id, e));
+    }
+    measurements
+}
+
+fn run(
+    id: usize,
+    addr: SocketAddr,
+    options: &Options,
+    files: &[Vec<u8>],
+    start: Instant,
+    measurements: &mut Measurements,
+) -> io::Result<()> {
+    let time = Instant::now();
+    let mut control = Control::connect(addr)?;
+    let reply = control.send(&format!("USER {}", options.user))?;
+    // Most servers want a password, some are happy with just the user.
+    match reply.code {
+        331 => {
+            control.command(&format!("PASS {}", options.pass), 2)?;
+        }
+        code if code / 100 == 2 => {}
+        _ => return Err(invalid(reply.text)),
+    }
+    measurements.logins.push(time.elapsed());
+    measurements.logged_in = Some(start.elapsed());
+
+    if let Some(dir) = &options.dir {
+        control.command(&format!("CWD {}", dir), 2)?;
+    }
+    control.command("TYPE I", 2)?;
+    for iteration in 0..options.iterations {
+        let time = Instant::now();
+        control.transfer("LIST", |mut data| {
+            io::copy(&mut data, &mut io::sink())?;
+            Ok(())
+        })?;
+        measurements.lists.push(time.elapsed());
+
+        for (i, file) in files.iter().enumerate() {
+            let name = format!("firetrap-bench-{}-{}-{}.bin", id, iteration, i);
+            let time = Instant::now();
+            control.transfer(&format!("STOR {}", name), |mut data| data.write_all(file))?;
+            measurements.stors[i].push(time.elapsed());
+
+            let time = Instant::now();
+            let len = control.transfer(&format!("RETR {}", name), |mut data| {
+                let mut buffer = vec![0; 64 * 1024];
+                let mut len = 0;
+                loop {
+                    match data.read(&mut buffer)? {
+                        0 => return Ok(len),
+                        n => len += n,
+                    }
+                }
+            })?;
+            measurements.retrs[i].push(time.elapsed());
+            if len != file.len() {
+                return Err(invalid(format!(
+                    "Retrieved {} bytes of {} instead of {}",
+                    len,
+                    name,
+                    file.len()
+                )));
+            }
+            control.command(&format!("DELE {}", name), 2)?;
+        }
+    }
+    control.command("QUIT", 2)?;
+    Ok(())
+}
+
+// Bytes that don't compress, so `MODE Z` doesn't flatter the results.
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+// The minimum, median, 95th percentile and maximum.
+fn latencies(durations: &mut [Duration]) -> String {
+    if durations.is_empty() {
+        return "no measurements".to_string();
+    }
+    durations.sort();
+    format!(
+        "latency min {:.1}ms, median {:.1}ms, p95 {:.1}ms, max {:.1}ms",
+        millis(durations[0]),
+        millis(percentile(durations, 0.5)),
+        millis(percentile(durations, 0.95)),
+        millis(durations[durations.len() - 1])
+    )
+}
+
+// The throughput of a single transfer, over all transfers.
+fn throughput(size: usize, durations: &[Duration]) -> String {
+    let total: Duration = durations.iter().sum();
+    if total == Duration::from_secs(0) {
+        return String::new();
+    }
+    let bytes = (size * durations.len()) as f64;
+    format!(
+        ", {:.1} MiB/s",
+        bytes / total.as_secs_f64() / f64::from(1 << 20)
+    )
+}
+
+fn report(options: &Options, mut all: Vec<Measurements>) -> bool {
+    let errors: Vec<String> = all.iter_mut().filter_map(|m| m.error.take()).collect();
+    let mut logins: Vec<Duration> = all.iter().flat_map(|m| m.logins.clone()).collect();
+    let last_login = all.iter().filter_map(|m| m.logged_in).max();
+    let login_rate = match last_login {
+        Some(last) if last > Duration::from_secs(0) => {
+            format!(" ({:.1}/s)", logins.len() as f64 / last.as_secs_f64())
+        }
+        _ => String::new(),
+    };
+    println!(
+        "logins       {:>6}{}, {}",
+        logins.len(),
+        login_rate,
+        latencies(&mut logins)
+    );
+    let mut lists: Vec<Duration> = all.iter().flat_map(|m| m.lists.clone()).collect();
+    println!("LIST         {:>6}, {}", lists.len(), latencies(&mut lists));
+    for (i, &size) in options.sizes.iter().enumerate() {
+        for (command, durations) in &mut [
+            (
+                "STOR",
+                all.iter().flat_map(|m| m.stors[i].clone()).collect(),
+            ),
+            (
+                "RETR",
+                all.iter().flat_map(|m| m.retrs[i].clone()).collect(),
+            ),
+        ] {
+            let durations: &mut Vec<Duration> = durations;
+            println!(
+                "{} {:<7} {:>6}{}, {}",
+                command,
+                human_size(size),
+                durations.len(),
+                throughput(size, durations),
+                latencies(durations)
+            );
+        }
+    }
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    errors.is_empty()
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            if message.is_empty() {
+                println!("{}", USAGE);
+                return;
+            }
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    let addr = match options.addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        _ => {
+            eprintln!("Can't resolve {}", options.addr);
+            process::exit(2);
+        }
+    };
+    let files: Arc<Vec<Vec<u8>>> = Arc::new(
+        options
+            .sizes
+            .iter()
+            .map(|&size| random_bytes(size))
+            .collect(),
+    );
+    let options = Arc::new(options);
+
+    println!(
+        "{} sessions x {} iterations against {}",
+        options.sessions, options.iterations, addr
+    );
+    // Let all sessions start at once.
+    let barrier = Arc::new(Barrier::new(options.sessions + 1));
+    let sessions: Vec<_> = (0..options.sessions)
+        .map(|id| {
+            let (options, files, barrier) = (
+                Arc::clone(&options),
+                Arc::clone(&files),
+                Arc::clone(&barrier),
+            );
+            thread::spawn(move || {
+                barrier.wait();
+                session(id, addr, &options, &files, Instant::now())
+            })
+        })
+        .collect();
+    let start = Instant::now();
+    barrier.wait();
+    let measurements: Vec<Measurements> = sessions
+        .into_iter()
+        .map(|session| session.join().unwrap_or_default())
+        .collect();
+    println!("finished in {:.2}s", start.elapsed().as_secs_f64());
+    if !report(&options, measurements) {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_options() {
+        let args = |args: &[&str]| Options::parse(args.iter().map(|arg| arg.to_string()));
+        let options = args(&[
+            "--sessions",
+            "4",
+            "--size",
+            "64K",
+            "--size",
+            "8m",
+            "localhost:21",
+        ])
+        .unwrap();
+        assert_eq!(options.addr, "localhost:21");
+        assert_eq!(options.sessions, 4);
+        assert_eq!(options.iterations, 10);
+        assert_eq!(options.sizes, vec![64 << 10, 8 << 20]);
+        assert_eq!(args(&["localhost:21"]).unwrap().sizes, vec![1 << 20]);
+
+        assert!(args(&[]).is_err());
+        assert!(args(&["--sessions", "0", "localhost:21"]).is_err());
+        assert!(args(&["--size", "lots", "localhost:21"]).is_err());
+        assert!(args(&["--sessions"]).is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(size("512"), Ok(512));
+        assert_eq!(size("4k"), Ok(4096));
+        assert_eq!(size("2MB"), Ok(2 << 20));
+        assert_eq!(size("1G"), Ok(1 << 30));
+        assert!(size("M").is_err());
+        assert_eq!(human_size(8 << 20), "8MiB");
+        assert_eq!(human_size(1000), "1000B");
+    }
+
+    #[test]
+    fn parses_pasv_replies() {
+        assert_eq!(
+            pasv_addr("227 Entering Passive Mode (127,0,0,1,4,210)"),
+            Some(SocketAddr::from(([127, 0, 0, 1], 1234)))
+        );
+        assert_eq!(pasv_addr("227 Entering Passive Mode (127,0,0,1,4)"), None);
+        assert_eq!(pasv_addr("227 =127,0,0,1,4,210"), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Translations of the `Server`'s reply texts, for clients that pick a language with the `LANG`
+/// command of RFC 2640. The languages of the catalog are advertised in the `FEAT` reply.
+///
+/// Translations are keyed by the English text of a reply line, without its reply code: the
+/// translation of `Permission denied` is used for `550 Permission denied`. Replies without a
+/// translation are sent in English, so a catalog can start small (e.g. with just the error
+/// replies that end users get to see) and grow later.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::catalog::Catalog;
+///
+/// let mut catalog = Catalog::new();
+/// catalog.add("nl", "Permission denied", "Toegang geweigerd");
+/// assert_eq!(
+///     catalog.translate("NL", "550 Permission denied\r\n"),
+///     "550 Toegang geweigerd\r\n"
+/// );
+/// assert_eq!(catalog.translate("NL", "221 bye!\r\n"), "221 bye!\r\n");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Catalog {
+    // Keyed by the upper-cased language tag, so `FEAT` lists the languages in a stable order.
+    languages: BTreeMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    /// Create an empty catalog, i.e. one that only knows English.
+    pub fn new() -> Self {
+        Catalog::default()
+    }
+
+    /// Add the translation of the given English reply text to the language with the given tag
+    /// (e.g. `fr` or `pt-BR`), adding the language if it's new.
+    pub fn add(&mut self, language: &str, english: &str, translated: &str) {
+        self.languages
+            .entry(language.to_uppercase())
+            .or_default()
+            .insert(english.to_string(), translated.to_string());
+    }
+
+    /// Returns the tags of the languages in the catalog, in upper case and ordered
+    /// alphabetically. English is always available, so it isn't included unless it was added.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.languages.keys().map(String::as_str)
+    }
+
+    /// Returns the tag of the language of the catalog that best matches the given tag, if any.
+    /// A tag with a subtag falls back to its primary language, so `fr-CA` matches `FR` when
+    /// there are no Canadian translations. English always matches.
+    pub fn find(&self, language: &str) -> Option<String> {
+        let language = language.to_uppercase();
+        let primary = language.split('-').next().unwrap_or("");
+        if self.languages.contains_key(&language) {
+            Some(language)
+        } else if self.languages.contains_key(primary) {
+            Some(primary.to_string())
+        } else if primary == "EN" {
+            Some("EN".to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Translates all the lines of the given reply that start with a reply code into the given
+    /// language, as far as the catalog has translations for them.
+    pub fn translate(&self, language: &str, reply: &str) -> String {
+        let translations = match self.find(language).and_then(|l| self.languages.get(&l)) {
+            Some(translations) => translations,
+            None => return reply.to_string(),
+        };
+        reply
+            .split_inclusive("\r\n")
+            .map(|line| {
+                let is_reply_line = line.len() > 4
+                    && line.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+                    && (line.as_bytes()[3] == b' ' || line.as_bytes()[3] == b'-');
+                let text = line[4.min(line.len())..].trim_end_matches("\r\n");
+                match translations.get(text) {
+                    Some(translated) if is_reply_line => {
+                        format!("{}{}{}", &line[..4], translated, &line[4 + text.len()..])
+                    }
+                    _ => line.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn catalog() -> Catalog {
+        let mut catalog = Catalog::new();
+        catalog.add("fr", "Permission denied", "Permission refusée");
+        catalog.add("fr", "End", "Fin");
+        catalog.add("pt-br", "Permission denied", "Permissão negada");
+        catalog
+    }
+
+    #[test]
+    fn finds_languages() {
+        let catalog = catalog();
+        assert_eq!(catalog.languages().collect::<Vec<_>>(), vec!["FR", "PT-BR"]);
+        assert_eq!(catalog.find("fr-CA"), Some("FR".to_string()));
+        assert_eq!(catalog.find("pt-BR"), Some("PT-BR".to_string()));
+        assert_eq!(catalog.find("pt"), None);
+        assert_eq!(catalog.find("en-US"), Some("EN".to_string()));
+        assert_eq!(catalog.find("de"), None);
+    }
+
+    #[test]
+    fn translates_reply_lines() {
+        let catalog = catalog();
+        assert_eq!(
+            catalog.translate("fr", "550 Permission denied\r\n"),
+            "550 Permission refusée\r\n"
+        );
+        assert_eq!(
+            catalog.translate("pt-BR", "550 Permission denied\r\n"),
+            "550 Permissão negada\r\n"
+        );
+        // Only the lines that start with a reply code, and only the ones with a translation
+        assert_eq!(
+            catalog.translate("fr", "211-Status\r\n End\r\n211 End\r\n"),
+            "211-Status\r\n End\r\n211 Fin\r\n"
+        );
+        assert_eq!(
+            catalog.translate("en", "550 Permission denied\r\n"),
+            "550 Permission denied\r\n"
+        );
+    }
+}
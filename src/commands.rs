@@ -18,6 +18,16 @@ pub enum StruParam {
     Page,
 }
 
+/// The parameter that can be given to the `TYPE` command, specifying the representation of the
+/// transferred data.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeParam {
+    /// Text, with CRLF line endings on the wire.
+    Ascii,
+    /// Binary data, transferred as-is.
+    Image,
+}
+
 /// The parameter that can be given to the `MODE` command. The `MODE` command is obsolete, and we
 /// only support the `Stream` mode. We still have to support the command itself for compatibility
 /// reasons, though.
@@ -29,14 +39,45 @@ pub enum ModeParam {
     Block,
     /// Some round-about way of sending compressed data.
     Compressed,
+    /// Data is sent as a zlib (deflate) compressed stream, non-standard but widely supported.
+    Deflate,
+    /// Data is sent in blocks with a CRC32C each, which the receiver acknowledges or asks for
+    /// again. Our own extension.
+    Integrity,
+}
+
+/// The parameter that can be given to the `EPSV` command.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EpsvParam {
+    /// No parameter: use the network protocol of the control connection.
+    Any,
+    /// Use the given network protocol, as numbered in RFC 2428 (`1` for IPv4, `2` for IPv6).
+    Protocol(u8),
+    /// Only accept `EPSV` for setting up data connections for the remainder of the session.
+    All,
 }
 
 /// The parameter that can be given to the `OPTS` command, specifying the option the client wants
 /// to set.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Opt {
-    /// The client wants us to enable UTF-8 encoding for file paths and such.
-    UTF8,
+    /// The client wants us to enable (`OPTS UTF8` or `OPTS UTF8 ON`) or disable (`OPTS UTF8 OFF`)
+    /// UTF-8 encoding for file paths and such.
+    UTF8 {
+        /// Whether UTF-8 is switched on.
+        enabled: bool,
+    },
+    /// The client wants to know, or change, the algorithm used by `HASH`.
+    Hash {
+        /// The name of the algorithm to switch to, or `None` to ask for the current one.
+        algorithm: Option<String>,
+    },
+    /// The client wants to know (`OPTS MODE Z`), or change (`OPTS MODE Z ENGINE <name>`), the
+    /// compression codec of `MODE Z` transfers.
+    ModeZ {
+        /// The name of the codec to switch to, or `None` to ask for the current one.
+        engine: Option<String>,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -72,7 +113,10 @@ pub enum Command {
         path: Option<Bytes>,
     },
     /// The `TYPE` command
-    Type,
+    Type {
+        /// The representation type the client would like to switch to.
+        type_: TypeParam,
+    },
     /// The `STRU` command
     Stru {
         /// The structure to which the client would like to switch. Only the `File` structure is
@@ -86,13 +130,30 @@ pub enum Command {
         mode: ModeParam,
     },
     /// The `HELP` command
-    Help,
+    Help {
+        /// The command the client wants to know about (e.g. `RETR` or `SITE CHMOD`), or `None`
+        /// for the list of all commands.
+        topic: Option<String>,
+    },
     /// The `NOOP` command
     Noop,
     /// The `PASSV` command
     Pasv,
     /// The `PORT` command
-    Port,
+    Port {
+        /// The address the client would like us to connect to for the data connection.
+        addr: std::net::SocketAddr,
+    },
+    /// The `EPRT` command
+    Eprt {
+        /// The address the client would like us to connect to for the data connection.
+        addr: std::net::SocketAddr,
+    },
+    /// The `EPSV` command
+    Epsv {
+        /// The network protocol the client would like to use, or `ALL`.
+        param: EpsvParam,
+    },
     /// The `RETR` command
     Retr {
         /// The path to the file the client would like to retrieve.
@@ -103,6 +164,11 @@ pub enum Command {
         /// The path to the file the client would like to store.
         path: String,
     },
+    /// The `APPE` command
+    Appe {
+        /// The path to the file the client would like to append to.
+        path: String,
+    },
     /// The `LIST` command
     List {
         /// The path of the file/directory the clients wants to list
@@ -136,15 +202,23 @@ pub enum Command {
     },
     /// The `QUIT` command
     Quit,
+    /// The `REIN` command
+    Rein,
     /// The `MKD` command
     Mkd {
         /// The path to the directory the client wants to create.
         path: std::path::PathBuf,
     },
+    /// The `RMD` command
+    Rmd {
+        /// The path to the (empty) directory the client wants to remove.
+        path: std::path::PathBuf,
+    },
     /// The `ALLO` command
     Allo {
-        // The `ALLO` command can actually have an optional argument, but since we regard `ALLO`
-    // as noop, we won't even parse it.
+        /// The number of bytes the client wants to upload, if it said so. An optional record or
+        /// page size following it is ignored.
+        size: Option<u64>,
     },
     /// The `ABOR` command
     Abor,
@@ -160,13 +234,141 @@ pub enum Command {
         /// The filename to rename to
         file: std::path::PathBuf,
     },
+    /// The `SIZE` command
+    Size {
+        /// The file the client wants to know the size of.
+        file: std::path::PathBuf,
+    },
+    /// The `MDTM` command
+    Mdtm {
+        /// The file the client wants to know the modification time of.
+        file: std::path::PathBuf,
+    },
+    /// The `RANG` command
+    Rang {
+        /// The bytes the next `RETR` should send, or `None` to send whole files again.
+        range: Option<std::ops::Range<u64>>,
+    },
+    /// The `MLST` command
+    Mlst {
+        /// The file or directory the client wants the facts of, or `None` for the current
+        /// directory.
+        path: Option<std::path::PathBuf>,
+    },
+    /// The `HASH` command
+    Hash {
+        /// The file the client wants to know the checksum of.
+        file: std::path::PathBuf,
+    },
+    /// The legacy `XCRC` and `XMD5` commands, which some clients use instead of `HASH`
+    Checksum {
+        /// The algorithm that goes with the verb: CRC-32 for `XCRC` and MD5 for `XMD5`.
+        algorithm: crate::storage::HashAlgorithm,
+        /// The file the client wants to know the checksum of.
+        file: std::path::PathBuf,
+        /// The byte range to calculate the checksum of, if not the whole file.
+        range: Option<std::ops::Range<u64>>,
+    },
+    /// The `MFMT` command
+    Mfmt {
+        /// The modification time the client would like the file to have.
+        time: std::time::SystemTime,
+        /// The file of which the modification time should be changed.
+        file: std::path::PathBuf,
+    },
+    /// The `CLNT` command
+    Clnt {
+        /// The name (and usually the version) of the client software.
+        client: String,
+    },
+    /// The `LANG` command
+    Lang {
+        /// The tag of the language the client wants replies in (e.g. `fr` or `pt-BR`), or
+        /// `None` to go back to the default.
+        tag: Option<String>,
+    },
+    /// The `SITE` command
+    Site {
+        /// The name of the `SITE` subcommand, e.g. `HELP`.
+        command: String,
+        /// Everything following the subcommand name.
+        args: String,
+    },
+}
+
+/// The verbs of all the commands that [`Command::parse`] recognizes, in upper case and ordered
+/// alphabetically. Aliases (like `XPWD` for `PWD`) are listed separately.
+///
+/// [`Command::parse`]: ./enum.Command.html#method.parse
+pub(crate) const SUPPORTED: &[&str] = &[
+    "ABOR", "ACCT", "ALLO", "APPE", "CDUP", "CLNT", "CWD", "DELE", "EPRT", "EPSV", "FEAT", "HASH",
+    "HELP", "LANG", "LIST", "MDTM", "MFMT", "MKD", "MLST", "MODE", "NLST", "NOOP", "OPTS", "PASS",
+    "PASV", "PORT", "PWD", "QUIT", "RANG", "REIN", "RETR", "RMD", "RNFR", "RNTO", "SITE", "SIZE",
+    "STAT", "STOR", "STOU", "STRU", "SYST", "TYPE", "USER", "XCRC", "XCWD", "XMD5", "XMKD", "XPWD",
+    "XRMD",
+];
+
+/// Returns the syntax and a short explanation of the command with the given verb, for `HELP`.
+/// Every verb in [`SUPPORTED`] has one.
+///
+/// [`SUPPORTED`]: ./constant.SUPPORTED.html
+pub(crate) fn help(verb: &str) -> Option<&'static str> {
+    let help = match verb.to_uppercase().as_str() {
+        "ABOR" => "ABOR: Abort the running transfer",
+        "ACCT" => "ACCT <account>: Send account information",
+        "ALLO" => "ALLO <bytes>: Check whether an upload of the given size fits",
+        "APPE" => "APPE <path>: Append to a file",
+        "CDUP" => "CDUP: Change to the parent directory",
+        "CWD" | "XCWD" => "CWD <path>: Change the working directory",
+        "DELE" => "DELE <path>: Delete a file",
+        "EPRT" => "EPRT |<protocol>|<address>|<port>|: Open an active data connection",
+        "EPSV" => "EPSV [<protocol> | ALL]: Open a passive data connection",
+        "FEAT" => "FEAT: List the supported extensions",
+        "HASH" => "HASH <path>: Calculate the checksum of a file",
+        "HELP" => "HELP [<command>]: Explain a command, or list all of them",
+        "CLNT" => "CLNT <client>: Tell the server which client software this is",
+        "LANG" => "LANG [<language>]: Change the language of the replies",
+        "LIST" => "LIST [<path>]: List a directory, with details",
+        "MDTM" => "MDTM <path>: Show the modification time of a file",
+        "MFMT" => "MFMT <YYYYMMDDHHMMSS> <path>: Change the modification time of a file",
+        "MKD" | "XMKD" => "MKD <path>: Create a directory",
+        "MLST" => "MLST [<path>]: Show the facts of a file or directory",
+        "MODE" => "MODE <S | X | Z>: Change the transfer mode",
+        "NLST" => "NLST [<path>]: List the names in a directory",
+        "NOOP" => "NOOP: Do nothing",
+        "OPTS" => "OPTS <command> [<options>]: Set the options of a command",
+        "PASS" => "PASS <password>: Send the password",
+        "PASV" => "PASV: Open a passive data connection",
+        "PORT" => "PORT <h1,h2,h3,h4,p1,p2>: Open an active data connection",
+        "PWD" | "XPWD" => "PWD: Show the working directory",
+        "QUIT" => "QUIT: Close the connection",
+        "REIN" => "REIN: Log out and start over, without closing the connection",
+        "RANG" => "RANG <start> <end>: Only send the given bytes with the next RETR",
+        "RETR" => "RETR <path>: Download a file",
+        "RMD" | "XRMD" => "RMD <path>: Remove a directory",
+        "RNFR" => "RNFR <path>: Pick the file to rename",
+        "RNTO" => "RNTO <path>: Rename the file picked with RNFR",
+        "SITE" => "SITE <command> [<arguments>]: Run a site specific command, see SITE HELP",
+        "SIZE" => "SIZE <path>: Show the size of a file",
+        "STAT" => "STAT [<path>]: Show the status of the session, or list a directory",
+        "STOR" => "STOR <path>: Upload a file",
+        "STOU" => "STOU: Upload a file under a unique name",
+        "STRU" => "STRU F: Change the file structure",
+        "SYST" => "SYST: Show the system type",
+        "TYPE" => "TYPE <A | I>: Change the transfer type",
+        "USER" => "USER <username>: Send the username",
+        "XCRC" => "XCRC <path> [<start> [<end>]]: Calculate the CRC-32 of a file",
+        "XMD5" => "XMD5 <path> [<start> [<end>]]: Calculate the MD5 of a file",
+        _ => return None,
+    };
+    Some(help)
 }
 
 impl Command {
     /// Parse the given bytes into a [`Command`].
     ///
     /// [`Command`]: ./enum.Command.html
-    #[allow(clippy::cyclomatic_complexity)]
+    #[allow(clippy::cognitive_complexity)]
     pub fn parse<T: AsRef<[u8]> + Into<Bytes>>(buf: T) -> Result<Command> {
         let vec = buf.into().to_vec();
         let mut iter = vec.splitn(2, |&b| b == b' ' || b == b'\r' || b == b'\n');
@@ -198,9 +400,15 @@ impl Command {
                 Command::Stat { path }
             }
             b"TYPE" | b"type" => {
-                // We don't care about text format conversion, so we'll ignore the params and we're
-                // just always in binary mode.
-                Command::Type
+                let params = parse_to_eol(cmd_params)?;
+                // We only support the (default) non-print format of ASCII, and 8-bit bytes for the
+                // local type, which is the same as the image type.
+                let type_ = match params.as_ref() {
+                    b"A" | b"a" | b"A N" | b"a n" => TypeParam::Ascii,
+                    b"I" | b"i" | b"L 8" | b"l 8" => TypeParam::Image,
+                    _ => return Err(ParseErrorKind::InvalidCommand)?,
+                };
+                Command::Type { type_ }
             }
             b"STRU" | b"stru" => {
                 let params = parse_to_eol(cmd_params)?;
@@ -235,10 +443,22 @@ impl Command {
                     Some(b'C') => Command::Mode {
                         mode: ModeParam::Compressed,
                     },
+                    Some(b'Z') => Command::Mode {
+                        mode: ModeParam::Deflate,
+                    },
+                    Some(b'X') => Command::Mode {
+                        mode: ModeParam::Integrity,
+                    },
                     _ => return Err(ParseErrorKind::InvalidCommand)?,
                 }
             }
-            b"HELP" | b"help" => Command::Help,
+            b"HELP" | b"help" => {
+                let params = parse_to_eol(cmd_params)?;
+                let topic = String::from_utf8_lossy(&params).trim().to_string();
+                Command::Help {
+                    topic: Some(topic).filter(|topic| !topic.is_empty()),
+                }
+            }
             b"NOOP" | b"noop" => {
                 let params = parse_to_eol(cmd_params)?;
                 if !params.is_empty() {
@@ -254,19 +474,35 @@ impl Command {
                 }
                 Command::Pasv
             }
+            b"EPSV" | b"epsv" => {
+                let params = parse_to_eol(cmd_params)?;
+                let param = match params.as_ref() {
+                    b"" => EpsvParam::Any,
+                    b"1" => EpsvParam::Protocol(1),
+                    b"2" => EpsvParam::Protocol(2),
+                    p if p.eq_ignore_ascii_case(b"ALL") => EpsvParam::All,
+                    _ => return Err(ParseErrorKind::InvalidCommand)?,
+                };
+                Command::Epsv { param }
+            }
+            b"EPRT" | b"eprt" => {
+                let params = parse_to_eol(cmd_params)?;
+                Command::Eprt {
+                    addr: parse_eprt_addr(&params)?,
+                }
+            }
             b"PORT" | b"port" => {
                 let params = parse_to_eol(cmd_params)?;
-                if params.is_empty() {
-                    return Err(ParseErrorKind::InvalidCommand)?;
+                Command::Port {
+                    addr: parse_port_addr(&params)?,
                 }
-                Command::Port
             }
             b"RETR" | b"retr" => {
                 let path = parse_to_eol(cmd_params)?;
                 if path.is_empty() {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
-                let path = String::from_utf8_lossy(&path);
+                let path = parse_path(&path)?;
                 // TODO: Can we do this without allocation?
                 Command::Retr {
                     path: path.to_string(),
@@ -278,17 +514,27 @@ impl Command {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
                 // TODO:: Can we do this without allocation?
-                let path = String::from_utf8_lossy(&path);
+                let path = parse_path(&path)?;
                 Command::Stor {
                     path: path.to_string(),
                 }
             }
+            b"APPE" | b"appe" => {
+                let path = parse_to_eol(cmd_params)?;
+                if path.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+                let path = parse_path(&path)?;
+                Command::Appe {
+                    path: path.to_string(),
+                }
+            }
             b"LIST" | b"list" => {
                 let path = parse_to_eol(cmd_params)?;
                 let path = if path.is_empty() {
                     None
                 } else {
-                    Some(String::from_utf8_lossy(&path).to_string())
+                    Some(parse_path(&path)?)
                 };
                 Command::List { path }
             }
@@ -297,7 +543,7 @@ impl Command {
                 let path = if path.is_empty() {
                     None
                 } else {
-                    Some(String::from_utf8_lossy(&path).to_string())
+                    Some(parse_path(&path)?)
                 };
                 Command::Nlst { path }
             }
@@ -320,7 +566,7 @@ impl Command {
                 if path.is_empty() {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
-                let path = String::from_utf8_lossy(&path).to_string();
+                let path = parse_path(&path)?;
                 let path = path.into();
                 Command::Cwd { path }
             }
@@ -337,8 +583,45 @@ impl Command {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
 
-                match &params[..] {
-                    b"UTF8" => Command::Opts { option: Opt::UTF8 },
+                let params = String::from_utf8_lossy(&params);
+                let mut params = params.splitn(2, ' ');
+                let option = params.next().unwrap_or("");
+                let value = params
+                    .next()
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty());
+                match (option.to_uppercase().as_str(), value) {
+                    ("UTF8", value) => {
+                        let enabled = match value.map(str::to_uppercase).as_deref() {
+                            None | Some("ON") => true,
+                            Some("OFF") => false,
+                            Some(_) => return Err(ParseErrorKind::InvalidCommand)?,
+                        };
+                        Command::Opts {
+                            option: Opt::UTF8 { enabled },
+                        }
+                    }
+                    ("HASH", algorithm) => Command::Opts {
+                        option: Opt::Hash {
+                            algorithm: algorithm.map(str::to_string),
+                        },
+                    },
+                    ("MODE", Some(value)) => {
+                        let words: Vec<&str> = value.split_whitespace().collect();
+                        let engine = match words.as_slice() {
+                            [mode] if mode.eq_ignore_ascii_case("Z") => None,
+                            [mode, option, engine]
+                                if mode.eq_ignore_ascii_case("Z")
+                                    && option.eq_ignore_ascii_case("ENGINE") =>
+                            {
+                                Some(engine.to_string())
+                            }
+                            _ => return Err(ParseErrorKind::InvalidCommand)?,
+                        };
+                        Command::Opts {
+                            option: Opt::ModeZ { engine },
+                        }
+                    }
                     _ => return Err(ParseErrorKind::InvalidCommand)?,
                 }
             }
@@ -348,7 +631,7 @@ impl Command {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
 
-                let path = String::from_utf8_lossy(&path).to_string();
+                let path = parse_path(&path)?;
                 Command::Dele { path }
             }
             b"QUIT" | b"quit" => {
@@ -359,17 +642,43 @@ impl Command {
 
                 Command::Quit
             }
+            b"REIN" | b"rein" => {
+                let params = parse_to_eol(cmd_params)?;
+                if !params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                Command::Rein
+            }
             b"MKD" | b"XMKD" | b"mkd" | b"xmkd" => {
                 let params = parse_to_eol(cmd_params)?;
                 if params.is_empty() {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
 
-                let path = String::from_utf8_lossy(&params).to_string();
+                let path = parse_path(&params)?;
                 let path = path.into();
                 Command::Mkd { path }
             }
-            b"ALLO" | b"allo" => Command::Allo {},
+            b"RMD" | b"XRMD" | b"rmd" | b"xrmd" => {
+                let params = parse_to_eol(cmd_params)?;
+                if params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                let path = parse_path(&params)?;
+                let path = path.into();
+                Command::Rmd { path }
+            }
+            b"ALLO" | b"allo" => {
+                let params = parse_to_eol(cmd_params)?;
+                let params = String::from_utf8_lossy(&params);
+                let size = params
+                    .split_whitespace()
+                    .next()
+                    .and_then(|size| size.parse().ok());
+                Command::Allo { size }
+            }
             b"ABOR" | b"abor" => {
                 let params = parse_to_eol(cmd_params)?;
                 if !params.is_empty() {
@@ -390,7 +699,7 @@ impl Command {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
 
-                let file = String::from_utf8_lossy(&params).to_string();
+                let file = parse_path(&params)?;
                 // We really match on "/" and not some cross-OS-portable delimiter, because RFC
                 // 3659 actually defines "/" as the standard delimiter.
                 if file.contains('/') {
@@ -406,7 +715,7 @@ impl Command {
                     return Err(ParseErrorKind::InvalidCommand)?;
                 }
 
-                let file = String::from_utf8_lossy(&params).to_string();
+                let file = parse_path(&params)?;
                 // We really match on "/" and not some cross-OS-portable delimiter, because RFC
                 // 3659 actually defines "/" as the standard delimiter.
                 if file.contains('/') {
@@ -416,6 +725,134 @@ impl Command {
                 let file = file.into();
                 Command::Rnto { file }
             }
+            b"SIZE" | b"size" => {
+                let params = parse_to_eol(cmd_params)?;
+                if params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                let file = parse_path(&params)?;
+                let file = file.into();
+                Command::Size { file }
+            }
+            b"MDTM" | b"mdtm" => {
+                let params = parse_to_eol(cmd_params)?;
+                if params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                let file = parse_path(&params)?;
+                let file = file.into();
+                Command::Mdtm { file }
+            }
+            b"RANG" | b"rang" => {
+                let params = parse_to_eol(cmd_params)?;
+                let params = String::from_utf8_lossy(&params);
+                let mut offsets = params.split(' ').map(str::parse::<u64>);
+                let range = match (offsets.next(), offsets.next(), offsets.next()) {
+                    // `RANG 1 0` resets the range.
+                    (Some(Ok(1)), Some(Ok(0)), None) => None,
+                    // Both offsets are inclusive.
+                    (Some(Ok(start)), Some(Ok(end)), None) if start <= end => {
+                        Some(start..end.saturating_add(1))
+                    }
+                    _ => return Err(ParseErrorKind::InvalidCommand)?,
+                };
+                Command::Rang { range }
+            }
+            b"MLST" | b"mlst" => {
+                let params = parse_to_eol(cmd_params)?;
+                let path = if params.is_empty() {
+                    None
+                } else {
+                    Some(parse_path(&params)?.into())
+                };
+                Command::Mlst { path }
+            }
+            b"HASH" | b"hash" => {
+                let params = parse_to_eol(cmd_params)?;
+                if params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                let file = parse_path(&params)?;
+                let file = file.into();
+                Command::Hash { file }
+            }
+            b"XCRC" | b"xcrc" | b"XMD5" | b"xmd5" => {
+                let params = parse_to_eol(cmd_params)?;
+                let algorithm = if cmd_token.eq_ignore_ascii_case(b"XCRC") {
+                    crate::storage::HashAlgorithm::Crc32
+                } else {
+                    crate::storage::HashAlgorithm::Md5
+                };
+                let (file, range) = parse_checksum_params(&parse_path(&params)?)
+                    .ok_or(ParseErrorKind::InvalidCommand)?;
+                Command::Checksum {
+                    algorithm,
+                    file: file.into(),
+                    range,
+                }
+            }
+            b"CLNT" | b"clnt" => {
+                let client = String::from_utf8_lossy(&parse_to_eol(cmd_params)?)
+                    .trim()
+                    .to_string();
+                if client.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+                Command::Clnt { client }
+            }
+            b"LANG" | b"lang" => {
+                let params = parse_to_eol(cmd_params)?;
+                let tag = String::from_utf8_lossy(&params).trim().to_string();
+                // RFC 2640 language tags: a primary tag, optionally followed by subtags
+                let valid = tag.split('-').all(|subtag| {
+                    !subtag.is_empty()
+                        && subtag.len() <= 8
+                        && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+                });
+                if !tag.is_empty() && !valid {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+                Command::Lang {
+                    tag: Some(tag).filter(|tag| !tag.is_empty()),
+                }
+            }
+            b"MFMT" | b"mfmt" => {
+                let params = parse_to_eol(cmd_params)?;
+                let params = parse_path(&params)?;
+                let mut params = params.splitn(2, ' ');
+                let time = params.next().unwrap_or("");
+                let file = match params.next() {
+                    Some(file) if !file.is_empty() => file,
+                    _ => return Err(ParseErrorKind::InvalidCommand)?,
+                };
+
+                // The time is given as `YYYYMMDDHHMMSS[.sss]` in UTC, see
+                // https://tools.ietf.org/html/draft-somers-ftp-mfxx-04
+                let time = chrono::NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S%.f")
+                    .map_err(|_| ParseErrorKind::InvalidCommand)?;
+                let time =
+                    chrono::DateTime::<chrono::Utc>::from_naive_utc_and_offset(time, chrono::Utc)
+                        .into();
+                Command::Mfmt {
+                    time,
+                    file: file.into(),
+                }
+            }
+            b"SITE" | b"site" => {
+                let params = parse_to_eol(cmd_params)?;
+                if params.is_empty() {
+                    return Err(ParseErrorKind::InvalidCommand)?;
+                }
+
+                let params = String::from_utf8_lossy(&params);
+                let mut params = params.splitn(2, ' ');
+                let command = params.next().unwrap_or("").to_string();
+                let args = params.next().unwrap_or("").to_string();
+                Command::Site { command, args }
+            }
             _ => {
                 return Err(ParseErrorKind::UnknownCommand {
                     command: std::str::from_utf8(cmd_token)
@@ -429,6 +866,54 @@ impl Command {
     }
 }
 
+/// Decodes a path parameter. RFC 2640 has paths exchanged in UTF-8, and rather than guessing
+/// what other bytes mean (or replacing them and touching the wrong file), we refuse them.
+fn parse_path(params: &[u8]) -> Result<String> {
+    Ok(String::from_utf8(params.to_vec()).context(ParseErrorKind::InvalidUTF8)?)
+}
+
+/// Parses the `h1,h2,h3,h4,p1,p2` parameter of the `PORT` command, where `h1` to `h4` are the
+/// octets of the IPv4 address and the port is `p1 * 256 + p2`.
+fn parse_port_addr(params: &[u8]) -> Result<std::net::SocketAddr> {
+    let params = std::str::from_utf8(params).map_err(|_| ParseErrorKind::InvalidCommand)?;
+    let fields = params
+        .split(',')
+        .map(|field| field.parse::<u8>())
+        .collect::<result::Result<Vec<u8>, _>>()
+        .map_err(|_| ParseErrorKind::InvalidCommand)?;
+    match fields.as_slice() {
+        [h1, h2, h3, h4, p1, p2] => Ok(std::net::SocketAddr::new(
+            std::net::Ipv4Addr::new(*h1, *h2, *h3, *h4).into(),
+            u16::from(*p1) << 8 | u16::from(*p2),
+        )),
+        _ => Err(ParseErrorKind::InvalidCommand)?,
+    }
+}
+
+/// Parses the `<d><net-prt><d><net-addr><d><tcp-port><d>` parameter of the `EPRT` command (RFC
+/// 2428), where the delimiter `<d>` is the first character of the parameter.
+fn parse_eprt_addr(params: &[u8]) -> Result<std::net::SocketAddr> {
+    let delimiter = match params.first() {
+        Some(d) if (33..=126).contains(d) => *d as char,
+        _ => return Err(ParseErrorKind::InvalidCommand)?,
+    };
+    let params = std::str::from_utf8(params).map_err(|_| ParseErrorKind::InvalidCommand)?;
+    let fields: Vec<&str> = params.split(delimiter).collect();
+    // The leading and trailing delimiters result in empty fields at both ends
+    let (protocol, addr, port) = match fields.as_slice() {
+        ["", protocol, addr, port, ""] => (*protocol, *addr, *port),
+        _ => return Err(ParseErrorKind::InvalidCommand)?,
+    };
+    let ip: std::net::IpAddr = addr.parse().map_err(|_| ParseErrorKind::InvalidCommand)?;
+    let port: u16 = port.parse().map_err(|_| ParseErrorKind::InvalidCommand)?;
+    match (protocol, ip) {
+        ("1", std::net::IpAddr::V4(_)) | ("2", std::net::IpAddr::V6(_)) => {
+            Ok(std::net::SocketAddr::new(ip, port))
+        }
+        _ => Err(ParseErrorKind::InvalidCommand)?,
+    }
+}
+
 /// Try to parse a buffer of bytes, up to end of line into a `&str`.
 fn parse_to_eol<T: AsRef<[u8]> + Into<Bytes>>(bytes: T) -> Result<Bytes> {
     let mut pos: usize = 0;
@@ -453,7 +938,7 @@ fn parse_to_eol<T: AsRef<[u8]> + Into<Bytes>>(bytes: T) -> Result<Bytes> {
         }
 
         if !is_valid_token_char(*b) {
-            return Err(ParseErrorKind::InvalidToken { token: *b })?;
+            return Err(ParseErrorKind::InvalidToken { token: *b }.into());
         }
 
         // We don't have to be afraid of an overflow here, since a `Bytes` can never be bigger than
@@ -462,8 +947,44 @@ fn parse_to_eol<T: AsRef<[u8]> + Into<Bytes>>(bytes: T) -> Result<Bytes> {
     }
 }
 
+// Parses the parameters of `XCRC` and `XMD5`: a file name, optionally in double quotes, followed
+// by an optional start offset and an optional end offset (exclusive). Without quotes, the trailing
+// numbers are taken to be offsets rather than part of the name.
+fn parse_checksum_params(params: &str) -> Option<(String, Option<std::ops::Range<u64>>)> {
+    let params = params.trim();
+    let (file, offsets) = if let Some(quoted) = params.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        (&quoted[..end], &quoted[end + 1..])
+    } else {
+        let mut file = params;
+        for _ in 0..2 {
+            match file.rfind(' ') {
+                Some(i) if file[i + 1..].bytes().all(|b| b.is_ascii_digit()) => {
+                    file = file[..i].trim_end()
+                }
+                _ => break,
+            }
+        }
+        (file, &params[file.len()..])
+    };
+    if file.is_empty() {
+        return None;
+    }
+
+    let mut offsets = offsets.split_whitespace().map(str::parse::<u64>);
+    let range = match (offsets.next(), offsets.next(), offsets.next()) {
+        (None, _, _) => None,
+        (Some(Ok(start)), None, _) => Some(start..u64::MAX),
+        (Some(Ok(start)), Some(Ok(end)), None) if start <= end => Some(start..end),
+        _ => return None,
+    };
+    Some((file.to_string(), range))
+}
+
+// Anything but control characters. That includes the bytes above 0x7F, which make up the
+// non-ASCII characters of UTF-8 (RFC 2640) paths.
 fn is_valid_token_char(b: u8) -> bool {
-    b > 0x1F && b < 0x7F
+    b > 0x1F && b != 0x7F
 }
 
 /// The error type returned by the [Command::parse] method.
@@ -511,7 +1032,7 @@ pub enum ParseErrorKind {
 }
 
 impl Fail for ParseError {
-    fn cause(&self) -> Option<&Fail> {
+    fn cause(&self) -> Option<&dyn Fail> {
         self.inner.cause()
     }
 
@@ -555,6 +1076,31 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn supported_commands_are_recognized() {
+        for verb in SUPPORTED {
+            // Without parameters many commands are invalid, but none of them should be unknown.
+            if let Err(e) = Command::parse(format!("{}\r\n", verb)) {
+                assert_ne!(
+                    std::mem::discriminant(e.kind()),
+                    std::mem::discriminant(&ParseErrorKind::UnknownCommand {
+                        command: String::new()
+                    }),
+                    "{} is listed as supported, but not recognized",
+                    verb
+                );
+            }
+        }
+        assert!(SUPPORTED.windows(2).all(|w| w[0] < w[1]));
+
+        assert_eq!(
+            Command::parse("XYZZY\r\n"),
+            Err(ParseError::from(ParseErrorKind::UnknownCommand {
+                command: "XYZZY".to_string()
+            }))
+        );
+    }
+
     #[test]
     fn parse_user_cmd_crnl() {
         let input = "USER Dolores\r\n";
@@ -787,6 +1333,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_type() {
+        for (input, type_) in &[
+            ("TYPE A\r\n", TypeParam::Ascii),
+            ("type a n\r\n", TypeParam::Ascii),
+            ("TYPE I\r\n", TypeParam::Image),
+            ("TYPE L 8\r\n", TypeParam::Image),
+        ] {
+            assert_eq!(
+                Command::parse(*input).unwrap(),
+                Command::Type {
+                    type_: type_.clone()
+                }
+            );
+        }
+
+        for input in &["TYPE\r\n", "TYPE E\r\n", "TYPE A T\r\n"] {
+            assert_eq!(
+                Command::parse(*input),
+                Err(ParseError {
+                    inner: Context::new(ParseErrorKind::InvalidCommand)
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn parse_mode_z() {
+        let input = "MODE Z\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Mode {
+                mode: ModeParam::Deflate
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mode_x() {
+        let input = "MODE X\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Mode {
+                mode: ModeParam::Integrity
+            }
+        );
+    }
+
     #[test]
     fn parse_mode_garbage() {
         let input = "MODE SKDJF\r\n";
@@ -817,10 +1411,37 @@ mod tests {
     #[test]
     fn parse_help() {
         let input = "HELP\r\n";
-        assert_eq!(Command::parse(input).unwrap(), Command::Help);
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Help { topic: None }
+        );
 
         let input = "HELP bla\r\n";
-        assert_eq!(Command::parse(input).unwrap(), Command::Help);
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Help {
+                topic: Some("bla".to_string())
+            }
+        );
+
+        let input = "HELP SITE CHMOD\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Help {
+                topic: Some("SITE CHMOD".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn help_for_every_command() {
+        for verb in SUPPORTED {
+            let help = help(verb).unwrap_or_else(|| panic!("No help for {}", verb));
+            // Aliases explain the command they're an alias for.
+            assert!(help.starts_with(verb) || help.starts_with(&verb[1..]));
+        }
+        assert_eq!(help("retr"), help("RETR"));
+        assert_eq!(help("XYZZ"), None);
     }
 
     #[test]
@@ -837,6 +1458,83 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_epsv() {
+        let input = "EPSV\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Epsv {
+                param: EpsvParam::Any
+            }
+        );
+
+        let input = "EPSV 2\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Epsv {
+                param: EpsvParam::Protocol(2)
+            }
+        );
+
+        let input = "epsv all\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Epsv {
+                param: EpsvParam::All
+            }
+        );
+
+        let input = "EPSV 3\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_eprt() {
+        let input = "EPRT |1|132.235.1.2|6275|\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Eprt {
+                addr: "132.235.1.2:6275".parse().unwrap()
+            }
+        );
+
+        let input = "EPRT |2|1080::8:800:200C:417A|5282|\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Eprt {
+                addr: "[1080::8:800:200C:417A]:5282".parse().unwrap()
+            }
+        );
+
+        // Any printable delimiter is allowed
+        let input = "EPRT !1!10.0.0.1!21!\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Eprt {
+                addr: "10.0.0.1:21".parse().unwrap()
+            }
+        );
+
+        for input in &[
+            "EPRT\r\n",
+            "EPRT |2|132.235.1.2|6275|\r\n",
+            "EPRT |1|132.235.1.2|70000|\r\n",
+            "EPRT |1|132.235.1.2|6275\r\n",
+        ] {
+            assert_eq!(
+                Command::parse(*input),
+                Err(ParseError {
+                    inner: Context::new(ParseErrorKind::InvalidCommand)
+                })
+            );
+        }
+    }
+
     #[test]
     fn parse_pasv() {
         let input = "PASV\r\n";
@@ -861,8 +1559,27 @@ mod tests {
             })
         );
 
-        let input = "PORT a1,a2,a3,a4,p1,p2\r\n";
-        assert_eq!(Command::parse(input).unwrap(), Command::Port);
+        let input = "PORT 132,235,1,2,24,131\r\n";
+        assert_eq!(
+            Command::parse(input).unwrap(),
+            Command::Port {
+                addr: "132.235.1.2:6275".parse().unwrap()
+            }
+        );
+
+        for input in &[
+            "PORT a1,a2,a3,a4,p1,p2\r\n",
+            "PORT 132,235,1,2,24\r\n",
+            "PORT 132,235,1,2,24,131,1\r\n",
+            "PORT 132,235,1,256,24,131\r\n",
+        ] {
+            assert_eq!(
+                Command::parse(*input),
+                Err(ParseError {
+                    inner: Context::new(ParseErrorKind::InvalidCommand)
+                })
+            );
+        }
     }
 
     #[test]
@@ -880,6 +1597,45 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_utf8_paths() {
+        let input = &b"RETR caf\xc3\xa9.txt\r\n"[..];
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Retr {
+                path: "café.txt".to_string()
+            })
+        );
+
+        // Latin-1 rather than UTF-8
+        let input = &b"DELE caf\xe9.txt\r\n"[..];
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidUTF8)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_appe() {
+        let input = "APPE\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "APPE server.log\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Appe {
+                path: "server.log".into()
+            })
+        );
+    }
+
     #[test]
     fn parse_feat() {
         let input = "FEAT\r\n";
@@ -970,7 +1726,142 @@ mod tests {
         let input = "OPTS UTF8\r\n";
         assert_eq!(
             Command::parse(input),
-            Ok(Command::Opts { option: Opt::UTF8 })
+            Ok(Command::Opts {
+                option: Opt::UTF8 { enabled: true }
+            })
+        );
+        let input = "OPTS UTF8 ON\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::UTF8 { enabled: true }
+            })
+        );
+        let input = "OPTS utf8 off\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::UTF8 { enabled: false }
+            })
+        );
+        let input = "OPTS UTF8 MAYBE\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "OPTS HASH\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::Hash { algorithm: None }
+            })
+        );
+
+        let input = "OPTS HASH SHA-1\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::Hash {
+                    algorithm: Some("SHA-1".to_string())
+                }
+            })
+        );
+
+        let input = "OPTS MODE Z\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::ModeZ { engine: None }
+            })
+        );
+
+        let input = "OPTS mode z engine zstd\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Opts {
+                option: Opt::ModeZ {
+                    engine: Some("zstd".to_string())
+                }
+            })
+        );
+
+        let input = "OPTS MODE Z LEVEL 9\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+    }
+
+    #[test]
+    fn parse_hash() {
+        let input = "HASH\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "HASH my file.iso\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Hash {
+                file: "my file.iso".into()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_xcrc_and_xmd5() {
+        let input = "XCRC\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "XCRC setup.exe\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Checksum {
+                algorithm: crate::storage::HashAlgorithm::Crc32,
+                file: "setup.exe".into(),
+                range: None,
+            })
+        );
+
+        let input = "XMD5 \"my file 2.iso\" 1024 4096\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Checksum {
+                algorithm: crate::storage::HashAlgorithm::Md5,
+                file: "my file 2.iso".into(),
+                range: Some(1024..4096),
+            })
+        );
+
+        let input = "xmd5 my file.iso 1024\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Checksum {
+                algorithm: crate::storage::HashAlgorithm::Md5,
+                file: "my file.iso".into(),
+                range: Some(1024..u64::MAX),
+            })
+        );
+
+        let input = "XCRC \"setup.exe\" 4096 1024\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
         );
     }
 
@@ -1007,6 +1898,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_rein() {
+        let input = "REIN\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Rein));
+
+        let input = "REIN alice\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError::from(ParseErrorKind::InvalidCommand))
+        );
+    }
+
     #[test]
     fn parse_mkd() {
         let input = "MKD\r\n";
@@ -1024,18 +1927,116 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_rmd() {
+        let input = "RMD\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "XRMD old stuff\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Rmd {
+                path: "old stuff".into()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rang() {
+        let input = "RANG 10 19\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Rang {
+                range: Some(10..20)
+            })
+        );
+
+        let input = "RANG 1 0\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Rang { range: None }));
+
+        for input in &["RANG 5 4\r\n", "RANG 5\r\n", "RANG 1 2 3\r\n", "RANG\r\n"] {
+            assert_eq!(
+                Command::parse(*input),
+                Err(ParseError::from(ParseErrorKind::InvalidCommand))
+            );
+        }
+    }
+
+    #[test]
+    fn parse_mlst() {
+        let input = "MLST\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Mlst { path: None }));
+
+        let input = "MLST some file.txt\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Mlst {
+                path: Some("some file.txt".into())
+            })
+        );
+    }
+
+    #[test]
+    fn parse_clnt() {
+        let input = "CLNT WS_FTP Pro 12.9\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Clnt {
+                client: "WS_FTP Pro 12.9".to_string()
+            })
+        );
+
+        let input = "CLNT\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError::from(ParseErrorKind::InvalidCommand))
+        );
+    }
+
+    #[test]
+    fn parse_lang() {
+        let input = "LANG\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Lang { tag: None }));
+
+        let input = "LANG fr-CA\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Lang {
+                tag: Some("fr-CA".to_string())
+            })
+        );
+
+        let input = "LANG fr_CA\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError::from(ParseErrorKind::InvalidCommand))
+        );
+    }
+
     #[test]
     fn parse_allo() {
         let input = "ALLO\r\n";
-        assert_eq!(Command::parse(input), Ok(Command::Allo {}));
+        assert_eq!(Command::parse(input), Ok(Command::Allo { size: None }));
 
-        // This is actually not a valid `ALLO` command, but since we ignore it anyway there is no
-        // need to add complexity by actually parsing it.
         let input = "ALLO 5\r\n";
-        assert_eq!(Command::parse(input), Ok(Command::Allo {}));
+        assert_eq!(Command::parse(input), Ok(Command::Allo { size: Some(5) }));
+
+        let input = "ALLO 6442450944 R 512\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Allo {
+                size: Some(6 << 30)
+            })
+        );
 
+        // Not a valid `ALLO` command, but rejecting it wouldn't help anybody.
         let input = "ALLO R 5\r\n";
-        assert_eq!(Command::parse(input), Ok(Command::Allo {}));
+        assert_eq!(Command::parse(input), Ok(Command::Allo { size: None }));
     }
 
     #[test]
@@ -1135,4 +2136,116 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn parse_size() {
+        let input = "SIZE\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "SIZE some file.txt\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Size {
+                file: "some file.txt".into()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_mdtm() {
+        let input = "MDTM\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "MDTM backup.tar\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Mdtm {
+                file: "backup.tar".into()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_mfmt() {
+        let input = "MFMT\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "MFMT 20190401120000\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "MFMT yesterday my file.txt\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "MFMT 20190401120000 my file.txt\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Mfmt {
+                time: std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_554_120_000),
+                file: "my file.txt".into(),
+            })
+        );
+
+        let input = "MFMT 20190401120000.500 my file.txt\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Mfmt {
+                time: std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_554_120_000_500),
+                file: "my file.txt".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_site() {
+        let input = "SITE\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Err(ParseError {
+                inner: Context::new(ParseErrorKind::InvalidCommand)
+            })
+        );
+
+        let input = "SITE HELP\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Site {
+                command: "HELP".into(),
+                args: "".into(),
+            })
+        );
+
+        let input = "SITE CHMOD 644 my file\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Site {
+                command: "CHMOD".into(),
+                args: "644 my file".into(),
+            })
+        );
+    }
 }
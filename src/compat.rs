@@ -0,0 +1,31 @@
+use std::future::Future;
+
+use futures03::{FutureExt, TryFutureExt};
+
+// Turns a future of the storage backend or the authenticator into one that the Tokio runtime can
+// run, and that can be chained with the other futures of the server.
+pub(crate) fn compat<T, E, F>(future: F) -> Box<dyn futures::Future<Item = T, Error = E> + Send>
+where
+    T: 'static,
+    E: 'static,
+    F: Future<Output = Result<T, E>> + Send + 'static,
+{
+    Box::new(future.boxed().compat())
+}
+
+// Runs the given test on a thread of a Tokio runtime, where `block_on` can wait for futures that
+// make blocking calls, like the ones of `tokio::fs` do. Timers don't fire while it waits, so tests
+// of what takes time have to run their futures on the runtime with `compat`.
+#[cfg(test)]
+pub(crate) fn on_runtime<F>(test: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut runtime = tokio::runtime::Runtime::new().expect("Failed to start a tokio runtime");
+    runtime
+        .block_on(futures::future::lazy(move || {
+            test();
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+}
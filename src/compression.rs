@@ -0,0 +1,189 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use flate2::read::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+use tokio_io::AsyncRead;
+
+/// A boxed reader for the data connection, so transfers look the same whether they are
+/// compressed or not.
+pub type Reader = Box<dyn AsyncRead + Send>;
+
+// Lets the `Read` adapters of flate2 be used as `AsyncRead`. They pass `WouldBlock` errors of the
+// underlying reader on without losing any state, which is all `AsyncRead` requires.
+struct Async<R>(R);
+
+impl<R: Read> Read for Async<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> AsyncRead for Async<R> {}
+
+/// A compression format that `MODE Z` transfers can use. The `Server` always has [`Zlib`], which
+/// is what `MODE Z` means to every client; other codecs, like zstd, can be added with
+/// `Server::compression_codec` for clients that pick them with `OPTS MODE Z ENGINE <name>`.
+///
+/// Both sides of a codec work on readers: `compress` for downloads and listings, reading from the
+/// storage backend, and `decompress` for uploads, reading from the data connection. Like every
+/// `AsyncRead`, the returned readers have to pass `WouldBlock` errors of the reader they wrap on
+/// without losing any state.
+///
+/// # Example
+///
+/// A codec that's only good for tests, as it doesn't compress at all:
+///
+/// ```rust
+/// use firetrap::compression::{Codec, Reader};
+/// use firetrap::Server;
+/// use std::sync::Arc;
+///
+/// struct Identity;
+///
+/// impl Codec for Identity {
+///     fn name(&self) -> &str {
+///         "identity"
+///     }
+///
+///     fn compress(&self, reader: Reader) -> Reader {
+///         reader
+///     }
+///
+///     fn decompress(&self, reader: Reader) -> Reader {
+///         reader
+///     }
+/// }
+///
+/// let server = Server::with_root("/tmp").compression_codec(Arc::new(Identity));
+/// ```
+///
+/// [`Zlib`]: struct.Zlib.html
+pub trait Codec {
+    /// The name clients select the codec by, e.g. `zstd`. It's compared case-insensitively.
+    fn name(&self) -> &str;
+
+    /// Returns a reader that compresses what it reads from `reader`.
+    fn compress(&self, reader: Reader) -> Reader;
+
+    /// Returns a reader that decompresses what it reads from `reader`.
+    fn decompress(&self, reader: Reader) -> Reader;
+}
+
+/// The zlib stream that `MODE Z` is about (see draft-preston-ftpext-deflate), compressed with the
+/// level the `Server` is configured with (see `Server::deflate_level`).
+#[derive(Clone, Copy, Debug)]
+pub struct Zlib {
+    level: u32,
+}
+
+impl Zlib {
+    /// Compress with the given level, from `0` (none) to `9` (best).
+    pub fn new(level: u32) -> Self {
+        Zlib {
+            level: std::cmp::min(level, 9),
+        }
+    }
+}
+
+impl Codec for Zlib {
+    fn name(&self) -> &str {
+        "zlib"
+    }
+
+    fn compress(&self, reader: Reader) -> Reader {
+        Box::new(Async(ZlibEncoder::new(
+            reader,
+            Compression::new(self.level),
+        )))
+    }
+
+    fn decompress(&self, reader: Reader) -> Reader {
+        Box::new(Async(ZlibDecoder::new(reader)))
+    }
+}
+
+// The codec of a transfer in `MODE Z`, if it is one.
+pub(crate) type Selected = Option<Arc<dyn Codec + Send + Sync>>;
+
+// Compresses the data read from `reader` with the selected codec, if any.
+pub(crate) fn compress<R>(reader: R, codec: &Selected) -> Reader
+where
+    R: AsyncRead + Send + 'static,
+{
+    match codec {
+        Some(codec) => codec.compress(Box::new(reader)),
+        None => Box::new(reader),
+    }
+}
+
+// Decompresses the data read from `reader` with the selected codec, if any.
+pub(crate) fn decompress<R>(reader: R, codec: &Selected) -> Reader
+where
+    R: AsyncRead + Send + 'static,
+{
+    match codec {
+        Some(codec) => codec.decompress(Box::new(reader)),
+        None => Box::new(reader),
+    }
+}
+
+// Counts the bytes that go through a reader, for the compression ratio of a transfer: the bytes
+// that go into the codec on one side, while the other side is counted by the transfer itself.
+pub(crate) struct Counted<R> {
+    inner: R,
+    bytes: Arc<AtomicU64>,
+}
+
+pub(crate) fn counted<R>(inner: R, bytes: &Arc<AtomicU64>) -> Counted<R> {
+    Counted {
+        inner,
+        bytes: Arc::clone(bytes),
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Counted<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let data = b"Lorem ipsum dolor sit amet. ".repeat(100);
+
+        let zlib: Selected = Some(Arc::new(Zlib::new(9)));
+        let read = Arc::new(AtomicU64::new(0));
+
+        let mut compressed = vec![];
+        compress(counted(std::io::Cursor::new(data.clone()), &read), &zlib)
+            .read_to_end(&mut compressed)
+            .unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(read.load(Ordering::Relaxed), data.len() as u64);
+
+        let mut decompressed = vec![];
+        decompress(std::io::Cursor::new(compressed), &zlib)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut out = vec![];
+        compress(std::io::Cursor::new(b"plain".to_vec()), &None)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"plain");
+    }
+}
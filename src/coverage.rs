@@ -0,0 +1,216 @@
+use std::fmt::Write;
+
+use crate::commands;
+
+/// An RFC that defines FTP commands.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rfc {
+    /// RFC 959: File Transfer Protocol.
+    Rfc959,
+    /// RFC 2228: FTP Security Extensions.
+    Rfc2228,
+    /// RFC 2389: Feature negotiation mechanism for the File Transfer Protocol.
+    Rfc2389,
+    /// RFC 2640: Internationalization of the File Transfer Protocol.
+    Rfc2640,
+    /// RFC 3659: Extensions to FTP.
+    Rfc3659,
+}
+
+impl Rfc {
+    /// The number of the RFC, e.g. `959`.
+    pub fn number(self) -> u16 {
+        match self {
+            Rfc::Rfc959 => 959,
+            Rfc::Rfc2228 => 2228,
+            Rfc::Rfc2389 => 2389,
+            Rfc::Rfc2640 => 2640,
+            Rfc::Rfc3659 => 3659,
+        }
+    }
+
+    /// The commands defined by this RFC.
+    pub fn commands(self) -> &'static [&'static str] {
+        match self {
+            Rfc::Rfc959 => &[
+                "ABOR", "ACCT", "ALLO", "APPE", "CDUP", "CWD", "DELE", "HELP", "LIST", "MKD",
+                "MODE", "NLST", "NOOP", "PASS", "PASV", "PORT", "PWD", "QUIT", "REIN", "REST",
+                "RETR", "RMD", "RNFR", "RNTO", "SITE", "SMNT", "STAT", "STOR", "STOU", "STRU",
+                "SYST", "TYPE", "USER",
+            ],
+            Rfc::Rfc2228 => &["ADAT", "AUTH", "CCC", "CONF", "ENC", "MIC", "PBSZ", "PROT"],
+            Rfc::Rfc2389 => &["FEAT", "OPTS"],
+            Rfc::Rfc2640 => &["LANG"],
+            Rfc::Rfc3659 => &["MDTM", "MLSD", "MLST", "SIZE"],
+        }
+    }
+
+    /// All RFCs the coverage is reported for.
+    pub fn all() -> &'static [Rfc] {
+        &[
+            Rfc::Rfc959,
+            Rfc::Rfc2228,
+            Rfc::Rfc2389,
+            Rfc::Rfc2640,
+            Rfc::Rfc3659,
+        ]
+    }
+}
+
+/// Whether a single command of an RFC is implemented.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommandCoverage {
+    /// The command, e.g. `RETR`.
+    pub command: &'static str,
+    /// The RFC that defines the command.
+    pub rfc: Rfc,
+    /// Whether the `Server` implements the command.
+    pub implemented: bool,
+}
+
+/// A comparison of the commands the [`Server`] implements with the ones defined by RFC 959,
+/// 2228, 2389, 2640 and 3659. It's meant for developers: tests can compare it with the expected coverage
+/// so that commands don't get lost silently while refactoring, and [`to_json`] turns it into a
+/// machine-readable report.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::coverage::CoverageReport;
+///
+/// let report = CoverageReport::new();
+/// assert!(report.missing().all(|c| c.command != "RETR"));
+/// println!("{}", report.to_json());
+/// ```
+///
+/// [`Server`]: ../server/struct.Server.html
+/// [`to_json`]: #method.to_json
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoverageReport {
+    /// The coverage of every command of every RFC, in the order of [`Rfc::all`].
+    ///
+    /// [`Rfc::all`]: enum.Rfc.html#method.all
+    pub commands: Vec<CommandCoverage>,
+    /// The implemented commands that aren't defined by any of the RFCs, like `EPSV` or `XPWD`.
+    pub extensions: Vec<&'static str>,
+}
+
+impl CoverageReport {
+    /// Create the report for the commands of this version of firetrap.
+    pub fn new() -> Self {
+        Self::for_commands(commands::SUPPORTED)
+    }
+
+    fn for_commands(supported: &[&'static str]) -> Self {
+        let commands = Rfc::all()
+            .iter()
+            .flat_map(|&rfc| {
+                rfc.commands().iter().map(move |&command| CommandCoverage {
+                    command,
+                    rfc,
+                    implemented: supported.contains(&command),
+                })
+            })
+            .collect();
+        let extensions = supported
+            .iter()
+            .filter(|command| {
+                !Rfc::all()
+                    .iter()
+                    .any(|rfc| rfc.commands().contains(command))
+            })
+            .cloned()
+            .collect();
+        CoverageReport {
+            commands,
+            extensions,
+        }
+    }
+
+    /// Returns the commands of the RFCs that are implemented.
+    pub fn implemented(&self) -> impl Iterator<Item = &CommandCoverage> {
+        self.commands.iter().filter(|c| c.implemented)
+    }
+
+    /// Returns the commands of the RFCs that are not implemented.
+    pub fn missing(&self) -> impl Iterator<Item = &CommandCoverage> {
+        self.commands.iter().filter(|c| !c.implemented)
+    }
+
+    /// Renders the report as JSON, with the implemented and missing commands per RFC, e.g.
+    /// `{"rfcs":[{"rfc":959,"implemented":["ABOR",...],"missing":["REIN",...]},...],
+    /// "extensions":["EPRT",...]}`.
+    pub fn to_json(&self) -> String {
+        fn list<'a>(commands: impl Iterator<Item = &'a str>) -> String {
+            let quoted: Vec<String> = commands.map(|c| format!("\"{}\"", c)).collect();
+            format!("[{}]", quoted.join(","))
+        }
+
+        let mut json = "{\"rfcs\":[".to_string();
+        for (i, &rfc) in Rfc::all().iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let of_rfc = || self.commands.iter().filter(move |c| c.rfc == rfc);
+            write!(
+                json,
+                "{{\"rfc\":{},\"implemented\":{},\"missing\":{}}}",
+                rfc.number(),
+                list(of_rfc().filter(|c| c.implemented).map(|c| c.command)),
+                list(of_rfc().filter(|c| !c.implemented).map(|c| c.command)),
+            )
+            .unwrap();
+        }
+        write!(
+            json,
+            "],\"extensions\":{}}}",
+            list(self.extensions.iter().cloned())
+        )
+        .unwrap();
+        json
+    }
+}
+
+impl Default for CoverageReport {
+    fn default() -> Self {
+        CoverageReport::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    // Update this when adding (or removing!) commands.
+    #[test]
+    fn current_coverage() {
+        assert_eq!(
+            CoverageReport::new().to_json(),
+            "{\"rfcs\":[\
+             {\"rfc\":959,\"implemented\":[\"ABOR\",\"ACCT\",\"ALLO\",\"APPE\",\"CDUP\",\"CWD\",\
+             \"DELE\",\"HELP\",\"LIST\",\"MKD\",\"MODE\",\"NLST\",\"NOOP\",\"PASS\",\"PASV\",\
+             \"PORT\",\"PWD\",\"QUIT\",\"REIN\",\"RETR\",\"RMD\",\"RNFR\",\"RNTO\",\"SITE\",\"STAT\",\
+             \"STOR\",\"STOU\",\"STRU\",\"SYST\",\"TYPE\",\"USER\"],\
+             \"missing\":[\"REST\",\"SMNT\"]},\
+             {\"rfc\":2228,\"implemented\":[],\
+             \"missing\":[\"ADAT\",\"AUTH\",\"CCC\",\"CONF\",\"ENC\",\"MIC\",\"PBSZ\",\"PROT\"]},\
+             {\"rfc\":2389,\"implemented\":[\"FEAT\",\"OPTS\"],\"missing\":[]},\
+             {\"rfc\":2640,\"implemented\":[\"LANG\"],\"missing\":[]},\
+             {\"rfc\":3659,\"implemented\":[\"MDTM\",\"MLST\",\"SIZE\"],\"missing\":[\"MLSD\"]}],\
+             \"extensions\":[\"CLNT\",\"EPRT\",\"EPSV\",\"HASH\",\"MFMT\",\"RANG\",\"XCRC\",\"XCWD\",\"XMD5\",\"XMKD\",\
+             \"XPWD\",\"XRMD\"]}"
+        );
+    }
+
+    #[test]
+    fn splits_implemented_and_missing() {
+        let report = CoverageReport::for_commands(&["FEAT", "XYZZ"]);
+        assert_eq!(
+            report.implemented().map(|c| c.command).collect::<Vec<_>>(),
+            vec!["FEAT"]
+        );
+        assert_eq!(report.missing().count(), report.commands.len() - 1);
+        assert_eq!(report.extensions, vec!["XYZZ"]);
+    }
+}
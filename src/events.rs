@@ -0,0 +1,387 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Details of the control connection of a session, captured when the connection was accepted.
+/// Together they identify the connection in e.g. firewall logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// The address and port of the client.
+    pub peer: SocketAddr,
+    /// The address and port of the server the client connected to.
+    pub local: SocketAddr,
+    /// The IP time-to-live of the socket, if it could be determined.
+    pub ttl: Option<u32>,
+    /// The address of the load balancer the connection came through, for connections that
+    /// started with a PROXY protocol header. `peer` and `local` are then the addresses from the
+    /// header. For clients of a trusted FTP proxy that sent `SITE IDNT`, it's the address of that
+    /// proxy, and `peer` is the address it gave.
+    pub proxy: Option<SocketAddr>,
+}
+
+/// Sent to the [`SessionListener`] when the `Server` closes a session because it was connected
+/// for longer than the maximum session lifetime. A [`SessionEnded`] event follows, like for every
+/// session.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+/// [`SessionEnded`]: struct.SessionEnded.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionExpired {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The control connection of the session.
+    pub connection: ConnectionInfo,
+    /// The username the client logged in with, if it did.
+    pub username: Option<String>,
+    /// How long the client was connected.
+    pub lifetime: Duration,
+}
+
+/// The options a client negotiated for its session. Together they describe how the bytes of a
+/// transfer looked on the wire, and how the stored bytes came about: e.g. with `TYPE A` the line
+/// endings of uploads were converted before they were stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NegotiatedOptions {
+    /// Whether paths are exchanged in UTF-8, as RFC 2640 describes. On by default, because the
+    /// `Server` advertises `UTF8` in its `FEAT` reply.
+    pub utf8: bool,
+    /// Set by `TYPE A`: line endings are converted between the network and the storage backend.
+    pub ascii: bool,
+    /// Set by `MODE Z`: data is compressed on the wire (with zlib, unless the client chose another
+    /// codec), but not in the storage backend.
+    pub mode_z: bool,
+    /// Whether the data connection is protected with TLS (`PROT P`). Firetrap doesn't support
+    /// TLS yet, so for now this is always `false`.
+    pub encrypted: bool,
+}
+
+impl Default for NegotiatedOptions {
+    fn default() -> Self {
+        NegotiatedOptions {
+            utf8: true,
+            ascii: false,
+            mode_z: false,
+            encrypted: false,
+        }
+    }
+}
+
+/// Sent to the [`SessionListener`] when a client connected.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionStarted {
+    /// The unique ID of the session.
+    pub session_id: String,
+    /// The control connection of the session.
+    pub connection: ConnectionInfo,
+    /// When the client connected.
+    pub started: SystemTime,
+}
+
+/// Summary of a finished control connection, handed to the [`SessionListener`] when the client
+/// disconnects. The aggregate numbers make it possible to spot suspicious sessions (e.g. a burst
+/// of downloads touching many paths) without processing every single command.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionEnded {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The control connection of the session.
+    pub connection: ConnectionInfo,
+    /// The username the client logged in with. `None` if the client never sent `USER`.
+    pub username: Option<String>,
+    /// When the client connected.
+    pub started: SystemTime,
+    /// How long the client was connected.
+    pub duration: Duration,
+    /// The number of commands the client sent.
+    pub commands: u64,
+    /// The number of commands that were answered with a transient or permanent negative reply
+    /// (i.e. a 4xx or 5xx reply code).
+    pub failed_operations: u64,
+    /// The number of file bytes the client uploaded.
+    pub bytes_received: u64,
+    /// The number of file bytes the client downloaded.
+    pub bytes_sent: u64,
+    /// The number of distinct paths the client operated on.
+    pub distinct_paths: usize,
+    /// The features the client negotiated during the session, e.g. `EPSV ALL`.
+    pub features: Vec<String>,
+    /// The options of the session when it ended.
+    pub options: NegotiatedOptions,
+}
+
+/// The direction of a file transfer, seen from the client.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum TransferDirection {
+    /// The client downloaded a file, with `RETR`.
+    Download,
+    /// The client uploaded a file, with `STOR`, `APPE` or `STOU`.
+    Upload,
+}
+
+/// Sent to the [`SessionListener`] when a file transfer finished successfully.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferEnded {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The username the client logged in with.
+    pub username: Option<String>,
+    /// The path of the file in the storage backend.
+    pub path: PathBuf,
+    /// Whether the file was downloaded or uploaded.
+    pub direction: TransferDirection,
+    /// The number of file bytes that were transferred.
+    pub bytes: u64,
+    /// The number of bytes that went over the data connection, which is less than `bytes` when
+    /// `MODE Z` compressed them.
+    pub wire_bytes: u64,
+    /// The name of the `MODE Z` codec the transfer was compressed with, if it was.
+    pub codec: Option<String>,
+    /// How long the transfer took.
+    pub duration: Duration,
+    /// The options of the session when the transfer started.
+    pub options: NegotiatedOptions,
+    /// Identifies the transfer: the session ID, followed by the number of the data connection in
+    /// the session, like `4f1c2a.3`. The storage backend got it as well, so cloud backends can
+    /// pass it on to the provider and their request logs can be joined with these events.
+    pub transfer_id: String,
+}
+
+impl TransferEnded {
+    /// How many times smaller `MODE Z` made the transfer: the file bytes per byte on the wire.
+    /// `None` if it wasn't compressed, or nothing went over the wire.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match self.codec {
+            Some(_) if self.wire_bytes > 0 => Some(self.bytes as f64 / self.wire_bytes as f64),
+            _ => None,
+        }
+    }
+}
+
+impl SessionEnded {
+    /// The average number of commands per second over the whole session.
+    pub fn commands_per_second(&self) -> f64 {
+        per_second(self.commands, self.duration)
+    }
+
+    /// The average number of file bytes, in either direction, transferred per second over the
+    /// whole session.
+    pub fn bytes_per_second(&self) -> f64 {
+        per_second(
+            self.bytes_received.saturating_add(self.bytes_sent),
+            self.duration,
+        )
+    }
+}
+
+fn per_second(count: u64, duration: Duration) -> f64 {
+    let secs = duration.as_secs_f64();
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// The size and modification time of a file before or after it changed, for [`FileMutated`]
+/// events.
+///
+/// [`FileMutated`]: struct.FileMutated.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileState {
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The last modification time of the file, if the storage backend knows it.
+    pub modified: Option<SystemTime>,
+}
+
+/// The ways a client can change an existing file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mutation {
+    /// The file was deleted with `DELE`.
+    Delete,
+    /// The file was renamed (with `RNFR` and `RNTO`) from the given path.
+    Rename {
+        /// The path the file had before.
+        from: PathBuf,
+    },
+    /// The file was written with `STOR` or appended to with `APPE`.
+    Write,
+}
+
+/// Sent to the [`SessionListener`] when a client deleted, renamed or wrote a file, with the state
+/// of the file before the change. That way an audit log can show exactly what was destroyed or
+/// replaced.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMutated {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The username the client logged in with.
+    pub username: Option<String>,
+    /// What happened to the file.
+    pub mutation: Mutation,
+    /// The path of the file. For a rename, that's the new path.
+    pub path: PathBuf,
+    /// What was at the path before the change; `None` if there was nothing. For a rename, that's
+    /// the file that was replaced, if any.
+    pub before: Option<FileState>,
+    /// What is at the path after the change; `None` if there is nothing anymore, like after a
+    /// delete. For a rename, that's the renamed file.
+    pub after: Option<FileState>,
+}
+
+/// Sent to the [`SessionListener`] when a client tried to create a file or directory after it
+/// created as many as [`User::max_new_entries`] allows. The attempt was refused.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+/// [`User::max_new_entries`]: ../auth/struct.User.html#structfield.max_new_entries
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryLimitReached {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The username the client logged in with.
+    pub username: Option<String>,
+    /// The path of the file or directory the client tried to create.
+    pub path: PathBuf,
+    /// The number of files and directories the user may create per session.
+    pub limit: u64,
+}
+
+/// Sent to the [`SessionListener`] when an upload failed because the storage ran out of space,
+/// or the user ran out of quota. That usually needs someone to step in, so it's worth an alert.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageFull {
+    /// The unique ID of the session, as given in the [`SessionStarted`] event.
+    ///
+    /// [`SessionStarted`]: struct.SessionStarted.html
+    pub session_id: String,
+    /// The username the client logged in with.
+    pub username: Option<String>,
+    /// The path of the file that was being uploaded.
+    pub path: PathBuf,
+    /// Whether what was written of the file was deleted, according to the
+    /// [`StorageFullPolicy`].
+    ///
+    /// [`StorageFullPolicy`]: ../server/struct.StorageFullPolicy.html
+    pub partial_deleted: bool,
+    /// Whether this made the server refuse all uploads until there's enough free space again.
+    pub read_only: bool,
+}
+
+/// Sent to the [`SessionListener`] when the server accepts uploads again, because the storage
+/// has enough free space since it ran out of it.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageRecovered {
+    /// The unique ID of the session whose upload found the free space.
+    pub session_id: String,
+    /// The free space there is now, in bytes.
+    pub free: u64,
+}
+
+/// Defines the interface for receiving notifications about the sessions of a [`Server`], e.g. to
+/// forward them to a SIEM. All methods have a default implementation that does nothing.
+///
+/// The methods are called from the connection's task, so implementations should not block.
+///
+/// [`Server`]: ../server/struct.Server.html
+pub trait SessionListener {
+    /// Called once a client connected, before the greeting is sent.
+    fn session_started(&self, _event: &SessionStarted) {}
+
+    /// Called once a file transfer of a session finished successfully.
+    fn transfer_ended(&self, _event: &TransferEnded) {}
+
+    /// Called once a client deleted, renamed or wrote a file.
+    fn file_mutated(&self, _event: &FileMutated) {}
+
+    /// Called when the `Server` closes a session that exceeded the maximum session lifetime.
+    fn session_expired(&self, _event: &SessionExpired) {}
+
+    /// Called when a client tried to create more files and directories than its user may.
+    fn entry_limit_reached(&self, _event: &EntryLimitReached) {}
+
+    /// Called when an upload ran out of space.
+    fn storage_full(&self, _event: &StorageFull) {}
+
+    /// Called when the server accepts uploads again after running out of space.
+    fn storage_recovered(&self, _event: &StorageRecovered) {}
+
+    /// Called once the control connection of a session was closed.
+    fn session_ended(&self, _event: &SessionEnded) {}
+}
+
+/// [`SessionListener`] implementation that ignores all events. This is the default.
+///
+/// [`SessionListener`]: trait.SessionListener.html
+pub struct NoopListener;
+
+impl SessionListener for NoopListener {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ended_after(duration: Duration) -> SessionEnded {
+        SessionEnded {
+            session_id: "0123456789abcdef0123456789abcdef".to_string(),
+            connection: ConnectionInfo {
+                peer: "10.0.0.1:50123".parse().unwrap(),
+                local: "10.0.0.2:21".parse().unwrap(),
+                ttl: Some(64),
+                proxy: None,
+            },
+            username: Some("alice".to_string()),
+            started: SystemTime::now(),
+            duration,
+            commands: 30,
+            failed_operations: 2,
+            bytes_received: 1000,
+            bytes_sent: 5000,
+            distinct_paths: 4,
+            features: vec![],
+            options: NegotiatedOptions::default(),
+        }
+    }
+
+    #[test]
+    fn rates() {
+        let event = ended_after(Duration::from_secs(10));
+        assert_eq!(event.commands_per_second(), 3.0);
+        assert_eq!(event.bytes_per_second(), 600.0);
+
+        let event = ended_after(Duration::from_secs(0));
+        assert_eq!(event.commands_per_second(), 0.0);
+        assert_eq!(event.bytes_per_second(), 0.0);
+    }
+
+    #[test]
+    fn huge_transfers() {
+        let event = SessionEnded {
+            bytes_received: u64::MAX,
+            bytes_sent: 5 << 30,
+            ..ended_after(Duration::from_secs(1))
+        };
+        assert_eq!(event.bytes_per_second(), u64::MAX as f64);
+    }
+}
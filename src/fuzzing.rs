@@ -0,0 +1,274 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`, which need to get at the internals of the
+//! protocol handling. Only compiled with the `fuzzing` feature, and not part of the public API.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::{Async, Poll, Sink};
+use tokio_codec::{Decoder, Encoder};
+use tokio_io::AsyncWrite;
+
+use crate::ascii;
+use crate::commands::Command;
+use crate::compression;
+use crate::integrity;
+use crate::proxy;
+use crate::replies::ReplyWriter;
+use crate::server::FTPCodec;
+
+/// Parses a single command line.
+pub fn parse_command(data: &[u8]) {
+    let _ = Command::parse(data);
+}
+
+/// Decodes the bytes a client sent on the control channel, both in UTF-8 and in ISO-8859-1, and
+/// checks that the commands don't depend on how the bytes were split up between reads from the
+/// socket.
+pub fn control_channel(data: &[u8]) {
+    // Derived from the data rather than taken from it, so real sessions can go in the corpus as
+    // they are.
+    let chunk = 1 + data.iter().map(|&b| usize::from(b)).sum::<usize>() % 16;
+    for &utf8 in &[true, false] {
+        let whole = decode(data, data.len().max(1), utf8);
+        assert_eq!(whole, decode(data, chunk, utf8));
+    }
+}
+
+// Feeds `data` to the codec `chunk` bytes at a time, like the reads from a slow socket. Commands
+// that fail to parse show up as `None`.
+fn decode(data: &[u8], chunk: usize, utf8: bool) -> Vec<Option<Command>> {
+    let mut codec = FTPCodec::new(Arc::new(AtomicBool::new(utf8)));
+    let mut buf = BytesMut::new();
+    let mut commands = vec![];
+    for chunk in data.chunks(chunk) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(command)) => commands.push(Some(command)),
+                Ok(None) => break,
+                Err(_) => commands.push(None),
+            }
+        }
+    }
+    commands
+}
+
+/// Parses a PROXY protocol header, and checks that a header never claims more bytes than it was
+/// given.
+pub fn proxy_header(data: &[u8]) {
+    if let proxy::Parsed::Header { len, .. } = proxy::parse(data) {
+        assert!(len <= data.len());
+    }
+}
+
+/// Runs the bytes of a data connection through the `TYPE A` and `MODE Z` decoding selected by
+/// the first byte, and checks that `MODE Z` round-trips the rest.
+pub fn data_channel(data: &[u8]) {
+    let (flags, data) = match data.split_first() {
+        Some((flags, data)) => (*flags, data),
+        None => return,
+    };
+    let (ascii, mode_z) = (flags & 1 != 0, flags & 2 != 0);
+
+    let zlib: compression::Selected = Some(Arc::new(compression::Zlib::new(u32::from(flags % 10))));
+    let selected = if mode_z { zlib.clone() } else { None };
+
+    let mut decoded = vec![];
+    let _ = ascii::from_network(
+        compression::decompress(std::io::Cursor::new(data.to_vec()), &selected),
+        ascii,
+    )
+    .read_to_end(&mut decoded);
+
+    let mut compressed = vec![];
+    compression::compress(std::io::Cursor::new(data.to_vec()), &zlib)
+        .read_to_end(&mut compressed)
+        .unwrap();
+    let mut roundtrip = vec![];
+    compression::decompress(std::io::Cursor::new(compressed), &zlib)
+        .read_to_end(&mut roundtrip)
+        .unwrap();
+    assert_eq!(roundtrip, data);
+}
+
+/// Both reads the bytes as `MODE X` blocks a client sent, and sends the rest as blocks, cut up by
+/// the first byte and with one of them corrupted on the way, checking that the payload arrives as
+/// it was sent.
+pub fn mode_x(data: &[u8]) {
+    let _ = Duplex::new(data.to_vec()).receive();
+
+    let (flags, data) = match data.split_first() {
+        Some((flags, data)) => (*flags, data),
+        None => return,
+    };
+    let chunk = 1 + usize::from(flags);
+    let mut blocks = vec![];
+    let mut sent = 0;
+    for (seq, payload) in data.chunks(chunk).chain(Some(&[][..])).enumerate() {
+        let block = integrity::encode(seq as u32, payload);
+        if seq == usize::from(flags) % 4 {
+            // Not the length, or we can't tell where the next block starts.
+            let mut corrupted = block.clone();
+            corrupted[8 + usize::from(flags) % (block.len() - 8)] ^= 0x20;
+            blocks.extend(corrupted);
+            sent += 1;
+        }
+        blocks.extend(block);
+        sent += 1;
+    }
+    let mut duplex = Duplex::new(blocks);
+    assert_eq!(duplex.receive().unwrap(), data);
+    assert_eq!(duplex.output.len(), sent);
+}
+
+// Reads from `input`, like from the other end of a data connection, and keeps what's written.
+struct Duplex {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Duplex {
+    fn new(input: Vec<u8>) -> Self {
+        Duplex {
+            input: io::Cursor::new(input),
+            output: vec![],
+        }
+    }
+
+    // Reads the payload of the blocks in the input.
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        integrity::Receiver::new(&mut *self).read_to_end(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends the lines of the data as replies on the control channel, to a client that reads them a
+/// few bytes at a time and makes the server wait in between, and checks that it gets every byte
+/// of them in order. The first byte picks the encoding, the size of the reads, and how many bytes
+/// the server buffers.
+pub fn reply_writer(data: &[u8]) {
+    let (flags, data) = match data.split_first() {
+        Some((flags, data)) => (*flags, data),
+        None => return,
+    };
+    let utf8 = Arc::new(AtomicBool::new(flags & 1 != 0));
+    let replies: Vec<String> = data
+        .split(|&b| b == b'\n')
+        .map(|line| format!("{}\r\n", String::from_utf8_lossy(line)))
+        .collect();
+
+    let mut expected = BytesMut::new();
+    for reply in &replies {
+        FTPCodec::new(Arc::clone(&utf8))
+            .encode(reply.clone(), &mut expected)
+            .unwrap();
+    }
+
+    let client = SlowClient {
+        received: Arc::new(Mutex::new(vec![])),
+        read: 1 + usize::from(flags >> 4),
+        waiting: false,
+    };
+    let received = Arc::clone(&client.received);
+    let writer = ReplyWriter::new(
+        client,
+        FTPCodec::new(utf8),
+        usize::from(flags & 0x0e) * 8,
+        Duration::from_secs(60),
+    );
+    let _ = tokio::runtime::current_thread::Runtime::new()
+        .unwrap()
+        .block_on(writer.send_all(futures::stream::iter_ok::<_, io::Error>(replies)))
+        .unwrap();
+    assert_eq!(*received.lock().unwrap(), expected.to_vec());
+}
+
+// Reads at most `read` bytes at a time, and only every other time it's asked to.
+struct SlowClient {
+    received: Arc<Mutex<Vec<u8>>>,
+    read: usize,
+    waiting: bool,
+}
+
+impl Write for SlowClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.waiting = !self.waiting;
+        if self.waiting {
+            // Ready again right away, so the runtime doesn't wait for a socket that isn't there.
+            futures::task::current().notify();
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.read);
+        self.received.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for SlowClient {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the seed corpus of the given target, which has to pass or fuzzing starts from a crash.
+    fn replay(target: &str, run: fn(&[u8])) {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/seeds");
+        let mut seeds = 0;
+        for entry in std::fs::read_dir(dir.join(target)).unwrap() {
+            run(&std::fs::read(entry.unwrap().path()).unwrap());
+            seeds += 1;
+        }
+        assert!(seeds > 0, "no seeds for {}", target);
+    }
+
+    #[test]
+    fn seeds() {
+        replay("control_channel", control_channel);
+        replay("proxy_header", proxy_header);
+        replay("mode_x", mode_x);
+        replay("reply_writer", reply_writer);
+    }
+
+    #[test]
+    fn control_channel_splits() {
+        let commands = decode(b"USER alice\r\nPASS secret\r\nBOGUS\r\nNOOP", 3, true);
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[2], None);
+
+        let commands = decode(b"DELE caf\xe9.txt\r\n", 4, false);
+        assert_eq!(
+            commands,
+            vec![Some(Command::Dele {
+                path: "café.txt".to_string()
+            })]
+        );
+    }
+}
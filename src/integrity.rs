@@ -0,0 +1,492 @@
+use std::io::{self, Read, Write};
+
+use futures::{Async, Poll};
+use log::debug;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::compression::Reader;
+
+// The framing of `MODE X`, for networks that corrupt data in a way TCP doesn't notice. Every
+// block is the sequence number of the block (counting from 0) and the length of its payload, as
+// 32-bit big endian numbers, followed by the payload and the CRC32C of all of that (big endian as
+// well). The receiver answers every block with a single byte on the same connection: `ACK` to
+// accept it, or `NAK` to get it again. Anything but `ACK` counts as `NAK`, and a block that was
+// already accepted (i.e. whose `ACK` got corrupted) is acknowledged again and dropped. An empty
+// block ends the transfer.
+
+// Accepts a block.
+const ACK: u8 = 0x06;
+// Asks for a block again.
+const NAK: u8 = 0x15;
+// The payload of the blocks we send.
+const BLOCK_SIZE: usize = 64 * 1024;
+// The largest payload we accept. A larger length means the header was corrupted, which we can't
+// recover from, since we don't know where the next block starts anymore.
+const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+// How often a block is sent again before we give up on the transfer.
+const MAX_RESENDS: u32 = 8;
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Continues the CRC32C (Castagnoli) `crc` of earlier data with `data`. Start with 0.
+fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Frames the given payload as the block with the given sequence number.
+pub(crate) fn encode(seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(payload.len() + 12);
+    block.extend_from_slice(&seq.to_be_bytes());
+    block.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    block.extend_from_slice(payload);
+    let crc = crc32c(0, &block);
+    block.extend_from_slice(&crc.to_be_bytes());
+    block
+}
+
+fn too_many_resends(seq: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Block {} was corrupted {} times, giving up",
+            seq,
+            MAX_RESENDS + 1
+        ),
+    )
+}
+
+// Turns a `WouldBlock` error into `NotReady`.
+fn poll(res: io::Result<()>) -> Poll<(), io::Error> {
+    match res {
+        Ok(()) => Ok(Async::Ready(())),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes what it's given as `MODE X` blocks to the data connection, and sends every block again
+/// until the client acknowledges it. The transfer only ends with the empty block that `shutdown`
+/// sends.
+pub(crate) struct Sender<S> {
+    inner: S,
+    // The payload of the next block.
+    pending: Vec<u8>,
+    // The block that's being sent or that waits for its answer, empty if there's none.
+    block: Vec<u8>,
+    written: usize,
+    awaiting_answer: bool,
+    seq: u32,
+    naks: u32,
+    // Whether the empty block was sealed.
+    finishing: bool,
+}
+
+impl<S: Read + Write> Sender<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Sender {
+            inner,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            block: vec![],
+            written: 0,
+            awaiting_answer: false,
+            seq: 0,
+            naks: 0,
+            finishing: false,
+        }
+    }
+
+    fn seal(&mut self) {
+        self.block = encode(self.seq, &self.pending);
+        self.pending.clear();
+        self.written = 0;
+    }
+
+    // Sends the current block until it's acknowledged.
+    fn drive(&mut self) -> io::Result<()> {
+        while !self.block.is_empty() {
+            if !self.awaiting_answer {
+                while self.written < self.block.len() {
+                    let n = self.inner.write(&self.block[self.written..])?;
+                    if n == 0 {
+                        return Err(io::ErrorKind::WriteZero.into());
+                    }
+                    self.written += n;
+                }
+                self.inner.flush()?;
+                self.awaiting_answer = true;
+            }
+            let mut answer = [0; 1];
+            if self.inner.read(&mut answer)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The client closed the data connection before acknowledging every block",
+                ));
+            }
+            self.awaiting_answer = false;
+            self.written = 0;
+            if answer[0] == ACK {
+                self.block.clear();
+                self.seq = self.seq.wrapping_add(1);
+                self.naks = 0;
+            } else {
+                self.naks += 1;
+                if self.naks > MAX_RESENDS {
+                    return Err(too_many_resends(self.seq));
+                }
+                debug!("Sending block {} again", self.seq);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Write for Sender<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drive()?;
+        let n = buf.len().min(BLOCK_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == BLOCK_SIZE {
+            self.seal();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drive()?;
+        if !self.pending.is_empty() {
+            self.seal();
+            self.drive()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for Sender<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.finishing {
+            if let Async::NotReady = poll(self.flush())? {
+                return Ok(Async::NotReady);
+            }
+            self.seal();
+            self.finishing = true;
+        }
+        if let Async::NotReady = poll(self.drive())? {
+            return Ok(Async::NotReady);
+        }
+        self.inner.shutdown()
+    }
+}
+
+/// Reads the payload of the `MODE X` blocks the client sends on the data connection, and answers
+/// every block, asking for the corrupted ones again. Reading ends with the empty block.
+pub(crate) struct Receiver<S> {
+    inner: S,
+    // The sequence number and length of the block that's being read.
+    header: [u8; 8],
+    // Its payload and CRC.
+    body: Vec<u8>,
+    filled: usize,
+    // The answer to the last block, if it wasn't sent yet.
+    answer: Option<u8>,
+    // The payload of the last accepted block that wasn't read yet.
+    data: Vec<u8>,
+    pos: usize,
+    seq: u32,
+    naks: u32,
+    done: bool,
+}
+
+impl<S: Read + Write> Receiver<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Receiver {
+            inner,
+            header: [0; 8],
+            body: vec![],
+            filled: 0,
+            answer: None,
+            data: vec![],
+            pos: 0,
+            seq: 0,
+            naks: 0,
+            done: false,
+        }
+    }
+
+    // Checks the block that was read completely, and decides on the answer.
+    fn check(&mut self) -> io::Result<()> {
+        let seq = u32::from_be_bytes([
+            self.header[0],
+            self.header[1],
+            self.header[2],
+            self.header[3],
+        ]);
+        let (payload, crc) = self.body.split_at(self.body.len() - 4);
+        let intact = crc32c(crc32c(0, &self.header), payload)
+            == u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+        if intact && seq == self.seq {
+            self.seq = self.seq.wrapping_add(1);
+            self.naks = 0;
+            self.done = payload.is_empty();
+            self.data = payload.to_vec();
+            self.pos = 0;
+            self.answer = Some(ACK);
+        } else if intact && seq == self.seq.wrapping_sub(1) {
+            // Our `ACK` didn't make it.
+            self.answer = Some(ACK);
+        } else {
+            self.naks += 1;
+            if self.naks > MAX_RESENDS {
+                return Err(too_many_resends(self.seq));
+            }
+            debug!("Block {} is corrupted, asking for it again", self.seq);
+            self.answer = Some(NAK);
+        }
+        self.filled = 0;
+        self.body.clear();
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for Receiver<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(answer) = self.answer {
+                if self.inner.write(&[answer])? == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.answer = None;
+                self.inner.flush()?;
+            }
+            if self.pos < self.data.len() {
+                let n = buf.len().min(self.data.len() - self.pos);
+                buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            let n = if self.filled < self.header.len() {
+                let n = self.inner.read(&mut self.header[self.filled..])?;
+                if self.filled + n == self.header.len() {
+                    let len = u32::from_be_bytes([
+                        self.header[4],
+                        self.header[5],
+                        self.header[6],
+                        self.header[7],
+                    ]) as usize;
+                    if len > MAX_BLOCK_SIZE {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Block {} is too large, its header must be corrupted",
+                                self.seq
+                            ),
+                        ));
+                    }
+                    self.body.resize(len + 4, 0);
+                }
+                n
+            } else {
+                self.inner
+                    .read(&mut self.body[self.filled - self.header.len()..])?
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The client closed the data connection in the middle of the transfer",
+                ));
+            }
+            self.filled += n;
+            if self.filled == self.header.len() + self.body.len() && self.filled > self.header.len()
+            {
+                self.check()?;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for Receiver<S> {}
+
+/// Reads the data the client uploads on `socket`, from `MODE X` blocks when `enabled`.
+pub(crate) fn receive<S>(socket: S, enabled: bool) -> Reader
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    if enabled {
+        Box::new(Receiver::new(socket))
+    } else {
+        Box::new(socket)
+    }
+}
+
+/// A boxed writer for the data connection, so downloads look the same whether they're framed or
+/// not.
+pub(crate) type Writer = Box<dyn AsyncWrite + Send>;
+
+/// Sends downloads on `socket`, as `MODE X` blocks when `enabled`.
+pub(crate) fn send<S>(socket: S, enabled: bool) -> Writer
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    if enabled {
+        Box::new(Sender::new(socket))
+    } else {
+        Box::new(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    // Reads the answers (or blocks) of the other end from `input`, and keeps what's written.
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Duplex {
+        fn new(input: Vec<u8>) -> Self {
+            Duplex {
+                input: Cursor::new(input),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Duplex {}
+
+    impl AsyncWrite for Duplex {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn corrupt(mut block: Vec<u8>) -> Vec<u8> {
+        block[10] ^= 0x20;
+        block
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(0, b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), 0xE306_9283);
+    }
+
+    #[test]
+    fn sends_blocks_again_until_acknowledged() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let mut sender = Sender::new(Duplex::new(vec![NAK, ACK, b'?', ACK, ACK]));
+        sender.write_all(&data).unwrap();
+        assert_eq!(sender.shutdown().unwrap(), Async::Ready(()));
+
+        let first = encode(0, &data[..BLOCK_SIZE]);
+        let second = encode(1, &data[BLOCK_SIZE..]);
+        let expected = [&first[..], &first, &second, &second, &encode(2, &[])].concat();
+        assert_eq!(sender.inner.output, expected);
+    }
+
+    #[test]
+    fn sender_gives_up() {
+        let mut sender = Sender::new(Duplex::new(vec![NAK; MAX_RESENDS as usize + 1]));
+        sender.write_all(b"data").unwrap();
+        let e = sender.flush().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn asks_for_corrupted_blocks_again() {
+        let blocks = [
+            corrupt(encode(0, b"hello ")),
+            encode(0, b"hello "),
+            encode(1, b"world"),
+            // The sender didn't get our `ACK`.
+            encode(1, b"world"),
+            encode(2, b""),
+        ]
+        .concat();
+        let mut receiver = Receiver::new(Duplex::new(blocks));
+        let mut data = String::new();
+        receiver.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello world");
+        assert_eq!(receiver.inner.output, vec![NAK, ACK, ACK, ACK, ACK]);
+    }
+
+    #[test]
+    fn receiver_gives_up() {
+        let blocks = vec![corrupt(encode(0, b"data")); MAX_RESENDS as usize + 1].concat();
+        let e = Receiver::new(Duplex::new(blocks))
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut block = encode(0, b"data");
+        block[4] = 0xff;
+        let e = Receiver::new(Duplex::new(block))
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let e = Receiver::new(Duplex::new(encode(0, b"data")))
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+        let mut sender = Sender::new(Duplex::new(vec![ACK; 4]));
+        sender.write_all(&data).unwrap();
+        sender.shutdown().unwrap();
+
+        let mut received = vec![];
+        Receiver::new(Duplex::new(sender.inner.output))
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received, data);
+    }
+}
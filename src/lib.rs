@@ -1,4 +1,6 @@
 #![deny(missing_docs)]
+// The `Fail` derive from `failure_derive` generates its impls inside an anonymous const.
+#![allow(non_local_definitions)]
 //! FTP server library for Rust
 //!
 //! Firetrap helps you create modern, safe and extensible FTP servers in Rust.
@@ -27,10 +29,103 @@ pub use crate::server::Server;
 
 pub(crate) mod commands;
 
+/// Contains the [`Codec`] trait of the compression formats of `MODE Z` transfers, and the
+/// built-in [`Zlib`] codec.
+///
+/// [`Codec`]: ./compression/trait.Codec.html
+/// [`Zlib`]: ./compression/struct.Zlib.html
+pub mod compression;
+
+pub(crate) mod integrity;
+
+pub(crate) mod ascii;
+
+pub(crate) mod compat;
+
+pub(crate) mod locks;
+
+pub(crate) mod readahead;
+
+pub(crate) mod replies;
+
+pub(crate) mod proxy;
+
+pub(crate) mod telnet;
+
+pub(crate) mod transfer;
+
+pub(crate) mod support;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
 /// Contains the `Authenticator` trait that is used by the `Server` to authenticate users, as well
 /// as its various implementations.
 pub mod auth;
 
+/// Contains the [`Allowlist`] of the clients that may connect to the `Server`, by network or by
+/// hostname.
+///
+/// [`Allowlist`]: ./allowlist/struct.Allowlist.html
+pub mod allowlist;
+
 /// Contains the `StorageBackend` trait that is by the `Server` and its various
 /// implementations.
 pub mod storage;
+
+/// Contains the [`SiteCommands`] registry of `SITE` subcommands that is used by the `Server`.
+///
+/// [`SiteCommands`]: ./site/struct.SiteCommands.html
+pub mod site;
+
+/// Contains the [`SessionListener`] trait that the `Server` notifies about finished sessions, and
+/// the events it receives.
+///
+/// [`SessionListener`]: ./events/trait.SessionListener.html
+pub mod events;
+
+/// Contains the [`RandomSource`] trait that the `Server` uses for unpredictable names and IDs, as
+/// well as its implementations.
+///
+/// [`RandomSource`]: ./random/trait.RandomSource.html
+pub mod random;
+
+/// Contains the [`BandwidthLimiter`] that the `Server` can use to limit the bandwidth of data
+/// transfers, and the [`TransferPriority`] classes it shares the bandwidth by.
+///
+/// [`BandwidthLimiter`]: ./bandwidth/struct.BandwidthLimiter.html
+/// [`TransferPriority`]: ./bandwidth/enum.TransferPriority.html
+pub mod bandwidth;
+
+/// Contains the [`CoverageReport`] that compares the commands the `Server` implements with the
+/// ones defined by the FTP RFCs.
+///
+/// [`CoverageReport`]: ./coverage/struct.CoverageReport.html
+pub mod coverage;
+
+/// Contains the [`Catalog`] of translated reply texts that the `Server` uses for clients that
+/// choose a language with `LANG`.
+///
+/// [`Catalog`]: ./catalog/struct.Catalog.html
+pub mod catalog;
+
+/// Contains the [`Quirks`] profiles of clients that misread some replies, which the `Server`
+/// works around once a client tells its name with `CLNT`.
+///
+/// [`Quirks`]: ./quirks/struct.Quirks.html
+pub mod quirks;
+
+/// Contains the [`Metrics`] registry in which the `Server` records how its transfers perform, and
+/// the [`Histogram`]s it keeps them in.
+///
+/// [`Metrics`]: ./metrics/struct.Metrics.html
+/// [`Histogram`]: ./metrics/struct.Histogram.html
+pub mod metrics;
+
+/// Contains the [`Staging`] directories in which the sessions of a `Server` keep temporary files,
+/// like the uploads it only stores once they're complete, and the [`StagingArea`] they're in.
+///
+/// [`Staging`]: ./staging/struct.Staging.html
+/// [`StagingArea`]: ./staging/struct.StagingArea.html
+pub mod staging;
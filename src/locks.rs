@@ -0,0 +1,126 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{future, Future};
+
+/// Write locks on paths, shared by all sessions of a `Server`, so that two clients uploading to
+/// the same path don't interleave their writes.
+#[derive(Default)]
+pub(crate) struct PathLocks {
+    // The locked paths, with the sessions waiting for them in the order they asked.
+    locked: Mutex<HashMap<PathBuf, VecDeque<oneshot::Sender<PathGuard>>>>,
+}
+
+impl PathLocks {
+    /// Locks the given path, unless it's locked already.
+    pub(crate) fn try_lock(locks: &Arc<Self>, path: PathBuf) -> Option<PathGuard> {
+        let mut locked = locks.locked.lock().unwrap();
+        if locked.contains_key(&path) {
+            return None;
+        }
+        locked.insert(path.clone(), VecDeque::new());
+        Some(PathGuard::new(locks, path))
+    }
+
+    /// Locks the given path, waiting for whoever holds the lock (and everybody who asked before
+    /// us) to release it first.
+    pub(crate) fn lock(
+        locks: &Arc<Self>,
+        path: PathBuf,
+    ) -> Box<dyn Future<Item = PathGuard, Error = ()> + Send> {
+        let mut locked = locks.locked.lock().unwrap();
+        match locked.get_mut(&path) {
+            Some(waiting) => {
+                let (tx, rx) = oneshot::channel();
+                waiting.push_back(tx);
+                Box::new(rx.map_err(|_| ()))
+            }
+            None => {
+                locked.insert(path.clone(), VecDeque::new());
+                Box::new(future::ok(PathGuard::new(locks, path)))
+            }
+        }
+    }
+}
+
+/// Holds the lock on a path until it is dropped. It then hands the lock over to the next waiting
+/// session, if any.
+pub(crate) struct PathGuard {
+    locks: Arc<PathLocks>,
+    // `None` once the lock has been handed over.
+    path: Option<PathBuf>,
+}
+
+impl PathGuard {
+    fn new(locks: &Arc<PathLocks>, path: PathBuf) -> Self {
+        PathGuard {
+            locks: Arc::clone(locks),
+            path: Some(path),
+        }
+    }
+}
+
+impl Drop for PathGuard {
+    fn drop(&mut self) {
+        let path = match self.path.take() {
+            Some(path) => path,
+            None => return,
+        };
+        let mut locked = self.locks.locked.lock().unwrap();
+        if let Some(waiting) = locked.get_mut(&path) {
+            while let Some(next) = waiting.pop_front() {
+                let guard = PathGuard::new(&self.locks, path.clone());
+                match next.send(guard) {
+                    Ok(()) => return,
+                    // That session stopped waiting. Make sure its guard doesn't try to hand the
+                    // lock over as well.
+                    Err(mut guard) => guard.path = None,
+                }
+            }
+        }
+        locked.remove(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_lock() {
+        let locks = Arc::new(PathLocks::default());
+        let guard = PathLocks::try_lock(&locks, "/a".into()).unwrap();
+        assert!(PathLocks::try_lock(&locks, "/a".into()).is_none());
+        assert!(PathLocks::try_lock(&locks, "/b".into()).is_some());
+        drop(guard);
+        assert!(PathLocks::try_lock(&locks, "/a".into()).is_some());
+    }
+
+    #[test]
+    fn queue() {
+        let locks = Arc::new(PathLocks::default());
+        let first = PathLocks::lock(&locks, "/a".into()).wait().unwrap();
+        let mut second = PathLocks::lock(&locks, "/a".into());
+        let gave_up = PathLocks::lock(&locks, "/a".into());
+        let mut third = PathLocks::lock(&locks, "/a".into());
+
+        let not_ready = |f: &mut Box<dyn Future<Item = PathGuard, Error = ()> + Send>| {
+            future::lazy(|| Ok::<_, ()>(f.poll().unwrap().is_not_ready()))
+                .wait()
+                .unwrap()
+        };
+        assert!(not_ready(&mut second));
+        drop(gave_up);
+
+        drop(first);
+        let second = second.wait().unwrap();
+        assert!(not_ready(&mut third));
+        drop(second);
+        let third = third.wait().unwrap();
+        assert!(PathLocks::try_lock(&locks, "/a".into()).is_none());
+        drop(third);
+        assert!(PathLocks::try_lock(&locks, "/a".into()).is_some());
+    }
+}
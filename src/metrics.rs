@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::bandwidth::TransferPriority;
+use crate::events::TransferDirection;
+
+// Bucket `i` holds the values from `2^i` up to (but not including) `2^(i + 1)`, so 64 buckets
+// cover every `u64`.
+const BUCKETS: usize = 64;
+
+/// A distribution of values, like bytes per second, in buckets that double in size. Percentiles
+/// are reported as the upper bound of their bucket (and never above the biggest value seen), so
+/// they are accurate to within a factor of two, at a fixed size however many values there are.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::metrics::Histogram;
+///
+/// let mut histogram = Histogram::new();
+/// for value in 1..=100 {
+///     histogram.record(value);
+/// }
+/// assert_eq!(histogram.count(), 100);
+/// assert_eq!(histogram.percentile(50.0), Some(64));
+/// assert_eq!(histogram.percentile(99.0), Some(100));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u128,
+    max: u64,
+}
+
+impl Histogram {
+    /// Create an empty histogram.
+    pub fn new() -> Self {
+        Histogram {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    /// Add the given value to the distribution.
+    pub fn record(&mut self, value: u64) {
+        let bucket = (63 - value.max(1).leading_zeros()) as usize;
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += u128::from(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Returns the number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the average of the values recorded, if any.
+    pub fn mean(&self) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some((self.sum / u128::from(self.count)) as u64)
+    }
+
+    /// Returns the value that the given percentage (e.g. `99.0`) of the recorded values doesn't
+    /// exceed, if any values were recorded.
+    pub fn percentile(&self, percentage: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentage / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = 1u64.checked_shl(bucket as u32 + 1).unwrap_or(u64::MAX);
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+/// What a throughput distribution of the [`Metrics`] is about.
+///
+/// [`Metrics`]: struct.Metrics.html
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ThroughputKey {
+    /// Whether the transfers were downloads or uploads.
+    pub direction: TransferDirection,
+    /// The type of the storage backend, e.g. `Filesystem` or `CaseInsensitive<Filesystem>`.
+    pub backend: String,
+    /// The class of the users that made the transfers: their bandwidth priority.
+    pub class: TransferPriority,
+}
+
+impl fmt::Display for ThroughputKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            TransferDirection::Download => "download",
+            TransferDirection::Upload => "upload",
+        };
+        write!(f, "{}/{}/{:?}", direction, self.backend, self.class)
+    }
+}
+
+/// Why a `PORT` or `EPRT` command was refused, under the `ActiveModePolicy` of the `Server`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RejectedPort {
+    /// The client asked for a connection to a port below 1024.
+    Privileged,
+    /// The client asked for a connection to the server itself.
+    ServerAddress,
+}
+
+impl fmt::Display for RejectedPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectedPort::Privileged => write!(f, "privileged"),
+            RejectedPort::ServerAddress => write!(f, "server-address"),
+        }
+    }
+}
+
+/// How often a `Server` replied with a `4xx` or `5xx` reply code, and when it last did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorReplies {
+    /// The reply code, like `550`.
+    pub code: u16,
+    /// The number of replies with the code.
+    pub count: u64,
+    /// When the last one went out.
+    pub last: SystemTime,
+}
+
+/// The registry of metrics that a `Server` keeps about its transfers, so capacity planning can
+/// be based on how transfers really perform. A summary of it goes into the periodic stats log
+/// line (see `Server::stats_log_interval`), and embedders that pass their own registry to
+/// `Server::metrics` can read it whenever they like.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::metrics::Metrics;
+/// use std::sync::Arc;
+///
+/// let metrics = Arc::new(Metrics::new());
+/// let server = Server::with_root("/tmp").metrics(Arc::clone(&metrics));
+/// // ...and later, e.g. from a monitoring endpoint:
+/// for (key, histogram) in metrics.throughput() {
+///     println!("{}: {:?} bytes/s", key, histogram.percentile(50.0));
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Metrics {
+    throughput: Mutex<BTreeMap<ThroughputKey, Histogram>>,
+    compression: Mutex<BTreeMap<String, Histogram>>,
+    rejected_ports: Mutex<BTreeMap<RejectedPort, u64>>,
+    error_replies: Mutex<BTreeMap<u16, (u64, SystemTime)>>,
+}
+
+impl Metrics {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Record the throughput, in bytes per second, of a finished transfer. Empty transfers say
+    /// nothing about throughput, so they are left out.
+    pub fn record_transfer(&self, key: ThroughputKey, bytes: u64, duration: Duration) {
+        if bytes == 0 {
+            return;
+        }
+        let nanos = duration.as_nanos().max(1);
+        let per_second = (u128::from(bytes) * 1_000_000_000 / nanos).min(u128::from(u64::MAX));
+        if let Ok(mut throughput) = self.throughput.lock() {
+            throughput.entry(key).or_default().record(per_second as u64);
+        }
+    }
+
+    /// Record how well a `MODE Z` transfer was compressed with the given codec: `bytes` file bytes
+    /// in `wire_bytes` on the data connection. The ratio is kept in percent, so `250` means the
+    /// transfer took 2.5 times fewer bytes. Empty transfers are left out.
+    pub fn record_compression(&self, codec: &str, bytes: u64, wire_bytes: u64) {
+        if bytes == 0 || wire_bytes == 0 {
+            return;
+        }
+        let percent = (u128::from(bytes) * 100 / u128::from(wire_bytes)).min(u128::from(u64::MAX));
+        if let Ok(mut compression) = self.compression.lock() {
+            compression
+                .entry(codec.to_string())
+                .or_default()
+                .record(percent as u64);
+        }
+    }
+
+    /// Returns the distributions of the compression ratios of `MODE Z` transfers, in percent, by
+    /// codec name.
+    pub fn compression(&self) -> Vec<(String, Histogram)> {
+        match self.compression.lock() {
+            Ok(compression) => compression
+                .iter()
+                .map(|(codec, histogram)| (codec.clone(), histogram.clone()))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Record a refused `PORT` or `EPRT` command, which may have been an attempt at a bounce
+    /// attack.
+    pub fn record_rejected_port(&self, reason: RejectedPort) {
+        if let Ok(mut rejected) = self.rejected_ports.lock() {
+            *rejected.entry(reason).or_default() += 1;
+        }
+    }
+
+    /// Returns the number of refused `PORT` and `EPRT` commands, by the reason they were refused.
+    pub fn rejected_ports(&self) -> Vec<(RejectedPort, u64)> {
+        match self.rejected_ports.lock() {
+            Ok(rejected) => rejected
+                .iter()
+                .map(|(reason, count)| (*reason, *count))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Record a reply with a `4xx` or `5xx` reply code.
+    pub fn record_error_reply(&self, code: u16) {
+        if let Ok(mut replies) = self.error_replies.lock() {
+            let (count, last) = replies.entry(code).or_insert((0, SystemTime::now()));
+            *count += 1;
+            *last = SystemTime::now();
+        }
+    }
+
+    /// Returns how often every `4xx` and `5xx` reply code was replied, ordered by the code.
+    pub fn error_replies(&self) -> Vec<ErrorReplies> {
+        match self.error_replies.lock() {
+            Ok(replies) => replies
+                .iter()
+                .map(|(code, (count, last))| ErrorReplies {
+                    code: *code,
+                    count: *count,
+                    last: *last,
+                })
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Returns the throughput distributions, in bytes per second, ordered by their key.
+    pub fn throughput(&self) -> Vec<(ThroughputKey, Histogram)> {
+        match self.throughput.lock() {
+            Ok(throughput) => throughput
+                .iter()
+                .map(|(key, histogram)| (key.clone(), histogram.clone()))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Returns a one-line summary of the throughput distributions, with the number of transfers
+    /// and the median, 90th and 99th percentile of each, followed by the median compression
+    /// ratios of `MODE Z` transfers and the numbers of refused `PORT` commands, if there were any.
+    pub fn summary(&self) -> String {
+        let mut summary = self.transfer_summary();
+        let rejected: Vec<String> = self
+            .rejected_ports()
+            .iter()
+            .map(|(reason, count)| format!("{}={}", reason, count))
+            .collect();
+        if !rejected.is_empty() {
+            summary.push_str(&format!("; rejected ports {}", rejected.join(", ")));
+        }
+        summary
+    }
+
+    fn transfer_summary(&self) -> String {
+        let throughput = self.throughput();
+        if throughput.is_empty() {
+            return "no transfers".to_string();
+        }
+        let mut summary = throughput
+            .iter()
+            .map(|(key, histogram)| {
+                let percentile = |p| {
+                    histogram
+                        .percentile(p)
+                        .map(|rate| format!("{}/s", crate::storage::human_readable_size(rate)))
+                        .unwrap_or_default()
+                };
+                format!(
+                    "{} n={} p50={} p90={} p99={}",
+                    key,
+                    histogram.count(),
+                    percentile(50.0),
+                    percentile(90.0),
+                    percentile(99.0)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let compression: Vec<String> = self
+            .compression()
+            .iter()
+            .map(|(codec, histogram)| {
+                let ratio = histogram.percentile(50.0).unwrap_or_default();
+                format!(
+                    "{} n={} p50={}.{:02}x",
+                    codec,
+                    histogram.count(),
+                    ratio / 100,
+                    ratio % 100
+                )
+            })
+            .collect();
+        if !compression.is_empty() {
+            summary.push_str(&format!("; compression {}", compression.join(", ")));
+        }
+        summary
+    }
+}
+
+/// Shortens a type name from `std::any::type_name` to the names without their module paths, e.g.
+/// `CaseInsensitive<Filesystem>` for `firetrap::storage::CaseInsensitive<firetrap::storage::Filesystem>`.
+pub(crate) fn short_type_name(name: &str) -> String {
+    let mut short = String::new();
+    let mut segment = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            segment.push(c);
+        } else {
+            short.push_str(segment.rsplit("::").next().unwrap_or(""));
+            segment.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(segment.rsplit("::").next().unwrap_or(""));
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+        for _ in 0..90 {
+            histogram.record(1000);
+        }
+        for _ in 0..10 {
+            histogram.record(1_000_000);
+        }
+        assert_eq!(histogram.mean(), Some(100_900));
+        assert_eq!(histogram.percentile(50.0), Some(1024));
+        assert_eq!(histogram.percentile(90.0), Some(1024));
+        assert_eq!(histogram.percentile(99.0), Some(1_000_000));
+        histogram.record(0);
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(100.0), Some(u64::MAX));
+    }
+
+    #[test]
+    fn records_transfers() {
+        let metrics = Metrics::new();
+        let key = |direction| ThroughputKey {
+            direction,
+            backend: "Filesystem".to_string(),
+            class: TransferPriority::Normal,
+        };
+        metrics.record_transfer(key(TransferDirection::Upload), 2048, Duration::from_secs(2));
+        metrics.record_transfer(key(TransferDirection::Upload), 0, Duration::from_secs(1));
+        metrics.record_transfer(
+            key(TransferDirection::Download),
+            1 << 20,
+            Duration::from_millis(500),
+        );
+        assert_eq!(
+            metrics.summary(),
+            "download/Filesystem/Normal n=1 p50=2.0M/s p90=2.0M/s p99=2.0M/s, \
+             upload/Filesystem/Normal n=1 p50=1.0K/s p90=1.0K/s p99=1.0K/s"
+        );
+    }
+
+    #[test]
+    fn records_compression() {
+        let metrics = Metrics::new();
+        metrics.record_transfer(
+            ThroughputKey {
+                direction: TransferDirection::Download,
+                backend: "Filesystem".to_string(),
+                class: TransferPriority::Normal,
+            },
+            1 << 20,
+            Duration::from_secs(1),
+        );
+        metrics.record_compression("zlib", 1000, 400);
+        metrics.record_compression("zlib", 0, 0);
+        metrics.record_compression("zstd", 1000, 1000);
+        assert_eq!(
+            metrics.summary(),
+            "download/Filesystem/Normal n=1 p50=1.0M/s p90=1.0M/s p99=1.0M/s; \
+             compression zlib n=1 p50=2.50x, zstd n=1 p50=1.00x"
+        );
+    }
+
+    #[test]
+    fn records_error_replies() {
+        let metrics = Metrics::new();
+        let before = SystemTime::now();
+        metrics.record_error_reply(550);
+        metrics.record_error_reply(451);
+        metrics.record_error_reply(550);
+        let replies = metrics.error_replies();
+        let counts: Vec<_> = replies.iter().map(|r| (r.code, r.count)).collect();
+        assert_eq!(counts, vec![(451, 1), (550, 2)]);
+        assert!(replies.iter().all(|r| r.last >= before));
+    }
+
+    #[test]
+    fn records_rejected_ports() {
+        let metrics = Metrics::new();
+        metrics.record_rejected_port(RejectedPort::ServerAddress);
+        metrics.record_rejected_port(RejectedPort::Privileged);
+        metrics.record_rejected_port(RejectedPort::Privileged);
+        assert_eq!(
+            metrics.summary(),
+            "no transfers; rejected ports privileged=2, server-address=1"
+        );
+    }
+
+    #[test]
+    fn shortens_type_names() {
+        assert_eq!(
+            short_type_name("firetrap::storage::CaseInsensitive<firetrap::storage::Filesystem>"),
+            "CaseInsensitive<Filesystem>"
+        );
+        assert_eq!(short_type_name("Filesystem"), "Filesystem");
+    }
+}
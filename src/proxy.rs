@@ -0,0 +1,292 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tokio_io::AsyncRead;
+
+use crate::server::ProxyProtocol;
+
+// The signature every PROXY protocol v2 header starts with.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Version 1 headers are a single line of at most this many bytes, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+// The addresses a PROXY protocol header reports: those of the client and of the server it
+// connected to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ProxiedAddrs {
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    // Need more bytes to tell
+    Incomplete,
+    // A header of the given length. The addresses are missing for `LOCAL` (e.g. health check)
+    // connections and unknown protocols.
+    Header {
+        addrs: Option<ProxiedAddrs>,
+        len: usize,
+    },
+    Invalid,
+}
+
+// Parses the PROXY protocol header at the start of `buf`.
+pub(crate) fn parse(buf: &[u8]) -> Parsed {
+    let prefix = |signature: &[u8]| {
+        let n = buf.len().min(signature.len());
+        buf[..n] == signature[..n]
+    };
+    if prefix(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if prefix(b"PROXY ") {
+        parse_v1(buf)
+    } else {
+        Parsed::Invalid
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Parsed {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Parsed::Invalid,
+        None if buf.len() < V1_MAX_LEN => return Parsed::Incomplete,
+        None => return Parsed::Invalid,
+    };
+    let line = match std::str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return Parsed::Invalid,
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addrs = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4", source, destination, source_port, destination_port]
+        | ["PROXY", "TCP6", source, destination, source_port, destination_port] => {
+            let addr = |ip: &str, port: &str| -> Option<SocketAddr> {
+                let ip: IpAddr = ip.parse().ok()?;
+                let port: u16 = port.parse().ok()?;
+                let ipv4 = fields[1] == "TCP4";
+                if ip.is_ipv4() != ipv4 {
+                    return None;
+                }
+                Some(SocketAddr::new(ip, port))
+            };
+            match (
+                addr(source, source_port),
+                addr(destination, destination_port),
+            ) {
+                (Some(source), Some(destination)) => Some(ProxiedAddrs {
+                    source,
+                    destination,
+                }),
+                _ => return Parsed::Invalid,
+            }
+        }
+        _ => return Parsed::Invalid,
+    };
+    Parsed::Header {
+        addrs,
+        len: end + 2,
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Parsed {
+    if buf.len() < 16 {
+        return Parsed::Incomplete;
+    }
+    let (version, command) = (buf[12] >> 4, buf[12] & 0x0f);
+    if version != 2 || command > 1 {
+        return Parsed::Invalid;
+    }
+    let len = 16 + usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    if buf.len() < len {
+        return Parsed::Incomplete;
+    }
+    let body = &buf[16..len];
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    let addrs = match buf[13] {
+        // `LOCAL` connections are made by the proxy itself, and their addresses are ignored.
+        _ if command == 0 => None,
+        // TCP over IPv4
+        0x11 if body.len() >= 12 => {
+            let ip = |bytes: &[u8]| Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(&body[0..4]).into(), port(&body[8..10])),
+                destination: SocketAddr::new(ip(&body[4..8]).into(), port(&body[10..12])),
+            })
+        }
+        // TCP over IPv6
+        0x21 if body.len() >= 36 => {
+            let ip = |bytes: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                Ipv6Addr::from(octets)
+            };
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(&body[0..16]).into(), port(&body[32..34])),
+                destination: SocketAddr::new(ip(&body[16..32]).into(), port(&body[34..36])),
+            })
+        }
+        0x11 | 0x21 => return Parsed::Invalid,
+        // Other protocols, like UDP or UNIX sockets
+        _ => None,
+    };
+    Parsed::Header { addrs, len }
+}
+
+// Reads the PROXY protocol header from the start of a control connection, if `mode` expects one.
+// Resolves to the connection and the addresses from the header, if any.
+pub(crate) fn read_header(
+    socket: TcpStream,
+    mode: ProxyProtocol,
+) -> Box<dyn Future<Item = (TcpStream, Option<ProxiedAddrs>), Error = io::Error> + Send> {
+    let timeout = match mode {
+        ProxyProtocol::Disabled => return Box::new(futures::future::ok((socket, None))),
+        ProxyProtocol::Optional => Duration::from_millis(200),
+        ProxyProtocol::Required => Duration::from_secs(5),
+    };
+    Box::new(ReadHeader {
+        socket: Some(socket),
+        buf: vec![],
+        deadline: Delay::new(Instant::now() + timeout),
+        optional: mode == ProxyProtocol::Optional,
+    })
+}
+
+struct ReadHeader {
+    socket: Option<TcpStream>,
+    buf: Vec<u8>,
+    deadline: Delay,
+    optional: bool,
+}
+
+impl Future for ReadHeader {
+    type Item = (TcpStream, Option<ProxiedAddrs>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid PROXY header");
+        loop {
+            match parse(&self.buf) {
+                // FTP clients wait for the greeting, so nothing should follow the header yet.
+                Parsed::Header { addrs, len } if len == self.buf.len() => {
+                    return Ok(Async::Ready((self.socket.take().unwrap(), addrs)));
+                }
+                Parsed::Incomplete => {}
+                Parsed::Header { .. } | Parsed::Invalid => return Err(invalid()),
+            }
+
+            let mut chunk = [0; 256];
+            match self.socket.as_mut().unwrap().poll_read(&mut chunk)? {
+                Async::Ready(0) => {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Async::Ready(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Async::NotReady => {
+                    let expired = self
+                        .deadline
+                        .poll()
+                        .map_err(|e| io::Error::other(e.to_string()))?
+                        .is_ready();
+                    return match expired {
+                        false => Ok(Async::NotReady),
+                        true if self.optional && self.buf.is_empty() => {
+                            Ok(Async::Ready((self.socket.take().unwrap(), None)))
+                        }
+                        true => Err(io::ErrorKind::TimedOut.into()),
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn addrs(source: &str, destination: &str) -> Option<ProxiedAddrs> {
+        Some(ProxiedAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 21\r\n";
+        assert_eq!(
+            parse(header),
+            Parsed::Header {
+                addrs: addrs("192.0.2.1:56324", "198.51.100.1:21"),
+                len: header.len(),
+            }
+        );
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 21\r\n";
+        assert_eq!(
+            parse(header),
+            Parsed::Header {
+                addrs: addrs("[2001:db8::1]:56324", "[2001:db8::2]:21"),
+                len: header.len(),
+            }
+        );
+        assert_eq!(
+            parse(b"PROXY UNKNOWN\r\n"),
+            Parsed::Header {
+                addrs: None,
+                len: 15
+            }
+        );
+
+        assert_eq!(parse(b"PRO"), Parsed::Incomplete);
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1"), Parsed::Incomplete);
+        assert_eq!(
+            parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n"),
+            Parsed::Invalid
+        );
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 1\r\n"),
+            Parsed::Invalid
+        );
+        assert_eq!(parse(&[b'a'; V1_MAX_LEN]), Parsed::Invalid);
+        assert_eq!(parse(b"USER root\r\n"), Parsed::Invalid);
+    }
+
+    #[test]
+    fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 21]);
+        assert_eq!(
+            parse(&header),
+            Parsed::Header {
+                addrs: addrs("192.0.2.1:56324", "198.51.100.1:21"),
+                len: 28,
+            }
+        );
+        assert_eq!(parse(&header[..20]), Parsed::Incomplete);
+
+        // A health check of the proxy itself
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(
+            parse(&local),
+            Parsed::Header {
+                addrs: None,
+                len: 16
+            }
+        );
+
+        let mut version_3 = header.clone();
+        version_3[12] = 0x31;
+        assert_eq!(parse(&version_3), Parsed::Invalid);
+        let mut too_short = V2_SIGNATURE.to_vec();
+        too_short.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert_eq!(parse(&too_short), Parsed::Invalid);
+    }
+}
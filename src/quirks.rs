@@ -0,0 +1,198 @@
+/// A known way in which a client misreads replies, that the `Server` can work around.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Quirk {
+    /// The client only recognizes the first line of a multi-line reply if there's a space after
+    /// the dash, like in `211- Extensions supported:`.
+    SpaceAfterDash,
+    /// The client expects every line of a multi-line reply to start with the reply code, like
+    /// `211-EPRT`, instead of just the first and the last one.
+    CodeOnEveryLine,
+    /// The client gets the multi-line `FEAT` reply wrong, so it's better off being told there are
+    /// no features at all.
+    NoFeatures,
+}
+
+/// The quirks of a client, recognized by the name it gives with the `CLNT` command.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::quirks::{Profile, Quirk};
+///
+/// let profile = Profile::new("acme", "AcmeFTP").quirk(Quirk::SpaceAfterDash);
+/// assert!(profile.matches("acmeftp 2.1 (Windows)"));
+/// assert!(!profile.matches("FileZilla 3.66"));
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    name: String,
+    // Lower-cased, clients aren't consistent about the case.
+    client: String,
+    quirks: Vec<Quirk>,
+}
+
+impl Profile {
+    /// Create a profile with the given name, for the clients whose `CLNT` string contains
+    /// `client` (ignoring case). It has no quirks yet.
+    pub fn new<N: Into<String>>(name: N, client: &str) -> Self {
+        Profile {
+            name: name.into(),
+            client: client.to_lowercase(),
+            quirks: vec![],
+        }
+    }
+
+    /// Add a quirk to the profile.
+    pub fn quirk(mut self, quirk: Quirk) -> Self {
+        if !self.quirks.contains(&quirk) {
+            self.quirks.push(quirk);
+        }
+        self
+    }
+
+    /// Returns the name of the profile, which shows up in the logs.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the quirks of the profile.
+    pub fn quirks(&self) -> &[Quirk] {
+        &self.quirks
+    }
+
+    /// Returns whether the profile is for the client that sent the given `CLNT` string.
+    pub fn matches(&self, client: &str) -> bool {
+        client.to_lowercase().contains(&self.client)
+    }
+}
+
+/// The quirk profiles a `Server` picks from when a client tells its name with `CLNT`. The first
+/// profile that matches wins. By default the server uses the [`builtin`] profiles.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::quirks::{Profile, Quirk, Quirks};
+///
+/// let mut quirks = Quirks::builtin();
+/// quirks.add(Profile::new("in-house", "OurUploader").quirk(Quirk::CodeOnEveryLine));
+/// assert_eq!(quirks.for_client("OurUploader/1.0").map(Profile::name), Some("in-house"));
+/// assert_eq!(quirks.for_client("lftp 4.9.2"), None);
+/// ```
+///
+/// [`builtin`]: #method.builtin
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Quirks {
+    profiles: Vec<Profile>,
+}
+
+impl Quirks {
+    /// Create an empty set of profiles, which leaves the replies to all clients alone.
+    pub fn new() -> Self {
+        Quirks::default()
+    }
+
+    /// The profiles that come with firetrap, for clients that are known to need them.
+    pub fn builtin() -> Self {
+        let mut quirks = Quirks::new();
+        quirks.add(Profile::new("ws_ftp", "WS_FTP").quirk(Quirk::NoFeatures));
+        quirks.add(Profile::new("cuteftp", "CuteFTP").quirk(Quirk::SpaceAfterDash));
+        quirks.add(
+            Profile::new("trumpet", "Trumpet")
+                .quirk(Quirk::SpaceAfterDash)
+                .quirk(Quirk::CodeOnEveryLine),
+        );
+        quirks
+    }
+
+    /// Add a profile, after the ones already there.
+    pub fn add(&mut self, profile: Profile) {
+        self.profiles.push(profile);
+    }
+
+    /// Returns the profile for the client that sent the given `CLNT` string, if any.
+    pub fn for_client(&self, client: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.matches(client))
+    }
+}
+
+// Rewrites the (possibly multi-line) replies in a response for a client with the given quirks.
+pub(crate) fn apply(quirks: &[Quirk], response: &str) -> String {
+    if quirks.is_empty() {
+        return response.to_string();
+    }
+    let mut rewritten = String::with_capacity(response.len() * 2);
+    // The code of the multi-line reply we're in the middle of, if any.
+    let mut multi_line: Option<&str> = None;
+    for line in response.split_terminator("\r\n") {
+        match multi_line {
+            None if line.len() > 3
+                && line.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+                && line.as_bytes()[3] == b'-' =>
+            {
+                multi_line = Some(&line[..3]);
+                rewritten.push_str(&line[..4]);
+                if quirks.contains(&Quirk::SpaceAfterDash) && !line[4..].starts_with(' ') {
+                    rewritten.push(' ');
+                }
+                rewritten.push_str(&line[4..]);
+            }
+            Some(code) if line.starts_with(code) && line[3..].starts_with(' ') => {
+                multi_line = None;
+                rewritten.push_str(line);
+            }
+            Some(code) if quirks.contains(&Quirk::CodeOnEveryLine) => {
+                rewritten.push_str(code);
+                rewritten.push('-');
+                if quirks.contains(&Quirk::SpaceAfterDash) {
+                    rewritten.push(' ');
+                }
+                rewritten.push_str(line.trim_start());
+            }
+            _ => rewritten.push_str(line),
+        }
+        rewritten.push_str("\r\n");
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const FEAT: &str = "211-Extensions supported:\r\n EPRT\r\n EPSV\r\n211 END\r\n";
+
+    #[test]
+    fn applies_quirks() {
+        assert_eq!(apply(&[], FEAT), FEAT);
+        assert_eq!(
+            apply(&[Quirk::SpaceAfterDash], FEAT),
+            "211- Extensions supported:\r\n EPRT\r\n EPSV\r\n211 END\r\n"
+        );
+        assert_eq!(
+            apply(&[Quirk::CodeOnEveryLine], FEAT),
+            "211-Extensions supported:\r\n211-EPRT\r\n211-EPSV\r\n211 END\r\n"
+        );
+        assert_eq!(
+            apply(&[Quirk::SpaceAfterDash, Quirk::CodeOnEveryLine], FEAT),
+            "211- Extensions supported:\r\n211- EPRT\r\n211- EPSV\r\n211 END\r\n"
+        );
+        // Single-line replies are fine as they are.
+        let replies = "426 Transfer aborted\r\n226 Closed data channel\r\n";
+        assert_eq!(apply(&[Quirk::SpaceAfterDash], replies), replies);
+        // So are lines that aren't replies, like the ones of a `STAT` listing.
+        let line = "-rw-r--r-- 1 ftp ftp 5 Jan 01 00:00 hello.txt\r\n";
+        assert_eq!(apply(&[Quirk::SpaceAfterDash], line), line);
+    }
+
+    #[test]
+    fn finds_profiles() {
+        let quirks = Quirks::builtin();
+        let profile = quirks.for_client("WS_FTP Pro 12.9").unwrap();
+        assert_eq!(profile.name(), "ws_ftp");
+        assert_eq!(profile.quirks(), &[Quirk::NoFeatures]);
+        assert_eq!(quirks.for_client("FileZilla/3.66.4"), None);
+        assert_eq!(Quirks::new().for_client("WS_FTP Pro 12.9"), None);
+    }
+}
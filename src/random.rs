@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::RngCore;
+
+/// Defines the source of randomness used by the [`Server`] for everything that must not be
+/// predictable, like the names of files created with `STOU`, names of temporary files and session
+/// IDs. Predictable names would allow other local users to pre-create those files on a shared
+/// filesystem.
+///
+/// [`Server`]: ../server/struct.Server.html
+pub trait RandomSource {
+    /// Fill `dest` with random bytes.
+    fn fill_bytes(&self, dest: &mut [u8]);
+
+    /// Returns a random name that is safe to use as a filename, consisting of 32 lowercase
+    /// hexadecimal characters.
+    fn unique_name(&self) -> String {
+        let mut bytes = [0u8; 16];
+        self.fill_bytes(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// [`RandomSource`] that uses a cryptographically secure generator seeded by the operating
+/// system. This is the default.
+///
+/// [`RandomSource`]: trait.RandomSource.html
+pub struct SecureRandom;
+
+impl RandomSource for SecureRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest);
+    }
+}
+
+/// [`RandomSource`] that generates the same sequence of bytes for the same seed. This makes the
+/// generated names predictable, by design, so it's only meant for tests.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::random::{DeterministicRandom, RandomSource};
+///
+/// let a = DeterministicRandom::new(42);
+/// let b = DeterministicRandom::new(42);
+/// assert_eq!(a.unique_name(), b.unique_name());
+/// ```
+///
+/// [`RandomSource`]: trait.RandomSource.html
+pub struct DeterministicRandom {
+    state: AtomicU64,
+}
+
+impl DeterministicRandom {
+    /// Create a new `DeterministicRandom` with the given seed.
+    pub const fn new(seed: u64) -> Self {
+        DeterministicRandom {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    // SplitMix64, which is simple and good enough to not produce obviously related names.
+    fn next_u64(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self
+            .state
+            .fetch_add(GAMMA, Ordering::SeqCst)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for DeterministicRandom {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_names() {
+        let random = SecureRandom;
+        let name = random.unique_name();
+        assert_eq!(name.len(), 32);
+        assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(name, random.unique_name());
+    }
+
+    #[test]
+    fn deterministic() {
+        let a = DeterministicRandom::new(1);
+        let b = DeterministicRandom::new(1);
+        let names: Vec<String> = (0..3).map(|_| a.unique_name()).collect();
+        assert_eq!(names, (0..3).map(|_| b.unique_name()).collect::<Vec<_>>());
+        assert_ne!(names[0], names[1]);
+        assert_ne!(names[0], DeterministicRandom::new(2).unique_name());
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{self, Read};
+
+use bytes::Bytes;
+use futures::sync::mpsc;
+use futures::{future, Async, Future, Sink, Stream};
+use tokio_io::AsyncRead;
+
+use crate::compression::Reader;
+
+/// How far ahead of the data connection to read from the storage backend during `RETR`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ReadAhead {
+    /// The size of a single read from the storage backend, in bytes.
+    pub(crate) chunk_size: usize,
+    /// The number of chunks to read before they're sent to the client.
+    pub(crate) depth: usize,
+}
+
+/// Reads from `reader` in a separate task, up to `depth` chunks ahead of whoever reads from the
+/// returned reader, so the latency of every single read from a remote storage backend overlaps
+/// with sending the previous chunks to the client. Without `read_ahead`, the reader is returned
+/// as is.
+///
+/// Dropping the returned reader (e.g. when the transfer is aborted) stops the reading task.
+pub(crate) fn read_ahead<R>(reader: R, read_ahead: Option<ReadAhead>) -> Reader
+where
+    R: AsyncRead + Send + 'static,
+{
+    let ReadAhead { chunk_size, depth } = match read_ahead {
+        Some(read_ahead) if read_ahead.depth > 0 && read_ahead.chunk_size > 0 => read_ahead,
+        _ => return Box::new(reader),
+    };
+
+    // The channel buffers `depth` chunks, plus one for every sender.
+    let (tx, rx) = mpsc::channel(depth - 1);
+    tokio::spawn(
+        future::loop_fn((reader, tx), move |(reader, tx)| {
+            tokio_io::io::read(reader, vec![0; chunk_size]).then(move |res| {
+                let (chunk, reader) = match res {
+                    Ok((reader, mut buf, n)) => {
+                        buf.truncate(n);
+                        (Ok(Bytes::from(buf)), Some(reader))
+                    }
+                    Err(e) => (Err(e), None),
+                };
+                let done = match &chunk {
+                    Ok(chunk) => chunk.is_empty(),
+                    Err(_) => true,
+                };
+                // Fails if the transfer is gone, which ends this task as well.
+                tx.send(chunk).map(move |tx| match reader {
+                    Some(reader) if !done => future::Loop::Continue((reader, tx)),
+                    _ => future::Loop::Break(()),
+                })
+            })
+        })
+        .map_err(|_| ()),
+    );
+
+    Box::new(Prefetched {
+        chunks: rx,
+        current: Bytes::new(),
+        done: false,
+    })
+}
+
+// The reading end of `read_ahead`.
+struct Prefetched {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    // What's left of the chunk that is being read
+    current: Bytes,
+    done: bool,
+}
+
+impl Read for Prefetched {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() && !self.done {
+            match self.chunks.poll() {
+                Ok(Async::Ready(Some(Ok(chunk)))) => {
+                    self.done = chunk.is_empty();
+                    self.current = chunk;
+                }
+                Ok(Async::Ready(Some(Err(e)))) => {
+                    self.done = true;
+                    return Err(e);
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    self.done = true;
+                    return Err(io::Error::other("Reading from the storage backend stopped"));
+                }
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+impl AsyncRead for Prefetched {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn read_all(read_ahead_by: Option<ReadAhead>, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(future::lazy(move || {
+            tokio_io::io::read_to_end(read_ahead(io::Cursor::new(data), read_ahead_by), vec![])
+                .map(|(_, data)| data)
+        }))
+    }
+
+    #[test]
+    fn reads_everything_in_order() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        for &(chunk_size, depth) in &[(1, 1), (7, 3), (4096, 2), (1 << 20, 8)] {
+            let read_ahead_by = Some(ReadAhead { chunk_size, depth });
+            assert_eq!(read_all(read_ahead_by, data.clone()).unwrap(), data);
+        }
+        assert_eq!(read_all(None, data.clone()).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_file() {
+        let read_ahead_by = Some(ReadAhead {
+            chunk_size: 16,
+            depth: 4,
+        });
+        assert_eq!(read_all(read_ahead_by, vec![]).unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,314 @@
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use futures::sync::mpsc;
+use futures::{try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend};
+use tokio::timer::Delay;
+use tokio_codec::Encoder;
+use tokio_io::AsyncWrite;
+
+// The states of a command that replies later, from a task of its own.
+const WAITING: u8 = 0;
+const REPLIED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+
+// A command that's still working on its reply, and may run out of time for it.
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    state: AtomicU8,
+}
+
+impl Pending {
+    // Marks the command timed out. Returns `false` if it replied in time after all.
+    pub(crate) fn time_out(&self) -> bool {
+        self.state
+            .compare_exchange(WAITING, TIMED_OUT, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    // Marks the command as replied to. Returns `false` if it's too late for that.
+    fn reply(&self) -> bool {
+        let _ = self
+            .state
+            .compare_exchange(WAITING, REPLIED, Ordering::SeqCst, Ordering::SeqCst);
+        self.state.load(Ordering::SeqCst) == REPLIED
+    }
+}
+
+// The command that's being processed, if it might time out.
+pub(crate) type Current = Arc<Mutex<Option<Arc<Pending>>>>;
+
+// The sender that commands send their replies to the session through. Every clone belongs to
+// the command that was being processed when it was made, so the replies of a command that timed
+// out can be dropped, instead of being taken for the replies to later commands.
+pub(crate) struct Replies<T> {
+    tx: mpsc::Sender<T>,
+    // Only set for the original, that the clones take the current command from.
+    current: Option<Current>,
+    pending: Option<Arc<Pending>>,
+}
+
+impl<T> Replies<T> {
+    pub(crate) fn new(tx: mpsc::Sender<T>, current: Current) -> Self {
+        Replies {
+            tx,
+            current: Some(current),
+            pending: None,
+        }
+    }
+
+    // Returns the plain sender, for replies that don't belong to a command, like the ones from
+    // the data channel.
+    pub(crate) fn sender(&self) -> mpsc::Sender<T> {
+        self.tx.clone()
+    }
+}
+
+impl<T> Clone for Replies<T> {
+    fn clone(&self) -> Self {
+        let pending = match &self.current {
+            Some(current) => current.lock().ok().and_then(|current| current.clone()),
+            None => self.pending.clone(),
+        };
+        Replies {
+            tx: self.tx.clone(),
+            current: None,
+            pending,
+        }
+    }
+}
+
+impl<T> Sink for Replies<T> {
+    type SinkItem = T;
+    type SinkError = mpsc::SendError<T>;
+
+    fn start_send(&mut self, item: T) -> StartSend<T, Self::SinkError> {
+        if let Some(pending) = &self.pending {
+            if !pending.reply() {
+                // The session has moved on already.
+                return Ok(AsyncSink::Ready);
+            }
+        }
+        self.tx.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.tx.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.tx.close()
+    }
+}
+
+// Writes the replies to the control connection, buffering at most `limit` bytes (plus the one
+// reply that went over it) for a client that doesn't keep up. Once it's full, the session waits
+// for the client to read instead of queueing more, and a client that reads nothing at all for
+// `timeout` is disconnected, so a stalled reader can't make us hold on to ever more replies.
+pub(crate) struct ReplyWriter<W, E> {
+    writer: W,
+    encoder: E,
+    buffer: BytesMut,
+    limit: usize,
+    timeout: Duration,
+    // Running while the client isn't reading.
+    stalled: Option<Delay>,
+}
+
+impl<W, E> ReplyWriter<W, E> {
+    pub(crate) fn new(writer: W, encoder: E, limit: usize, timeout: Duration) -> Self {
+        ReplyWriter {
+            writer,
+            encoder,
+            buffer: BytesMut::new(),
+            limit,
+            timeout,
+            stalled: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite, E: Encoder> ReplyWriter<W, E>
+where
+    E::Error: From<io::Error>,
+{
+    // Checks whether the client has been blocking us for too long.
+    fn poll_stalled(&mut self) -> Result<(), io::Error> {
+        let timeout = self.timeout;
+        let stalled = self
+            .stalled
+            .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        match stalled.poll() {
+            Ok(Async::NotReady) => Ok(()),
+            Ok(Async::Ready(())) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client stopped reading replies",
+            )),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl<W: AsyncWrite, E: Encoder> Sink for ReplyWriter<W, E>
+where
+    E::Error: From<io::Error>,
+{
+    type SinkItem = E::Item;
+    type SinkError = E::Error;
+
+    fn start_send(&mut self, item: E::Item) -> StartSend<E::Item, E::Error> {
+        if !self.buffer.is_empty() && self.buffer.len() >= self.limit {
+            self.poll_complete()?;
+            if self.buffer.len() >= self.limit {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+        self.encoder.encode(item, &mut self.buffer)?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), E::Error> {
+        while !self.buffer.is_empty() {
+            match self.writer.poll_write(&self.buffer)? {
+                Async::Ready(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "Failed to write the reply to the control connection",
+                    )
+                    .into());
+                }
+                Async::Ready(n) => {
+                    self.buffer.advance(n);
+                    self.stalled = None;
+                }
+                Async::NotReady => {
+                    self.poll_stalled()?;
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+        self.stalled = None;
+        try_ready!(self.writer.poll_flush());
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), E::Error> {
+        try_ready!(self.poll_complete());
+        Ok(self.writer.shutdown()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, stream, Future, Stream};
+    use pretty_assertions::assert_eq;
+    use std::io::{Cursor, Write};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_codec::LinesCodec;
+
+    // A client that never reads its replies.
+    struct Stalled;
+
+    impl Write for Stalled {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncWrite for Stalled {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::NotReady)
+        }
+    }
+
+    #[test]
+    fn drops_late_replies() {
+        let (tx, rx) = mpsc::channel(4);
+        let current: Current = Arc::new(Mutex::new(None));
+        let replies = Replies::new(tx, Arc::clone(&current));
+
+        // A command that replies in time
+        let pending = Arc::new(Pending::default());
+        *current.lock().unwrap() = Some(Arc::clone(&pending));
+        let in_time = replies.clone();
+        *current.lock().unwrap() = None;
+        let in_time = in_time.send("in time").wait().unwrap();
+        assert!(!pending.time_out());
+
+        // A command that doesn't, even through a clone of its clone
+        let pending = Arc::new(Pending::default());
+        *current.lock().unwrap() = Some(Arc::clone(&pending));
+        let too_late = replies.clone().clone();
+        *current.lock().unwrap() = None;
+        assert!(pending.time_out());
+        too_late.send("too late").wait().unwrap();
+
+        // Replies that don't belong to a command always get through.
+        replies.clone().send("no command").wait().unwrap();
+        drop((replies, in_time));
+        assert_eq!(rx.collect().wait().unwrap(), vec!["in time", "no command"]);
+    }
+
+    #[test]
+    fn writes_replies() {
+        let writer = ReplyWriter::new(
+            Cursor::new(Vec::new()),
+            LinesCodec::new(),
+            16,
+            Duration::from_secs(60),
+        );
+        let writer = Runtime::new()
+            .unwrap()
+            .block_on(writer.send_all(stream::iter_ok::<_, io::Error>(vec![
+                "200 Some reply that's longer than the limit".to_string(),
+                "200 Another".to_string(),
+            ])))
+            .unwrap()
+            .0;
+        assert_eq!(
+            writer.writer.into_inner(),
+            b"200 Some reply that's longer than the limit\n200 Another\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn bounds_the_buffer() {
+        let mut writer = ReplyWriter::new(Stalled, LinesCodec::new(), 16, Duration::from_secs(60));
+        Runtime::new()
+            .unwrap()
+            .block_on(future::lazy(move || {
+                let reply = || "200 Okay!".to_string();
+                assert!(writer.start_send(reply()).unwrap().is_ready());
+                assert!(writer.start_send(reply()).unwrap().is_ready());
+                // Full now, even after trying to get rid of some.
+                assert!(writer.start_send(reply()).unwrap().is_not_ready());
+                assert_eq!(writer.buffer.len(), 20);
+                Ok::<_, io::Error>(())
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    fn disconnects_stalled_readers() {
+        let writer = ReplyWriter::new(Stalled, LinesCodec::new(), 16, Duration::from_millis(50));
+        let started = Instant::now();
+        let error = Runtime::new()
+            .unwrap()
+            .block_on(writer.send_all(stream::iter_ok::<_, io::Error>(vec![
+                "200 Okay!".to_string();
+                100
+            ])))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -1,22 +1,51 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
 use bytes::{BufMut, BytesMut};
 use failure::*;
 use futures::prelude::*;
-use futures::sync::mpsc;
+use futures::sync::{mpsc, oneshot};
 use futures::Sink;
 use log::{error, info, warn};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_codec::{Decoder, Encoder};
-use uuid::Uuid;
 
+use crate::allowlist::{Allowlist, Network};
+use crate::ascii;
 use crate::auth;
+use crate::auth::authorization::{Authorizer, Operation};
 use crate::auth::Authenticator;
+use crate::bandwidth;
+use crate::catalog::Catalog;
 use crate::commands;
 use crate::commands::Command;
+use crate::compat::compat;
+use crate::compression;
+use crate::events::{
+    ConnectionInfo, EntryLimitReached, FileMutated, FileState, Mutation, NegotiatedOptions,
+    SessionEnded, SessionExpired, SessionListener, SessionStarted, StorageFull, StorageRecovered,
+    TransferDirection, TransferEnded,
+};
+use crate::integrity;
+use crate::locks;
+use crate::metrics;
+use crate::proxy;
+use crate::quirks::{Quirk, Quirks};
+use crate::random::RandomSource;
+use crate::readahead;
+use crate::replies;
+use crate::site;
+use crate::staging;
 use crate::storage;
+use crate::storage::Metadata;
+use crate::support;
+use crate::telnet;
+use crate::transfer;
 
 /// InternalMsg represents a status message from the data channel handler to our main (per connection)
 /// event handler.
@@ -27,14 +56,43 @@ enum InternalMsg {
     PermissionDenied,
     // File not found
     NotFound,
-    // Send the data to the client
-    SendData,
-    // We've written the data from the client to the StorageBackend
-    WrittenData,
+    // Sent the given number of bytes to the client
+    SendData {
+        bytes: u64,
+    },
+    // We've written the given number of bytes from the client to the StorageBackend
+    WrittenData {
+        bytes: u64,
+    },
+    // The session exceeded the maximum session lifetime
+    LifetimeExceeded,
+    // The `MLST` entry (facts and path) of a file or directory
+    Facts(String),
+    // Picked the given name for the file uploaded with `STOU`
+    UniqueName(String),
+    // We've written the given number of bytes to the file with the given name for `STOU`
+    WrittenUnique {
+        bytes: u64,
+        name: String,
+    },
+    // An upload of the size given with `ALLO` fits in the free space
+    EnoughSpace,
+    // An upload of the size given with `ALLO` doesn't fit in the given free space
+    InsufficientSpace {
+        free: u64,
+    },
+    // The storage backend doesn't know how much space is left
+    UnknownSpace,
     // Data connection was unexpectedly closed
     ConnectionReset,
     // Failed to write data to disk
     WriteFailed,
+    // An upload ran out of space in the storage, or is refused until there's space again
+    OutOfSpace,
+    // The command failed with the given error of the storage, which has a reply of its own
+    StorageFailed(storage::Error),
+    // Failed to list a directory
+    ListFailed,
     // Started sending data to the client
     SendingData,
     // Unknown Error retrieving file
@@ -47,10 +105,81 @@ enum InternalMsg {
     DelFail,
     // Quit the client connection
     Quit,
+    // The transfer that was running when the client sent `QUIT` is done
+    QuitAfterTransfer,
+    // A command took longer than the command timeout
+    CommandTimedOut,
+    // The user created as many files and directories as it may in a session
+    EntryLimitReached,
     // Successfully created directory
     MkdirSuccess(std::path::PathBuf),
     // Failed to crate directory
     MkdirFail,
+    // Successfully removed a directory
+    RmdirSuccess,
+    // Failed to remove a directory
+    RmdirFail,
+    // The directory to create exists already
+    DirectoryExists,
+    // The size of the file the client asked for
+    Size(u64),
+    // The path the client asked about is not a regular file
+    NotAFile,
+    // Changed the working directory to the given one
+    CwdSuccess(std::path::PathBuf),
+    // The user with the given name gave the right password
+    LoggedIn(String),
+    // The user gave the wrong password
+    WrongPassword,
+    // The authenticator failed to tell whether the password is right
+    AuthenticationFailed,
+    // The path the client wanted to change into is not a directory
+    NotADirectory,
+    // The modification time of the file the client asked for
+    ModificationTime(std::time::SystemTime),
+    // Successfully changed the modification time of the file
+    MfmtSuccess(std::time::SystemTime, std::path::PathBuf),
+    // Failed to change the modification time of the file
+    MfmtFail,
+    // Cancelled a running transfer because of an `ABOR`
+    TransferAborted,
+    // Closed the (idle) data channel because of an `ABOR`
+    DataChannelClosed,
+    // The directory listing for `STAT <path>` starts
+    StatListing,
+    // A line of the directory listing for `STAT <path>`
+    StatLine(Vec<u8>),
+    // The directory listing for `STAT <path>` is done, or failed halfway
+    StatListed {
+        complete: bool,
+    },
+    // Successfully changed the permissions of a file
+    ChmodSuccess,
+    // Failed to change the permissions of a file
+    ChmodFail,
+    // The presigned URL for `SITE PRESIGN`
+    Presigned(String),
+    // The storage backend can't presign URLs
+    PresignUnsupported,
+    // The reply of a `SITE` subcommand
+    SiteReply(String),
+    // Another session is writing to the path the client wants to write to
+    PathLocked,
+    // The file didn't match the precondition set with `SITE EXPECT`
+    PreconditionFailed,
+    // Successfully renamed a file
+    RenameSuccess,
+    // Failed to rename a file
+    RenameFail,
+    // The checksum of (the first `len` bytes of) a file
+    Hash {
+        algorithm: storage::HashAlgorithm,
+        len: u64,
+        hash: String,
+        file: std::path::PathBuf,
+    },
+    // The checksum asked for with `XCRC` or `XMD5`
+    Checksum(String),
 }
 
 /// Event represents an `Event` that will be handled by our per-client event loop. It can be either
@@ -65,17 +194,29 @@ enum Event {
 
 // FTPCodec implements tokio's `Decoder` and `Encoder` traits for the control channel, that we'll
 // use to decode FTP commands and encode their responses.
-struct FTPCodec {
+pub(crate) struct FTPCodec {
     // Stored index of the next index to examine for a '\n' character. This is used to optimize
     // searching. For example, if `decode` was called with `abc`, it would hold `3`, because that
     // is the next index to examine. The next time `decode` is called with `abcde\n`, we will only
     // look at `de\n` before returning.
     next_index: usize,
+    // The bytes received so far, without the Telnet commands that `telnet` took out.
+    received: BytesMut,
+    telnet: telnet::Filter,
+    // Whether commands and replies are in UTF-8, shared with the session so `OPTS UTF8` can
+    // switch it. Otherwise they are in ISO-8859-1, which (unlike guessing) maps every byte to
+    // exactly one character and back.
+    utf8: Arc<AtomicBool>,
 }
 
 impl FTPCodec {
-    fn new() -> Self {
-        FTPCodec { next_index: 0 }
+    pub(crate) fn new(utf8: Arc<AtomicBool>) -> Self {
+        FTPCodec {
+            next_index: 0,
+            received: BytesMut::new(),
+            telnet: telnet::Filter::new(),
+            utf8,
+        }
     }
 }
 
@@ -86,11 +227,18 @@ impl Decoder for FTPCodec {
     // Here we decode the incoming bytes into a meaningful command. We'll split on newlines, and
     // parse the resulting line using `Command::parse()`. This method will be called by tokio.
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Command>, Self::Error> {
+        self.telnet.filter(buf, &mut self.received);
+        let buf = &mut self.received;
         if let Some(newline_offset) = buf[self.next_index..].iter().position(|b| *b == b'\n') {
             let newline_index = newline_offset + self.next_index;
             let line = buf.split_to(newline_index + 1);
             self.next_index = 0;
-            Ok(Some(Command::parse(line)?))
+            if self.utf8.load(Ordering::SeqCst) {
+                Ok(Some(Command::parse(line)?))
+            } else {
+                let line: String = line.iter().map(|&b| char::from(b)).collect();
+                Ok(Some(Command::parse(line)?))
+            }
         } else {
             self.next_index = buf.len();
             Ok(None)
@@ -105,7 +253,19 @@ impl Encoder for FTPCodec {
     // Here we encode the outgoing response, nothing special going on.
     fn encode(&mut self, response: String, buf: &mut BytesMut) -> Result<(), Self::Error> {
         buf.reserve(response.len());
-        buf.put(response);
+        if self.utf8.load(Ordering::SeqCst) {
+            buf.put(response);
+        } else {
+            // Characters that ISO-8859-1 doesn't have can't be helped.
+            for c in response.chars() {
+                let b = u8::try_from(u32::from(c)).unwrap_or(b'?');
+                // A 255 byte would be a Telnet command, unless it's escaped.
+                if b == telnet::IAC {
+                    buf.put_u8(telnet::IAC);
+                }
+                buf.put_u8(b);
+            }
+        }
         Ok(())
     }
 }
@@ -164,7 +324,7 @@ impl FTPError {
 }
 
 impl Fail for FTPError {
-    fn cause(&self) -> Option<&Fail> {
+    fn cause(&self) -> Option<&dyn Fail> {
         self.inner.cause()
     }
 
@@ -243,22 +403,155 @@ where
     <S as storage::StorageBackend>::Metadata: storage::Metadata,
     <S as storage::StorageBackend>::Error: Send,
 {
+    id: String,
     username: Option<String>,
+    connection: ConnectionInfo,
+    // The address of our end of the control connection, which active data connections are made
+    // from. Unlike `connection.local`, a PROXY header doesn't change it.
+    socket_local: std::net::SocketAddr,
     storage: Arc<S>,
-    data_cmd_tx: Option<mpsc::Sender<Command>>,
-    data_cmd_rx: Option<mpsc::Receiver<Command>>,
-    data_abort_tx: Option<mpsc::Sender<()>>,
-    data_abort_rx: Option<mpsc::Receiver<()>>,
+    data_cmd_tx: Option<mpsc::Sender<DataTransfer>>,
+    data_cmd_rx: Option<mpsc::Receiver<DataTransfer>>,
+    data_abort_tx: Option<AbortSender>,
+    data_abort_rx: Option<AbortReceiver>,
+    // The passive listener or active connect that's waiting for the data connection. Dropping it
+    // gives up on that connection, when another PASV or PORT (or a REIN) comes first.
+    data_pending: Option<oneshot::Sender<()>>,
+    // The transfer of the current data connection. Dropping it cancels the transfer.
+    transfer: Option<transfer::TransferHandle>,
     cwd: std::path::PathBuf,
     rename_from: Option<std::path::PathBuf>,
     state: SessionState,
+    list_options: storage::ListOptions,
+    stats: SessionStats,
+    // Set by `EPSV ALL`: the client promised to only use `EPSV` to set up data connections.
+    epsv_all: bool,
+    // Set by `MODE Z`: transfers are compressed with the codec.
+    mode_z: bool,
+    // Set by `MODE X`: transfers are sent in blocks with a CRC, if the server offers it.
+    mode_x: bool,
+    data_integrity: bool,
+    // The codecs the client may choose for `MODE Z`, the first being the default, and the one it
+    // chose with `OPTS MODE Z ENGINE`.
+    codecs: Arc<Vec<Arc<dyn compression::Codec + Send + Sync>>>,
+    codec: Option<Arc<dyn compression::Codec + Send + Sync>>,
+    read_ahead: Option<readahead::ReadAhead>,
+    bandwidth_limiter: Option<Arc<bandwidth::BandwidthLimiter>>,
+    priority: bandwidth::TransferPriority,
+    // Set by `TYPE A`: convert line endings of transferred files.
+    ascii: bool,
+    // Switched off by `OPTS UTF8 OFF`: the control channel is in ISO-8859-1 rather than UTF-8.
+    utf8: Arc<AtomicBool>,
+    // Set by `OPTS HASH`: the algorithm used by `HASH`.
+    hash_algorithm: storage::HashAlgorithm,
+    path_locks: Arc<locks::PathLocks>,
+    concurrent_writes: ConcurrentWrites,
+    recursive_rmd: bool,
+    random: &'static (dyn RandomSource + Send + Sync),
+    // Set by `SITE EXPECT`: the precondition for the next `DELE` or `RNTO`.
+    precondition: Option<storage::Precondition>,
+    // Set by `RANG`: the byte range the next `RETR` sends.
+    range: Option<std::ops::Range<u64>>,
+    // Set by `LANG`: the language of the catalog that replies are translated to, if not English.
+    language: Option<String>,
+    // Set by `CLNT`: the quirks of the client that the replies work around.
+    quirks: Vec<Quirk>,
+    // Set by `SITE IDNT`, which may only be sent once.
+    identified: bool,
+    // Set by `SITE IDNT`: the ident the proxy gave for the client, if any.
+    ident: Option<String>,
+    session_listener: &'static (dyn SessionListener + Send + Sync),
+    metrics: Arc<metrics::Metrics>,
+    command_timeout: Option<std::time::Duration>,
+    // The number of data connections so far, for the transfer IDs.
+    data_connections: u64,
+    new_entries: NewEntries,
+    storage_full_policy: StorageFullPolicy,
+    active_mode_policy: ActiveModePolicy,
+    // Set when an upload ran out of space: uploads are refused until there's space again.
+    storage_full: Arc<AtomicBool>,
+    // Where the session keeps its temporary files, removed when the session ends.
+    staging: staging::Staging,
+    atomic_uploads: bool,
+}
+
+// Counts the files and directories that a session created, against the limit of its user.
+#[derive(Clone, Debug, Default)]
+struct NewEntries {
+    limit: Option<u64>,
+    created: Arc<AtomicU64>,
+}
+
+impl NewEntries {
+    fn new(limit: Option<u64>) -> Self {
+        NewEntries {
+            limit,
+            created: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Counts a new entry. Returns `false` if the limit was reached already.
+    fn take(&self) -> bool {
+        match self.limit {
+            None => true,
+            Some(limit) => self
+                .created
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |created| {
+                    if created < limit {
+                        Some(created + 1)
+                    } else {
+                        None
+                    }
+                })
+                .is_ok(),
+        }
+    }
+
+    // Stops counting an entry that couldn't be created after all.
+    fn give_back(&self) {
+        if self.limit.is_some() {
+            self.created.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Running totals for the `SessionEnded` event.
+struct SessionStats {
+    started: SystemTime,
+    start: Instant,
+    commands: u64,
+    failed_operations: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    paths: HashSet<std::path::PathBuf>,
+}
+
+impl SessionStats {
+    fn new() -> Self {
+        SessionStats {
+            started: SystemTime::now(),
+            start: Instant::now(),
+            commands: 0,
+            failed_operations: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+            paths: HashSet::new(),
+        }
+    }
 }
 
+// The channel an `ABOR` is sent over to the data channel. The data channel answers whether it
+// cancelled a running transfer.
+type AbortSender = mpsc::Sender<oneshot::Sender<bool>>;
+type AbortReceiver = mpsc::Receiver<oneshot::Sender<bool>>;
+
+// A transfer command for the data channel, together with the byte range that `RANG` set for it.
+type DataTransfer = (Command, Option<std::ops::Range<u64>>);
+
 // Commands that can be send to the data channel.
-#[derive(PartialEq)]
 enum DataCommand {
-    ExternalCommand(Command),
-    Abort,
+    ExternalCommand(Command, Option<std::ops::Range<u64>>),
+    Abort(oneshot::Sender<bool>),
 }
 
 impl<S> Session<S>
@@ -268,95 +561,596 @@ where
     <S as storage::StorageBackend>::Metadata: storage::Metadata,
     <S as storage::StorageBackend>::Error: Send,
 {
-    fn with_storage(storage: Arc<S>) -> Self {
+    fn new(id: String, storage: Arc<S>, connection: ConnectionInfo) -> Self {
         Session {
+            staging: staging::StagingArea::default().session(&id),
+            id,
             username: None,
+            socket_local: connection.local,
+            connection,
             storage,
             data_cmd_tx: None,
             data_cmd_rx: None,
             data_abort_tx: None,
             data_abort_rx: None,
+            data_pending: None,
+            transfer: None,
             cwd: "/".into(),
             rename_from: None,
             state: SessionState::New,
+            list_options: storage::ListOptions::default(),
+            stats: SessionStats::new(),
+            epsv_all: false,
+            mode_z: false,
+            mode_x: false,
+            data_integrity: false,
+            codecs: Arc::new(vec![Arc::new(compression::Zlib::new(
+                DEFAULT_DEFLATE_LEVEL,
+            ))]),
+            codec: None,
+            read_ahead: None,
+            bandwidth_limiter: None,
+            priority: bandwidth::TransferPriority::default(),
+            ascii: false,
+            utf8: Arc::new(AtomicBool::new(true)),
+            hash_algorithm: storage::HashAlgorithm::default(),
+            path_locks: Arc::new(locks::PathLocks::default()),
+            concurrent_writes: ConcurrentWrites::Reject,
+            recursive_rmd: false,
+            random: &crate::random::SecureRandom {},
+            precondition: None,
+            range: None,
+            language: None,
+            quirks: vec![],
+            identified: false,
+            ident: None,
+            session_listener: &crate::events::NoopListener {},
+            metrics: Arc::new(metrics::Metrics::new()),
+            command_timeout: None,
+            data_connections: 0,
+            new_entries: NewEntries::default(),
+            storage_full_policy: StorageFullPolicy::default(),
+            active_mode_policy: ActiveModePolicy::default(),
+            storage_full: Arc::new(AtomicBool::new(false)),
+            atomic_uploads: false,
+        }
+    }
+
+    // The reply to `STAT` without arguments: a summary of the state of this session.
+    fn status(&self) -> String {
+        let mut status = format!(
+            "211-FTP server status:\r\n Connected from {}\r\n Connected to {}\r\n",
+            self.connection.peer, self.connection.local
+        );
+        if let Some(ident) = &self.ident {
+            status.push_str(&format!(" Identified as {}\r\n", ident));
+        }
+        if let Some(username) = &self.username {
+            status.push_str(&format!(" Logged in as {}\r\n", username));
+        }
+        status.push_str(&format!(
+            " TYPE: {}, STRUcture: File, MODE: {}\r\n",
+            if self.ascii { "ASCII" } else { "BINARY" },
+            if self.mode_z {
+                "Deflate"
+            } else if self.mode_x {
+                "Integrity"
+            } else {
+                "Stream"
+            }
+        ));
+        if self.data_cmd_tx.is_some() {
+            status.push_str(" Data connection ready\r\n");
+        }
+        if let Some(transfer) = self.transfer.as_ref().and_then(|t| t.status()) {
+            status.push_str(&format!(" Transferring {}\r\n", transfer));
+        }
+        status.push_str(&format!(
+            " {} commands, {} bytes received, {} bytes sent\r\n",
+            self.stats.commands, self.stats.bytes_received, self.stats.bytes_sent
+        ));
+        status.push_str("211 End of status\r\n");
+        status
+    }
+
+    fn started(&self) -> SessionStarted {
+        SessionStarted {
+            session_id: self.id.clone(),
+            connection: self.connection,
+            started: self.stats.started,
+        }
+    }
+
+    fn ended(&self) -> SessionEnded {
+        SessionEnded {
+            session_id: self.id.clone(),
+            connection: self.connection,
+            username: self.username.clone(),
+            started: self.stats.started,
+            duration: self.stats.start.elapsed(),
+            commands: self.stats.commands,
+            failed_operations: self.stats.failed_operations,
+            bytes_received: self.stats.bytes_received,
+            bytes_sent: self.stats.bytes_sent,
+            distinct_paths: self.stats.paths.len(),
+            features: self.negotiated_features(),
+            options: self.options(),
+        }
+    }
+
+    // Returns a callback that counts a new file or directory at the given path. If the user created
+    // as many as it may already, it notifies the session listener and returns `false`.
+    fn new_entry(&self) -> impl Fn(&std::path::Path) -> bool + Send {
+        let new_entries = self.new_entries.clone();
+        let session_listener = self.session_listener;
+        let session_id = self.id.clone();
+        let username = self.username.clone();
+        move |path| {
+            if new_entries.take() {
+                return true;
+            }
+            session_listener.entry_limit_reached(&EntryLimitReached {
+                session_id: session_id.clone(),
+                username: username.clone(),
+                path: path.to_path_buf(),
+                limit: new_entries.limit.unwrap_or_default(),
+            });
+            false
+        }
+    }
+
+    // Returns a callback that resolves once an upload to the given path may go ahead, or fails
+    // with `StorageFull` while the storage is out of space. The free space is checked again on
+    // every upload until there's enough.
+    fn writable(
+        &self,
+    ) -> impl FnOnce(&std::path::Path) -> Box<dyn Future<Item = (), Error = std::io::Error> + Send> + Send
+    {
+        let storage = Arc::clone(&self.storage);
+        let storage_full = Arc::clone(&self.storage_full);
+        let threshold = self.storage_full_policy.read_only_until_free.unwrap_or(0);
+        let session_listener = self.session_listener;
+        let session_id = self.id.clone();
+        move |path| {
+            if !storage_full.load(Ordering::SeqCst) {
+                return Box::new(futures::future::ok(()));
+            }
+            let dir = path.parent().unwrap_or(path).to_path_buf();
+            Box::new(
+                compat(async move { storage.free_space(dir).await }).then(move |free| {
+                    match free {
+                        Ok(Some(free)) if free < threshold => {
+                            return Err(ErrorKind::StorageFull.into());
+                        }
+                        Ok(Some(free)) => {
+                            if storage_full.swap(false, Ordering::SeqCst) {
+                                info!(
+                                "The storage has {} bytes of free space again, accepting uploads",
+                                free
+                            );
+                                session_listener
+                                    .storage_recovered(&StorageRecovered { session_id, free });
+                            }
+                        }
+                        // The upload itself will tell whether there's space.
+                        Ok(None) | Err(_) => storage_full.store(false, Ordering::SeqCst),
+                    }
+                    Ok(())
+                }),
+            )
+        }
+    }
+
+    // Returns a callback that turns the error of an upload to the given path into an I/O error
+    // that carries it. When the storage ran out of space, that's dealt with according to the
+    // policy first. Only partial files of uploads that may be deleted are.
+    #[allow(clippy::type_complexity)]
+    fn upload_failed(
+        &self,
+    ) -> impl FnOnce(
+        S::Error,
+        std::path::PathBuf,
+        bool,
+    ) -> Box<dyn Future<Item = u64, Error = std::io::Error> + Send>
+           + Send {
+        let storage = Arc::clone(&self.storage);
+        let storage_full = Arc::clone(&self.storage_full);
+        let policy = self.storage_full_policy;
+        let session_listener = self.session_listener;
+        let session_id = self.id.clone();
+        let username = self.username.clone();
+        move |e, path, deletable| {
+            let e: storage::Error = e.into();
+            if e.kind() != storage::ErrorKind::StorageFull {
+                return Box::new(futures::future::err(e.into()));
+            }
+            let read_only =
+                policy.read_only_until_free.is_some() && !storage_full.swap(true, Ordering::SeqCst);
+            error!(
+                "Ran out of space writing {}{}",
+                path.display(),
+                if read_only {
+                    ", refusing uploads until there's space again"
+                } else {
+                    ""
+                }
+            );
+            let report = move |path, partial_deleted| {
+                session_listener.storage_full(&StorageFull {
+                    session_id,
+                    username,
+                    path,
+                    partial_deleted,
+                    read_only,
+                });
+                Err(e.into())
+            };
+            if policy.delete_partial && deletable {
+                let deleted = path.clone();
+                Box::new(
+                    compat(async move { storage.del(deleted).await })
+                        .then(move |deleted| report(path, deleted.is_ok())),
+                )
+            } else {
+                Box::new(futures::future::result(report(path, false)))
+            }
+        }
+    }
+
+    // Returns a callback that notifies the session listener of a successful change to a file.
+    fn file_mutated(
+        &self,
+    ) -> impl FnOnce(Mutation, std::path::PathBuf, Option<FileState>, Option<FileState>) + Send
+    {
+        let session_listener = self.session_listener;
+        let session_id = self.id.clone();
+        let username = self.username.clone();
+        move |mutation, path, before, after| {
+            session_listener.file_mutated(&FileMutated {
+                session_id,
+                username,
+                mutation,
+                path,
+                before,
+                after,
+            })
         }
     }
 
+    fn options(&self) -> NegotiatedOptions {
+        NegotiatedOptions {
+            utf8: self.utf8.load(Ordering::SeqCst),
+            ascii: self.ascii,
+            mode_z: self.mode_z,
+            ..NegotiatedOptions::default()
+        }
+    }
+
+    // The codec of transfers in `MODE Z`: the one the client chose, or else the default. `None` if
+    // the server doesn't allow any.
+    fn codec(&self) -> compression::Selected {
+        self.codec
+            .clone()
+            .or_else(|| self.codecs.first().map(Arc::clone))
+    }
+
+    // The features the client switched on during the session.
+    fn negotiated_features(&self) -> Vec<String> {
+        let mut features = vec![];
+        if self.epsv_all {
+            features.push("EPSV ALL".to_string());
+        }
+        if self.mode_z {
+            features.push("MODE Z".to_string());
+        }
+        if self.mode_x {
+            features.push("MODE X".to_string());
+        }
+        features
+    }
+
+    // Goes back to the state right after connecting, for `REIN`, with a fresh storage backend for
+    // the next user. A running transfer gets to finish, but a data connection that's waiting for
+    // its command is closed.
+    fn reinitialize(&mut self, storage: Arc<S>) {
+        self.username = None;
+        self.state = SessionState::New;
+        self.storage = storage;
+        self.priority = bandwidth::TransferPriority::default();
+        self.cwd = "/".into();
+        self.rename_from = None;
+        self.data_cmd_tx = None;
+        self.data_cmd_rx = None;
+        self.data_abort_tx = None;
+        self.data_abort_rx = None;
+        self.data_pending = None;
+        self.epsv_all = false;
+        self.mode_z = false;
+        self.codec = None;
+        self.mode_x = false;
+        self.ascii = false;
+        self.utf8.store(true, Ordering::SeqCst);
+        self.hash_algorithm = storage::HashAlgorithm::default();
+        self.precondition = None;
+        self.range = None;
+        self.language = None;
+        self.new_entries = NewEntries::default();
+    }
+
+    // Creates the channels that hand the next transfer command (or an abort) to the data
+    // connection that's about to be established, and returns the future that resolves when it's
+    // no longer wanted.
+    fn prepare_data_channel(&mut self) -> impl Future<Item = (), Error = ()> {
+        let (pending, replaced) = oneshot::channel();
+        self.data_pending = Some(pending);
+        let (cmd_tx, cmd_rx): (mpsc::Sender<DataTransfer>, mpsc::Receiver<DataTransfer>) =
+            mpsc::channel(1);
+        let (data_abort_tx, data_abort_rx): (AbortSender, AbortReceiver) = mpsc::channel(1);
+        self.data_cmd_tx = Some(cmd_tx);
+        self.data_cmd_rx = Some(cmd_rx);
+        self.data_abort_tx = Some(data_abort_tx);
+        self.data_abort_rx = Some(data_abort_rx);
+        // Nothing ever gets sent, the receiver resolves when the sender is dropped.
+        replaced.then(|_| Ok(()))
+    }
+
     /// socket: the data socket we'll be working with
     /// tx: channel to send the result of our operation on
     /// rx: channel to receive the command on
     fn process_data(&mut self, socket: TcpStream, tx: mpsc::Sender<InternalMsg>) {
-        // TODO: Either take the rx as argument, or properly check the result instead of
-        // `unwrap()`.
-        let rx = self.data_cmd_rx.take().unwrap();
-        // TODO: Same as above, don't `unwrap()` here. Ideally we solve this by refactoring to a
-        // proper state machine.
-        let abort_rx = self.data_abort_rx.take().unwrap();
+        self.data_pending = None;
+        let (rx, abort_rx) = match (self.data_cmd_rx.take(), self.data_abort_rx.take()) {
+            (Some(rx), Some(abort_rx)) => (rx, abort_rx),
+            _ => {
+                // Nothing's waiting for it anymore, so it's closed right away.
+                warn!("Closing a data connection that nothing is waiting for");
+                return;
+            }
+        };
         let storage = Arc::clone(&self.storage);
         let cwd = self.cwd.clone();
+        let list_options = self.list_options;
+        let mode_z = self.mode_z;
+        let mode_x = self.mode_x;
+        let ascii = self.ascii;
+        let read_ahead = self.read_ahead;
+        let limiter = self.bandwidth_limiter.clone();
+        let priority = self.priority;
+        let command_timeout = self.command_timeout;
+        // Replacing the handle of a previous data connection cancels whatever it was still doing.
+        let (handle, progress, cancelled) = transfer::new();
+        self.transfer = Some(handle);
+        // Limits the speed at which we read from the data connection or the storage backend, and
+        // counts the bytes for `STAT`.
+        let counter = Arc::clone(&progress);
+        let throttle =
+            move |reader| bandwidth::throttle(counter.count(reader), limiter.as_ref(), priority);
+        let codec: compression::Selected = if mode_z { self.codec() } else { None };
+        let codec_name = codec.as_ref().map(|codec| codec.name().to_string());
+        // The bytes of an upload as they came in, before they're decompressed.
+        let received = Arc::new(AtomicU64::new(0));
+        let path_locks = Arc::clone(&self.path_locks);
+        let concurrent_writes = self.concurrent_writes;
+        let random = self.random;
+        let options = self.options();
+        storage.transfer_options(&options);
+        self.data_connections += 1;
+        let transfer_id = format!("{}.{}", self.id, self.data_connections);
+        storage.transfer_id(&transfer_id);
+        // The bytes of an upload as they arrive, or, with atomic uploads, once they've all arrived
+        // in the staging directory, so the storage never sees a partial upload.
+        let staging = if self.atomic_uploads {
+            Some(self.staging.clone())
+        } else {
+            None
+        };
+        let incoming = move |bytes: compression::Reader| -> Box<
+            dyn Future<Item = compression::Reader, Error = std::io::Error> + Send,
+        > {
+            match &staging {
+                Some(staging) => Box::new(
+                    staging
+                        .stage(bytes)
+                        .map(|file| -> compression::Reader { Box::new(file) }),
+                ),
+                None => Box::new(futures::future::ok(bytes)),
+            }
+        };
+        let file_mutated = self.file_mutated();
+        let new_entry = self.new_entry();
+        let writable = self.writable();
+        let upload_failed = self.upload_failed();
+        let session_listener = self.session_listener;
+        let session_id = self.id.clone();
+        let username = self.username.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let backend = metrics::short_type_name(std::any::type_name::<S>());
+        let class = self.priority;
+        // Notifies the session listener of a finished file transfer, and records its throughput
+        // and how well it was compressed.
+        let transfer_ended =
+            move |path: std::path::PathBuf, direction, bytes, wire_bytes, start: Instant| {
+                let duration = start.elapsed();
+                let key = metrics::ThroughputKey {
+                    direction,
+                    backend,
+                    class,
+                };
+                metrics.record_transfer(key, bytes, duration);
+                if let Some(codec) = &codec_name {
+                    metrics.record_compression(codec, bytes, wire_bytes);
+                }
+                session_listener.transfer_ended(&TransferEnded {
+                    session_id,
+                    username,
+                    path,
+                    direction,
+                    bytes,
+                    wire_bytes,
+                    codec: codec_name,
+                    duration,
+                    options,
+                    transfer_id,
+                })
+            };
+        // Resolves to the write lock on the given path, or fails with `WouldBlock` if we shouldn't
+        // wait for it.
+        let write_lock = move |path: std::path::PathBuf| -> Box<
+            dyn Future<Item = locks::PathGuard, Error = std::io::Error> + Send,
+        > {
+            let locked =
+                || std::io::Error::new(ErrorKind::WouldBlock, "Path is locked by another session");
+            match concurrent_writes {
+                ConcurrentWrites::Reject => Box::new(futures::future::result(
+                    locks::PathLocks::try_lock(&path_locks, path).ok_or_else(locked),
+                )),
+                ConcurrentWrites::Queue => {
+                    Box::new(locks::PathLocks::lock(&path_locks, path).map_err(move |_| locked()))
+                }
+            }
+        };
         let task = rx
             .take(1)
-            .map(DataCommand::ExternalCommand)
+            .map(|(cmd, range)| DataCommand::ExternalCommand(cmd, range))
             .select(abort_rx
-                .map(|_| DataCommand::Abort)
+                .map(DataCommand::Abort)
             )
-            .take_while(|data_cmd| {
-                Ok(*data_cmd != DataCommand::Abort)
-            })
             .into_future()
-            .map(move |(cmd, _)| {
+            .map(move |(cmd, rest)| {
                 use self::DataCommand::ExternalCommand;
+                // Only aborts can follow the transfer command on this stream. An abort cancels the
+                // running transfer, which closes the data connection.
+                let aborted = rest
+                    .filter_map(|data_cmd| match data_cmd {
+                        DataCommand::Abort(ack) => Some(ack),
+                        DataCommand::ExternalCommand(..) => None,
+                    })
+                    .into_future()
+                    .map_err(|_| ())
+                    .and_then(|(ack, _)| match ack {
+                        Some(ack) => futures::future::Either::A(futures::future::ok(ack)),
+                        // The session is gone, and its transfer handle cancels the transfer.
+                        None => futures::future::Either::B(futures::future::empty()),
+                    });
+                // Runs the transfer as a task of its own, so the control channel keeps processing
+                // commands in the meantime.
+                let tx_quit = tx.clone();
+                let run = move |what: String, transfer: Box<dyn Future<Item = (), Error = ()> + Send>| {
+                    progress.started(what);
+                    tokio::spawn(transfer.select2(aborted).select2(cancelled).then(move |res| {
+                        match res {
+                            Ok(futures::future::Either::A((futures::future::Either::B((ack, transfer)), _))) => {
+                                drop(transfer);
+                                let _ = ack.send(true);
+                            }
+                            Ok(futures::future::Either::B(_)) => {
+                                info!("Cancelled the transfer of a closed session");
+                            }
+                            _ => {}
+                        }
+                        if progress.finished() {
+                            futures::future::Either::A(tx_quit.send(InternalMsg::QuitAfterTransfer).then(|_| Ok(())))
+                        } else {
+                            futures::future::Either::B(futures::future::ok(()))
+                        }
+                    }));
+                };
                 match cmd {
-                    Some(ExternalCommand(Command::Retr{path})) => {
+                    Some(ExternalCommand(Command::Retr{path}, range)) => {
+                        let start = Instant::now();
                         let tx_sending = tx.clone();
                         let tx_error = tx.clone();
-                        tokio::spawn(
-                            storage.get(path)
-                            .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to get file"))
-                            .and_then(|f| {
+                        let path = cwd.join(path);
+                        let file = {
+                            let (storage, path) = (Arc::clone(&storage), path.clone());
+                            compat(async move {
+                                match range {
+                                    Some(range) => storage.get_range(path, range).await,
+                                    None => Ok(Box::new(storage.get(path).await?) as compression::Reader),
+                                }
+                            })
+                        };
+                        run(format!("RETR {}", path.display()), Box::new(
+                            within(command_timeout, file.map_err(storage_error))
+                            .and_then(move |f| {
                                 tx_sending.send(InternalMsg::SendingData)
-                                .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to send 'SendingData' message to data channel"))
-                                .and_then(|_| {
-                                    tokio_io::io::copy(f, socket)
+                                .map_err(|_| std::io::Error::other("Failed to send 'SendingData' message to data channel"))
+                                .and_then(move |_| {
+                                    let read = Arc::new(AtomicU64::new(0));
+                                    let file_bytes = Arc::clone(&read);
+                                    tokio_io::io::copy(throttle(compression::compress(ascii::to_network(compression::counted(readahead::read_ahead(f, read_ahead), &read), ascii), &codec)), integrity::send(socket, mode_x))
+                                    .and_then(|(bytes, _, socket)| tokio_io::io::shutdown(socket).map(move |_| bytes))
+                                    .map(move |bytes| (bytes, file_bytes.load(Ordering::Relaxed)))
                                 })
-                                .and_then(|_| {
-                                    tx.send(InternalMsg::SendData)
-                                    .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to send 'SendData' message to data channel"))
+                                .and_then(move |(bytes, file_bytes)| {
+                                    transfer_ended(path, TransferDirection::Download, file_bytes, bytes, start);
+                                    tx.send(InternalMsg::SendData { bytes })
+                                    .map_err(|_| std::io::Error::other("Failed to send 'SendData' message to data channel"))
                                 })
                             })
                             .or_else(|e| {
-                                let msg = match e.kind() {
-                                    ErrorKind::NotFound => InternalMsg::NotFound,
-                                    ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
-                                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
-                                    _ => InternalMsg::UnknownRetrieveError,
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::UnknownRetrieveError),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::NotFound => InternalMsg::NotFound,
+                                        ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::TimedOut => InternalMsg::CommandTimedOut,
+                                        _ => InternalMsg::UnknownRetrieveError,
+                                    },
                                 };
                                 tx_error.send(msg)
-                                .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to send ErrorMessage to data channel"))
+                                .map_err(|_| std::io::Error::other("Failed to send ErrorMessage to data channel"))
                             })
                             .map(|_| ())
                             .map_err(|e| {
                                 warn!("Failed to send file: {:?}", e);
                             })
-                         );
+                         ));
                     }
-                    Some(ExternalCommand(Command::Stor{path})) => {
+                    Some(ExternalCommand(Command::Stor{path}, _)) => {
+                        let start = Instant::now();
                         let tx_ok = tx.clone();
                         let tx_error = tx.clone();
-                        tokio::spawn(
-                            storage.put(socket, path)
-                            .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to put file"))
-                            .and_then(|_| {
-                                tx_ok.send(InternalMsg::WrittenData)
-                                .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to send WrittenData to data channel"))
+                        let path = cwd.join(path);
+                        let lock_path = path.clone();
+                        run(format!("STOR {}", path.display()), Box::new(
+                            writable(&path)
+                            .and_then(move |_| write_lock(lock_path))
+                            .and_then(move |guard| {
+                                file_state(&storage, &path).and_then(move |before| {
+                                    if before.is_none() && !new_entry(&path) {
+                                        return futures::future::Either::A(futures::future::err(ErrorKind::QuotaExceeded.into()));
+                                    }
+                                    let failed_path = path.clone();
+                                    let put_path = path.clone();
+                                    let put_storage = Arc::clone(&storage);
+                                    futures::future::Either::B(incoming(Box::new(ascii::from_network(compression::decompress(compression::counted(throttle(integrity::receive(socket, mode_x)), &received), &codec), ascii)))
+                                    .and_then(move |bytes| compat(async move { put_storage.put(bytes, put_path).await }).or_else(move |e| upload_failed(e, failed_path, true)))
+                                    .and_then(move |bytes| file_state(&storage, &path).map(move |after| {
+                                        drop(guard);
+                                        transfer_ended(path.clone(), TransferDirection::Upload, bytes, received.load(Ordering::Relaxed), start);
+                                        file_mutated(Mutation::Write, path, before, after);
+                                        bytes
+                                    })))
+                                })
+                            })
+                            .and_then(|bytes| {
+                                tx_ok.send(InternalMsg::WrittenData { bytes })
+                                .map_err(|_| std::io::Error::other("Failed to send WrittenData to data channel"))
                             })
                             .or_else(|e| {
-                                let msg = match e.kind() {
-                                    ErrorKind::NotFound => InternalMsg::NotFound,
-                                    ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
-                                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
-                                    _ => InternalMsg::WriteFailed,
-
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::WriteFailed),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::NotFound => InternalMsg::NotFound,
+                                        ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::WouldBlock => InternalMsg::PathLocked,
+                                        ErrorKind::QuotaExceeded => InternalMsg::EntryLimitReached,
+                                        ErrorKind::StorageFull => InternalMsg::OutOfSpace,
+                                        _ => InternalMsg::WriteFailed,
+                                    },
                                 };
                                 tx_error.send(msg)
                             })
@@ -364,30 +1158,135 @@ where
                             .map_err(|e| {
                                 warn!("Failed to send file: {:?}", e);
                             })
-                        );
+                        ));
+                    },
+                    Some(ExternalCommand(Command::Stou, _)) => {
+                        let start = Instant::now();
+                        let tx_ok = tx.clone();
+                        let tx_error = tx.clone();
+                        let name = random.unique_name();
+                        let path = cwd.join(&name);
+                        let counted = if new_entry(&path) { Ok(()) } else { Err(ErrorKind::QuotaExceeded.into()) };
+                        let unique_name = name.clone();
+                        let writable_path = path.clone();
+                        run(format!("STOU {}", path.display()), Box::new(
+                            futures::future::result(counted)
+                            .and_then(move |_| writable(&writable_path))
+                            .and_then(move |_| {
+                                tx.send(InternalMsg::UniqueName(unique_name))
+                                .map_err(|_| std::io::Error::other("Failed to send UniqueName to data channel"))
+                            })
+                            .and_then(move |_| {
+                                let failed_path = path.clone();
+                                let put_path = path.clone();
+                                incoming(Box::new(ascii::from_network(compression::decompress(compression::counted(throttle(integrity::receive(socket, mode_x)), &received), &codec), ascii)))
+                                .and_then(move |bytes| compat(async move { storage.put_unique(bytes, put_path).await }).or_else(move |e| upload_failed(e, failed_path, true)))
+                                .map(move |bytes| {
+                                    transfer_ended(path, TransferDirection::Upload, bytes, received.load(Ordering::Relaxed), start);
+                                    bytes
+                                })
+                            })
+                            .and_then(|bytes| {
+                                tx_ok.send(InternalMsg::WrittenUnique { bytes, name })
+                                .map_err(|_| std::io::Error::other("Failed to send WrittenUnique to data channel"))
+                            })
+                            .or_else(|e| {
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::WriteFailed),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::QuotaExceeded => InternalMsg::EntryLimitReached,
+                                        ErrorKind::StorageFull => InternalMsg::OutOfSpace,
+                                        _ => InternalMsg::WriteFailed,
+                                    },
+                                };
+                                tx_error.send(msg)
+                            })
+                            .map(|_| ())
+                            .map_err(|e| {
+                                warn!("Failed to send file: {:?}", e);
+                            })
+                        ));
+                    },
+                    Some(ExternalCommand(Command::Appe{path}, _)) => {
+                        let start = Instant::now();
+                        let tx_ok = tx.clone();
+                        let tx_error = tx.clone();
+                        let path = cwd.join(path);
+                        let lock_path = path.clone();
+                        run(format!("APPE {}", path.display()), Box::new(
+                            writable(&path)
+                            .and_then(move |_| write_lock(lock_path))
+                            .and_then(move |guard| {
+                                file_state(&storage, &path).and_then(move |before| {
+                                    if before.is_none() && !new_entry(&path) {
+                                        return futures::future::Either::A(futures::future::err(ErrorKind::QuotaExceeded.into()));
+                                    }
+                                    let failed_path = path.clone();
+                                    let append_path = path.clone();
+                                    let append_storage = Arc::clone(&storage);
+                                    futures::future::Either::B(incoming(Box::new(ascii::from_network(compression::decompress(compression::counted(throttle(integrity::receive(socket, mode_x)), &received), &codec), ascii)))
+                                    .and_then(move |bytes| compat(async move { append_storage.append(bytes, append_path).await }).or_else(move |e| upload_failed(e, failed_path, false)))
+                                    .and_then(move |bytes| file_state(&storage, &path).map(move |after| {
+                                        drop(guard);
+                                        transfer_ended(path.clone(), TransferDirection::Upload, bytes, received.load(Ordering::Relaxed), start);
+                                        file_mutated(Mutation::Write, path, before, after);
+                                        bytes
+                                    })))
+                                })
+                            })
+                            .and_then(|bytes| {
+                                tx_ok.send(InternalMsg::WrittenData { bytes })
+                                .map_err(|_| std::io::Error::other("Failed to send WrittenData to data channel"))
+                            })
+                            .or_else(|e| {
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::WriteFailed),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::NotFound => InternalMsg::NotFound,
+                                        ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::WouldBlock => InternalMsg::PathLocked,
+                                        ErrorKind::QuotaExceeded => InternalMsg::EntryLimitReached,
+                                        ErrorKind::StorageFull => InternalMsg::OutOfSpace,
+                                        _ => InternalMsg::WriteFailed,
+                                    },
+                                };
+                                tx_error.send(msg)
+                            })
+                            .map(|_| ())
+                            .map_err(|e| {
+                                warn!("Failed to append to file: {:?}", e);
+                            })
+                        ));
                     },
-                    Some(ExternalCommand(Command::List{path})) => {
+                    Some(ExternalCommand(Command::List{path}, _)) => {
                         let path = match path {
                             Some(path) => cwd.join(path),
                             None => cwd,
                         };
                         let tx_ok = tx.clone();
                         let tx_error = tx.clone();
-                        tokio::spawn(
-                            storage.list_fmt(path)
-                            .and_then(|res| tokio::io::copy(res, socket))
+                        run(format!("LIST {}", path.display()), Box::new(
+                            tokio::io::copy(
+                                throttle(compression::compress(storage::ListingReader::new(each_within(command_timeout, storage.list_fmt(path, list_options))), &codec)),
+                                integrity::send(socket, mode_x),
+                            )
+                            .and_then(|(_, _, socket)| tokio_io::io::shutdown(socket))
                             .and_then(|_| {
                                 tx_ok.send(InternalMsg::DirectorySuccesfullyListed)
-                                .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to Send `DirectorySuccesfullyListed` event"))
+                                .map_err(|_| std::io::Error::other("Failed to Send `DirectorySuccesfullyListed` event"))
                             })
                             .or_else(|e| {
-                                let msg = match e.kind() {
-                                    // TODO: Consider making these events unique (so don't reuse
-                                    // the `Stor` messages here)
-                                    ErrorKind::NotFound => InternalMsg::NotFound,
-                                    ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
-                                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
-                                    _ => InternalMsg::WriteFailed,
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::ListFailed),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::NotFound => InternalMsg::NotFound,
+                                        ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::TimedOut => InternalMsg::CommandTimedOut,
+                                        _ => InternalMsg::ListFailed,
+                                    },
                                 };
                                 tx_error.send(msg)
                             })
@@ -395,30 +1294,35 @@ where
                             .map_err(|e| {
                                 warn!("Failed to send directory list: {:?}", e);
                             })
-                        );
+                        ));
                     },
-                    Some(ExternalCommand(Command::Nlst{path})) => {
+                    Some(ExternalCommand(Command::Nlst{path}, _)) => {
                         let path = match path {
                             Some(path) => cwd.join(path),
                             None => cwd,
                         };
                         let tx_ok = tx.clone();
                         let tx_error = tx.clone();
-                        tokio::spawn(
-                            storage.nlst(path)
-                            .and_then(|res| tokio::io::copy(res, socket))
+                        run(format!("NLST {}", path.display()), Box::new(
+                            tokio::io::copy(
+                                throttle(compression::compress(storage::ListingReader::new(each_within(command_timeout, storage.nlst(path))), &codec)),
+                                integrity::send(socket, mode_x),
+                            )
+                            .and_then(|(_, _, socket)| tokio_io::io::shutdown(socket))
                             .and_then(|_| {
                                 tx_ok.send(InternalMsg::DirectorySuccesfullyListed)
-                                .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to Send `DirectorySuccesfullyListed` event"))
+                                .map_err(|_| std::io::Error::other("Failed to Send `DirectorySuccesfullyListed` event"))
                             })
                             .or_else(|e| {
-                                let msg = match e.kind() {
-                                    // TODO: Consider making these events unique (so don't reuse
-                                    // the `Stor` messages here)
-                                    ErrorKind::NotFound => InternalMsg::NotFound,
-                                    ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
-                                    ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
-                                    _ => InternalMsg::WriteFailed,
+                                let msg = match e.downcast() {
+                                    Ok(e) => storage_failure_msg(e, InternalMsg::ListFailed),
+                                    Err(e) => match e.kind() {
+                                        ErrorKind::NotFound => InternalMsg::NotFound,
+                                        ErrorKind::PermissionDenied => InternalMsg::PermissionDenied,
+                                        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => InternalMsg::ConnectionReset,
+                                        ErrorKind::TimedOut => InternalMsg::CommandTimedOut,
+                                        _ => InternalMsg::ListFailed,
+                                    },
                                 };
                                 tx_error.send(msg)
                             })
@@ -426,12 +1330,14 @@ where
                             .map_err(|e| {
                                 warn!("Failed to send directory list: {:?}", e);
                             })
-                        );
+                        ));
                     },
 					// TODO: Remove catch-all Some(_) when I'm done implementing :)
-                    Some(ExternalCommand(_)) => unimplemented!(),
-                    Some(DataCommand::Abort) => {
-                        unreachable!()
+                    Some(ExternalCommand(..)) => unimplemented!(),
+                    Some(DataCommand::Abort(ack)) => {
+                        // Aborted before a transfer started, dropping the socket closes the data
+                        // connection.
+                        let _ = ack.send(false);
                     },
                     None => { /* This probably happened because the control channel was closed before we got here */ },
                 }
@@ -445,6 +1351,274 @@ where
     }
 }
 
+// Gives the storage backend at most the command timeout to resolve the future, failing with
+// `ErrorKind::TimedOut` if it takes longer.
+fn within<T, F>(
+    timeout: Option<std::time::Duration>,
+    future: F,
+) -> Box<dyn Future<Item = T, Error = std::io::Error> + Send>
+where
+    T: Send + 'static,
+    F: Future<Item = T, Error = std::io::Error> + Send + 'static,
+{
+    match timeout {
+        Some(timeout) => Box::new(tokio::timer::Timeout::new(future, timeout).map_err(timed_out)),
+        None => Box::new(future),
+    }
+}
+
+// Gives the storage backend at most the command timeout for every chunk of the stream, rather
+// than for all of it, so that a large listing takes as long as it needs while it keeps coming.
+fn each_within<T, S>(
+    timeout: Option<std::time::Duration>,
+    stream: S,
+) -> Box<dyn Stream<Item = T, Error = std::io::Error> + Send>
+where
+    T: Send + 'static,
+    S: Stream<Item = T, Error = std::io::Error> + Send + 'static,
+{
+    match timeout {
+        Some(timeout) => Box::new(tokio::timer::Timeout::new(stream, timeout).map_err(timed_out)),
+        None => Box::new(stream),
+    }
+}
+
+fn timed_out(e: tokio::timer::timeout::Error<std::io::Error>) -> std::io::Error {
+    if e.is_inner() {
+        e.into_inner().unwrap()
+    } else if e.is_elapsed() {
+        std::io::Error::from(ErrorKind::TimedOut)
+    } else {
+        std::io::Error::other(format!("Timer failed: {}", e))
+    }
+}
+
+// Resolves to the state of the file at the given path, or `None` if there's nothing there, for
+// `FileMutated` events. Never fails.
+fn file_state<S>(
+    storage: &Arc<S>,
+    path: &std::path::Path,
+) -> Box<dyn Future<Item = Option<FileState>, Error = std::io::Error> + Send>
+where
+    S: storage::StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + 'static,
+    S::Error: 'static,
+{
+    let (storage, path) = (Arc::clone(storage), path.to_path_buf());
+    compat(async move {
+        let state = storage.stat(path).await.ok().map(|metadata| FileState {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+        Ok(state)
+    })
+}
+
+// Turns an error of the storage backend into an `io::Error` that carries it, so the reply can
+// tell what it is.
+fn storage_error<E: Into<storage::Error>>(error: E) -> std::io::Error {
+    error.into().into()
+}
+
+// The reply to a command that failed with the given error of the storage: the one for its kind,
+// like `550 File not found` or `552` for an exceeded quota, or the given one for `Backend` errors,
+// which have no reply of their own.
+fn storage_failure_msg(error: storage::Error, fallback: InternalMsg) -> InternalMsg {
+    if error.reply_code().is_some() {
+        InternalMsg::StorageFailed(error)
+    } else {
+        fallback
+    }
+}
+
+// The reply to a command that failed with the given error: the one for the error of the storage
+// that it carries, from `storage_error`, or the given one for other errors.
+fn failure_msg(error: std::io::Error, fallback: InternalMsg) -> InternalMsg {
+    match error.downcast() {
+        Ok(error) => storage_failure_msg(error, fallback),
+        Err(_) => fallback,
+    }
+}
+
+// Sends the given listing for `STAT <path>`, a line at a time, so a client that doesn't read it
+// fast enough holds it up at the reply buffer, instead of the whole listing piling up in memory.
+fn send_stat_listing(
+    listing: Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>,
+    tx: replies::Replies<InternalMsg>,
+) -> impl Future<Item = (), Error = ()> {
+    let tx_listed = tx.clone();
+    listing
+        .into_future()
+        .then(move |first| {
+            let (first, rest) = match first {
+                Ok(first) => first,
+                Err((e, _)) => {
+                    let msg = failure_msg(e, InternalMsg::NotFound);
+                    return futures::future::Either::A(tx.send(msg).map(|_| ()));
+                }
+            };
+            let lines = futures::stream::iter_ok(first)
+                .chain(rest)
+                .map(InternalMsg::StatLine);
+            futures::future::Either::B(
+                tx.send(InternalMsg::StatListing)
+                    .map_err(|_| std::io::Error::other("Failed to send the STAT listing"))
+                    .and_then(|tx| {
+                        lines.forward(tx.sink_map_err(|_| {
+                            std::io::Error::other("Failed to send the STAT listing")
+                        }))
+                    })
+                    .then(move |listed| {
+                        if let Err(e) = &listed {
+                            warn!("Failed to list the directory for STAT: {}", e);
+                        }
+                        let complete = listed.is_ok();
+                        tx_listed
+                            .send(InternalMsg::StatListed { complete })
+                            .map(|_| ())
+                    }),
+            )
+        })
+        .map_err(|e| {
+            warn!("Failed to send STAT listing: {:?}", e);
+        })
+}
+
+// Returns the reply to a command that the authorizer didn't allow, if it didn't.
+fn denial(decision: Result<bool, ()>) -> Option<String> {
+    match decision {
+        Ok(true) => None,
+        Ok(false) => Some("550 Permission denied\r\n".to_string()),
+        Err(()) => {
+            warn!("Unknown Authorization backend failure");
+            Some("451 Failed to authorize\r\n".to_string())
+        }
+    }
+}
+
+// Returns the operation that has to be authorized before the given command may be executed, and
+// the path it applies to.
+fn required_authorization(
+    cmd: &Command,
+    cwd: &std::path::Path,
+) -> Option<(Operation, std::path::PathBuf)> {
+    match cmd {
+        Command::Retr { path } => Some((Operation::Read, cwd.join(path))),
+        Command::Stor { path } | Command::Appe { path } => Some((Operation::Write, cwd.join(path))),
+        // Changing the modification time changes the file as much as rewriting it does.
+        Command::Mfmt { file, .. } => Some((Operation::Write, cwd.join(file))),
+        // The name is only picked later, so ask about the directory it ends up in.
+        Command::Stou => Some((Operation::Write, cwd.to_path_buf())),
+        Command::Hash { file } | Command::Checksum { file, .. } => {
+            Some((Operation::Read, cwd.join(file)))
+        }
+        Command::List { path } | Command::Nlst { path } => Some((
+            Operation::List,
+            path.as_ref()
+                .map_or_else(|| cwd.to_path_buf(), |path| cwd.join(path)),
+        )),
+        Command::Mlst { path } => Some((
+            Operation::List,
+            path.as_ref()
+                .map_or_else(|| cwd.to_path_buf(), |path| cwd.join(path)),
+        )),
+        Command::Stat { path: Some(path) } => std::str::from_utf8(path)
+            .ok()
+            .map(|path| (Operation::List, cwd.join(path))),
+        Command::Site { command, args } if command.eq_ignore_ascii_case("CHMOD") => {
+            site::parse_chmod(args).map(|(_, path)| (Operation::ChangePermissions, cwd.join(path)))
+        }
+        Command::Site { command, args } if command.eq_ignore_ascii_case("PRESIGN") => {
+            site::parse_presign(args).map(|(path, _)| (Operation::Presign, cwd.join(path)))
+        }
+        Command::Site { command, .. } if command.eq_ignore_ascii_case("DEBUG") => {
+            Some((Operation::Debug, cwd.to_path_buf()))
+        }
+        Command::Dele { path } => Some((Operation::Delete, cwd.join(path))),
+        Command::Mkd { path } => Some((Operation::CreateDirectory, cwd.join(path))),
+        Command::Rmd { path } => Some((Operation::RemoveDirectory, cwd.join(path))),
+        Command::Rnfr { file } | Command::Rnto { file } => {
+            Some((Operation::Rename, cwd.join(file)))
+        }
+        _ => None,
+    }
+}
+
+// Whether the (final) reply to the given command comes from the data channel, rather than from
+// the command itself. Those aren't subject to the command timeout, because transfers may take as
+// long as they need.
+fn replied_by_data_channel(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Retr { .. }
+            | Command::Stor { .. }
+            | Command::Stou
+            | Command::Appe { .. }
+            | Command::List { .. }
+            | Command::Nlst { .. }
+            | Command::Quit
+    )
+}
+
+// Opens an active mode data connection to `addr`. Unless a different `source_port` is configured,
+// we connect from the port just below the control connection's port, like RFC 959 prescribes.
+// If we can't bind to that port (e.g. because it's privileged) we fall back to any free port.
+fn connect_active(
+    control: std::net::SocketAddr,
+    source_port: Option<u16>,
+    addr: std::net::SocketAddr,
+) -> Box<dyn Future<Item = TcpStream, Error = std::io::Error> + Send> {
+    let source_port = source_port.unwrap_or_else(|| control.port().saturating_sub(1));
+    // The client may have asked for a data connection of the other address family.
+    let source_ip = if control.is_ipv4() == addr.is_ipv4() {
+        control.ip()
+    } else if addr.is_ipv4() {
+        std::net::Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        std::net::Ipv6Addr::UNSPECIFIED.into()
+    };
+
+    let bind = |port| -> std::io::Result<std::net::TcpStream> {
+        let builder = if addr.is_ipv4() {
+            net2::TcpBuilder::new_v4()?
+        } else {
+            net2::TcpBuilder::new_v6()?
+        };
+        // Every active connection of every session uses the same source port.
+        builder.reuse_address(true)?;
+        builder.bind(std::net::SocketAddr::new(source_ip, port))?;
+        builder.to_tcp_stream()
+    };
+    let socket = match bind(source_port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(
+                "Failed to bind data socket to port {}, using any port instead: {}",
+                source_port, e
+            );
+            match bind(0) {
+                Ok(socket) => socket,
+                Err(e) => return Box::new(futures::future::err(e)),
+            }
+        }
+    };
+    Box::new(TcpStream::connect_std(
+        socket,
+        &addr,
+        &tokio::reactor::Handle::default(),
+    ))
+}
+
+// Captures the details of a freshly accepted control connection.
+fn connection_info(socket: &TcpStream) -> std::io::Result<ConnectionInfo> {
+    Ok(ConnectionInfo {
+        peer: socket.peer_addr()?,
+        local: socket.local_addr()?,
+        ttl: socket.ttl().ok(),
+        proxy: None,
+    })
+}
+
 /// An instance of a FTP server. It contains a reference to an [`Authenticator`] that will be used
 /// for authentication, and a [`StorageBackend`] that will be used as the storage backend.
 ///
@@ -468,14 +1642,77 @@ pub struct Server<S>
 where
     S: storage::StorageBackend,
 {
-    storage: Box<(Fn() -> S) + Send>,
-    greeting: &'static str,
-    authenticator: &'static (Authenticator + Send + Sync),
+    // Shared with the sessions, that need a fresh backend after a `REIN`.
+    storage: Arc<Mutex<Box<dyn (Fn() -> S) + Send>>>,
+    greeting: Greeting,
+    greeting_delay: Option<(std::time::Duration, std::time::Duration)>,
+    authenticator: &'static (dyn Authenticator + Send + Sync),
+    authorizer: &'static (dyn Authorizer + Send + Sync),
     passive_addrs: Arc<Vec<std::net::SocketAddr>>,
+    passive_host: PassiveHost,
+    deflate_level: u32,
+    compression_codecs: Vec<Arc<dyn compression::Codec + Send + Sync>>,
+    allowed_codecs: Option<Vec<String>>,
+    read_ahead: Option<readahead::ReadAhead>,
+    bandwidth_limiter: Option<Arc<bandwidth::BandwidthLimiter>>,
+    list_options: storage::ListOptions,
+    site_commands: Arc<site::SiteCommands>,
+    session_listener: &'static (dyn SessionListener + Send + Sync),
+    active_source_port: Option<u16>,
+    random: &'static (dyn RandomSource + Send + Sync),
+    runtime: RuntimeConfig,
+    path_locks: Arc<locks::PathLocks>,
+    concurrent_writes: ConcurrentWrites,
+    recursive_rmd: bool,
+    data_integrity: bool,
+    catalog: Arc<Catalog>,
+    quirks: Arc<Quirks>,
+    max_session_lifetime: Option<std::time::Duration>,
+    session_sweep_interval: std::time::Duration,
+    command_timeout: Option<std::time::Duration>,
+    reply_buffer: usize,
+    reply_timeout: std::time::Duration,
+    storage_full_policy: StorageFullPolicy,
+    active_mode_policy: ActiveModePolicy,
+    storage_full: Arc<AtomicBool>,
+    staging: staging::StagingArea,
+    atomic_uploads: bool,
+    live_sessions: Arc<Mutex<HashMap<String, LiveSession>>>,
+    proxy_protocol: ProxyProtocol,
+    load_balancers: Arc<Vec<Network>>,
+    metrics: Arc<metrics::Metrics>,
+    stats_log_interval: Option<std::time::Duration>,
+    allowlist: Option<Arc<Allowlist>>,
+    trusted_proxies: Arc<Vec<Network>>,
+    // The address the server listens on, and since when.
+    listening: Arc<Mutex<Option<(std::net::SocketAddr, SystemTime)>>>,
+    // Whether `SITE DEBUG` hands out the support bundle.
+    site_debug: bool,
+    // The handle that `SITE DEBUG` gets the support bundle from, once the server listens.
+    handle: Option<ServerHandle>,
 }
 
-impl Server<storage::Filesystem> {
-    /// Create a new `Server` with the given filesystem root.
+/// A handle on a [`Server`], to look into it while it runs. Get one with [`handle`] before the
+/// server starts listening.
+///
+/// [`Server`]: struct.Server.html
+/// [`handle`]: struct.Server.html#method.handle
+#[derive(Clone, Debug)]
+pub struct ServerHandle {
+    // The configuration of the server, as a JSON object.
+    configuration: Arc<String>,
+    metrics: Arc<metrics::Metrics>,
+    listening: Arc<Mutex<Option<(std::net::SocketAddr, SystemTime)>>>,
+}
+
+impl ServerHandle {
+    /// Returns a support bundle to attach to bug reports: a single line of JSON with the version
+    /// of firetrap, the features it was built with, the configuration of the server, how often
+    /// it replied with which error code, and details of the system it runs on. Anything that may
+    /// be secret is left out: the storage backend only shows up with the name of its type, and
+    /// the greeting, the authenticator and the authorizer not at all. Admins can get the same
+    /// with `SITE DEBUG`, when the server has [`site_debug`] on and the [`Authorizer`] allows
+    /// them [`Operation::Debug`].
     ///
     /// # Example
     ///
@@ -483,86 +1720,1193 @@ impl Server<storage::Filesystem> {
     /// use firetrap::Server;
     ///
     /// let server = Server::with_root("/srv/ftp");
+    /// let handle = server.handle();
+    /// assert!(handle.support_bundle().starts_with("{\"version\":"));
     /// ```
-    pub fn with_root<P: Into<std::path::PathBuf> + Send + 'static>(path: P) -> Self {
-        let p = path.into();
-        let server = Server {
-            storage: Box::new(move || {
-                let p = &p.clone();
-                storage::Filesystem::new(p)
+    ///
+    /// [`site_debug`]: struct.Server.html#method.site_debug
+    /// [`Authorizer`]: ../auth/authorization/trait.Authorizer.html
+    /// [`Operation::Debug`]: ../auth/authorization/enum.Operation.html#variant.Debug
+    pub fn support_bundle(&self) -> String {
+        let now = SystemTime::now();
+        let rfc3339 = |time: SystemTime| chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339();
+        let features = support::features()
+            .into_iter()
+            .fold(support::Json::new(), |json, (name, enabled)| {
+                json.boolean(name, enabled)
+            });
+        let replies = self.metrics.error_replies().into_iter().map(|replies| {
+            support::Json::new()
+                .number("code", replies.code)
+                .number("count", replies.count)
+                .string("last", rfc3339(replies.last))
+        });
+        let rejected_ports = self
+            .metrics
+            .rejected_ports()
+            .into_iter()
+            .fold(support::Json::new(), |json, (reason, count)| {
+                json.number(&reason.to_string(), count)
+            });
+        let listening = *self.listening.lock().unwrap_or_else(|e| e.into_inner());
+        let environment = support::Json::new()
+            .string("os", std::env::consts::OS)
+            .string("arch", std::env::consts::ARCH)
+            .optional("hostname", support::hostname())
+            .number("pid", std::process::id())
+            .optional_number(
+                "cpus",
+                std::thread::available_parallelism().ok().map(|n| n.get()),
+            )
+            .optional("listening", listening.map(|(addr, _)| addr))
+            .optional("since", listening.map(|(_, since)| rfc3339(since)))
+            .optional_number(
+                "uptime_seconds",
+                listening
+                    .and_then(|(_, since)| now.duration_since(since).ok().map(|up| up.as_secs())),
+            );
+        support::Json::new()
+            .string("version", env!("CARGO_PKG_VERSION"))
+            .string("generated", rfc3339(now))
+            .object("features", features)
+            .raw("configuration", self.configuration.to_string())
+            .object(
+                "errors",
+                support::Json::new()
+                    .objects("replies", replies)
+                    .object("rejected_ports", rejected_ports),
+            )
+            .string("throughput", self.metrics.summary())
+            .object("environment", environment)
+            .to_string()
+    }
+}
+
+// A session that's still connected, for the sweep that enforces the maximum session lifetime.
+struct LiveSession {
+    start: Instant,
+    tx: mpsc::Sender<InternalMsg>,
+}
+
+// How often we look for sessions that exceeded the maximum session lifetime, unless configured
+// otherwise.
+const DEFAULT_SESSION_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// How many bytes of replies we hold on to for a client that doesn't read them, and how long we
+// wait for it to start reading again, unless configured otherwise.
+const DEFAULT_REPLY_BUFFER: usize = 64 * 1024;
+const DEFAULT_REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+// The greeting, unless configured otherwise.
+const DEFAULT_GREETING: &str = "Welcome to the firetrap FTP server";
+
+// The compression level for `MODE Z` transfers, unless configured otherwise.
+const DEFAULT_DEFLATE_LEVEL: u32 = 6;
+
+/// A callback that returns the IP address to announce in `PASV` replies, given the local address
+/// of the control connection. See [`Server::passive_host_resolver`].
+///
+/// [`Server::passive_host_resolver`]: struct.Server.html#method.passive_host_resolver
+pub type PassiveHostResolver =
+    Arc<dyn Fn(std::net::SocketAddr) -> Option<std::net::Ipv4Addr> + Send + Sync>;
+
+/// A callback that returns the greeting for a new control connection. See
+/// [`Server::greeting_generator`].
+///
+/// [`Server::greeting_generator`]: struct.Server.html#method.greeting_generator
+pub type GreetingGenerator = Arc<dyn Fn(&ConnectionInfo) -> String + Send + Sync>;
+
+// The text that we send new connections in the `220` reply.
+#[derive(Clone)]
+enum Greeting {
+    Text(String),
+    // Read again for every connection, so it can be changed without a restart.
+    File(std::path::PathBuf),
+    Generator(GreetingGenerator),
+}
+
+impl Greeting {
+    // Returns the complete `220` reply for the given connection, spread over multiple lines if
+    // the text has line breaks.
+    fn reply(&self, connection: &ConnectionInfo) -> String {
+        let text = match self {
+            Greeting::Text(text) => text.clone(),
+            Greeting::File(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+                warn!("Failed to read the greeting from {:?}: {}", path, e);
+                DEFAULT_GREETING.to_string()
             }),
-            greeting: "Welcome to the firetrap FTP server",
-            authenticator: &auth::AnonymousAuthenticator {},
-            passive_addrs: Arc::new(vec![]),
+            Greeting::Generator(generate) => generate(connection),
         };
-        server.passive_ports(49152..65535)
+        let lines: Vec<&str> = text.trim_end().lines().collect();
+        match lines.split_last() {
+            Some((last, lines)) => {
+                let mut reply: String = lines
+                    .iter()
+                    .map(|line| format!("220-{}\r\n", line.trim_end_matches('\r')))
+                    .collect();
+                reply.push_str(&format!("220 {}\r\n", last.trim_end_matches('\r')));
+                reply
+            }
+            None => "220 \r\n".to_string(),
+        }
     }
 }
 
-impl<S> Server<S>
-where
-    S: 'static + storage::StorageBackend + Sync + Send,
-    <S as storage::StorageBackend>::File: tokio_io::AsyncRead + Send,
-    <S as storage::StorageBackend>::Metadata: storage::Metadata,
-    <S as storage::StorageBackend>::Error: Send,
-{
-    /// Construct a new [`Server`] with the given [`StorageBackend`]. The other parameters will be
+// The IP address that we announce in `PASV` replies.
+#[derive(Clone)]
+enum PassiveHost {
+    // The address of the passive listener
+    Listener,
+    Ip(std::net::Ipv4Addr),
+    Resolver(PassiveHostResolver),
+}
+
+/// The kinds of runtime the [`Server`] can run its connections on.
+///
+/// [`Server`]: struct.Server.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RuntimeFlavor {
+    /// A pool of worker threads, plus a pool of threads for blocking operations. This is the
+    /// default.
+    MultiThreaded,
+    /// Everything runs on the thread that calls [`listen`]. There is no pool for blocking
+    /// operations, so this only works with storage backends that don't need it. Notably, the
+    /// [`Filesystem`] backend does.
+    ///
+    /// [`listen`]: struct.Server.html#method.listen
+    /// [`Filesystem`]: ../storage/struct.Filesystem.html
+    CurrentThread,
+}
+
+/// What happens when a client starts uploading (with `STOR` or `APPE`) to a path that another
+/// session of the same [`Server`] is still writing to.
+///
+/// [`Server`]: struct.Server.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConcurrentWrites {
+    /// The second client gets a `450` reply, and can try again later. This is the default.
+    Reject,
+    /// The second upload waits until the first one finished, so uploads to the same path happen
+    /// one after the other, in the order they were started.
+    Queue,
+}
+
+/// What the [`Server`] does when an upload runs out of space, like when the filesystem is full
+/// (`ENOSPC`) or the user is over quota (`EDQUOT`). Either way the client gets a `452` reply, and
+/// the [`SessionListener`] a [`StorageFull`] event.
+///
+/// [`Server`]: struct.Server.html
+/// [`SessionListener`]: ../events/trait.SessionListener.html
+/// [`StorageFull`]: ../events/struct.StorageFull.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StorageFullPolicy {
+    /// Delete what was written of the file with `STOR` or `STOU`, instead of leaving a truncated
+    /// file behind. The file of an `APPE` is never deleted, as it had more in it than the upload.
+    /// Off by default.
+    pub delete_partial: bool,
+    /// Refuse all uploads until the storage backend reports at least this many bytes of free
+    /// space again, so that clients don't keep squeezing the last bytes out of the storage.
+    /// Storage backends that don't know their free space accept uploads again right away.
+    /// `None` to keep on accepting uploads. 64MiB by default.
+    pub read_only_until_free: Option<u64>,
+}
+
+impl Default for StorageFullPolicy {
+    fn default() -> Self {
+        StorageFullPolicy {
+            delete_partial: false,
+            read_only_until_free: Some(64 << 20),
+        }
+    }
+}
+
+/// Which data connections the [`Server`] makes for `PORT` and `EPRT`. A client can ask for one to
+/// any address and port, which lets it use the server to reach hosts and services it couldn't
+/// reach itself (an FTP bounce attack). By default, the server refuses ports below 1024 and its
+/// own addresses, unless the client is on the same host, with a `504` reply. Refused commands are
+/// counted in the [`Metrics`] of the server.
+///
+/// [`Server`]: struct.Server.html
+/// [`Metrics`]: ../metrics/struct.Metrics.html
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ActiveModePolicy {
+    /// Connect to ports below 1024, where the well-known services are. Off by default.
+    pub allow_privileged_ports: bool,
+    /// Connect to the address the client connected to, or to a loopback address, for clients on
+    /// other hosts. Off by default.
+    pub allow_server_addresses: bool,
+}
+
+impl ActiveModePolicy {
+    // Why a data connection to the given address is refused for the given control connection,
+    // if it is.
+    fn refuses(
+        self,
+        connection: &ConnectionInfo,
+        addr: std::net::SocketAddr,
+    ) -> Option<metrics::RejectedPort> {
+        if !self.allow_privileged_ports && addr.port() < 1024 {
+            return Some(metrics::RejectedPort::Privileged);
+        }
+        let local = connection.local.ip();
+        let on_server =
+            |ip: std::net::IpAddr| ip == local || ip.is_loopback() || ip.is_unspecified();
+        // Clients on the same host can reach its services anyway.
+        if !self.allow_server_addresses && on_server(addr.ip()) && !on_server(connection.peer.ip())
+        {
+            return Some(metrics::RejectedPort::ServerAddress);
+        }
+        None
+    }
+}
+
+/// Whether the [`Server`] expects control connections to start with a PROXY protocol header (v1
+/// or v2), like TCP load balancers such as HAProxy send. The header tells the address of the
+/// client the connection is for, which then shows up as the peer address of the session in the
+/// logs and events, instead of the address of the load balancer. Only connections from the
+/// [load balancers] may send one; the others are taken as they are, as if this was `Disabled`.
+///
+/// [`Server`]: struct.Server.html
+/// [load balancers]: struct.Server.html#method.load_balancer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    /// Connections don't start with a header. This is the default.
+    Disabled,
+    /// Connections may start with a header, e.g. while moving behind a load balancer. FTP clients
+    /// wait for the greeting before they send anything, so connections without a header only get
+    /// their greeting after a fifth of a second.
+    Optional,
+    /// Connections have to start with a header. Connections without one (within five seconds)
+    /// are closed.
+    Required,
+}
+
+#[derive(Clone, Copy)]
+struct RuntimeConfig {
+    flavor: RuntimeFlavor,
+    worker_threads: Option<usize>,
+    blocking_threads: Option<usize>,
+    pin_accept_loop: bool,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            flavor: RuntimeFlavor::MultiThreaded,
+            worker_threads: None,
+            blocking_threads: None,
+            pin_accept_loop: false,
+        }
+    }
+}
+
+impl Server<storage::Filesystem> {
+    /// Create a new `Server` with the given filesystem root.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/srv/ftp");
+    /// ```
+    pub fn with_root<P: Into<std::path::PathBuf> + Send + 'static>(path: P) -> Self {
+        let p = path.into();
+        let server = Server {
+            storage: Arc::new(Mutex::new(Box::new(move || {
+                let p = &p.clone();
+                storage::Filesystem::new(p)
+            }))),
+            greeting: Greeting::Text(DEFAULT_GREETING.to_string()),
+            greeting_delay: None,
+            authenticator: &auth::AnonymousAuthenticator {},
+            authorizer: &auth::authorization::AllowAll {},
+            passive_addrs: Arc::new(vec![]),
+            passive_host: PassiveHost::Listener,
+            deflate_level: DEFAULT_DEFLATE_LEVEL,
+            compression_codecs: vec![],
+            allowed_codecs: None,
+            read_ahead: None,
+            bandwidth_limiter: None,
+            list_options: storage::ListOptions::default(),
+            site_commands: Arc::new(site::SiteCommands::new()),
+            session_listener: &crate::events::NoopListener {},
+            active_source_port: None,
+            random: &crate::random::SecureRandom {},
+            runtime: RuntimeConfig::default(),
+            path_locks: Arc::new(locks::PathLocks::default()),
+            concurrent_writes: ConcurrentWrites::Reject,
+            proxy_protocol: ProxyProtocol::Disabled,
+            recursive_rmd: false,
+            data_integrity: false,
+            catalog: Arc::new(Catalog::new()),
+            quirks: Arc::new(Quirks::builtin()),
+            max_session_lifetime: None,
+            session_sweep_interval: DEFAULT_SESSION_SWEEP_INTERVAL,
+            command_timeout: None,
+            reply_buffer: DEFAULT_REPLY_BUFFER,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            storage_full_policy: StorageFullPolicy::default(),
+            active_mode_policy: ActiveModePolicy::default(),
+            storage_full: Arc::new(AtomicBool::new(false)),
+            staging: staging::StagingArea::default(),
+            atomic_uploads: false,
+            live_sessions: Arc::new(Mutex::new(HashMap::new())),
+            listening: Arc::new(Mutex::new(None)),
+            handle: None,
+            site_debug: false,
+            metrics: Arc::new(metrics::Metrics::new()),
+            stats_log_interval: None,
+            allowlist: None,
+            trusted_proxies: Arc::new(vec![]),
+            load_balancers: Arc::new(vec![]),
+        };
+        server.passive_ports(49152..65535)
+    }
+}
+
+impl<S> Server<S>
+where
+    S: 'static + storage::StorageBackend + Sync + Send,
+    <S as storage::StorageBackend>::File: tokio_io::AsyncRead + Send,
+    <S as storage::StorageBackend>::Metadata: storage::Metadata,
+    <S as storage::StorageBackend>::Error: Send,
+{
+    /// Construct a new [`Server`] with the given [`StorageBackend`]. The other parameters will be
     /// set to defaults.
     ///
-    /// [`Server`]: struct.Server.html
-    /// [`StorageBackend`]: ../storage/trait.StorageBackend.html
-    pub fn new(s: Box<Fn() -> S + Send>) -> Self {
-        let server = Server {
-            storage: s,
-            greeting: "Welcome to the firetrap FTP server",
-            authenticator: &auth::AnonymousAuthenticator {},
-            passive_addrs: Arc::new(vec![]),
-        };
-        server.passive_ports(49152..65535)
+    /// [`Server`]: struct.Server.html
+    /// [`StorageBackend`]: ../storage/trait.StorageBackend.html
+    pub fn new(s: Box<dyn Fn() -> S + Send>) -> Self {
+        let server = Server {
+            storage: Arc::new(Mutex::new(s)),
+            greeting: Greeting::Text(DEFAULT_GREETING.to_string()),
+            greeting_delay: None,
+            authenticator: &auth::AnonymousAuthenticator {},
+            authorizer: &auth::authorization::AllowAll {},
+            passive_addrs: Arc::new(vec![]),
+            passive_host: PassiveHost::Listener,
+            deflate_level: DEFAULT_DEFLATE_LEVEL,
+            compression_codecs: vec![],
+            allowed_codecs: None,
+            read_ahead: None,
+            bandwidth_limiter: None,
+            list_options: storage::ListOptions::default(),
+            site_commands: Arc::new(site::SiteCommands::new()),
+            session_listener: &crate::events::NoopListener {},
+            active_source_port: None,
+            random: &crate::random::SecureRandom {},
+            runtime: RuntimeConfig::default(),
+            path_locks: Arc::new(locks::PathLocks::default()),
+            concurrent_writes: ConcurrentWrites::Reject,
+            proxy_protocol: ProxyProtocol::Disabled,
+            recursive_rmd: false,
+            data_integrity: false,
+            catalog: Arc::new(Catalog::new()),
+            quirks: Arc::new(Quirks::builtin()),
+            max_session_lifetime: None,
+            session_sweep_interval: DEFAULT_SESSION_SWEEP_INTERVAL,
+            command_timeout: None,
+            reply_buffer: DEFAULT_REPLY_BUFFER,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            storage_full_policy: StorageFullPolicy::default(),
+            active_mode_policy: ActiveModePolicy::default(),
+            storage_full: Arc::new(AtomicBool::new(false)),
+            staging: staging::StagingArea::default(),
+            atomic_uploads: false,
+            live_sessions: Arc::new(Mutex::new(HashMap::new())),
+            listening: Arc::new(Mutex::new(None)),
+            handle: None,
+            site_debug: false,
+            metrics: Arc::new(metrics::Metrics::new()),
+            stats_log_interval: None,
+            allowlist: None,
+            trusted_proxies: Arc::new(vec![]),
+            load_balancers: Arc::new(vec![]),
+        };
+        server.passive_ports(49152..65535)
+    }
+
+    /// Set the greeting that will be sent to the client after connecting. Line breaks in the
+    /// greeting make it a multi-line reply, e.g. for a legal notice.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::with_root("/tmp").greeting("Welcome to my FTP Server");
+    ///
+    /// // Or instead if you prefer:
+    /// let mut server = Server::with_root("/tmp");
+    /// server.greeting("Welcome to my FTP Server");
+    /// ```
+    pub fn greeting<G: Into<String>>(mut self, greeting: G) -> Self {
+        self.greeting = Greeting::Text(greeting.into());
+        self
+    }
+
+    /// Like [`greeting`], but reads the greeting from the given file for every connection, so it
+    /// can be changed without restarting the server. When the file can't be read, the default
+    /// greeting is sent.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").greeting_file("/etc/firetrap/banner.txt");
+    /// ```
+    ///
+    /// [`greeting`]: #method.greeting
+    pub fn greeting_file<P: Into<std::path::PathBuf>>(mut self, path: P) -> Self {
+        self.greeting = Greeting::File(path.into());
+        self
+    }
+
+    /// Like [`greeting`], but asks the given callback for the greeting of every connection. It
+    /// receives the details of the connection, so the greeting can e.g. mention the address the
+    /// client connected to.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").greeting_generator(|connection| {
+    ///     format!(
+    ///         "Welcome to {}\nAuthorized use only, connections are logged",
+    ///         connection.local.ip()
+    ///     )
+    /// });
+    /// ```
+    ///
+    /// [`greeting`]: #method.greeting
+    pub fn greeting_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn(&ConnectionInfo) -> String + Send + Sync + 'static,
+    {
+        self.greeting = Greeting::Generator(Arc::new(generator));
+        self
+    }
+
+    /// Wait a random time between `min` and `max` before greeting new connections, and silently
+    /// close the connections that send anything before they're greeted. Real clients wait for
+    /// the greeting, while many scanners and bots don't, so this keeps them out of the logs and
+    /// off the authenticator. By default connections are greeted right away.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::with_root("/tmp")
+    ///     .greeting_delay(Duration::from_millis(200), Duration::from_millis(800));
+    /// ```
+    pub fn greeting_delay(mut self, min: std::time::Duration, max: std::time::Duration) -> Self {
+        self.greeting_delay = Some((min, std::cmp::max(min, max)));
+        self
+    }
+
+    /// Set the range of passive ports that we'll use for passive connections.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::with_root("/tmp").passive_ports(49152..65535);
+    ///
+    ///
+    /// // Or instead if you prefer:
+    /// let mut server = Server::with_root("/tmp");
+    /// server.passive_ports(49152..65535);
+    /// ```
+    pub fn passive_ports(mut self, range: std::ops::Range<u16>) -> Self {
+        let mut addrs = vec![];
+        for port in range {
+            let ip = std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0));
+            let addr = std::net::SocketAddr::new(ip, port);
+            addrs.push(addr);
+        }
+        self.passive_addrs = Arc::new(addrs);
+        self
+    }
+
+    /// Set the IP address to announce in `PASV` replies, instead of the local address of the
+    /// passive listener. This is needed when the server runs behind NAT, e.g. in a Docker
+    /// container, so clients connect to the public address.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::net::Ipv4Addr;
+    ///
+    /// let server = Server::with_root("/tmp").passive_host(Ipv4Addr::new(203, 0, 113, 7));
+    /// ```
+    pub fn passive_host(mut self, ip: std::net::Ipv4Addr) -> Self {
+        self.passive_host = PassiveHost::Ip(ip);
+        self
+    }
+
+    /// Like [`passive_host`], but asks the given callback for the IP address on every `PASV`
+    /// command. It receives the local address of the control connection, so it can e.g. pick the
+    /// public address that belongs to the interface the client connected to, or return a
+    /// periodically refreshed lookup. When it returns `None`, the address of the passive listener
+    /// is announced.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::net::Ipv4Addr;
+    ///
+    /// let server = Server::with_root("/tmp").passive_host_resolver(|local| {
+    ///     if local.ip().is_loopback() {
+    ///         None
+    ///     } else {
+    ///         Some(Ipv4Addr::new(203, 0, 113, 7))
+    ///     }
+    /// });
+    /// ```
+    ///
+    /// [`passive_host`]: #method.passive_host
+    pub fn passive_host_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(std::net::SocketAddr) -> Option<std::net::Ipv4Addr> + Send + Sync + 'static,
+    {
+        self.passive_host = PassiveHost::Resolver(Arc::new(resolver));
+        self
+    }
+
+    /// Set the compression level, from `0` (none) to `9` (best), of transfers in `MODE Z`. This
+    /// trades CPU time for bandwidth. The default is `6`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").deflate_level(9);
+    /// ```
+    pub fn deflate_level(mut self, level: u32) -> Self {
+        self.deflate_level = std::cmp::min(level, 9);
+        self
+    }
+
+    /// Offer another compression [`Codec`] for `MODE Z` transfers, next to zlib, e.g. one for
+    /// zstd. Clients that know about it pick it with `OPTS MODE Z ENGINE <name>`; the others keep
+    /// getting zlib, which is what `MODE Z` means to them. The `FEAT` reply lists the codecs.
+    ///
+    /// # Example
+    ///
+    /// See [`Codec`].
+    ///
+    /// [`Codec`]: ../compression/trait.Codec.html
+    pub fn compression_codec(mut self, codec: Arc<dyn compression::Codec + Send + Sync>) -> Self {
+        self.compression_codecs.push(codec);
+        self
+    }
+
+    /// Only offer the `MODE Z` codecs with the given names (`zlib`, or the name of a codec added
+    /// with [`compression_codec`]), e.g. to leave out one that costs too much CPU time. Without
+    /// any, `MODE Z` isn't offered at all. By default, all of them are.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // Clients that ask for `MODE Z` get a `504` reply.
+    /// let server = Server::with_root("/tmp").allowed_codecs(&[]);
+    /// ```
+    ///
+    /// [`compression_codec`]: #method.compression_codec
+    pub fn allowed_codecs(mut self, names: &[&str]) -> Self {
+        self.allowed_codecs = Some(names.iter().map(|name| name.to_lowercase()).collect());
+        self
+    }
+
+    // The codecs sessions may choose from for `MODE Z`, zlib first.
+    fn mode_z_codecs(&self) -> Vec<Arc<dyn compression::Codec + Send + Sync>> {
+        let zlib: Arc<dyn compression::Codec + Send + Sync> =
+            Arc::new(compression::Zlib::new(self.deflate_level));
+        std::iter::once(zlib)
+            .chain(self.compression_codecs.iter().map(Arc::clone))
+            .filter(|codec| match &self.allowed_codecs {
+                Some(allowed) => allowed.contains(&codec.name().to_lowercase()),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Read files ahead of the data connection during `RETR`: up to `depth` reads of `chunk_size`
+    /// bytes from the storage backend are done before the data is sent to the client. For
+    /// backends with a high latency per read, like object stores, this keeps the throughput from
+    /// being bounded by that latency. It costs up to `chunk_size * depth` bytes of memory per
+    /// transfer. A `depth` of `0` turns read-ahead off, which is the default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // Keep up to 4 reads of 1MiB in flight.
+    /// let server = Server::with_root("/tmp").read_ahead(1 << 20, 4);
+    /// ```
+    pub fn read_ahead(mut self, chunk_size: usize, depth: usize) -> Self {
+        self.read_ahead = if chunk_size > 0 && depth > 0 {
+            Some(readahead::ReadAhead { chunk_size, depth })
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Limit the combined bandwidth of all data transfers to the given number of bytes per
+    /// second. The bandwidth is shared between the running transfers by the
+    /// [`TransferPriority`] of their users. By default the bandwidth isn't limited.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // 10 MiB/s
+    /// let server = Server::with_root("/tmp").bandwidth_limit(10 * 1024 * 1024);
+    /// ```
+    ///
+    /// [`TransferPriority`]: ../bandwidth/enum.TransferPriority.html
+    pub fn bandwidth_limit(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth_limiter = Some(Arc::new(bandwidth::BandwidthLimiter::new(bytes_per_second)));
+        self
+    }
+
+    /// Render file sizes in human-readable form (e.g. `1.5K`) in `LIST` output, for the benefit
+    /// of interactive users. This is off by default, because many clients parse the exact
+    /// sizes from the listing. Machine-oriented replies always stay numeric.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").human_readable_sizes(true);
+    /// ```
+    pub fn human_readable_sizes(mut self, enabled: bool) -> Self {
+        self.list_options.human_readable_sizes = enabled;
+        self
+    }
+
+    /// Set what happens to file names with control characters (e.g. a line feed) in `LIST`
+    /// output, which would otherwise corrupt the listing. By default they are replaced with `?`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::storage::ControlChars;
+    ///
+    /// let server = Server::with_root("/tmp").control_chars_in_listings(ControlChars::Skip);
+    /// ```
+    pub fn control_chars_in_listings(mut self, policy: storage::ControlChars) -> Self {
+        self.list_options.control_chars = policy;
+        self
+    }
+
+    /// Register a custom `SITE` subcommand. The handler receives the arguments following the
+    /// subcommand name and returns the complete reply for the client. The given description is
+    /// shown to clients that issue `SITE HELP`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").site_command("PING", "Check that we're alive", |_| {
+    ///     "200 PONG\r\n".to_string()
+    /// });
+    /// ```
+    pub fn site_command<F>(mut self, name: &str, description: &str, handler: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.site_commands).register(name, description, handler);
+        self
+    }
+
+    /// Register a custom `SITE` subcommand with an asynchronous handler, for operations that take
+    /// a while (e.g. triggering a job and waiting for it to be accepted). The handler receives the
+    /// [`SiteContext`] of the session and the arguments following the subcommand name, and
+    /// resolves to the complete reply for the client. If it fails, the client gets a `451` reply.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use futures::future::{self, Future};
+    ///
+    /// let server = Server::with_root("/tmp").site_command_async(
+    ///     "PURGE",
+    ///     "Purge the CDN cache for a path",
+    ///     |context, path| {
+    ///         let path = context.cwd.join(path);
+    ///         // E.g. call the CDN's API here
+    ///         future::ok(format!("200 Purged {}\r\n", path.display()))
+    ///     },
+    /// );
+    /// ```
+    ///
+    /// [`SiteContext`]: ../site/struct.SiteContext.html
+    pub fn site_command_async<F, R>(mut self, name: &str, description: &str, handler: F) -> Self
+    where
+        F: Fn(site::SiteContext, &str) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = String, Error = ()>,
+        R::Future: Send + 'static,
+    {
+        Arc::make_mut(&mut self.site_commands).register_async(name, description, handler);
+        self
+    }
+
+    /// Set what happens when a client uploads to a path that another session is still writing
+    /// to. By default the second upload is rejected, so writes of the two sessions never get
+    /// mixed up.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::server::ConcurrentWrites;
+    ///
+    /// let server = Server::with_root("/tmp").concurrent_writes(ConcurrentWrites::Queue);
+    /// ```
+    pub fn concurrent_writes(mut self, policy: ConcurrentWrites) -> Self {
+        self.concurrent_writes = policy;
+        self
+    }
+
+    /// Set what happens when an upload runs out of space. By default, partially written files
+    /// stay where they are, and all uploads are refused until there's 64MiB of free space again.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::server::StorageFullPolicy;
+    ///
+    /// let server = Server::with_root("/tmp").storage_full_policy(StorageFullPolicy {
+    ///     delete_partial: true,
+    ///     read_only_until_free: Some(1 << 30),
+    /// });
+    /// ```
+    pub fn storage_full_policy(mut self, policy: StorageFullPolicy) -> Self {
+        self.storage_full_policy = policy;
+        self
+    }
+
+    /// Set which data connections the server makes in active mode. By default, it refuses
+    /// `PORT` and `EPRT` commands for ports below 1024 and for its own addresses.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::server::ActiveModePolicy;
+    ///
+    /// let server = Server::with_root("/tmp").active_mode_policy(ActiveModePolicy {
+    ///     allow_privileged_ports: true,
+    ///     ..ActiveModePolicy::default()
+    /// });
+    /// ```
+    pub fn active_mode_policy(mut self, policy: ActiveModePolicy) -> Self {
+        self.active_mode_policy = policy;
+        self
+    }
+
+    /// Set where the sessions keep their temporary files, and how much each may keep there. See
+    /// [`StagingArea`] for the defaults.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::staging::StagingArea;
+    ///
+    /// let server = Server::with_root("/tmp").staging(StagingArea::new("/var/tmp/ftp").limit(1 << 30));
+    /// ```
+    ///
+    /// [`StagingArea`]: ../staging/struct.StagingArea.html
+    pub fn staging(mut self, area: staging::StagingArea) -> Self {
+        self.staging = area;
+        self
+    }
+
+    /// Set whether uploads go to the staging directory of the session first, and only to the
+    /// storage backend once they're complete. Uploads that fail or get aborted then never leave a
+    /// partial file in the storage, whatever the backend, at the cost of writing every upload
+    /// twice. An upload can't be bigger than the limit of the [`staging`] area. Disabled by
+    /// default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").atomic_uploads(true);
+    /// ```
+    ///
+    /// [`staging`]: #method.staging
+    pub fn atomic_uploads(mut self, enabled: bool) -> Self {
+        self.atomic_uploads = enabled;
+        self
+    }
+
+    /// Set whether control connections start with a PROXY protocol header, which is what to use
+    /// when the server runs behind a TCP load balancer like HAProxy. The client address from the
+    /// header is then used for the sessions, in the logs, in the [`SessionListener`] events and
+    /// for the `Allowlist`. Headers are only read from the [`load_balancer`]s, since anyone else
+    /// could claim any address with one. Disabled by default.
+    ///
+    /// [`SessionListener`]: ../events/trait.SessionListener.html
+    /// [`load_balancer`]: #method.load_balancer
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::server::ProxyProtocol;
+    ///
+    /// let server = Server::with_root("/tmp")
+    ///     .proxy_protocol(ProxyProtocol::Required)
+    ///     .load_balancer("10.0.1.0/24".parse().unwrap());
+    /// ```
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> Self {
+        self.proxy_protocol = mode;
+        self
+    }
+
+    /// Read the PROXY protocol header of the connections from the load balancers in the given
+    /// network, when the [`proxy_protocol`] is enabled. Call this once for every network. None
+    /// are trusted by default, so no header is ever read until this is called.
+    ///
+    /// [`proxy_protocol`]: #method.proxy_protocol
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::server::ProxyProtocol;
+    ///
+    /// let server = Server::with_root("/tmp")
+    ///     .proxy_protocol(ProxyProtocol::Optional)
+    ///     .load_balancer("10.0.1.0/24".parse().unwrap())
+    ///     .load_balancer("fd00::10/128".parse().unwrap());
+    /// ```
+    pub fn load_balancer(mut self, network: Network) -> Self {
+        Arc::make_mut(&mut self.load_balancers).push(network);
+        self
+    }
+
+    /// Record the metrics of the server in the given registry, instead of in one of its own, so
+    /// they can be read while the server runs.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::metrics::Metrics;
+    /// use std::sync::Arc;
+    ///
+    /// let metrics = Arc::new(Metrics::new());
+    /// let server = Server::with_root("/tmp").metrics(Arc::clone(&metrics));
+    /// ```
+    pub fn metrics(mut self, metrics: Arc<metrics::Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Log a line with statistics, like the percentiles of the transfer throughput, at the given
+    /// interval. Off by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::with_root("/tmp").stats_log_interval(Duration::from_secs(300));
+    /// ```
+    pub fn stats_log_interval(mut self, interval: std::time::Duration) -> Self {
+        self.stats_log_interval = Some(interval);
+        self
+    }
+
+    /// Only accept connections from the clients on the given [`Allowlist`]. Everyone may connect
+    /// by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use firetrap::allowlist::Allowlist;
+    ///
+    /// let allowlist = Allowlist::new()
+    ///     .network("192.0.2.0/24".parse().unwrap())
+    ///     .hostname("ftp.partner.example");
+    /// let server = Server::with_root("/tmp").allowlist(allowlist);
+    /// ```
+    ///
+    /// [`Allowlist`]: ../allowlist/struct.Allowlist.html
+    pub fn allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = Some(Arc::new(allowlist));
+        self
+    }
+
+    /// Accept `SITE IDNT` from the FTP proxies in the given network, which tell who the client
+    /// really is before it logs in, e.g. `SITE IDNT alice@192.0.2.7:50123`. The address then
+    /// takes the place of the proxy's in the logs, the events and the `Allowlist`, which is checked
+    /// again; the address of the proxy moves to [`ConnectionInfo::proxy`]. Call this once for
+    /// every network. No proxy is trusted by default, and `SITE IDNT` is refused from anyone
+    /// else.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp")
+    ///     .trusted_proxy("10.0.0.0/24".parse().unwrap())
+    ///     .trusted_proxy("fd00::1/128".parse().unwrap());
+    /// ```
+    ///
+    /// [`ConnectionInfo::proxy`]: ../events/struct.ConnectionInfo.html#structfield.proxy
+    pub fn trusted_proxy(mut self, network: Network) -> Self {
+        Arc::make_mut(&mut self.trusted_proxies).push(network);
+        self
+    }
+
+    /// Offer `MODE X` to clients, for lossy networks that corrupt data without TCP noticing. In
+    /// that mode, the data of every transfer (listings too) is sent in blocks of at most 1 MiB:
+    /// the sequence number of the block (counting from 0) and the length of its payload, as
+    /// 32-bit big endian numbers, then the payload, then the CRC32C of all that, also big endian.
+    /// The receiving end answers every block with a single byte on the data connection, `0x06`
+    /// (ACK) to accept it or `0x15` (NAK) to get it again; anything but ACK counts as NAK, and a
+    /// repeated block that was already accepted is acknowledged again. An empty block ends the
+    /// transfer, and a block that was corrupted 9 times fails it. This is our own extension, so
+    /// it needs a client that knows about it. Off by default; `FEAT` lists `MODE X` when on.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").data_integrity(true);
+    /// ```
+    pub fn data_integrity(mut self, enabled: bool) -> Self {
+        self.data_integrity = enabled;
+        self
+    }
+
+    /// Let `RMD` remove directories that aren't empty, together with everything in them. Only
+    /// users for whom the [`Authorizer`] allows [`Operation::RemoveDirectoryRecursively`] on the
+    /// directory get this; `RMD` still fails on non-empty directories for everybody else. Off by
+    /// default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").recursive_rmd(true);
+    /// ```
+    ///
+    /// [`Authorizer`]: ../auth/authorization/trait.Authorizer.html
+    /// [`Operation::RemoveDirectoryRecursively`]: ../auth/authorization/enum.Operation.html#variant.RemoveDirectoryRecursively
+    pub fn recursive_rmd(mut self, enabled: bool) -> Self {
+        self.recursive_rmd = enabled;
+        self
     }
 
-    /// Set the greeting that will be sent to the client after connecting.
+    /// Let `SITE DEBUG` reply with the [support bundle] of the server, which tells a lot about
+    /// its configuration and the system it runs on. Only users for whom the [`Authorizer`]
+    /// allows [`Operation::Debug`] get it, and the default authorizer allows everything, so only
+    /// turn this on together with an authorizer that keeps it to the admins. Off by default.
     ///
     /// # Example
     ///
     /// ```rust
     /// use firetrap::Server;
     ///
-    /// // Use it in a builder-like pattern:
-    /// let mut server = Server::with_root("/tmp").greeting("Welcome to my FTP Server");
+    /// let server = Server::with_root("/tmp").site_debug(true);
+    /// ```
     ///
-    /// // Or instead if you prefer:
-    /// let mut server = Server::with_root("/tmp");
-    /// server.greeting("Welcome to my FTP Server");
+    /// [support bundle]: struct.ServerHandle.html#method.support_bundle
+    /// [`Authorizer`]: ../auth/authorization/trait.Authorizer.html
+    /// [`Operation::Debug`]: ../auth/authorization/enum.Operation.html#variant.Debug
+    pub fn site_debug(mut self, enabled: bool) -> Self {
+        self.site_debug = enabled;
+        self
+    }
+
+    /// Close sessions that have been connected for longer than the given lifetime, even if the
+    /// client keeps them busy (e.g. with a `NOOP` every few seconds). The client gets a `421`
+    /// reply, and the [`SessionListener`] a [`SessionExpired`] event. By default sessions can
+    /// last forever.
+    ///
+    /// A sweep checks the sessions every [`session_sweep_interval`], so they may last up to that
+    /// much longer.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::with_root("/tmp").max_session_lifetime(Duration::from_secs(24 * 60 * 60));
     /// ```
-    pub fn greeting(mut self, greeting: &'static str) -> Self {
-        self.greeting = greeting;
+    ///
+    /// [`SessionListener`]: ../events/trait.SessionListener.html
+    /// [`SessionExpired`]: ../events/struct.SessionExpired.html
+    /// [`session_sweep_interval`]: #method.session_sweep_interval
+    pub fn max_session_lifetime(mut self, lifetime: std::time::Duration) -> Self {
+        self.max_session_lifetime = Some(lifetime);
         self
     }
 
-    /// Set the range of passive ports that we'll use for passive connections.
+    /// Give commands at most the given time to get their work done, e.g. a `SIZE` or `LIST`
+    /// against a slow storage backend. A command that takes longer gets a `451` reply, and the
+    /// session continues with the next command, dropping the reply of the stuck one if it comes
+    /// after all. Transfers only have to get going in time: once it has started, a big file can
+    /// take as long as it needs. By default commands get all the time they want.
     ///
     /// # Example
     ///
     /// ```rust
     /// use firetrap::Server;
+    /// use std::time::Duration;
     ///
-    /// // Use it in a builder-like pattern:
-    /// let mut server = Server::with_root("/tmp").passive_ports(49152..65535);
+    /// let server = Server::with_root("/tmp").command_timeout(Duration::from_secs(30));
+    /// ```
+    pub fn command_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Set how many bytes of replies may wait for a client that doesn't read them from the control
+    /// connection. Once they're full, the session stops taking commands until the client catches
+    /// up, instead of holding on to ever more replies. The default is 64 KiB.
     ///
+    /// # Example
     ///
-    /// // Or instead if you prefer:
-    /// let mut server = Server::with_root("/tmp");
-    /// server.passive_ports(49152..65535);
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").reply_buffer(16 * 1024);
     /// ```
-    pub fn passive_ports(mut self, range: std::ops::Range<u16>) -> Self {
-        let mut addrs = vec![];
-        for port in range {
-            let ip = std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0));
-            let addr = std::net::SocketAddr::new(ip, port);
-            addrs.push(addr);
-        }
-        self.passive_addrs = Arc::new(addrs);
+    pub fn reply_buffer(mut self, bytes: usize) -> Self {
+        self.reply_buffer = bytes;
+        self
+    }
+
+    /// Set how long a client may leave its replies unread before it's disconnected, see
+    /// [`reply_buffer`]. The default is a minute.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::with_root("/tmp").reply_timeout(Duration::from_secs(10));
+    /// ```
+    ///
+    /// [`reply_buffer`]: #method.reply_buffer
+    pub fn reply_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.reply_timeout = timeout;
+        self
+    }
+
+    /// Set how often the sweep for [`max_session_lifetime`] runs. The default is once a minute.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::with_root("/tmp")
+    ///     .max_session_lifetime(Duration::from_secs(60 * 60))
+    ///     .session_sweep_interval(Duration::from_secs(10));
+    /// ```
+    ///
+    /// [`max_session_lifetime`]: #method.max_session_lifetime
+    pub fn session_sweep_interval(mut self, interval: std::time::Duration) -> Self {
+        self.session_sweep_interval = interval;
+        self
+    }
+
+    /// Set the [`Catalog`] with the translations of the reply texts, for clients that choose a
+    /// language other than English with `LANG`. The languages of the catalog are advertised in
+    /// the `FEAT` reply.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::catalog::Catalog;
+    /// use firetrap::Server;
+    ///
+    /// let mut catalog = Catalog::new();
+    /// catalog.add("de", "Permission denied", "Zugriff verweigert");
+    /// let server = Server::with_root("/tmp").reply_catalog(catalog);
+    /// ```
+    ///
+    /// [`Catalog`]: ../catalog/struct.Catalog.html
+    pub fn reply_catalog(mut self, catalog: Catalog) -> Self {
+        self.catalog = Arc::new(catalog);
+        self
+    }
+
+    /// Set the [`Quirks`] profiles of clients that need their replies adjusted, recognized by
+    /// the name they give with `CLNT`. By default the [`builtin`] profiles are used, pass an
+    /// empty `Quirks` to send every client the same replies.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::quirks::{Profile, Quirk, Quirks};
+    /// use firetrap::Server;
+    ///
+    /// let mut quirks = Quirks::builtin();
+    /// quirks.add(Profile::new("in-house", "OurUploader").quirk(Quirk::NoFeatures));
+    /// let server = Server::with_root("/tmp").quirks(quirks);
+    /// ```
+    ///
+    /// [`Quirks`]: ../quirks/struct.Quirks.html
+    /// [`builtin`]: ../quirks/struct.Quirks.html#method.builtin
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = Arc::new(quirks);
+        self
+    }
+
+    /// Set the port that active mode (`PORT` and `EPRT`) data connections are made from. By
+    /// default this is the port just below the port of the control connection (e.g. `20` when
+    /// listening on port `21`). Use `0` to let the operating system pick any free port. When the
+    /// port can't be used, e.g. because the server lacks the privileges to bind to it, any free
+    /// port is used instead.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").active_source_port(2020);
+    /// ```
+    pub fn active_source_port(mut self, port: u16) -> Self {
+        self.active_source_port = Some(port);
         self
     }
 
@@ -590,6 +2934,157 @@ where
         self
     }
 
+    /// Set the [`Authorizer`] that decides which operations an authenticated user may perform.
+    /// By default everything is allowed.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::auth::authorization::AllowAll;
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").authorizer(&AllowAll {});
+    /// ```
+    ///
+    /// [`Authorizer`]: ../auth/authorization/trait.Authorizer.html
+    pub fn authorizer<A: Authorizer + Send + Sync>(mut self, authorizer: &'static A) -> Self {
+        self.authorizer = authorizer;
+        self
+    }
+
+    /// Set the [`SessionListener`] that is notified about sessions and their transfers. By default
+    /// the events are discarded.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::events::{SessionEnded, SessionListener};
+    /// use firetrap::Server;
+    ///
+    /// struct Siem;
+    ///
+    /// impl SessionListener for Siem {
+    ///     fn session_ended(&self, event: &SessionEnded) {
+    ///         println!("{:?} sent {:.1} commands/s", event.username, event.commands_per_second());
+    ///     }
+    /// }
+    ///
+    /// let server = Server::with_root("/tmp").session_listener(&Siem);
+    /// ```
+    ///
+    /// [`SessionListener`]: ../events/trait.SessionListener.html
+    pub fn session_listener<L: SessionListener + Send + Sync>(
+        mut self,
+        listener: &'static L,
+    ) -> Self {
+        self.session_listener = listener;
+        self
+    }
+
+    /// Set the [`RandomSource`] used for unpredictable names, like those of files created with
+    /// `STOU`, and for session IDs. By default a cryptographically secure generator is used.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::random::DeterministicRandom;
+    /// use firetrap::Server;
+    ///
+    /// static RANDOM: DeterministicRandom = DeterministicRandom::new(42);
+    ///
+    /// // Predictable names, for testing only!
+    /// let server = Server::with_root("/tmp").random_source(&RANDOM);
+    /// ```
+    ///
+    /// [`RandomSource`]: ../random/trait.RandomSource.html
+    pub fn random_source<R: RandomSource + Send + Sync>(mut self, random: &'static R) -> Self {
+        self.random = random;
+        self
+    }
+
+    /// Set the kind of runtime the server runs on. See [`RuntimeFlavor`] for the options.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::server::RuntimeFlavor;
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").runtime_flavor(RuntimeFlavor::MultiThreaded);
+    /// ```
+    ///
+    /// [`RuntimeFlavor`]: enum.RuntimeFlavor.html
+    pub fn runtime_flavor(mut self, flavor: RuntimeFlavor) -> Self {
+        self.runtime.flavor = flavor;
+        self
+    }
+
+    /// Set the number of worker threads of the multi-threaded runtime. Defaults to the number of
+    /// CPU cores.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").worker_threads(2);
+    /// ```
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        self.runtime.worker_threads = Some(n);
+        self
+    }
+
+    /// Set the maximum number of threads of the multi-threaded runtime that run blocking
+    /// operations, like filesystem access. Defaults to 100.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// // E.g. for a small ARM box
+    /// let server = Server::with_root("/tmp").worker_threads(1).blocking_threads(4);
+    /// ```
+    pub fn blocking_threads(mut self, n: usize) -> Self {
+        self.runtime.blocking_threads = Some(n);
+        self
+    }
+
+    /// Accept new connections on the thread that calls [`listen`] instead of on one of the
+    /// workers of the multi-threaded runtime, so busy sessions can't delay accepting new ones.
+    /// Off by default. This has no effect on the current-thread runtime.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp").pin_accept_loop(true);
+    /// ```
+    ///
+    /// [`listen`]: #method.listen
+    pub fn pin_accept_loop(mut self, enabled: bool) -> Self {
+        self.runtime.pin_accept_loop = enabled;
+        self
+    }
+
+    /// Returns the verbs of the commands this server implements, in upper case and ordered
+    /// alphabetically. See [`CoverageReport`] for how they relate to the FTP RFCs.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    ///
+    /// let server = Server::with_root("/tmp");
+    /// assert!(server.supported_commands().contains(&"RETR"));
+    /// ```
+    ///
+    /// [`CoverageReport`]: ../coverage/struct.CoverageReport.html
+    pub fn supported_commands(&self) -> Vec<&'static str> {
+        commands::SUPPORTED.to_vec()
+    }
+
     /// Start the server and listen for connections on the given address.
     ///
     /// # Example
@@ -608,28 +3103,341 @@ where
     ///
     /// This function panics when called with invalid addresses or when the process is unable to
     /// `bind()` to the address.
-    pub fn listen(self, addr: &str) {
+    pub fn listen(mut self, addr: &str) {
         let addr = addr.parse().unwrap();
         let listener = TcpListener::bind(&addr).unwrap();
+        let local = listener.local_addr().unwrap_or(addr);
+        *self.listening.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((local, SystemTime::now()));
+        if self.site_debug {
+            self.handle = Some(self.handle());
+        }
+        let runtime = self.runtime;
+        let background = self
+            .sweep()
+            .join3(self.stats_log(), self.allowlist_refresh())
+            .map(|_| ());
+
+        match runtime.flavor {
+            RuntimeFlavor::CurrentThread => {
+                let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+                rt.spawn(background);
+                let accept = listener
+                    .incoming()
+                    .map_err(|e| warn!("Failed to accept socket: {}", e))
+                    .for_each(move |socket| {
+                        tokio::spawn(self.process(socket));
+                        Ok(())
+                    });
+                rt.block_on(accept).unwrap();
+            }
+            RuntimeFlavor::MultiThreaded => {
+                let mut builder = tokio::runtime::Builder::new();
+                if let Some(n) = runtime.worker_threads {
+                    builder.core_threads(n);
+                }
+                if let Some(n) = runtime.blocking_threads {
+                    builder.blocking_threads(n);
+                }
+                let mut rt = builder.build().unwrap();
+                rt.spawn(background);
+
+                if runtime.pin_accept_loop {
+                    // Accept on this thread, with its own reactor, and hand the sessions to the
+                    // workers.
+                    let executor = rt.executor();
+                    let mut accept_rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+                    let accept = listener
+                        .incoming()
+                        .map_err(|e| warn!("Failed to accept socket: {}", e))
+                        .for_each(move |socket| {
+                            executor.spawn(self.process(socket));
+                            Ok(())
+                        });
+                    accept_rt.block_on(accept).unwrap();
+                } else {
+                    let accept = listener
+                        .incoming()
+                        .map_err(|e| warn!("Failed to accept socket: {}", e))
+                        .for_each(move |socket| {
+                            tokio::spawn(self.process(socket));
+                            Ok(())
+                        });
+                    rt.spawn(accept);
+                    rt.shutdown_on_idle().wait().unwrap();
+                }
+            }
+        }
+    }
+
+    /// Returns a [`ServerHandle`], to look into the server once it runs, e.g. for its
+    /// [support bundle]. Get it once the server is configured, before calling [`listen`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::Server;
+    /// # use std::thread;
+    ///
+    /// let server = Server::with_root("/srv/ftp");
+    /// let handle = server.handle();
+    /// # thread::spawn(move || {
+    /// server.listen("127.0.0.1:2001");
+    /// # });
+    /// // ...and later, when something's off:
+    /// eprintln!("{}", handle.support_bundle());
+    /// ```
+    ///
+    /// [`ServerHandle`]: struct.ServerHandle.html
+    /// [support bundle]: struct.ServerHandle.html#method.support_bundle
+    /// [`listen`]: #method.listen
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            configuration: Arc::new(self.configuration().to_string()),
+            metrics: Arc::clone(&self.metrics),
+            listening: Arc::clone(&self.listening),
+        }
+    }
+
+    // The configuration of the server, for the support bundle.
+    fn configuration(&self) -> support::Json {
+        let seconds = |duration: Option<std::time::Duration>| duration.map(|d| d.as_secs_f64());
+        let ports = self.passive_addrs.iter().map(|addr| addr.port());
+        let passive_host = match &self.passive_host {
+            PassiveHost::Listener => "listener".to_string(),
+            PassiveHost::Ip(ip) => ip.to_string(),
+            PassiveHost::Resolver(_) => "resolver".to_string(),
+        };
+        let codecs: Vec<String> = self
+            .mode_z_codecs()
+            .iter()
+            .map(|codec| codec.name().to_string())
+            .collect();
+        let runtime = support::Json::new()
+            .string("flavor", format!("{:?}", self.runtime.flavor))
+            .optional_number("worker_threads", self.runtime.worker_threads)
+            .optional_number("blocking_threads", self.runtime.blocking_threads)
+            .boolean("pin_accept_loop", self.runtime.pin_accept_loop);
+        let policy = self.storage_full_policy;
+        let storage_full_policy = support::Json::new()
+            .boolean("delete_partial", policy.delete_partial)
+            .optional_number("read_only_until_free", policy.read_only_until_free);
+        let policy = self.active_mode_policy;
+        let active_mode_policy = support::Json::new()
+            .boolean("allow_privileged_ports", policy.allow_privileged_ports)
+            .boolean("allow_server_addresses", policy.allow_server_addresses);
+        support::Json::new()
+            .string(
+                "storage",
+                metrics::short_type_name(std::any::type_name::<S>()),
+            )
+            .optional("passive_ports_min", ports.clone().min())
+            .optional("passive_ports_max", ports.max())
+            .string("passive_host", passive_host)
+            .strings("mode_z_codecs", codecs)
+            .number("deflate_level", self.deflate_level)
+            .optional(
+                "read_ahead",
+                self.read_ahead
+                    .map(|read_ahead| format!("{}x{}", read_ahead.depth, read_ahead.chunk_size)),
+            )
+            .optional_number(
+                "bandwidth_limit",
+                self.bandwidth_limiter
+                    .as_ref()
+                    .map(|limiter| limiter.bytes_per_second()),
+            )
+            .boolean(
+                "human_readable_sizes",
+                self.list_options.human_readable_sizes,
+            )
+            .string(
+                "control_chars",
+                format!("{:?}", self.list_options.control_chars),
+            )
+            .strings(
+                "site_commands",
+                self.site_commands.descriptions().map(|(name, _)| name),
+            )
+            .optional_number("active_source_port", self.active_source_port)
+            .object("runtime", runtime)
+            .string("concurrent_writes", format!("{:?}", self.concurrent_writes))
+            .boolean("recursive_rmd", self.recursive_rmd)
+            .boolean("site_debug", self.site_debug)
+            .boolean("data_integrity", self.data_integrity)
+            .optional_number(
+                "max_session_lifetime_seconds",
+                seconds(self.max_session_lifetime),
+            )
+            .optional_number("command_timeout_seconds", seconds(self.command_timeout))
+            .number("reply_buffer", self.reply_buffer)
+            .number("reply_timeout_seconds", self.reply_timeout.as_secs_f64())
+            .object("storage_full_policy", storage_full_policy)
+            .object("active_mode_policy", active_mode_policy)
+            .boolean("atomic_uploads", self.atomic_uploads)
+            .string("proxy_protocol", format!("{:?}", self.proxy_protocol))
+            .strings("load_balancers", self.load_balancers.iter())
+            .optional_number(
+                "stats_log_interval_seconds",
+                seconds(self.stats_log_interval),
+            )
+            .boolean("allowlist", self.allowlist.is_some())
+            .strings("trusted_proxies", self.trusted_proxies.iter())
+    }
 
-        tokio::run({
-            listener
-                .incoming()
-                .map_err(|e| warn!("Failed to accept socket: {}", e))
-                .for_each(move |socket| {
-                    self.process(socket);
+    // Returns the task that periodically closes the sessions that exceeded the maximum session
+    // lifetime. It also forgets about sessions that are gone without us noticing.
+    fn sweep(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let max_lifetime = match self.max_session_lifetime {
+            Some(max_lifetime) => max_lifetime,
+            None => return Box::new(futures::future::ok(())),
+        };
+        let live_sessions = Arc::clone(&self.live_sessions);
+        Box::new(
+            tokio::timer::Interval::new_interval(self.session_sweep_interval)
+                .map_err(|e| warn!("Failed to sweep the sessions: {}", e))
+                .for_each(move |_| {
+                    let mut live_sessions = match live_sessions.lock() {
+                        Ok(live_sessions) => live_sessions,
+                        Err(_) => return Ok(()),
+                    };
+                    live_sessions.retain(|id, session| {
+                        if session.tx.is_closed() {
+                            return false;
+                        }
+                        if session.start.elapsed() < max_lifetime {
+                            return true;
+                        }
+                        info!(
+                            "Closing connection {}: it exceeded the maximum lifetime",
+                            id
+                        );
+                        tokio::spawn(
+                            session
+                                .tx
+                                .clone()
+                                .send(InternalMsg::LifetimeExceeded)
+                                .map(|_| ())
+                                .map_err(|_| ()),
+                        );
+                        false
+                    });
                     Ok(())
-                })
-        });
+                }),
+        )
+    }
+
+    // Returns the task that periodically logs the statistics of the server.
+    fn stats_log(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let interval = match self.stats_log_interval {
+            Some(interval) => interval,
+            None => return Box::new(futures::future::ok(())),
+        };
+        let metrics = Arc::clone(&self.metrics);
+        Box::new(
+            tokio::timer::Interval::new_interval(interval)
+                .map_err(|e| warn!("Failed to log the stats: {}", e))
+                .for_each(move |_| {
+                    info!("Stats: throughput {}", metrics.summary());
+                    Ok(())
+                }),
+        )
+    }
+
+    // Returns the task that keeps the hostnames on the allowlist resolved.
+    fn allowlist_refresh(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        match &self.allowlist {
+            Some(allowlist) => Arc::clone(allowlist).refresher(),
+            None => Box::new(futures::future::ok(())),
+        }
     }
 
-    fn process(&self, socket: TcpStream) {
+    // Sets up a new session for the given control connection, and returns the task that handles
+    // it.
+    fn process(&self, socket: TcpStream) -> Box<dyn Future<Item = (), Error = ()> + Send> {
         let authenticator = self.authenticator;
+        let authorizer = self.authorizer;
+        let session_listener = self.session_listener;
+        let active_source_port = self.active_source_port;
+        let random = self.random;
+        let connection = match connection_info(&socket) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(
+                    "Failed to get the addresses of the control connection: {}",
+                    e
+                );
+                return Box::new(futures::future::ok(()));
+            }
+        };
+        let id = random.unique_name();
+        info!(
+            "Accepted connection {} from {} on {} (ttl {:?})",
+            id, connection.peer, connection.local, connection.ttl
+        );
+        let new_storage = {
+            let generator = Arc::clone(&self.storage);
+            move || -> Arc<S> {
+                let generator = generator
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner);
+                Arc::new(generator())
+            }
+        };
         // TODO: I think we can do with least one `Arc` less...
-        let storage = Arc::new((self.storage)());
-        let session = Arc::new(Mutex::new(Session::with_storage(storage)));
+        let storage = new_storage();
+        let mut session = Session::new(id, storage, connection);
+        session.list_options = self.list_options;
+        session.codecs = Arc::new(self.mode_z_codecs());
+        session.read_ahead = self.read_ahead;
+        session.bandwidth_limiter = self.bandwidth_limiter.clone();
+        session.path_locks = Arc::clone(&self.path_locks);
+        session.concurrent_writes = self.concurrent_writes;
+        session.recursive_rmd = self.recursive_rmd;
+        session.data_integrity = self.data_integrity;
+        session.random = random;
+        session.session_listener = session_listener;
+        session.metrics = Arc::clone(&self.metrics);
+        session.command_timeout = self.command_timeout;
+        session.storage_full_policy = self.storage_full_policy;
+        session.active_mode_policy = self.active_mode_policy;
+        session.storage_full = Arc::clone(&self.storage_full);
+        session.staging = self.staging.session(&session.id);
+        session.atomic_uploads = self.atomic_uploads;
+        let session_id = session.id.clone();
+        let utf8 = Arc::clone(&session.utf8);
+        let session = Arc::new(Mutex::new(session));
+        let session_stats = Arc::clone(&session);
+        let session_auth = Arc::clone(&session);
+        let reply_metrics = Arc::clone(&self.metrics);
+        let server_handle = self.handle.clone();
+        let session_language = Arc::clone(&session);
+        let quirks = Arc::clone(&self.quirks);
+        let catalog = Arc::clone(&self.catalog);
+        let reply_catalog = Arc::clone(&self.catalog);
+        let session_end = Arc::clone(&session);
+        let session_proxied = Arc::clone(&session);
         let (tx, rx): (mpsc::Sender<InternalMsg>, mpsc::Receiver<InternalMsg>) = mpsc::channel(1);
+        let live_sessions = Arc::clone(&self.live_sessions);
+        if self.max_session_lifetime.is_some() {
+            if let Ok(mut live_sessions) = live_sessions.lock() {
+                let start = Instant::now();
+                let tx = tx.clone();
+                live_sessions.insert(session_id.clone(), LiveSession { start, tx });
+            }
+        }
         let passive_addrs = Arc::clone(&self.passive_addrs);
+        let passive_host = self.passive_host.clone();
+        let site_commands = Arc::clone(&self.site_commands);
+        let trusted_proxies = Arc::clone(&self.trusted_proxies);
+        let client_allowlist = self.allowlist.clone();
+        // Commands reply through `tx`, so the replies of the ones that timed out can be dropped.
+        let command_timeout = self.command_timeout;
+        let current_command: replies::Current = Arc::new(Mutex::new(None));
+        let reply_buffer = self.reply_buffer;
+        let reply_timeout = self.reply_timeout;
+        let tx_timeout = tx.clone();
+        let tx = replies::Replies::new(tx, Arc::clone(&current_command));
 
         macro_rules! respond {
             ($closure:expr) => {{
@@ -676,25 +3484,31 @@ where
                             }
                         }
                         Command::Pass { password } => {
-                            let mut session = session.lock()?;
+                            let session = session.lock()?;
                             match session.state {
                                 WaitPass => {
-                                    let pass = std::str::from_utf8(&password)?;
+                                    let pass = std::str::from_utf8(&password)?.to_string();
                                     let user = session.username.clone().unwrap();
-                                    let res = authenticator.authenticate(&user, pass);
-                                    match res {
-                                        Ok(true) => {
-                                            session.state = WaitCmd;
-                                            Ok("230 User logged in, proceed\r\n".to_string())
-                                        }
-                                        Ok(false) => {
-                                            Ok("530 Wrong username or password\r\n".to_string())
-                                        }
-                                        Err(_) => {
-                                            warn!("Unknown Authentication backend failure");
-                                            Ok("530 Failed to authenticate\r\n".to_string())
-                                        }
-                                    }
+                                    let tx = tx.clone();
+                                    tokio::spawn(
+                                        compat(async move {
+                                            let msg = match authenticator
+                                                .authenticate(&user, &pass)
+                                                .await
+                                            {
+                                                Ok(true) => InternalMsg::LoggedIn(user),
+                                                Ok(false) => InternalMsg::WrongPassword,
+                                                Err(()) => InternalMsg::AuthenticationFailed,
+                                            };
+                                            Ok(msg)
+                                        })
+                                        .and_then(|msg| tx.send(msg))
+                                        .map(|_| ())
+                                        .map_err(|e| {
+                                            warn!("Failed to send the PASS reply: {:?}", e);
+                                        }),
+                                    );
+                                    Ok("".to_string())
                                 }
                                 New => Ok("503 Please give me a username first\r\n".to_string()),
                                 _ => Ok("530 Please open a new connection to re-authenticate\r\n"
@@ -707,22 +3521,32 @@ where
                         Command::Syst => respond!(|| Ok("215 UNIX Type: L8\r\n".to_string())),
                         Command::Stat { path } => {
                             ensure_authenticated!();
+                            let session = session.lock()?;
                             match path {
-                                None => Ok("211 I'm just a humble FTP server\r\n".to_string()),
+                                None => Ok(session.status()),
                                 Some(path) => {
-                                    let path = std::str::from_utf8(&path)?;
-                                    // TODO: Implement :)
-                                    info!("Got command STAT {}, but we don't support parameters yet\r\n", path);
-                                    Ok("504 Stat with paths unsupported atm\r\n".to_string())
+                                    // Sends the listing over the control connection, for
+                                    // clients that can't set up a data connection.
+                                    let path = session.cwd.join(std::str::from_utf8(&path)?);
+                                    let listing =
+                                        session.storage.list_fmt(path, session.list_options);
+                                    tokio::spawn(send_stat_listing(listing, tx.clone()));
+                                    Ok("".to_string())
                                 }
                             }
                         }
                         Command::Acct { .. } => {
                             respond!(|| Ok("530 I don't know accounting man\r\n".to_string()))
                         }
-                        Command::Type => respond!(|| Ok(
-                            "200 I'm always in binary mode, dude...\r\n".to_string()
-                        )),
+                        Command::Type { type_ } => respond!(|| {
+                            let ascii = type_ == commands::TypeParam::Ascii;
+                            session.lock()?.ascii = ascii;
+                            if ascii {
+                                Ok("200 Switching to ASCII mode\r\n".to_string())
+                            } else {
+                                Ok("200 Switching to binary mode\r\n".to_string())
+                            }
+                        }),
                         Command::Stru { structure } => {
                             ensure_authenticated!();
                             match structure {
@@ -734,21 +3558,90 @@ where
                         }
                         Command::Mode { mode } => respond!(|| match mode {
                             commands::ModeParam::Stream => {
+                                let mut session = session.lock()?;
+                                session.mode_z = false;
+                                session.mode_x = false;
                                 Ok("200 Using Stream transfer mode\r\n".to_string())
                             }
+                            commands::ModeParam::Deflate if !session.lock()?.codecs.is_empty() => {
+                                let mut session = session.lock()?;
+                                session.mode_z = true;
+                                session.mode_x = false;
+                                Ok("200 Using Deflate transfer mode\r\n".to_string())
+                            }
+                            commands::ModeParam::Integrity if session.lock()?.data_integrity => {
+                                let mut session = session.lock()?;
+                                session.mode_x = true;
+                                session.mode_z = false;
+                                Ok("200 Using Integrity transfer mode\r\n".to_string())
+                            }
                             _ => Ok("504 Only Stream transfer mode is supported\r\n".to_string()),
                         }),
-                        Command::Help => respond!(|| Ok(
-                            "214 We haven't implemented a useful HELP command, sorry\r\n"
-                                .to_string()
-                        )),
+                        // RFC 959 lets clients ask for help before they log in.
+                        Command::Help { topic: None } => {
+                            let mut reply =
+                                "214-The following commands are recognized:\r\n".to_string();
+                            for verbs in commands::SUPPORTED.chunks(8) {
+                                let verbs: Vec<String> =
+                                    verbs.iter().map(|verb| format!("{:5}", verb)).collect();
+                                reply.push_str(&format!(" {}\r\n", verbs.join(" ").trim_end()));
+                            }
+                            reply.push_str("214 Use HELP <command> to learn more about one\r\n");
+                            Ok(reply)
+                        }
+                        Command::Help { topic: Some(topic) } => {
+                            let mut words = topic.split_whitespace();
+                            let verb = words.next().unwrap_or("");
+                            if !verb.eq_ignore_ascii_case("SITE") {
+                                return match commands::help(verb) {
+                                    Some(help) => Ok(format!("214 Syntax: {}\r\n", help)),
+                                    None => Ok(format!(
+                                        "502 Unknown command {}\r\n",
+                                        verb.to_uppercase()
+                                    )),
+                                };
+                            }
+                            // The registered ones, too
+                            match words.next() {
+                                None => Ok(site_commands.help()),
+                                Some(command) => match site_commands
+                                    .descriptions()
+                                    .find(|(name, _)| name.eq_ignore_ascii_case(command))
+                                {
+                                    Some((name, description)) => {
+                                        Ok(format!("214 SITE {}: {}\r\n", name, description))
+                                    }
+                                    None => Ok(format!(
+                                        "502 Unknown SITE command {}\r\n",
+                                        command.to_uppercase()
+                                    )),
+                                },
+                            }
+                        }
                         Command::Noop => {
                             respond!(|| Ok("200 Successfully did nothing\r\n".to_string()))
                         }
-                        Command::Pasv => {
+                        Command::Epsv {
+                            param: commands::EpsvParam::All,
+                        } => {
+                            ensure_authenticated!();
+                            session.lock()?.epsv_all = true;
+                            Ok("200 EPSV ALL ok, only accepting EPSV from now on\r\n".to_string())
+                        }
+                        Command::Epsv {
+                            param: commands::EpsvParam::Protocol(protocol),
+                        } if protocol != 1 => {
+                            ensure_authenticated!();
+                            Ok("522 Network protocol not supported, use (1)\r\n".to_string())
+                        }
+                        Command::Pasv | Command::Epsv { .. } => {
                             ensure_authenticated!();
+                            let extended = cmd != Command::Pasv;
+                            if !extended && session.lock()?.epsv_all {
+                                return Ok("503 PASV not allowed after EPSV ALL\r\n".to_string());
+                            }
 
-                            let listener = std::net::TcpListener::bind(&passive_addrs.as_slice())?;
+                            let listener = std::net::TcpListener::bind(passive_addrs.as_slice())?;
                             let addr = match listener.local_addr()? {
                                 std::net::SocketAddr::V4(addr) => addr,
                                 std::net::SocketAddr::V6(_) => {
@@ -760,25 +3653,20 @@ where
                                 &tokio::reactor::Handle::default(),
                             )?;
 
-                            let octets = addr.ip().octets();
+                            let octets = match &passive_host {
+                                PassiveHost::Listener => *addr.ip(),
+                                PassiveHost::Ip(ip) => *ip,
+                                PassiveHost::Resolver(resolve) => {
+                                    let local = session.lock()?.connection.local;
+                                    resolve(local).unwrap_or_else(|| *addr.ip())
+                                }
+                            }
+                            .octets();
                             let port = addr.port();
                             let p1 = port >> 8;
                             let p2 = port - (p1 * 256);
                             let tx = tx.clone();
-
-                            let (cmd_tx, cmd_rx): (mpsc::Sender<Command>, mpsc::Receiver<Command>) =
-                                mpsc::channel(1);
-                            let (data_abort_tx, data_abort_rx): (
-                                mpsc::Sender<()>,
-                                mpsc::Receiver<()>,
-                            ) = mpsc::channel(1);
-                            {
-                                let mut session = session.lock()?;
-                                session.data_cmd_tx = Some(cmd_tx);
-                                session.data_cmd_rx = Some(cmd_rx);
-                                session.data_abort_tx = Some(data_abort_tx);
-                                session.data_abort_rx = Some(data_abort_rx);
-                            }
+                            let replaced = session.lock()?.prepare_data_channel();
 
                             let session = session.clone();
                             tokio::spawn(Box::new(
@@ -787,28 +3675,80 @@ where
                                     .take(1)
                                     .map_err(|e| warn!("Failed to accept data socket: {:?}", e))
                                     .for_each(move |socket| {
-                                        let tx = tx.clone();
-                                        let session = session.clone();
-                                        let mut session = session.lock().unwrap_or_else(|res| {
-                                            // TODO: Send signal to `tx` here, so we can handle the
-                                            // error
-                                            error!("session lock() result: {}", res);
-                                            panic!()
-                                        });
-                                        session.process_data(socket, tx);
+                                        let mut session = session.lock().map_err(|e| {
+                                            error!("session lock() result: {}", e);
+                                        })?;
+                                        session.process_data(socket, tx.sender());
                                         Ok(())
-                                    }),
+                                    })
+                                    .select(replaced)
+                                    .map(|_| ())
+                                    .map_err(|_| ()),
                             ));
 
-                            Ok(format!(
-                                "227 Entering Passive Mode ({},{},{},{},{},{})\r\n",
-                                octets[0], octets[1], octets[2], octets[3], p1, p2
-                            ))
+                            if extended {
+                                // The client connects to the address of the control connection,
+                                // so we only tell it the port.
+                                Ok(format!(
+                                    "229 Entering Extended Passive Mode (|||{}|)\r\n",
+                                    port
+                                ))
+                            } else {
+                                Ok(format!(
+                                    "227 Entering Passive Mode ({},{},{},{},{},{})\r\n",
+                                    octets[0], octets[1], octets[2], octets[3], p1, p2
+                                ))
+                            }
                         }
-                        Command::Port => {
+                        Command::Eprt { addr } | Command::Port { addr } => {
                             ensure_authenticated!();
-                            Ok("502 ACTIVE mode is not supported - use PASSIVE instead\r\n"
-                                .to_string())
+                            let name = if let Command::Port { .. } = cmd {
+                                "PORT"
+                            } else {
+                                "EPRT"
+                            };
+                            let (replaced, local) = {
+                                let mut session = session.lock()?;
+                                if session.epsv_all {
+                                    return Ok(format!(
+                                        "503 {} not allowed after EPSV ALL\r\n",
+                                        name
+                                    ));
+                                }
+                                let policy = session.active_mode_policy;
+                                if let Some(reason) = policy.refuses(&session.connection, addr) {
+                                    warn!(
+                                        "Refused {} to {} for {} ({})",
+                                        name, addr, session.connection.peer, reason
+                                    );
+                                    session.metrics.record_rejected_port(reason);
+                                    return Ok(format!(
+                                        "504 {} to that address and port not allowed\r\n",
+                                        name
+                                    ));
+                                }
+                                (session.prepare_data_channel(), session.socket_local)
+                            };
+
+                            let tx = tx.clone();
+                            let session = session.clone();
+                            tokio::spawn(
+                                connect_active(local, active_source_port, addr)
+                                    .map_err(move |e| {
+                                        warn!("Failed to connect data socket to {}: {}", addr, e)
+                                    })
+                                    .and_then(move |socket| {
+                                        let mut session = session.lock().map_err(|e| {
+                                            error!("session lock() result: {}", e);
+                                        })?;
+                                        session.process_data(socket, tx.sender());
+                                        Ok(())
+                                    })
+                                    .select(replaced)
+                                    .map(|_| ())
+                                    .map_err(|_| ()),
+                            );
+                            Ok(format!("200 {} command successful\r\n", name))
                         }
                         Command::Retr { .. } => {
                             ensure_authenticated!();
@@ -817,7 +3757,8 @@ where
                                 Some(tx) => tx,
                                 None => return Err(FTPErrorKind::InternalServerError.into()),
                             };
-                            spawn!(tx.send(cmd.clone()));
+                            let range = session.range.take();
+                            spawn!(tx.send((cmd.clone(), range)));
                             // TODO: Return a Option<String> or something, to prevent us from
                             // returning "" ><
                             Ok("".to_string())
@@ -831,7 +3772,19 @@ where
                                     return Ok("425 No data connection established\r\n".to_string());
                                 }
                             };
-                            spawn!(tx.send(cmd.clone()));
+                            spawn!(tx.send((cmd.clone(), None)));
+                            Ok("150 Ready to receive data\r\n".to_string())
+                        }
+                        Command::Appe { .. } => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            let tx = match session.data_cmd_tx.take() {
+                                Some(tx) => tx,
+                                None => {
+                                    return Ok("425 No data connection established\r\n".to_string());
+                                }
+                            };
+                            spawn!(tx.send((cmd.clone(), None)));
                             Ok("150 Ready to receive data\r\n".to_string())
                         }
                         Command::List { .. } => {
@@ -844,7 +3797,7 @@ where
                                     return Ok("425 No data connection established\r\n".to_string());
                                 }
                             };
-                            spawn!(tx.send(cmd.clone()));
+                            spawn!(tx.send((cmd.clone(), None)));
                             Ok("150 Sending directory list\r\n".to_string())
                         }
                         Command::Nlst { .. } => {
@@ -856,15 +3809,120 @@ where
                                     return Ok("425 No data connection established\r\n".to_string());
                                 }
                             };
-                            spawn!(tx.send(cmd.clone()));
+                            spawn!(tx.send((cmd.clone(), None)));
                             Ok("150 Sending directory list\r\n".to_string())
                         }
+                        Command::Rang { range } => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            let reply = match &range {
+                                Some(range) => format!(
+                                    "350 Restarting at {}. Ending byte range at {}\r\n",
+                                    range.start,
+                                    range.end - 1
+                                ),
+                                None => {
+                                    "350 Restarting at 0. Ending byte range at EOF\r\n".to_string()
+                                }
+                            };
+                            session.range = range;
+                            Ok(reply)
+                        }
+                        Command::Clnt { client } => {
+                            let mut session = session.lock()?;
+                            session.quirks = match quirks.for_client(&client) {
+                                Some(profile) => {
+                                    info!("Using the {} quirks for {}", profile.name(), client);
+                                    profile.quirks().to_vec()
+                                }
+                                None => vec![],
+                            };
+                            Ok("200 Noted\r\n".to_string())
+                        }
+                        Command::Lang { tag } => {
+                            let mut session = session.lock()?;
+                            match tag {
+                                None => {
+                                    session.language = None;
+                                    Ok("200 Responses changed to EN\r\n".to_string())
+                                }
+                                Some(tag) => match catalog.find(&tag) {
+                                    Some(language) => {
+                                        let reply =
+                                            format!("200 Responses changed to {}\r\n", language);
+                                        session.language =
+                                            Some(language).filter(|language| language != "EN");
+                                        Ok(reply)
+                                    }
+                                    None => Ok("504 Unsupported parameter\r\n".to_string()),
+                                },
+                            }
+                        }
                         Command::Feat => {
                             ensure_authenticated!();
-                            let response = "211 I support some cool features\r\n\
-                                            211 End\r\n"
-                                .to_string();
-                            Ok(response)
+                            let session = session.lock()?;
+                            if session.quirks.contains(&Quirk::NoFeatures) {
+                                return Ok("211 no-features\r\n".to_string());
+                            }
+                            let algorithms: Vec<String> = storage::HashAlgorithm::all()
+                                .iter()
+                                .map(|&algorithm| {
+                                    if algorithm == session.hash_algorithm {
+                                        format!("{}*", algorithm)
+                                    } else {
+                                        algorithm.to_string()
+                                    }
+                                })
+                                .collect();
+                            // Plain `MODE Z` is zlib; the engines are only worth listing if
+                            // there are others.
+                            let mut modes = String::new();
+                            if session.data_integrity {
+                                modes.push_str("MODE X\r\n ");
+                            }
+                            let codecs: Vec<&str> =
+                                session.codecs.iter().map(|codec| codec.name()).collect();
+                            match codecs.as_slice() {
+                                [] => {}
+                                ["zlib"] => modes.push_str("MODE Z\r\n "),
+                                _ => modes
+                                    .push_str(&format!("MODE Z ENGINE {}\r\n ", codecs.join(";"))),
+                            }
+                            let current = session.language.as_ref().map_or("EN", String::as_str);
+                            let mut languages: Vec<&str> = std::iter::once("EN")
+                                .chain(catalog.languages().filter(|&l| l != "EN"))
+                                .collect();
+                            languages.sort_unstable();
+                            let languages: Vec<String> = languages
+                                .into_iter()
+                                .map(|language| {
+                                    if language == current {
+                                        format!("{}*", language)
+                                    } else {
+                                        language.to_string()
+                                    }
+                                })
+                                .collect();
+                            Ok(format!(
+                                "211-Extensions supported:\r\n \
+                                 EPRT\r\n \
+                                 EPSV\r\n \
+                                 HASH {}\r\n \
+                                 LANG {}\r\n \
+                                 MDTM\r\n \
+                                 MFMT\r\n \
+                                 MLST type*;size*;modify*;UNIX.uid*;UNIX.gid*;\r\n \
+                                 {}\
+                                 RANG STREAM\r\n \
+                                 SIZE\r\n \
+                                 UTF8\r\n \
+                                 XCRC\r\n \
+                                 XMD5\r\n\
+                                 211 End\r\n",
+                                algorithms.join(";"),
+                                languages.join(";"),
+                                modes
+                            ))
                         }
                         Command::Pwd => {
                             ensure_authenticated!();
@@ -873,165 +3931,879 @@ where
                             Ok(format!("257 \"{}\"\r\n", session.cwd.as_path().display()))
                         }
                         Command::Cwd { path } => {
-                            // TODO: We current accept all CWD requests. Consider only allowing
-                            // this if the directory actually exists and the user has the proper
-                            // permission.
-                            respond!(|| {
-                                let mut session = session.lock()?;
-                                session.cwd.push(path);
-                                Ok("250 Okay.\r\n".to_string())
-                            })
+                            ensure_authenticated!();
+                            // Only change into directories that exist, which for object stores
+                            // means that there's something under the prefix, or a marker.
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(path);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let metadata = {
+                                let (storage, path) = (Arc::clone(&storage), path.clone());
+                                compat(async move { storage.stat(path).await })
+                            };
+                            tokio::spawn(
+                                metadata
+                                    .map_err(storage_error)
+                                    .and_then(move |metadata| {
+                                        let msg = if metadata.is_dir() {
+                                            InternalMsg::CwdSuccess(path)
+                                        } else {
+                                            InternalMsg::NotADirectory
+                                        };
+                                        tx_success.send(msg).map_err(|_| {
+                                            std::io::Error::other("Failed to send 'Cwd' message")
+                                        })
+                                    })
+                                    .or_else(|e| {
+                                        tx_fail.send(failure_msg(e, InternalMsg::NotFound)).map_err(
+                                            |_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'NotFound' message",
+                                                )
+                                            },
+                                        )
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to change the working directory: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
                         }
                         Command::Cdup => respond!(|| {
                             let mut session = session.lock()?;
                             session.cwd.pop();
                             Ok("250 Okay.\r\n".to_string())
                         }),
-                        Command::Opts { option } => {
+                        // Clients switch UTF-8 on right away, some even before they log in.
+                        Command::Opts {
+                            option: commands::Opt::UTF8 { enabled },
+                        } => {
+                            let session = session.lock()?;
+                            session.utf8.store(enabled, Ordering::SeqCst);
+                            if enabled {
+                                Ok("200 UTF8 mode enabled\r\n".to_string())
+                            } else {
+                                Ok("200 UTF8 mode disabled, paths are in ISO-8859-1\r\n"
+                                    .to_string())
+                            }
+                        }
+                        Command::Opts {
+                            option: commands::Opt::ModeZ { engine },
+                        } => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            match engine {
+                                None => match session.codec() {
+                                    Some(codec) => {
+                                        Ok(format!("200 MODE Z ENGINE {}\r\n", codec.name()))
+                                    }
+                                    None => Ok("504 MODE Z isn't offered\r\n".to_string()),
+                                },
+                                Some(engine) => {
+                                    let codec = session
+                                        .codecs
+                                        .iter()
+                                        .find(|codec| codec.name().eq_ignore_ascii_case(&engine))
+                                        .map(Arc::clone);
+                                    match codec {
+                                        Some(codec) => {
+                                            let reply =
+                                                format!("200 MODE Z ENGINE {}\r\n", codec.name());
+                                            session.codec = Some(codec);
+                                            Ok(reply)
+                                        }
+                                        None => {
+                                            Ok("501 Unsupported compression engine\r\n".to_string())
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Command::Opts {
+                            option: commands::Opt::Hash { algorithm },
+                        } => {
+                            ensure_authenticated!();
+                            match algorithm {
+                                None => Ok(format!("200 {}\r\n", session.lock()?.hash_algorithm)),
+                                Some(algorithm) => match algorithm.parse() {
+                                    Ok(algorithm) => {
+                                        session.lock()?.hash_algorithm = algorithm;
+                                        Ok(format!("200 {}\r\n", algorithm))
+                                    }
+                                    Err(_) => Ok("501 Unknown algorithm\r\n".to_string()),
+                                },
+                            }
+                        }
+                        Command::Dele { path } => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(path);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let precondition = session.precondition.take();
+                            let file_mutated = session.file_mutated();
+                            tokio::spawn(
+                                file_state(&storage, &path)
+                                    .and_then(move |before| {
+                                        let deleting = path.clone();
+                                        let deleted = compat(async move {
+                                            match precondition {
+                                                Some(precondition) => {
+                                                    storage.del_if(deleting, precondition).await
+                                                }
+                                                None => storage.del(deleting).await.map(|()| true),
+                                            }
+                                        });
+                                        deleted.map_err(storage_error).map(move |deleted| {
+                                            if deleted {
+                                                file_mutated(Mutation::Delete, path, before, None);
+                                            }
+                                            deleted
+                                        })
+                                    })
+                                    .and_then(|deleted| {
+                                        let msg = if deleted {
+                                            InternalMsg::DelSuccess
+                                        } else {
+                                            InternalMsg::PreconditionFailed
+                                        };
+                                        tx_success.send(msg).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'DelSuccess' to data channel",
+                                            )
+                                        })
+                                    })
+                                    .or_else(|e| {
+                                        let msg = failure_msg(e, InternalMsg::DelFail);
+                                        tx_fail.send(msg).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'DelFail' to data channel",
+                                            )
+                                        })
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to delete file: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Quit => {
+                            // Like RFC 959 asks, a running transfer gets to finish (and send its
+                            // reply) first.
+                            let session = session.lock()?;
+                            if let Some(transfer) = &session.transfer {
+                                if transfer.quit_when_done() {
+                                    return Ok("".to_string());
+                                }
+                            }
+                            let tx = tx.clone();
+                            spawn!(tx.send(InternalMsg::Quit));
+                            Ok("221 bye!\r\n".to_string())
+                        }
+                        Command::Rein => {
+                            session.lock()?.reinitialize(new_storage());
+                            Ok("220 Service ready for new user\r\n".to_string())
+                        }
+                        Command::Mkd { path } => {
+                            ensure_authenticated!();
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let path_in_storage = session.cwd.join(&path);
+                            if !session.new_entry()(&path_in_storage) {
+                                return Ok(
+                                    "550 Too many new files and directories in this session\r\n"
+                                        .to_string(),
+                                );
+                            }
+                            let new_entries = session.new_entries.clone();
+                            let made = {
+                                let storage = Arc::clone(&storage);
+                                compat(async move { storage.mkd(path_in_storage).await })
+                            };
+                            tokio::spawn(
+                                made.map_err(storage_error)
+                                    .and_then(|_| {
+                                        tx_success.send(InternalMsg::MkdirSuccess(path)).map_err(
+                                            |_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'MkdirSuccess' message",
+                                                )
+                                            },
+                                        )
+                                    })
+                                    .or_else(move |e| {
+                                        new_entries.give_back();
+                                        // RFC 959 has `550` for a directory that's already there, it's
+                                        // not a file that mustn't be overwritten.
+                                        let msg = match failure_msg(e, InternalMsg::MkdirFail) {
+                                            InternalMsg::StorageFailed(ref error)
+                                                if error.kind() == storage::ErrorKind::Exists =>
+                                            {
+                                                InternalMsg::DirectoryExists
+                                            }
+                                            msg => msg,
+                                        };
+                                        tx_fail.send(msg).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'MkdirFail' message",
+                                            )
+                                        })
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to create directory: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Rmd { path } => {
+                            ensure_authenticated!();
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(path);
+                            let recursive =
+                                session.username.clone().filter(|_| session.recursive_rmd);
+                            let removed = compat(async move {
+                                // The authorizer is asked here, without holding the session.
+                                let recursive = match recursive {
+                                    Some(username) => {
+                                        authorizer
+                                            .authorize(
+                                                &username,
+                                                &path,
+                                                Operation::RemoveDirectoryRecursively,
+                                            )
+                                            .await
+                                            == Ok(true)
+                                    }
+                                    None => false,
+                                };
+                                if recursive {
+                                    storage.rmd_recursive(path).await
+                                } else {
+                                    storage.rmd(path).await
+                                }
+                            });
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            tokio::spawn(
+                                removed
+                                    .map_err(storage_error)
+                                    .and_then(|_| {
+                                        tx_success.send(InternalMsg::RmdirSuccess).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'RmdirSuccess' message",
+                                            )
+                                        })
+                                    })
+                                    .or_else(|e| {
+                                        let msg = failure_msg(e, InternalMsg::RmdirFail);
+                                        tx_fail.send(msg).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'RmdirFail' message",
+                                            )
+                                        })
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to remove directory: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Allo { size: None } => {
+                            ensure_authenticated!();
+                            Ok("202 I don't need to allocate anything\r\n".to_string())
+                        }
+                        Command::Allo { size: Some(size) } => {
+                            ensure_authenticated!();
+                            // We don't reserve anything, but we can tell the client right away when
+                            // its upload won't fit, instead of failing halfway.
+                            let session = session.lock()?;
+                            let tx = tx.clone();
+                            let free = {
+                                let (storage, cwd) =
+                                    (Arc::clone(&session.storage), session.cwd.clone());
+                                compat(async move { storage.free_space(cwd).await })
+                            };
+                            tokio::spawn(
+                                free.then(move |free| {
+                                    tx.send(match free {
+                                        Ok(Some(free)) if free < size => {
+                                            InternalMsg::InsufficientSpace { free }
+                                        }
+                                        Ok(Some(_)) => InternalMsg::EnoughSpace,
+                                        _ => InternalMsg::UnknownSpace,
+                                    })
+                                })
+                                .map(|_| ())
+                                .map_err(|e| {
+                                    warn!("Failed to send the ALLO reply: {:?}", e);
+                                }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Abor => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            match session.data_abort_tx.take() {
+                                Some(abort_tx) => {
+                                    let (ack_tx, ack_rx) = oneshot::channel();
+                                    let tx = tx.clone();
+                                    // If the data channel is already gone, the `ack_tx` is dropped
+                                    // without an answer.
+                                    spawn!(abort_tx.send(ack_tx).then(|_| ack_rx).then(
+                                        move |ack| {
+                                            let msg = match ack {
+                                                Ok(true) => InternalMsg::TransferAborted,
+                                                _ => InternalMsg::DataChannelClosed,
+                                            };
+                                            tx.send(msg)
+                                        }
+                                    ));
+                                    Ok("".to_string())
+                                }
+                                None => Ok("226 Data channel already closed\r\n".to_string()),
+                            }
+                        }
+                        Command::Stou => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            let tx = match session.data_cmd_tx.take() {
+                                Some(tx) => tx,
+                                None => {
+                                    return Ok("425 No data connection established\r\n".to_string());
+                                }
+                            };
+                            // The data channel picks the name and tells the client.
+                            spawn!(tx.send((cmd.clone(), None)));
+                            Ok("".to_string())
+                        }
+                        Command::Rnfr { file } => {
+                            ensure_authenticated!();
+                            let mut session = session.lock()?;
+                            session.rename_from = Some(session.cwd.join(file));
+                            Ok("350 Tell me, what would you like the new name to be?\r\n"
+                                .to_string())
+                        }
+                        Command::Rnto { file } => {
                             ensure_authenticated!();
-                            match option {
-                                commands::Opt::UTF8 => {
-                                    Ok("250 Okay, I'm always in UTF8 mode.\r\n".to_string())
+                            let mut session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let to = session.cwd.join(file);
+                            match (session.rename_from.take(), session.precondition.take()) {
+                                (Some(from), precondition) => {
+                                    let file_mutated = session.file_mutated();
+                                    let renaming = Arc::clone(&storage);
+                                    let renamed = file_state(&storage, &to)
+                                        .join(file_state(&storage, &from))
+                                        .and_then(move |(before, after)| {
+                                            let (source, target) = (from.clone(), to.clone());
+                                            let renamed = compat(async move {
+                                                match precondition {
+                                                    Some(precondition) => {
+                                                        renaming
+                                                            .rename_if(source, target, precondition)
+                                                            .await
+                                                    }
+                                                    None => renaming
+                                                        .rename(source, target)
+                                                        .await
+                                                        .map(|()| true),
+                                                }
+                                            });
+                                            renamed.map_err(storage_error).map(move |renamed| {
+                                                if renamed {
+                                                    let mutation = Mutation::Rename { from };
+                                                    file_mutated(mutation, to, before, after);
+                                                }
+                                                renamed
+                                            })
+                                        });
+                                    let tx = tx.clone();
+                                    tokio::spawn(
+                                        renamed
+                                            .then(move |renamed| {
+                                                tx.send(match renamed {
+                                                    Ok(true) => InternalMsg::RenameSuccess,
+                                                    Ok(false) => InternalMsg::PreconditionFailed,
+                                                    Err(e) => {
+                                                        failure_msg(e, InternalMsg::RenameFail)
+                                                    }
+                                                })
+                                            })
+                                            .map(|_| ())
+                                            .map_err(|e| {
+                                                warn!("Failed to send the RNTO reply: {:?}", e);
+                                            }),
+                                    );
+                                    Ok("".to_string())
+                                }
+                                (None, _) => {
+                                    Ok("450 Please tell me what file you want to rename first\r\n"
+                                        .to_string())
                                 }
                             }
                         }
-                        Command::Dele { path } => {
+                        Command::Size { file } => {
                             ensure_authenticated!();
                             let session = session.lock()?;
                             let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(file);
                             let tx_success = tx.clone();
                             let tx_fail = tx.clone();
+                            let metadata = {
+                                let storage = Arc::clone(&storage);
+                                compat(async move { storage.stat(path).await })
+                            };
                             tokio::spawn(
-                                storage
-                                    .del(path)
-                                    .map_err(|_| {
-                                        std::io::Error::new(
-                                            ErrorKind::Other,
-                                            "Failed to delete file",
+                                metadata
+                                    .map_err(storage_error)
+                                    .and_then(|metadata| {
+                                        let msg = if metadata.is_file() {
+                                            InternalMsg::Size(metadata.len())
+                                        } else {
+                                            InternalMsg::NotAFile
+                                        };
+                                        tx_success.send(msg).map_err(|_| {
+                                            std::io::Error::other("Failed to send 'Size' message")
+                                        })
+                                    })
+                                    .or_else(|e| {
+                                        tx_fail.send(failure_msg(e, InternalMsg::NotFound)).map_err(
+                                            |_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'NotFound' message",
+                                                )
+                                            },
                                         )
                                     })
-                                    .and_then(|_| {
-                                        tx_success.send(InternalMsg::DelSuccess).map_err(|_| {
-                                            std::io::Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to send 'DelSuccess' to data channel",
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to get file size: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Mdtm { file } => {
+                            ensure_authenticated!();
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(file);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let metadata = {
+                                let storage = Arc::clone(&storage);
+                                compat(async move { storage.stat(path).await })
+                            };
+                            tokio::spawn(
+                                metadata
+                                    .map_err(storage_error)
+                                    .and_then(|metadata| {
+                                        let msg = match metadata.modified() {
+                                            Ok(modified) if metadata.is_file() => {
+                                                InternalMsg::ModificationTime(modified)
+                                            }
+                                            _ => InternalMsg::NotAFile,
+                                        };
+                                        tx_success.send(msg).map_err(|_| {
+                                            std::io::Error::other(
+                                                "Failed to send 'ModificationTime' message",
                                             )
                                         })
                                     })
-                                    .or_else(|_| {
-                                        tx_fail.send(InternalMsg::DelFail).map_err(|_| {
-                                            std::io::Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to send 'DelFail' to data channel",
-                                            )
-                                        })
+                                    .or_else(|e| {
+                                        tx_fail.send(failure_msg(e, InternalMsg::NotFound)).map_err(
+                                            |_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'NotFound' message",
+                                                )
+                                            },
+                                        )
                                     })
                                     .map(|_| ())
                                     .map_err(|e| {
-                                        warn!("Failed to delete file: {}", e);
+                                        warn!("Failed to get modification time: {}", e);
                                     }),
                             );
                             Ok("".to_string())
                         }
-                        Command::Quit => {
-                            let tx = tx.clone();
-                            spawn!(tx.send(InternalMsg::Quit));
-                            Ok("221 bye!\r\n".to_string())
-                        }
-                        Command::Mkd { path } => {
+                        Command::Mlst { path } => {
                             ensure_authenticated!();
                             let session = session.lock()?;
                             let storage = Arc::clone(&session.storage);
+                            // Only the named entry, so `stat` it instead of listing its parent.
+                            let path = match path {
+                                Some(path) => session.cwd.join(path),
+                                None => session.cwd.clone(),
+                            };
                             let tx_success = tx.clone();
                             let tx_fail = tx.clone();
+                            let metadata = {
+                                let (storage, path) = (Arc::clone(&storage), path.clone());
+                                compat(async move { storage.stat(path).await })
+                            };
                             tokio::spawn(
-                                storage
-                                    .mkd(&path)
-                                    .map_err(|_| {
-                                        std::io::Error::new(
-                                            ErrorKind::Other,
-                                            "Failed to create directory",
-                                        )
+                                metadata
+                                    .map_err(storage_error)
+                                    .and_then(move |metadata| {
+                                        let entry = storage::Fileinfo { path, metadata };
+                                        tx_success
+                                            .send(InternalMsg::Facts(entry.format_facts()))
+                                            .map_err(|_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'Facts' message",
+                                                )
+                                            })
                                     })
-                                    .and_then(|_| {
-                                        tx_success.send(InternalMsg::MkdirSuccess(path)).map_err(
+                                    .or_else(|e| {
+                                        tx_fail.send(failure_msg(e, InternalMsg::NotFound)).map_err(
                                             |_| {
-                                                std::io::Error::new(
-                                                    ErrorKind::Other,
-                                                    "Failed to send 'MkdirSuccess' message",
+                                                std::io::Error::other(
+                                                    "Failed to send 'NotFound' message",
                                                 )
                                             },
                                         )
                                     })
-                                    .or_else(|_| {
-                                        tx_fail.send(InternalMsg::MkdirFail).map_err(|_| {
-                                            std::io::Error::new(
-                                                ErrorKind::Other,
-                                                "Failed to send 'MkdirFail' message",
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to get the facts: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Hash { file } => {
+                            ensure_authenticated!();
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(&file);
+                            let algorithm = session.hash_algorithm;
+                            let tx = tx.clone();
+                            let metadata = {
+                                let (storage, path) = (Arc::clone(&storage), path.clone());
+                                compat(async move { storage.stat(path).await })
+                            };
+                            tokio::spawn(
+                                metadata
+                                    .and_then(move |metadata| {
+                                        if metadata.is_file() {
+                                            let len = metadata.len();
+                                            futures::future::Either::A(
+                                                compat(async move {
+                                                    storage.checksum(path, algorithm, None).await
+                                                })
+                                                .map(move |hash| InternalMsg::Hash {
+                                                    algorithm,
+                                                    len,
+                                                    hash,
+                                                    file,
+                                                }),
                                             )
-                                        })
+                                        } else {
+                                            futures::future::Either::B(futures::future::ok(
+                                                InternalMsg::NotAFile,
+                                            ))
+                                        }
                                     })
+                                    .or_else(|e| {
+                                        Ok(failure_msg(storage_error(e), InternalMsg::NotFound))
+                                    })
+                                    .and_then(|msg| tx.send(msg))
                                     .map(|_| ())
                                     .map_err(|e| {
-                                        warn!("Failed to create directory: {}", e);
+                                        warn!("Failed to send the HASH reply: {:?}", e);
                                     }),
                             );
                             Ok("".to_string())
                         }
-                        Command::Allo { .. } => {
+                        Command::Checksum {
+                            algorithm,
+                            file,
+                            range,
+                        } => {
                             ensure_authenticated!();
-                            // ALLO is obsolete and we'll just ignore it.
-                            Ok("202 I don't need to allocate anything\r\n".to_string())
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(&file);
+                            let tx = tx.clone();
+                            let metadata = {
+                                let (storage, path) = (Arc::clone(&storage), path.clone());
+                                compat(async move { storage.stat(path).await })
+                            };
+                            tokio::spawn(
+                                metadata
+                                    .and_then(move |metadata| {
+                                        if metadata.is_file() {
+                                            futures::future::Either::A(
+                                                compat(async move {
+                                                    storage.checksum(path, algorithm, range).await
+                                                })
+                                                .map(InternalMsg::Checksum),
+                                            )
+                                        } else {
+                                            futures::future::Either::B(futures::future::ok(
+                                                InternalMsg::NotAFile,
+                                            ))
+                                        }
+                                    })
+                                    .or_else(|e| {
+                                        Ok(failure_msg(storage_error(e), InternalMsg::NotFound))
+                                    })
+                                    .and_then(|msg| tx.send(msg))
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to send the checksum reply: {:?}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
                         }
-                        Command::Abor => {
+                        Command::Mfmt { time, file } => {
                             ensure_authenticated!();
-                            let mut session = session.lock()?;
-                            match session.data_abort_tx.take() {
-                                Some(tx) => {
-                                    spawn!(tx.send(()));
-                                    Ok("226 Closed data channel\r\n".to_string())
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(&file);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let touched = {
+                                let storage = Arc::clone(&storage);
+                                compat(async move { storage.set_mtime(path, time).await })
+                            };
+                            tokio::spawn(
+                                touched
+                                    .map_err(storage_error)
+                                    .and_then(move |_| {
+                                        tx_success
+                                            .send(InternalMsg::MfmtSuccess(time, file))
+                                            .map_err(|_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'MfmtSuccess' message",
+                                                )
+                                            })
+                                    })
+                                    .or_else(|e| {
+                                        tx_fail.send(failure_msg(e, InternalMsg::MfmtFail)).map_err(
+                                            |_| {
+                                                std::io::Error::other(
+                                                    "Failed to send 'MfmtFail' message",
+                                                )
+                                            },
+                                        )
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to set modification time: {}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
+                        }
+                        Command::Site { command, args }
+                            if command.eq_ignore_ascii_case("CHMOD")
+                                && site_commands.is_builtin(&command) =>
+                        {
+                            ensure_authenticated!();
+                            let (mode, path) = match site::parse_chmod(&args) {
+                                Some(parsed) => parsed,
+                                None => {
+                                    return Ok("501 Usage: SITE CHMOD <mode> <path>\r\n".to_string())
                                 }
-                                None => Ok("226 Data channel already closed\r\n".to_string()),
-                            }
+                            };
+                            let session = session.lock()?;
+                            let path = session.cwd.join(path);
+                            let tx_success = tx.clone();
+                            let tx_fail = tx.clone();
+                            let changed = {
+                                let storage = Arc::clone(&session.storage);
+                                compat(async move { storage.chmod(path, mode).await })
+                            };
+                            tokio::spawn(
+                                changed
+                                    .then(move |res| match res {
+                                        Ok(()) => tx_success.send(InternalMsg::ChmodSuccess),
+                                        Err(e) => tx_fail.send(failure_msg(
+                                            storage_error(e),
+                                            InternalMsg::ChmodFail,
+                                        )),
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to send the result of SITE CHMOD: {:?}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
                         }
-                        // TODO: Write functional test for STOU command.
-                        Command::Stou => {
+                        Command::Site { command, args }
+                            if command.eq_ignore_ascii_case("PRESIGN")
+                                && site_commands.is_builtin(&command) =>
+                        {
                             ensure_authenticated!();
-                            let mut session = session.lock()?;
-                            let tx = match session.data_cmd_tx.take() {
-                                Some(tx) => tx,
+                            let (path, ttl) = match site::parse_presign(&args) {
+                                Some(parsed) => parsed,
                                 None => {
-                                    return Ok("425 No data connection established\r\n".to_string());
+                                    return Ok(
+                                        "501 Usage: SITE PRESIGN <path> <ttl>\r\n".to_string()
+                                    )
                                 }
                             };
-
-                            let uuid = Uuid::new_v4().to_string();
-                            let filename = std::path::Path::new(&uuid);
-                            let path = session.cwd.join(&filename).to_string_lossy().to_string();
-                            spawn!(tx.send(Command::Stor { path: path }));
-                            Ok(format!("150 {}\r\n", filename.to_string_lossy()))
+                            let session = session.lock()?;
+                            let storage = Arc::clone(&session.storage);
+                            let path = session.cwd.join(path);
+                            let tx = tx.clone();
+                            let metadata = {
+                                let (storage, path) = (Arc::clone(&storage), path.clone());
+                                compat(async move { storage.stat(path).await })
+                            };
+                            tokio::spawn(
+                                metadata
+                                    .and_then(move |metadata| {
+                                        if metadata.is_file() {
+                                            futures::future::Either::A(
+                                                compat(
+                                                    async move { storage.presign(path, ttl).await },
+                                                )
+                                                .map(|url| match url {
+                                                    Some(url) => InternalMsg::Presigned(url),
+                                                    None => InternalMsg::PresignUnsupported,
+                                                }),
+                                            )
+                                        } else {
+                                            futures::future::Either::B(futures::future::ok(
+                                                InternalMsg::NotAFile,
+                                            ))
+                                        }
+                                    })
+                                    .or_else(|e| {
+                                        Ok(failure_msg(storage_error(e), InternalMsg::NotFound))
+                                    })
+                                    .and_then(|msg| tx.send(msg))
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to send the SITE PRESIGN reply: {:?}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
                         }
-                        Command::Rnfr { file } => {
+                        Command::Site { command, .. }
+                            if command.eq_ignore_ascii_case("DEBUG")
+                                && site_commands.is_builtin(&command) =>
+                        {
                             ensure_authenticated!();
-                            let mut session = session.lock()?;
-                            session.rename_from = Some(file);
-                            Ok("350 Tell me, what would you like the new name to be?\r\n"
-                                .to_string())
+                            match &server_handle {
+                                Some(handle) => Ok(format!("200 {}\r\n", handle.support_bundle())),
+                                None => Ok("502 SITE DEBUG is not available\r\n".to_string()),
+                            }
                         }
-                        Command::Rnto { file } => {
+                        Command::Site { command, args }
+                            if command.eq_ignore_ascii_case("EXPECT")
+                                && site_commands.is_builtin(&command) =>
+                        {
                             ensure_authenticated!();
                             let mut session = session.lock()?;
-                            let storage = Arc::clone(&session.storage);
-                            match session.rename_from.take() {
-                                Some(from) => {
-                                    spawn!(storage.rename(from, file));
-                                    Ok("250 sure, it shall be known\r\n".to_string())
+                            match site::parse_expect(&args) {
+                                Some(ref precondition)
+                                    if *precondition == storage::Precondition::default() =>
+                                {
+                                    session.precondition = None;
+                                    Ok("200 Precondition cleared\r\n".to_string())
                                 }
-                                None => {
-                                    Ok("450 Please tell me what file you want to rename first\r\n"
+                                Some(precondition) => {
+                                    session.precondition = Some(precondition);
+                                    Ok("200 Precondition set for the next DELE or RNTO\r\n"
                                         .to_string())
                                 }
+                                None => Ok("501 Usage: SITE EXPECT [size=<bytes>] \
+                                            [mtime=<YYYYMMDDHHMMSS>] [etag=<etag>]\r\n"
+                                    .to_string()),
+                            }
+                        }
+                        Command::Site { command, args }
+                            if command.eq_ignore_ascii_case("IDNT")
+                                && site_commands.is_builtin(&command) =>
+                        {
+                            let mut session = session.lock()?;
+                            if session.state != New || session.identified {
+                                return Ok("503 SITE IDNT is only accepted once, before USER\r\n"
+                                    .to_string());
+                            }
+                            let proxy = session.connection.peer;
+                            if !trusted_proxies
+                                .iter()
+                                .any(|network| network.contains(proxy.ip()))
+                            {
+                                warn!(
+                                    "Refused SITE IDNT on connection {}: {} is not a trusted proxy",
+                                    session.id, proxy
+                                );
+                                return Ok("550 Not a trusted proxy\r\n".to_string());
+                            }
+                            let (ident, client) = match site::parse_idnt(&args) {
+                                Some(parsed) => parsed,
+                                None => {
+                                    return Ok(
+                                        "501 Usage: SITE IDNT [<ident>@]<address>[:<port>]\r\n"
+                                            .to_string(),
+                                    )
+                                }
+                            };
+                            info!(
+                                "Connection {} through proxy {} is for {}{}",
+                                session.id,
+                                proxy,
+                                client,
+                                ident
+                                    .map(|ident| format!(" ({})", ident))
+                                    .unwrap_or_default()
+                            );
+                            session.identified = true;
+                            session.ident = ident.map(str::to_string);
+                            session.connection.proxy = Some(proxy);
+                            session.connection.peer = client;
+                            let allowed = client_allowlist
+                                .as_ref()
+                                .is_none_or(|allowlist| allowlist.allows(client.ip()));
+                            if !allowed {
+                                info!(
+                                    "Closing connection {} for {}: it's not on the allowlist",
+                                    session.id, client
+                                );
+                                let tx = tx.clone();
+                                spawn!(tx.send(InternalMsg::Quit));
+                                return Ok(
+                                    "421 Service not available, closing control connection\r\n"
+                                        .to_string(),
+                                );
                             }
+                            Ok("200 Identity accepted\r\n".to_string())
+                        }
+                        Command::Site { command, args } => {
+                            let session = session.lock()?;
+                            let context = site::SiteContext {
+                                session_id: session.id.clone(),
+                                username: session.username.clone(),
+                                cwd: session.cwd.clone(),
+                                connection: session.connection,
+                            };
+                            let tx = tx.clone();
+                            tokio::spawn(
+                                site_commands
+                                    .handle(context, &command, &args)
+                                    .then(|reply| {
+                                        tx.send(InternalMsg::SiteReply(reply.unwrap_or_else(
+                                            |_| "451 SITE command failed\r\n".to_string(),
+                                        )))
+                                    })
+                                    .map(|_| ())
+                                    .map_err(|e| {
+                                        warn!("Failed to send the SITE reply: {:?}", e);
+                                    }),
+                            );
+                            Ok("".to_string())
                         }
                     }
                 }
@@ -1039,15 +4811,58 @@ where
                 Event::InternalMsg(NotFound) => Ok("550 File not found\r\n".to_string()),
                 Event::InternalMsg(PermissionDenied) => Ok("550 Permision denied\r\n".to_string()),
                 Event::InternalMsg(SendingData) => Ok("150 Sending Data\r\n".to_string()),
-                Event::InternalMsg(SendData) => Ok("226 Send you something nice\r\n".to_string()),
+                Event::InternalMsg(SendData { bytes }) => {
+                    let stats = &mut session.lock()?.stats;
+                    stats.bytes_sent = stats.bytes_sent.saturating_add(bytes);
+                    Ok("226 Send you something nice\r\n".to_string())
+                }
+                Event::InternalMsg(EnoughSpace) => Ok("200 There's enough space\r\n".to_string()),
+                Event::InternalMsg(InsufficientSpace { free }) => Ok(format!(
+                    "452 Insufficient storage space, {} bytes available\r\n",
+                    free
+                )),
+                Event::InternalMsg(UnknownSpace) => {
+                    Ok("202 I don't need to allocate anything\r\n".to_string())
+                }
                 Event::InternalMsg(WriteFailed) => Ok("450 Failed to write file\r\n".to_string()),
+                Event::InternalMsg(OutOfSpace) => {
+                    Ok("452 Insufficient storage space in system\r\n".to_string())
+                }
+                Event::InternalMsg(StorageFailed(error)) => {
+                    let message = match error.kind() {
+                        storage::ErrorKind::NotFound => "File not found",
+                        storage::ErrorKind::PermissionDenied => "Permision denied",
+                        storage::ErrorKind::Exists => "The file exists, and may not be overwritten",
+                        storage::ErrorKind::NotEmpty => "Directory not empty",
+                        storage::ErrorKind::Quota => "Exceeded storage allocation",
+                        storage::ErrorKind::StorageFull => "Insufficient storage space in system",
+                        storage::ErrorKind::Backend => "The storage failed",
+                    };
+                    Ok(format!(
+                        "{} {}\r\n",
+                        error.reply_code().unwrap_or(450),
+                        message
+                    ))
+                }
                 Event::InternalMsg(ConnectionReset) => {
                     Ok("426 Datachannel unexpectedly closed\r\n".to_string())
                 }
-                Event::InternalMsg(WrittenData) => {
+                // The format RFC 1123 prescribes, so clients can pick the name out of the reply.
+                Event::InternalMsg(UniqueName(name)) => Ok(format!("150 FILE: {}\r\n", name)),
+                Event::InternalMsg(WrittenUnique { bytes, name }) => {
+                    let stats = &mut session.lock()?.stats;
+                    stats.bytes_received = stats.bytes_received.saturating_add(bytes);
+                    Ok(format!("250 Transfer complete, FILE: {}\r\n", name))
+                }
+                Event::InternalMsg(WrittenData { bytes }) => {
+                    let stats = &mut session.lock()?.stats;
+                    stats.bytes_received = stats.bytes_received.saturating_add(bytes);
                     Ok("226 File succesfully written\r\n".to_string())
                 }
                 Event::InternalMsg(UnknownRetrieveError) => Ok("450 Unknown Error\r\n".to_string()),
+                Event::InternalMsg(ListFailed) => {
+                    Ok("450 Failed to list the directory\r\n".to_string())
+                }
                 Event::InternalMsg(DirectorySuccesfullyListed) => {
                     Ok("226 Listed the directory\r\n".to_string())
                 }
@@ -1058,36 +4873,264 @@ where
                 // The InternalMsg::Quit will never be reached, because we catch it in the task before
                 // this closure is called (because we have to close the connection).
                 Event::InternalMsg(Quit) => Ok("221 bye!\r\n".to_string()),
+                Event::InternalMsg(EntryLimitReached) => {
+                    Ok("550 Too many new files and directories in this session\r\n".to_string())
+                }
+                Event::InternalMsg(CommandTimedOut) => {
+                    Ok("451 The command timed out, please try again later\r\n".to_string())
+                }
+                Event::InternalMsg(QuitAfterTransfer) => {
+                    let tx = tx.clone();
+                    spawn!(tx.send(InternalMsg::Quit));
+                    Ok("221 bye!\r\n".to_string())
+                }
                 Event::InternalMsg(MkdirSuccess(path)) => {
                     Ok(format!("257 {}\r\n", path.to_string_lossy()))
                 }
                 Event::InternalMsg(MkdirFail) => {
                     Ok("550 Failed to create directory\r\n".to_string())
                 }
+                Event::InternalMsg(RmdirSuccess) => {
+                    Ok("250 Directory successfully removed\r\n".to_string())
+                }
+                Event::InternalMsg(DirectoryExists) => {
+                    Ok("550 Directory already exists\r\n".to_string())
+                }
+                Event::InternalMsg(RmdirFail) => {
+                    Ok("550 Failed to remove directory, it may not be empty\r\n".to_string())
+                }
+                // Always the exact number of bytes, `SIZE` is meant to be parsed by the client.
+                Event::InternalMsg(Size(size)) => Ok(format!("213 {}\r\n", size)),
+                Event::InternalMsg(NotAFile) => Ok("550 Not a regular file\r\n".to_string()),
+                Event::InternalMsg(CwdSuccess(path)) => {
+                    session.lock()?.cwd = path;
+                    Ok("250 Okay.\r\n".to_string())
+                }
+                Event::InternalMsg(LoggedIn(username)) => {
+                    let mut session = session.lock()?;
+                    let user = authenticator.user(&username);
+                    session.priority = user.priority;
+                    session.new_entries = NewEntries::new(user.max_new_entries);
+                    match Arc::get_mut(&mut session.storage) {
+                        Some(storage) => storage.set_user(&user),
+                        None => warn!("Storage backend in use during login, not setting the user"),
+                    }
+                    session.state = WaitCmd;
+                    Ok("230 User logged in, proceed\r\n".to_string())
+                }
+                Event::InternalMsg(WrongPassword) => {
+                    Ok("530 Wrong username or password\r\n".to_string())
+                }
+                Event::InternalMsg(AuthenticationFailed) => {
+                    warn!("Unknown Authentication backend failure");
+                    Ok("530 Failed to authenticate\r\n".to_string())
+                }
+                Event::InternalMsg(NotADirectory) => Ok("550 Not a directory\r\n".to_string()),
+                // RFC 3659 requires the leading space, so clients can tell the entry from the
+                // reply lines.
+                Event::InternalMsg(Facts(entry)) => {
+                    Ok(format!("250-Listing\r\n {}\r\n250 End\r\n", entry))
+                }
+                Event::InternalMsg(ModificationTime(modified)) => {
+                    let modified: chrono::DateTime<chrono::Utc> = modified.into();
+                    Ok(format!("213 {}\r\n", modified.format("%Y%m%d%H%M%S")))
+                }
+                Event::InternalMsg(MfmtSuccess(modified, file)) => {
+                    let modified: chrono::DateTime<chrono::Utc> = modified.into();
+                    Ok(format!(
+                        "213 Modify={}; {}\r\n",
+                        modified.format("%Y%m%d%H%M%S"),
+                        file.display()
+                    ))
+                }
+                Event::InternalMsg(StatListing) => Ok("213-Status follows:\r\n".to_string()),
+                // The quirks only get to see one line of the listing at a time, so they can't tell
+                // it's part of a multi-line reply: the code goes on here.
+                Event::InternalMsg(StatLine(line)) => {
+                    let line = String::from_utf8_lossy(&line);
+                    if session.lock()?.quirks.contains(&Quirk::CodeOnEveryLine) {
+                        Ok(format!("213-{}", line))
+                    } else {
+                        Ok(line.into_owned())
+                    }
+                }
+                Event::InternalMsg(StatListed { complete: true }) => {
+                    Ok("213 End of status\r\n".to_string())
+                }
+                Event::InternalMsg(StatListed { complete: false }) => {
+                    Ok("213 End of status, the listing is incomplete\r\n".to_string())
+                }
+                Event::InternalMsg(TransferAborted) => Ok(
+                    "426 Connection closed; transfer aborted\r\n226 Closed data channel\r\n"
+                        .to_string(),
+                ),
+                Event::InternalMsg(DataChannelClosed) => {
+                    Ok("226 Closed data channel\r\n".to_string())
+                }
+                Event::InternalMsg(MfmtFail) => {
+                    Ok("550 Failed to change the modification time\r\n".to_string())
+                }
+                Event::InternalMsg(ChmodSuccess) => {
+                    Ok("200 SITE CHMOD command successful\r\n".to_string())
+                }
+                Event::InternalMsg(SiteReply(reply)) => Ok(reply),
+                Event::InternalMsg(LifetimeExceeded) => {
+                    let session = session.lock()?;
+                    session_listener.session_expired(&SessionExpired {
+                        session_id: session.id.clone(),
+                        connection: session.connection,
+                        username: session.username.clone(),
+                        lifetime: session.stats.start.elapsed(),
+                    });
+                    let tx = tx.clone();
+                    spawn!(tx.send(InternalMsg::Quit));
+                    Ok("421 Session lasted too long, closing the connection\r\n".to_string())
+                }
+                Event::InternalMsg(PreconditionFailed) => {
+                    Ok("450 Precondition failed, the file has changed\r\n".to_string())
+                }
+                Event::InternalMsg(RenameSuccess) => {
+                    Ok("250 sure, it shall be known\r\n".to_string())
+                }
+                Event::InternalMsg(RenameFail) => {
+                    Ok("550 Failed to rename the file\r\n".to_string())
+                }
+                Event::InternalMsg(PathLocked) => {
+                    Ok("450 The file is being written by another session\r\n".to_string())
+                }
+                Event::InternalMsg(Hash {
+                    algorithm,
+                    len,
+                    hash,
+                    file,
+                }) => Ok(format!(
+                    "213 {} 0-{} {} {}\r\n",
+                    algorithm,
+                    len,
+                    hash,
+                    file.display()
+                )),
+                // Like other servers with these commands, in upper case.
+                Event::InternalMsg(Checksum(hash)) => {
+                    Ok(format!("250 {}\r\n", hash.to_uppercase()))
+                }
+                Event::InternalMsg(Presigned(url)) => Ok(format!("200 {}\r\n", url)),
+                Event::InternalMsg(PresignUnsupported) => {
+                    Ok("502 The storage backend can't presign URLs\r\n".to_string())
+                }
+                Event::InternalMsg(ChmodFail) => {
+                    Ok("550 Failed to change the permissions\r\n".to_string())
+                }
             }
         };
 
-        let codec = FTPCodec::new();
-        let (sink, stream) = codec.framed(socket).split();
-        let task = sink
-            .send(format!("220 {}\r\n", self.greeting))
-            .and_then(|sink| sink.flush())
-            .and_then(move |sink| {
-                sink.send_all(
-                    stream
-                        .map(Event::Command)
-                        .select(
-                            rx.map(Event::InternalMsg)
-                                .map_err(|_| FTPErrorKind::InternalMsgError.into()),
-                        )
-                        .take_while(|event| {
-                            // TODO: Make sure data connections are closed
-                            Ok(*event != Event::InternalMsg(InternalMsg::Quit))
-                        })
-                        .and_then(respond)
-                        .or_else(|e| {
-                            warn!("Failed to process command: {}", e);
-                            let response = match e.kind() {
+        // Asks the authorizer about the commands before they're run, and returns the reply of the
+        // ones it denies. The session is only locked to see who's asking, not while waiting.
+        let authorize = move |event: Event| -> Box<
+            dyn Future<Item = (Event, Option<String>), Error = FTPError> + Send,
+        > {
+            let asking = match &event {
+                Event::Command(cmd) => {
+                    let mut session = match session_auth.lock() {
+                        Ok(session) => session,
+                        Err(e) => return Box::new(futures::future::err(e.into())),
+                    };
+                    session.stats.commands += 1;
+                    let required = required_authorization(cmd, &session.cwd);
+                    if let Some((_, path)) = &required {
+                        session.stats.paths.insert(path.clone());
+                    }
+                    match (&session.state, &session.username, required) {
+                        (SessionState::WaitCmd, Some(username), Some((operation, path))) => {
+                            Some((username.clone(), path, operation))
+                        }
+                        _ => None,
+                    }
+                }
+                Event::InternalMsg(_) => None,
+            };
+            match asking {
+                Some((username, path, operation)) => Box::new(
+                    compat(async move {
+                        Ok::<_, FTPError>(authorizer.authorize(&username, &path, operation).await)
+                    })
+                    .map(move |decision| (event, denial(decision))),
+                ),
+                None => Box::new(futures::future::ok((event, None))),
+            }
+        };
+
+        let serve = move |socket: TcpStream, greeting: String| {
+            if let Err(e) = telnet::receive_urgent_inline(&socket) {
+                warn!("Failed to receive urgent data inline: {}", e);
+            }
+            let (reader, writer) = tokio_io::AsyncRead::split(socket);
+            let stream = tokio_codec::FramedRead::new(reader, FTPCodec::new(Arc::clone(&utf8)));
+            let sink =
+                replies::ReplyWriter::new(writer, FTPCodec::new(utf8), reply_buffer, reply_timeout);
+            sink.send(greeting)
+                .and_then(|sink| sink.flush())
+                .and_then(move |sink| {
+                    sink.send_all(
+                        stream
+                            .map(Event::Command)
+                            // The client closing the control connection ends the session like a
+                            // `QUIT`, even when the data channel still holds on to `tx`.
+                            .chain(futures::stream::once(Ok(Event::InternalMsg(
+                                InternalMsg::Quit,
+                            ))))
+                            .select(
+                                rx.map(Event::InternalMsg)
+                                    .map_err(|_| FTPErrorKind::InternalMsgError.into()),
+                            )
+                            .take_while(|event| {
+                                // TODO: Make sure data connections are closed
+                                Ok(*event != Event::InternalMsg(InternalMsg::Quit))
+                            })
+                            .and_then(authorize)
+                            .and_then(move |(event, denied)| {
+                                if let Some(reply) = denied {
+                                    return Ok(reply);
+                                }
+                                let timeout = match &event {
+                                    Event::Command(cmd) if !replied_by_data_channel(cmd) => {
+                                        command_timeout
+                                    }
+                                    _ => None,
+                                };
+                                let pending = Arc::new(replies::Pending::default());
+                                if timeout.is_some() {
+                                    *current_command.lock()? = Some(Arc::clone(&pending));
+                                }
+                                let response = respond(event);
+                                *current_command.lock()? = None;
+                                match (timeout, &response) {
+                                    // The reply is coming later, but maybe not in time.
+                                    (Some(timeout), Ok(reply)) if reply.is_empty() => {
+                                        let tx = tx_timeout.clone();
+                                        tokio::spawn(
+                                            tokio::timer::Delay::new(Instant::now() + timeout)
+                                                .then(move |_| {
+                                                    if pending.time_out() {
+                                                        futures::future::Either::A(
+                                                            tx.send(InternalMsg::CommandTimedOut)
+                                                                .then(|_| Ok(())),
+                                                        )
+                                                    } else {
+                                                        futures::future::Either::B(
+                                                            futures::future::ok(()),
+                                                        )
+                                                    }
+                                                }),
+                                        );
+                                    }
+                                    _ => {}
+                                }
+                                response
+                            })
+                            .or_else(|e| {
+                                warn!("Failed to process command: {}", e);
+                                let response = match e.kind() {
                                 FTPErrorKind::UnknownCommand { .. } => {
                                     "500 Command not implemented\r\n".to_string()
                                 }
@@ -1102,20 +5145,161 @@ where
                                         .to_string()
                                 }
                             };
-                            futures::future::ok(response)
-                        })
-                        // Needed for type annotation, we can possible remove this once the compiler is
-                        // smarter about inference :)
-                        .map_err(|e: FTPError| e),
+                                futures::future::ok(response)
+                            })
+                            .inspect(move |response| {
+                                if response.starts_with('4') || response.starts_with('5') {
+                                    if let Ok(mut session) = session_stats.lock() {
+                                        session.stats.failed_operations += 1;
+                                    }
+                                    if let Some(Ok(code)) = response.get(..3).map(str::parse) {
+                                        reply_metrics.record_error_reply(code);
+                                    }
+                                }
+                            })
+                            .map(move |response| {
+                                let session = match session_language.lock() {
+                                    Ok(session) => session,
+                                    Err(_) => return response,
+                                };
+                                let response = match &session.language {
+                                    Some(language) => reply_catalog.translate(language, &response),
+                                    None => response,
+                                };
+                                crate::quirks::apply(&session.quirks, &response)
+                            })
+                            // Needed for type annotation, we can possible remove this once the compiler is
+                            // smarter about inference :)
+                            .map_err(|e: FTPError| e),
+                    )
+                })
+                .map(|_| ())
+        };
+        let greeting_delay = self.greeting_delay;
+        let greet = move |socket: TcpStream| -> Box<
+            dyn Future<Item = (TcpStream, bool), Error = std::io::Error> + Send,
+        > {
+            match greeting_delay {
+                Some((min, max)) => {
+                    let mut bytes = [0; 8];
+                    random.fill_bytes(&mut bytes);
+                    let spread = (max - min).as_millis() as u64;
+                    let delay = min
+                        + std::time::Duration::from_millis(
+                            u64::from_le_bytes(bytes) % spread.saturating_add(1),
+                        );
+                    let mut delay = tokio::timer::Delay::new(Instant::now() + delay);
+                    let mut socket = Some(socket);
+                    Box::new(futures::future::poll_fn(move || {
+                        // Anything but "nothing to read yet" means the client didn't wait for us,
+                        // whether it sent data or hung up already.
+                        let early = !matches!(
+                            socket.as_mut().unwrap().poll_peek(&mut [0; 1]),
+                            Ok(Async::NotReady)
+                        );
+                        if !early {
+                            if let Ok(Async::NotReady) = delay.poll() {
+                                return Ok(Async::NotReady);
+                            }
+                        }
+                        Ok(Async::Ready((socket.take().unwrap(), early)))
+                    }))
+                }
+                None => Box::new(futures::future::ok((socket, false))),
+            }
+        };
+        let greeting = self.greeting.clone();
+        let allowlist = self.allowlist.clone();
+        let from_load_balancer = self
+            .load_balancers
+            .iter()
+            .any(|network| network.contains(connection.peer.ip()));
+        let proxy_protocol = if from_load_balancer {
+            self.proxy_protocol
+        } else {
+            ProxyProtocol::Disabled
+        };
+        let proxied = proxy::read_header(socket, proxy_protocol).then(move |res| {
+            let mut session = session_proxied
+                .lock()
+                .map_err(|_| std::io::Error::other("Failed to lock the session"))?;
+            if let Ok((_, Some(addrs))) = &res {
+                info!(
+                    "Connection {} is proxied for {} on {}",
+                    session.id, addrs.source, addrs.destination
+                );
+                session.connection.proxy = Some(session.connection.peer);
+                session.connection.peer = addrs.source;
+                session.connection.local = addrs.destination;
+            }
+            session_listener.session_started(&session.started());
+            let peer = session.connection.peer;
+            let allowed = allowlist.is_none_or(|allowlist| allowlist.allows(peer.ip()));
+            if !allowed {
+                info!(
+                    "Refused connection {} from {}: it's not on the allowlist",
+                    session.id, peer
+                );
+            }
+            res.map(|(socket, _)| (socket, allowed, greeting.reply(&session.connection)))
+        });
+        let task = proxied
+            .and_then(move |(socket, allowed, greeting)| {
+                if !allowed {
+                    return futures::future::Either::A(
+                        tokio_io::io::write_all(
+                            socket,
+                            "421 Service not available, closing control connection\r\n",
+                        )
+                        .map(|_| None),
+                    );
+                }
+                futures::future::Either::B(
+                    greet(socket).map(move |(socket, early)| Some((socket, early, greeting))),
                 )
             })
-            .then(|res| {
+            .map_err(FTPError::from)
+            .and_then(move |greeted| match greeted {
+                Some((socket, false, greeting)) => {
+                    futures::future::Either::B(serve(socket, greeting))
+                }
+                Some((_, true, _)) => {
+                    info!(
+                        "Dropped connection {}: it talked before the greeting",
+                        session_id
+                    );
+                    futures::future::Either::A(futures::future::ok(()))
+                }
+                None => futures::future::Either::A(futures::future::ok(())),
+            })
+            .then(move |res| {
                 if let Err(e) = res {
                     warn!("Failed to process connection: {}", e);
                 }
 
+                match session_end.lock() {
+                    Ok(session) => {
+                        if let Ok(mut live_sessions) = live_sessions.lock() {
+                            live_sessions.remove(&session.id);
+                        }
+                        let ended = session.ended();
+                        info!(
+                            "Closed connection {} from {} on {} (ttl {:?}, features {:?})",
+                            ended.session_id,
+                            ended.connection.peer,
+                            ended.connection.local,
+                            ended.connection.ttl,
+                            ended.features
+                        );
+                        session_listener.session_ended(&ended)
+                    }
+                    Err(_) => {
+                        warn!("Failed to lock the session, not sending the SessionEnded event")
+                    }
+                }
+
                 Ok(())
             });
-        tokio::spawn(task);
+        Box::new(task)
     }
 }
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::{future, Future, IntoFuture};
+
+use crate::events::ConnectionInfo;
+use crate::storage::Precondition;
+
+/// The reply of a `SITE` subcommand, resolved once the subcommand finished. Failing it without a
+/// reply gets the client a generic `451` reply.
+pub type SiteReply = Box<dyn Future<Item = String, Error = ()> + Send>;
+
+/// The handler of a `SITE` subcommand. It receives the [`SiteContext`] of the session and the
+/// arguments that followed the subcommand name, and resolves to the complete reply (including the
+/// reply code and line terminator) that will be sent to the client.
+///
+/// [`SiteContext`]: struct.SiteContext.html
+pub type SiteHandler = Arc<dyn Fn(SiteContext, &str) -> SiteReply + Send + Sync>;
+
+/// The details of the session that issued a `SITE` subcommand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SiteContext {
+    /// The unique ID of the session.
+    pub session_id: String,
+    /// The username the client logged in with, if it did.
+    pub username: Option<String>,
+    /// The current working directory of the session.
+    pub cwd: PathBuf,
+    /// The control connection of the session.
+    pub connection: ConnectionInfo,
+}
+
+#[derive(Clone)]
+struct SiteEntry {
+    description: String,
+    // `None` for the built-in subcommands, which are handled by the `Server` itself.
+    handler: Option<SiteHandler>,
+}
+
+/// The registry of `SITE` subcommands known to a [`Server`]. It always contains the built-in
+/// `HELP` subcommand, which lists every registered subcommand together with its description,
+/// `CHMOD`, which changes the permissions of a file in the storage backend, `DEBUG`, which replies
+/// with the [support bundle] of the server if it has [`site_debug`] on, and `EXPECT`, which sets a [`Precondition`] for the
+/// next `DELE` or `RNFR`/`RNTO` of the session (e.g.
+/// `SITE EXPECT size=1024 mtime=20200101120000 etag=abc`). `IDNT`, which FTP proxies send before
+/// logging in to tell who the client is (e.g. `SITE IDNT alice@192.0.2.7:50123`), is only
+/// accepted from the [trusted proxies] of the server. Registering a subcommand with the name of a
+/// built-in one replaces it.
+///
+/// [`Precondition`]: ../storage/struct.Precondition.html
+/// [`Server`]: ../server/struct.Server.html
+/// [trusted proxies]: ../server/struct.Server.html#method.trusted_proxy
+/// [support bundle]: ../server/struct.ServerHandle.html#method.support_bundle
+/// [`site_debug`]: ../server/struct.Server.html#method.site_debug
+#[derive(Clone)]
+pub struct SiteCommands {
+    // Keyed by the upper-cased subcommand name, so lookups are case insensitive and `SITE HELP`
+    // lists the subcommands in a stable order.
+    entries: BTreeMap<String, SiteEntry>,
+}
+
+impl SiteCommands {
+    /// Create a new registry containing only the built-in subcommands.
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(
+            "HELP".to_string(),
+            SiteEntry {
+                description: "Show the available SITE commands".to_string(),
+                handler: None,
+            },
+        );
+        entries.insert(
+            "EXPECT".to_string(),
+            SiteEntry {
+                description: "Only DELE or RNTO if the file still matches: EXPECT [size=<bytes>] \
+                              [mtime=<YYYYMMDDHHMMSS>] [etag=<etag>]"
+                    .to_string(),
+                handler: None,
+            },
+        );
+        entries.insert(
+            "CHMOD".to_string(),
+            SiteEntry {
+                description: "Change the permissions of a file: CHMOD <mode> <path>".to_string(),
+                handler: None,
+            },
+        );
+        entries.insert(
+            "IDNT".to_string(),
+            SiteEntry {
+                description: "Tell who the client of a trusted proxy is: \
+                              IDNT [<ident>@]<address>[:<port>]"
+                    .to_string(),
+                handler: None,
+            },
+        );
+        entries.insert(
+            "DEBUG".to_string(),
+            SiteEntry {
+                description: "Show the support bundle of the server, as JSON".to_string(),
+                handler: None,
+            },
+        );
+        entries.insert(
+            "PRESIGN".to_string(),
+            SiteEntry {
+                description: "Get an HTTP URL of a file that expires after the given number of \
+                              seconds: PRESIGN <path> <ttl>"
+                    .to_string(),
+                handler: None,
+            },
+        );
+        SiteCommands { entries }
+    }
+
+    /// Register the given subcommand, replacing any earlier registration with the same name. The
+    /// handler returns its reply right away, so it should not block; use [`register_async`] for
+    /// anything that takes a while.
+    ///
+    /// [`register_async`]: #method.register_async
+    pub fn register<F>(&mut self, name: &str, description: &str, handler: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.register_async(name, description, move |_, args| Ok::<_, ()>(handler(args)));
+    }
+
+    /// Register the given asynchronous subcommand, replacing any earlier registration with the
+    /// same name. The handler also receives the [`SiteContext`] of the session, e.g. to check
+    /// who's asking.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::site::SiteCommands;
+    ///
+    /// let mut commands = SiteCommands::new();
+    /// commands.register_async("WHOAMI", "Show your username", |context, _args| {
+    ///     Ok(format!("200 {}\r\n", context.username.unwrap_or_default()))
+    /// });
+    /// ```
+    ///
+    /// [`SiteContext`]: struct.SiteContext.html
+    pub fn register_async<F, R>(&mut self, name: &str, description: &str, handler: F)
+    where
+        F: Fn(SiteContext, &str) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = String, Error = ()>,
+        R::Future: Send + 'static,
+    {
+        let handler: SiteHandler =
+            Arc::new(move |context, args| Box::new(handler(context, args).into_future()));
+        self.entries.insert(
+            name.to_uppercase(),
+            SiteEntry {
+                description: description.to_string(),
+                handler: Some(handler),
+            },
+        );
+    }
+
+    /// Returns the names and descriptions of all registered subcommands, ordered by name.
+    pub fn descriptions(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.description.as_str()))
+    }
+
+    /// Run the given subcommand with the given arguments on behalf of the session with the given
+    /// context, resolving to the reply for the client.
+    pub fn handle(&self, context: SiteContext, name: &str, args: &str) -> SiteReply {
+        let name = name.to_uppercase();
+        let reply = match self.entries.get(&name) {
+            Some(SiteEntry {
+                handler: Some(handler),
+                ..
+            }) => return handler(context, args),
+            Some(SiteEntry { handler: None, .. }) if name == "HELP" => self.help(),
+            Some(SiteEntry { handler: None, .. }) => {
+                format!("502 SITE {} is handled by the server\r\n", name)
+            }
+            None => format!("500 Unknown SITE command: {}\r\n", name),
+        };
+        Box::new(future::ok(reply))
+    }
+
+    /// Returns whether the given subcommand is the built-in one, rather than a registered
+    /// replacement.
+    pub(crate) fn is_builtin(&self, name: &str) -> bool {
+        match self.entries.get(&name.to_uppercase()) {
+            Some(entry) => entry.handler.is_none(),
+            None => false,
+        }
+    }
+
+    // The reply to `SITE HELP` and `HELP SITE`.
+    pub(crate) fn help(&self) -> String {
+        let width = self.entries.keys().map(String::len).max().unwrap_or(0);
+        let mut reply = "214-The following SITE commands are recognized:\r\n".to_string();
+        for (name, description) in self.descriptions() {
+            reply.push_str(&format!(
+                " {:width$}  {}\r\n",
+                name,
+                description,
+                width = width
+            ));
+        }
+        reply.push_str("214 End\r\n");
+        reply
+    }
+}
+
+impl Default for SiteCommands {
+    fn default() -> Self {
+        SiteCommands::new()
+    }
+}
+
+/// Parses the arguments of `SITE CHMOD`: an octal mode of at most `0o777`, followed by a path.
+pub(crate) fn parse_chmod(args: &str) -> Option<(u32, &str)> {
+    let mut args = args.trim_start().splitn(2, ' ');
+    let mode = args.next()?;
+    let path = args.next()?.trim_start();
+    if path.is_empty() || mode.is_empty() || !mode.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return None;
+    }
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o777 => Some((mode, path)),
+        _ => None,
+    }
+}
+
+/// Parses the arguments of `SITE PRESIGN`: a path, followed by the number of seconds the URL
+/// should stay valid. The path may contain spaces.
+pub(crate) fn parse_presign(args: &str) -> Option<(&str, std::time::Duration)> {
+    let args = args.trim();
+    let split = args.rfind(' ')?;
+    let path = args[..split].trim_end();
+    let ttl: u64 = args[split + 1..].parse().ok()?;
+    if path.is_empty() || ttl == 0 {
+        return None;
+    }
+    Some((path, std::time::Duration::from_secs(ttl)))
+}
+
+/// Parses the arguments of `SITE IDNT`: the address of the client, optionally with its port (`0`
+/// if there's none) and preceded by its ident and an `@`. IPv6 addresses with a port go between
+/// brackets.
+pub(crate) fn parse_idnt(args: &str) -> Option<(Option<&str>, SocketAddr)> {
+    let args = args.trim();
+    let (ident, addr) = match args.rfind('@') {
+        Some(at) => (Some(&args[..at]), &args[at + 1..]),
+        None => (None, args),
+    };
+    if ident == Some("") {
+        return None;
+    }
+    let addr = match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddr::new(addr.parse::<IpAddr>().ok()?, 0),
+    };
+    Some((ident, addr))
+}
+
+/// Parses the arguments of `SITE EXPECT`: any of `size=<bytes>`, `mtime=<YYYYMMDDHHMMSS>` (in UTC,
+/// like `MDTM` replies) and `etag=<etag>`, separated by spaces.
+pub(crate) fn parse_expect(args: &str) -> Option<Precondition> {
+    let mut precondition = Precondition::default();
+    for condition in args.split_whitespace() {
+        let mut condition = condition.splitn(2, '=');
+        let key = condition.next()?.to_lowercase();
+        let value = condition.next()?;
+        match key.as_str() {
+            "size" => precondition.size = Some(value.parse().ok()?),
+            "mtime" => {
+                let time = chrono::NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S").ok()?;
+                let time =
+                    chrono::DateTime::<chrono::Utc>::from_naive_utc_and_offset(time, chrono::Utc);
+                precondition.modified = Some(time.into());
+            }
+            "etag" => precondition.etag = Some(value.trim_matches('"').to_string()),
+            _ => return None,
+        }
+    }
+    Some(precondition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn context() -> SiteContext {
+        SiteContext {
+            session_id: "0123456789abcdef0123456789abcdef".to_string(),
+            username: Some("alice".to_string()),
+            cwd: "/uploads".into(),
+            connection: ConnectionInfo {
+                peer: "10.0.0.1:50123".parse().unwrap(),
+                local: "10.0.0.2:21".parse().unwrap(),
+                ttl: None,
+                proxy: None,
+            },
+        }
+    }
+
+    fn handle(commands: &SiteCommands, name: &str, args: &str) -> String {
+        commands.handle(context(), name, args).wait().unwrap()
+    }
+
+    #[test]
+    fn help_lists_builtin_and_registered_commands() {
+        let mut commands = SiteCommands::new();
+        commands.register("purge", "Purge the CDN cache", |_| {
+            "200 Purged\r\n".to_string()
+        });
+
+        assert_eq!(
+            handle(&commands, "help", ""),
+            "214-The following SITE commands are recognized:\r\n \
+             CHMOD    Change the permissions of a file: CHMOD <mode> <path>\r\n \
+             DEBUG    Show the support bundle of the server, as JSON\r\n \
+             EXPECT   Only DELE or RNTO if the file still matches: EXPECT [size=<bytes>] \
+             [mtime=<YYYYMMDDHHMMSS>] [etag=<etag>]\r\n \
+             HELP     Show the available SITE commands\r\n \
+             IDNT     Tell who the client of a trusted proxy is: IDNT [<ident>@]<address>[:<port>]\r\n \
+             PRESIGN  Get an HTTP URL of a file that expires after the given number of seconds: \
+             PRESIGN <path> <ttl>\r\n \
+             PURGE    Purge the CDN cache\r\n\
+             214 End\r\n"
+        );
+    }
+
+    #[test]
+    fn handle_dispatches_to_registered_command() {
+        let mut commands = SiteCommands::new();
+        commands.register("ECHO", "Echo the arguments", |args| {
+            format!("200 {}\r\n", args)
+        });
+
+        assert_eq!(
+            handle(&commands, "echo", "hello there"),
+            "200 hello there\r\n"
+        );
+        assert_eq!(
+            handle(&commands, "BOGUS", ""),
+            "500 Unknown SITE command: BOGUS\r\n"
+        );
+    }
+
+    #[test]
+    fn async_handler_gets_the_context() {
+        let mut commands = SiteCommands::new();
+        commands.register_async("WHERE", "Show the session's directory", |context, args| {
+            let args = args.to_string();
+            future::lazy(move || {
+                Ok(format!(
+                    "200 {} is in {} ({})\r\n",
+                    context.username.unwrap(),
+                    context.cwd.display(),
+                    args
+                ))
+            })
+        });
+        commands.register_async("FAIL", "Always fails", |_, _| Err(()));
+
+        assert_eq!(
+            handle(&commands, "where", "now"),
+            "200 alice is in /uploads (now)\r\n"
+        );
+        assert_eq!(commands.handle(context(), "FAIL", "").wait(), Err(()));
+    }
+
+    #[test]
+    fn chmod_arguments() {
+        assert_eq!(parse_chmod("644 file.txt"), Some((0o644, "file.txt")));
+        assert_eq!(
+            parse_chmod("0755  dir/my script.sh"),
+            Some((0o755, "dir/my script.sh"))
+        );
+        assert_eq!(parse_chmod("644"), None);
+        assert_eq!(parse_chmod("rwx file.txt"), None);
+        assert_eq!(parse_chmod("+x file.txt"), None);
+        // No setuid, setgid or sticky bits
+        assert_eq!(parse_chmod("4755 file.txt"), None);
+    }
+
+    #[test]
+    fn expect_arguments() {
+        assert_eq!(parse_expect(""), Some(Precondition::default()));
+        assert_eq!(
+            parse_expect("size=1024 mtime=19700101000010 etag=\"abc\""),
+            Some(Precondition {
+                size: Some(1024),
+                modified: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(10)),
+                etag: Some("abc".to_string()),
+            })
+        );
+        assert_eq!(parse_expect("size=big"), None);
+        assert_eq!(parse_expect("mtime=yesterday"), None);
+        assert_eq!(parse_expect("owner=alice"), None);
+    }
+
+    #[test]
+    fn presign_arguments() {
+        assert_eq!(
+            parse_presign("big.iso 3600"),
+            Some(("big.iso", std::time::Duration::from_secs(3600)))
+        );
+        assert_eq!(
+            parse_presign(" my videos/big one.mp4   600 "),
+            Some(("my videos/big one.mp4", std::time::Duration::from_secs(600)))
+        );
+        assert_eq!(parse_presign("big.iso"), None);
+        assert_eq!(parse_presign("big.iso 0"), None);
+        assert_eq!(parse_presign("big.iso 1h"), None);
+        assert_eq!(parse_presign(" 3600"), None);
+    }
+
+    #[test]
+    fn idnt_arguments() {
+        assert_eq!(
+            parse_idnt("alice@192.0.2.7:50123"),
+            Some((Some("alice"), "192.0.2.7:50123".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_idnt(" 192.0.2.7 "),
+            Some((None, "192.0.2.7:0".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_idnt("bob@[2001:db8::7]:21000"),
+            Some((Some("bob"), "[2001:db8::7]:21000".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_idnt("2001:db8::7"),
+            Some((None, "[2001:db8::7]:0".parse().unwrap()))
+        );
+        assert_eq!(parse_idnt("@192.0.2.7"), None);
+        assert_eq!(parse_idnt("alice@client.example"), None);
+        assert_eq!(parse_idnt(""), None);
+    }
+}
@@ -0,0 +1,379 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Async, Future, Poll};
+use log::warn;
+use tokio::prelude::{AsyncRead, AsyncWrite};
+
+/// Where the sessions of a `Server` keep their temporary files, and how many bytes each session
+/// may keep there. Every session gets a [`Staging`] directory of its own in it, which is created
+/// when it's first needed and removed with everything in it when the session ends. By default,
+/// that's in `firetrap` in the temporary directory of the system, without a limit.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::staging::StagingArea;
+///
+/// let staging = StagingArea::new("/var/tmp/ftp").limit(1 << 30);
+/// let server = Server::with_root("/tmp").staging(staging).atomic_uploads(true);
+/// ```
+///
+/// [`Staging`]: ./struct.Staging.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct StagingArea {
+    dir: PathBuf,
+    limit: Option<u64>,
+}
+
+impl StagingArea {
+    /// Keep the staging directories of the sessions in the given directory.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        StagingArea {
+            dir: dir.into(),
+            limit: None,
+        }
+    }
+
+    /// Set the number of bytes that every session may keep in its staging directory at any time.
+    /// Writes beyond that fail with `StorageFull`.
+    pub fn limit(mut self, bytes: u64) -> Self {
+        self.limit = Some(bytes);
+        self
+    }
+
+    /// Returns the staging directory of the session with the given ID. Nothing is created on
+    /// disk until the first file in it is.
+    pub fn session(&self, id: &str) -> Staging {
+        Staging::new(
+            self.dir.join(format!("session-{}", id)),
+            Arc::new(Budget {
+                used: AtomicU64::new(0),
+                limit: self.limit,
+            }),
+        )
+    }
+}
+
+impl Default for StagingArea {
+    fn default() -> Self {
+        StagingArea::new(std::env::temp_dir().join("firetrap"))
+    }
+}
+
+// The bytes that the files of a session's staging directory take, against its limit.
+#[derive(Debug)]
+struct Budget {
+    used: AtomicU64,
+    limit: Option<u64>,
+}
+
+impl Budget {
+    // Returns `false` if the bytes don't fit.
+    fn reserve(&self, bytes: u64) -> bool {
+        let limit = self.limit.unwrap_or(u64::MAX);
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Dir {
+    path: PathBuf,
+    budget: Arc<Budget>,
+    created: AtomicBool,
+    // Makes sure the directory the staging directory is in outlives it.
+    parent: Option<Staging>,
+    // The names of the files and directories in it so far.
+    names: AtomicU64,
+    creating: Mutex<()>,
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        if !self.created.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!(
+                "Failed to remove the staging directory {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A temporary directory for the files that features like atomic uploads keep before they're
+/// done with them. Clones share the directory, which is removed with everything in it when the
+/// last clone is dropped. A session has one for as long as it lives, and can hand out
+/// [`subdir`]s to transfers that need a directory of their own. The files in a staging directory
+/// and its subdirectories share the limit of its [`StagingArea`].
+///
+/// [`subdir`]: #method.subdir
+/// [`StagingArea`]: ./struct.StagingArea.html
+#[derive(Clone, Debug)]
+pub struct Staging {
+    dir: Arc<Dir>,
+}
+
+impl Staging {
+    fn new(path: PathBuf, budget: Arc<Budget>) -> Self {
+        Staging {
+            dir: Arc::new(Dir {
+                path,
+                budget,
+                created: AtomicBool::new(false),
+                parent: None,
+                names: AtomicU64::new(0),
+                creating: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns the path of the directory. It only exists once something was created in it.
+    pub fn path(&self) -> &Path {
+        &self.dir.path
+    }
+
+    /// Returns the number of bytes in the files of this staging directory, and of the other
+    /// directories that share its limit.
+    pub fn used(&self) -> u64 {
+        self.dir.budget.used.load(Ordering::SeqCst)
+    }
+
+    fn name(&self) -> PathBuf {
+        let name = self.dir.names.fetch_add(1, Ordering::SeqCst) + 1;
+        self.dir.path.join(name.to_string())
+    }
+
+    // Creates the directory (and the ones it's in) if it doesn't exist yet.
+    fn create(&self) -> io::Result<()> {
+        if self.dir.created.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let _creating = self.dir.creating.lock().unwrap();
+        if let Some(parent) = &self.dir.parent {
+            parent.create()?;
+        }
+        if !self.dir.created.load(Ordering::SeqCst) {
+            std::fs::create_dir_all(&self.dir.path)?;
+            self.dir.created.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Returns a new staging directory in this one, e.g. for a single transfer, which is removed
+    /// when it's dropped (or this one is).
+    pub fn subdir(&self) -> Staging {
+        Staging {
+            dir: Arc::new(Dir {
+                path: self.name(),
+                budget: Arc::clone(&self.dir.budget),
+                created: AtomicBool::new(false),
+                parent: Some(self.clone()),
+                names: AtomicU64::new(0),
+                creating: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Creates a new, empty file in the staging directory, which is removed when it's dropped.
+    pub fn file(&self) -> Box<dyn Future<Item = StagedFile, Error = io::Error> + Send> {
+        if let Err(e) = self.create() {
+            return Box::new(future::err(e));
+        }
+        let path = self.name();
+        let staging = self.clone();
+        Box::new(
+            tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path.clone())
+                .map(move |file| StagedFile {
+                    file,
+                    path,
+                    len: 0,
+                    staging,
+                }),
+        )
+    }
+
+    /// Copies everything the given reader has to a new file in the staging directory, and
+    /// resolves to that file once it's all there, ready to be read from the start.
+    pub fn stage<R: AsyncRead + Send + 'static>(
+        &self,
+        reader: R,
+    ) -> Box<dyn Future<Item = StagedFile, Error = io::Error> + Send> {
+        Box::new(
+            self.file()
+                .and_then(|file| tokio_io::io::copy(reader, file))
+                .and_then(|(_, _, file)| tokio_io::io::flush(file))
+                .and_then(StagedFile::rewind),
+        )
+    }
+}
+
+/// A file in a [`Staging`] directory, which can be written to and read back, and is removed when
+/// it's dropped. What's written to it counts against the limit of the staging directory until
+/// then.
+///
+/// [`Staging`]: ./struct.Staging.html
+#[derive(Debug)]
+pub struct StagedFile {
+    file: tokio::fs::File,
+    path: PathBuf,
+    len: u64,
+    staging: Staging,
+}
+
+impl StagedFile {
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of bytes written to the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns whether nothing was written to the file.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves back to the start of the file, to read what was written to it.
+    pub fn rewind(self) -> Box<dyn Future<Item = StagedFile, Error = io::Error> + Send> {
+        let mut file = Some(self);
+        Box::new(future::poll_fn(move || {
+            futures::try_ready!(file
+                .as_mut()
+                .expect("polled after completion")
+                .file
+                .poll_seek(io::SeekFrom::Start(0)));
+            Ok(Async::Ready(file.take().unwrap()))
+        }))
+    }
+}
+
+impl Read for StagedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl AsyncRead for StagedFile {}
+
+impl Write for StagedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let budget = &self.staging.dir.budget;
+        if !budget.reserve(buf.len() as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "The staging directory is full",
+            ));
+        }
+        match self.file.write(buf) {
+            Ok(n) => {
+                budget.release((buf.len() - n) as u64);
+                self.len += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                budget.release(buf.len() as u64);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl AsyncWrite for StagedFile {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.file.shutdown()
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        self.staging.dir.budget.release(self.len);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove the staged file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn run<F: Future + Send + 'static>(future: F) -> Result<F::Item, F::Error>
+    where
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn stages_files_and_cleans_up() {
+        let root = tempfile::TempDir::new().unwrap();
+        let staging = StagingArea::new(root.path()).session("abc");
+        assert!(!staging.path().exists());
+
+        let file = run(staging.stage(Cursor::new(b"staged".to_vec()))).unwrap();
+        assert_eq!(file.len(), 6);
+        assert_eq!(staging.used(), 6);
+        let path = file.path().to_path_buf();
+        assert!(path.starts_with(root.path().join("session-abc")));
+        let (_, contents) = run(tokio_io::io::read_to_end(file, vec![])).unwrap();
+        assert_eq!(contents, b"staged");
+        assert!(!path.exists());
+        assert_eq!(staging.used(), 0);
+
+        let transfer = staging.subdir();
+        let kept = run(transfer.stage(Cursor::new(b"kept".to_vec()))).unwrap();
+        let transfer_path = transfer.path().to_path_buf();
+        drop(transfer);
+        // The file keeps its directory around.
+        assert!(kept.path().exists());
+        drop(kept);
+        assert!(!transfer_path.exists());
+        assert!(staging.path().exists());
+        drop(staging);
+        assert!(!root.path().join("session-abc").exists());
+    }
+
+    #[test]
+    fn limits_the_staged_bytes() {
+        let root = tempfile::TempDir::new().unwrap();
+        let staging = StagingArea::new(root.path()).limit(10).session("abc");
+        let first = run(staging.stage(Cursor::new(vec![0; 8]))).unwrap();
+        let error = run(staging.subdir().stage(Cursor::new(vec![0; 8]))).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(staging.used(), 8);
+        drop(first);
+        run(staging.stage(Cursor::new(vec![0; 10]))).unwrap();
+    }
+}
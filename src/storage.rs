@@ -2,8 +2,109 @@ use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use std::{fmt, result};
 
+use async_trait::async_trait;
 use chrono::prelude::*;
 use futures::{future, Future, Stream};
+use futures03::compat::{Future01CompatExt, Stream01CompatExt};
+use futures03::StreamExt;
+use log::warn;
+
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+/// Golden test vectors for the directory listing formats, for use in tests of storage backends.
+pub mod fixtures;
+
+/// Checks of the promises that the [`StorageBackend`] trait makes about every backend, for use in
+/// tests of storage backends.
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+pub mod conformance;
+
+mod checksum;
+pub use self::checksum::{HashAlgorithm, Hasher};
+
+mod multipart;
+pub use self::multipart::MultipartStrategy;
+
+mod case_insensitive;
+pub use self::case_insensitive::{CaseCollision, CaseInsensitive};
+
+mod memory;
+pub use self::memory::{Memory, MemoryMetadata};
+
+mod read_only;
+pub use self::read_only::ReadOnly;
+
+mod user_rooted;
+pub use self::user_rooted::UserRooted;
+
+mod throttled;
+pub use self::throttled::{Throttled, ThrottledFile};
+
+mod trash_bin;
+pub use self::trash_bin::{Retention, TrashBin};
+
+mod versioned;
+pub use self::versioned::Versioned;
+
+mod write_once;
+pub use self::write_once::{FileExists, WriteOnce};
+
+mod archive;
+pub use self::archive::{Archive, ArchiveFile, ArchiveMetadata};
+
+mod cached;
+pub use self::cached::{Cached, DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL};
+
+mod mounts;
+pub use self::mounts::{MountMetadata, Mounts};
+
+mod overlay;
+pub use self::overlay::Overlay;
+
+pub mod quota;
+
+pub mod audit;
+
+pub mod scan;
+
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+
+/// A storage backend that passes everything on to an SFTP server.
+#[cfg(feature = "sftp")]
+pub mod sftp;
+
+/// A storage backend that passes everything on to another FTP server.
+#[cfg(feature = "ftp")]
+pub mod ftp;
+
+/// A storage backend for Backblaze B2.
+#[cfg(feature = "b2")]
+pub mod b2;
+#[cfg(any(
+    feature = "s3",
+    feature = "gcs",
+    feature = "b2",
+    feature = "webdav",
+    feature = "hdfs"
+))]
+mod cloud;
+/// A storage backend for Google Cloud Storage.
+#[cfg(feature = "gcs")]
+pub mod gcs;
+/// A storage backend for HDFS, through the WebHDFS REST API.
+#[cfg(feature = "hdfs")]
+pub mod hdfs;
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+mod prefixes;
+/// A storage backend for Amazon S3 and compatible object stores.
+#[cfg(feature = "s3")]
+pub mod s3;
+/// A storage backend for WebDAV servers, like Nextcloud and SharePoint.
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 /// Represents the Metadata of a file
 pub trait Metadata {
@@ -19,6 +120,12 @@ pub trait Metadata {
     /// Returns true if the path is a file.
     fn is_file(&self) -> bool;
 
+    /// Returns true if the path is a symbolic link. The default implementation returns `false`,
+    /// for backends that don't have them.
+    fn is_symlink(&self) -> bool {
+        false
+    }
+
     /// Returns the last modified time of the path.
     fn modified(&self) -> Result<SystemTime>;
 
@@ -27,6 +134,49 @@ pub trait Metadata {
 
     /// Returns the `uid` of the file.
     fn uid(&self) -> u32;
+
+    /// Returns the entity tag of the file: an opaque value that changes whenever the contents of
+    /// the file change, like the ETag of an object in S3. The default implementation returns
+    /// `None`, for backends that don't have them.
+    fn etag(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The state a file is expected to be in before it is deleted or renamed, so that automation
+/// doesn't clobber a file that was changed after it was inspected. Every given field has to
+/// match.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Precondition {
+    /// The expected size, in bytes.
+    pub size: Option<u64>,
+    /// The expected last modified time, to the second.
+    pub modified: Option<SystemTime>,
+    /// The expected entity tag. Files without one never match.
+    pub etag: Option<String>,
+}
+
+impl Precondition {
+    /// Returns whether the given metadata matches this precondition.
+    pub fn matches<M: Metadata>(&self, metadata: &M) -> bool {
+        // Compare modification times at the resolution clients can express (e.g. with `MDTM`).
+        let seconds = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .ok()
+        };
+        self.size.is_none_or(|size| metadata.len() == size)
+            && self.modified.is_none_or(|modified| {
+                metadata
+                    .modified()
+                    .ok()
+                    .is_some_and(|actual| seconds(actual) == seconds(modified))
+            })
+            && self
+                .etag
+                .as_ref()
+                .is_none_or(|etag| metadata.etag().as_ref() == Some(etag))
+    }
 }
 
 /// Fileinfo contains the path and `Metadata` of a file.
@@ -43,180 +193,585 @@ where
     pub metadata: M,
 }
 
-impl<P, M> std::fmt::Display for Fileinfo<P, M>
+/// Options that control how a [`Fileinfo`] is rendered in human-oriented listings, like the
+/// output of the `LIST` command.
+///
+/// Machine-oriented output (e.g. `NLST` or `SIZE`) never depends on these options, so that the
+/// values clients parse stay exact.
+///
+/// [`Fileinfo`]: ./struct.Fileinfo.html
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ListOptions {
+    /// Render sizes like `ls -h` does (e.g. `1.5K`) instead of as an exact number of bytes. This
+    /// is off by default, since many clients parse the size column of `LIST` output.
+    pub human_readable_sizes: bool,
+    /// What to do with file names that contain control characters, like a line feed, which would
+    /// corrupt the listing. See [`ControlChars`].
+    ///
+    /// [`ControlChars`]: ./enum.ControlChars.html
+    pub control_chars: ControlChars,
+}
+
+/// The policy for file names with control characters (e.g. CR or LF) in directory listings.
+/// Sending such names as they are would break the line-based parsing of clients, or even let a
+/// file name inject fake entries into the listing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ControlChars {
+    /// Replace every control character with a `?`, like `ls -q` does. This is the default.
+    #[default]
+    Replace,
+    /// Leave the file out of the listing and log a warning.
+    Skip,
+    /// Encode CR and LF the way RFC 959 does for pathnames: a CR is followed by a NUL and a LF is
+    /// sent as a NUL. Other control characters are sent as they are, since they don't break the
+    /// lines of the listing.
+    Encode,
+}
+
+impl ControlChars {
+    /// Applies this policy to the given name. Returns `None` if the file should be skipped.
+    fn apply(self, name: &str) -> Option<String> {
+        if !name.chars().any(char::is_control) {
+            return Some(name.to_string());
+        }
+        match self {
+            ControlChars::Replace => Some(
+                name.chars()
+                    .map(|c| if c.is_control() { '?' } else { c })
+                    .collect(),
+            ),
+            ControlChars::Skip => None,
+            ControlChars::Encode => Some(name.replace('\r', "\r\0").replace('\n', "\0")),
+        }
+    }
+}
+
+impl<P, M> Fileinfo<P, M>
 where
     P: AsRef<Path>,
     M: Metadata,
 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    /// Formats this file as a single (`ls -l` like) line of a directory listing, without the
+    /// trailing line terminator.
+    ///
+    /// Control characters in the name are handled according to [`ListOptions::control_chars`].
+    /// Since a single line can't be skipped, they are replaced when that is
+    /// [`ControlChars::Skip`]; use [`format_listed`] to leave the file out instead.
+    ///
+    /// [`ListOptions::control_chars`]: ./struct.ListOptions.html#structfield.control_chars
+    /// [`ControlChars::Skip`]: ./enum.ControlChars.html#variant.Skip
+    /// [`format_listed`]: #method.format_listed
+    pub fn format(&self, options: ListOptions) -> String {
+        let name = self.name();
+        let name = options
+            .control_chars
+            .apply(&name)
+            .or_else(|| ControlChars::Replace.apply(&name))
+            .unwrap_or_default();
+        self.format_with_name(options, &name)
+    }
+
+    /// Like [`format`], but returns `None` if the file should be left out of the listing because
+    /// of [`ControlChars::Skip`].
+    ///
+    /// [`format`]: #method.format
+    /// [`ControlChars::Skip`]: ./enum.ControlChars.html#variant.Skip
+    pub fn format_listed(&self, options: ListOptions) -> Option<String> {
+        let name = self.name();
+        match options.control_chars.apply(&name) {
+            Some(name) => Some(self.format_with_name(options, &name)),
+            None => {
+                warn!(
+                    "Leaving {:?} out of the listing, its name contains control characters",
+                    self.path.as_ref()
+                );
+                None
+            }
+        }
+    }
+
+    /// Formats this file as an entry of an `MLST` or `MLSD` listing (RFC 3659): the facts about
+    /// the file, followed by a space and the full path, e.g.
+    /// `type=file;size=10;modify=20200101120000;UNIX.uid=0;UNIX.gid=0; /data/file.txt`. The
+    /// `MLST` reply puts this on a line that starts with a space.
+    ///
+    /// CRs and LFs in the path are encoded like [`ControlChars::Encode`] does, because RFC 3659
+    /// requires that.
+    ///
+    /// [`ControlChars::Encode`]: ./enum.ControlChars.html#variant.Encode
+    pub fn format_facts(&self) -> String {
+        let filetype = if self.metadata.is_symlink() {
+            "OS.unix=symlink"
+        } else if self.metadata.is_dir() {
+            "dir"
+        } else {
+            "file"
+        };
+        let mut facts = format!("type={};size={};", filetype, self.metadata.len());
+        if let Ok(modified) = self.metadata.modified() {
+            let modified: DateTime<Utc> = modified.into();
+            facts.push_str(&format!("modify={};", modified.format("%Y%m%d%H%M%S")));
+        }
+        let path = self.path.as_ref().to_string_lossy();
+        format!(
+            "{}UNIX.uid={};UNIX.gid={}; {}",
+            facts,
+            self.metadata.uid(),
+            self.metadata.gid(),
+            ControlChars::Encode.apply(&path).unwrap_or_default()
+        )
+    }
+
+    // The last component of the path.
+    fn name(&self) -> String {
+        self.path
+            .as_ref()
+            .components()
+            .next_back()
+            .unwrap()
+            .as_os_str()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn format_with_name(&self, options: ListOptions, name: &str) -> String {
         let modified: DateTime<Local> =
             DateTime::from(self.metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
-        write!(
-            f,
-            "{filetype}{permissions}     {owner} {group} {size} {modified} {path}",
-            filetype = if self.metadata.is_dir() { "d" } else { "-" },
-            // TODO: Don't hardcode permissions ;)
-            permissions = "rwxr-xr-x",
+        let size = if options.human_readable_sizes {
+            human_readable_size(self.metadata.len())
+        } else {
+            self.metadata.len().to_string()
+        };
+        // TODO: Don't hardcode permissions ;)
+        format!(
+            "{filetype}rwxr-xr-x     {owner} {group} {size} {modified} {path}",
+            filetype = if self.metadata.is_symlink() {
+                "l"
+            } else if self.metadata.is_dir() {
+                "d"
+            } else {
+                "-"
+            },
             // TODO: Consider showing canonical names here
             owner = self.metadata.uid(),
             group = self.metadata.gid(),
-            size = self.metadata.len(),
+            size = size,
             modified = modified.format("%b %d %Y"),
-            path = self
-                .path
-                .as_ref()
-                .components()
-                .last()
-                .unwrap()
-                .as_os_str()
-                .to_string_lossy(),
+            path = name,
         )
     }
 }
 
+impl<P, M> std::fmt::Display for Fileinfo<P, M>
+where
+    P: AsRef<Path>,
+    M: Metadata,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(ListOptions::default()))
+    }
+}
+
+/// Formats the given number of bytes the way `ls -h` does: values below 1024 are shown as is,
+/// bigger values get one decimal below 10 and none above, followed by a binary unit suffix.
+pub(crate) fn human_readable_size(len: u64) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+
+    if len < 1024 {
+        return len.to_string();
+    }
+
+    let mut size = len as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if size < 10.0 {
+        format!("{:.1}{}", size, UNITS[unit])
+    } else {
+        format!("{:.0}{}", size, UNITS[unit])
+    }
+}
+
 /// The `Storage` trait defines a common interface to different storage backends for our FTP
 /// [`Server`], e.g. for a [`Filesystem`] or GCP buckets.
 ///
+/// Its operations are `async` methods, through the [`async_trait`] attribute, which
+/// implementations need as well: `#[async_trait] impl StorageBackend for MyBackend { ... }`.
+/// Directory listings are still futures 0.1 `Stream`s, like the data connections they're sent
+/// over, as there's no `Stream` in the standard library yet.
+///
+/// # Cancellation
+///
+/// The server drops the futures of storage operations that are no longer needed, for instance
+/// when the client aborts a transfer with `ABOR` or its connection goes away. Backends have to
+/// leave the storage in a state that makes sense when that happens:
+///
+/// * Dropping the futures of the methods that only read, like `stat`, `list`, `get` and
+///   `checksum`, has no effect on the storage.
+/// * When a `put` or `put_unique` is dropped before it finishes, the file is left as it was
+///   before or not at all, but never partially written.
+/// * When an `append` is dropped before it finishes, the file is left as it was before the
+///   append. If the append created it, it may be gone.
+/// * `del`, `mkd`, `rmd`, `rename`, `set_mtime` and `chmod` are either done or not done when
+///   dropped, never half done. Dropping an `rmd_recursive` may leave some of the entries
+///   deleted, as may dropping the implementations of `del_if` and `rename_if` once they've
+///   checked the precondition.
+///
+/// The writes can be checked with [`conformance::check_cancellation`] in the tests of a backend.
+///
 /// [`Server`]: ../server/struct.Server.html
 /// [`filesystem`]: ./struct.Filesystem.html
-pub trait StorageBackend {
+/// [`async_trait`]: https://docs.rs/async-trait
+/// [`conformance::check_cancellation`]: ./conformance/fn.check_cancellation.html
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
     /// The concrete type of the Files returned by this StorageBackend.
     type File;
     /// The concrete type of the `Metadata` used by this StorageBackend.
     type Metadata;
-    /// The concrete type of the error returned by this StorageBackend.
-    type Error;
+    /// The concrete type of the error returned by this StorageBackend. It converts to an
+    /// [`Error`], whose [`ErrorKind`] tells the server what reply to give.
+    ///
+    /// [`Error`]: ./struct.Error.html
+    /// [`ErrorKind`]: ./enum.ErrorKind.html
+    type Error: Into<Error>;
+
+    /// Called once the client has successfully logged in, before any other operation, with the
+    /// [`User`] details provided by the [`Authenticator`]. Backends can use it to apply per-user
+    /// settings. The default implementation does nothing.
+    ///
+    /// [`User`]: ../auth/struct.User.html
+    /// [`Authenticator`]: ../auth/trait.Authenticator.html
+    fn set_user(&mut self, _user: &User) {}
 
     /// Returns the `Metadata` for the given file.
     ///
     /// [`Metadata`]: ./trait.Metadata.html
-    fn stat<P: AsRef<Path>>(
+    async fn stat<P: AsRef<Path> + Send>(
         &self,
         path: P,
-    ) -> Box<Future<Item = Self::Metadata, Error = Self::Error> + Send>;
+    ) -> result::Result<Self::Metadata, Self::Error>;
 
     /// Returns the list of files in the given directory.
     fn list<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> Box<Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
     where
         <Self as StorageBackend>::Metadata: Metadata;
 
-    /// Returns some bytes that make up a directory listing that can immediately be sent to the
-    /// client.
+    /// Returns a directory listing rendered according to the given [`ListOptions`], as chunks of
+    /// bytes that can be sent to the client as soon as they come in: the listing doesn't have to
+    /// be built up in memory first, however large the directory.
+    ///
+    /// [`ListOptions`]: ./struct.ListOptions.html
     fn list_fmt<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> Box<Future<Item = std::io::Cursor<Vec<u8>>, Error = std::io::Error> + Send>
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
     where
         <Self as StorageBackend>::Metadata: Metadata + 'static,
         <Self as StorageBackend>::Error: Send + 'static,
     {
-        let res = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
-
-        let stream: Box<
-            Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
-        > = self.list(path);
-        let res_work = res.clone();
-        let fut = stream
-            .for_each(move |file: Fileinfo<std::path::PathBuf, Self::Metadata>| {
-                let mut res = res_work.lock().unwrap();
-                let fmt = format!("{}\r\n", file);
-                let fmt_vec = fmt.into_bytes();
-                res.extend_from_slice(&fmt_vec);
-                Ok(())
-            })
-            .and_then(|_| Ok(()))
-            .map(move |_| {
-                std::sync::Arc::try_unwrap(res)
-                    .expect("failed try_unwrap")
-                    .into_inner()
-                    .unwrap()
-            })
-            .map(move |res| std::io::Cursor::new(res))
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "shut up"));
-
-        Box::new(fut)
+        Box::new(
+            self.list(path)
+                .filter_map(move |file| {
+                    file.format_listed(options)
+                        .map(|line| format!("{}\r\n", line).into_bytes())
+                })
+                .map_err(|e| std::io::Error::from(e.into())),
+        )
     }
 
-    /// Returns some bytes that make up a NLST directory listing (only the basename) that can
-    /// immediately be sent to the client.
+    /// Returns a NLST directory listing (only the basenames), as chunks of bytes that can be sent
+    /// to the client as soon as they come in.
     fn nlst<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> Box<Future<Item = std::io::Cursor<Vec<u8>>, Error = std::io::Error> + Send>
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
     where
         <Self as StorageBackend>::Metadata: Metadata + 'static,
         <Self as StorageBackend>::Error: Send + 'static,
     {
-        let res = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
-
-        let stream: Box<
-            Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
-        > = self.list(path);
-        let res_work = res.clone();
-        let fut = stream
-            .for_each(move |file: Fileinfo<std::path::PathBuf, Self::Metadata>| {
-                let mut res = res_work.lock().unwrap();
-                let fmt = format!(
-                    "{}\r\n",
-                    file.path
-                        .file_name()
-                        .unwrap_or_else(|| std::ffi::OsStr::new(""))
-                        .to_str()
-                        .unwrap_or("")
-                );
-                let fmt_vec = fmt.into_bytes();
-                res.extend_from_slice(&fmt_vec);
-                Ok(())
-            })
-            .and_then(|_| Ok(()))
-            .map(move |_| {
-                std::sync::Arc::try_unwrap(res)
-                    .expect("failed try_unwrap")
-                    .into_inner()
-                    .unwrap()
-            })
-            .map(move |res| std::io::Cursor::new(res))
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "shut up"));
-
-        Box::new(fut)
+        Box::new(
+            self.list(path)
+                .map(|file| {
+                    format!(
+                        "{}\r\n",
+                        file.path
+                            .file_name()
+                            .unwrap_or_else(|| std::ffi::OsStr::new(""))
+                            .to_str()
+                            .unwrap_or("")
+                    )
+                    .into_bytes()
+                })
+                .map_err(|e| std::io::Error::from(e.into())),
+        )
     }
 
     /// Returns the content of the given file.
-    // TODO: Future versions of Rust will probably allow use to use `impl Future<...>` here. Use it
-    // if/when available. By that time, also see if we can replace Self::File with the AsyncRead
-    // Trait.
-    fn get<P: AsRef<Path>>(
+    // TODO: See if we can replace Self::File with the AsyncRead trait.
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> result::Result<Self::File, Self::Error>;
+
+    /// Returns the bytes of the given file in the given range, or as far as the file goes if it
+    /// ends before the range does. It's used for `RANG`, which lets clients download a file in
+    /// segments, in parallel.
+    ///
+    /// The default implementation reads the bytes before the range from [`get`] and throws them
+    /// away. Backends that can seek or request ranges should override it.
+    ///
+    /// [`get`]: #tymethod.get
+    async fn get_range<P: AsRef<Path> + Send>(
         &self,
         path: P,
-    ) -> Box<Future<Item = Self::File, Error = Self::Error> + Send>;
+        range: std::ops::Range<u64>,
+    ) -> result::Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        use std::io::Read;
+
+        let file = self.get(path).await?;
+        let len = range.end.saturating_sub(range.start);
+        Ok(Box::new(
+            Skip {
+                inner: file,
+                remaining: range.start,
+            }
+            .take(len),
+        ))
+    }
 
     /// Write the given bytes to the given file.
-    fn put<P: AsRef<Path>, R: tokio::prelude::AsyncRead + Send + 'static>(
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> result::Result<u64, Self::Error>;
+
+    /// Write the given bytes to the given file, which must not exist yet. Unlike [`put`] this
+    /// never overwrites an existing file; it fails instead. It's used for `STOU`.
+    ///
+    /// [`put`]: #tymethod.put
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> result::Result<u64, Self::Error>;
+
+    /// Append the given bytes to the given file, creating the file if it doesn't exist yet.
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
         &self,
         bytes: R,
         path: P,
-    ) -> Box<Future<Item = u64, Error = Self::Error> + Send>;
+    ) -> result::Result<u64, Self::Error>;
+
+    /// Returns the number of bytes that can still be stored in the given directory, or `None` if
+    /// the backend doesn't know (or there is no real limit, like with most object stores). It's
+    /// used to reject `ALLO` requests for uploads that won't fit. The default implementation
+    /// returns `None`.
+    async fn free_space<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+    ) -> result::Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Returns a URL that lets anyone download the given file over HTTP, bypassing the FTP server,
+    /// until `ttl` has passed. Returns `None` if the backend can't hand out such URLs (like the
+    /// local filesystem). Object stores implement it with their presigned (or signed) URLs, so
+    /// `SITE PRESIGN` can hand very large downloads off to HTTP. The default implementation
+    /// returns `None`.
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+        _ttl: std::time::Duration,
+    ) -> result::Result<Option<String>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Called right before every data transfer of the session, with the options the client
+    /// negotiated for it. A storage backend is created for every session, so wrappers can keep
+    /// them to e.g. record with an upload whether line endings were converted. The default
+    /// implementation ignores them.
+    fn transfer_options(&self, _options: &NegotiatedOptions) {}
+
+    /// Called right before every data transfer of the session, with the ID that the
+    /// [`TransferEnded`] event for it will carry. Backends that talk to a cloud provider should
+    /// send it along with the requests they make for the transfer, e.g. in a header or as a suffix
+    /// of the user agent, so the provider's request logs can be traced back to the transfer. The
+    /// default implementation ignores it.
+    ///
+    /// [`TransferEnded`]: ../events/struct.TransferEnded.html
+    fn transfer_id(&self, _id: &str) {}
 
     /// Delete the given file.
-    fn del<P: AsRef<Path>>(&self, path: P) -> Box<Future<Item = (), Error = Self::Error> + Send>;
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> result::Result<(), Self::Error>;
+
+    /// Delete the given file, but only if it matches the given [`Precondition`]. Resolves to
+    /// whether the file was deleted.
+    ///
+    /// The default implementation checks the precondition against the result of [`stat`] first,
+    /// so another client could still change the file in between. Backends with conditional
+    /// operations (e.g. on the ETag of an S3 object) should override it to use those.
+    ///
+    /// [`Precondition`]: ./struct.Precondition.html
+    /// [`stat`]: #tymethod.stat
+    async fn del_if(
+        &self,
+        path: PathBuf,
+        precondition: Precondition,
+    ) -> result::Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        if !precondition.matches(&self.stat(&path).await?) {
+            return Ok(false);
+        }
+        self.del(path).await?;
+        Ok(true)
+    }
 
     /// Create the given directory.
-    fn mkd<P: AsRef<Path>>(&self, path: P) -> Box<Future<Item = (), Error = Self::Error> + Send>;
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> result::Result<(), Self::Error>;
+
+    /// Remove the given directory. Fails if the directory isn't empty.
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> result::Result<(), Self::Error>;
+
+    /// Remove the given directory together with everything in it. Symbolic links are removed
+    /// rather than followed.
+    ///
+    /// The default implementation walks the tree with [`list`], removing one entry at a time with
+    /// [`del`] and [`rmd`]. Backends that can delete in batches (like most object stores) should
+    /// override it.
+    ///
+    /// [`list`]: #tymethod.list
+    /// [`del`]: #tymethod.del
+    /// [`rmd`]: #tymethod.rmd
+    async fn rmd_recursive(&self, path: PathBuf) -> result::Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let mut entries = self.list(&path).compat();
+        loop {
+            let (entry, dir) = match entries.next().await {
+                Some(Ok(entry)) => {
+                    let dir = entry.metadata.is_dir() && !entry.metadata.is_symlink();
+                    (entry.path, dir)
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+            if dir {
+                self.rmd_recursive(entry).await?;
+            } else {
+                self.del(entry).await?;
+            }
+        }
+        self.rmd(path).await
+    }
 
     /// Rename the given file to the given filename.
-    fn rename<P: AsRef<Path>>(
+    async fn rename<P: AsRef<Path> + Send>(
         &self,
         from: P,
         to: P,
-    ) -> Box<Future<Item = (), Error = Self::Error> + Send>;
+    ) -> result::Result<(), Self::Error>;
+
+    /// Rename the given file to the given filename, but only if the file matches the given
+    /// [`Precondition`]. Resolves to whether the file was renamed. The same caveat as for
+    /// [`del_if`] applies to the default implementation.
+    ///
+    /// [`Precondition`]: ./struct.Precondition.html
+    /// [`del_if`]: #method.del_if
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> result::Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        if !precondition.matches(&self.stat(&from).await?) {
+            return Ok(false);
+        }
+        self.rename(from, to).await?;
+        Ok(true)
+    }
+
+    /// Set the modification time of the given file.
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> result::Result<(), Self::Error>;
+
+    /// Change the permissions of the given path to the given (unix) `mode`, e.g. `0o644`. It is
+    /// only called with the permission bits (i.e. at most `0o777`). Backends without file modes
+    /// should either ignore it or return an error.
+    async fn chmod<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mode: u32,
+    ) -> result::Result<(), Self::Error>;
+
+    /// Returns the checksum of the given file, calculated with the given [`HashAlgorithm`], as
+    /// lowercase hexadecimal digits. If a `range` is given, only those bytes of the file are
+    /// hashed. A [`Hasher`] can be used to calculate it while streaming the file.
+    ///
+    /// [`HashAlgorithm`]: ./enum.HashAlgorithm.html
+    /// [`Hasher`]: ./struct.Hasher.html
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> result::Result<String, Self::Error>;
+}
+
+// Skips the given number of bytes of the inner reader, for the default `get_range`.
+struct Skip<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for Skip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining > 0 {
+            let mut skipped = [0; 8192];
+            let want = std::cmp::min(self.remaining, skipped.len() as u64) as usize;
+            match self.inner.read(&mut skipped[..want])? {
+                0 => return Ok(0),
+                n => self.remaining -= n as u64,
+            }
+        }
+        self.inner.read(buf)
+    }
 }
 
+impl<R: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for Skip<R> {}
+
 /// StorageBackend that uses a local filesystem, like a traditional FTP server.
+///
+/// New files and directories get the modes of the logged in [`User`], if it has any. Uploads
+/// never write through a symlink: one where the file would go makes them fail with a permission
+/// error. A `put` writes to a hidden `.<name>.<pid>-<n>.part` file next to the one it replaces,
+/// and renames it into place, with the permissions of the old file, once it's done; listings
+/// leave these files out.
+///
+/// [`User`]: ../auth/struct.User.html
 pub struct Filesystem {
     root: PathBuf,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
 }
 
 /// Returns the canonical path corresponding to the input path, sequences like '../' resolved.
@@ -235,60 +790,87 @@ impl Filesystem {
     /// of the root. For example, when the `Filesystem` root is set to `/srv/ftp`, and a client
     /// asks for `hello.txt`, the server will send it `/srv/ftp/hello.txt`.
     pub fn new<P: Into<PathBuf>>(root: P) -> Self {
-        Filesystem { root: root.into() }
+        Filesystem {
+            root: root.into(),
+            file_mode: None,
+            dir_mode: None,
+        }
     }
 
     /// Returns the full, absolute and canonical path corresponding to the (relative to FTP root)
     /// input path, resolving symlinks and sequences like '../'.
     fn full_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let real_full_path = canonicalize(self.unchecked_path(path))?;
+
+        if real_full_path.starts_with(&self.root) {
+            Ok(real_full_path)
+        } else {
+            Err(Error::from(ErrorKind::PermissionDenied))
+        }
+    }
+
+    // Returns the path of a file that a write creates or changes. The file doesn't have to exist
+    // yet, but the directory it's in does, and has to be under the root: the resolved parent is
+    // checked, so `../` can't get out of it. A symlink in its place is refused, since writing to
+    // it would write to wherever it points.
+    fn write_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let path = self.unchecked_path(path);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(Error::from(ErrorKind::PermissionDenied)),
+        };
+        let parent = canonicalize(parent)?;
+        if !parent.starts_with(&self.root) {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        let path = parent.join(name);
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                Err(Error::from(ErrorKind::PermissionDenied))
+            }
+            _ => Ok(path),
+        }
+    }
+
+    // Returns the path under the root corresponding to the input path, as it is, for the files
+    // that writes create.
+    fn unchecked_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         // `path.join(other_path)` replaces `path` with `other_path` if `other_path` is absolute,
         // so we have to check for it.
         let path = path.as_ref();
-        let full_path = if path.starts_with("/") {
+        if path.starts_with("/") {
             self.root.join(path.strip_prefix("/").unwrap())
         } else {
             self.root.join(path)
-        };
-
-        // TODO: Use `?` operator here, when we can use `impl Future`
-        let real_full_path = match canonicalize(full_path) {
-            Ok(path) => path,
-            Err(e) => return Err(e),
-        };
-
-        if real_full_path.starts_with(&self.root) {
-            Ok(real_full_path)
-        } else {
-            Err(Error::PathError)
         }
     }
 }
 
+#[async_trait]
 impl StorageBackend for Filesystem {
     type File = tokio::fs::File;
     type Metadata = std::fs::Metadata;
     type Error = Error;
 
-    fn stat<P: AsRef<Path>>(
-        &self,
-        path: P,
-    ) -> Box<Future<Item = Self::Metadata, Error = Self::Error> + Send> {
-        let full_path = match self.full_path(path) {
-            Ok(path) => path,
-            Err(err) => return Box::new(future::err(err)),
-        };
-        // TODO: Some more useful error reporting
-        Box::new(tokio::fs::symlink_metadata(full_path).map_err(|_| Error::IOError))
+    fn set_user(&mut self, user: &User) {
+        self.file_mode = user.effective_file_mode();
+        self.dir_mode = user.effective_dir_mode();
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+        let full_path = self.full_path(path)?;
+        Ok(tokio::fs::symlink_metadata(full_path).compat().await?)
     }
 
     fn list<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> Box<Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
     where
         <Self as StorageBackend>::Metadata: Metadata,
     {
-        // TODO: Use `?` operator here when we can use `impl Future`
         let full_path = match self.full_path(path) {
             Ok(path) => path,
             Err(e) => return Box::new(future::err(e).into_stream()),
@@ -303,6 +885,9 @@ impl StorageBackend for Filesystem {
                 let path = dir_entry.path();
                 let relpath = path.strip_prefix(prefix).unwrap();
                 let relpath = std::path::PathBuf::from(relpath);
+                if is_temp_path(&relpath) {
+                    return None;
+                }
                 match std::fs::metadata(dir_entry.path()) {
                     Ok(stat) => Some(Fileinfo {
                         path: relpath,
@@ -312,92 +897,347 @@ impl StorageBackend for Filesystem {
                 }
             });
 
-        // TODO: Some more useful error reporting
-        Box::new(fut.map_err(|_| Error::IOError))
+        Box::new(fut.map_err(Error::from))
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<tokio::fs::File> {
+        let full_path = self.full_path(path)?;
+        Ok(tokio::fs::file::File::open(full_path).compat().await?)
     }
 
-    fn get<P: AsRef<Path>>(
+    async fn get_range<P: AsRef<Path> + Send>(
         &self,
         path: P,
-    ) -> Box<Future<Item = tokio::fs::File, Error = Self::Error> + Send> {
-        let full_path = match self.full_path(path) {
-            Ok(path) => path,
-            Err(e) => return Box::new(future::err(e)),
-        };
-        // TODO: Some more useful error reporting
-        Box::new(tokio::fs::file::File::open(full_path).map_err(|_| Error::IOError))
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>> {
+        use std::io::Read;
+
+        let full_path = self.full_path(path)?;
+        let file = tokio::fs::file::File::open(full_path).compat().await?;
+        let (file, _) = file
+            .seek(std::io::SeekFrom::Start(range.start))
+            .compat()
+            .await?;
+        Ok(Box::new(file.take(range.end.saturating_sub(range.start))))
     }
 
-    fn put<P: AsRef<Path>, R: tokio::prelude::AsyncRead + Send + 'static>(
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
         &self,
         bytes: R,
         path: P,
-    ) -> Box<Future<Item = u64, Error = Self::Error> + Send> {
-        // TODO: Add permission checks
-        let path = path.as_ref();
-        let full_path = if path.starts_with("/") {
-            self.root.join(path.strip_prefix("/").unwrap())
-        } else {
-            self.root.join(path)
-        };
+    ) -> Result<u64> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let full_path = self.write_path(path)?;
+        let existing = tokio::fs::metadata(full_path.clone()).compat().await.ok();
+        if existing
+            .as_ref()
+            .is_some_and(|metadata| !metadata.is_file())
+        {
+            // Devices and pipes can't be replaced, only written to.
+            let f = tokio::fs::file::File::create(full_path).compat().await?;
+            return Ok(tokio_io::io::copy(bytes, f).compat().await?.0);
+        }
 
-        let fut = tokio::fs::file::File::create(full_path)
-            .and_then(|f| tokio_io::io::copy(bytes, f))
-            .map(|(n, _, _)| n)
-            // TODO: Some more useful error reporting
-            .map_err(|_| Error::IOError);
-        Box::new(fut)
+        // The bytes go to a file next to the one they replace, which is renamed into its place
+        // once they're all written, so a put that fails or gets dropped leaves the old file as it
+        // was. The new file gets the permissions of the old one, unless the user has a mode of its
+        // own.
+        let mode = self
+            .file_mode
+            .or_else(|| existing.map(|metadata| metadata.permissions().mode()));
+        let temp_path = temp_path(&full_path);
+        let rollback = Rollback::default();
+        let written = async {
+            let f = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(temp_path.clone())
+                .compat()
+                .await?;
+            rollback.arm(temp_path.clone(), Undo::Remove);
+            let f = set_mode(f, temp_path.clone(), mode).compat().await?;
+            let (written, _, f) = tokio_io::io::copy(bytes, f).compat().await?;
+            drop(f);
+            tokio::fs::rename(temp_path, full_path).compat().await?;
+            rollback.disarm();
+            Ok::<_, std::io::Error>(written)
+        }
+        .await;
+        Ok(written?)
     }
 
-    fn del<P: AsRef<Path>>(&self, path: P) -> Box<Future<Item = (), Error = Self::Error> + Send> {
-        let full_path = match self.full_path(path) {
-            Ok(path) => path,
-            Err(e) => return Box::new(future::err(e)),
-        };
-        Box::new(tokio::fs::remove_file(full_path).map_err(|_| Error::IOError))
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64> {
+        let full_path = self.write_path(path)?;
+        let rollback = Rollback::default();
+        let written = async {
+            let f = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(full_path.clone())
+                .compat()
+                .await?;
+            rollback.arm(full_path.clone(), Undo::Remove);
+            let f = set_mode(f, full_path, self.file_mode).compat().await?;
+            tokio_io::io::copy(bytes, f).compat().await
+        }
+        .await;
+        rollback.disarm();
+        Ok(written?.0)
     }
 
-    fn mkd<P: AsRef<Path>>(&self, path: P) -> Box<Future<Item = (), Error = Self::Error> + Send> {
-        let full_path = match self.full_path(path) {
-            Ok(path) => path,
-            Err(e) => return Box::new(future::err(e)),
-        };
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64> {
+        let full_path = self.write_path(path)?;
+        let rollback = Rollback::default();
+        let written = async {
+            // What the file was like before, to put it back that way if the append gets dropped.
+            let len = tokio::fs::metadata(full_path.clone())
+                .compat()
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+            let f = tokio::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(full_path.clone())
+                .compat()
+                .await?;
+            rollback.arm(full_path.clone(), len.map_or(Undo::Remove, Undo::Truncate));
+            let f = set_mode(f, full_path, self.file_mode).compat().await?;
+            tokio_io::io::copy(bytes, f).compat().await
+        }
+        .await;
+        rollback.disarm();
+        Ok(written?.0)
+    }
 
-        Box::new(tokio::fs::create_dir(full_path).map_err(|e| {
-            println!("error: {}", e);
-            Error::IOError
-        }))
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>> {
+        let full_path = self.full_path(path)?;
+        let free = blocking(move || {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = std::ffi::CString::new(full_path.as_os_str().as_bytes())?;
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            // Safe, because `path` is a valid C string and `stat` is big enough for the result.
+            if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // What unprivileged users may use, like `df` reports.
+            #[allow(clippy::unnecessary_cast)]
+            Ok(Some(
+                (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+            ))
+        });
+        Ok(free.compat().await?)
     }
 
-    fn rename<P: AsRef<Path>>(
-        &self,
-        from: P,
-        to: P,
-    ) -> Box<Future<Item = (), Error = Self::Error> + Send> {
-        let from = match self.full_path(from) {
-            Ok(path) => path,
-            Err(e) => return Box::new(future::err(e)),
-        };
-        let to = match self.full_path(to) {
-            Ok(path) => path,
-            Err(e) => return Box::new(future::err(e)),
-        };
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        Ok(tokio::fs::remove_file(full_path).compat().await?)
+    }
 
-        let from_rename = from.clone(); // Alright, borrow checker, have it your way.
-        let fut = tokio::fs::metadata(from)
-            .map_err(|_| Error::IOError)
-            .and_then(move |metadata| {
-                if metadata.is_file() {
-                    future::Either::A(
-                        tokio::fs::rename(from_rename, to).map_err(|_| Error::IOError),
-                    )
-                } else {
-                    future::Either::B(future::err(Error::IOError))
-                }
-            });
-        Box::new(fut)
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        tokio::fs::create_dir(full_path.clone()).compat().await?;
+        Ok(set_mode((), full_path, self.dir_mode).compat().await?)
     }
-}
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        Ok(tokio::fs::remove_dir(full_path).compat().await?)
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        // Never remove the root itself.
+        if full_path == self.root {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        Ok(blocking(move || std::fs::remove_dir_all(full_path))
+            .compat()
+            .await?)
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<()> {
+        let from = self.full_path(from)?;
+        let to = self.full_path(to)?;
+
+        let metadata = tokio::fs::metadata(from.clone()).compat().await?;
+        if !metadata.is_file() {
+            return Err(Error::backend("Only files can be renamed"));
+        }
+        Ok(tokio::fs::rename(from, to).compat().await?)
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(&self, path: P, mtime: SystemTime) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        Ok(
+            blocking(move || std::fs::File::open(full_path)?.set_modified(mtime))
+                .compat()
+                .await?,
+        )
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<()> {
+        let full_path = self.full_path(path)?;
+        Ok(set_mode((), full_path, Some(mode)).compat().await?)
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let full_path = self.full_path(path)?;
+        let checksum = blocking(move || {
+            let mut file = std::fs::File::open(full_path)?;
+            let range = range.unwrap_or(0..u64::MAX);
+            file.seek(SeekFrom::Start(range.start))?;
+            let mut file = file.take(range.end.saturating_sub(range.start));
+            let mut hasher = algorithm.hasher();
+            let mut chunk = vec![0u8; 64 * 1024];
+            loop {
+                match file.read(&mut chunk)? {
+                    0 => return Ok(hasher.finish()),
+                    n => hasher.update(&chunk[..n]),
+                }
+            }
+        });
+        Ok(checksum.compat().await?)
+    }
+}
+
+/// Sets the permissions of the given path to `mode`, if given, and resolves to `item` afterwards.
+/// Unlike the mode passed to `open(2)`, this is not subject to the umask of the server process.
+fn set_mode<T>(
+    item: T,
+    path: PathBuf,
+    mode: Option<u32>,
+) -> impl Future<Item = T, Error = std::io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match mode {
+        Some(mode) => future::Either::A(
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .map(move |_| item),
+        ),
+        None => future::Either::B(future::ok(item)),
+    }
+}
+
+/// How to undo an unfinished write to a file.
+enum Undo {
+    /// The write created the file.
+    Remove,
+    /// The write appended to a file of the given length.
+    Truncate(u64),
+}
+
+/// Undoes the write to a file when dropped while armed, so a `put`, `put_unique` or `append` that
+/// gets dropped before it finishes doesn't leave a partial file behind. The futures of these
+/// writes hold on to it until they finish, and disarm it then, whether they succeeded or failed.
+#[derive(Default)]
+struct Rollback {
+    armed: std::sync::Mutex<Option<(PathBuf, Undo)>>,
+}
+
+impl Rollback {
+    fn arm(&self, path: PathBuf, undo: Undo) {
+        *self.armed.lock().unwrap() = Some((path, undo));
+    }
+
+    fn disarm(&self) {
+        self.armed.lock().unwrap().take();
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        let (path, undo) = match self.armed.get_mut().ok().and_then(Option::take) {
+            Some(armed) => armed,
+            None => return,
+        };
+        let undone = match undo {
+            Undo::Remove => std::fs::remove_file(&path),
+            Undo::Truncate(len) => std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_len(len)),
+        };
+        if let Err(e) = undone {
+            warn!(
+                "Failed to undo the interrupted write to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Returns the path of a hidden file next to the given one, for a put to write to before it
+// replaces the file.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}-{}.part", name, std::process::id(), n))
+}
+
+// Tells whether the given path looks like one from `temp_path`, which listings leave out, so
+// clients don't see uploads in progress or the leftovers of a crash.
+fn is_temp_path(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let rest = match name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".part"))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let suffix = match rest.rsplit_once('.') {
+        Some((name, suffix)) if !name.is_empty() => suffix,
+        _ => return false,
+    };
+    match suffix.split_once('-') {
+        Some((pid, n)) => {
+            !pid.is_empty()
+                && !n.is_empty()
+                && pid.bytes().chain(n.bytes()).all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// Runs the given closure on the blocking section of the tokio threadpool, for the filesystem
+/// operations that `tokio::fs` doesn't offer.
+fn blocking<F, T>(f: F) -> impl Future<Item = T, Error = std::io::Error>
+where
+    F: FnOnce() -> std::io::Result<T>,
+{
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| (f.take().expect("polled after completion"))()) {
+            Ok(futures::Async::Ready(Ok(res))) => Ok(futures::Async::Ready(res)),
+            Ok(futures::Async::Ready(Err(e))) => Err(e),
+            Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    })
+}
 
 use std::os::unix::fs::MetadataExt;
 impl Metadata for std::fs::Metadata {
@@ -417,6 +1257,10 @@ impl Metadata for std::fs::Metadata {
         self.is_file()
     }
 
+    fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
     fn modified(&self) -> Result<SystemTime> {
         self.modified().map_err(|e| e.into())
     }
@@ -430,185 +1274,712 @@ impl Metadata for std::fs::Metadata {
     }
 }
 
-#[derive(Debug, PartialEq)]
-/// The `Error` variants that can be produced by the [`StorageBackend`] implementations.
+/// The errors of the [`StorageBackend`] implementations of this crate. Each [`ErrorKind`] gets its
+/// own reply from the server, as [`reply_code`] tells, and the error that caused it (like the
+/// `io::Error` of a filesystem, or the reply of an object store) is kept as its [`source`], for
+/// the logs.
 ///
 /// [`StorageBackend`]: ./trait.StorageBackend.html
-pub enum Error {
-    /// An IO Error
-    IOError,
-    /// Path error
-    PathError,
+/// [`ErrorKind`]: ./enum.ErrorKind.html
+/// [`reply_code`]: #method.reply_code
+/// [`source`]: https://doc.rust-lang.org/std/error/trait.Error.html#method.source
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+}
+
+/// The kinds of [`Error`]s a [`StorageBackend`] can fail with.
+///
+/// [`Error`]: ./struct.Error.html
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// There's no file or directory at the path
+    NotFound,
+    /// The operation isn't allowed
+    PermissionDenied,
+    /// The target exists, and mustn't be overwritten
+    Exists,
+    /// The directory has files or directories in it
+    NotEmpty,
+    /// The operation would take the account over its quota
+    Quota,
+    /// The storage ran out of space, or the user out of quota of the filesystem
+    StorageFull,
+    /// The storage failed
+    Backend,
 }
 
 impl Error {
-    fn description_str(&self) -> &'static str {
-        ""
+    /// An error of the given kind, caused by the given source, like an error of the storage, or a
+    /// message that tells what went wrong.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::storage::{Error, ErrorKind};
+    /// use std::error::Error as _;
+    ///
+    /// let error = Error::new(ErrorKind::NotFound, "404 No such key");
+    /// assert_eq!(error.kind(), ErrorKind::NotFound);
+    /// assert_eq!(error.source().unwrap().to_string(), "404 No such key");
+    /// ```
+    pub fn new<E: Into<Box<dyn std::error::Error + Send + Sync>>>(
+        kind: ErrorKind,
+        source: E,
+    ) -> Self {
+        Error {
+            kind,
+            source: Some(source.into()),
+        }
+    }
+
+    /// A `Backend` error with the given source, like an error of the storage, or a message that
+    /// tells what went wrong.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::storage::Error;
+    ///
+    /// let error = Error::backend("The bucket is gone");
+    /// assert_eq!(error.to_string(), "The storage failed: The bucket is gone");
+    /// ```
+    pub fn backend<E: Into<Box<dyn std::error::Error + Send + Sync>>>(source: E) -> Self {
+        Error::new(ErrorKind::Backend, source)
+    }
+
+    /// The kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The reply code that the server answers commands that fail with this error with: `550` to
+    /// `NotFound`, `PermissionDenied` and `NotEmpty`, `553` to `Exists`, `552` to `Quota` and
+    /// `452` to `StorageFull`. `Backend` errors have none of their own: they get the failure
+    /// reply of the command, like `450` for `DELE` or `550` for `MKD`.
+    pub fn reply_code(&self) -> Option<u32> {
+        match self.kind {
+            ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::NotEmpty => Some(550),
+            ErrorKind::Exists => Some(553),
+            ErrorKind::Quota => Some(552),
+            ErrorKind::StorageFull => Some(452),
+            ErrorKind::Backend => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error { kind, source: None }
+    }
+}
+
+// Errors of the same kind are the same, except for `Backend` errors: those are the same if their
+// sources say the same.
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        self.kind == other.kind
+            && (self.kind != ErrorKind::Backend
+                || self.source.as_ref().map(|source| source.to_string())
+                    == other.source.as_ref().map(|source| source.to_string()))
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(&self.description_str())
+        match (self.kind, &self.source) {
+            (ErrorKind::NotFound, _) => f.write_str("No such file or directory"),
+            (ErrorKind::PermissionDenied, _) => f.write_str("Permission denied"),
+            (ErrorKind::Exists, _) => f.write_str("The file exists, and mustn't be overwritten"),
+            (ErrorKind::NotEmpty, _) => f.write_str("The directory isn't empty"),
+            (ErrorKind::Quota, _) => f.write_str("The quota of the account is exceeded"),
+            (ErrorKind::StorageFull, _) => f.write_str("The storage is full"),
+            (ErrorKind::Backend, Some(source)) => write!(f, "The storage failed: {}", source),
+            (ErrorKind::Backend, None) => f.write_str("The storage failed"),
+        }
     }
 }
 
 impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        self.description_str()
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| &**source as &(dyn std::error::Error + 'static))
     }
 }
 
 impl From<std::io::Error> for Error {
-    fn from(_err: std::io::Error) -> Error {
-        Error::IOError
+    fn from(err: std::io::Error) -> Error {
+        let out_of_space = matches!(err.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT))
+            || matches!(
+                err.kind(),
+                std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+            );
+        let kind = if out_of_space {
+            ErrorKind::StorageFull
+        } else {
+            match err.kind() {
+                std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                std::io::ErrorKind::AlreadyExists => ErrorKind::Exists,
+                std::io::ErrorKind::DirectoryNotEmpty => ErrorKind::NotEmpty,
+                _ => ErrorKind::Backend,
+            }
+        };
+        Error::new(kind, err)
     }
 }
 
 impl From<path_abs::Error> for Error {
-    fn from(_err: path_abs::Error) -> Error {
-        Error::PathError
+    fn from(err: path_abs::Error) -> Error {
+        let kind = match err.io_error().kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Backend,
+        };
+        Error::new(kind, err)
+    }
+}
+
+// The server passes the errors of the storage through the data connection as `io::Error`s of the
+// matching kind, that keep the error itself so the reply can tell what it is.
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        let kind = match err.kind {
+            ErrorKind::NotFound => std::io::ErrorKind::NotFound,
+            ErrorKind::PermissionDenied => std::io::ErrorKind::PermissionDenied,
+            ErrorKind::Exists => std::io::ErrorKind::AlreadyExists,
+            ErrorKind::NotEmpty => std::io::ErrorKind::DirectoryNotEmpty,
+            ErrorKind::Quota => std::io::ErrorKind::FileTooLarge,
+            ErrorKind::StorageFull => std::io::ErrorKind::StorageFull,
+            ErrorKind::Backend => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
     }
 }
 
 type Result<T> = result::Result<T, Error>;
 
+// Reads the chunks of a listing from `list_fmt` or `nlst` as they come in, so it can be copied to
+// the data connection like a file. A read takes as many chunks as are ready and fit, and would
+// block when there are none yet.
+pub(crate) struct ListingReader {
+    chunks: futures::stream::Fuse<Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>>,
+    chunk: std::io::Cursor<Vec<u8>>,
+}
+
+impl ListingReader {
+    pub(crate) fn new(
+        chunks: Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>,
+    ) -> Self {
+        ListingReader {
+            chunks: chunks.fuse(),
+            chunk: std::io::Cursor::new(vec![]),
+        }
+    }
+}
+
+impl std::io::Read for ListingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            if self.chunk.position() == self.chunk.get_ref().len() as u64 {
+                match self.chunks.poll()? {
+                    futures::Async::Ready(Some(chunk)) => self.chunk = std::io::Cursor::new(chunk),
+                    futures::Async::Ready(None) => break,
+                    futures::Async::NotReady if read == 0 => {
+                        return Err(std::io::ErrorKind::WouldBlock.into());
+                    }
+                    futures::Async::NotReady => break,
+                }
+            }
+            read += self.chunk.read(&mut buf[read..])?;
+        }
+        Ok(read)
+    }
+}
+
+impl tokio::io::AsyncRead for ListingReader {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compat;
+    use futures03::executor::block_on;
     use pretty_assertions::assert_eq;
     use std::fs::File;
     use std::io::prelude::*;
 
     #[test]
     fn fs_stat() {
-        let root = std::env::temp_dir();
-
-        // Create a temp file and get it's metadata
-        let file = tempfile::NamedTempFile::new_in(&root).unwrap();
-        let path = file.path();
-        let file = file.as_file();
-        let meta = file.metadata().unwrap();
-
-        // Create a filesystem StorageBackend with the directory containing our temp file as root
-        let fs = Filesystem::new(&root);
-
-        // Since the filesystem backend is based on futures, we need a runtime to run it
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let filename = path.file_name().unwrap();
-        let my_meta = rt.block_on(fs.stat(filename)).unwrap();
-
-        assert_eq!(meta.is_dir(), my_meta.is_dir());
-        assert_eq!(meta.is_file(), my_meta.is_file());
-        assert_eq!(meta.len(), my_meta.len());
-        assert_eq!(meta.modified().unwrap(), my_meta.modified().unwrap());
+        compat::on_runtime(move || {
+            let root = std::env::temp_dir();
+
+            // Create a temp file and get it's metadata
+            let file = tempfile::NamedTempFile::new_in(&root).unwrap();
+            let path = file.path();
+            let file = file.as_file();
+            let meta = file.metadata().unwrap();
+
+            // Create a filesystem StorageBackend with the directory containing our temp file as root
+            let fs = Filesystem::new(&root);
+
+            // Since the filesystem backend is based on futures, we need a runtime to run it
+            let filename = path.file_name().unwrap();
+            let my_meta = block_on(fs.stat(filename)).unwrap();
+
+            assert_eq!(meta.is_dir(), my_meta.is_dir());
+            assert_eq!(meta.is_file(), my_meta.is_file());
+            assert_eq!(meta.len(), my_meta.len());
+            assert_eq!(meta.modified().unwrap(), my_meta.modified().unwrap());
+        });
     }
 
     #[test]
     fn fs_list() {
-        // Create a temp directory and create some files in it
-        let root = tempfile::tempdir().unwrap();
-        let file = tempfile::NamedTempFile::new_in(&root.path()).unwrap();
-        let path = file.path();
-        let relpath = path.strip_prefix(&root.path()).unwrap();
-        let file = file.as_file();
-        let meta = file.metadata().unwrap();
-
-        // Create a filesystem StorageBackend with our root dir
-        let fs = Filesystem::new(&root.path());
-
-        // Since the filesystem backend is based on futures, we need a runtime to run it
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let my_list = rt.block_on(fs.list("/").collect()).unwrap();
-
-        assert_eq!(my_list.len(), 1);
-
-        let my_fileinfo = &my_list[0];
-        assert_eq!(my_fileinfo.path, relpath);
-        assert_eq!(my_fileinfo.metadata.is_dir(), meta.is_dir());
-        assert_eq!(my_fileinfo.metadata.is_file(), meta.is_file());
-        assert_eq!(my_fileinfo.metadata.len(), meta.len());
-        assert_eq!(
-            my_fileinfo.metadata.modified().unwrap(),
-            meta.modified().unwrap()
-        );
+        compat::on_runtime(move || {
+            // Create a temp directory and create some files in it
+            let root = tempfile::tempdir().unwrap();
+            let file = tempfile::NamedTempFile::new_in(root.path()).unwrap();
+            let path = file.path();
+            let relpath = path.strip_prefix(root.path()).unwrap();
+            let file = file.as_file();
+            let meta = file.metadata().unwrap();
+
+            // Create a filesystem StorageBackend with our root dir
+            let fs = Filesystem::new(root.path());
+
+            // Since the filesystem backend is based on futures, we need a runtime to run it
+            let my_list = block_on(fs.list("/").collect().compat()).unwrap();
+
+            assert_eq!(my_list.len(), 1);
+
+            let my_fileinfo = &my_list[0];
+            assert_eq!(my_fileinfo.path, relpath);
+            assert_eq!(my_fileinfo.metadata.is_dir(), meta.is_dir());
+            assert_eq!(my_fileinfo.metadata.is_file(), meta.is_file());
+            assert_eq!(my_fileinfo.metadata.len(), meta.len());
+            assert_eq!(
+                my_fileinfo.metadata.modified().unwrap(),
+                meta.modified().unwrap()
+            );
+        });
     }
 
     #[test]
     fn fs_list_fmt() {
-        // Create a temp directory and create some files in it
-        let root = tempfile::tempdir().unwrap();
-        let file = tempfile::NamedTempFile::new_in(&root.path()).unwrap();
-        let path = file.path();
-        let relpath = path.strip_prefix(&root.path()).unwrap();
+        compat::on_runtime(move || {
+            // Create a temp directory and create some files in it
+            let root = tempfile::tempdir().unwrap();
+            let file = tempfile::NamedTempFile::new_in(root.path()).unwrap();
+            let path = file.path();
+            let relpath = path.strip_prefix(root.path()).unwrap();
 
-        // Create a filesystem StorageBackend with our root dir
-        let fs = Filesystem::new(&root.path());
+            // Create a filesystem StorageBackend with our root dir
+            let fs = Filesystem::new(root.path());
 
-        // Since the filesystem backend is based on futures, we need a runtime to run it
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let my_list = rt.block_on(fs.list_fmt("/")).unwrap();
+            // Since the filesystem backend is based on futures, we need a runtime to run it
+            let my_list =
+                block_on(fs.list_fmt("/", ListOptions::default()).concat2().compat()).unwrap();
 
-        let my_list = std::string::String::from_utf8(my_list.into_inner()).unwrap();
+            let my_list = std::string::String::from_utf8(my_list).unwrap();
 
-        assert!(my_list.contains(relpath.to_str().unwrap()));
+            assert!(my_list.contains(relpath.to_str().unwrap()));
+        });
+    }
+
+    #[test]
+    fn listing_reader() {
+        let chunks = futures::stream::iter_ok(vec![b"one\r\n".to_vec(), b"two\r\n".to_vec()]);
+        let mut reader = ListingReader::new(Box::new(chunks));
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"one\r\ntwo");
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        // A read takes what's there, and would block until there's more.
+        future::lazy(|| {
+            let (tx, rx) = futures::sync::mpsc::unbounded();
+            let mut reader = ListingReader::new(Box::new(
+                rx.map_err(|_| std::io::Error::other("Channel failed")),
+            ));
+            tx.unbounded_send(b"one\r\n".to_vec()).unwrap();
+            assert_eq!(reader.read(&mut buf).unwrap(), 5);
+            let blocked = reader.read(&mut buf).unwrap_err();
+            assert_eq!(blocked.kind(), std::io::ErrorKind::WouldBlock);
+            drop(tx);
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+            future::ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
     }
 
     #[test]
     fn fs_get() {
-        let root = std::env::temp_dir();
+        compat::on_runtime(move || {
+            let root = std::env::temp_dir();
 
-        let mut file = tempfile::NamedTempFile::new_in(&root).unwrap();
-        let path = file.path().to_owned();
+            let mut file = tempfile::NamedTempFile::new_in(&root).unwrap();
+            let path = file.path().to_owned();
 
-        // Write some data to our test file
-        let data = b"Koen was here\n";
-        file.write_all(data).unwrap();
+            // Write some data to our test file
+            let data = b"Koen was here\n";
+            file.write_all(data).unwrap();
 
-        let filename = path.file_name().unwrap();
-        let fs = Filesystem::new(&root);
+            let filename = path.file_name().unwrap();
+            let fs = Filesystem::new(&root);
 
-        // Since the filesystem backend is based on futures, we need a runtime to run it
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let mut my_file = rt.block_on(fs.get(filename)).unwrap();
-        let mut my_content = Vec::new();
-        rt.block_on(future::lazy(move || {
-            tokio::prelude::AsyncRead::read_to_end(&mut my_file, &mut my_content).unwrap();
+            // Since the filesystem backend is based on futures, we need a runtime to run it
+            let mut my_file = block_on(fs.get(filename)).unwrap();
+            let mut my_content = Vec::new();
+            my_file.read_to_end(&mut my_content).unwrap();
             assert_eq!(data.as_ref(), &*my_content);
-            // We need a `Err` branch because otherwise the compiler can't infer the `E` type,
-            // and I'm not sure where/how to annotate it.
-            if true {
-                Ok(())
-            } else {
-                Err(())
-            }
-        }))
-        .unwrap();
+        });
     }
 
     #[test]
     fn fs_put() {
-        let root = std::env::temp_dir();
-        let orig_content = b"hallo";
-        let fs = Filesystem::new(&root);
+        compat::on_runtime(move || {
+            let root = std::env::temp_dir();
+            let orig_content = b"hallo";
+            let fs = Filesystem::new(&root);
+
+            // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
+            // to completion
+
+            block_on(fs.put(orig_content.as_ref(), "greeting.txt")).expect("Failed to `put` file");
+
+            let mut written_content = Vec::new();
+            let mut f = File::open(root.join("greeting.txt")).unwrap();
+            f.read_to_end(&mut written_content).unwrap();
+
+            assert_eq!(orig_content, written_content.as_slice());
+        });
+    }
+
+    #[test]
+    fn fs_put_replaces() {
+        compat::on_runtime(move || {
+            use std::os::unix::fs::PermissionsExt;
+
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            std::fs::write(root.join("script.sh"), b"old").unwrap();
+            std::fs::set_permissions(
+                root.join("script.sh"),
+                std::fs::Permissions::from_mode(0o750),
+            )
+            .unwrap();
+            block_on(fs.put(b"new".as_ref(), "script.sh")).unwrap();
+            assert_eq!(std::fs::read(root.join("script.sh")).unwrap(), b"new");
+            let mode = std::fs::metadata(root.join("script.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o750);
+
+            // Uploads in progress, or left behind by a crash, aren't listed. Other hidden files
+            // are.
+            std::fs::write(root.join(".script.sh.123-4.part"), b"half").unwrap();
+            std::fs::write(root.join(".profile"), b"").unwrap();
+            std::fs::write(root.join(".old.part"), b"").unwrap();
+            let mut names: Vec<_> = block_on(fs.list("/").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect();
+            names.sort();
+            assert_eq!(
+                names,
+                vec![
+                    PathBuf::from(".old.part"),
+                    PathBuf::from(".profile"),
+                    PathBuf::from("script.sh")
+                ]
+            );
+            assert!(is_temp_path(&temp_path(&root.join("script.sh"))));
+        });
+    }
+
+    #[test]
+    fn fs_put_unique() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            std::fs::write(root.join("taken.txt"), b"first").unwrap();
+            assert_eq!(
+                block_on(fs.put_unique(b"second".as_ref(), "free.txt")),
+                Ok(6)
+            );
+            assert!(block_on(fs.put_unique(b"second".as_ref(), "taken.txt")).is_err());
+            assert_eq!(std::fs::read(root.join("taken.txt")).unwrap(), b"first");
+            assert_eq!(std::fs::read(root.join("free.txt")).unwrap(), b"second");
+        });
+    }
+
+    #[test]
+    fn fs_get_range() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+            let read = |range| {
+                let file = block_on(fs.get_range("data.txt", range)).unwrap();
+                block_on(tokio_io::io::read_to_end(file, vec![]).compat())
+                    .unwrap()
+                    .1
+            };
+            assert_eq!(read(2..5), b"234");
+            assert_eq!(read(8..20), b"89");
+            assert_eq!(read(20..30), b"");
+        });
+    }
+
+    #[test]
+    fn skip() {
+        use std::io::Read;
+
+        let mut skipped = Skip {
+            inner: std::io::Cursor::new(
+                vec![7; 10_000]
+                    .into_iter()
+                    .chain(0..10)
+                    .collect::<Vec<u8>>(),
+            ),
+            remaining: 10_000,
+        };
+        let mut data = vec![];
+        skipped.read_to_end(&mut data).unwrap();
+        assert_eq!(data, (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn fs_free_space() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            let free = block_on(fs.free_space("/")).unwrap().unwrap();
+            assert!(free > 0);
+            assert!(block_on(fs.free_space("/missing")).is_err());
+        });
+    }
 
-        // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
-        // to completion
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+    #[test]
+    fn fs_out_of_space() {
+        compat::on_runtime(move || {
+            // Writing to `/dev/full` fails with `ENOSPC`.
+            let fs = Filesystem::new("/dev");
+            let e = block_on(fs.put(std::io::Cursor::new(vec![0; 1024]), "/full")).unwrap_err();
+            assert_eq!(e, Error::from(ErrorKind::StorageFull));
+            // The error of the filesystem is kept as the source.
+            let source = std::error::Error::source(&e)
+                .and_then(|source| source.downcast_ref::<std::io::Error>())
+                .and_then(std::io::Error::raw_os_error);
+            assert_eq!(source, Some(libc::ENOSPC));
+            assert_eq!(Error::backend("Disk on fire").kind(), ErrorKind::Backend);
+            assert_eq!(
+                Error::from(std::io::Error::from_raw_os_error(libc::EDQUOT)),
+                Error::from(ErrorKind::StorageFull)
+            );
+            assert_eq!(
+                Error::from(std::io::Error::from(std::io::ErrorKind::NotFound)),
+                Error::from(ErrorKind::NotFound)
+            );
+        });
+    }
+
+    #[test]
+    fn fs_write_symlink() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let outside = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(outside.join("precious.txt"), b"don't touch").unwrap();
+            std::os::unix::fs::symlink(outside.join("precious.txt"), root.join("link")).unwrap();
+            std::os::unix::fs::symlink(outside.join("new.txt"), root.join("dangling")).unwrap();
+            let fs = Filesystem::new(&root);
+            let denied = Error::from(ErrorKind::PermissionDenied);
+            for path in &["link", "dangling"] {
+                let bytes = || std::io::Cursor::new(b"gotcha".to_vec());
+                assert_eq!(block_on(fs.put(bytes(), path)).unwrap_err(), denied);
+                assert_eq!(block_on(fs.append(bytes(), path)).unwrap_err(), denied);
+                assert_eq!(block_on(fs.put_unique(bytes(), path)).unwrap_err(), denied);
+            }
+            assert_eq!(
+                std::fs::read(outside.join("precious.txt")).unwrap(),
+                b"don't touch"
+            );
+            assert!(!outside.join("new.txt").exists());
+            assert!(std::fs::symlink_metadata(root.join("link"))
+                .unwrap()
+                .file_type()
+                .is_symlink());
+        });
+    }
+
+    #[test]
+    fn fs_errors() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap();
+            std::fs::create_dir(root.path().join("full")).unwrap();
+            std::fs::write(root.path().join("full/keep.txt"), b"keep me").unwrap();
+            let fs = Filesystem::new(root.path());
+            let e = block_on(fs.stat("/missing.txt")).unwrap_err();
+            assert_eq!(e, Error::from(ErrorKind::NotFound));
+            assert!(std::error::Error::source(&e).is_some());
+            let e = block_on(fs.rmd("/full")).unwrap_err();
+            assert_eq!(e, Error::from(ErrorKind::NotEmpty));
+            assert!(std::error::Error::source(&e).is_some());
+            assert_eq!(
+                block_on(fs.mkd("/full")).unwrap_err(),
+                Error::from(ErrorKind::Exists)
+            );
+            assert_eq!(
+                block_on(fs.rename("/full", "/moved")).unwrap_err(),
+                Error::backend("Only files can be renamed")
+            );
+        });
+    }
+
+    #[test]
+    fn maps_errors_to_replies() {
+        assert_eq!(Error::from(ErrorKind::NotFound).reply_code(), Some(550));
+        assert_eq!(Error::from(ErrorKind::Exists).reply_code(), Some(553));
+        assert_eq!(Error::from(ErrorKind::Quota).reply_code(), Some(552));
+        assert_eq!(Error::from(ErrorKind::StorageFull).reply_code(), Some(452));
+
+        // The error of the storage stays around, for the logs.
+        let e = Error::from(std::io::Error::other("Bad sector"));
+        assert_eq!(e.reply_code(), None);
+        assert_eq!(e.to_string(), "The storage failed: Bad sector");
+        assert_eq!(
+            std::error::Error::source(&e).unwrap().to_string(),
+            "Bad sector"
+        );
+        assert_ne!(e, Error::backend("Bad block"));
+    }
+
+    #[test]
+    fn fs_cancellation() {
+        let root = tempfile::TempDir::new().unwrap();
+        conformance::check_cancellation(Filesystem::new(root.path()));
+        let mut names: Vec<_> = std::fs::read_dir(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["appended.txt", "replaced.txt"]);
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("replaced.txt")).unwrap(),
+            "before"
+        );
+    }
+
+    #[test]
+    fn fs_append() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+
+            // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
+            // to completion
+
+            block_on(fs.append(b"hello ".as_ref(), "log.txt"))
+                .expect("Failed to `append` to new file");
+            block_on(fs.append(b"world".as_ref(), "log.txt"))
+                .expect("Failed to `append` to existing file");
+
+            let mut written_content = Vec::new();
+            let mut f = File::open(root.join("log.txt")).unwrap();
+            f.read_to_end(&mut written_content).unwrap();
+
+            assert_eq!(b"hello world", written_content.as_slice());
+        });
+    }
+
+    #[test]
+    fn fs_writes_stay_under_root() {
+        compat::on_runtime(move || {
+            let outside = tempfile::TempDir::new().unwrap().keep();
+            let root = outside.join("root");
+            std::fs::create_dir_all(root.join("dir")).unwrap();
+            std::fs::write(outside.join("victim"), b"orig\n").unwrap();
+            let fs = Filesystem::new(&root);
+
+            for path in &["../victim", "/../victim", "dir/../../victim"] {
+                assert_eq!(
+                    block_on(fs.put(b"PWNED\n".as_ref(), path)),
+                    Err(Error::from(ErrorKind::PermissionDenied))
+                );
+                assert_eq!(
+                    block_on(fs.append(b"PWNED\n".as_ref(), path)),
+                    Err(Error::from(ErrorKind::PermissionDenied))
+                );
+            }
+            assert_eq!(
+                block_on(fs.put_unique(b"PWNED\n".as_ref(), "../new")),
+                Err(Error::from(ErrorKind::PermissionDenied))
+            );
+            assert_eq!(std::fs::read(outside.join("victim")).unwrap(), b"orig\n");
+            assert!(!outside.join("new").exists());
+
+            // Going up and back in is fine.
+            block_on(fs.put(b"in".as_ref(), "dir/../in.txt")).unwrap();
+            assert_eq!(std::fs::read(root.join("in.txt")).unwrap(), b"in");
+        });
+    }
 
-        rt.block_on(fs.put(orig_content.as_ref(), "greeting.txt"))
-            .expect("Failed to `put` file");
+    #[test]
+    fn fs_set_mtime() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let file = tempfile::NamedTempFile::new_in(&root).unwrap();
+            let filename = file.path().file_name().unwrap();
+            let fs = Filesystem::new(&root);
+            let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+
+            // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
+            // to completion
+            block_on(fs.set_mtime(filename, mtime)).expect("Failed to set mtime");
+
+            let metadata = std::fs::metadata(file.path()).unwrap();
+            assert_eq!(metadata.modified().unwrap(), mtime);
+        });
+    }
+
+    #[test]
+    fn fs_user_modes() {
+        compat::on_runtime(move || {
+            use std::os::unix::fs::PermissionsExt;
+
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let mut fs = Filesystem::new(&root);
+            fs.set_user(&User::new("partner").umask(0o027).dir_mode(0o770));
 
-        let mut written_content = Vec::new();
-        let mut f = File::open(root.join("greeting.txt")).unwrap();
-        f.read_to_end(&mut written_content).unwrap();
+            // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
+            // to completion
+            block_on(fs.put(b"data".as_ref(), "upload.txt")).expect("Failed to `put` file");
+            block_on(fs.mkd("incoming")).expect("Failed to mkd");
 
-        assert_eq!(orig_content, written_content.as_slice());
+            let file_mode = std::fs::metadata(root.join("upload.txt"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(file_mode & 0o777, 0o640);
+            let dir_mode = std::fs::metadata(root.join("incoming"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(dir_mode & 0o777, 0o750);
+        });
     }
 
     #[test]
     fn fileinfo_fmt() {
-        struct MockMetadata {};
+        struct MockMetadata {}
         impl Metadata for MockMetadata {
             fn len(&self) -> u64 {
                 5
@@ -648,44 +2019,316 @@ mod tests {
         assert_eq!(my_format, format);
     }
 
+    #[test]
+    fn fileinfo_fmt_human_readable_sizes() {
+        struct MockMetadata {}
+        impl Metadata for MockMetadata {
+            fn len(&self) -> u64 {
+                1536
+            }
+            fn is_empty(&self) -> bool {
+                false
+            }
+            fn is_dir(&self) -> bool {
+                false
+            }
+            fn is_file(&self) -> bool {
+                true
+            }
+            fn modified(&self) -> Result<SystemTime> {
+                Ok(std::time::SystemTime::UNIX_EPOCH)
+            }
+            fn uid(&self) -> u32 {
+                1
+            }
+            fn gid(&self) -> u32 {
+                2
+            }
+        }
+
+        let fileinfo = Fileinfo {
+            path: "/some/file.txt",
+            metadata: MockMetadata {},
+        };
+        let options = ListOptions {
+            human_readable_sizes: true,
+            ..ListOptions::default()
+        };
+        assert_eq!(
+            fileinfo.format(options),
+            "-rwxr-xr-x     1 2 1.5K Jan 01 1970 file.txt"
+        );
+        // The default stays numeric for clients that parse the listing.
+        assert_eq!(
+            fileinfo.format(ListOptions::default()),
+            "-rwxr-xr-x     1 2 1536 Jan 01 1970 file.txt"
+        );
+    }
+
+    #[test]
+    fn fileinfo_format_facts() {
+        struct MockMetadata {}
+        impl Metadata for MockMetadata {
+            fn len(&self) -> u64 {
+                10
+            }
+            fn is_empty(&self) -> bool {
+                false
+            }
+            fn is_dir(&self) -> bool {
+                false
+            }
+            fn is_file(&self) -> bool {
+                true
+            }
+            fn modified(&self) -> Result<SystemTime> {
+                Ok(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86_400))
+            }
+            fn uid(&self) -> u32 {
+                1
+            }
+            fn gid(&self) -> u32 {
+                2
+            }
+        }
+
+        let fileinfo = Fileinfo {
+            path: "/some/file.txt",
+            metadata: MockMetadata {},
+        };
+        assert_eq!(
+            fileinfo.format_facts(),
+            "type=file;size=10;modify=19700102000000;UNIX.uid=1;UNIX.gid=2; /some/file.txt"
+        );
+
+        let fileinfo = Fileinfo {
+            path: "/some/evil\r\nfile.txt",
+            metadata: MockMetadata {},
+        };
+        assert!(fileinfo
+            .format_facts()
+            .ends_with(" /some/evil\r\0\0file.txt"));
+    }
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(human_readable_size(0), "0");
+        assert_eq!(human_readable_size(1023), "1023");
+        assert_eq!(human_readable_size(1024), "1.0K");
+        assert_eq!(human_readable_size(10 * 1024), "10K");
+        assert_eq!(human_readable_size(5 * 1024 * 1024 * 1024), "5.0G");
+        assert_eq!(human_readable_size(u64::MAX), "16E");
+    }
+
+    #[test]
+    fn fs_list_fmt_control_chars() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(root.join("bad\nname"), b"").unwrap();
+            std::fs::write(root.join("good"), b"").unwrap();
+            let fs = Filesystem::new(&root);
+
+            let list = |control_chars| {
+                let options = ListOptions {
+                    control_chars,
+                    ..ListOptions::default()
+                };
+                let listing = block_on(fs.list_fmt("/", options).concat2().compat()).unwrap();
+                let mut names: Vec<String> = String::from_utf8(listing)
+                    .unwrap()
+                    .lines()
+                    .map(|line| line.rsplit(' ').next().unwrap().to_string())
+                    .collect();
+                names.sort();
+                names
+            };
+            assert_eq!(list(ControlChars::Replace), vec!["bad?name", "good"]);
+            assert_eq!(list(ControlChars::Skip), vec!["good"]);
+        });
+    }
+
+    #[test]
+    fn fs_chmod() {
+        compat::on_runtime(move || {
+            use std::os::unix::fs::PermissionsExt;
+
+            let root = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(root.join("script.sh"), b"").unwrap();
+            let fs = Filesystem::new(&root);
+
+            block_on(fs.chmod("script.sh", 0o750)).unwrap();
+            let mode = std::fs::metadata(root.join("script.sh"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o750);
+
+            block_on(fs.chmod("nonexistent", 0o644)).unwrap_err();
+        });
+    }
+
+    #[test]
+    fn fs_huge_sparse_file() {
+        compat::on_runtime(move || {
+            // Bigger than both `u32::MAX` and `i32::MAX`, so any 32-bit size arithmetic would show
+            let len: u64 = (5 << 30) + 7;
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let file = File::create(root.join("huge.img")).unwrap();
+            file.set_len(len).unwrap();
+            let fs = Filesystem::new(&root);
+
+            let meta = block_on(fs.stat("huge.img")).unwrap();
+            assert_eq!(Metadata::len(&meta), len);
+
+            let listing =
+                block_on(fs.list_fmt("/", ListOptions::default()).concat2().compat()).unwrap();
+            let listing = String::from_utf8(listing).unwrap();
+            assert!(listing.contains(" 5368709127 "), "{}", listing);
+        });
+    }
+
+    #[test]
+    fn fs_checksum() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(
+                root.join("fox.txt"),
+                b"The quick brown fox jumps over the lazy dog",
+            )
+            .unwrap();
+            let fs = Filesystem::new(&root);
+
+            assert_eq!(
+                block_on(fs.checksum("fox.txt", HashAlgorithm::Md5, None)).unwrap(),
+                "9e107d9d372bb6826bd81d3542a419d6"
+            );
+            // Only "quick"
+            let mut hasher = HashAlgorithm::Sha1.hasher();
+            hasher.update(b"quick");
+            assert_eq!(
+                block_on(fs.checksum("fox.txt", HashAlgorithm::Sha1, Some(4..9))).unwrap(),
+                hasher.finish()
+            );
+            block_on(fs.checksum("missing.txt", HashAlgorithm::Sha256, None)).unwrap_err();
+        });
+    }
+
+    #[test]
+    fn fs_del_if() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(root.join("report.csv"), b"1,2,3").unwrap();
+            let fs = std::sync::Arc::new(Filesystem::new(&root));
+            let modified = std::fs::metadata(root.join("report.csv"))
+                .unwrap()
+                .modified()
+                .unwrap();
+
+            let changed = Precondition {
+                size: Some(4),
+                modified: Some(modified),
+                ..Precondition::default()
+            };
+            assert!(!block_on(fs.del_if("report.csv".into(), changed)).unwrap());
+            assert!(root.join("report.csv").exists());
+
+            // The filesystem doesn't have ETags, so that can never match
+            let etag = Precondition {
+                etag: Some("abc".to_string()),
+                ..Precondition::default()
+            };
+            assert!(!block_on(fs.rename_if("report.csv".into(), "old.csv".into(), etag)).unwrap());
+
+            let unchanged = Precondition {
+                size: Some(5),
+                modified: Some(modified),
+                ..Precondition::default()
+            };
+            assert!(block_on(fs.rename_if(
+                "report.csv".into(),
+                "old.csv".into(),
+                unchanged.clone()
+            ))
+            .unwrap());
+            assert!(block_on(fs.del_if("old.csv".into(), unchanged)).unwrap());
+            assert!(!root.join("old.csv").exists());
+        });
+    }
+
     #[test]
     fn fs_mkd() {
-        let root = tempfile::TempDir::new().unwrap().into_path();
-        let fs = Filesystem::new(&root);
-        let new_dir_name = "bla";
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            let new_dir_name = "bla";
+
+            // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
+            // to completion
+
+            block_on(fs.mkd(new_dir_name)).expect("Failed to mkd");
 
-        // Since the Filesystem StorageBackend is based on futures, we need a runtime to run them
-        // to completion
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+            let full_path = root.join(new_dir_name);
+            let metadata = std::fs::metadata(full_path).unwrap();
+            assert!(metadata.is_dir());
+        });
+    }
 
-        rt.block_on(fs.mkd(new_dir_name)).expect("Failed to mkd");
+    #[test]
+    fn fs_rmd() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let fs = Filesystem::new(&root);
+            std::fs::create_dir_all(root.join("full/sub")).unwrap();
+            std::fs::create_dir(root.join("empty")).unwrap();
+            block_on(fs.rmd("empty")).expect("Failed to rmd");
+            assert!(!root.join("empty").exists());
+            assert!(block_on(fs.rmd("full")).is_err());
+            assert!(root.join("full/sub").is_dir());
+        });
+    }
 
-        let full_path = root.join(new_dir_name);
-        let metadata = std::fs::metadata(full_path).unwrap();
-        assert!(metadata.is_dir());
+    #[test]
+    fn fs_rmd_recursive() {
+        compat::on_runtime(move || {
+            use std::sync::Arc;
+
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let outside = tempfile::TempDir::new().unwrap().keep();
+            std::fs::write(outside.join("precious.txt"), b"don't touch").unwrap();
+            std::fs::create_dir_all(root.join("tree/a/b")).unwrap();
+            std::fs::write(root.join("tree/a/b/file.txt"), b"bye").unwrap();
+            std::fs::write(root.join("tree/top.txt"), b"bye").unwrap();
+            std::os::unix::fs::symlink(&outside, root.join("tree/link")).unwrap();
+            let fs = Arc::new(Filesystem::new(&root));
+            block_on(fs.rmd_recursive("tree".into())).expect("Failed to rmd_recursive");
+            assert!(!root.join("tree").exists());
+            assert!(outside.join("precious.txt").exists());
+            assert!(block_on(fs.rmd_recursive("/".into())).is_err());
+            assert!(root.exists());
+        });
     }
 
     #[test]
     fn fs_rename() {
-        let root = tempfile::TempDir::new().unwrap().into_path();
-        let file = tempfile::NamedTempFile::new_in(&root).unwrap();
-        let old_filename = file.path().file_name().unwrap().to_str().unwrap();
-        let new_filename = "hello.txt";
-
-        // Since the Filesystem StorageBAckend is based on futures, we need a runtime to run them
-        // to completion
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
-
-        let fs = Filesystem::new(&root);
-        rt.block_on(fs.rename(&old_filename, &new_filename))
-            .expect("Failed to rename");
-
-        let new_full_path = root.join(new_filename);
-        assert!(std::fs::metadata(new_full_path)
-            .expect("new filename not found")
-            .is_file());
-
-        let old_full_path = root.join(old_filename);
-        std::fs::metadata(old_full_path).expect_err("Old filename should not exists anymore");
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap().keep();
+            let file = tempfile::NamedTempFile::new_in(&root).unwrap();
+            let old_filename = file.path().file_name().unwrap().to_str().unwrap();
+            let new_filename = "hello.txt";
+
+            // Since the Filesystem StorageBAckend is based on futures, we need a runtime to run them
+            // to completion
+
+            let fs = Filesystem::new(&root);
+            block_on(fs.rename(&old_filename, &new_filename)).expect("Failed to rename");
+
+            let new_full_path = root.join(new_filename);
+            assert!(std::fs::metadata(new_full_path)
+                .expect("new filename not found")
+                .is_file());
+
+            let old_full_path = root.join(old_filename);
+            std::fs::metadata(old_full_path).expect_err("Old filename should not exists anymore");
+        });
     }
 }
@@ -0,0 +1,1079 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::{Bound, Range};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, TimeZone, Utc};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use futures::{future, stream, Async, Future, Poll, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+
+use super::read_only::denied;
+use super::{
+    blocking, Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, Result, StorageBackend,
+};
+
+// PAX and GNU long name headers bigger than this are taken for a broken archive.
+const MAX_NAME_HEADER: u64 = 1 << 20;
+
+// Central directories bigger than this are taken for a broken archive.
+const MAX_CENTRAL_DIRECTORY: u64 = 1 << 30;
+
+/// A read-only [`StorageBackend`] that serves the contents of a zip file or tarball as they are,
+/// without extracting them: the archive is indexed once, when it's opened, and every download
+/// reads the file it asks for straight from the archive. That suits firmware bundles and dataset
+/// snapshots that are published as a single archive anyway.
+///
+/// Archives can be zip files, with files that are stored or deflated, including ZIP64 ones, and
+/// tarballs in the ustar, GNU or PAX formats, which may be gzipped. Directories that the archive
+/// doesn't have entries for show up anyway, for the files in them. Symbolic links are listed,
+/// but can't be downloaded, and hard links are served as the file they link to. Entries whose
+/// paths go outside the archive, with `..`, are left out.
+///
+/// Downloads from a gzipped tarball have to decompress it up to the file, since gzip has no
+/// index, so those get slower towards the end of big ones. Everything that would change the
+/// archive fails with a permission error, like with [`ReadOnly`]. Clones share the index.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::Archive;
+///
+/// let archive = Archive::open("/srv/firmware/bundle-2.4.zip").unwrap();
+/// let server = Server::new(Box::new(move || archive.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`ReadOnly`]: ./struct.ReadOnly.html
+#[derive(Clone)]
+pub struct Archive {
+    index: Arc<Index>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+// Where the contents of an entry are.
+#[derive(Clone, Debug)]
+enum Data {
+    // Directories and symbolic links.
+    None,
+    // At this offset in the (decompressed) tarball.
+    Tar(u64),
+    // After the local header at this offset in the zip file.
+    Zip {
+        header: u64,
+        method: u16,
+        compressed: u64,
+        encrypted: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    metadata: ArchiveMetadata,
+    data: Data,
+}
+
+struct Index {
+    path: PathBuf,
+    format: Format,
+    // Keyed by the path relative to the root, which is the empty path.
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+/// The metadata of a file or directory in an [`Archive`], as the archive has it.
+///
+/// [`Archive`]: ./struct.Archive.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveMetadata {
+    len: u64,
+    dir: bool,
+    symlink: bool,
+    modified: SystemTime,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl ArchiveMetadata {
+    fn dir(modified: SystemTime) -> Self {
+        ArchiveMetadata {
+            len: 0,
+            dir: true,
+            symlink: false,
+            modified,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+        }
+    }
+
+    /// Returns the permissions of the file or directory, e.g. `0o644`.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+impl Metadata for ArchiveMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir && !self.symlink
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    fn modified(&self) -> Result<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// The path relative to the root, with sequences like '../' resolved.
+fn key<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let mut key = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => key.push(name),
+            Component::ParentDir => {
+                if !key.pop() {
+                    return Err(Error::from(ErrorKind::PermissionDenied));
+                }
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) => return Err(Error::from(ErrorKind::PermissionDenied)),
+        }
+    }
+    Ok(key)
+}
+
+// The path of an entry of the archive, or `None` if it has one that goes outside of it.
+fn entry_key(name: &str) -> Option<PathBuf> {
+    let mut key = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(name) => key.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(key)
+}
+
+// Reads past the given number of bytes.
+fn skip<R: Read>(reader: &mut R, bytes: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(bytes), &mut io::sink())?;
+    if skipped < bytes {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+// Reads a little-endian number of `n` bytes.
+fn le(data: &[u8], at: usize, n: usize) -> io::Result<u64> {
+    let bytes = data
+        .get(at..at + n)
+        .ok_or_else(|| invalid("Truncated zip file"))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte)))
+}
+
+// Parses a number field of a tar header: octal digits, or big-endian base-256 for big ones.
+fn tar_number(field: &[u8]) -> u64 {
+    if field.first().is_some_and(|&byte| byte & 0x80 != 0) {
+        return field[1..]
+            .iter()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte));
+    }
+    field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| (b'0'..=b'7').contains(&byte))
+        .fold(0, |value, &byte| value << 3 | u64::from(byte - b'0'))
+}
+
+// A NUL-terminated string field of a tar header.
+fn tar_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+// Returns the value of the given key in the records of a PAX header, like `30 mtime=1600000000.5`.
+fn pax_value<'a>(records: &'a str, wanted: &str) -> Option<&'a str> {
+    records
+        .lines()
+        .filter_map(|record| record.split_once(' ')?.1.split_once('='))
+        .rfind(|(key, _)| *key == wanted)
+        .map(|(_, value)| value)
+}
+
+// What the PAX and GNU headers say about the entry that follows them.
+#[derive(Default)]
+struct Overrides {
+    path: Option<String>,
+    link: Option<String>,
+    size: Option<u64>,
+    mtime: Option<u64>,
+}
+
+// Reads the entries of a tarball, with the offsets of their contents.
+fn read_tar<R: Read>(mut reader: R) -> io::Result<Vec<(String, Entry, Option<String>)>> {
+    let mut entries = vec![];
+    let mut position = 0;
+    let mut overrides = Overrides::default();
+    let mut header = [0; 512];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => position += 512,
+            // Some tarballs end without the blocks of zeros.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && position > 0 => break,
+            Err(e) => return Err(e),
+        }
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let checksum = header[..148]
+            .iter()
+            .chain([b' '; 8].iter())
+            .chain(header[156..].iter())
+            .map(|&b| u64::from(b))
+            .sum::<u64>();
+        if checksum != tar_number(&header[148..156]) {
+            return Err(invalid("Not a tarball, or a broken one"));
+        }
+
+        let kind = header[156];
+        let mut size = tar_number(&header[124..136]);
+        if !matches!(kind, b'L' | b'K' | b'x' | b'g') {
+            size = overrides.size.take().unwrap_or(size);
+        }
+        let padded = size.div_ceil(512) * 512;
+        if matches!(kind, b'L' | b'K' | b'x') {
+            if size > MAX_NAME_HEADER {
+                return Err(invalid("Tarball with an oversized header"));
+            }
+            let mut data = vec![0; size as usize];
+            reader.read_exact(&mut data)?;
+            skip(&mut reader, padded - size)?;
+            position += padded;
+            match kind {
+                b'L' => overrides.path = Some(tar_string(&data)),
+                b'K' => overrides.link = Some(tar_string(&data)),
+                _ => {
+                    let records = String::from_utf8_lossy(&data);
+                    if let Some(path) = pax_value(&records, "path") {
+                        overrides.path = Some(path.to_string());
+                    }
+                    if let Some(link) = pax_value(&records, "linkpath") {
+                        overrides.link = Some(link.to_string());
+                    }
+                    overrides.size = pax_value(&records, "size").and_then(|s| s.parse().ok());
+                    overrides.mtime = pax_value(&records, "mtime")
+                        .and_then(|mtime| mtime.split('.').next()?.parse().ok());
+                }
+            }
+            continue;
+        }
+
+        let name = overrides.path.take().unwrap_or_else(|| {
+            let name = tar_string(&header[..100]);
+            let prefix = tar_string(&header[345..500]);
+            if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                format!("{}/{}", prefix, name)
+            } else {
+                name
+            }
+        });
+        let link = overrides
+            .link
+            .take()
+            .unwrap_or_else(|| tar_string(&header[157..257]));
+        let mtime = overrides
+            .mtime
+            .take()
+            .unwrap_or_else(|| tar_number(&header[136..148]));
+        let metadata = ArchiveMetadata {
+            len: 0,
+            dir: kind == b'5',
+            symlink: kind == b'2',
+            modified: UNIX_EPOCH + Duration::from_secs(mtime),
+            mode: tar_number(&header[100..108]) as u32 & 0o7777,
+            uid: tar_number(&header[108..116]) as u32,
+            gid: tar_number(&header[116..124]) as u32,
+        };
+        match kind {
+            b'0' | b'\0' | b'7' => entries.push((
+                name,
+                Entry {
+                    metadata: ArchiveMetadata {
+                        len: size,
+                        ..metadata
+                    },
+                    data: Data::Tar(position),
+                },
+                None,
+            )),
+            b'1' => entries.push((
+                name,
+                Entry {
+                    metadata,
+                    data: Data::None,
+                },
+                Some(link),
+            )),
+            b'2' | b'5' => entries.push((
+                name,
+                Entry {
+                    metadata,
+                    data: Data::None,
+                },
+                None,
+            )),
+            // Devices, FIFOs, global PAX headers and the like.
+            _ => {}
+        }
+        skip(&mut reader, padded)?;
+        position += padded;
+    }
+    Ok(entries)
+}
+
+// Converts an MS-DOS date and time, which zip files have in local time, but for lack of a time
+// zone are taken as UTC.
+fn dos_time(date: u64, time: u64) -> SystemTime {
+    NaiveDate::from_ymd_opt(
+        (date >> 9) as i32 + 1980,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+    )
+    .and_then(|day| {
+        day.and_hms_opt(
+            (time >> 11) as u32,
+            ((time >> 5) & 0x3f) as u32,
+            ((time & 0x1f) * 2) as u32,
+        )
+    })
+    .map_or(UNIX_EPOCH, |time| Utc.from_utc_datetime(&time).into())
+}
+
+// Reads the central directory of a zip file.
+fn read_zip(file: &mut File) -> io::Result<Vec<(String, Entry)>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    // The end of central directory record is at the end, followed by a comment of at most 64K.
+    let tail_len = len.min(22 + 0xffff);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| tail[i..i + 4] == [0x50, 0x4b, 0x05, 0x06])
+        .ok_or_else(|| invalid("Not a zip file"))?;
+    let mut count = le(&tail, end + 10, 2)?;
+    let mut size = le(&tail, end + 12, 4)?;
+    let mut offset = le(&tail, end + 16, 4)?;
+    if count == 0xffff || size == 0xffff_ffff || offset == 0xffff_ffff {
+        let locator = end
+            .checked_sub(20)
+            .filter(|&at| tail[at..at + 4] == [0x50, 0x4b, 0x06, 0x07])
+            .ok_or_else(|| invalid("ZIP64 file without its locator"))?;
+        file.seek(SeekFrom::Start(le(&tail, locator + 8, 8)?))?;
+        let mut record = [0; 56];
+        file.read_exact(&mut record)?;
+        if record[..4] != [0x50, 0x4b, 0x06, 0x06] {
+            return Err(invalid("Broken ZIP64 end of central directory record"));
+        }
+        count = le(&record, 32, 8)?;
+        size = le(&record, 40, 8)?;
+        offset = le(&record, 48, 8)?;
+    }
+    if size > MAX_CENTRAL_DIRECTORY {
+        return Err(invalid("Zip file with an oversized central directory"));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut directory = vec![0; size as usize];
+    file.read_exact(&mut directory)?;
+
+    let mut entries = vec![];
+    let mut at = 0;
+    for _ in 0..count {
+        if le(&directory, at, 4)? != 0x0201_4b50 {
+            return Err(invalid("Broken zip central directory"));
+        }
+        let made_by = le(&directory, at + 4, 2)?;
+        let flags = le(&directory, at + 8, 2)?;
+        let method = le(&directory, at + 10, 2)? as u16;
+        let mut modified = dos_time(le(&directory, at + 14, 2)?, le(&directory, at + 12, 2)?);
+        let mut compressed = le(&directory, at + 20, 4)?;
+        let mut len = le(&directory, at + 24, 4)?;
+        let name_len = le(&directory, at + 28, 2)? as usize;
+        let extra_len = le(&directory, at + 30, 2)? as usize;
+        let comment_len = le(&directory, at + 32, 2)? as usize;
+        let external = le(&directory, at + 38, 4)?;
+        let mut header = le(&directory, at + 42, 4)?;
+        let name = directory
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid("Truncated zip file"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        let extra = directory
+            .get(at + 46 + name_len..at + 46 + name_len + extra_len)
+            .ok_or_else(|| invalid("Truncated zip file"))?;
+        let mut field = 0;
+        while field + 4 <= extra.len() {
+            let id = le(extra, field, 2)?;
+            let data_len = le(extra, field + 2, 2)? as usize;
+            let data = &extra[field + 4..(field + 4 + data_len).min(extra.len())];
+            match id {
+                // The ZIP64 sizes and offset, for the ones that didn't fit.
+                0x0001 => {
+                    let mut next = 0;
+                    for value in [&mut len, &mut compressed, &mut header] {
+                        if *value == 0xffff_ffff {
+                            *value = le(data, next, 8)?;
+                            next += 8;
+                        }
+                    }
+                }
+                // The extended timestamp, in UTC.
+                0x5455 if data.first().is_some_and(|flags| flags & 1 != 0) => {
+                    modified = UNIX_EPOCH + Duration::from_secs(le(data, 1, 4)?);
+                }
+                _ => {}
+            }
+            field += 4 + data_len;
+        }
+        at += 46 + name_len + extra_len + comment_len;
+
+        // The permissions are only there in zip files made on Unix.
+        let mode = if made_by >> 8 == 3 { external >> 16 } else { 0 } as u32;
+        let dir = name.ends_with('/') || mode & 0o170_000 == 0o040_000;
+        let symlink = mode & 0o170_000 == 0o120_000;
+        let metadata = ArchiveMetadata {
+            len: if dir { 0 } else { len },
+            dir,
+            symlink,
+            modified,
+            mode: match mode & 0o7777 {
+                0 if dir => 0o755,
+                0 => 0o644,
+                mode => mode,
+            },
+            uid: 0,
+            gid: 0,
+        };
+        let data = if dir || symlink {
+            Data::None
+        } else {
+            Data::Zip {
+                header,
+                method,
+                compressed,
+                encrypted: flags & 1 != 0,
+            }
+        };
+        entries.push((name, Entry { metadata, data }));
+    }
+    Ok(entries)
+}
+
+impl Index {
+    fn read(path: PathBuf) -> io::Result<Index> {
+        let mut file = File::open(&path)?;
+        let archived = file.metadata()?.modified().unwrap_or(UNIX_EPOCH);
+        let mut magic = [0; 4];
+        let magic_len = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+        let format = match &magic[..magic_len] {
+            [0x50, 0x4b, 0x03, 0x04] | [0x50, 0x4b, 0x05, 0x06] => Format::Zip,
+            [0x1f, 0x8b, ..] => Format::TarGz,
+            _ => Format::Tar,
+        };
+        let (entries, links) = match format {
+            Format::Zip => (read_zip(&mut file)?, vec![]),
+            Format::Tar => split_links(read_tar(BufReader::new(file))?),
+            Format::TarGz => split_links(read_tar(MultiGzDecoder::new(BufReader::new(file)))?),
+        };
+
+        let mut index = Index {
+            path,
+            format,
+            entries: BTreeMap::new(),
+        };
+        index
+            .entries
+            .insert(PathBuf::new(), Entry::implicit(archived));
+        for (name, entry) in entries {
+            index.insert(&name, entry, archived);
+        }
+        // Hard links are to files earlier in the archive.
+        for (name, mut entry, target) in links {
+            match entry_key(&target).and_then(|target| index.entries.get(&target)) {
+                Some(target) if !target.metadata.dir => {
+                    entry.metadata.len = target.metadata.len;
+                    entry.data = target.data.clone();
+                    index.insert(&name, entry, archived);
+                }
+                _ => warn!("Leaving out {:?}, a hard link to a missing file", name),
+            }
+        }
+        Ok(index)
+    }
+
+    fn insert(&mut self, name: &str, entry: Entry, archived: SystemTime) {
+        let key = match entry_key(name) {
+            Some(key) if key != Path::new("") => key,
+            _ => {
+                warn!("Leaving out {:?}, its path is outside the archive", name);
+                return;
+            }
+        };
+        for parent in key.ancestors().skip(1) {
+            self.entries
+                .entry(parent.to_path_buf())
+                .or_insert_with(|| Entry::implicit(archived));
+        }
+        // Later entries replace earlier ones, like when extracting.
+        self.entries.insert(key, entry);
+    }
+
+    fn get(&self, key: &Path) -> Result<&Entry> {
+        self.entries
+            .get(key)
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    fn children<'a>(&'a self, key: &'a Path) -> impl Iterator<Item = (&'a PathBuf, &'a Entry)> {
+        self.entries
+            .range::<Path, _>((Bound::Excluded(key), Bound::Unbounded))
+            .take_while(move |(path, _)| path.starts_with(key))
+            .filter(move |(path, _)| path.parent() == Some(key))
+    }
+
+    // Opens the given range of the contents of a file.
+    fn open(&self, entry: &Entry, range: Range<u64>) -> io::Result<Box<dyn Read + Send>> {
+        let start = range.start.min(entry.metadata.len);
+        let len = range.end.min(entry.metadata.len).saturating_sub(start);
+        let mut file = File::open(&self.path)?;
+        match entry.data {
+            Data::None => Err(io::Error::other("Not a file")),
+            Data::Tar(offset) if self.format == Format::TarGz => {
+                let mut decoder = MultiGzDecoder::new(BufReader::new(file));
+                skip(&mut decoder, offset + start)?;
+                Ok(Box::new(decoder.take(len)))
+            }
+            Data::Tar(offset) => {
+                file.seek(SeekFrom::Start(offset + start))?;
+                Ok(Box::new(BufReader::new(file).take(len)))
+            }
+            Data::Zip {
+                encrypted: true, ..
+            } => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "The file is encrypted",
+            )),
+            Data::Zip {
+                header,
+                method,
+                compressed,
+                ..
+            } => {
+                file.seek(SeekFrom::Start(header))?;
+                let mut local = [0; 30];
+                file.read_exact(&mut local)?;
+                if local[..4] != [0x50, 0x4b, 0x03, 0x04] {
+                    return Err(invalid("Broken zip local header"));
+                }
+                let data = header + 30 + le(&local, 26, 2)? + le(&local, 28, 2)?;
+                file.seek(SeekFrom::Start(data))?;
+                let compressed = BufReader::new(file).take(compressed);
+                match method {
+                    0 => {
+                        let mut stored = compressed;
+                        skip(&mut stored, start)?;
+                        Ok(Box::new(stored.take(len)))
+                    }
+                    8 => {
+                        let mut decoder = DeflateDecoder::new(compressed);
+                        skip(&mut decoder, start)?;
+                        Ok(Box::new(decoder.take(len)))
+                    }
+                    method => Err(io::Error::other(format!(
+                        "Unsupported zip compression method {}",
+                        method
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+type TarEntries = (Vec<(String, Entry)>, Vec<(String, Entry, String)>);
+
+// Splits the entries of a tarball into the ones with contents of their own, and the hard links.
+fn split_links(entries: Vec<(String, Entry, Option<String>)>) -> TarEntries {
+    let mut files = vec![];
+    let mut links = vec![];
+    for (name, entry, link) in entries {
+        match link {
+            Some(target) => links.push((name, entry, target)),
+            None => files.push((name, entry)),
+        }
+    }
+    (files, links)
+}
+
+impl Entry {
+    // A directory that the archive has no entry for, but files in.
+    fn implicit(modified: SystemTime) -> Self {
+        Entry {
+            metadata: ArchiveMetadata::dir(modified),
+            data: Data::None,
+        }
+    }
+}
+
+impl Archive {
+    /// Open the zip file or tarball at the given path, and index its entries.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        Ok(Archive {
+            index: Arc::new(Index::read(path.into())?),
+        })
+    }
+
+    fn with<T, F>(&self, path: &Path, f: F) -> Result<T>
+    where
+        F: FnOnce(&Index, PathBuf) -> Result<T>,
+    {
+        key(path).and_then(|key| f(&self.index, key))
+    }
+
+    // Opens the given range of a file, on the blocking section of the tokio threadpool.
+    async fn open_range(&self, path: &Path, range: Range<u64>) -> Result<ArchiveFile> {
+        let entry = key(path).and_then(|key| self.index.get(&key).cloned())?;
+        if !entry.metadata.is_file() {
+            return Err(Error::backend("Not a file"));
+        }
+        let index = Arc::clone(&self.index);
+        let reader = blocking(move || index.open(&entry, range)).compat().await?;
+        Ok(ArchiveFile { reader })
+    }
+}
+
+// Runs blocking reads from within a reader, on the blocking section of the tokio threadpool.
+fn block<T, F: FnOnce() -> io::Result<T>>(f: F) -> Poll<T, io::Error> {
+    match tokio_threadpool::blocking(f) {
+        Ok(Async::Ready(Ok(result))) => Ok(Async::Ready(result)),
+        Ok(Async::Ready(Err(e))) => Err(e),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// A file being read from an [`Archive`], and decompressed if it has to be.
+///
+/// [`Archive`]: ./struct.Archive.html
+pub struct ArchiveFile {
+    reader: Box<dyn Read + Send>,
+}
+
+impl Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = &mut self.reader;
+        match block(|| reader.read(buf))? {
+            Async::Ready(n) => Ok(n),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl tokio::prelude::AsyncRead for ArchiveFile {}
+
+#[async_trait]
+impl StorageBackend for Archive {
+    type File = ArchiveFile;
+    type Metadata = ArchiveMetadata;
+    type Error = Error;
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+        self.with(path.as_ref(), |index, key| {
+            Ok(index.get(&key)?.metadata.clone())
+        })
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    > {
+        let entries = self.with(path.as_ref(), |index, key| {
+            if !index.get(&key)?.metadata.dir {
+                return Err(Error::backend("Not a directory"));
+            }
+            Ok(index
+                .children(&key)
+                .map(|(path, entry)| Fileinfo {
+                    path: path.clone(),
+                    metadata: entry.metadata.clone(),
+                })
+                .collect::<Vec<_>>())
+        });
+        Box::new(
+            future::result(entries)
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File> {
+        self.open_range(path.as_ref(), 0..u64::MAX).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>> {
+        Ok(Box::new(self.open_range(path.as_ref(), range).await?))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64> {
+        denied()
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64> {
+        denied()
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64> {
+        denied()
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+        denied()
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+        denied()
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+        denied()
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, _from: P, _to: P) -> Result<()> {
+        denied()
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(&self, _path: P, _mtime: SystemTime) -> Result<()> {
+        denied()
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, _path: P, _mode: u32) -> Result<()> {
+        denied()
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<Range<u64>>,
+    ) -> Result<String> {
+        let range = range.unwrap_or(0..u64::MAX);
+        let file = self.open_range(path.as_ref(), range).await?;
+        let checksum = blocking(move || {
+            let mut reader = file.reader;
+            let mut hasher = algorithm.hasher();
+            let mut chunk = vec![0; 64 * 1024];
+            loop {
+                match reader.read(&mut chunk)? {
+                    0 => return Ok(hasher.finish()),
+                    n => hasher.update(&chunk[..n]),
+                }
+            }
+        });
+        Ok(checksum.compat().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    fn contents(archive: &Archive, path: &str, range: Range<u64>) -> Vec<u8> {
+        block_on(async {
+            let file = archive.get_range(path, range).await?;
+            tokio_io::io::read_to_end(file, vec![])
+                .compat()
+                .await
+                .map_err(Error::from)
+        })
+        .unwrap()
+        .1
+    }
+
+    fn names(archive: &Archive, path: &str) -> Vec<String> {
+        block_on(archive.list(path).collect().compat())
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn tar_header(name: &str, kind: u8, size: usize, link: &str) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[108..115].copy_from_slice(b"0001750");
+        header[116..123].copy_from_slice(b"0000144");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[136..147].copy_from_slice(b"13727410000");
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    fn tar_entry(tarball: &mut Vec<u8>, name: &str, kind: u8, data: &[u8], link: &str) {
+        tarball.extend(tar_header(name, kind, data.len(), link));
+        tarball.extend(data);
+        tarball.resize(tarball.len().div_ceil(512) * 512, 0);
+    }
+
+    fn tarball() -> Vec<u8> {
+        let long = format!("data/{}.bin", "x".repeat(120));
+        let mut tarball = vec![];
+        tar_entry(&mut tarball, "./data/", b'5', b"", "");
+        tar_entry(&mut tarball, "./data/hello.txt", b'0', b"Hello, world!", "");
+        tar_entry(&mut tarball, "././@LongLink", b'L', long.as_bytes(), "");
+        tar_entry(&mut tarball, "data/xxx", b'0', &[7; 1000], "");
+        tar_entry(&mut tarball, "latest", b'2', b"", "data/hello.txt");
+        tar_entry(&mut tarball, "copy.txt", b'1', b"", "data/hello.txt");
+        tar_entry(&mut tarball, "../escaped.txt", b'0', b"nope", "");
+        tarball.extend(vec![0; 1024]);
+        tarball
+    }
+
+    fn write_archive(dir: &tempfile::TempDir, name: &str, contents: &[u8]) -> Archive {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        Archive::open(path).unwrap()
+    }
+
+    #[test]
+    fn serves_tarballs() {
+        compat::on_runtime(move || {
+            let dir = tempfile::TempDir::new().unwrap();
+            let tarball = tarball();
+            let mut gzipped = GzEncoder::new(vec![], Compression::default());
+            gzipped.write_all(&tarball).unwrap();
+            let gzipped = gzipped.finish().unwrap();
+
+            for archive in [
+                write_archive(&dir, "data.tar", &tarball),
+                write_archive(&dir, "data.tar.gz", &gzipped),
+            ] {
+                assert_eq!(names(&archive, "/"), vec!["copy.txt", "data", "latest"]);
+                let long = format!("data/{}.bin", "x".repeat(120));
+                assert_eq!(
+                    names(&archive, "/data"),
+                    vec!["data/hello.txt".to_string(), long.clone()]
+                );
+
+                let hello = block_on(archive.stat("/data/hello.txt")).unwrap();
+                assert_eq!(hello.len(), 13);
+                assert_eq!((hello.mode(), hello.uid(), hello.gid()), (0o644, 1000, 100));
+                assert_eq!(
+                    hello.modified().unwrap(),
+                    UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+                );
+                assert_eq!(contents(&archive, "/data/hello.txt", 7..12), b"world");
+                assert_eq!(
+                    contents(&archive, "copy.txt", 0..u64::MAX),
+                    b"Hello, world!"
+                );
+                assert_eq!(contents(&archive, &long, 990..2000), vec![7; 10]);
+                assert!(block_on(archive.stat("latest")).unwrap().is_symlink());
+                assert!(block_on(archive.get("latest")).is_err());
+                assert!(block_on(archive.get("/data")).is_err());
+                assert_eq!(
+                    block_on(archive.del("/data/hello.txt")).unwrap_err(),
+                    Error::from(ErrorKind::PermissionDenied)
+                );
+            }
+            assert!(Archive::open(dir.path().join("missing.tar")).is_err());
+            let broken = dir.path().join("broken.tar");
+            std::fs::write(&broken, vec![1; 1024]).unwrap();
+            assert!(Archive::open(broken).is_err());
+        });
+    }
+
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut zip = vec![];
+        let mut directory = vec![];
+        for (name, data, deflate) in entries {
+            let stored = if *deflate {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                data.to_vec()
+            };
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(data);
+            let crc = crc.finalize();
+            let method: u16 = if *deflate { 8 } else { 0 };
+            // 2020-09-13 12:26:40
+            let (time, date): (u16, u16) = (12 << 11 | 26 << 5 | 20, 40 << 9 | 9 << 5 | 13);
+            let offset = zip.len() as u32;
+            let mut common = vec![];
+            common.extend(&20u16.to_le_bytes());
+            common.extend(&0u16.to_le_bytes());
+            common.extend(&method.to_le_bytes());
+            common.extend(&time.to_le_bytes());
+            common.extend(&date.to_le_bytes());
+            common.extend(&crc.to_le_bytes());
+            common.extend(&(stored.len() as u32).to_le_bytes());
+            common.extend(&(data.len() as u32).to_le_bytes());
+            common.extend(&(name.len() as u16).to_le_bytes());
+            common.extend(&0u16.to_le_bytes());
+
+            zip.extend(&[0x50, 0x4b, 0x03, 0x04]);
+            zip.extend(&common);
+            zip.extend(name.as_bytes());
+            zip.extend(&stored);
+
+            directory.extend(&[0x50, 0x4b, 0x01, 0x02]);
+            // Made on Unix.
+            directory.extend(&(3u16 << 8 | 20).to_le_bytes());
+            directory.extend(&common);
+            directory.extend(&[0; 6]);
+            let mode: u32 = if name.ends_with('/') {
+                0o040_755
+            } else {
+                0o100_600
+            };
+            directory.extend(&(mode << 16).to_le_bytes());
+            directory.extend(&offset.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let offset = zip.len() as u32;
+        zip.extend(&directory);
+        zip.extend(&[0x50, 0x4b, 0x05, 0x06, 0, 0, 0, 0]);
+        zip.extend(&(entries.len() as u16).to_le_bytes());
+        zip.extend(&(entries.len() as u16).to_le_bytes());
+        zip.extend(&(directory.len() as u32).to_le_bytes());
+        zip.extend(&offset.to_le_bytes());
+        zip.extend(&3u16.to_le_bytes());
+        zip.extend(b"abc");
+        zip
+    }
+
+    #[test]
+    fn serves_zip_files() {
+        compat::on_runtime(move || {
+            let dir = tempfile::TempDir::new().unwrap();
+            let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+            let archive = write_archive(
+                &dir,
+                "firmware.zip",
+                &zip(&[
+                    ("README", b"Flash me", false),
+                    ("images/", b"", false),
+                    ("images/rootfs.img", &big, true),
+                    ("docs/notes/changes.txt", b"Fixed things", true),
+                ]),
+            );
+            assert_eq!(names(&archive, ""), vec!["README", "docs", "images"]);
+            assert_eq!(names(&archive, "docs"), vec!["docs/notes"]);
+            assert!(block_on(archive.stat("docs/notes")).unwrap().is_dir());
+            assert!(block_on(archive.list("README").collect().compat()).is_err());
+
+            let rootfs = block_on(archive.stat("images/rootfs.img")).unwrap();
+            assert_eq!((rootfs.len(), rootfs.mode()), (100_000, 0o600));
+            assert_eq!(
+                rootfs.modified().unwrap(),
+                UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+            );
+            assert_eq!(contents(&archive, "README", 0..u64::MAX), b"Flash me");
+            assert_eq!(
+                contents(&archive, "images/rootfs.img", 0..u64::MAX),
+                big.clone()
+            );
+            assert_eq!(
+                contents(&archive, "images/rootfs.img", 99_990..100_000),
+                big[99_990..].to_vec()
+            );
+            assert_eq!(
+                block_on(archive.checksum(
+                    "docs/notes/../notes/changes.txt",
+                    HashAlgorithm::Crc32,
+                    None
+                ))
+                .unwrap(),
+                format!("{:08x}", crc32fast::hash(b"Fixed things"))
+            );
+            assert_eq!(
+                block_on(archive.stat("/../README")).unwrap_err(),
+                Error::from(ErrorKind::PermissionDenied)
+            );
+            assert!(block_on(archive.stat("missing")).is_err());
+        });
+    }
+}
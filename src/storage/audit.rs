@@ -0,0 +1,726 @@
+//! Contains the [`Audit`] wrapper, which records every storage operation in an [`AuditTrail`],
+//! and the [`AuditSink`] the records end up in.
+//!
+//! [`Audit`]: struct.Audit.html
+//! [`AuditTrail`]: struct.AuditTrail.html
+//! [`AuditSink`]: trait.AuditSink.html
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use futures::Stream;
+use log::warn;
+
+use super::{Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+// The previous hash of the first record of a trail.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The storage operations that end up in the [`AuditTrail`].
+///
+/// [`AuditTrail`]: struct.AuditTrail.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditOperation {
+    /// Looking up the metadata of a file or directory.
+    Stat,
+    /// Listing a directory.
+    List,
+    /// Downloading a file.
+    Get,
+    /// Downloading part of a file.
+    GetRange,
+    /// Uploading a file.
+    Put,
+    /// Uploading a file that must not exist yet.
+    PutUnique,
+    /// Appending to a file.
+    Append,
+    /// Deleting a file.
+    Del,
+    /// Creating a directory.
+    Mkd,
+    /// Removing a directory.
+    Rmd,
+    /// Renaming a file or directory.
+    Rename,
+    /// Changing the modification time of a file.
+    SetMtime,
+    /// Changing the permissions of a file or directory.
+    Chmod,
+    /// Calculating the checksum of a file.
+    Checksum,
+    /// Handing out a URL to download a file with.
+    Presign,
+}
+
+impl fmt::Display for AuditOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AuditOperation::Stat => "stat",
+            AuditOperation::List => "list",
+            AuditOperation::Get => "get",
+            AuditOperation::GetRange => "get_range",
+            AuditOperation::Put => "put",
+            AuditOperation::PutUnique => "put_unique",
+            AuditOperation::Append => "append",
+            AuditOperation::Del => "del",
+            AuditOperation::Mkd => "mkd",
+            AuditOperation::Rmd => "rmd",
+            AuditOperation::Rename => "rename",
+            AuditOperation::SetMtime => "set_mtime",
+            AuditOperation::Chmod => "chmod",
+            AuditOperation::Checksum => "checksum",
+            AuditOperation::Presign => "presign",
+        })
+    }
+}
+
+/// A storage operation, as recorded in the [`AuditTrail`].
+///
+/// Every record holds the hash of the record before it, and its own hash covers that, so the
+/// records form a chain: changing, removing or inserting a record anywhere breaks every hash
+/// after it, which [`verify`] finds. The `Display` implementation renders the record as the
+/// single line that the hash is calculated over, with the hash at the end.
+///
+/// [`AuditTrail`]: struct.AuditTrail.html
+/// [`verify`]: fn.verify.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    /// The position of the record in the trail, starting at 1.
+    pub sequence: u64,
+    /// When the operation finished.
+    pub time: SystemTime,
+    /// The user the operation was done for, if one logged in.
+    pub username: Option<String>,
+    /// What was done.
+    pub operation: AuditOperation,
+    /// The path of the file or directory, as given to the storage backend.
+    pub path: PathBuf,
+    /// The path a file or directory was renamed to.
+    pub to: Option<PathBuf>,
+    /// The number of bytes that were uploaded or downloaded.
+    pub bytes: Option<u64>,
+    /// How long the operation took. For downloads, that's until the file was read.
+    pub duration: Duration,
+    /// Why the operation failed; `None` if it succeeded.
+    pub error: Option<String>,
+    /// The hash of the record before this one.
+    pub previous: String,
+    /// The SHA-256 hash of this record, as lowercase hexadecimal digits.
+    pub hash: String,
+}
+
+impl AuditRecord {
+    // Everything the hash is calculated over.
+    fn contents(&self) -> String {
+        let mut line = format!(
+            "seq={} time={} user={} op={} path={:?}",
+            self.sequence,
+            chrono::DateTime::<chrono::Utc>::from(self.time)
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            self.username
+                .as_ref()
+                .map_or("-".to_string(), |username| format!("{:?}", username)),
+            self.operation,
+            self.path,
+        );
+        if let Some(to) = &self.to {
+            line.push_str(&format!(" to={:?}", to));
+        }
+        if let Some(bytes) = self.bytes {
+            line.push_str(&format!(" bytes={}", bytes));
+        }
+        line.push_str(&format!(" duration_us={}", self.duration.as_micros()));
+        match &self.error {
+            Some(error) => line.push_str(&format!(" result=error error={:?}", error)),
+            None => line.push_str(" result=ok"),
+        }
+        line.push_str(&format!(" prev={}", self.previous));
+        line
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} hash={}", self.contents(), self.hash)
+    }
+}
+
+fn sha256(data: &str) -> String {
+    let mut hasher = HashAlgorithm::Sha256.hasher();
+    hasher.update(data.as_bytes());
+    hasher.finish()
+}
+
+/// Checks that the given lines, as written by the `Display` implementation of [`AuditRecord`],
+/// form an unbroken chain from the start of an [`AuditTrail`]. Returns the number of records, or
+/// the (1-based) number of the first line that was changed, or doesn't follow the one before it.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::audit::verify;
+///
+/// assert_eq!(verify(Vec::<String>::new()), Ok(0));
+/// assert_eq!(verify(vec!["seq=1 op=del hash=forged"]), Err(1));
+/// ```
+///
+/// [`AuditRecord`]: struct.AuditRecord.html
+/// [`AuditTrail`]: struct.AuditTrail.html
+pub fn verify<I, L>(lines: I) -> Result<u64, u64>
+where
+    I: IntoIterator<Item = L>,
+    L: AsRef<str>,
+{
+    let mut previous = GENESIS.to_string();
+    let mut count = 0;
+    for line in lines {
+        count += 1;
+        let (contents, hash) = match line.as_ref().rsplit_once(" hash=") {
+            Some(split) => split,
+            None => return Err(count),
+        };
+        if !contents.ends_with(&format!(" prev={}", previous)) || sha256(contents) != hash {
+            return Err(count);
+        }
+        previous = hash.to_string();
+    }
+    Ok(count)
+}
+
+/// Receives the records of an [`AuditTrail`], e.g. to append them to a write-once log or ship
+/// them to a SIEM. Records arrive one at a time, in the order of the chain.
+///
+/// The sink is called from the connection's task, so implementations should not block for long.
+///
+/// [`AuditTrail`]: struct.AuditTrail.html
+pub trait AuditSink {
+    /// Called with every record once the operation finished.
+    fn record(&self, record: &AuditRecord);
+}
+
+/// An [`AuditSink`] that writes every record as a line to the given writer, like a file opened
+/// for appending, and flushes it.
+///
+/// [`AuditSink`]: trait.AuditSink.html
+pub struct WriterSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> WriterSink<W> {
+    /// Write the records to the given writer.
+    pub fn new(writer: W) -> Self {
+        WriterSink {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write> AuditSink for WriterSink<W> {
+    fn record(&self, record: &AuditRecord) {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        if let Err(e) = writeln!(writer, "{}", record).and_then(|_| writer.flush()) {
+            warn!(
+                "Failed to write the audit record {}: {}",
+                record.sequence, e
+            );
+        }
+    }
+}
+
+/// The chain of [`AuditRecord`]s that the [`Audit`] storages of all sessions add to, passing
+/// every record on to an [`AuditSink`]. Every trail starts a new chain, so a restart of the
+/// server shows in the log.
+///
+/// [`AuditRecord`]: struct.AuditRecord.html
+/// [`Audit`]: struct.Audit.html
+/// [`AuditSink`]: trait.AuditSink.html
+pub struct AuditTrail {
+    sink: Box<dyn AuditSink + Send + Sync>,
+    // The sequence number and hash of the last record.
+    last: Mutex<(u64, String)>,
+}
+
+impl AuditTrail {
+    /// Start a trail that passes its records on to the given sink.
+    pub fn new(sink: Box<dyn AuditSink + Send + Sync>) -> Self {
+        AuditTrail {
+            sink,
+            last: Mutex::new((0, GENESIS.to_string())),
+        }
+    }
+
+    fn add(&self, entry: Entry, bytes: Option<u64>, error: Option<String>) {
+        let mut last = match self.last.lock() {
+            Ok(last) => last,
+            Err(_) => return,
+        };
+        let mut record = AuditRecord {
+            sequence: last.0 + 1,
+            time: SystemTime::now(),
+            username: entry.username,
+            operation: entry.operation,
+            path: entry.path,
+            to: entry.to,
+            bytes,
+            duration: entry.started.elapsed(),
+            error,
+            previous: last.1.clone(),
+            hash: String::new(),
+        };
+        record.hash = sha256(&record.contents());
+        *last = (record.sequence, record.hash.clone());
+        // Still holding the lock, so the sink gets the records in order.
+        self.sink.record(&record);
+    }
+}
+
+// An operation that's underway.
+struct Entry {
+    trail: Arc<AuditTrail>,
+    username: Option<String>,
+    operation: AuditOperation,
+    path: PathBuf,
+    to: Option<PathBuf>,
+    started: Instant,
+}
+
+impl Entry {
+    fn finish(self, bytes: Option<u64>, error: Option<String>) {
+        let trail = Arc::clone(&self.trail);
+        trail.add(self, bytes, error);
+    }
+}
+
+/// A [`StorageBackend`] wrapper that records every operation on the wrapped backend in an
+/// [`AuditTrail`]: who did what to which path, how many bytes were transferred, how long it took
+/// and whether it worked. Downloads are recorded once the file has been read, or the client
+/// stopped reading it.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::audit::{Audit, AuditTrail, WriterSink};
+/// use firetrap::storage::Filesystem;
+/// use std::sync::Arc;
+///
+/// let log = std::fs::OpenOptions::new()
+///     .create(true)
+///     .append(true)
+///     .open(std::env::temp_dir().join("audit.log"))
+///     .unwrap();
+/// let trail = Arc::new(AuditTrail::new(Box::new(WriterSink::new(log))));
+/// let server = Server::new(Box::new(move || {
+///     Audit::new(Filesystem::new("/srv/ftp"), trail.clone())
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`AuditTrail`]: struct.AuditTrail.html
+pub struct Audit<S> {
+    inner: S,
+    trail: Arc<AuditTrail>,
+    username: Option<String>,
+}
+
+impl<S> Audit<S> {
+    /// Wrap the given backend, adding its operations to the given trail.
+    pub fn new(inner: S, trail: Arc<AuditTrail>) -> Self {
+        Audit {
+            inner,
+            trail,
+            username: None,
+        }
+    }
+
+    fn entry<P: AsRef<Path>>(&self, operation: AuditOperation, path: P) -> Entry {
+        Entry {
+            trail: Arc::clone(&self.trail),
+            username: self.username.clone(),
+            operation,
+            path: path.as_ref().to_path_buf(),
+            to: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+// Records the operation with its result, and the bytes `bytes` makes of it.
+fn recorded<T, E, F>(entry: Entry, result: Result<T, E>, bytes: F) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnOnce(&T) -> Option<u64>,
+{
+    match &result {
+        Ok(item) => entry.finish(bytes(item), None),
+        Err(e) => entry.finish(None, Some(e.to_string())),
+    }
+    result
+}
+
+fn no_bytes<T>(_: &T) -> Option<u64> {
+    None
+}
+
+/// A file downloaded from an [`Audit`] storage, which records the download once it has been read.
+///
+/// [`Audit`]: struct.Audit.html
+pub struct AuditedFile<R> {
+    inner: R,
+    bytes: u64,
+    entry: Option<Entry>,
+}
+
+impl<R> AuditedFile<R> {
+    fn new(inner: R, entry: Entry) -> Self {
+        AuditedFile {
+            inner,
+            bytes: 0,
+            entry: Some(entry),
+        }
+    }
+
+    fn finish(&mut self, error: Option<String>) {
+        if let Some(entry) = self.entry.take() {
+            entry.finish(Some(self.bytes), error);
+        }
+    }
+}
+
+impl<R: Read> Read for AuditedFile<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.finish(None);
+                Ok(0)
+            }
+            Ok(n) => {
+                self.bytes += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::Interrupted {
+                    self.finish(Some(e.to_string()));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<R: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for AuditedFile<R> {}
+
+impl<R> Drop for AuditedFile<R> {
+    fn drop(&mut self) {
+        self.finish(Some("The download ended early".to_string()));
+    }
+}
+
+// A listing, which is recorded once it's complete.
+struct Listing<T> {
+    inner: T,
+    entry: Option<Entry>,
+}
+
+impl<T: Stream> Stream for Listing<T>
+where
+    T::Error: fmt::Display,
+{
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<T::Item>, T::Error> {
+        let result = self.inner.poll();
+        let error = match &result {
+            Ok(futures::Async::Ready(None)) => None,
+            Err(e) => Some(e.to_string()),
+            _ => return result,
+        };
+        if let Some(entry) = self.entry.take() {
+            entry.finish(None, error);
+        }
+        result
+    }
+}
+
+impl<T> Drop for Listing<T> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.finish(None, Some("The listing ended early".to_string()));
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Audit<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: fmt::Display + Send + 'static,
+{
+    type File = AuditedFile<S::File>;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.username = Some(user.username.clone());
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let entry = self.entry(AuditOperation::Stat, &path);
+        recorded(entry, self.inner.stat(path).await, no_bytes)
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let entry = self.entry(AuditOperation::List, &path);
+        Box::new(Listing {
+            inner: self.inner.list(path),
+            entry: Some(entry),
+        })
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let entry = self.entry(AuditOperation::Get, &path);
+        match self.inner.get(path).await {
+            Ok(file) => Ok(AuditedFile::new(file, entry)),
+            Err(e) => {
+                entry.finish(None, Some(e.to_string()));
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        let entry = self.entry(AuditOperation::GetRange, &path);
+        match self.inner.get_range(path, range).await {
+            Ok(file) => Ok(Box::new(AuditedFile::new(file, entry))),
+            Err(e) => {
+                entry.finish(None, Some(e.to_string()));
+                Err(e)
+            }
+        }
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let entry = self.entry(AuditOperation::Put, &path);
+        recorded(entry, self.inner.put(bytes, path).await, |n| Some(*n))
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let entry = self.entry(AuditOperation::PutUnique, &path);
+        recorded(entry, self.inner.put_unique(bytes, path).await, |n| {
+            Some(*n)
+        })
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let entry = self.entry(AuditOperation::Append, &path);
+        recorded(entry, self.inner.append(bytes, path).await, |n| Some(*n))
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        let entry = self.entry(AuditOperation::Presign, &path);
+        recorded(entry, self.inner.presign(path, ttl).await, no_bytes)
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let entry = self.entry(AuditOperation::Del, &path);
+        recorded(entry, self.inner.del(path).await, no_bytes)
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let entry = self.entry(AuditOperation::Mkd, &path);
+        recorded(entry, self.inner.mkd(path).await, no_bytes)
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let entry = self.entry(AuditOperation::Rmd, &path);
+        recorded(entry, self.inner.rmd(path).await, no_bytes)
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let mut entry = self.entry(AuditOperation::Rename, &from);
+        entry.to = Some(to.as_ref().to_path_buf());
+        recorded(entry, self.inner.rename(from, to).await, no_bytes)
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let entry = self.entry(AuditOperation::SetMtime, &path);
+        recorded(entry, self.inner.set_mtime(path, mtime).await, no_bytes)
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        let entry = self.entry(AuditOperation::Chmod, &path);
+        recorded(entry, self.inner.chmod(path, mode).await, no_bytes)
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let entry = self.entry(AuditOperation::Checksum, &path);
+        recorded(
+            entry,
+            self.inner.checksum(path, algorithm, range).await,
+            no_bytes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+    use futures::Future;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[derive(Default)]
+    struct Collected(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for Arc<Collected> {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn records_operations() {
+        let collected = Arc::new(Collected::default());
+        let trail = Arc::new(AuditTrail::new(Box::new(Arc::clone(&collected))));
+        let mut storage = Audit::new(Memory::new(), trail);
+        storage.set_user(&User::new("alice"));
+
+        block_on(storage.put(Cursor::new(b"contents".to_vec()), "file.txt")).unwrap();
+        let mut contents = String::new();
+        block_on(storage.get("file.txt"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(storage.list("/").collect().wait().unwrap().len(), 1);
+        block_on(storage.rename("file.txt", "moved.txt")).unwrap();
+        block_on(storage.del("file.txt")).unwrap_err();
+        // Dropped without reading it
+        block_on(storage.get("moved.txt")).unwrap();
+
+        let records = collected.0.lock().unwrap().clone();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.sequence,
+                    record.operation,
+                    record.path.to_str().unwrap(),
+                    record.bytes,
+                    record.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, AuditOperation::Put, "file.txt", Some(8), false),
+                (2, AuditOperation::Get, "file.txt", Some(8), false),
+                (3, AuditOperation::List, "/", None, false),
+                (4, AuditOperation::Rename, "file.txt", None, false),
+                (5, AuditOperation::Del, "file.txt", None, true),
+                (6, AuditOperation::Get, "moved.txt", Some(0), true),
+            ]
+        );
+        assert_eq!(records[3].to, Some(PathBuf::from("moved.txt")));
+        assert!(records
+            .iter()
+            .all(|record| record.username.as_deref() == Some("alice")));
+        assert_eq!(records[1].previous, records[0].hash);
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let trail = AuditTrail::new(Box::new(WriterSink::new(Shared(Arc::clone(&log)))));
+        let storage = Audit::new(Memory::new(), Arc::new(trail));
+        block_on(storage.mkd("dir")).unwrap();
+        block_on(storage.put(Cursor::new(b"secret".to_vec()), "dir/file\nseq=99")).unwrap();
+        block_on(storage.del("dir/file\nseq=99")).unwrap();
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        // The newline in the name doesn't make it look like another record.
+        assert_eq!(lines.len(), 3);
+        assert_eq!(verify(&lines), Ok(3));
+
+        let changed = lines[1].replace("bytes=6", "bytes=0");
+        assert_eq!(verify(vec![lines[0], &changed, lines[2]]), Err(2));
+        assert_eq!(verify(vec![lines[0], lines[2]]), Err(2));
+        assert_eq!(verify(&lines[1..]), Err(1));
+    }
+}
@@ -0,0 +1,1190 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use futures::{future, stream, Future, Stream};
+use futures03::compat::Future01CompatExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use log::warn;
+use serde::Deserialize;
+use tokio::prelude::AsyncRead;
+
+use super::cloud::{uri_encode, Chain, Parts};
+pub use super::cloud::{Object, ObjectMetadata};
+use super::prefixes::{Listed, Marker, Namespace, Page};
+use super::{
+    Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, MultipartStrategy, StorageBackend,
+};
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+// The parts of a large file must be at least 5MB, except for the last one, and at most 5GB.
+const MIN_PART_SIZE: u64 = 5_000_000;
+const MAX_PART_SIZE: u64 = 5_000_000_000;
+// A large file has at most 10000 parts.
+const MAX_PARTS: u32 = 10_000;
+// Files up to this size are copied in one request, bigger ones part by part.
+const MAX_COPY_SIZE: u64 = 5_000_000_000;
+// The file that the B2 web UI puts in the folders it creates, to keep them around when they're
+// empty.
+const FOLDER_MARKER: Marker = Marker::File(".bzEmpty");
+// Authorization tokens are good for a day.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(23 * 60 * 60);
+// Download authorizations are good for a week at most.
+const MAX_PRESIGN_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Clone)]
+struct Config {
+    // The scheme and authority of the API to authorize with, like `https://api.backblazeb2.com`.
+    api_url: String,
+    key_id: String,
+    application_key: String,
+    bucket: String,
+    namespace: Namespace,
+    parts: MultipartStrategy,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Leave the application key out of the logs.
+        f.debug_struct("Config")
+            .field("api_url", &self.api_url)
+            .field("key_id", &self.key_id)
+            .field("bucket", &self.bucket)
+            .field("namespace", &self.namespace)
+            .field("parts", &self.parts)
+            .finish()
+    }
+}
+
+// What `b2_authorize_account` tells about the account, and when its token expires.
+#[derive(Clone, Debug)]
+struct Account {
+    token: String,
+    api_url: String,
+    download_url: String,
+    bucket_id: String,
+    expires: Instant,
+}
+
+/// A [`StorageBackend`] that stores files in a Backblaze B2 bucket, through the native B2 API,
+/// with an application key.
+///
+/// Directories map to name prefixes: `/backups/2019.tar` is stored as the file
+/// `backups/2019.tar`. `MKD` creates an empty `.bzEmpty` file in the directory, like the B2 web
+/// UI does, but directories also exist as long as there are files in them. Files that fit in
+/// the first part of the [`MultipartStrategy`] are uploaded in one go, bigger ones as a large
+/// file, part by part while the client is still uploading.
+///
+/// B2 keeps the versions of a file: uploads add a new version, and `DELE` hides the file rather
+/// than deleting it, so the lifecycle rules of the bucket decide how long the old versions are
+/// kept. B2 can't append to a file or change its modification time, so `APPE` downloads the file
+/// and uploads it again with the new bytes after it, and `MFMT` fails. Only files can be renamed
+/// (by copying and then hiding them). `STOU` checks that the name is free before it uploads, but
+/// B2 can't make sure that nobody else takes it in the meantime. File modes are ignored.
+///
+/// Every backend has its own HTTP client with its own connection pool and authorization token,
+/// so create it once and hand every session a clone.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::b2::B2StorageBackend;
+///
+/// let b2 = B2StorageBackend::new("backup-box", "0012ab34cd56ef0000000001", "K001secret")
+///     .root("incoming");
+/// let server = Server::new(Box::new(move || b2.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`MultipartStrategy`]: ../struct.MultipartStrategy.html
+#[derive(Clone, Debug)]
+pub struct B2StorageBackend {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<Config>,
+    account: Arc<Mutex<Option<Account>>>,
+    // Set by `transfer_id`: sent along with the requests, from the start of a transfer until the
+    // start of the next one.
+    transfer_id: Arc<Mutex<Option<String>>>,
+}
+
+impl B2StorageBackend {
+    /// Create a backend for the given bucket, authorized with the application key with the
+    /// given ID.
+    pub fn new<B, I, K>(bucket: B, key_id: I, application_key: K) -> Self
+    where
+        B: Into<String>,
+        I: Into<String>,
+        K: Into<String>,
+    {
+        B2StorageBackend {
+            client: Client::builder().build(HttpsConnector::new(4)),
+            config: Arc::new(Config {
+                api_url: "https://api.backblazeb2.com".to_string(),
+                key_id: key_id.into(),
+                application_key: application_key.into(),
+                bucket: bucket.into(),
+                namespace: Namespace::new(FOLDER_MARKER),
+                parts: MultipartStrategy {
+                    max_part_size: MAX_PART_SIZE,
+                    ..MultipartStrategy::default()
+                },
+            }),
+            account: Arc::new(Mutex::new(None)),
+            transfer_id: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Authorize with the given API instead of the one of Backblaze, like
+    /// `http://localhost:8080` for a local test server. The URLs of the rest of the API come
+    /// from there.
+    pub fn endpoint<E: Into<String>>(mut self, endpoint: E) -> Self {
+        let endpoint = endpoint.into();
+        Arc::make_mut(&mut self.config).api_url = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Serve the files under the given name prefix, instead of the whole bucket. The prefix is a
+    /// directory: with `incoming`, the file `/a.txt` is stored as `incoming/a.txt`.
+    pub fn root<P: AsRef<str>>(mut self, prefix: P) -> Self {
+        Arc::make_mut(&mut self.config)
+            .namespace
+            .set_root(prefix.as_ref());
+        self
+    }
+
+    /// Split large files into parts according to the given strategy, instead of parts of 5MiB
+    /// that double every 1000 parts. The part sizes are kept between the 5MB and 5GB that B2
+    /// allows, and the number of parts at 10000 at most.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use firetrap::storage::MultipartStrategy;
+    /// use firetrap::storage::b2::B2StorageBackend;
+    ///
+    /// let b2 = B2StorageBackend::new("backup-box", "0012ab34cd56ef0000000001", "K001secret")
+    ///     .parts(MultipartStrategy {
+    ///         initial_part_size: 100_000_000,
+    ///         concurrency: 2,
+    ///         ..MultipartStrategy::default()
+    ///     });
+    /// ```
+    pub fn parts(mut self, strategy: MultipartStrategy) -> Self {
+        let clamp = |size: u64| size.clamp(MIN_PART_SIZE, MAX_PART_SIZE);
+        Arc::make_mut(&mut self.config).parts = MultipartStrategy {
+            initial_part_size: clamp(strategy.initial_part_size),
+            max_part_size: clamp(strategy.max_part_size),
+            max_parts: strategy.max_parts.min(MAX_PARTS),
+            ..strategy
+        };
+        self
+    }
+
+    // The name of the file at the given path, without a trailing slash.
+    fn name<P: AsRef<Path>>(&self, path: P) -> String {
+        self.config.namespace.name(path)
+    }
+
+    // The prefix of the names in the directory at the given path.
+    fn prefix<P: AsRef<Path>>(&self, path: P) -> String {
+        self.config.namespace.prefix(path)
+    }
+
+    // The URL to download the file with the given name from.
+    fn download_url(&self, account: &Account, name: &str) -> String {
+        format!(
+            "{}/file/{}/{}",
+            account.download_url,
+            uri_encode(&self.config.bucket, true),
+            uri_encode(name, false)
+        )
+    }
+
+    // Sends the given request, which is authorized already.
+    fn send(&self, request: B2Request) -> BoxFuture<Response<Body>> {
+        let mut builder = Request::builder();
+        builder.method(request.method).uri(request.uri.as_str());
+        for (name, value) in &request.headers {
+            builder.header(name.as_str(), value.as_str());
+        }
+        let agent = format!("firetrap/{}", env!("CARGO_PKG_VERSION"));
+        match &*self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(id) => builder.header(
+                hyper::header::USER_AGENT,
+                format!("{} transfer/{}", agent, id),
+            ),
+            None => builder.header(hyper::header::USER_AGENT, agent),
+        };
+        let http_request = match builder.body(Body::from(request.body)) {
+            Ok(http_request) => http_request,
+            Err(e) => {
+                warn!("Invalid B2 request: {}", e);
+                return Box::new(future::err(Error::backend(e)));
+            }
+        };
+        let account = Arc::clone(&self.account);
+        Box::new(
+            self.client
+                .request(http_request)
+                .map_err(|e| {
+                    warn!("B2 request failed: {}", e);
+                    Error::backend(e)
+                })
+                .map(move |response| {
+                    // The token may have expired: get a new one for the next request.
+                    if response.status() == StatusCode::UNAUTHORIZED {
+                        *account.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                    }
+                    response
+                }),
+        )
+    }
+
+    // Resolves to the account, authorizing with the application key when the last token is
+    // about to expire.
+    fn authorize(&self) -> BoxFuture<Account> {
+        if let Some(account) = &*self.account.lock().unwrap_or_else(|e| e.into_inner()) {
+            if account.expires > Instant::now() {
+                return Box::new(future::ok(account.clone()));
+            }
+        }
+        let config = &self.config;
+        let credentials = base64::encode(format!("{}:{}", config.key_id, config.application_key));
+        let mut request = B2Request::new(
+            Method::GET,
+            format!("{}/b2api/v2/b2_authorize_account", config.api_url),
+        );
+        request.headers.push((
+            hyper::header::AUTHORIZATION.to_string(),
+            format!("Basic {}", credentials),
+        ));
+        let backend = self.clone();
+        Box::new(
+            self.send(request)
+                .and_then(success)
+                .and_then(json::<AuthorizeResponse>)
+                .and_then(move |mut response| {
+                    let expires = Instant::now() + TOKEN_LIFETIME;
+                    let bucket = backend.config.bucket.clone();
+                    // Keys that are restricted to a bucket can't list the buckets.
+                    let bucket_id: BoxFuture<String> = match std::mem::take(&mut response.allowed) {
+                        Allowed {
+                            bucket_id: Some(id),
+                            bucket_name: Some(name),
+                        } if name == bucket => Box::new(future::ok(id)),
+                        _ => {
+                            let mut request = B2Request::new(
+                                Method::POST,
+                                format!("{}/b2api/v2/b2_list_buckets", response.api_url),
+                            );
+                            request.headers.push((
+                                hyper::header::AUTHORIZATION.to_string(),
+                                response.authorization_token.clone(),
+                            ));
+                            request.body = serde_json::json!({
+                                "accountId": response.account_id,
+                                "bucketName": bucket,
+                            })
+                            .to_string()
+                            .into_bytes();
+                            Box::new(
+                                backend
+                                    .send(request)
+                                    .and_then(success)
+                                    .and_then(json::<BucketList>)
+                                    .and_then(move |list| match list.buckets.into_iter().next() {
+                                        Some(found) => Ok(found.bucket_id),
+                                        None => {
+                                            warn!("There's no B2 bucket named {}", bucket);
+                                            Err(Error::backend(format!(
+                                                "There's no B2 bucket named {}",
+                                                bucket
+                                            )))
+                                        }
+                                    }),
+                            )
+                        }
+                    };
+                    let cache = Arc::clone(&backend.account);
+                    bucket_id.map(move |bucket_id| {
+                        let account = Account {
+                            token: response.authorization_token,
+                            api_url: response.api_url,
+                            download_url: response.download_url,
+                            bucket_id,
+                            expires,
+                        };
+                        *cache.lock().unwrap_or_else(|e| e.into_inner()) = Some(account.clone());
+                        account
+                    })
+                }),
+        )
+    }
+
+    // Calls the given operation of the API, with the JSON body made from the account.
+    fn call<T, F>(&self, operation: &'static str, body: F) -> BoxFuture<T>
+    where
+        T: for<'de> Deserialize<'de> + Send + 'static,
+        F: FnOnce(&Account) -> serde_json::Value + Send + 'static,
+    {
+        let backend = self.clone();
+        Box::new(self.authorize().and_then(move |account| {
+            let mut request = B2Request::new(
+                Method::POST,
+                format!("{}/b2api/v2/{}", account.api_url, operation),
+            );
+            request.body = body(&account).to_string().into_bytes();
+            request
+                .headers
+                .push((hyper::header::AUTHORIZATION.to_string(), account.token));
+            backend.send(request).and_then(success).and_then(json)
+        }))
+    }
+
+    // Resolves to one page of the file names under the given prefix, from the given name on.
+    // With a delimiter the names in directories are rolled up into one entry per directory.
+    fn list_page(
+        &self,
+        prefix: String,
+        start: Option<String>,
+        delimiter: bool,
+        max: u32,
+    ) -> BoxFuture<FileList> {
+        self.call("b2_list_file_names", move |account| {
+            let mut body = serde_json::json!({
+                "bucketId": account.bucket_id,
+                "prefix": prefix,
+                "maxFileCount": max,
+            });
+            if let Some(start) = start {
+                body["startFileName"] = start.into();
+            }
+            if delimiter {
+                body["delimiter"] = "/".into();
+            }
+            body
+        })
+    }
+
+    // Resolves to the newest version of the file with the given name, unless it's hidden.
+    fn find(&self, name: String) -> BoxFuture<Option<FileVersion>> {
+        Box::new(
+            self.list_page(name.clone(), Some(name.clone()), false, 1)
+                .map(move |page| {
+                    page.files
+                        .into_iter()
+                        .find(|file| file.file_name == name && file.action == "upload")
+                }),
+        )
+    }
+
+    // Resolves to the contents of the file with the given name, or `None` if there's no such
+    // file.
+    fn download(
+        &self,
+        name: String,
+        range: Option<std::ops::Range<u64>>,
+    ) -> BoxFuture<Option<Object>> {
+        if let Some(range) = &range {
+            if range.start >= range.end {
+                return Box::new(future::ok(Some(Object::new(Body::empty()))));
+            }
+        }
+        let backend = self.clone();
+        Box::new(self.authorize().and_then(move |account| {
+            let mut request = B2Request::new(Method::GET, backend.download_url(&account, &name));
+            request
+                .headers
+                .push((hyper::header::AUTHORIZATION.to_string(), account.token));
+            if let Some(range) = &range {
+                request.headers.push((
+                    hyper::header::RANGE.to_string(),
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                ));
+            }
+            backend
+                .send(request)
+                .and_then(move |response| -> BoxFuture<Option<Object>> {
+                    match response.status() {
+                        StatusCode::NOT_FOUND => Box::new(future::ok(None)),
+                        // The range starts after the end of the file.
+                        StatusCode::RANGE_NOT_SATISFIABLE if range.is_some() => {
+                            Box::new(future::ok(Some(Object::new(Body::empty()))))
+                        }
+                        _ => Box::new(
+                            success(response)
+                                .map(|response| Some(Object::new(response.into_body()))),
+                        ),
+                    }
+                })
+        }))
+    }
+
+    fn get_object(&self, name: String, range: Option<std::ops::Range<u64>>) -> BoxFuture<Object> {
+        Box::new(
+            self.download(name, range)
+                .and_then(|object| object.ok_or(Error::from(ErrorKind::NotFound))),
+        )
+    }
+
+    // Uploads the bytes to the file with the given name, in one go if they fit in one part, or as
+    // a large file otherwise.
+    fn upload<R: AsyncRead + Send + 'static>(&self, bytes: R, name: String) -> BoxFuture<u64> {
+        let backend = self.clone();
+        let parts = Parts::new(bytes, self.config.parts);
+        Box::new(
+            parts
+                .into_future()
+                .map_err(|(e, _)| Error::from(e))
+                .and_then(move |(first, parts)| -> BoxFuture<u64> {
+                    match first {
+                        // Small enough for a single request
+                        Some((_, body)) if parts.done => backend.upload_file(name, body),
+                        None => backend.upload_file(name, vec![]),
+                        Some(first) => backend.upload_large_file(name, first, parts),
+                    }
+                }),
+        )
+    }
+
+    fn upload_file(&self, name: String, body: Vec<u8>) -> BoxFuture<u64> {
+        let backend = self.clone();
+        let len = body.len() as u64;
+        Box::new(
+            self.call(
+                "b2_get_upload_url",
+                |account| serde_json::json!({ "bucketId": account.bucket_id }),
+            )
+            .and_then(move |url: UploadUrl| {
+                let mut request = B2Request::new(Method::POST, url.upload_url);
+                request.headers.extend(vec![
+                    (
+                        hyper::header::AUTHORIZATION.to_string(),
+                        url.authorization_token,
+                    ),
+                    ("X-Bz-File-Name".to_string(), uri_encode(&name, false)),
+                    (
+                        hyper::header::CONTENT_TYPE.to_string(),
+                        "b2/x-auto".to_string(),
+                    ),
+                    ("X-Bz-Content-Sha1".to_string(), sha1(&body)),
+                ]);
+                request.body = body;
+                backend.send(request).and_then(success).map(move |_| len)
+            }),
+        )
+    }
+
+    fn upload_large_file<R: AsyncRead + Send + 'static>(
+        &self,
+        name: String,
+        first: (u32, Vec<u8>),
+        parts: Parts<R>,
+    ) -> BoxFuture<u64> {
+        let backend = self.clone();
+        let concurrency = self.config.parts.concurrency.max(1);
+        Box::new(
+            self.call("b2_start_large_file", move |account| {
+                serde_json::json!({
+                    "bucketId": account.bucket_id,
+                    "fileName": name,
+                    "contentType": "b2/x-auto",
+                })
+            })
+            .and_then(move |large: LargeFile| {
+                let uploader = backend.clone();
+                let canceller = backend.clone();
+                let file_id = large.file_id;
+                let (part_file_id, cancelled_file_id) = (file_id.clone(), file_id.clone());
+                stream::once(Ok(first))
+                    .chain(parts)
+                    .map_err(Error::from)
+                    .map(move |(number, body)| uploader.upload_part(&part_file_id, number, body))
+                    .buffered(concurrency)
+                    .collect()
+                    .and_then(move |parts: Vec<(String, u64)>| {
+                        let total = parts.iter().map(|(_, len)| len).sum::<u64>();
+                        let sha1s: Vec<String> = parts.into_iter().map(|(sha1, _)| sha1).collect();
+                        backend
+                            .call("b2_finish_large_file", move |_| {
+                                serde_json::json!({ "fileId": file_id, "partSha1Array": sha1s })
+                            })
+                            .map(move |_: serde_json::Value| total)
+                    })
+                    .or_else(move |e| {
+                        // Don't leave the parts lying around, they're billed for.
+                        canceller
+                            .call(
+                                "b2_cancel_large_file",
+                                move |_| serde_json::json!({ "fileId": cancelled_file_id }),
+                            )
+                            .then(move |_: Result<serde_json::Value, Error>| Err(e))
+                    })
+            }),
+        )
+    }
+
+    // Resolves to the SHA-1 and size of the uploaded part.
+    fn upload_part(&self, file_id: &str, number: u32, body: Vec<u8>) -> BoxFuture<(String, u64)> {
+        let backend = self.clone();
+        let file_id = file_id.to_string();
+        Box::new(
+            self.call(
+                "b2_get_upload_part_url",
+                move |_| serde_json::json!({ "fileId": file_id }),
+            )
+            .and_then(move |url: UploadUrl| {
+                let (sha1, len) = (sha1(&body), body.len() as u64);
+                let mut request = B2Request::new(Method::POST, url.upload_url);
+                request.headers.extend(vec![
+                    (
+                        hyper::header::AUTHORIZATION.to_string(),
+                        url.authorization_token,
+                    ),
+                    ("X-Bz-Part-Number".to_string(), number.to_string()),
+                    ("X-Bz-Content-Sha1".to_string(), sha1.clone()),
+                ]);
+                request.body = body;
+                backend
+                    .send(request)
+                    .and_then(success)
+                    .map(move |_| (sha1, len))
+            }),
+        )
+    }
+
+    // Hides the file with the given name, so it's gone but for its old versions.
+    fn hide(&self, name: String) -> BoxFuture<()> {
+        Box::new(
+            self.call("b2_hide_file", move |account| {
+                serde_json::json!({ "bucketId": account.bucket_id, "fileName": name })
+            })
+            .map(|_: serde_json::Value| ()),
+        )
+    }
+
+    // Copies the given file to the given name, part by part if it's too big for one request.
+    fn copy(&self, from: FileVersion, to: String) -> BoxFuture<()> {
+        let source = from.file_id.unwrap_or_default();
+        if from.content_length <= MAX_COPY_SIZE {
+            return Box::new(
+                self.call(
+                    "b2_copy_file",
+                    move |_| serde_json::json!({ "sourceFileId": source, "fileName": to }),
+                )
+                .map(|_: serde_json::Value| ()),
+            );
+        }
+        let backend = self.clone();
+        let len = from.content_length;
+        Box::new(
+            self.call("b2_start_large_file", move |account| {
+                serde_json::json!({
+                    "bucketId": account.bucket_id,
+                    "fileName": to,
+                    "contentType": "b2/x-auto",
+                })
+            })
+            .and_then(move |large: LargeFile| {
+                let copier = backend.clone();
+                let file_id = large.file_id;
+                let copied_file_id = file_id.clone();
+                let ranges: Vec<(u64, u64)> = (0..len)
+                    .step_by(MAX_COPY_SIZE as usize)
+                    .map(|start| (start, (start + MAX_COPY_SIZE).min(len) - 1))
+                    .collect();
+                stream::iter_ok(ranges.into_iter().enumerate())
+                    .and_then(move |(i, (start, end))| {
+                        let (source, file_id) = (source.clone(), copied_file_id.clone());
+                        copier
+                            .call("b2_copy_part", move |_| {
+                                serde_json::json!({
+                                    "sourceFileId": source,
+                                    "largeFileId": file_id,
+                                    "partNumber": i + 1,
+                                    "range": format!("bytes={}-{}", start, end),
+                                })
+                            })
+                            .map(|part: CopiedPart| part.content_sha1)
+                    })
+                    .collect()
+                    .and_then(move |sha1s| {
+                        backend
+                            .call("b2_finish_large_file", move |_| {
+                                serde_json::json!({ "fileId": file_id, "partSha1Array": sha1s })
+                            })
+                            .map(|_: serde_json::Value| ())
+                    })
+            }),
+        )
+    }
+}
+
+#[async_trait]
+impl StorageBackend for B2StorageBackend {
+    type File = Object;
+    type Metadata = ObjectMetadata;
+    type Error = Error;
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let name = self.name(&path);
+        if name == self.config.namespace.root() {
+            return Ok(ObjectMetadata::dir(SystemTime::UNIX_EPOCH));
+        }
+        let prefix = self.prefix(&path);
+        if let Some(file) = self.find(name).compat().await? {
+            return Ok(file.metadata());
+        }
+        let page = self
+            .list_page(prefix.clone(), None, false, 1)
+            .compat()
+            .await?;
+        let namespace = &self.config.namespace;
+        namespace
+            .stat_dir(&prefix, &page.into_page())
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let backend = self.clone();
+        let prefix = self.prefix(path);
+        let config = Arc::clone(&self.config);
+        // `None` once the last page is in.
+        let pages = stream::unfold(Some(None), move |start: Option<Option<String>>| {
+            let start = start?;
+            Some(
+                backend
+                    .list_page(prefix.clone(), start, true, 1000)
+                    .map(|mut page| {
+                        let next = page.next_file_name.take();
+                        (page, next.map(Some))
+                    }),
+            )
+        });
+        Box::new(
+            pages
+                .map(move |page| stream::iter_ok(config.namespace.fileinfos(page.into_page())))
+                .flatten(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.get_object(self.name(path), None).compat().await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn AsyncRead + Send>, Self::Error> {
+        let object = self
+            .get_object(self.name(path), Some(range))
+            .compat()
+            .await?;
+        Ok(Box::new(object))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, self.name(path)).compat().await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let name = self.name(path);
+        if self.find(name.clone()).compat().await?.is_some() {
+            return Err(Error::from(ErrorKind::Exists));
+        }
+        self.upload(bytes, name).compat().await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let name = self.name(path);
+        // Appending to nothing creates the file.
+        let existing = self.download(name.clone(), None).compat().await?;
+        let chained = Chain {
+            first: existing,
+            second: bytes,
+        };
+        self.upload(chained, name).compat().await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        let name = self.name(path);
+        let seconds = ttl.min(MAX_PRESIGN_TTL).as_secs().max(1);
+        // Make sure there's something to download at all.
+        if self.find(name.clone()).compat().await?.is_none() {
+            return Ok(None);
+        }
+        let prefix = name.clone();
+        let authorization: DownloadAuthorization = self
+            .call("b2_get_download_authorization", move |account| {
+                serde_json::json!({
+                    "bucketId": account.bucket_id,
+                    "fileNamePrefix": prefix,
+                    "validDurationInSeconds": seconds,
+                })
+            })
+            .compat()
+            .await?;
+        let account = self.authorize().compat().await?;
+        Ok(Some(format!(
+            "{}?Authorization={}",
+            self.download_url(&account, &name),
+            uri_encode(&authorization.authorization_token, true)
+        )))
+    }
+
+    fn transfer_id(&self, id: &str) {
+        *self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(id.to_string());
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.hide(self.name(path)).compat().await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let marker = self.config.namespace.marker(&self.prefix(path));
+        self.upload_file(marker, vec![]).compat().await?;
+        Ok(())
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let prefix = self.prefix(path);
+        if prefix == self.config.namespace.root() {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        let page = self
+            .list_page(prefix.clone(), None, false, 2)
+            .compat()
+            .await?;
+        let marker = self
+            .config
+            .namespace
+            .removable(&prefix, &page.into_page())?;
+        self.hide(marker).compat().await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let (from, to) = (self.name(from), self.name(to));
+        let file = self
+            .find(from.clone())
+            .compat()
+            .await?
+            .ok_or(Error::from(ErrorKind::NotFound))?;
+        self.copy(file, to).compat().await?;
+        self.hide(from).compat().await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+        _mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        // The modification time of a file is when it was uploaded.
+        Err(Error::backend("B2 doesn't change modification times"))
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, _path: P, _mode: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let name = self.name(path);
+        // B2 knows the SHA-1 of the files that were uploaded in one go.
+        if let (HashAlgorithm::Sha1, None) = (algorithm, &range) {
+            let found = self.find(name.clone()).compat().await?;
+            if let Some(sha1) = found.and_then(|file| file.sha1()) {
+                return Ok(sha1);
+            }
+        }
+        let object = self.get_object(name, range).compat().await?;
+        let hasher = object
+            .body
+            .fold(algorithm.hasher(), |mut hasher, chunk| {
+                hasher.update(&chunk);
+                Ok::<_, hyper::Error>(hasher)
+            })
+            .compat()
+            .await
+            .map_err(|e| {
+                warn!("Failed to read the file from B2: {}", e);
+                Error::backend(e)
+            })?;
+        Ok(hasher.finish())
+    }
+}
+
+// A request to the API.
+struct B2Request {
+    method: Method,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl B2Request {
+    fn new(method: Method, uri: String) -> Self {
+        B2Request {
+            method,
+            uri,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+}
+
+fn sha1(data: &[u8]) -> String {
+    let mut hasher = HashAlgorithm::Sha1.hasher();
+    hasher.update(data);
+    hasher.finish()
+}
+
+// The error that B2 replied with.
+fn error(status: StatusCode, response: &ErrorResponse) -> Error {
+    let kind = match (status, response.code.as_str()) {
+        (StatusCode::FORBIDDEN, "storage_cap_exceeded") => ErrorKind::StorageFull,
+        (StatusCode::UNAUTHORIZED, "unauthorized") => ErrorKind::PermissionDenied,
+        (StatusCode::NOT_FOUND, _) => ErrorKind::NotFound,
+        _ => ErrorKind::Backend,
+    };
+    Error::new(
+        kind,
+        format!("B2 replied with {}: {}", status, response.code),
+    )
+}
+
+// Resolves to the response if it has a success code, or logs the error B2 replied with.
+fn success(response: Response<Body>) -> BoxFuture<Response<Body>> {
+    if response.status().is_success() {
+        return Box::new(future::ok(response));
+    }
+    let status = response.status();
+    Box::new(response.into_body().concat2().then(move |body| {
+        let response = body
+            .ok()
+            .and_then(|body| serde_json::from_slice::<ErrorResponse>(&body).ok())
+            .unwrap_or_default();
+        warn!(
+            "B2 replied with {}: {} ({})",
+            status, response.message, response.code
+        );
+        Err(error(status, &response))
+    }))
+}
+
+// Resolves to the JSON body of the response.
+fn json<T>(response: Response<Body>) -> BoxFuture<T>
+where
+    T: for<'de> Deserialize<'de> + Send + 'static,
+{
+    Box::new(
+        response
+            .into_body()
+            .concat2()
+            .map_err(|e| {
+                warn!("Failed to read the B2 reply: {}", e);
+                Error::backend(e)
+            })
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|e| {
+                    warn!("Invalid B2 reply: {}", e);
+                    Error::backend(e)
+                })
+            }),
+    )
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ErrorResponse {
+    code: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeResponse {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+    #[serde(default)]
+    allowed: Allowed,
+}
+
+// What the application key may touch.
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Allowed {
+    bucket_id: Option<String>,
+    bucket_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BucketList {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LargeFile {
+    file_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CopiedPart {
+    content_sha1: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadAuthorization {
+    authorization_token: String,
+}
+
+// A version of a file, or a directory, as `b2_list_file_names` describes it.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct FileVersion {
+    file_name: String,
+    // Directories have none.
+    #[serde(default)]
+    file_id: Option<String>,
+    // `upload` for files, `folder` for directories.
+    action: String,
+    #[serde(default)]
+    content_length: u64,
+    #[serde(default)]
+    content_sha1: Option<String>,
+    // In milliseconds since the epoch.
+    #[serde(default)]
+    upload_timestamp: u64,
+}
+
+impl FileVersion {
+    fn modified(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.upload_timestamp)
+    }
+
+    fn metadata(&self) -> ObjectMetadata {
+        ObjectMetadata {
+            len: self.content_length,
+            dir: false,
+            modified: self.modified(),
+            // Every upload makes a new version, with a new ID.
+            etag: self.file_id.clone(),
+        }
+    }
+
+    // The SHA-1 of the contents, if B2 knows it: large files have none.
+    fn sha1(&self) -> Option<String> {
+        self.content_sha1
+            .as_ref()
+            .map(|sha1| sha1.trim_start_matches("unverified:").to_string())
+            .filter(|sha1| sha1.len() == 40)
+    }
+}
+
+// A page of the listing of a prefix.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+struct FileList {
+    files: Vec<FileVersion>,
+    // Where the next page starts, if there is one.
+    next_file_name: Option<String>,
+}
+
+impl FileList {
+    // The page, with the versions of files as objects and the folders as prefixes.
+    fn into_page(self) -> Page {
+        let mut page = Page::default();
+        for file in self.files {
+            match file.action.as_str() {
+                "folder" => page.prefixes.push(file.file_name),
+                "upload" => page.objects.push(Listed {
+                    metadata: file.metadata(),
+                    name: file.file_name,
+                }),
+                _ => {}
+            }
+        }
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    fn account() -> Account {
+        Account {
+            token: "token".to_string(),
+            api_url: "https://api001.backblazeb2.com".to_string(),
+            download_url: "https://f001.backblazeb2.com".to_string(),
+            bucket_id: "4a48fe8875c6214145260818".to_string(),
+            expires: Instant::now() + TOKEN_LIFETIME,
+        }
+    }
+
+    #[test]
+    fn maps_paths_to_names() {
+        let b2 = B2StorageBackend::new("backup box", "id", "K001secret");
+        assert_eq!(b2.name("/"), "");
+        assert_eq!(b2.name("/backups/2019.tar"), "backups/2019.tar");
+        assert_eq!(b2.prefix("/backups"), "backups/");
+
+        let b2 = b2.root("/incoming/").endpoint("http://localhost:8080/");
+        assert_eq!(b2.config.api_url, "http://localhost:8080");
+        assert_eq!(b2.name("/../../a.txt"), "incoming/a.txt");
+        assert_eq!(b2.prefix("/"), "incoming/");
+        assert_eq!(
+            b2.download_url(&account(), "incoming/caf\u{e9} 1+1.txt"),
+            "https://f001.backblazeb2.com/file/backup%20box/incoming/caf%C3%A9%201%2B1.txt"
+        );
+        // The application key stays out of the logs.
+        assert!(!format!("{:?}", b2).contains("K001secret"));
+    }
+
+    #[test]
+    fn clamps_part_sizes() {
+        let b2 = B2StorageBackend::new("bucket", "id", "key");
+        assert_eq!(b2.config.parts.max_part_size, MAX_PART_SIZE);
+        let b2 = b2.parts(MultipartStrategy {
+            initial_part_size: 1000,
+            max_part_size: u64::MAX,
+            max_parts: u32::MAX,
+            ..MultipartStrategy::default()
+        });
+        assert_eq!(b2.config.parts.initial_part_size, MIN_PART_SIZE);
+        assert_eq!(b2.config.parts.max_part_size, MAX_PART_SIZE);
+        assert_eq!(b2.config.parts.max_parts, MAX_PARTS);
+    }
+
+    #[test]
+    fn parses_listings() {
+        let json = r#"{
+  "files": [
+    {"accountId": "12f634bf3cbe", "action": "upload", "bucketId": "4a48fe8875c6214145260818",
+     "contentLength": 0, "contentSha1": "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+     "contentType": "application/octet-stream",
+     "fileId": "4_z4a48fe8875c6214145260818_f1000000000000001_d20190501_m100000_c001_v0001000_t0001",
+     "fileInfo": {}, "fileName": "incoming/.bzEmpty", "uploadTimestamp": 1556704800000},
+    {"accountId": "12f634bf3cbe", "action": "upload", "bucketId": "4a48fe8875c6214145260818",
+     "contentLength": 43, "contentSha1": "unverified:2aae6c35c94fcfb415dbe95f408b9ce91ee846ed",
+     "contentType": "text/plain",
+     "fileId": "4_z4a48fe8875c6214145260818_f1000000000000002_d20190502_m113000_c001_v0001000_t0002",
+     "fileInfo": {}, "fileName": "incoming/Tom & Jerry.txt", "uploadTimestamp": 1556796600000},
+    {"accountId": "12f634bf3cbe", "action": "folder", "bucketId": "4a48fe8875c6214145260818",
+     "contentLength": 0, "contentSha1": null, "contentType": null, "fileId": null,
+     "fileInfo": {}, "fileName": "incoming/reports/", "uploadTimestamp": 0}
+  ],
+  "nextFileName": "incoming/reports/\u0000"
+}"#;
+        let mut page: FileList = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            page.next_file_name.take().as_deref(),
+            Some("incoming/reports/\u{0}")
+        );
+        assert_eq!(
+            page.files[1].sha1().as_deref(),
+            Some("2aae6c35c94fcfb415dbe95f408b9ce91ee846ed")
+        );
+        assert_eq!(
+            page.files[1].modified(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_556_796_600)
+        );
+
+        let b2 = B2StorageBackend::new("bucket", "id", "key").root("incoming");
+        let fileinfos = b2.config.namespace.fileinfos(page.into_page());
+        let paths: Vec<_> = fileinfos.iter().map(|fileinfo| &fileinfo.path).collect();
+        assert_eq!(
+            paths,
+            vec![&PathBuf::from("reports"), &PathBuf::from("Tom & Jerry.txt")]
+        );
+        assert!(fileinfos[0].metadata.is_dir());
+        assert_eq!(fileinfos[1].metadata.len(), 43);
+        assert!(fileinfos[1].metadata.etag().unwrap().ends_with("_t0002"));
+
+        let large = FileVersion {
+            file_name: "big.tar".to_string(),
+            file_id: None,
+            action: "upload".to_string(),
+            content_length: 1 << 40,
+            content_sha1: Some("none".to_string()),
+            upload_timestamp: 0,
+        };
+        assert_eq!(large.sha1(), None);
+    }
+
+    #[test]
+    fn maps_errors() {
+        let reply = |json: &str| serde_json::from_str::<ErrorResponse>(json).unwrap();
+        let full = reply(
+            r#"{"status": 403, "code": "storage_cap_exceeded", "message": "Cannot upload files, storage cap exceeded."}"#,
+        );
+        assert_eq!(
+            error(StatusCode::FORBIDDEN, &full),
+            Error::from(ErrorKind::StorageFull)
+        );
+        let denied = reply(r#"{"status": 401, "code": "unauthorized", "message": ""}"#);
+        assert_eq!(
+            error(StatusCode::UNAUTHORIZED, &denied),
+            Error::from(ErrorKind::PermissionDenied)
+        );
+        let expired = reply(r#"{"status": 401, "code": "expired_auth_token", "message": ""}"#);
+        assert_eq!(
+            error(StatusCode::UNAUTHORIZED, &expired),
+            Error::backend("B2 replied with 401 Unauthorized: expired_auth_token")
+        );
+    }
+}
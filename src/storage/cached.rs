@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use futures::{stream, Future, Stream};
+
+use super::{Fileinfo, HashAlgorithm, Metadata, Precondition, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+/// The default time [`Cached`] remembers a result.
+///
+/// [`Cached`]: ./struct.Cached.html
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// The default number of results [`Cached`] remembers.
+///
+/// [`Cached`]: ./struct.Cached.html
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
+
+struct Remembered<T> {
+    value: T,
+    expires: Instant,
+    last_used: u64,
+}
+
+struct Entries<M> {
+    stats: HashMap<PathBuf, Remembered<M>>,
+    lists: HashMap<PathBuf, Remembered<Vec<(PathBuf, M)>>>,
+    // Counts the lookups, to find the least recently used entry.
+    clock: u64,
+    // Counts the invalidations, so results that were fetched before one aren't remembered.
+    generation: u64,
+}
+
+// Returns when the least recently used entry of `map` was used, and its path.
+fn oldest<T>(map: &HashMap<PathBuf, Remembered<T>>) -> Option<(u64, PathBuf)> {
+    map.iter()
+        .min_by_key(|(_, remembered)| remembered.last_used)
+        .map(|(path, remembered)| (remembered.last_used, path.clone()))
+}
+
+// Returns what `map` remembers for `path`, unless it expired.
+fn lookup<T: Clone>(
+    map: &mut HashMap<PathBuf, Remembered<T>>,
+    clock: &mut u64,
+    path: &Path,
+) -> Option<T> {
+    match map.get_mut(path) {
+        Some(remembered) if remembered.expires > Instant::now() => {
+            *clock += 1;
+            remembered.last_used = *clock;
+            Some(remembered.value.clone())
+        }
+        Some(_) => {
+            map.remove(path);
+            None
+        }
+        None => None,
+    }
+}
+
+impl<M> Entries<M> {
+    // Makes room for one more entry, dropping the expired ones and then the least recently used.
+    fn evict(&mut self, capacity: usize) {
+        if self.stats.len() + self.lists.len() < capacity {
+            return;
+        }
+        let now = Instant::now();
+        self.stats.retain(|_, remembered| remembered.expires > now);
+        self.lists.retain(|_, remembered| remembered.expires > now);
+        while self.stats.len() + self.lists.len() >= capacity {
+            let stat = oldest(&self.stats);
+            let list = oldest(&self.lists);
+            match (stat, list) {
+                (Some(stat), Some(list)) if stat.0 > list.0 => {
+                    self.lists.remove(&list.1);
+                }
+                (Some(stat), _) => {
+                    self.stats.remove(&stat.1);
+                }
+                (None, Some(list)) => {
+                    self.lists.remove(&list.1);
+                }
+                (None, None) => return,
+            }
+        }
+    }
+
+    // Forgets everything about `path`, everything below it, and the listing of its parent.
+    fn invalidate(&mut self, path: &Path) {
+        self.generation += 1;
+        self.stats.retain(|cached, _| !cached.starts_with(path));
+        self.lists.retain(|cached, _| !cached.starts_with(path));
+        if let Some(parent) = path.parent() {
+            self.stats.remove(parent);
+            self.lists.remove(parent);
+        }
+    }
+}
+
+/// A [`StorageBackend`] wrapper that remembers the results of `stat` and `list` for a while, so
+/// that a chatty client's `SIZE`, `MDTM`, `CWD` and repeated `LIST`s don't cost an API call each
+/// on an object store. Changes made through the wrapper forget what they affect right away;
+/// changes made by other sessions or behind the server's back show after the [`ttl`] at the
+/// latest, [`DEFAULT_CACHE_TTL`] by default. Failures aren't remembered.
+///
+/// Every session gets its own wrapper from the factory, and with it its own cache of (by
+/// default) [`DEFAULT_CACHE_CAPACITY`] results; the least recently used go first.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Cached, Filesystem};
+/// use std::time::Duration;
+///
+/// let server = Server::new(Box::new(|| {
+///     Cached::new(Filesystem::new("/srv/ftp"))
+///         .ttl(Duration::from_secs(30))
+///         .capacity(5000)
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`ttl`]: #method.ttl
+/// [`DEFAULT_CACHE_TTL`]: ./constant.DEFAULT_CACHE_TTL.html
+/// [`DEFAULT_CACHE_CAPACITY`]: ./constant.DEFAULT_CACHE_CAPACITY.html
+pub struct Cached<S: StorageBackend> {
+    inner: S,
+    entries: Arc<Mutex<Entries<S::Metadata>>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl<S: StorageBackend> Cached<S> {
+    /// Wrap the given backend.
+    pub fn new(inner: S) -> Self {
+        Cached {
+            inner,
+            entries: Arc::new(Mutex::new(Entries {
+                stats: HashMap::new(),
+                lists: HashMap::new(),
+                clock: 0,
+                generation: 0,
+            })),
+            ttl: DEFAULT_CACHE_TTL,
+            capacity: DEFAULT_CACHE_CAPACITY,
+        }
+    }
+
+    /// Set how long a result is remembered.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set how many results (of `stat` and `list` together) are remembered.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Entries<S::Metadata>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl<S> Cached<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Clone + Send + 'static,
+    S::Error: Send + 'static,
+{
+    // Passes on `result`, forgetting about `paths` now that the operation is done, whether it
+    // succeeded or not.
+    fn invalidating<T>(
+        &self,
+        paths: Vec<PathBuf>,
+        result: Result<T, S::Error>,
+    ) -> Result<T, S::Error> {
+        let mut entries = self.entries();
+        for path in &paths {
+            entries.invalidate(path);
+        }
+        result
+    }
+
+    // Remembers `value` in the map `pick` returns, unless something was invalidated since
+    // `generation`.
+    fn store<T, F>(
+        entries: &Mutex<Entries<S::Metadata>>,
+        ttl: Duration,
+        capacity: usize,
+        generation: u64,
+        path: PathBuf,
+        value: T,
+        pick: F,
+    ) where
+        F: FnOnce(&mut Entries<S::Metadata>) -> &mut HashMap<PathBuf, Remembered<T>>,
+    {
+        let mut entries = entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if entries.generation != generation || capacity == 0 {
+            return;
+        }
+        entries.evict(capacity);
+        entries.clock += 1;
+        let last_used = entries.clock;
+        pick(&mut entries).insert(
+            path,
+            Remembered {
+                value,
+                expires: Instant::now() + ttl,
+                last_used,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Cached<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Clone + Send + 'static,
+    S::Error: Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        {
+            let mut entries = self.entries();
+            entries.generation += 1;
+            entries.stats.clear();
+            entries.lists.clear();
+        }
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let path = path.as_ref().to_path_buf();
+        let generation = {
+            let mut entries = self.entries();
+            let Entries { stats, clock, .. } = &mut *entries;
+            if let Some(metadata) = lookup(stats, clock, &path) {
+                return Ok(metadata);
+            }
+            entries.generation
+        };
+        let metadata = self.inner.stat(path.clone()).await?;
+        Self::store(
+            &self.entries,
+            self.ttl,
+            self.capacity,
+            generation,
+            path,
+            metadata.clone(),
+            |e| &mut e.stats,
+        );
+        Ok(metadata)
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let path = path.as_ref().to_path_buf();
+        let to_stream = |files: Vec<(PathBuf, S::Metadata)>| {
+            stream::iter_ok(
+                files
+                    .into_iter()
+                    .map(|(path, metadata)| Fileinfo { path, metadata }),
+            )
+        };
+        let generation = {
+            let mut entries = self.entries();
+            let Entries { lists, clock, .. } = &mut *entries;
+            if let Some(files) = lookup(lists, clock, &path) {
+                return Box::new(to_stream(files));
+            }
+            entries.generation
+        };
+        let entries = Arc::clone(&self.entries);
+        let (ttl, capacity) = (self.ttl, self.capacity);
+        Box::new(
+            self.inner
+                .list(path.clone())
+                .map(|file| (file.path, file.metadata))
+                .collect()
+                .map(move |files| {
+                    Self::store(
+                        &entries,
+                        ttl,
+                        capacity,
+                        generation,
+                        path,
+                        files.clone(),
+                        |e| &mut e.lists,
+                    );
+                    to_stream(files)
+                })
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.put(bytes, path).await)
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.put_unique(bytes, path).await)
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.append(bytes, path).await)
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.del(path).await)
+    }
+
+    // Preconditions are checked against the wrapped backend, never against what we remember.
+    async fn del_if(&self, path: PathBuf, precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let deleted = self.inner.del_if(path.clone(), precondition).await;
+        self.invalidating(vec![path], deleted)
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.mkd(path).await)
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.rmd(path).await)
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let removed = self.inner.rmd_recursive(path.clone()).await;
+        self.invalidating(vec![path], removed)
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let paths = vec![from.as_ref().to_path_buf(), to.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.rename(from, to).await)
+    }
+
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let renamed = self
+            .inner
+            .rename_if(from.clone(), to.clone(), precondition)
+            .await;
+        self.invalidating(vec![from, to], renamed)
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.set_mtime(path, mtime).await)
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        let paths = vec![path.as_ref().to_path_buf()];
+        self.invalidating(paths, self.inner.chmod(path, mode).await)
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn put<S: StorageBackend>(storage: &S, path: &str, contents: &str)
+    where
+        S::Error: std::fmt::Debug,
+    {
+        block_on(storage.put(Cursor::new(contents.as_bytes().to_vec()), path)).unwrap();
+    }
+
+    fn len<S: StorageBackend>(storage: &S, path: &str) -> u64
+    where
+        S::Metadata: Metadata,
+        S::Error: std::fmt::Debug,
+    {
+        block_on(storage.stat(path)).unwrap().len()
+    }
+
+    fn names<S>(storage: &S, path: &str) -> Vec<PathBuf>
+    where
+        S: StorageBackend,
+        S::Metadata: Metadata,
+        S::Error: std::fmt::Debug,
+    {
+        let mut names: Vec<_> = storage
+            .list(path)
+            .map(|file| file.path)
+            .collect()
+            .wait()
+            .unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn remembers_until_changed() {
+        let memory = Memory::new();
+        put(&memory, "/a", "one");
+        let cached = Cached::new(memory.clone());
+        assert_eq!(len(&cached, "/a"), 3);
+        assert_eq!(names(&cached, "/").len(), 1);
+
+        // Behind its back.
+        put(&memory, "/a", "three");
+        put(&memory, "/b", "two");
+        assert_eq!(len(&cached, "/a"), 3);
+        assert_eq!(names(&cached, "/").len(), 1);
+
+        put(&cached, "/a", "eleven");
+        assert_eq!(len(&cached, "/a"), 6);
+        assert_eq!(names(&cached, "/").len(), 2);
+        block_on(cached.del("/a")).unwrap();
+        assert!(block_on(cached.stat("/a")).is_err());
+
+        block_on(cached.mkd("/dir")).unwrap();
+        put(&cached, "/dir/c", "c");
+        assert_eq!(names(&cached, "/dir").len(), 1);
+        block_on(cached.rename("/dir", "/moved")).unwrap();
+        assert!(cached.list("/dir").collect().wait().is_err());
+        assert_eq!(names(&cached, "/moved").len(), 1);
+    }
+
+    #[test]
+    fn forgets_after_ttl() {
+        let memory = Memory::new();
+        put(&memory, "/a", "one");
+        let cached = Cached::new(memory.clone()).ttl(Duration::from_millis(20));
+        assert_eq!(len(&cached, "/a"), 3);
+        put(&memory, "/a", "three");
+        assert_eq!(len(&cached, "/a"), 3);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(len(&cached, "/a"), 5);
+    }
+
+    #[test]
+    fn forgets_least_recently_used() {
+        let memory = Memory::new();
+        put(&memory, "/a", "a");
+        put(&memory, "/b", "b");
+        let cached = Cached::new(memory.clone()).capacity(2);
+        assert_eq!(len(&cached, "/a"), 1);
+        assert_eq!(len(&cached, "/b"), 1);
+        assert_eq!(len(&cached, "/a"), 1);
+        assert_eq!(names(&cached, "/").len(), 2);
+
+        put(&memory, "/a", "aa");
+        put(&memory, "/b", "bb");
+        assert_eq!(len(&cached, "/a"), 1);
+        assert_eq!(len(&cached, "/b"), 2);
+    }
+}
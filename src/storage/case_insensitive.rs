@@ -0,0 +1,509 @@
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{Future, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+
+use super::{Fileinfo, HashAlgorithm, ListOptions, Metadata, Precondition, StorageBackend};
+use crate::auth::User;
+use crate::compat::compat;
+use crate::events::NegotiatedOptions;
+
+/// What [`CaseInsensitive`] does with an upload (or `MKD`, or the target of a rename) whose name
+/// only differs in case from an existing entry, like `Readme.txt` when `README.TXT` exists.
+///
+/// [`CaseInsensitive`]: ./struct.CaseInsensitive.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseCollision {
+    /// Write to the existing entry, keeping its name, like a case-insensitive filesystem does.
+    /// This is the default.
+    Overwrite,
+    /// Fail, so the existing entry is left alone.
+    Reject,
+}
+
+/// A [`StorageBackend`] wrapper that matches paths case-insensitively, so that `readme.txt`,
+/// `README.TXT` and `Readme.txt` are the same file, whatever the wrapped backend thinks of it.
+///
+/// Without it, the behavior depends on the backend: on Linux a [`Filesystem`] (like an object
+/// store) is case-sensitive, so uploading `Readme.txt` next to `README.TXT` makes two files,
+/// while on macOS or Windows it overwrites `README.TXT`. With the wrapper, paths that don't exist
+/// as given are looked up component by component in the listings of their parents, and uploads
+/// that would only differ in case from an existing entry are handled according to the
+/// [`CaseCollision`] policy. Names are compared lower-cased.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{CaseCollision, CaseInsensitive, Filesystem};
+///
+/// let server = Server::new(Box::new(|| {
+///     CaseInsensitive::new(Filesystem::new("/srv/ftp")).collisions(CaseCollision::Reject)
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`Filesystem`]: ./struct.Filesystem.html
+/// [`CaseCollision`]: ./enum.CaseCollision.html
+pub struct CaseInsensitive<S> {
+    inner: Arc<S>,
+    collisions: CaseCollision,
+}
+
+impl<S> CaseInsensitive<S> {
+    /// Wrap the given backend, with the [`CaseCollision::Overwrite`] policy.
+    ///
+    /// [`CaseCollision::Overwrite`]: ./enum.CaseCollision.html#variant.Overwrite
+    pub fn new(inner: S) -> Self {
+        CaseInsensitive {
+            inner: Arc::new(inner),
+            collisions: CaseCollision::Overwrite,
+        }
+    }
+
+    /// Set what happens to uploads whose name only differs in case from an existing entry.
+    pub fn collisions(mut self, policy: CaseCollision) -> Self {
+        self.collisions = policy;
+        self
+    }
+}
+
+// Returns the name out of `names` that matches `wanted`: the exact one if it's there, or else the
+// first one (alphabetically, so the choice doesn't depend on the order of the listing) that only
+// differs in case.
+fn matching(names: &[OsString], wanted: &OsString) -> Option<OsString> {
+    if names.contains(wanted) {
+        return Some(wanted.clone());
+    }
+    let wanted = wanted.to_string_lossy().to_lowercase();
+    names
+        .iter()
+        .filter(|name| name.to_string_lossy().to_lowercase() == wanted)
+        .min()
+        .cloned()
+}
+
+impl<S> CaseInsensitive<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    // The names of the entries of the given directory, or none if it can't be listed.
+    async fn names(&self, dir: PathBuf) -> Vec<OsString> {
+        self.inner
+            .list(dir)
+            .filter_map(|entry| entry.path.file_name().map(OsString::from))
+            .collect()
+            .compat()
+            .await
+            .unwrap_or_default()
+    }
+
+    // Resolves to the path of the existing entry that matches `path` case-insensitively.
+    // Components without a match (and everything after them) are kept as they are, so the
+    // operation on the path fails like it would have otherwise.
+    async fn resolve(&self, path: &Path) -> PathBuf {
+        if self.inner.stat(path).await.is_ok() {
+            return path.to_path_buf();
+        }
+        self.walk(path).await
+    }
+
+    // Looks up every component of `path` in the listing of its parent.
+    async fn walk(&self, path: &Path) -> PathBuf {
+        let components: Vec<Component> = path.components().collect();
+        let mut resolved = PathBuf::new();
+        let mut remaining = vec![];
+        for (i, component) in components.iter().enumerate() {
+            match component {
+                Component::Normal(_) => {
+                    remaining = components[i..]
+                        .iter()
+                        .map(|c| c.as_os_str().to_os_string())
+                        .collect();
+                    break;
+                }
+                other => resolved.push(other.as_os_str()),
+            }
+        }
+        remaining.reverse();
+        while let Some(wanted) = remaining.pop() {
+            match matching(&self.names(resolved.clone()).await, &wanted) {
+                Some(name) => resolved.push(name),
+                None => {
+                    resolved.push(wanted);
+                    while let Some(component) = remaining.pop() {
+                        resolved.push(component);
+                    }
+                }
+            }
+        }
+        resolved
+    }
+
+    // Resolves the path to write to for an upload to `path`: its parent is looked up like any
+    // path, while an existing entry that differs only in case is subject to the collision policy.
+    async fn resolve_new(
+        &self,
+        path: &Path,
+        collisions: CaseCollision,
+    ) -> Result<PathBuf, S::Error> {
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => return Ok(self.resolve(path).await),
+        };
+        let parent = self
+            .resolve(path.parent().unwrap_or_else(|| Path::new("")))
+            .await;
+        match matching(&self.names(parent.clone()).await, &name) {
+            Some(existing) if existing != name => match collisions {
+                CaseCollision::Overwrite => Ok(parent.join(existing)),
+                CaseCollision::Reject => Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{:?} exists with a different case", parent.join(existing)),
+                )
+                .into()),
+            },
+            _ => Ok(parent.join(name)),
+        }
+    }
+
+    // A copy that shares the wrapped backend, for use in listings.
+    fn clone_handle(&self) -> Self {
+        CaseInsensitive {
+            inner: Arc::clone(&self.inner),
+            collisions: self.collisions,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for CaseInsensitive<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.set_user(user),
+            None => warn!("Storage backend in use during login, not setting the user"),
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let this = self.clone_handle();
+        let path = path.as_ref().to_path_buf();
+        Box::new(
+            compat(async move {
+                let path = this.resolve(&path).await;
+                Ok(this.inner.list(path))
+            })
+            .flatten_stream(),
+        )
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        let this = self.clone_handle();
+        let path = path.as_ref().to_path_buf();
+        Box::new(
+            compat(async move {
+                let path = this.resolve(&path).await;
+                Ok(this.inner.list_fmt(path, options))
+            })
+            .flatten_stream(),
+        )
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        let this = self.clone_handle();
+        let path = path.as_ref().to_path_buf();
+        Box::new(
+            compat(async move {
+                let path = this.resolve(&path).await;
+                Ok(this.inner.nlst(path))
+            })
+            .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let path = self.resolve_new(path.as_ref(), self.collisions).await?;
+        self.inner.put(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        // A unique name must not exist in any case, whatever the policy.
+        let path = self
+            .resolve_new(path.as_ref(), CaseCollision::Reject)
+            .await?;
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let path = self.resolve_new(path.as_ref(), self.collisions).await?;
+        self.inner.append(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.del(path).await
+    }
+
+    async fn del_if(&self, path: PathBuf, precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let path = self.resolve(&path).await;
+        self.inner.del_if(path, precondition).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.resolve_new(path.as_ref(), self.collisions).await?;
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.rmd(path).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let path = self.resolve(&path).await;
+        self.inner.rmd_recursive(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let (from, to) = self.resolve_renaming(from.as_ref(), to.as_ref()).await?;
+        self.inner.rename(from, to).await
+    }
+
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let (from, to) = self.resolve_renaming(&from, &to).await?;
+        self.inner.rename_if(from, to, precondition).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let path = self.resolve(path.as_ref()).await;
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+impl<S> CaseInsensitive<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    // Resolves both sides of a rename. Renaming an entry to a different case of its own name is a
+    // plain rename, not a collision.
+    async fn resolve_renaming(
+        &self,
+        from: &Path,
+        to: &Path,
+    ) -> Result<(PathBuf, PathBuf), S::Error> {
+        let from = self.resolve(from).await;
+        let resolved = self.resolve_new(to, CaseCollision::Overwrite).await?;
+        if resolved == from {
+            Ok((from, to.to_path_buf()))
+        } else if resolved != to && self.collisions == CaseCollision::Reject {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{:?} exists with a different case", resolved),
+            )
+            .into())
+        } else {
+            Ok((from, resolved))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use crate::storage::Filesystem;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn matches_names() {
+        let names: Vec<OsString> = vec!["README".into(), "Readme".into(), "readme.txt".into()];
+        assert_eq!(matching(&names, &"Readme".into()), Some("Readme".into()));
+        assert_eq!(matching(&names, &"readme".into()), Some("README".into()));
+        assert_eq!(
+            matching(&names, &"README.TXT".into()),
+            Some("readme.txt".into())
+        );
+        assert_eq!(matching(&names, &"other".into()), None);
+    }
+
+    #[test]
+    fn resolves_paths() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::create_dir(root.path().join("Docs")).unwrap();
+            std::fs::write(root.path().join("Docs/README.TXT"), b"hello").unwrap();
+            let storage = CaseInsensitive::new(Filesystem::new(root.path()));
+
+            assert_eq!(
+                block_on(storage.resolve(Path::new("/docs/readme.txt"))),
+                PathBuf::from("/Docs/README.TXT")
+            );
+            // What doesn't exist is kept as it is
+            assert_eq!(
+                block_on(storage.resolve(Path::new("/docs/new/file"))),
+                PathBuf::from("/Docs/new/file")
+            );
+            assert_eq!(block_on(storage.stat("/DOCS/Readme.txt")).unwrap().len(), 5);
+        });
+    }
+
+    #[test]
+    fn handles_collisions() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::write(root.path().join("README.TXT"), b"hello").unwrap();
+
+            let storage = CaseInsensitive::new(Filesystem::new(root.path()));
+            block_on(storage.put(std::io::Cursor::new(b"bye".to_vec()), "/Readme.txt")).unwrap();
+            assert_eq!(
+                std::fs::read(root.path().join("README.TXT")).unwrap(),
+                b"bye"
+            );
+            assert!(!root.path().join("Readme.txt").exists());
+
+            let storage = storage.collisions(CaseCollision::Reject);
+            assert!(
+                block_on(storage.put(std::io::Cursor::new(b"again".to_vec()), "/readme.txt"))
+                    .is_err()
+            );
+            assert!(block_on(storage.mkd("/readme.TXT")).is_err());
+            // The same name is fine, as is changing the case of a name.
+            block_on(storage.put(std::io::Cursor::new(b"again".to_vec()), "/README.TXT")).unwrap();
+            block_on(storage.rename("/readme.txt", "/Readme.txt")).unwrap();
+            assert_eq!(
+                std::fs::read(root.path().join("Readme.txt")).unwrap(),
+                b"again"
+            );
+        });
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+
+use sha1::Digest;
+
+/// The hash algorithms that can be used for the `HASH` command.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// SHA-256. This is the default.
+    #[default]
+    Sha256,
+    /// SHA-1
+    Sha1,
+    /// MD5
+    Md5,
+    /// CRC-32, as used by zlib and zip files.
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// All supported algorithms, the default first.
+    pub fn all() -> &'static [HashAlgorithm] {
+        &[
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Md5,
+            HashAlgorithm::Crc32,
+        ]
+    }
+
+    /// The name of the algorithm as used in the `HASH` command, e.g. `SHA-256`.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha1 => "SHA-1",
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Crc32 => "CRC32",
+        }
+    }
+
+    /// Returns a new [`Hasher`] for this algorithm.
+    ///
+    /// [`Hasher`]: struct.Hasher.html
+    pub fn hasher(self) -> Hasher {
+        let state = match self {
+            HashAlgorithm::Sha256 => State::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha1 => State::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Md5 => State::Md5(md5::Md5::new()),
+            HashAlgorithm::Crc32 => State::Crc32(crc32fast::Hasher::new()),
+        };
+        Hasher { state }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = ();
+
+    /// Parses the name of an algorithm, ignoring case.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        HashAlgorithm::all()
+            .iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+            .cloned()
+            .ok_or(())
+    }
+}
+
+enum State {
+    Sha256(sha2::Sha256),
+    Sha1(sha1::Sha1),
+    Md5(md5::Md5),
+    Crc32(crc32fast::Hasher),
+}
+
+/// Calculates the checksum of data that arrives in chunks, for storage backends implementing
+/// [`StorageBackend::checksum`].
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::HashAlgorithm;
+///
+/// let mut hasher = HashAlgorithm::Crc32.hasher();
+/// hasher.update(b"Hello, ");
+/// hasher.update(b"world!");
+/// assert_eq!(hasher.finish(), "ebe6c6e6");
+/// ```
+///
+/// [`StorageBackend::checksum`]: ./trait.StorageBackend.html#tymethod.checksum
+pub struct Hasher {
+    state: State,
+}
+
+impl Hasher {
+    /// Feed the next chunk of data to the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            State::Sha256(hasher) => hasher.update(data),
+            State::Sha1(hasher) => hasher.update(data),
+            State::Md5(hasher) => hasher.update(data),
+            State::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the checksum of all the data, as lowercase hexadecimal digits.
+    pub fn finish(self) -> String {
+        let bytes = match self.state {
+            State::Sha256(hasher) => hasher.finalize().to_vec(),
+            State::Sha1(hasher) => hasher.finalize().to_vec(),
+            State::Md5(hasher) => hasher.finalize().to_vec(),
+            State::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn known_checksums() {
+        let checksum = |algorithm: HashAlgorithm| {
+            let mut hasher = algorithm.hasher();
+            for chunk in b"The quick brown fox jumps over the lazy dog".chunks(7) {
+                hasher.update(chunk);
+            }
+            hasher.finish()
+        };
+        assert_eq!(
+            checksum(HashAlgorithm::Sha256),
+            "d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592"
+        );
+        assert_eq!(
+            checksum(HashAlgorithm::Sha1),
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"
+        );
+        assert_eq!(
+            checksum(HashAlgorithm::Md5),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(checksum(HashAlgorithm::Crc32), "414fa339");
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!("sha-256".parse(), Ok(HashAlgorithm::Sha256));
+        assert_eq!("MD5".parse(), Ok(HashAlgorithm::Md5));
+        assert_eq!("SHA-512".parse::<HashAlgorithm>(), Err(()));
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{self, Read};
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures::{Async, Stream};
+use hyper::Body;
+use tokio::prelude::AsyncRead;
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+use super::Metadata;
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+use super::MultipartStrategy;
+
+/// The metadata of an object in an object store, or of a directory.
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectMetadata {
+    pub(super) len: u64,
+    pub(super) dir: bool,
+    pub(super) modified: SystemTime,
+    pub(super) etag: Option<String>,
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+impl ObjectMetadata {
+    pub(super) fn dir(modified: SystemTime) -> Self {
+        ObjectMetadata {
+            len: 0,
+            dir: true,
+            modified,
+            etag: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+impl Metadata for ObjectMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn gid(&self) -> u32 {
+        0
+    }
+
+    fn uid(&self) -> u32 {
+        0
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+}
+
+/// The contents of an object in an object store, as it comes in.
+pub struct Object {
+    pub(super) body: Body,
+    // What's left of the last chunk of the body.
+    chunk: Bytes,
+}
+
+impl Object {
+    pub(super) fn new(body: Body) -> Self {
+        Object {
+            body,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for Object {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => self.chunk = chunk.into_bytes(),
+                Ok(Async::Ready(None)) => return Ok(0),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+impl AsyncRead for Object {}
+
+// The bytes of `first`, followed by those of `second`, for `append`.
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+pub(super) struct Chain<A, B> {
+    pub(super) first: Option<A>,
+    pub(super) second: B,
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+impl<A: Read, B: Read> Read for Chain<A, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(first) = &mut self.first {
+            match first.read(buf)? {
+                0 if !buf.is_empty() => self.first = None,
+                n => return Ok(n),
+            }
+        }
+        self.second.read(buf)
+    }
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+impl<A: AsyncRead, B: AsyncRead> AsyncRead for Chain<A, B> {}
+
+// Splits an upload into numbered parts, sized according to a `MultipartStrategy`.
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+pub(super) struct Parts<R> {
+    reader: R,
+    strategy: MultipartStrategy,
+    // The number of parts read so far.
+    count: u32,
+    buffer: Vec<u8>,
+    filled: usize,
+    // Whether the reader is at its end.
+    pub(super) done: bool,
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+impl<R> Parts<R> {
+    pub(super) fn new(reader: R, strategy: MultipartStrategy) -> Self {
+        Parts {
+            reader,
+            strategy,
+            count: 0,
+            buffer: vec![],
+            filled: 0,
+            done: false,
+        }
+    }
+}
+
+#[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+impl<R: AsyncRead> Stream for Parts<R> {
+    type Item = (u32, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, io::Error> {
+        let size = self.strategy.part_size(self.count) as usize;
+        if self.buffer.is_empty() {
+            self.buffer = vec![0; size];
+        }
+        while !self.done && self.filled < size {
+            match self.reader.poll_read(&mut self.buffer[self.filled..])? {
+                Async::Ready(0) => self.done = true,
+                Async::Ready(n) => self.filled += n,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        if self.filled == 0 {
+            return Ok(Async::Ready(None));
+        }
+        if self.count >= self.strategy.max_parts {
+            return Err(io::Error::other("The upload has too many parts"));
+        }
+        self.count += 1;
+        let mut part = std::mem::take(&mut self.buffer);
+        part.truncate(self.filled);
+        self.filled = 0;
+        Ok(Async::Ready(Some((self.count, part))))
+    }
+}
+
+// Percent-encodes everything but the unreserved characters of RFC 3986.
+pub(super) fn uri_encode(text: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Replaces the entity and character references in the given XML text with the characters they
+// stand for.
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub(super) fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16)
+                .ok()
+                .and_then(std::char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn encodes_uris() {
+        assert_eq!(uri_encode("/a b/Q&A~1.txt", false), "/a%20b/Q%26A~1.txt");
+        assert_eq!(uri_encode("/caf\u{e9}", true), "%2Fcaf%C3%A9");
+    }
+
+    #[test]
+    #[cfg(any(feature = "s3", feature = "gcs", feature = "b2"))]
+    fn splits_uploads_into_parts() {
+        use futures::Future;
+
+        let strategy = MultipartStrategy {
+            initial_part_size: 4,
+            growth_interval: 2,
+            ..MultipartStrategy::default()
+        };
+        let parts = Parts::new(io::Cursor::new(b"0123456789abcdefghij".to_vec()), strategy);
+        assert_eq!(
+            parts.collect().wait().unwrap(),
+            vec![
+                (1, b"0123".to_vec()),
+                (2, b"4567".to_vec()),
+                (3, b"89abcdef".to_vec()),
+                (4, b"ghij".to_vec()),
+            ]
+        );
+
+        let too_many = MultipartStrategy {
+            max_parts: 2,
+            ..strategy
+        };
+        let parts = Parts::new(io::Cursor::new(b"0123456789".to_vec()), too_many);
+        assert!(parts.collect().wait().is_err());
+    }
+
+    #[test]
+    #[cfg(any(feature = "s3", feature = "gcs", feature = "b2", feature = "webdav"))]
+    fn chains_readers() {
+        let mut chain = Chain {
+            first: Some(io::Cursor::new(b"one ".to_vec())),
+            second: io::Cursor::new(b"two".to_vec()),
+        };
+        let mut read = String::new();
+        chain.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "one two");
+    }
+}
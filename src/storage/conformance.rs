@@ -0,0 +1,247 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::{self, Cursor, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures03::compat::Future01CompatExt;
+use futures03::executor::block_on;
+use futures03::task::noop_waker_ref;
+
+use futures::Stream;
+
+use super::{HashAlgorithm, Metadata, StorageBackend};
+
+/// How long a write may take to read the bytes it's given, before the checks give up on it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A reader that hands out its bytes and then stalls, like an upload that's waiting for the
+// client to send more, and tells when it got there.
+struct Stalling {
+    bytes: Cursor<Vec<u8>>,
+    stalled: Arc<AtomicBool>,
+}
+
+impl Read for Stalling {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.bytes.read(buf)? {
+            0 => {
+                self.stalled.store(true, Ordering::SeqCst);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            n => Ok(n),
+        }
+    }
+}
+
+impl tokio::prelude::AsyncRead for Stalling {}
+
+// Polls the given write until it has read everything from its stalling reader, and then drops it,
+// the way the server drops the write of an aborted upload.
+fn interrupt<F, T, E>(write: F, stalled: &AtomicBool)
+where
+    F: Future<Output = Result<T, E>>,
+    E: Debug,
+{
+    let mut write = Box::pin(write);
+    let mut context = Context::from_waker(noop_waker_ref());
+    let deadline = Instant::now() + WRITE_TIMEOUT;
+    while !stalled.load(Ordering::SeqCst) {
+        match write.as_mut().poll(&mut context) {
+            Poll::Pending => {}
+            Poll::Ready(Ok(_)) => panic!("The write finished before its reader did"),
+            Poll::Ready(Err(e)) => panic!("The write failed: {:?}", e),
+        }
+        assert!(
+            Instant::now() < deadline,
+            "The write didn't read its bytes within {:?}",
+            WRITE_TIMEOUT
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Polls the given operation once, and then drops it, the way the server drops the operations of
+// a client that went away.
+fn poll_once<F: Future>(operation: F) {
+    let mut context = Context::from_waker(noop_waker_ref());
+    let _ = Box::pin(operation).as_mut().poll(&mut context);
+}
+
+fn stalling(contents: &str) -> (Stalling, Arc<AtomicBool>) {
+    let stalled = Arc::new(AtomicBool::new(false));
+    let reader = Stalling {
+        bytes: Cursor::new(contents.as_bytes().to_vec()),
+        stalled: Arc::clone(&stalled),
+    };
+    (reader, stalled)
+}
+
+fn contents<S>(storage: &S, path: &str) -> Option<String>
+where
+    S: StorageBackend,
+    S::File: tokio::prelude::AsyncRead,
+{
+    let file = block_on(storage.get(path)).ok()?;
+    let read = block_on(tokio_io::io::read_to_end(file, Vec::new()).compat());
+    Some(String::from_utf8_lossy(&read.expect("Failed to read a file").1).into_owned())
+}
+
+/// Checks that the operations of the backend keep the [cancellation] promises of the
+/// `StorageBackend` trait. The futures of `put`, `put_unique` and `append` are dropped after
+/// they've written some bytes, and the files have to be as they were before (or not there at all,
+/// where that's allowed). The futures of the other operations are dropped after they're polled
+/// once: the ones that only read mustn't change anything, and `del`, `mkd`, `rmd`, `rename`,
+/// `set_mtime` and `chmod` have to be either done or not done. The backend must be empty, and
+/// able to write files and create directories.
+///
+/// The checks run on a tokio runtime of their own, so backends are free to use `tokio::fs`.
+///
+/// # Panics
+///
+/// When the backend breaks a promise, or fails to do what it's asked to.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::{conformance, Filesystem};
+///
+/// let root = std::env::temp_dir().join("firetrap-conformance-example");
+/// std::fs::create_dir_all(&root).unwrap();
+/// conformance::check_cancellation(Filesystem::new(&root));
+/// std::fs::remove_dir_all(&root).unwrap();
+/// ```
+///
+/// [cancellation]: ../trait.StorageBackend.html#cancellation
+pub fn check_cancellation<S>(storage: S)
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata,
+    S::Error: Debug + Send + 'static,
+{
+    let mut runtime = tokio::runtime::Runtime::new().expect("Failed to start a tokio runtime");
+    runtime
+        .block_on(futures::future::lazy(move || {
+            let put = |path: &str, contents: &str| {
+                block_on(storage.put(Cursor::new(contents.as_bytes().to_vec()), path))
+                    .unwrap_or_else(|e| panic!("Failed to put {}: {:?}", path, e));
+            };
+
+            let (reader, stalled) = stalling("partial");
+            interrupt(storage.put(reader, "new.txt"), &stalled);
+            assert_eq!(
+                contents(&storage, "new.txt"),
+                None,
+                "A dropped put left a new file behind"
+            );
+
+            put("replaced.txt", "before");
+            let (reader, stalled) = stalling("partial");
+            interrupt(storage.put(reader, "replaced.txt"), &stalled);
+            let replaced = contents(&storage, "replaced.txt");
+            assert!(
+                replaced.is_none() || replaced.as_deref() == Some("before"),
+                "A dropped put left a partially written file behind: {:?}",
+                replaced
+            );
+
+            let (reader, stalled) = stalling("partial");
+            interrupt(storage.put_unique(reader, "unique.txt"), &stalled);
+            assert_eq!(
+                contents(&storage, "unique.txt"),
+                None,
+                "A dropped put_unique left a file behind"
+            );
+
+            put("appended.txt", "before");
+            let (reader, stalled) = stalling(" and partial");
+            interrupt(storage.append(reader, "appended.txt"), &stalled);
+            assert_eq!(
+                contents(&storage, "appended.txt").as_deref(),
+                Some("before"),
+                "A dropped append left some of its bytes behind"
+            );
+
+            put("kept.txt", "kept");
+            poll_once(storage.stat("kept.txt"));
+            poll_once(storage.get("kept.txt"));
+            poll_once(storage.get_range("kept.txt", 1..3));
+            poll_once(storage.checksum("kept.txt", HashAlgorithm::Sha256, None));
+            // This runs in a task of the runtime, so the listing can be polled right here.
+            let _ = storage.list("/").poll();
+            assert_eq!(
+                contents(&storage, "kept.txt").as_deref(),
+                Some("kept"),
+                "A dropped read changed the file"
+            );
+
+            put("deleted.txt", "deleted");
+            poll_once(storage.del("deleted.txt"));
+            let deleted = contents(&storage, "deleted.txt");
+            assert!(
+                deleted.is_none() || deleted.as_deref() == Some("deleted"),
+                "A dropped del left a broken file behind: {:?}",
+                deleted
+            );
+
+            poll_once(storage.mkd("made"));
+            if let Ok(made) = block_on(storage.stat("made")) {
+                assert!(
+                    made.is_dir(),
+                    "A dropped mkd left something that isn't a directory"
+                );
+            }
+
+            block_on(storage.mkd("removed"))
+                .unwrap_or_else(|e| panic!("Failed to create removed: {:?}", e));
+            poll_once(storage.rmd("removed"));
+            if let Ok(removed) = block_on(storage.stat("removed")) {
+                assert!(
+                    removed.is_dir(),
+                    "A dropped rmd left something that isn't a directory"
+                );
+            }
+
+            put("from.txt", "renamed");
+            poll_once(storage.rename("from.txt", "to.txt"));
+            let renamed = (contents(&storage, "from.txt"), contents(&storage, "to.txt"));
+            assert!(
+                matches!(
+                    (renamed.0.as_deref(), renamed.1.as_deref()),
+                    (Some("renamed"), None) | (None, Some("renamed"))
+                ),
+                "A dropped rename left the file half moved: {:?}",
+                renamed
+            );
+
+            put("touched.txt", "touched");
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+            poll_once(storage.set_mtime("touched.txt", mtime));
+            poll_once(storage.chmod("touched.txt", 0o600));
+            assert_eq!(
+                contents(&storage, "touched.txt").as_deref(),
+                Some("touched"),
+                "A dropped set_mtime or chmod changed the contents of the file"
+            );
+
+            // Leave only the files of the writes behind, for the tests of the backend to look at.
+            for path in &[
+                "kept.txt",
+                "deleted.txt",
+                "from.txt",
+                "to.txt",
+                "touched.txt",
+            ] {
+                let _ = block_on(storage.del(path));
+            }
+            for path in &["made", "removed"] {
+                let _ = block_on(storage.rmd(path));
+            }
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+}
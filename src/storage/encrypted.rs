@@ -0,0 +1,643 @@
+//! Contains the [`Encrypted`] wrapper, which encrypts the contents of the files it stores with
+//! AES-256-GCM, and the [`KeyProvider`] it gets its keys from.
+//!
+//! [`Encrypted`]: struct.Encrypted.html
+//! [`KeyProvider`]: trait.KeyProvider.html
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::Stream;
+use futures03::compat::Future01CompatExt;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+
+use super::{Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+use crate::random::{RandomSource, SecureRandom};
+
+// Every encrypted file starts with these, followed by the id of the key and the nonce prefix.
+const MAGIC: &[u8; 4] = b"FTE\x01";
+const PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = MAGIC.len() + 4 + PREFIX_LEN;
+// The contents are encrypted in chunks of this many bytes, each with its own tag, so files can be
+// streamed and every chunk is checked before it's handed out.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Hands out the 256-bit keys that an [`Encrypted`] storage encrypts and decrypts files with.
+/// Every key has an id, which is stored with the files encrypted with it, so keys can be rotated:
+/// new files get the current key, while older ones can still be read with the key they were
+/// written with, for as long as the provider knows it.
+///
+/// [`Encrypted`]: struct.Encrypted.html
+pub trait KeyProvider {
+    /// Returns the id and the key to encrypt new files with.
+    fn current_key(&self) -> (u32, [u8; 32]);
+
+    /// Returns the key with the given id, or `None` if it's unknown, e.g. because it has been
+    /// retired. Files encrypted with an unknown key can't be downloaded.
+    fn key(&self, id: u32) -> Option<[u8; 32]>;
+}
+
+/// A [`KeyProvider`] with a single key, that has id `0`.
+///
+/// [`KeyProvider`]: trait.KeyProvider.html
+pub struct StaticKey {
+    key: [u8; 32],
+}
+
+impl StaticKey {
+    /// Use the given key for all files.
+    pub fn new(key: [u8; 32]) -> Self {
+        StaticKey { key }
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn current_key(&self) -> (u32, [u8; 32]) {
+        (0, self.key)
+    }
+
+    fn key(&self, id: u32) -> Option<[u8; 32]> {
+        if id == 0 {
+            Some(self.key)
+        } else {
+            None
+        }
+    }
+}
+
+/// A [`StorageBackend`] wrapper that encrypts the contents of files on their way to the wrapped
+/// backend, and decrypts them on the way back, so uploads can be kept on storage that isn't
+/// trusted with them. Files are encrypted with AES-256-GCM in chunks of 64 KiB, each of which is
+/// authenticated: a download of a file that was changed, cut short or encrypted with an unknown
+/// key fails, instead of handing out anything that wasn't uploaded.
+///
+/// Names, directories and modification times are stored as they are. Listings and `SIZE` report
+/// the sizes of the decrypted contents, so every file under the wrapper has to be uploaded
+/// through it. Encrypted files can't be appended to, so `APPE` is denied; and neither does the
+/// wrapper hand out presigned URLs, as they'd only download the encrypted contents.
+///
+/// This wrapper is only available with the `encryption` feature.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::encrypted::{Encrypted, StaticKey};
+/// use firetrap::storage::Filesystem;
+/// use std::sync::Arc;
+///
+/// let keys = Arc::new(StaticKey::new([7; 32]));
+/// let server = Server::new(Box::new(move || {
+///     Encrypted::new(Filesystem::new("/srv/untrusted"), keys.clone())
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+pub struct Encrypted<S> {
+    inner: S,
+    keys: Arc<dyn KeyProvider + Send + Sync>,
+}
+
+impl<S> Encrypted<S> {
+    /// Wrap the given backend, encrypting with the keys of the given provider.
+    pub fn new(inner: S, keys: Arc<dyn KeyProvider + Send + Sync>) -> Self {
+        Encrypted { inner, keys }
+    }
+
+    fn encrypt<R: Read>(&self, bytes: R) -> io::Result<(Encryptor<R>, Arc<AtomicU64>)> {
+        let (id, key) = self.keys.current_key();
+        let mut prefix = [0; PREFIX_LEN];
+        SecureRandom.fill_bytes(&mut prefix);
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&id.to_be_bytes());
+        header.extend_from_slice(&prefix);
+        let plaintext = Arc::new(AtomicU64::new(0));
+        let encryptor = Encryptor {
+            inner: bytes,
+            key: cipher(&key)?,
+            prefix,
+            counter: 0,
+            plain: vec![0; CHUNK_SIZE],
+            filled: 0,
+            eof: false,
+            sealed: header,
+            pos: 0,
+            done: false,
+            plaintext: Arc::clone(&plaintext),
+        };
+        Ok((encryptor, plaintext))
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> io::Result<LessSafeKey> {
+    UnboundKey::new(&AES_256_GCM, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| io::Error::other("Invalid encryption key"))
+}
+
+// The nonce of every chunk is unique for the key: a random prefix for the file, the number of
+// the chunk, and whether it's the last one, so that a file can't be cut short unnoticed.
+fn nonce(prefix: &[u8; PREFIX_LEN], counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// The size of the contents of an encrypted file of `len` bytes. The last chunk is never full,
+// so a file whose size is a multiple of the chunk size ends with an empty one.
+fn plaintext_len(len: u64) -> u64 {
+    let sealed = len.saturating_sub(HEADER_LEN as u64);
+    let chunks = sealed / (CHUNK_SIZE + TAG_LEN) as u64 + 1;
+    sealed.saturating_sub(chunks * TAG_LEN as u64)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Encrypts what it reads from the upload.
+struct Encryptor<R> {
+    inner: R,
+    key: LessSafeKey,
+    prefix: [u8; PREFIX_LEN],
+    counter: u32,
+    // The contents of the chunk that's being read.
+    plain: Vec<u8>,
+    filled: usize,
+    eof: bool,
+    // What's ready to be handed out, starting with the header.
+    sealed: Vec<u8>,
+    pos: usize,
+    done: bool,
+    // The bytes of the upload, before encryption.
+    plaintext: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for Encryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.sealed.len() {
+                let n = std::cmp::min(buf.len(), self.sealed.len() - self.pos);
+                buf[..n].copy_from_slice(&self.sealed[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            // Whatever was read already is kept when the upload would block.
+            while !self.eof && self.filled < CHUNK_SIZE {
+                match self.inner.read(&mut self.plain[self.filled..])? {
+                    0 => self.eof = true,
+                    n => self.filled += n,
+                }
+            }
+            self.sealed.clear();
+            self.sealed.extend_from_slice(&self.plain[..self.filled]);
+            self.key
+                .seal_in_place_append_tag(
+                    nonce(&self.prefix, self.counter, self.eof),
+                    Aad::empty(),
+                    &mut self.sealed,
+                )
+                .map_err(|_| io::Error::other("Failed to encrypt the file"))?;
+            self.counter = self
+                .counter
+                .checked_add(1)
+                .ok_or_else(|| io::Error::other("The file is too large to encrypt"))?;
+            self.plaintext
+                .fetch_add(self.filled as u64, Ordering::SeqCst);
+            self.filled = 0;
+            self.pos = 0;
+            self.done = self.eof;
+        }
+    }
+}
+
+impl<R: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for Encryptor<R> {}
+
+/// The contents of a file in an [`Encrypted`] storage, decrypted while they're read.
+///
+/// [`Encrypted`]: struct.Encrypted.html
+pub struct Decryptor<R> {
+    inner: R,
+    keys: Arc<dyn KeyProvider + Send + Sync>,
+    // Known once the header has been read.
+    key: Option<(LessSafeKey, [u8; PREFIX_LEN])>,
+    counter: u32,
+    // The chunk that's being read, decrypted in place.
+    sealed: Vec<u8>,
+    filled: usize,
+    pos: usize,
+    plain: usize,
+    done: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    fn read_header(&mut self) -> io::Result<()> {
+        while self.filled < HEADER_LEN {
+            match self.inner.read(&mut self.sealed[self.filled..HEADER_LEN])? {
+                0 => return Err(invalid("The file isn't encrypted")),
+                n => self.filled += n,
+            }
+        }
+        if &self.sealed[..MAGIC.len()] != MAGIC {
+            return Err(invalid("The file isn't encrypted"));
+        }
+        let mut id = [0; 4];
+        id.copy_from_slice(&self.sealed[MAGIC.len()..MAGIC.len() + 4]);
+        let key = self
+            .keys
+            .key(u32::from_be_bytes(id))
+            .ok_or_else(|| invalid("The file is encrypted with an unknown key"))?;
+        let mut prefix = [0; PREFIX_LEN];
+        prefix.copy_from_slice(&self.sealed[MAGIC.len() + 4..HEADER_LEN]);
+        self.key = Some((cipher(&key)?, prefix));
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.plain {
+                let n = std::cmp::min(buf.len(), self.plain - self.pos);
+                buf[..n].copy_from_slice(&self.sealed[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            if self.key.is_none() {
+                self.read_header()?;
+            }
+            // Only the last chunk isn't full.
+            let mut last = false;
+            while self.filled < self.sealed.len() {
+                match self.inner.read(&mut self.sealed[self.filled..])? {
+                    0 => {
+                        last = true;
+                        break;
+                    }
+                    n => self.filled += n,
+                }
+            }
+            if self.filled < TAG_LEN {
+                return Err(invalid("The encrypted file was cut short"));
+            }
+            let (key, prefix) = self.key.as_ref().unwrap();
+            let plain = key
+                .open_in_place(
+                    nonce(prefix, self.counter, last),
+                    Aad::empty(),
+                    &mut self.sealed[..self.filled],
+                )
+                .map_err(|_| invalid("Failed to decrypt the file: it was changed, or cut short"))?
+                .len();
+            self.counter = self.counter.wrapping_add(1);
+            self.filled = 0;
+            self.pos = 0;
+            self.plain = plain;
+            self.done = last;
+        }
+    }
+}
+
+impl<R: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for Decryptor<R> {}
+
+/// The [`Metadata`] of a file in an [`Encrypted`] storage, which reports the size of the decrypted
+/// contents.
+///
+/// [`Metadata`]: ../trait.Metadata.html
+/// [`Encrypted`]: struct.Encrypted.html
+#[derive(Clone, Debug)]
+pub struct EncryptedMetadata<M> {
+    inner: M,
+}
+
+impl<M: Metadata> Metadata for EncryptedMetadata<M> {
+    fn len(&self) -> u64 {
+        if self.inner.is_file() {
+            plaintext_len(self.inner.len())
+        } else {
+            self.inner.len()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inner.is_dir()
+    }
+
+    fn is_file(&self) -> bool {
+        self.inner.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.inner.is_symlink()
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        self.inner.modified()
+    }
+
+    fn gid(&self) -> u32 {
+        self.inner.gid()
+    }
+
+    fn uid(&self) -> u32 {
+        self.inner.uid()
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.inner.etag()
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Encrypted<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<io::Error> + Send + 'static,
+{
+    type File = Decryptor<S::File>;
+    type Metadata = EncryptedMetadata<S::Metadata>;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let inner = self.inner.stat(path).await?;
+        Ok(EncryptedMetadata { inner })
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        Box::new(self.inner.list(path).map(|file| Fileinfo {
+            path: file.path,
+            metadata: EncryptedMetadata {
+                inner: file.metadata,
+            },
+        }))
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let inner = self.inner.get(path).await?;
+        Ok(Decryptor {
+            inner,
+            keys: Arc::clone(&self.keys),
+            key: None,
+            counter: 0,
+            sealed: vec![0; CHUNK_SIZE + TAG_LEN],
+            filled: 0,
+            pos: 0,
+            plain: 0,
+            done: false,
+        })
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let (bytes, plaintext) = self.encrypt(bytes)?;
+        self.inner.put(bytes, path).await?;
+        Ok(plaintext.load(Ordering::SeqCst))
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let (bytes, plaintext) = self.encrypt(bytes)?;
+        self.inner.put_unique(bytes, path).await?;
+        Ok(plaintext.load(Ordering::SeqCst))
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64, Self::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Encrypted files can't be appended to",
+        )
+        .into())
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.del(path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.rmd(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).await
+    }
+
+    /// Hashes the decrypted contents, as the wrapped backend only has the encrypted ones.
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let file: Box<dyn tokio::prelude::AsyncRead + Send> = match range {
+            Some(range) => self.get_range(path, range).await?,
+            None => Box::new(self.get(path).await?),
+        };
+        let hasher = tokio_codec::FramedRead::new(file, tokio_codec::BytesCodec::new())
+            .fold(algorithm.hasher(), |mut hasher, chunk| {
+                hasher.update(&chunk);
+                Ok::<_, io::Error>(hasher)
+            })
+            .compat()
+            .await?;
+        Ok(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Error, ErrorKind, Memory};
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    fn raw(memory: &Memory, path: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        block_on(memory.get(path))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    fn read(storage: &Encrypted<Memory>, path: &str) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        block_on(storage.get(path))
+            .unwrap()
+            .read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn round_trip() {
+        let memory = Memory::new();
+        let storage = Encrypted::new(memory.clone(), Arc::new(StaticKey::new([7; 32])));
+        for &len in &[0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 5] {
+            let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let written = block_on(storage.put(Cursor::new(contents.clone()), "file")).unwrap();
+            assert_eq!(written, len as u64);
+
+            let stored = raw(&memory, "file");
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(stored.len(), HEADER_LEN + len + chunks * TAG_LEN);
+            if len > 0 {
+                assert!(!stored
+                    .windows(len.min(10))
+                    .any(|w| w == &contents[..len.min(10)]));
+            }
+
+            assert_eq!(read(&storage, "file").unwrap(), contents);
+            assert_eq!(block_on(storage.stat("file")).unwrap().len(), len as u64);
+            let listed = block_on(storage.list("/").collect().compat()).unwrap();
+            assert_eq!(listed[0].metadata.len(), len as u64);
+        }
+        assert_eq!(
+            block_on(storage.checksum("file", HashAlgorithm::Sha256, Some(0..10))).unwrap(),
+            {
+                let mut hasher = HashAlgorithm::Sha256.hasher();
+                hasher.update(&(0..10).collect::<Vec<u8>>());
+                hasher.finish()
+            }
+        );
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let memory = Memory::new();
+        let storage = Encrypted::new(memory.clone(), Arc::new(StaticKey::new([7; 32])));
+        let contents = vec![1; CHUNK_SIZE + 100];
+        block_on(storage.put(Cursor::new(contents), "file")).unwrap();
+        let stored = raw(&memory, "file");
+
+        let mut changed = stored.clone();
+        changed[HEADER_LEN + 5] ^= 1;
+        block_on(memory.put(Cursor::new(changed), "file")).unwrap();
+        let error = read(&storage, "file").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Without the last chunk, the first one looks like the end of the file.
+        let cut = stored[..HEADER_LEN + CHUNK_SIZE + TAG_LEN].to_vec();
+        block_on(memory.put(Cursor::new(cut), "file")).unwrap();
+        assert!(read(&storage, "file").is_err());
+
+        block_on(memory.put(Cursor::new(b"plain".to_vec()), "plain")).unwrap();
+        assert!(read(&storage, "plain").is_err());
+
+        assert_eq!(
+            block_on(storage.append(Cursor::new(b"more".to_vec()), "file")).unwrap_err(),
+            Error::from(ErrorKind::PermissionDenied)
+        );
+    }
+
+    struct Rotating {
+        current: Mutex<u32>,
+    }
+
+    impl KeyProvider for Rotating {
+        fn current_key(&self) -> (u32, [u8; 32]) {
+            let id = *self.current.lock().unwrap();
+            (id, [id as u8; 32])
+        }
+
+        fn key(&self, id: u32) -> Option<[u8; 32]> {
+            if id <= 1 {
+                Some([id as u8; 32])
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn rotates_keys() {
+        let memory = Memory::new();
+        let keys = Arc::new(Rotating {
+            current: Mutex::new(0),
+        });
+        let storage = Encrypted::new(memory.clone(), keys.clone());
+        block_on(storage.put(Cursor::new(b"old".to_vec()), "old")).unwrap();
+        *keys.current.lock().unwrap() = 1;
+        block_on(storage.put(Cursor::new(b"new".to_vec()), "new")).unwrap();
+        assert_eq!(read(&storage, "old").unwrap(), b"old");
+        assert_eq!(read(&storage, "new").unwrap(), b"new");
+
+        // One the provider doesn't know anymore
+        *keys.current.lock().unwrap() = 2;
+        block_on(storage.put(Cursor::new(b"gone".to_vec()), "gone")).unwrap();
+        assert_eq!(
+            read(&storage, "gone").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,430 @@
+use std::time::{Duration, SystemTime};
+
+use super::{ControlChars, Fileinfo, ListOptions, Metadata, Result};
+
+/// [`Metadata`] with fixed values, as used by the [`ListingVector`]s and [`FactsVector`]s.
+///
+/// [`Metadata`]: ../trait.Metadata.html
+/// [`ListingVector`]: struct.ListingVector.html
+/// [`FactsVector`]: struct.FactsVector.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixtureMetadata {
+    /// The size of the file in bytes.
+    pub len: u64,
+    /// Whether the path is a directory.
+    pub dir: bool,
+    /// Whether the path is a symbolic link.
+    pub symlink: bool,
+    /// The last modified time of the path.
+    pub modified: SystemTime,
+    /// The `uid` of the file.
+    pub uid: u32,
+    /// The `gid` of the file.
+    pub gid: u32,
+}
+
+impl Metadata for FixtureMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir && !self.symlink
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    fn modified(&self) -> Result<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+/// A single golden test vector: a file and the exact line of `LIST` output it must be rendered
+/// as.
+///
+/// Backends with their own [`Metadata`] type can fill it in with the values of [`metadata`] and
+/// compare the output of [`Fileinfo::format`] with [`expected`], to make sure their metadata ends
+/// up in the listing as intended.
+///
+/// All modification times are at noon UTC, so the rendered dates are the same for every timezone
+/// between UTC-11 and UTC+11.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::fixtures::list_vectors;
+///
+/// for vector in list_vectors() {
+///     assert_eq!(vector.fileinfo().format(vector.options), vector.expected, "{}", vector.name);
+/// }
+/// ```
+///
+/// [`Metadata`]: ../trait.Metadata.html
+/// [`Fileinfo::format`]: ../struct.Fileinfo.html#method.format
+/// [`metadata`]: #structfield.metadata
+/// [`expected`]: #structfield.expected
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListingVector {
+    /// A short description of the edge case.
+    pub name: &'static str,
+    /// The full path of the file, of which only the last component is shown.
+    pub path: &'static str,
+    /// The metadata of the file.
+    pub metadata: FixtureMetadata,
+    /// The options to render the listing with.
+    pub options: ListOptions,
+    /// The expected line, without the trailing line terminator.
+    pub expected: &'static str,
+}
+
+impl ListingVector {
+    /// Returns the [`Fileinfo`] of this vector.
+    ///
+    /// [`Fileinfo`]: ../struct.Fileinfo.html
+    pub fn fileinfo(&self) -> Fileinfo<&'static str, FixtureMetadata> {
+        Fileinfo {
+            path: self.path,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// A single golden test vector: a file and the exact `MLST` or `MLSD` entry it must be rendered
+/// as.
+///
+/// Like a [`ListingVector`], but for the facts of [`Fileinfo::format_facts`], which always show
+/// the full path and the modification time in UTC.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::fixtures::facts_vectors;
+///
+/// for vector in facts_vectors() {
+///     assert_eq!(vector.fileinfo().format_facts(), vector.expected, "{}", vector.name);
+/// }
+/// ```
+///
+/// [`ListingVector`]: struct.ListingVector.html
+/// [`Fileinfo::format_facts`]: ../struct.Fileinfo.html#method.format_facts
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactsVector {
+    /// A short description of the edge case.
+    pub name: &'static str,
+    /// The full path of the file.
+    pub path: &'static str,
+    /// The metadata of the file.
+    pub metadata: FixtureMetadata,
+    /// The expected entry, without the leading space of `MLST` or the trailing line terminator.
+    pub expected: &'static str,
+}
+
+impl FactsVector {
+    /// Returns the [`Fileinfo`] of this vector.
+    ///
+    /// [`Fileinfo`]: ../struct.Fileinfo.html
+    pub fn fileinfo(&self) -> Fileinfo<&'static str, FixtureMetadata> {
+        Fileinfo {
+            path: self.path,
+            metadata: self.metadata,
+        }
+    }
+}
+
+// Noon UTC of the given number of days since the epoch.
+fn noon(days: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(days * 86_400 + 12 * 3600)
+}
+
+fn file(len: u64, modified: SystemTime) -> FixtureMetadata {
+    FixtureMetadata {
+        len,
+        dir: false,
+        symlink: false,
+        modified,
+        uid: 1000,
+        gid: 100,
+    }
+}
+
+/// Returns the golden test vectors for `LIST` output. They cover names with spaces and non-ASCII
+/// characters, sizes that don't fit in 32 bits, dates around a year boundary, directories,
+/// symbolic links and names with control characters.
+pub fn list_vectors() -> Vec<ListingVector> {
+    // 1999-12-31, 2000-01-01 and 2020-02-29
+    let new_years_eve = noon(10_956);
+    let new_year = noon(10_957);
+    let leap_day = noon(18_321);
+    let numeric = ListOptions::default();
+    let human = ListOptions {
+        human_readable_sizes: true,
+        ..ListOptions::default()
+    };
+    let encode = ListOptions {
+        control_chars: ControlChars::Encode,
+        ..ListOptions::default()
+    };
+
+    vec![
+        ListingVector {
+            name: "plain file",
+            path: "/data/report.txt",
+            metadata: file(1234, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 1234 Jan 01 2000 report.txt",
+        },
+        ListingVector {
+            name: "empty file",
+            path: "/data/empty",
+            metadata: file(0, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 0 Jan 01 2000 empty",
+        },
+        ListingVector {
+            name: "spaces in name",
+            path: "/data/My Documents/annual report 2019.pdf",
+            metadata: file(42, new_years_eve),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 42 Dec 31 1999 annual report 2019.pdf",
+        },
+        ListingVector {
+            name: "leading and trailing spaces",
+            path: "/data/ padded ",
+            metadata: file(1, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 1 Jan 01 2000  padded ",
+        },
+        ListingVector {
+            name: "UTF-8 name",
+            path: "/data/café ☕ 日本語.txt",
+            metadata: file(7, leap_day),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 7 Feb 29 2020 café ☕ 日本語.txt",
+        },
+        ListingVector {
+            name: "larger than 2GB",
+            path: "/data/disk.img",
+            metadata: file(3_000_000_000, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 3000000000 Jan 01 2000 disk.img",
+        },
+        ListingVector {
+            name: "larger than 4GB",
+            path: "/data/backup.tar",
+            metadata: file(5_000_000_000, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 5000000000 Jan 01 2000 backup.tar",
+        },
+        ListingVector {
+            name: "larger than 4GB, human readable",
+            path: "/data/backup.tar",
+            metadata: file(5_000_000_000, new_year),
+            options: human,
+            expected: "-rwxr-xr-x     1000 100 4.7G Jan 01 2000 backup.tar",
+        },
+        ListingVector {
+            name: "largest possible size",
+            path: "/data/sparse",
+            metadata: file(u64::MAX, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 18446744073709551615 Jan 01 2000 sparse",
+        },
+        ListingVector {
+            name: "last day of the year",
+            path: "/data/1999.log",
+            metadata: file(10, new_years_eve),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 10 Dec 31 1999 1999.log",
+        },
+        ListingVector {
+            name: "first day of the year",
+            path: "/data/2000.log",
+            metadata: file(10, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 10 Jan 01 2000 2000.log",
+        },
+        ListingVector {
+            name: "directory",
+            path: "/data/photos",
+            metadata: FixtureMetadata {
+                dir: true,
+                ..file(4096, new_year)
+            },
+            options: numeric,
+            expected: "drwxr-xr-x     1000 100 4096 Jan 01 2000 photos",
+        },
+        ListingVector {
+            name: "symbolic link",
+            path: "/data/latest",
+            metadata: FixtureMetadata {
+                symlink: true,
+                ..file(8, new_year)
+            },
+            options: numeric,
+            expected: "lrwxr-xr-x     1000 100 8 Jan 01 2000 latest",
+        },
+        ListingVector {
+            name: "line feed in name",
+            path: "/data/evil\r\n-rwxr-xr-x 0 0 0 Jan 01 2000 fake",
+            metadata: file(3, new_year),
+            options: numeric,
+            expected:
+                "-rwxr-xr-x     1000 100 3 Jan 01 2000 evil??-rwxr-xr-x 0 0 0 Jan 01 2000 fake",
+        },
+        ListingVector {
+            name: "other control characters in name",
+            path: "/data/bell\x07tab\t",
+            metadata: file(3, new_year),
+            options: numeric,
+            expected: "-rwxr-xr-x     1000 100 3 Jan 01 2000 bell?tab?",
+        },
+        ListingVector {
+            name: "line feed in name, encoded",
+            path: "/data/two\r\nlines\n",
+            metadata: file(3, new_year),
+            options: encode,
+            expected: "-rwxr-xr-x     1000 100 3 Jan 01 2000 two\r\0\0lines\0",
+        },
+    ]
+}
+
+/// Returns the golden test vectors for `MLST` and `MLSD` entries. They cover the file types,
+/// sizes that don't fit in 32 bits, dates around a year boundary, other owners, names with spaces
+/// and non-ASCII characters, and names with CRs and LFs, which are always encoded.
+pub fn facts_vectors() -> Vec<FactsVector> {
+    // 1999-12-31, 2000-01-01 and 2020-02-29
+    let new_years_eve = noon(10_956);
+    let new_year = noon(10_957);
+    let leap_day = noon(18_321);
+
+    vec![
+        FactsVector {
+            name: "plain file",
+            path: "/data/report.txt",
+            metadata: file(1234, new_year),
+            expected: "type=file;size=1234;modify=20000101120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/report.txt",
+        },
+        FactsVector {
+            name: "empty file",
+            path: "/data/empty",
+            metadata: file(0, new_year),
+            expected: "type=file;size=0;modify=20000101120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/empty",
+        },
+        FactsVector {
+            name: "spaces in name",
+            path: "/data/My Documents/annual report 2019.pdf",
+            metadata: file(42, new_years_eve),
+            expected: "type=file;size=42;modify=19991231120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/My Documents/annual report 2019.pdf",
+        },
+        FactsVector {
+            name: "UTF-8 name",
+            path: "/data/café ☕ 日本語.txt",
+            metadata: file(7, leap_day),
+            expected: "type=file;size=7;modify=20200229120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/café ☕ 日本語.txt",
+        },
+        FactsVector {
+            name: "larger than 4GB",
+            path: "/data/backup.tar",
+            metadata: file(5_000_000_000, new_year),
+            expected:
+                "type=file;size=5000000000;modify=20000101120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/backup.tar",
+        },
+        FactsVector {
+            name: "largest possible size",
+            path: "/data/sparse",
+            metadata: file(u64::MAX, new_year),
+            expected: "type=file;size=18446744073709551615;modify=20000101120000;UNIX.uid=1000;\
+                       UNIX.gid=100; /data/sparse",
+        },
+        FactsVector {
+            name: "owned by root",
+            path: "/etc/motd",
+            metadata: FixtureMetadata {
+                uid: 0,
+                gid: 0,
+                ..file(10, new_year)
+            },
+            expected: "type=file;size=10;modify=20000101120000;UNIX.uid=0;UNIX.gid=0; /etc/motd",
+        },
+        FactsVector {
+            name: "directory",
+            path: "/data/photos",
+            metadata: FixtureMetadata {
+                dir: true,
+                ..file(4096, new_year)
+            },
+            expected: "type=dir;size=4096;modify=20000101120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/photos",
+        },
+        FactsVector {
+            name: "symbolic link",
+            path: "/data/latest",
+            metadata: FixtureMetadata {
+                symlink: true,
+                ..file(8, new_year)
+            },
+            expected: "type=OS.unix=symlink;size=8;modify=20000101120000;UNIX.uid=1000;\
+                       UNIX.gid=100; /data/latest",
+        },
+        FactsVector {
+            name: "line feed in name",
+            path: "/data/two\r\nlines\n",
+            metadata: file(3, new_year),
+            expected: "type=file;size=3;modify=20000101120000;UNIX.uid=1000;UNIX.gid=100; \
+                       /data/two\r\0\0lines\0",
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn list() {
+        for vector in list_vectors() {
+            assert_eq!(
+                vector.fileinfo().format(vector.options),
+                vector.expected,
+                "{}",
+                vector.name
+            );
+        }
+    }
+
+    #[test]
+    fn facts() {
+        for vector in facts_vectors() {
+            assert_eq!(
+                vector.fileinfo().format_facts(),
+                vector.expected,
+                "{}",
+                vector.name
+            );
+        }
+    }
+}
@@ -0,0 +1,1403 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use futures::{future, stream, Async, Future, Poll, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+use tokio::prelude::AsyncRead;
+
+use super::{blocking, Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, Skip, StorageBackend};
+
+/// The number of idle connections to the upstream server that a backend keeps around by default.
+pub const DEFAULT_MAX_IDLE: usize = 8;
+
+/// How long connecting to the upstream server, and waiting for it to reply or to take more bytes,
+/// may take by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How many bytes to read from the client before sending them on.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// The [`Metadata`] of a file on the upstream FTP server, from its reply to `MLST` or `MLSD`, or
+/// from its `LIST` output. Servers may leave things out, so what's missing reads as zero.
+///
+/// [`Metadata`]: ../trait.Metadata.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FtpMetadata {
+    size: u64,
+    dir: bool,
+    symlink: bool,
+    modified: Option<SystemTime>,
+    uid: u32,
+    gid: u32,
+    permissions: Option<u32>,
+}
+
+impl FtpMetadata {
+    /// Returns the permission bits of the file, if the server told them.
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+}
+
+impl Metadata for FtpMetadata {
+    fn len(&self) -> u64 {
+        self.size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir && !self.symlink
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        self.modified
+            .ok_or_else(|| Error::backend("The modification time is unknown"))
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+// Parses a time like `20200101120000`, maybe with fractions of seconds, as FTP servers send them
+// in UTC.
+fn parse_time(time: &str) -> Option<SystemTime> {
+    let time = NaiveDateTime::parse_from_str(time.get(..14)?, "%Y%m%d%H%M%S").ok()?;
+    Some(Utc.from_utc_datetime(&time).into())
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<Utc>::from(time)
+        .format("%Y%m%d%H%M%S")
+        .to_string()
+}
+
+// Parses an entry of an `MLSD` listing, or the line of an `MLST` reply, like
+// `type=file;size=10;modify=20200101120000; name` (RFC 3659).
+fn parse_facts(line: &str) -> Option<(String, FtpMetadata)> {
+    let (facts, name) = line.split_once(' ')?;
+    let mut metadata = FtpMetadata::default();
+    for fact in facts.split(';') {
+        let (fact, value) = match fact.split_once('=') {
+            Some(fact) => fact,
+            None => continue,
+        };
+        match fact.to_ascii_lowercase().as_str() {
+            "type" => match value.to_ascii_lowercase().as_str() {
+                "dir" | "cdir" | "pdir" => metadata.dir = true,
+                "os.unix=symlink" | "os.unix=slink" => metadata.symlink = true,
+                _ => {}
+            },
+            "size" => metadata.size = value.parse().ok()?,
+            "modify" => metadata.modified = parse_time(value),
+            "unix.uid" => metadata.uid = value.parse().unwrap_or(0),
+            "unix.gid" => metadata.gid = value.parse().unwrap_or(0),
+            "unix.mode" => metadata.permissions = u32::from_str_radix(value, 8).ok(),
+            _ => {}
+        }
+    }
+    Some((name.to_string(), metadata))
+}
+
+// Parses a line of `LIST` output in the format of `ls -l`, like
+// `-rw-r--r--   1 1000  1000  1024 Jan 01 12:00 name`, where the link count, owner and group may
+// be missing. Lines that don't look like that, like the `total` line, are left out.
+fn parse_list_line(line: &str) -> Option<(String, FtpMetadata)> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c == ' ', start) {
+            (true, Some(s)) => {
+                tokens.push((s, &line[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &line[s..]));
+    }
+
+    let mode = tokens.first()?.1.as_bytes();
+    if mode.len() < 10 {
+        return None;
+    }
+    // The date is the month, the day, and either the time or the year.
+    let month = (2..tokens.len().saturating_sub(3)).find(|&i| {
+        MONTHS.contains(&tokens[i].1.to_ascii_lowercase().as_str())
+            && tokens[i + 1].1.parse::<u32>().is_ok()
+    })?;
+    let mut metadata = FtpMetadata {
+        size: tokens[month - 1].1.parse().ok()?,
+        dir: mode[0] == b'd',
+        symlink: mode[0] == b'l',
+        ..FtpMetadata::default()
+    };
+    if month >= 3 {
+        metadata.uid = tokens[month - 3].1.parse().unwrap_or(0);
+        metadata.gid = tokens[month - 2].1.parse().unwrap_or(0);
+    }
+    metadata.permissions = Some(mode[1..10].iter().enumerate().fold(0, |bits, (i, &c)| {
+        if c == b'-' {
+            bits
+        } else {
+            bits | 1 << (8 - i)
+        }
+    }));
+
+    let number = MONTHS
+        .iter()
+        .position(|m| *m == tokens[month].1.to_ascii_lowercase())? as u32
+        + 1;
+    let day = tokens[month + 1].1.parse().ok()?;
+    let time = tokens[month + 2].1;
+    let date = match time.split_once(':') {
+        // Only recent files have a time instead of a year: the ones of the last 6 months.
+        Some((hour, minute)) => {
+            let now = Utc::now().naive_utc();
+            let date = NaiveDate::from_ymd_opt(now.year(), number, day)?.and_hms_opt(
+                hour.parse().ok()?,
+                minute.parse().ok()?,
+                0,
+            )?;
+            if date > now + chrono::Duration::days(1) {
+                date.with_year(now.year() - 1)?
+            } else {
+                date
+            }
+        }
+        None => NaiveDate::from_ymd_opt(time.parse().ok()?, number, day)?.and_hms_opt(0, 0, 0)?,
+    };
+    metadata.modified = Some(Utc.from_utc_datetime(&date).into());
+
+    let mut name = &line[tokens[month + 3].0..];
+    if metadata.symlink {
+        name = name.split(" -> ").next().unwrap_or(name);
+    }
+    Some((name.to_string(), metadata))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Malformed reply from the upstream FTP server",
+    )
+}
+
+// The error for a reply that says a command failed.
+fn refused(code: u16, text: &str) -> io::Error {
+    let kind = match code {
+        550 => io::ErrorKind::NotFound,
+        530 | 532 => io::ErrorKind::PermissionDenied,
+        452 | 552 => io::ErrorKind::StorageFull,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("FTP error {}: {}", code, text.trim()))
+}
+
+// Parses the port from a reply to `EPSV`, like `Entering Extended Passive Mode (|||6446|)`.
+fn parse_epsv(text: &str) -> io::Result<u16> {
+    let start = text.find('(').ok_or_else(malformed)? + 1;
+    let inner = text[start..].split(')').next().unwrap_or("");
+    let delimiter = inner.chars().next().ok_or_else(malformed)?;
+    inner
+        .split(delimiter)
+        .nth(3)
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(malformed)
+}
+
+// Parses the port from a reply to `PASV`, like `Entering Passive Mode (127,0,0,1,25,46)`. The host
+// is ignored, since servers behind NAT often get it wrong.
+fn parse_pasv(text: &str) -> io::Result<u16> {
+    let numbers: Vec<u16> = text
+        .split(|c: char| !c.is_ascii_digit() && c != ',')
+        .find(|part| part.matches(',').count() == 5)
+        .ok_or_else(malformed)?
+        .split(',')
+        .map(|number| number.parse().map_err(|_| malformed()))
+        .collect::<io::Result<_>>()?;
+    Ok(numbers[4] << 8 | numbers[5])
+}
+
+// Where the upstream server is, and how to log in to it.
+struct Upstream {
+    address: String,
+    username: String,
+    password: String,
+    timeout: Duration,
+}
+
+// A control connection to the upstream server, which handles one command at a time.
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // The extensions that the server lists in its reply to `FEAT`, like `MLST`.
+    features: Vec<String>,
+    // The directory the server started in, where relative paths start.
+    home: String,
+    // Cleared when the server doesn't know `EPSV`, so it's `PASV` from then on.
+    epsv: bool,
+    timeout: Duration,
+    // Set when the connection failed, so it doesn't go back into the pool.
+    broken: bool,
+}
+
+impl Session {
+    fn connect(upstream: &Upstream) -> io::Result<Session> {
+        let mut last = io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "The address of the upstream FTP server didn't resolve",
+        );
+        let mut connected = None;
+        for addr in upstream.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, upstream.timeout) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(e) => last = e,
+            }
+        }
+        let stream = connected.ok_or(last)?;
+        stream.set_read_timeout(Some(upstream.timeout))?;
+        stream.set_write_timeout(Some(upstream.timeout))?;
+        let mut session = Session {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            features: vec![],
+            home: String::new(),
+            epsv: true,
+            timeout: upstream.timeout,
+            broken: false,
+        };
+
+        match session.reply()? {
+            (code, _) if code / 100 == 2 => {}
+            (code, text) => return Err(refused(code, &text)),
+        }
+        match session.command(&format!("USER {}", upstream.username))? {
+            (230, _) => {}
+            (331, _) => match session.command(&format!("PASS {}", upstream.password))? {
+                (code, _) if code / 100 == 2 => {}
+                (code, text) => return Err(refused(code, &text)),
+            },
+            (code, text) => return Err(refused(code, &text)),
+        }
+        session.expect("TYPE I", 2)?;
+        if let (211, text) = session.command("FEAT")? {
+            session.features = text
+                .lines()
+                .skip(1)
+                .filter(|line| line.starts_with(' '))
+                .filter_map(|line| line.split_whitespace().next())
+                .map(|feature| feature.to_ascii_uppercase())
+                .collect();
+        }
+        let pwd = session.expect("PWD", 2)?;
+        session.home = match (pwd.find('"'), pwd.rfind('"')) {
+            (Some(start), Some(end)) if start < end => pwd[start + 1..end].replace("\"\"", "\""),
+            _ => "/".to_string(),
+        };
+        Ok(session)
+    }
+
+    fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    fn send(&mut self, command: &str) -> io::Result<()> {
+        let sent = self
+            .writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .and_then(|_| self.writer.flush());
+        if sent.is_err() {
+            self.broken = true;
+        }
+        sent
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = vec![];
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The upstream FTP server hung up",
+            ));
+        }
+        while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    // Reads a reply, returning its code and its text: everything after the code on the first
+    // line, followed by the lines after that for replies that span more.
+    fn reply(&mut self) -> io::Result<(u16, String)> {
+        let reply = self.read_reply();
+        match reply {
+            Ok((421, _)) | Err(_) => self.broken = true,
+            _ => {}
+        }
+        reply
+    }
+
+    fn read_reply(&mut self) -> io::Result<(u16, String)> {
+        let first = self.read_line()?;
+        let code = first
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(malformed)?;
+        let mut text = first.get(4..).unwrap_or("").to_string();
+        if first.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{} ", code);
+            loop {
+                let line = self.read_line()?;
+                text.push('\n');
+                text.push_str(&line);
+                if line.starts_with(&last) || line == last.trim_end() {
+                    break;
+                }
+            }
+        }
+        Ok((code, text))
+    }
+
+    fn command(&mut self, command: &str) -> io::Result<(u16, String)> {
+        self.send(command)?;
+        self.reply()
+    }
+
+    // Sends the command, and fails unless the reply is of the given class, like 2 for `2xx`.
+    fn expect(&mut self, command: &str, class: u16) -> io::Result<String> {
+        match self.command(command)? {
+            (code, text) if code / 100 == class => Ok(text),
+            (code, text) => Err(refused(code, &text)),
+        }
+    }
+
+    // Opens a passive data connection.
+    fn passive(&mut self) -> io::Result<TcpStream> {
+        let ip = self.writer.peer_addr()?.ip();
+        let mut port = None;
+        if self.epsv {
+            match self.command("EPSV")? {
+                (229, text) => port = Some(parse_epsv(&text)?),
+                _ => self.epsv = false,
+            }
+        }
+        let port = match port {
+            Some(port) => port,
+            None => parse_pasv(&self.expect("PASV", 2)?)?,
+        };
+        let data = TcpStream::connect_timeout(&SocketAddr::new(ip, port), self.timeout)?;
+        data.set_read_timeout(Some(self.timeout))?;
+        data.set_write_timeout(Some(self.timeout))?;
+        Ok(data)
+    }
+
+    // Starts a transfer with the given command, like `RETR file`, returning its data connection.
+    fn transfer(&mut self, command: &str) -> io::Result<TcpStream> {
+        let data = self.passive()?;
+        match self.command(command)? {
+            (code, _) if code / 100 == 1 => Ok(data),
+            (code, text) => Err(refused(code, &text)),
+        }
+    }
+
+    // Reads the reply at the end of a transfer, once its data connection is closed.
+    fn finish(&mut self) -> io::Result<()> {
+        match self.reply()? {
+            (code, _) if code / 100 == 2 => Ok(()),
+            (code, text) => Err(refused(code, &text)),
+        }
+    }
+
+    // Starts downloading the file from the given offset. Servers without `REST` send all of it,
+    // so the bytes before the offset have to be skipped.
+    fn retrieve(&mut self, path: &str, offset: u64) -> io::Result<Skip<TcpStream>> {
+        let mut remaining = 0;
+        if offset > 0 {
+            match self.command(&format!("REST {}", offset))? {
+                (350, _) => {}
+                (code, _) if code / 100 == 5 => remaining = offset,
+                (code, text) => return Err(refused(code, &text)),
+            }
+        }
+        let inner = self.transfer(&format!("RETR {}", path))?;
+        Ok(Skip { inner, remaining })
+    }
+
+    fn stat(&mut self, path: &str) -> io::Result<FtpMetadata> {
+        if self.supports("MLST") {
+            let text = self.expect(&format!("MLST {}", path), 2)?;
+            return text
+                .lines()
+                .find(|line| line.starts_with(' '))
+                .and_then(|line| parse_facts(line.trim_start()))
+                .map(|(_, metadata)| metadata)
+                .ok_or_else(malformed);
+        }
+        // Without `MLST`, files are what has a size, and directories what can be changed into.
+        match self.command(&format!("SIZE {}", path))? {
+            (213, size) => {
+                let modified = match self.command(&format!("MDTM {}", path))? {
+                    (213, time) => parse_time(time.trim()),
+                    _ => None,
+                };
+                Ok(FtpMetadata {
+                    size: size.trim().parse().map_err(|_| malformed())?,
+                    modified,
+                    ..FtpMetadata::default()
+                })
+            }
+            _ => {
+                self.expect(&format!("CWD {}", path), 2)?;
+                let home = format!("CWD {}", self.home);
+                self.expect(&home, 2)?;
+                Ok(FtpMetadata {
+                    dir: true,
+                    ..FtpMetadata::default()
+                })
+            }
+        }
+    }
+
+    fn list(&mut self, path: &str) -> io::Result<Vec<(String, FtpMetadata)>> {
+        let mlsd = self.supports("MLSD");
+        let command = if mlsd { "MLSD" } else { "LIST" };
+        let mut data = self.transfer(&format!("{} {}", command, path))?;
+        let mut listing = vec![];
+        let read = data.read_to_end(&mut listing);
+        drop(data);
+        self.finish()?;
+        read?;
+        Ok(String::from_utf8_lossy(&listing)
+            .lines()
+            .filter_map(|line| {
+                if !mlsd {
+                    return parse_list_line(line);
+                }
+                // The entries of the directory itself and its parent are left out.
+                let facts = line.split(' ').next().unwrap_or("").to_ascii_lowercase();
+                if facts.contains("type=cdir;") || facts.contains("type=pdir;") {
+                    return None;
+                }
+                parse_facts(line).map(|(name, metadata)| {
+                    (name.rsplit('/').next().unwrap_or("").into(), metadata)
+                })
+            })
+            .filter(|(name, _)| !name.is_empty() && name != "." && name != "..")
+            .collect())
+    }
+
+    // Moves a file, replacing the one that's there unless told not to. Servers that don't
+    // replace files on `RNTO` get the old one deleted first. Some servers only rename files in
+    // the working directory, so renames within a directory happen from there.
+    fn rename(&mut self, from: &str, to: &str, replace: bool) -> io::Result<()> {
+        match (from.rsplit_once('/'), to.rsplit_once('/')) {
+            (Some((dir, from)), Some((to_dir, to))) if dir == to_dir => {
+                let dir = if dir.is_empty() { "/" } else { dir };
+                self.expect(&format!("CWD {}", dir), 2)?;
+                let renamed = self.rename_here(from, to, replace);
+                let home = format!("CWD {}", self.home);
+                renamed.and(self.expect(&home, 2).map(|_| ()))
+            }
+            _ => self.rename_here(from, to, replace),
+        }
+    }
+
+    fn rename_here(&mut self, from: &str, to: &str, replace: bool) -> io::Result<()> {
+        self.expect(&format!("RNFR {}", from), 3)?;
+        match self.command(&format!("RNTO {}", to))? {
+            (code, _) if code / 100 == 2 => Ok(()),
+            (code, text) if !replace || self.stat(to).is_err() => Err(refused(code, &text)),
+            _ => {
+                self.expect(&format!("DELE {}", to), 2)?;
+                self.expect(&format!("RNFR {}", from), 3)?;
+                self.expect(&format!("RNTO {}", to), 2).map(|_| ())
+            }
+        }
+    }
+}
+
+// The connections to the upstream server that are not in use.
+struct Pool {
+    upstream: Upstream,
+    idle: Mutex<Vec<Session>>,
+    max_idle: usize,
+}
+
+// A session taken from the pool, which goes back when dropped unless it broke.
+struct Pooled {
+    session: Option<Session>,
+    pool: Arc<Pool>,
+    // Whether the session was used before, so it may have gone stale while idle.
+    reused: bool,
+}
+
+impl std::ops::Deref for Pooled {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            if session.broken {
+                return;
+            }
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < self.pool.max_idle {
+                idle.push(session);
+            }
+        }
+    }
+}
+
+impl Pool {
+    fn connect(self: &Arc<Self>) -> io::Result<Pooled> {
+        Ok(Pooled {
+            session: Some(Session::connect(&self.upstream)?),
+            pool: Arc::clone(self),
+            reused: false,
+        })
+    }
+
+    fn take(self: &Arc<Self>) -> io::Result<Pooled> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(session) => Ok(Pooled {
+                session: Some(session),
+                pool: Arc::clone(self),
+                reused: true,
+            }),
+            None => self.connect(),
+        }
+    }
+
+    // Runs the given commands on a session from the pool, returning the session along with the
+    // result. When an idle session turns out to be dead, commands that can safely be sent twice
+    // are retried on a new connection.
+    fn checkout<T, F>(self: &Arc<Self>, idempotent: bool, f: F) -> io::Result<(Pooled, T)>
+    where
+        F: Fn(&mut Session) -> io::Result<T>,
+    {
+        let mut session = self.take()?;
+        match f(&mut session) {
+            Ok(result) => Ok((session, result)),
+            Err(e) if !(session.broken && session.reused && idempotent) => Err(e),
+            Err(e) => {
+                warn!("Reconnecting to the upstream FTP server after: {}", e);
+                drop(session);
+                let mut session = self.connect()?;
+                f(&mut session).map(|result| (session, result))
+            }
+        }
+    }
+}
+
+/// A [`StorageBackend`] that passes every operation on to another FTP server, as its client. That
+/// makes the server a gateway in front of an FTP server that can't be exposed itself, like a
+/// legacy one on an internal network: clients get FTPS and the access control of the
+/// [`Authorizer`], and the [`Audited`] wrapper can log what they do, while the upstream server
+/// only ever sees the gateway.
+///
+/// All sessions log in to the upstream server as the same user, which is `anonymous` unless
+/// [`login`] says otherwise. The control connections are kept in a pool that's shared by the
+/// clones of the backend, the way the SFTP backend does, and every transfer opens a passive data
+/// connection to the address of the control connection, with `EPSV` or else `PASV`. Listings use
+/// `MLSD` where the server has it and `LIST` in the format of `ls -l` where it doesn't, and
+/// ranges start with `REST`, or by skipping bytes on servers without it.
+///
+/// Uploads go to a hidden file next to the target first, which is renamed over it once they're
+/// done, so that dropped uploads never leave partial files behind, as the [cancellation]
+/// contract asks. An append copies the file to that hidden file before the new bytes, since FTP
+/// has no way to undo an `APPE`. Whether `put_unique` finds an existing file is only checked
+/// before the upload starts. `presign` isn't supported, and neither is `set_mtime` on servers
+/// without `MFMT`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::ftp::FtpStorageBackend;
+///
+/// let upstream = FtpStorageBackend::new("legacy.internal:21")
+///     .login("gateway", "secret")
+///     .root("/export");
+/// let server = Server::new(Box::new(move || upstream.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`Authorizer`]: ../../auth/authorization/trait.Authorizer.html
+/// [`Audited`]: ../audit/struct.Audited.html
+/// [`login`]: #method.login
+/// [cancellation]: ../trait.StorageBackend.html#cancellation
+#[derive(Clone)]
+pub struct FtpStorageBackend {
+    pool: Arc<Pool>,
+    // The remote path that the root of the gateway maps to. When empty, it's the directory that
+    // the upstream server starts in.
+    root: String,
+}
+
+impl FtpStorageBackend {
+    /// Create a backend for the FTP server at the given address, like `ftp.internal:21`.
+    pub fn new<A: Into<String>>(address: A) -> Self {
+        FtpStorageBackend {
+            pool: Arc::new(Pool {
+                upstream: Upstream {
+                    address: address.into(),
+                    username: "anonymous".to_string(),
+                    password: "firetrap@".to_string(),
+                    timeout: DEFAULT_TIMEOUT,
+                },
+                idle: Mutex::new(vec![]),
+                max_idle: DEFAULT_MAX_IDLE,
+            }),
+            root: String::new(),
+        }
+    }
+
+    /// Log in to the upstream server as the given user, instead of anonymously. Call this before
+    /// cloning the backend, since the clones share the pool.
+    pub fn login<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        if let Some(pool) = Arc::get_mut(&mut self.pool) {
+            pool.upstream.username = username.into();
+            pool.upstream.password = password.into();
+        }
+        self
+    }
+
+    /// Map the root of the gateway to the given path on the upstream server, instead of the
+    /// directory that the upstream server starts in.
+    pub fn root<P: Into<String>>(mut self, root: P) -> Self {
+        let root = root.into();
+        self.root = root.trim_end_matches('/').to_string();
+        if self.root.is_empty() && !root.is_empty() {
+            self.root.push('/');
+        }
+        self
+    }
+
+    /// Set how long connecting to the upstream server, and waiting for it, may take before an
+    /// operation fails. The default is [`DEFAULT_TIMEOUT`]. Call this before cloning the backend.
+    ///
+    /// [`DEFAULT_TIMEOUT`]: ./constant.DEFAULT_TIMEOUT.html
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        if let Some(pool) = Arc::get_mut(&mut self.pool) {
+            pool.upstream.timeout = timeout;
+        }
+        self
+    }
+
+    /// Set the number of idle connections to keep around for later operations. The default is
+    /// [`DEFAULT_MAX_IDLE`]. Call this before cloning the backend, since the clones share the pool.
+    ///
+    /// [`DEFAULT_MAX_IDLE`]: ./constant.DEFAULT_MAX_IDLE.html
+    pub fn max_idle(mut self, max_idle: usize) -> Self {
+        if let Some(pool) = Arc::get_mut(&mut self.pool) {
+            pool.max_idle = max_idle;
+        }
+        self
+    }
+
+    // The path relative to the root of the gateway, with sequences like '../' resolved. Line
+    // breaks would end the commands they're in, so paths with them are refused.
+    fn key<P: AsRef<Path>>(path: P) -> super::Result<PathBuf> {
+        let mut key = PathBuf::new();
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => {
+                    if name.to_string_lossy().contains(['\r', '\n']) {
+                        return Err(Error::backend("File names can't have line breaks"));
+                    }
+                    key.push(name)
+                }
+                Component::ParentDir => {
+                    if !key.pop() {
+                        return Err(Error::from(ErrorKind::PermissionDenied));
+                    }
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::Prefix(_) => return Err(Error::from(ErrorKind::PermissionDenied)),
+            }
+        }
+        Ok(key)
+    }
+
+    // The path on the upstream server.
+    fn remote(&self, key: &Path) -> String {
+        let key = key.to_string_lossy();
+        match (self.root.as_str(), key.is_empty()) {
+            ("", true) => ".".to_string(),
+            ("", false) => key.into_owned(),
+            ("/", _) => format!("/{}", key),
+            (root, true) => root.to_string(),
+            (root, false) => format!("{}/{}", root, key),
+        }
+    }
+
+    fn remote_path<P: AsRef<Path>>(&self, path: P) -> super::Result<String> {
+        Self::key(path).map(|key| self.remote(&key))
+    }
+
+    // Runs the given commands on the blocking section of the tokio threadpool.
+    fn run<T, F>(
+        &self,
+        path: super::Result<String>,
+        idempotent: bool,
+        f: F,
+    ) -> Box<dyn Future<Item = T, Error = Error> + Send>
+    where
+        T: Send + 'static,
+        F: Fn(&mut Session, &str) -> io::Result<T> + Send + 'static,
+    {
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let pool = Arc::clone(&self.pool);
+        Box::new(
+            blocking(move || {
+                pool.checkout(idempotent, |session| f(session, &path))
+                    .map(|(_, result)| result)
+            })
+            .map_err(Error::from),
+        )
+    }
+
+    fn open(
+        &self,
+        path: super::Result<String>,
+        range: std::ops::Range<u64>,
+    ) -> Box<dyn Future<Item = FtpFile, Error = Error> + Send> {
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let pool = Arc::clone(&self.pool);
+        Box::new(
+            blocking(move || {
+                let (session, data) =
+                    pool.checkout(true, |session| session.retrieve(&path, range.start))?;
+                Ok(FtpFile {
+                    session,
+                    data: Some(data),
+                    remaining: range.end.saturating_sub(range.start),
+                    finishing: true,
+                })
+            })
+            .map_err(Error::from),
+        )
+    }
+
+    fn upload<P: AsRef<Path>, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+        how: Upload,
+    ) -> Box<dyn Future<Item = u64, Error = Error> + Send> {
+        match self.remote_path(path) {
+            Ok(path) => Box::new(
+                Uploading {
+                    reader: bytes,
+                    pool: Arc::clone(&self.pool),
+                    path,
+                    how,
+                    file: None,
+                    written: 0,
+                    buffer: vec![0; CHUNK_SIZE],
+                    filled: 0,
+                    done: false,
+                }
+                .map_err(Error::from),
+            ),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+// Runs blocking commands from within a future or reader, on the blocking section of the tokio
+// threadpool.
+fn block<T, F: FnOnce() -> io::Result<T>>(f: F) -> Poll<T, io::Error> {
+    match tokio_threadpool::blocking(f) {
+        Ok(Async::Ready(Ok(result))) => Ok(Async::Ready(result)),
+        Ok(Async::Ready(Err(e))) => Err(e),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// A file being downloaded from the upstream FTP server.
+pub struct FtpFile {
+    session: Pooled,
+    data: Option<Skip<TcpStream>>,
+    remaining: u64,
+    // Whether the reply at the end of the transfer is still to be read.
+    finishing: bool,
+}
+
+impl Read for FtpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(data) = &mut self.data {
+            let len = buf
+                .len()
+                .min(self.remaining.min(usize::MAX as u64) as usize);
+            if len == 0 {
+                return Ok(0);
+            }
+            match block(|| data.read(&mut buf[..len]))? {
+                Async::Ready(0) => self.data = None,
+                Async::Ready(n) => {
+                    self.remaining -= n as u64;
+                    return Ok(n);
+                }
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        if self.finishing {
+            let session = &mut self.session;
+            match block(|| session.finish())? {
+                Async::Ready(()) => self.finishing = false,
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl AsyncRead for FtpFile {}
+
+impl Drop for FtpFile {
+    fn drop(&mut self) {
+        // The transfer was cut short, and who knows what the server makes of that.
+        if self.finishing {
+            self.session.broken = true;
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Upload {
+    Put,
+    PutUnique,
+    Append,
+}
+
+// An upload to the hidden file next to the target, which is removed unless it's finished.
+struct Writing {
+    session: Pooled,
+    data: Option<TcpStream>,
+    temporary: String,
+    done: bool,
+}
+
+impl Writing {
+    // Waits for the server to take all the bytes, and then moves the file to where it belongs.
+    fn finish(&mut self, path: &str, replace: bool) -> io::Result<()> {
+        if let Some(data) = self.data.take() {
+            data.shutdown(Shutdown::Write)?;
+            drop(data);
+            self.session.finish()?;
+        }
+        self.session.rename(&self.temporary, path, replace)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if self.data.take().is_some() {
+            let _ = self.session.reply();
+        }
+        let dele = format!("DELE {}", self.temporary);
+        let removed = if self.session.broken {
+            self.session
+                .pool
+                .connect()
+                .and_then(|mut session| session.expect(&dele, 2))
+        } else {
+            self.session.expect(&dele, 2)
+        };
+        if let Err(e) = removed {
+            warn!(
+                "Failed to remove {} after an unfinished upload: {}",
+                self.temporary, e
+            );
+        }
+    }
+}
+
+// Sends the bytes of the reader to the upstream server as they arrive.
+struct Uploading<R> {
+    reader: R,
+    pool: Arc<Pool>,
+    path: String,
+    how: Upload,
+    file: Option<Writing>,
+    written: u64,
+    buffer: Vec<u8>,
+    filled: usize,
+    done: bool,
+}
+
+impl<R> Uploading<R> {
+    fn open(&self) -> io::Result<Writing> {
+        let (dir, name) = match self.path.rsplit_once('/') {
+            Some((dir, name)) => (format!("{}/", dir), name),
+            None => (String::new(), self.path.as_str()),
+        };
+        let temporary = format!("{}.{}.{:016x}.part", dir, name, rand::random::<u64>());
+        let (session, data) = self.pool.checkout(true, |session| {
+            if self.how == Upload::PutUnique {
+                match session.stat(&self.path) {
+                    Ok(_) => return Err(io::ErrorKind::AlreadyExists.into()),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            session.transfer(&format!("STOR {}", temporary))
+        })?;
+        let mut file = Writing {
+            session,
+            data: Some(data),
+            temporary,
+            done: false,
+        };
+        if self.how == Upload::Append {
+            match self
+                .pool
+                .checkout(true, |source| source.retrieve(&self.path, 0))
+            {
+                Ok((mut source, mut original)) => {
+                    let copied = io::copy(&mut original, file.data.as_mut().unwrap());
+                    drop(original);
+                    let finished = source.finish();
+                    copied?;
+                    finished?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(file)
+    }
+}
+
+impl<R: AsyncRead> Future for Uploading<R> {
+    type Item = u64;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<u64, io::Error> {
+        if self.file.is_none() {
+            let file = futures::try_ready!(block(|| self.open()));
+            self.file = Some(file);
+        }
+        loop {
+            if self.filled == 0 && !self.done {
+                match self.reader.poll_read(&mut self.buffer)? {
+                    Async::Ready(0) => self.done = true,
+                    Async::Ready(n) => self.filled = n,
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+            let file = self.file.as_mut().unwrap();
+            if self.filled > 0 {
+                let data = file.data.as_mut().unwrap();
+                let chunk = &self.buffer[..self.filled];
+                futures::try_ready!(block(|| data.write_all(chunk)));
+                self.written += self.filled as u64;
+                self.filled = 0;
+            } else if self.done {
+                let (path, replace) = (&self.path, self.how != Upload::PutUnique);
+                futures::try_ready!(block(|| file.finish(path, replace)));
+                self.file = None;
+                return Ok(Async::Ready(self.written));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FtpStorageBackend {
+    type File = FtpFile;
+    type Metadata = FtpMetadata;
+    type Error = Error;
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.run(self.remote_path(path), true, |session, path| {
+            session.stat(path)
+        })
+        .compat()
+        .await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let key = match Self::key(path) {
+            Ok(key) => key,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let remote = Ok(self.remote(&key));
+        Box::new(
+            self.run(remote, true, |session, path| session.list(path))
+                .map(move |entries| {
+                    stream::iter_ok(entries.into_iter().map(move |(name, metadata)| Fileinfo {
+                        path: key.join(name),
+                        metadata,
+                    }))
+                })
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.open(self.remote_path(path), 0..u64::MAX)
+            .compat()
+            .await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error> {
+        let file = self.open(self.remote_path(path), range).compat().await?;
+        Ok(Box::new(file))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::Put).compat().await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::PutUnique).compat().await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::Append).compat().await
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            session.expect(&format!("DELE {}", path), 2).map(|_| ())
+        })
+        .compat()
+        .await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            session.expect(&format!("MKD {}", path), 2).map(|_| ())
+        })
+        .compat()
+        .await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            session.expect(&format!("RMD {}", path), 2).map(|_| ())
+        })
+        .compat()
+        .await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let to = self.remote_path(to)?;
+        self.run(self.remote_path(from), false, move |session, from| {
+            session.rename(from, &to, false)
+        })
+        .compat()
+        .await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let mtime = format_time(mtime);
+        self.run(self.remote_path(path), true, move |session, path| {
+            if !session.supports("MFMT") {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "The upstream FTP server can't set modification times",
+                ));
+            }
+            session
+                .expect(&format!("MFMT {} {}", mtime, path), 2)
+                .map(|_| ())
+        })
+        .compat()
+        .await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), true, move |session, path| {
+            session
+                .expect(&format!("SITE CHMOD {:o} {}", mode & 0o7777, path), 2)
+                .map(|_| ())
+        })
+        .compat()
+        .await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let range = range.unwrap_or(0..u64::MAX);
+        self.run(self.remote_path(path), true, move |session, path| {
+            let data = session.retrieve(path, range.start)?;
+            let mut hasher = algorithm.hasher();
+            let mut limited = data.take(range.end - range.start);
+            let mut chunk = vec![0; CHUNK_SIZE];
+            loop {
+                match limited.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => hasher.update(&chunk[..n]),
+                    Err(e) => {
+                        session.broken = true;
+                        return Err(e);
+                    }
+                }
+            }
+            let mut data = limited.into_inner().inner;
+            // Anything left means the range ended before the file did.
+            if data.read(&mut [0]).map_or(true, |n| n > 0) {
+                session.broken = true;
+                return Ok(hasher.finish());
+            }
+            drop(data);
+            session.finish().map(|_| hasher.finish())
+        })
+        .compat()
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    // Starts firetrap itself on the given port as the upstream server, with its files in a new
+    // temporary directory.
+    fn upstream(port: u16) -> (tempfile::TempDir, FtpStorageBackend) {
+        let root = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir(root.path().join("srv")).unwrap();
+        let server_root = root.path().to_path_buf();
+        let addr = format!("127.0.0.1:{}", port);
+        let listen = addr.clone();
+        std::thread::spawn(move || crate::Server::with_root(server_root).listen(&listen));
+        std::thread::sleep(Duration::from_millis(100));
+        (root, FtpStorageBackend::new(addr).root("/srv"))
+    }
+
+    fn contents(backend: &FtpStorageBackend, path: &str) -> Vec<u8> {
+        let file = block_on(backend.get(path)).unwrap();
+        block_on(tokio_io::io::read_to_end(file, vec![]).compat())
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn parses_listings() {
+        let (name, metadata) =
+            parse_list_line("drwxr-x---   2 1000  100     4096 Mar 14  2019 my dir").unwrap();
+        assert_eq!(name, "my dir");
+        assert!(metadata.is_dir());
+        assert_eq!((metadata.uid(), metadata.gid()), (1000, 100));
+        assert_eq!(metadata.permissions(), Some(0o750));
+        assert_eq!(
+            metadata.modified().unwrap(),
+            parse_time("20190314000000").unwrap()
+        );
+        let (name, metadata) =
+            parse_list_line("lrwxrwxrwx 1 ftp ftp 7 Jan 01 12:00 latest -> v1.2.3").unwrap();
+        assert_eq!(name, "latest");
+        assert!(metadata.is_symlink());
+        // What firetrap itself lists, without a link count.
+        let (name, metadata) =
+            parse_list_line("-rwxr-xr-x     0 0 12 Jan 01 2020 file.txt").unwrap();
+        assert_eq!((name.as_str(), metadata.len()), ("file.txt", 12));
+        assert_eq!(parse_list_line("total 8"), None);
+
+        let (name, metadata) =
+            parse_facts("type=file;Size=10;modify=20200101120000.5;UNIX.mode=0644; a b").unwrap();
+        assert_eq!((name.as_str(), metadata.len()), ("a b", 10));
+        assert_eq!(metadata.permissions(), Some(0o644));
+        assert_eq!(
+            metadata.modified().unwrap(),
+            parse_time("20200101120000").unwrap()
+        );
+
+        assert_eq!(
+            parse_epsv("Entering Extended Passive Mode (|||6446|)").unwrap(),
+            6446
+        );
+        assert_eq!(
+            parse_pasv("Entering Passive Mode (10,0,0,1,25,46)").unwrap(),
+            25 * 256 + 46
+        );
+    }
+
+    #[test]
+    fn transfers_files() {
+        compat::on_runtime(move || {
+            let (root, backend) = upstream(1318);
+            let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+            let written = block_on(backend.put(Cursor::new(data.clone()), "/big.bin")).unwrap();
+            assert_eq!(written, 100_000);
+            assert_eq!(contents(&backend, "big.bin"), data);
+            assert_eq!(block_on(backend.stat("/big.bin")).unwrap().len(), 100_000);
+
+            block_on(backend.append(Cursor::new(b"!".to_vec()), "/big.bin")).unwrap();
+            assert_eq!(block_on(backend.stat("/big.bin")).unwrap().len(), 100_001);
+
+            let file = block_on(backend.get_range("/big.bin", 99_999..100_001)).unwrap();
+            let range = block_on(tokio_io::io::read_to_end(file, vec![]).compat())
+                .unwrap()
+                .1;
+            assert_eq!(range, vec![data[99_999], b'!']);
+            assert!(block_on(backend.put_unique(Cursor::new(vec![]), "/big.bin")).is_err());
+            assert_eq!(
+                block_on(backend.checksum("/big.bin", HashAlgorithm::Md5, Some(0..3))).unwrap(),
+                block_on(
+                    crate::storage::Filesystem::new(root.path().join("srv")).checksum(
+                        "big.bin",
+                        HashAlgorithm::Md5,
+                        Some(0..3)
+                    )
+                )
+                .unwrap()
+            );
+            // No hidden files are left behind.
+            let names: Vec<_> = std::fs::read_dir(root.path().join("srv"))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, vec!["big.bin"]);
+        });
+    }
+
+    #[test]
+    fn manages_files() {
+        compat::on_runtime(move || {
+            let (root, backend) = upstream(1319);
+            block_on(backend.mkd("/dir")).unwrap();
+            block_on(backend.put(Cursor::new(b"a".to_vec()), "/dir/a.txt")).unwrap();
+            block_on(backend.rename("/dir/a.txt", "/dir/b c.txt")).unwrap();
+            let listed: Vec<_> = block_on(backend.list("/dir").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|file| (file.path, file.metadata.len(), file.metadata.is_file()))
+                .collect();
+            assert_eq!(listed, vec![(PathBuf::from("dir/b c.txt"), 1, true)]);
+            assert!(block_on(backend.stat("/dir")).unwrap().is_dir());
+            assert_eq!(
+                block_on(backend.stat("/dir/../..")).unwrap_err(),
+                Error::from(ErrorKind::PermissionDenied)
+            );
+            assert_eq!(
+                block_on(backend.stat("/dir/b\r\nDELE c.txt")).unwrap_err(),
+                Error::backend("File names can't have line breaks")
+            );
+            let mtime = parse_time("20200101120000").unwrap();
+            block_on(backend.set_mtime("/dir/b c.txt", mtime)).unwrap();
+            assert_eq!(
+                block_on(backend.stat("/dir/b c.txt"))
+                    .unwrap()
+                    .modified()
+                    .unwrap(),
+                mtime
+            );
+            block_on(backend.del("/dir/b c.txt")).unwrap();
+            assert_eq!(
+                block_on(backend.del("/dir/b c.txt")).unwrap_err(),
+                Error::from(ErrorKind::NotFound)
+            );
+            block_on(backend.rmd("/dir")).unwrap();
+            assert!(!root.path().join("srv/dir").exists());
+            // Everything went over the one connection.
+            assert_eq!(backend.pool.idle.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn cancellation() {
+        let (root, backend) = upstream(1320);
+        crate::storage::conformance::check_cancellation(backend);
+        let mut names: Vec<_> = std::fs::read_dir(root.path().join("srv"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["appended.txt", "replaced.txt"]);
+    }
+}
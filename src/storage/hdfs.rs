@@ -0,0 +1,878 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use futures::{future, stream, Future, Stream};
+use futures03::compat::Future01CompatExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use log::warn;
+use serde::Deserialize;
+use tokio::prelude::AsyncRead;
+use tokio_codec::{BytesCodec, FramedRead};
+
+use super::cloud::uri_encode;
+pub use super::cloud::Object;
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// The [`Metadata`] of a file or directory in HDFS, from its `FileStatus`.
+///
+/// [`Metadata`]: ../trait.Metadata.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdfsMetadata {
+    len: u64,
+    dir: bool,
+    symlink: bool,
+    modified: SystemTime,
+    permissions: u32,
+    owner: String,
+    group: String,
+}
+
+impl HdfsMetadata {
+    /// Returns the permission bits of the file.
+    pub fn permissions(&self) -> u32 {
+        self.permissions
+    }
+
+    /// Returns the name of the user that owns the file.
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// Returns the name of the group of the file.
+    pub fn group(&self) -> &str {
+        &self.group
+    }
+}
+
+impl Metadata for HdfsMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir && !self.symlink
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        Ok(self.modified)
+    }
+
+    // HDFS owners and groups are names, not IDs.
+    fn gid(&self) -> u32 {
+        0
+    }
+
+    fn uid(&self) -> u32 {
+        0
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    // The scheme and authority of the NameNode, or of an HttpFS gateway, like
+    // `http://namenode:9870`.
+    endpoint: String,
+    // The HDFS directory that the root of the FTP server maps to, without a trailing slash, like
+    // `/user/ftp`.
+    root: String,
+    // The user the requests are made as, with simple authentication.
+    user: Option<String>,
+    delegation: Option<String>,
+    // Whether to make the requests on behalf of the FTP user that logged in.
+    impersonate: bool,
+}
+
+/// A [`StorageBackend`] that passes everything on to HDFS, through the WebHDFS REST API of the
+/// NameNode or of an HttpFS gateway, so that partners that only speak FTP can get at the files
+/// in a Hadoop cluster.
+///
+/// The root of the FTP server maps to the HDFS directory at the URL the backend is created with.
+/// Downloads and uploads follow the redirects of the NameNode to the DataNodes, and uploads are
+/// streamed there while the client is still uploading. `APPE` appends to the file in HDFS, where
+/// the cluster allows it: where it doesn't, like in erasure coded directories, `APPE` fails.
+/// `MFMT` and `SITE CHMOD` change the modification time and permissions of the file in HDFS.
+/// HDFS doesn't replace files when renaming, so renaming onto a file that exists fails. Files
+/// that the user mayn't touch make commands fail with `550 Permission denied`, and exceeded space
+/// quotas make uploads fail with `552`.
+///
+/// The requests use simple authentication, as the [`user`] of the backend, or with a
+/// [`delegation_token`]. Kerberos (SPNEGO) isn't supported: get a delegation token with
+/// `hdfs fetchdt` instead. Every backend has its own HTTP client with its own connection pool, so
+/// create it once and hand every session a clone.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::hdfs::HdfsStorageBackend;
+///
+/// let hdfs = HdfsStorageBackend::new("http://namenode:9870/data/partners").user("ftp");
+/// let server = Server::new(Box::new(move || hdfs.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`user`]: #method.user
+/// [`delegation_token`]: #method.delegation_token
+#[derive(Clone, Debug)]
+pub struct HdfsStorageBackend {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<Config>,
+    // The FTP user the requests are made on behalf of, with `impersonate`.
+    doas: Option<String>,
+    // Set by `transfer_id`: sent along with the requests, from the start of a transfer until the
+    // start of the next one.
+    transfer_id: Arc<Mutex<Option<String>>>,
+}
+
+impl HdfsStorageBackend {
+    /// Create a backend for the HDFS directory at the given WebHDFS URL, like
+    /// `http://namenode:9870/user/ftp`, without `/webhdfs/v1`. Without a path, the root of the
+    /// FTP server is the root of HDFS.
+    pub fn new<U: AsRef<str>>(url: U) -> Self {
+        let url = url.as_ref().trim_end_matches('/');
+        let authority = url.find("://").map_or(0, |start| start + 3);
+        let (endpoint, root) = match url[authority..].find('/') {
+            Some(path) => url.split_at(authority + path),
+            None => (url, ""),
+        };
+        let root = root.strip_prefix("/webhdfs/v1").unwrap_or(root);
+        HdfsStorageBackend {
+            client: Client::builder().build(HttpsConnector::new(4)),
+            config: Arc::new(Config {
+                endpoint: endpoint.to_string(),
+                root: root.to_string(),
+                user: None,
+                delegation: None,
+                impersonate: false,
+            }),
+            doas: None,
+            transfer_id: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Make the requests as the given HDFS user, with simple authentication. Without one, the
+    /// cluster decides who the requests are made as, like `dr.who` by default.
+    pub fn user<U: Into<String>>(mut self, user: U) -> Self {
+        Arc::make_mut(&mut self.config).user = Some(user.into());
+        self
+    }
+
+    /// Authenticate the requests with the given delegation token, like `hdfs fetchdt` gets from
+    /// a cluster that uses Kerberos.
+    pub fn delegation_token<T: Into<String>>(mut self, token: T) -> Self {
+        Arc::make_mut(&mut self.config).delegation = Some(token.into());
+        self
+    }
+
+    /// Make the requests of every session on behalf of the FTP user that logged in, so that HDFS
+    /// checks their permissions instead of those of the [`user`] of the backend. The user of the
+    /// backend must be allowed to impersonate them, with the `hadoop.proxyuser` settings of the
+    /// cluster.
+    ///
+    /// [`user`]: #method.user
+    pub fn impersonate(mut self) -> Self {
+        Arc::make_mut(&mut self.config).impersonate = true;
+        self
+    }
+
+    // The absolute HDFS path of the file at the given path. `..` never leaves the root.
+    fn name<P: AsRef<Path>>(&self, path: P) -> String {
+        let mut names: Vec<String> = vec![];
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                Component::ParentDir => {
+                    names.pop();
+                }
+                _ => {}
+            }
+        }
+        format!("{}/{}", self.config.root, names.join("/"))
+    }
+
+    // The URL of the given operation on the file with the given HDFS path, with the given
+    // parameters.
+    fn url(&self, name: &str, op: &str, params: &[(&str, String)]) -> String {
+        let mut url = format!(
+            "{}/webhdfs/v1{}?op={}",
+            self.config.endpoint,
+            uri_encode(name, false),
+            op
+        );
+        let auth = vec![
+            ("delegation", self.config.delegation.clone()),
+            ("user.name", self.config.user.clone()),
+            ("doas", self.doas.clone()),
+        ];
+        let auth = auth
+            .into_iter()
+            .filter_map(|(param, value)| Some((param, value?)));
+        for (param, value) in params.iter().cloned().chain(auth) {
+            url.push_str(&format!("&{}={}", param, uri_encode(&value, true)));
+        }
+        url
+    }
+
+    fn send(&self, method: Method, uri: &str, body: Body) -> BoxFuture<Response<Body>> {
+        let mut builder = Request::builder();
+        // HttpFS insists on it for the bytes of uploads, and the NameNode doesn't mind.
+        if method == Method::PUT || method == Method::POST {
+            builder.header(hyper::header::CONTENT_TYPE, "application/octet-stream");
+        }
+        builder.method(method).uri(uri);
+        let agent = format!("firetrap/{}", env!("CARGO_PKG_VERSION"));
+        match &*self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(id) => builder.header(
+                hyper::header::USER_AGENT,
+                format!("{} transfer/{}", agent, id),
+            ),
+            None => builder.header(hyper::header::USER_AGENT, agent),
+        };
+        let http_request = match builder.body(body) {
+            Ok(http_request) => http_request,
+            Err(e) => {
+                warn!("Invalid WebHDFS request: {}", e);
+                return Box::new(future::err(Error::backend(e)));
+            }
+        };
+        Box::new(self.client.request(http_request).map_err(|e| {
+            warn!("WebHDFS request failed: {}", e);
+            Error::backend(e)
+        }))
+    }
+
+    // Calls an operation that the NameNode handles by itself, resolving to its JSON reply.
+    fn call<T>(
+        &self,
+        method: Method,
+        name: &str,
+        op: &str,
+        params: &[(&str, String)],
+    ) -> BoxFuture<T>
+    where
+        T: for<'de> Deserialize<'de> + Send + 'static,
+    {
+        let url = self.url(name, op, params);
+        Box::new(
+            self.send(method, &url, Body::empty())
+                .and_then(success)
+                .and_then(json),
+        )
+    }
+
+    // Calls an operation that returns `{"boolean": ...}`, failing when it's false.
+    fn call_true(
+        &self,
+        method: Method,
+        name: &str,
+        op: &str,
+        params: &[(&str, String)],
+    ) -> BoxFuture<()> {
+        let op = op.to_string();
+        Box::new(
+            self.call(method, name, &op, params)
+                .and_then(move |reply: BooleanReply| {
+                    if reply.boolean {
+                        Ok(())
+                    } else {
+                        warn!("WebHDFS refused to {}", op);
+                        Err(Error::backend(format!("WebHDFS refused to {}", op)))
+                    }
+                }),
+        )
+    }
+
+    // Calls an operation that the NameNode redirects to a DataNode, which the body is sent to.
+    fn redirected(
+        &self,
+        method: Method,
+        name: &str,
+        op: &str,
+        params: &[(&str, String)],
+        body: Body,
+    ) -> BoxFuture<Response<Body>> {
+        let backend = self.clone();
+        let url = self.url(name, op, params);
+        Box::new(self.send(method.clone(), &url, Body::empty()).and_then(
+            move |response| -> BoxFuture<Response<Body>> {
+                // Only downloads may come straight from the NameNode: an upload that isn't
+                // redirected wouldn't be stored anywhere.
+                if !response.status().is_redirection() && method == Method::GET {
+                    return success(response);
+                }
+                if !response.status().is_redirection() {
+                    return Box::new(success(response).and_then(|_| {
+                        warn!("WebHDFS didn't redirect the upload to a DataNode");
+                        Err(Error::backend(
+                            "WebHDFS didn't redirect the upload to a DataNode",
+                        ))
+                    }));
+                }
+                let location = response
+                    .headers()
+                    .get(hyper::header::LOCATION)
+                    .and_then(|location| location.to_str().ok());
+                match location {
+                    Some(location) => {
+                        Box::new(backend.send(method, location, body).and_then(success))
+                    }
+                    None => {
+                        warn!("WebHDFS redirected without a location");
+                        Box::new(future::err(Error::backend(
+                            "WebHDFS redirected without a location",
+                        )))
+                    }
+                }
+            },
+        ))
+    }
+
+    // Uploads the bytes to the DataNode that the given operation is redirected to, while they
+    // come in.
+    fn upload<R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        method: Method,
+        name: &str,
+        op: &str,
+        params: &[(&str, String)],
+    ) -> BoxFuture<u64> {
+        let sent = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&sent);
+        let chunks = FramedRead::new(bytes, BytesCodec::new()).map(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            chunk.freeze()
+        });
+        Box::new(
+            self.redirected(method, name, op, params, Body::wrap_stream(chunks))
+                .map(move |_| sent.load(Ordering::SeqCst)),
+        )
+    }
+
+    fn create<R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        name: &str,
+        overwrite: bool,
+    ) -> BoxFuture<u64> {
+        self.upload(
+            bytes,
+            Method::PUT,
+            name,
+            "CREATE",
+            &[("overwrite", overwrite.to_string())],
+        )
+    }
+
+    fn open(&self, name: &str, range: Option<std::ops::Range<u64>>) -> BoxFuture<Object> {
+        let params = match &range {
+            Some(range) if range.start >= range.end => {
+                return Box::new(future::ok(Object::new(Body::empty())));
+            }
+            Some(range) => vec![
+                ("offset", range.start.to_string()),
+                ("length", (range.end - range.start).to_string()),
+            ],
+            None => vec![],
+        };
+        Box::new(
+            self.redirected(Method::GET, name, "OPEN", &params, Body::empty())
+                .map(|response| Object::new(response.into_body())),
+        )
+    }
+
+    fn status(&self, name: &str) -> BoxFuture<HdfsMetadata> {
+        Box::new(
+            self.call(Method::GET, name, "GETFILESTATUS", &[])
+                .map(|reply: StatusReply| reply.file_status.metadata()),
+        )
+    }
+
+    fn delete(&self, name: &str) -> BoxFuture<()> {
+        self.call_true(
+            Method::DELETE,
+            name,
+            "DELETE",
+            &[("recursive", "false".to_string())],
+        )
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HdfsStorageBackend {
+    type File = Object;
+    type Metadata = HdfsMetadata;
+    type Error = Error;
+
+    fn set_user(&mut self, user: &User) {
+        if self.config.impersonate {
+            self.doas = Some(user.username.clone());
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.status(&self.name(path)).compat().await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let backend = self.clone();
+        let name = self.name(path);
+        // `None` once the last batch is in.
+        let batches = stream::unfold(Some(None), move |after: Option<Option<String>>| {
+            let after = after?;
+            let params: Vec<_> = after
+                .into_iter()
+                .map(|after| ("startAfter", after))
+                .collect();
+            Some(
+                backend
+                    .call(Method::GET, &name, "LISTSTATUS_BATCH", &params)
+                    .map(|reply: ListingReply| reply.directory_listing.into_batch()),
+            )
+        });
+        Box::new(batches.map(stream::iter_ok).flatten())
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.open(&self.name(path), None).compat().await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn AsyncRead + Send>, Self::Error> {
+        let object = self.open(&self.name(path), Some(range)).compat().await?;
+        Ok(Box::new(object))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.create(bytes, &self.name(path), true).compat().await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.create(bytes, &self.name(path), false).compat().await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let name = self.name(path);
+        match self.status(&name).compat().await {
+            Ok(_) => {
+                self.upload(bytes, Method::POST, &name, "APPEND", &[])
+                    .compat()
+                    .await
+            }
+            // Appending to nothing creates the file.
+            Err(_) => self.create(bytes, &name, false).compat().await,
+        }
+    }
+
+    fn transfer_id(&self, id: &str) {
+        *self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(id.to_string());
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        // Deleting deletes empty directories as well.
+        let name = self.name(path);
+        if self.status(&name).compat().await?.is_dir() {
+            return Err(Error::backend("Not a file"));
+        }
+        self.delete(&name).compat().await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.call_true(Method::PUT, &self.name(path), "MKDIRS", &[])
+            .compat()
+            .await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let name = self.name(path);
+        if name == format!("{}/", self.config.root) {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        // Without `recursive`, HDFS refuses to delete directories that aren't empty.
+        if !self.status(&name).compat().await?.is_dir() {
+            return Err(Error::backend("Not a directory"));
+        }
+        self.delete(&name).compat().await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        self.call_true(
+            Method::PUT,
+            &self.name(from),
+            "RENAME",
+            &[("destination", self.name(to))],
+        )
+        .compat()
+        .await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let millis = mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let url = self.url(
+            &self.name(path),
+            "SETTIMES",
+            &[("modificationtime", millis.to_string())],
+        );
+        let response = self.send(Method::PUT, &url, Body::empty()).compat().await?;
+        success(response).compat().await?;
+        Ok(())
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        let url = self.url(
+            &self.name(path),
+            "SETPERMISSION",
+            &[("permission", format!("{:o}", mode & 0o1777))],
+        );
+        let response = self.send(Method::PUT, &url, Body::empty()).compat().await?;
+        success(response).compat().await?;
+        Ok(())
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        // `GETFILECHECKSUM` is an MD5 of the MD5s of the CRCs of the blocks, which no client knows.
+        let file = self.open(&self.name(path), range).compat().await?;
+        let hasher = FramedRead::new(file, BytesCodec::new())
+            .fold(algorithm.hasher(), |mut hasher, chunk| {
+                hasher.update(&chunk);
+                Ok::<_, io::Error>(hasher)
+            })
+            .compat()
+            .await
+            .map_err(|e| {
+                warn!("Failed to read the file from HDFS: {}", e);
+                Error::backend(e)
+            })?;
+        Ok(hasher.finish())
+    }
+}
+
+// The error for the exception that WebHDFS replied with.
+fn error(status: StatusCode, exception: &str) -> Error {
+    let kind = match exception {
+        "AccessControlException" | "SecurityException" | "AuthorizationException" => {
+            ErrorKind::PermissionDenied
+        }
+        "DSQuotaExceededException" | "NSQuotaExceededException" | "QuotaExceededException" => {
+            ErrorKind::Quota
+        }
+        "ClusterStorageCapacityExceededException" => ErrorKind::StorageFull,
+        "FileNotFoundException" => ErrorKind::NotFound,
+        "FileAlreadyExistsException" => ErrorKind::Exists,
+        "PathIsNotEmptyDirectoryException" => ErrorKind::NotEmpty,
+        _ if status == StatusCode::UNAUTHORIZED => ErrorKind::PermissionDenied,
+        _ if status == StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        _ => ErrorKind::Backend,
+    };
+    Error::new(
+        kind,
+        format!("WebHDFS replied with {}: {}", status, exception),
+    )
+}
+
+// Resolves to the response if it has a success code, or logs the exception WebHDFS replied with.
+fn success(response: Response<Body>) -> BoxFuture<Response<Body>> {
+    if response.status().is_success() {
+        return Box::new(future::ok(response));
+    }
+    let status = response.status();
+    Box::new(response.into_body().concat2().then(move |body| {
+        let exception = body
+            .ok()
+            .and_then(|body| serde_json::from_slice::<ExceptionReply>(&body).ok())
+            .map(|reply| reply.remote_exception)
+            .unwrap_or_default();
+        warn!(
+            "WebHDFS replied with {}: {} ({})",
+            status, exception.message, exception.exception
+        );
+        Err(error(status, &exception.exception))
+    }))
+}
+
+// Resolves to the JSON body of the response.
+fn json<T>(response: Response<Body>) -> BoxFuture<T>
+where
+    T: for<'de> Deserialize<'de> + Send + 'static,
+{
+    Box::new(
+        response
+            .into_body()
+            .concat2()
+            .map_err(|e| {
+                warn!("Failed to read the WebHDFS reply: {}", e);
+                Error::backend(e)
+            })
+            .and_then(|body| {
+                serde_json::from_slice(&body).map_err(|e| {
+                    warn!("Invalid WebHDFS reply: {}", e);
+                    Error::backend(e)
+                })
+            }),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExceptionReply {
+    remote_exception: RemoteException,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RemoteException {
+    // The simple name of the Java exception, like `FileNotFoundException`.
+    exception: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct BooleanReply {
+    boolean: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StatusReply {
+    file_status: FileStatus,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListingReply {
+    directory_listing: DirectoryListing,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryListing {
+    partial_listing: PartialListing,
+    // How many entries there are after this batch.
+    remaining_entries: u64,
+}
+
+impl DirectoryListing {
+    // The entries of the batch, and what the next batch starts after, if there is one.
+    fn into_batch(self) -> (Vec<Fileinfo<PathBuf, HdfsMetadata>>, Option<Option<String>>) {
+        let statuses = self.partial_listing.file_statuses.file_status;
+        let next = match statuses.last() {
+            Some(last) if self.remaining_entries > 0 => Some(Some(last.path_suffix.clone())),
+            _ => None,
+        };
+        let fileinfos = statuses
+            .into_iter()
+            .map(|status| Fileinfo {
+                path: PathBuf::from(&status.path_suffix),
+                metadata: status.metadata(),
+            })
+            .collect();
+        (fileinfos, next)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PartialListing {
+    file_statuses: FileStatuses,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+struct FileStatuses {
+    file_status: Vec<FileStatus>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct FileStatus {
+    // The name of the file in a listing, empty for the file itself.
+    path_suffix: String,
+    // `FILE`, `DIRECTORY` or `SYMLINK`.
+    #[serde(rename = "type")]
+    kind: String,
+    length: u64,
+    // In milliseconds since the epoch.
+    modification_time: u64,
+    // In octal, like `644`.
+    permission: String,
+    owner: String,
+    group: String,
+}
+
+impl FileStatus {
+    fn metadata(&self) -> HdfsMetadata {
+        HdfsMetadata {
+            len: self.length,
+            dir: self.kind == "DIRECTORY",
+            symlink: self.kind == "SYMLINK",
+            modified: SystemTime::UNIX_EPOCH + Duration::from_millis(self.modification_time),
+            permissions: u32::from_str_radix(&self.permission, 8).unwrap_or(0),
+            owner: self.owner.clone(),
+            group: self.group.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn maps_paths_to_urls() {
+        let hdfs = HdfsStorageBackend::new("http://namenode:9870/webhdfs/v1/data/partners/");
+        assert_eq!(
+            hdfs.name("/../acme/Q&A 2019.csv"),
+            "/data/partners/acme/Q&A 2019.csv"
+        );
+        assert_eq!(
+            hdfs.url(
+                &hdfs.name("/acme/Q&A 2019.csv"),
+                "OPEN",
+                &[("offset", "10".to_string())]
+            ),
+            "http://namenode:9870/webhdfs/v1/data/partners/acme/Q%26A%202019.csv?op=OPEN&offset=10"
+        );
+
+        let mut hdfs = HdfsStorageBackend::new("https://namenode:9871")
+            .user("ftp")
+            .impersonate();
+        hdfs.set_user(&User::new("acme"));
+        assert_eq!(hdfs.name("/"), "/");
+        assert_eq!(
+            hdfs.url("/in", "RENAME", &[("destination", "/out/a b".to_string())]),
+            "https://namenode:9871/webhdfs/v1/in?op=RENAME&destination=%2Fout%2Fa%20b&user.name=ftp&doas=acme"
+        );
+    }
+
+    #[test]
+    fn parses_listings() {
+        let json = r#"{
+  "DirectoryListing": {
+    "partialListing": {
+      "FileStatuses": {
+        "FileStatus": [
+          {"accessTime": 1571127151000, "blockSize": 134217728, "childrenNum": 0, "fileId": 16390,
+           "group": "partners", "length": 1234, "modificationTime": 1571127151969, "owner": "acme",
+           "pathSuffix": "orders.csv", "permission": "640", "replication": 3,
+           "storagePolicy": 0, "type": "FILE"},
+          {"accessTime": 0, "blockSize": 0, "childrenNum": 2, "fileId": 16391,
+           "group": "supergroup", "length": 0, "modificationTime": 1571127000000, "owner": "hdfs",
+           "pathSuffix": "archive", "permission": "1777", "replication": 0,
+           "storagePolicy": 0, "type": "DIRECTORY"}
+        ]
+      }
+    },
+    "remainingEntries": 3
+  }
+}"#;
+        let reply: ListingReply = serde_json::from_str(json).unwrap();
+        let (fileinfos, next) = reply.directory_listing.into_batch();
+        assert_eq!(next, Some(Some("archive".to_string())));
+        assert_eq!(fileinfos[0].path, PathBuf::from("orders.csv"));
+        assert_eq!(
+            fileinfos[0].metadata,
+            HdfsMetadata {
+                len: 1234,
+                dir: false,
+                symlink: false,
+                modified: SystemTime::UNIX_EPOCH + Duration::from_millis(1_571_127_151_969),
+                permissions: 0o640,
+                owner: "acme".to_string(),
+                group: "partners".to_string(),
+            }
+        );
+        assert!(fileinfos[1].metadata.is_dir());
+        assert_eq!(fileinfos[1].metadata.permissions(), 0o1777);
+
+        let last: ListingReply = serde_json::from_str(
+            r#"{"DirectoryListing": {"partialListing": {"FileStatuses": {"FileStatus": []}}, "remainingEntries": 0}}"#,
+        )
+        .unwrap();
+        let (fileinfos, next) = last.directory_listing.into_batch();
+        assert!(fileinfos.is_empty());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn maps_exceptions() {
+        let json = r#"{"RemoteException": {"exception": "DSQuotaExceededException",
+            "javaClassName": "org.apache.hadoop.hdfs.protocol.DSQuotaExceededException",
+            "message": "The DiskSpace quota of /data/partners/acme is exceeded"}}"#;
+        let reply: ExceptionReply = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            error(StatusCode::FORBIDDEN, &reply.remote_exception.exception),
+            Error::from(ErrorKind::Quota)
+        );
+        assert_eq!(
+            error(StatusCode::FORBIDDEN, "AccessControlException"),
+            Error::from(ErrorKind::PermissionDenied)
+        );
+        assert_eq!(
+            error(StatusCode::UNAUTHORIZED, ""),
+            Error::from(ErrorKind::PermissionDenied)
+        );
+        assert_eq!(
+            error(StatusCode::FORBIDDEN, "FileAlreadyExistsException"),
+            Error::from(ErrorKind::Exists)
+        );
+        assert_eq!(
+            error(StatusCode::FORBIDDEN, "StandbyException"),
+            Error::backend("WebHDFS replied with 403 Forbidden: StandbyException")
+        );
+    }
+}
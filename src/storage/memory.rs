@@ -0,0 +1,697 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future, stream, Future, Stream};
+use futures03::compat::Future01CompatExt;
+
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, Result, StorageBackend};
+use crate::auth::User;
+
+/// A [`StorageBackend`] that keeps everything in memory, for tests, examples and ephemeral
+/// drop-boxes whose contents needn't survive a restart.
+///
+/// Clones share their contents, so the same files are seen by every session when a clone is
+/// handed out to each of them. New files get mode `0o644` and new directories `0o755`, or the
+/// modes of the logged in [`User`] if it has any. A `capacity` can be set, to refuse uploads (as
+/// out of space) once the files take up that many bytes.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::Memory;
+///
+/// let storage = Memory::new().capacity(64 << 20);
+/// let server = Server::new(Box::new(move || storage.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`User`]: ../auth/struct.User.html
+#[derive(Clone)]
+pub struct Memory {
+    store: Arc<Mutex<Store>>,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+}
+
+struct Store {
+    // Keyed by the path relative to the root, which is the empty path.
+    entries: BTreeMap<PathBuf, Entry>,
+    capacity: Option<u64>,
+    // The total length of the files.
+    used: u64,
+    // Bumped for every write, to give every version of a file its own ETag.
+    generation: u64,
+}
+
+struct Entry {
+    // `None` for directories.
+    data: Option<Bytes>,
+    modified: SystemTime,
+    mode: u32,
+    generation: u64,
+}
+
+impl Entry {
+    fn dir(mode: u32) -> Self {
+        Entry {
+            data: None,
+            modified: SystemTime::now(),
+            mode,
+            generation: 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.data.as_ref().map_or(0, |data| data.len() as u64)
+    }
+
+    fn metadata(&self) -> MemoryMetadata {
+        MemoryMetadata {
+            len: self.len(),
+            dir: self.data.is_none(),
+            modified: self.modified,
+            mode: self.mode,
+            etag: self
+                .data
+                .as_ref()
+                .map(|_| format!("{:016x}", self.generation)),
+        }
+    }
+}
+
+// How `write` treats an existing file.
+#[derive(Clone, Copy, PartialEq)]
+enum Write {
+    Replace,
+    New,
+    Append,
+}
+
+impl Store {
+    fn get(&self, key: &Path) -> Result<&Entry> {
+        self.entries
+            .get(key)
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    fn get_mut(&mut self, key: &Path) -> Result<&mut Entry> {
+        self.entries
+            .get_mut(key)
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    fn file(&self, key: &Path) -> Result<Bytes> {
+        self.get(key)?
+            .data
+            .clone()
+            .ok_or_else(|| Error::backend("Not a file"))
+    }
+
+    // Fails unless the parent of `key` is a directory, and marks it as modified.
+    fn touch_parent(&mut self, key: &Path) -> Result<()> {
+        let parent = key
+            .parent()
+            .ok_or(Error::from(ErrorKind::PermissionDenied))?;
+        let parent = self.get_mut(parent)?;
+        if parent.data.is_some() {
+            return Err(Error::backend("Not a directory"));
+        }
+        parent.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn children<'a>(&'a self, key: &'a Path) -> impl Iterator<Item = (&'a PathBuf, &'a Entry)> {
+        self.descendants(key)
+            .filter(move |(path, _)| path.parent() == Some(key))
+    }
+
+    fn descendants<'a>(&'a self, key: &'a Path) -> impl Iterator<Item = (&'a PathBuf, &'a Entry)> {
+        self.entries
+            .range(key.to_path_buf()..)
+            .skip(1)
+            .take_while(move |(path, _)| path.starts_with(key))
+    }
+
+    fn write(&mut self, key: PathBuf, data: Vec<u8>, how: Write, mode: u32) -> Result<()> {
+        let existing = match self.entries.get(&key) {
+            Some(entry) if entry.data.is_none() || how == Write::New => {
+                return Err(Error::from(ErrorKind::Exists))
+            }
+            Some(entry) => entry.data.clone(),
+            None => None,
+        };
+        self.touch_parent(&key)?;
+
+        let old_len = existing.as_ref().map_or(0, |data| data.len() as u64);
+        let data = match existing {
+            Some(existing) if how == Write::Append => {
+                let mut appended = existing.to_vec();
+                appended.extend_from_slice(&data);
+                appended
+            }
+            _ => data,
+        };
+        let used = self.used - old_len + data.len() as u64;
+        if self.capacity.is_some_and(|capacity| used > capacity) {
+            return Err(Error::from(ErrorKind::StorageFull));
+        }
+
+        self.used = used;
+        self.generation += 1;
+        let mode = self.entries.get(&key).map_or(mode, |entry| entry.mode);
+        self.entries.insert(
+            key,
+            Entry {
+                data: Some(data.into()),
+                modified: SystemTime::now(),
+                mode,
+                generation: self.generation,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Path) {
+        let keys: Vec<PathBuf> = std::iter::once(key.to_path_buf())
+            .chain(self.descendants(key).map(|(path, _)| path.clone()))
+            .collect();
+        for key in keys {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.used -= entry.len();
+            }
+        }
+    }
+}
+
+impl Memory {
+    /// Create a new, empty `Memory` backend, without a capacity.
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(PathBuf::new(), Entry::dir(0o755));
+        Memory {
+            store: Arc::new(Mutex::new(Store {
+                entries,
+                capacity: None,
+                used: 0,
+                generation: 0,
+            })),
+            file_mode: None,
+            dir_mode: None,
+        }
+    }
+
+    /// Set the number of bytes the files may take up together. Uploads that would go over it fail
+    /// as if the storage ran out of space. This applies to all clones.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use firetrap::storage::Memory;
+    ///
+    /// let storage = Memory::new().capacity(1 << 30);
+    /// ```
+    pub fn capacity(self, bytes: u64) -> Self {
+        self.store.lock().unwrap().capacity = Some(bytes);
+        self
+    }
+
+    // Applies `f` to the store.
+    fn with<T, F>(&self, path: &Path, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Store, PathBuf) -> Result<T>,
+    {
+        key(path).and_then(|key| f(&mut self.store.lock().unwrap(), key))
+    }
+
+    async fn write<P: AsRef<Path>, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+        how: Write,
+    ) -> Result<u64> {
+        let key = key(path)?;
+        let mode = self.file_mode.unwrap_or(0o644);
+        let (_, data) = tokio_io::io::read_to_end(bytes, Vec::new())
+            .compat()
+            .await?;
+        let len = data.len() as u64;
+        self.store.lock().unwrap().write(key, data, how, mode)?;
+        Ok(len)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+// Returns the key of the given path, relative to the root, with sequences like '../' resolved.
+fn key<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+    let mut key = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => key.push(name),
+            Component::ParentDir => {
+                if !key.pop() {
+                    return Err(Error::from(ErrorKind::PermissionDenied));
+                }
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::Prefix(_) => return Err(Error::from(ErrorKind::PermissionDenied)),
+        }
+    }
+    Ok(key)
+}
+
+// Returns the part of `data` in `range`, or as far as `data` goes.
+fn slice(data: Bytes, range: Range<u64>) -> Bytes {
+    let len = data.len() as u64;
+    let start = range.start.min(len);
+    let end = range.end.min(len).max(start);
+    data.slice(start as usize, end as usize)
+}
+
+/// The metadata of a file or directory in a [`Memory`] backend.
+///
+/// [`Memory`]: ./struct.Memory.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryMetadata {
+    len: u64,
+    dir: bool,
+    modified: SystemTime,
+    mode: u32,
+    etag: Option<String>,
+}
+
+impl MemoryMetadata {
+    /// Returns the (unix) mode of the file or directory, e.g. `0o644`.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+impl Metadata for MemoryMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir
+    }
+
+    fn modified(&self) -> Result<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn gid(&self) -> u32 {
+        0
+    }
+
+    fn uid(&self) -> u32 {
+        0
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Memory {
+    type File = Cursor<Bytes>;
+    type Metadata = MemoryMetadata;
+    type Error = Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.file_mode = user.effective_file_mode();
+        self.dir_mode = user.effective_dir_mode();
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+        self.with(path.as_ref(), |store, key| Ok(store.get(&key)?.metadata()))
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    > {
+        let entries = self.with(path.as_ref(), |store, key| {
+            if store.get(&key)?.data.is_some() {
+                return Err(Error::backend("Not a directory"));
+            }
+            Ok(store
+                .children(&key)
+                .map(|(path, entry)| Fileinfo {
+                    path: path.clone(),
+                    metadata: entry.metadata(),
+                })
+                .collect::<Vec<_>>())
+        });
+        Box::new(
+            future::result(entries)
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File> {
+        self.with(path.as_ref(), |store, key| {
+            Ok(Cursor::new(store.file(&key)?))
+        })
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>> {
+        self.with(path.as_ref(), move |store, key| {
+            let reader: Box<dyn tokio::prelude::AsyncRead + Send> =
+                Box::new(Cursor::new(slice(store.file(&key)?, range)));
+            Ok(reader)
+        })
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64> {
+        self.write(bytes, path, Write::Replace).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64> {
+        self.write(bytes, path, Write::New).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64> {
+        self.write(bytes, path, Write::Append).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, _path: P) -> Result<Option<u64>> {
+        let store = self.store.lock().unwrap();
+        let free = store
+            .capacity
+            .map(|capacity| capacity.saturating_sub(store.used));
+        Ok(free)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        self.with(path.as_ref(), |store, key| {
+            store.file(&key)?;
+            store.touch_parent(&key)?;
+            store.remove(&key);
+            Ok(())
+        })
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        let mode = self.dir_mode.unwrap_or(0o755);
+        self.with(path.as_ref(), move |store, key| {
+            if store.entries.contains_key(&key) {
+                return Err(Error::from(ErrorKind::Exists));
+            }
+            store.touch_parent(&key)?;
+            store.entries.insert(key, Entry::dir(mode));
+            Ok(())
+        })
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+        self.with(path.as_ref(), |store, key| {
+            if store.get(&key)?.data.is_some() {
+                return Err(Error::backend("Not a directory"));
+            }
+            if store.descendants(&key).next().is_some() {
+                return Err(Error::from(ErrorKind::NotEmpty));
+            }
+            store.touch_parent(&key)?;
+            store.remove(&key);
+            Ok(())
+        })
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<()> {
+        self.with(&path, |store, key| {
+            if store.get(&key)?.data.is_some() {
+                return Err(Error::backend("Not a directory"));
+            }
+            // Never remove the root itself.
+            store.touch_parent(&key)?;
+            store.remove(&key);
+            Ok(())
+        })
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<()> {
+        let to = key(to)?;
+        self.with(from.as_ref(), move |store, from| {
+            let is_file = store.get(&from)?.data.is_some();
+            if from.parent().is_none() || to.starts_with(&from) {
+                return Err(Error::from(ErrorKind::PermissionDenied));
+            }
+            // Like `rename(2)`, a file replaces a file and a directory an empty directory.
+            if let Some(existing) = store.entries.get(&to) {
+                let replaceable = match existing.data {
+                    Some(_) => is_file,
+                    None => !is_file && store.descendants(&to).next().is_none(),
+                };
+                if !replaceable {
+                    return Err(Error::from(ErrorKind::Exists));
+                }
+            }
+            store.touch_parent(&to)?;
+            store.touch_parent(&from)?;
+
+            store.remove(&to);
+            let moved: Vec<PathBuf> = std::iter::once(from.clone())
+                .chain(store.descendants(&from).map(|(path, _)| path.clone()))
+                .collect();
+            for path in moved {
+                let entry = store.entries.remove(&path).unwrap();
+                // Joining an empty path would add a trailing slash.
+                let renamed = match path.strip_prefix(&from).unwrap() {
+                    rest if rest.as_os_str().is_empty() => to.clone(),
+                    rest => to.join(rest),
+                };
+                store.entries.insert(renamed, entry);
+            }
+            Ok(())
+        })
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(&self, path: P, mtime: SystemTime) -> Result<()> {
+        self.with(path.as_ref(), move |store, key| {
+            store.get_mut(&key)?.modified = mtime;
+            Ok(())
+        })
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<()> {
+        self.with(path.as_ref(), move |store, key| {
+            store.get_mut(&key)?.mode = mode;
+            Ok(())
+        })
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<Range<u64>>,
+    ) -> Result<String> {
+        self.with(path.as_ref(), move |store, key| {
+            let mut data = store.file(&key)?;
+            if let Some(range) = range {
+                data = slice(data, range);
+            }
+            let mut hasher = algorithm.hasher();
+            hasher.update(&data);
+            Ok(hasher.finish())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Read;
+
+    fn put(storage: &Memory, path: &str, contents: &str) {
+        let bytes = Cursor::new(contents.as_bytes().to_vec());
+        assert_eq!(
+            block_on(storage.put(bytes, path)),
+            Ok(contents.len() as u64)
+        );
+    }
+
+    fn read(storage: &Memory, path: &str) -> String {
+        let mut contents = String::new();
+        block_on(storage.get(path))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    fn names(storage: &Memory, path: &str) -> Vec<String> {
+        storage
+            .list(path)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn writes_and_reads_files() {
+        let storage = Memory::new();
+        put(&storage, "/hello.txt", "hello");
+        assert_eq!(read(&storage, "hello.txt"), "hello");
+        assert_eq!(read(&storage.clone(), "/sub/../hello.txt"), "hello");
+
+        let bytes = Cursor::new(b", world".to_vec());
+        assert_eq!(block_on(storage.append(bytes, "hello.txt")), Ok(7));
+        assert_eq!(read(&storage, "hello.txt"), "hello, world");
+
+        let bytes = Cursor::new(b"again".to_vec());
+        assert!(block_on(storage.put_unique(bytes, "hello.txt")).is_err());
+
+        let mut range = String::new();
+        let mut reader = block_on(storage.get_range("hello.txt", 7..100)).unwrap();
+        reader.read_to_string(&mut range).unwrap();
+        assert_eq!(range, "world");
+
+        let metadata = block_on(storage.stat("hello.txt")).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 12);
+        assert_eq!(metadata.mode(), 0o644);
+        put(&storage, "hello.txt", "bye");
+        assert_ne!(
+            block_on(storage.stat("hello.txt")).unwrap().etag(),
+            metadata.etag()
+        );
+
+        assert_eq!(
+            block_on(storage.checksum("hello.txt", HashAlgorithm::Md5, None)),
+            Ok("bfa99df33b137bc8fb5f5407d7e58da8".to_string())
+        );
+
+        assert_eq!(
+            block_on(storage.get("../hello.txt")).err(),
+            Some(Error::from(ErrorKind::PermissionDenied))
+        );
+        assert!(block_on(storage.get("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn manages_directories() {
+        let storage = Memory::new();
+        block_on(storage.mkd("docs")).unwrap();
+        block_on(storage.mkd("docs/old")).unwrap();
+        put(&storage, "docs/a.txt", "a");
+        put(&storage, "docs/old/b.txt", "b");
+        put(&storage, "top.txt", "top");
+
+        assert!(block_on(storage.mkd("docs")).is_err());
+        assert!(block_on(storage.mkd("missing/dir")).is_err());
+        let bytes = Cursor::new(b"x".to_vec());
+        assert!(block_on(storage.put(bytes, "top.txt/x")).is_err());
+
+        assert_eq!(names(&storage, "/"), vec!["docs", "top.txt"]);
+        assert_eq!(names(&storage, "docs"), vec!["docs/a.txt", "docs/old"]);
+        assert!(block_on(storage.stat("docs/old")).unwrap().is_dir());
+
+        assert!(block_on(storage.rmd("docs/old")).is_err());
+        block_on(storage.del("docs/old/b.txt")).unwrap();
+        block_on(storage.rmd("docs/old")).unwrap();
+        assert_eq!(names(&storage, "docs"), vec!["docs/a.txt"]);
+
+        block_on(storage.rmd_recursive(PathBuf::from("docs"))).unwrap();
+        assert_eq!(names(&storage, "/"), vec!["top.txt"]);
+        assert!(block_on(storage.rmd_recursive(PathBuf::from("/"))).is_err());
+    }
+
+    #[test]
+    fn renames_files_and_directories() {
+        let storage = Memory::new();
+        block_on(storage.mkd("from")).unwrap();
+        block_on(storage.mkd("from/sub")).unwrap();
+        put(&storage, "from/sub/file.txt", "contents");
+        put(&storage, "other.txt", "other");
+
+        block_on(storage.rename("from", "to")).unwrap();
+        assert_eq!(names(&storage, "/"), vec!["other.txt", "to"]);
+        assert_eq!(read(&storage, "to/sub/file.txt"), "contents");
+        assert!(block_on(storage.stat("from/sub")).is_err());
+
+        assert!(block_on(storage.rename("to", "to/sub/inside")).is_err());
+        assert!(block_on(storage.rename("other.txt", "to")).is_err());
+        block_on(storage.rename("other.txt", "to/sub/file.txt")).unwrap();
+        assert_eq!(read(&storage, "to/sub/file.txt"), "other");
+    }
+
+    #[test]
+    fn refuses_uploads_beyond_capacity() {
+        let storage = Memory::new().capacity(10);
+        put(&storage, "a.txt", "12345678");
+        assert_eq!(block_on(storage.free_space("/")), Ok(Some(2)));
+
+        let bytes = Cursor::new(b"123".to_vec());
+        let error = block_on(storage.append(bytes, "a.txt")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert_eq!(read(&storage, "a.txt"), "12345678");
+
+        put(&storage, "a.txt", "1234567890");
+        block_on(storage.del("a.txt")).unwrap();
+        assert_eq!(block_on(storage.free_space("/")), Ok(Some(10)));
+    }
+
+    #[test]
+    fn sets_times_and_modes() {
+        let storage = Memory::new();
+        put(&storage, "file.txt", "x");
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        block_on(storage.set_mtime("file.txt", mtime)).unwrap();
+        block_on(storage.chmod("file.txt", 0o600)).unwrap();
+
+        let metadata = block_on(storage.stat("file.txt")).unwrap();
+        assert_eq!(metadata.modified(), Ok(mtime));
+        assert_eq!(metadata.mode(), 0o600);
+    }
+
+    #[test]
+    fn cancellation() {
+        crate::storage::conformance::check_cancellation(Memory::new());
+    }
+}
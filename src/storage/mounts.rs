@@ -0,0 +1,778 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{stream, Future, Stream};
+use log::warn;
+
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+type BoxStream<T> = Box<dyn Stream<Item = T, Error = Error> + Send>;
+type Reader = Box<dyn tokio::prelude::AsyncRead + Send>;
+
+/// The [`Metadata`] of the files and directories of a [`Mounts`] or an [`Overlay`] storage: a copy
+/// of what the backend underneath has to say about them, or made up for the directories that lead
+/// to a mount point.
+///
+/// [`Metadata`]: ./trait.Metadata.html
+/// [`Mounts`]: ./struct.Mounts.html
+/// [`Overlay`]: ./struct.Overlay.html
+#[derive(Clone, Debug)]
+pub struct MountMetadata {
+    len: u64,
+    dir: bool,
+    symlink: bool,
+    modified: Option<SystemTime>,
+    gid: u32,
+    uid: u32,
+    etag: Option<String>,
+}
+
+impl MountMetadata {
+    fn of<M: Metadata>(metadata: &M) -> Self {
+        MountMetadata {
+            len: metadata.len(),
+            dir: metadata.is_dir(),
+            symlink: metadata.is_symlink(),
+            modified: metadata.modified().ok(),
+            gid: metadata.gid(),
+            uid: metadata.uid(),
+            etag: metadata.etag(),
+        }
+    }
+
+    fn directory(modified: SystemTime) -> Self {
+        MountMetadata {
+            len: 0,
+            dir: true,
+            symlink: false,
+            modified: Some(modified),
+            gid: 0,
+            uid: 0,
+            etag: None,
+        }
+    }
+}
+
+impl Metadata for MountMetadata {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn is_file(&self) -> bool {
+        !self.dir
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.symlink
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        self.modified
+            .ok_or_else(|| Error::backend("The modification time is unknown"))
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Upload {
+    Put,
+    PutUnique,
+    Append,
+}
+
+// A mounted backend, with its types erased so backends of different types can be mounted side by
+// side. The overlay uses them too, for its layers.
+#[async_trait]
+pub(super) trait Mounted: Send + Sync {
+    fn set_user(&mut self, user: &User);
+    async fn stat(&self, path: PathBuf) -> Result<MountMetadata, Error>;
+    fn list(&self, path: PathBuf) -> BoxStream<Fileinfo<PathBuf, MountMetadata>>;
+    async fn get(&self, path: PathBuf) -> Result<Reader, Error>;
+    async fn get_range(&self, path: PathBuf, range: std::ops::Range<u64>) -> Result<Reader, Error>;
+    async fn upload(&self, bytes: Reader, path: PathBuf, how: Upload) -> Result<u64, Error>;
+    async fn free_space(&self, path: PathBuf) -> Result<Option<u64>, Error>;
+    async fn presign(
+        &self,
+        path: PathBuf,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Error>;
+    fn transfer_options(&self, options: &NegotiatedOptions);
+    fn transfer_id(&self, id: &str);
+    async fn del(&self, path: PathBuf) -> Result<(), Error>;
+    async fn mkd(&self, path: PathBuf) -> Result<(), Error>;
+    async fn rmd(&self, path: PathBuf) -> Result<(), Error>;
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> Result<(), Error>;
+    async fn set_mtime(&self, path: PathBuf, mtime: SystemTime) -> Result<(), Error>;
+    async fn chmod(&self, path: PathBuf, mode: u32) -> Result<(), Error>;
+    async fn checksum(
+        &self,
+        path: PathBuf,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Error>;
+}
+
+struct Erased<S> {
+    inner: Arc<S>,
+}
+
+pub(super) fn erase<S>(backend: S) -> Arc<dyn Mounted>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: Send + 'static,
+{
+    Arc::new(Erased {
+        inner: Arc::new(backend),
+    })
+}
+
+#[async_trait]
+impl<S> Mounted for Erased<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: Send + 'static,
+{
+    fn set_user(&mut self, user: &User) {
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.set_user(user),
+            None => warn!("Storage backend in use during login, not setting the user"),
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> Result<MountMetadata, Error> {
+        match self.inner.stat(path).await {
+            Ok(metadata) => Ok(MountMetadata::of(&metadata)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, path: PathBuf) -> BoxStream<Fileinfo<PathBuf, MountMetadata>> {
+        Box::new(
+            self.inner
+                .list(path)
+                .map(|file| Fileinfo {
+                    metadata: MountMetadata::of(&file.metadata),
+                    path: file.path,
+                })
+                .map_err(Into::into),
+        )
+    }
+
+    async fn get(&self, path: PathBuf) -> Result<Reader, Error> {
+        match self.inner.get(path).await {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(&self, path: PathBuf, range: std::ops::Range<u64>) -> Result<Reader, Error> {
+        self.inner.get_range(path, range).await.map_err(Into::into)
+    }
+
+    async fn upload(&self, bytes: Reader, path: PathBuf, how: Upload) -> Result<u64, Error> {
+        let written = match how {
+            Upload::Put => self.inner.put(bytes, path).await,
+            Upload::PutUnique => self.inner.put_unique(bytes, path).await,
+            Upload::Append => self.inner.append(bytes, path).await,
+        };
+        written.map_err(Into::into)
+    }
+
+    async fn free_space(&self, path: PathBuf) -> Result<Option<u64>, Error> {
+        self.inner.free_space(path).await.map_err(Into::into)
+    }
+
+    async fn presign(
+        &self,
+        path: PathBuf,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Error> {
+        self.inner.presign(path, ttl).await.map_err(Into::into)
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del(&self, path: PathBuf) -> Result<(), Error> {
+        self.inner.del(path).await.map_err(Into::into)
+    }
+
+    async fn mkd(&self, path: PathBuf) -> Result<(), Error> {
+        self.inner.mkd(path).await.map_err(Into::into)
+    }
+
+    async fn rmd(&self, path: PathBuf) -> Result<(), Error> {
+        self.inner.rmd(path).await.map_err(Into::into)
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> Result<(), Error> {
+        self.inner.rename(from, to).await.map_err(Into::into)
+    }
+
+    async fn set_mtime(&self, path: PathBuf, mtime: SystemTime) -> Result<(), Error> {
+        self.inner.set_mtime(path, mtime).await.map_err(Into::into)
+    }
+
+    async fn chmod(&self, path: PathBuf, mode: u32) -> Result<(), Error> {
+        self.inner.chmod(path, mode).await.map_err(Into::into)
+    }
+
+    async fn checksum(
+        &self,
+        path: PathBuf,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Error> {
+        self.inner
+            .checksum(path, algorithm, range)
+            .await
+            .map_err(Into::into)
+    }
+}
+
+struct Mount {
+    // Relative, like `pub/mirror`, and empty for the root.
+    point: PathBuf,
+    backend: Arc<dyn Mounted>,
+}
+
+/// A [`StorageBackend`] that puts other backends side by side, each at its own mount point, like
+/// a read-only mirror at `/pub` next to an object store for `/uploads`. Every path goes to the
+/// backend with the longest mount point it's under, as the path below that mount point. The
+/// directories that lead to mount points, like the root, are made up from the mount table when
+/// no backend is mounted there, and nothing can be created in them.
+///
+/// Renaming a file to another mount copies it there and deletes the original, unless that's
+/// switched off with [`copy_across_mounts`]; directories can't be moved across mounts at all.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, Mounts, ReadOnly};
+///
+/// let server = Server::new(Box::new(|| {
+///     Mounts::new()
+///         .mount("/pub", ReadOnly::new(Filesystem::new("/srv/mirror")))
+///         .mount("/uploads", Filesystem::new("/srv/uploads"))
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`copy_across_mounts`]: #method.copy_across_mounts
+pub struct Mounts {
+    mounts: Vec<Mount>,
+    copy_across_mounts: bool,
+    // The modification time of the made up directories.
+    created: SystemTime,
+}
+
+impl Default for Mounts {
+    fn default() -> Self {
+        Mounts::new()
+    }
+}
+
+// The path without `.`, `..` and the root, relative to the root of the mounts.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn denied<T>() -> Result<T, Error> {
+    Err(Error::from(ErrorKind::PermissionDenied))
+}
+
+fn not_found<T>() -> Result<T, Error> {
+    Err(Error::from(ErrorKind::NotFound))
+}
+
+impl Mounts {
+    /// Create a storage without any mounts.
+    pub fn new() -> Self {
+        Mounts {
+            mounts: vec![],
+            copy_across_mounts: true,
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Mount the given backend at the given path, e.g. `/uploads`. Mounting at `/` gives the
+    /// backend everything that's not under another mount point.
+    pub fn mount<P, S>(mut self, point: P, backend: S) -> Self
+    where
+        P: AsRef<Path>,
+        S: StorageBackend + Send + Sync + 'static,
+        S::File: tokio::prelude::AsyncRead + Send + 'static,
+        S::Metadata: Metadata + Send + 'static,
+        S::Error: Send + 'static,
+    {
+        let point = normalize(point.as_ref());
+        self.mounts.retain(|mount| mount.point != point);
+        self.mounts.push(Mount {
+            point,
+            backend: erase(backend),
+        });
+        self
+    }
+
+    /// Set whether renaming a file to another mount copies it there and deletes the original. If
+    /// not, such renames fail with a permission error. The default is `true`.
+    pub fn copy_across_mounts(mut self, enabled: bool) -> Self {
+        self.copy_across_mounts = enabled;
+        self
+    }
+
+    // The index of the mount the path is under, and the path within it.
+    fn route(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        let path = normalize(path);
+        self.mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, mount)| path.starts_with(&mount.point))
+            .max_by_key(|(_, mount)| mount.point.components().count())
+            .map(|(i, mount)| {
+                let within = path.strip_prefix(&mount.point).unwrap_or(&path);
+                (i, Path::new("/").join(within))
+            })
+    }
+
+    // The names of the made up directories in the given directory, or `None` if it doesn't lead
+    // to any mount point.
+    fn made_up(&self, path: &Path) -> Option<BTreeSet<OsString>> {
+        let path = normalize(path);
+        let names: BTreeSet<OsString> = self
+            .mounts
+            .iter()
+            .filter_map(|mount| mount.point.strip_prefix(&path).ok())
+            .filter_map(|rest| rest.components().next())
+            .map(|name| name.as_os_str().to_os_string())
+            .collect();
+        if names.is_empty() {
+            None
+        } else {
+            Some(names)
+        }
+    }
+
+    // Routes operations that change something: never on a made up directory or a mount point.
+    fn route_change(&self, path: &Path) -> Option<(usize, PathBuf)> {
+        if self.made_up(path).is_some() {
+            return None;
+        }
+        self.route(path)
+            .filter(|(_, within)| within.as_path() != Path::new("/"))
+    }
+
+    async fn upload<P: AsRef<Path>, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+        how: Upload,
+    ) -> Result<u64, Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => {
+                self.mounts[i]
+                    .backend
+                    .upload(Box::new(bytes), within, how)
+                    .await
+            }
+            None => denied(),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Mounts {
+    type File = Reader;
+    type Metadata = MountMetadata;
+    type Error = Error;
+
+    fn set_user(&mut self, user: &User) {
+        for mount in &mut self.mounts {
+            match Arc::get_mut(&mut mount.backend) {
+                Some(backend) => backend.set_user(user),
+                None => warn!("Storage backend in use during login, not setting the user"),
+            }
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<MountMetadata, Error> {
+        if self.made_up(path.as_ref()).is_some() {
+            return Ok(MountMetadata::directory(self.created));
+        }
+        match self.route(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.stat(within).await,
+            None => not_found(),
+        }
+    }
+
+    fn list<P: AsRef<Path>>(&self, path: P) -> BoxStream<Fileinfo<PathBuf, MountMetadata>>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let dir = normalize(path.as_ref());
+        let mounted = self.route(path.as_ref()).map(|(i, within)| {
+            let point = self.mounts[i].point.clone();
+            self.mounts[i].backend.list(within).map(move |file| {
+                let relative = file.path.strip_prefix("/").unwrap_or(&file.path);
+                Fileinfo {
+                    path: point.join(relative),
+                    metadata: file.metadata,
+                }
+            })
+        });
+        let made_up = match self.made_up(path.as_ref()) {
+            Some(names) => names,
+            None => {
+                return match mounted {
+                    Some(files) => Box::new(files),
+                    None => Box::new(stream::once(Err(Error::from(ErrorKind::NotFound)))),
+                };
+            }
+        };
+        let created = self.created;
+        let directories = move || {
+            made_up.clone().into_iter().map(move |name| Fileinfo {
+                path: dir.join(name),
+                metadata: MountMetadata::directory(created),
+            })
+        };
+        match mounted {
+            // A made up directory hides whatever the backend has by that name.
+            Some(files) => {
+                let names = self.made_up(path.as_ref()).unwrap_or_default();
+                Box::new(
+                    files
+                        .filter(move |file| {
+                            file.path
+                                .file_name()
+                                .is_none_or(|name| !names.contains(name))
+                        })
+                        .collect()
+                        .map(move |files| stream::iter_ok(directories().chain(files)))
+                        .flatten_stream(),
+                )
+            }
+            None => Box::new(stream::iter_ok(directories().collect::<Vec<_>>())),
+        }
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Reader, Error> {
+        match self.route(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.get(within).await,
+            None => not_found(),
+        }
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        match self.route(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.get_range(within, range).await,
+            None => not_found(),
+        }
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::Put).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::PutUnique).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::Append).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Error> {
+        match self.route(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.free_space(within).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Error> {
+        match self.route(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.presign(within, ttl).await,
+            None => Ok(None),
+        }
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        for mount in &self.mounts {
+            mount.backend.transfer_options(options);
+        }
+    }
+
+    fn transfer_id(&self, id: &str) {
+        for mount in &self.mounts {
+            mount.backend.transfer_id(id);
+        }
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.del(within).await,
+            None => denied(),
+        }
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.mkd(within).await,
+            None => denied(),
+        }
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.rmd(within).await,
+            None => denied(),
+        }
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Error> {
+        let (source, from) = match self.route_change(from.as_ref()) {
+            Some(route) => route,
+            None => return denied(),
+        };
+        let (target, to) = match self.route_change(to.as_ref()) {
+            Some(route) => route,
+            None => return denied(),
+        };
+        if source == target {
+            return self.mounts[source].backend.rename(from, to).await;
+        }
+        if !self.copy_across_mounts {
+            return denied();
+        }
+        let (source, target) = (&self.mounts[source].backend, &self.mounts[target].backend);
+        if source.stat(from.clone()).await?.is_dir() {
+            return denied();
+        }
+        let file = source.get(from.clone()).await?;
+        target.upload(file, to, Upload::Put).await?;
+        source.del(from).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.set_mtime(within, mtime).await,
+            None => denied(),
+        }
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Error> {
+        match self.route_change(path.as_ref()) {
+            Some((i, within)) => self.mounts[i].backend.chmod(within, mode).await,
+            None => denied(),
+        }
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Error> {
+        match self.route(path.as_ref()) {
+            Some((i, within)) => {
+                self.mounts[i]
+                    .backend
+                    .checksum(within, algorithm, range)
+                    .await
+            }
+            None => not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Memory, ReadOnly};
+    use futures::Future;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::{Cursor, Read};
+
+    fn put<S: StorageBackend>(storage: &S, path: &str, contents: &str)
+    where
+        S::Error: std::fmt::Debug,
+    {
+        block_on(storage.put(Cursor::new(contents.as_bytes().to_vec()), path)).unwrap();
+    }
+
+    fn contents(storage: &Mounts, path: &str) -> String {
+        let mut contents = String::new();
+        block_on(storage.get(path))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    fn names(storage: &Mounts, path: &str) -> Vec<PathBuf> {
+        let mut names: Vec<_> = storage
+            .list(path)
+            .map(|file| file.path)
+            .collect()
+            .wait()
+            .unwrap();
+        names.sort();
+        names
+    }
+
+    fn mounts() -> Mounts {
+        let mirror = Memory::new();
+        put(&mirror, "/readme.txt", "hello");
+        Mounts::new()
+            .mount("/pub/mirror", ReadOnly::new(mirror))
+            .mount("/uploads", Memory::new())
+    }
+
+    #[test]
+    fn routes_to_mounts() {
+        let storage = mounts();
+        assert_eq!(contents(&storage, "/pub/mirror/readme.txt"), "hello");
+        put(&storage, "/uploads/a.txt", "upload");
+        assert_eq!(contents(&storage, "uploads/./a.txt"), "upload");
+        assert_eq!(
+            block_on(storage.put(Cursor::new(vec![]), "/pub/mirror/b.txt")),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+        assert!(block_on(storage.get("/elsewhere/a.txt")).is_err());
+    }
+
+    #[test]
+    fn makes_up_the_directories_to_mount_points() {
+        let storage = mounts();
+        put(&storage, "/uploads/a.txt", "upload");
+        assert_eq!(
+            names(&storage, "/"),
+            vec![PathBuf::from("pub"), PathBuf::from("uploads")]
+        );
+        assert_eq!(names(&storage, "/pub"), vec![PathBuf::from("pub/mirror")]);
+        assert_eq!(
+            names(&storage, "/uploads"),
+            vec![PathBuf::from("uploads/a.txt")]
+        );
+        assert!(block_on(storage.stat("/pub")).unwrap().is_dir());
+        assert_eq!(
+            block_on(storage.mkd("/pub/other")),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+        assert_eq!(
+            block_on(storage.rmd("/uploads")),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+    }
+
+    #[test]
+    fn merges_the_root_mount_with_mount_points() {
+        let root = Memory::new();
+        put(&root, "/top.txt", "top");
+        let storage = Mounts::new()
+            .mount("/", root)
+            .mount("/uploads", Memory::new());
+        assert_eq!(
+            names(&storage, "/"),
+            vec![PathBuf::from("top.txt"), PathBuf::from("uploads")]
+        );
+        assert_eq!(contents(&storage, "/top.txt"), "top");
+    }
+
+    #[test]
+    fn renames_across_mounts() {
+        let storage = Mounts::new()
+            .mount("/a", Memory::new())
+            .mount("/b", Memory::new());
+        put(&storage, "/a/file.txt", "moved");
+        block_on(storage.rename("/a/file.txt", "/b/file.txt")).unwrap();
+        assert_eq!(contents(&storage, "/b/file.txt"), "moved");
+        assert!(block_on(storage.get("/a/file.txt")).is_err());
+
+        block_on(storage.mkd("/a/dir")).unwrap();
+        assert_eq!(
+            block_on(storage.rename("/a/dir", "/b/dir")),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+
+        let storage = storage.copy_across_mounts(false);
+        assert_eq!(
+            block_on(storage.rename("/b/file.txt", "/a/file.txt")),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+        assert_eq!(contents(&storage, "/b/file.txt"), "moved");
+    }
+}
@@ -0,0 +1,122 @@
+/// How an object store backend splits an upload into parts. Uploads over FTP don't announce
+/// their size, so the part size starts small and grows as the upload goes on: small files don't
+/// waste memory on huge buffers, while huge files don't run out of parts. Up to
+/// `concurrency` parts are uploaded at the same time, so a slow uplink to the object store
+/// doesn't stall the client.
+///
+/// The defaults match the limits of S3 and most compatible stores: parts of 5MiB at first,
+/// doubling every 1000 parts up to 5GiB, and at most 10000 parts. That allows for objects of
+/// about 5TB.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::MultipartStrategy;
+///
+/// let strategy = MultipartStrategy {
+///     initial_part_size: 8 << 20,
+///     concurrency: 8,
+///     ..MultipartStrategy::default()
+/// };
+/// assert_eq!(strategy.part_size(0), 8 << 20);
+/// assert_eq!(strategy.part_size(1000), 16 << 20);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MultipartStrategy {
+    /// The size of the first parts, in bytes.
+    pub initial_part_size: u64,
+    /// The size the parts never grow beyond, in bytes.
+    pub max_part_size: u64,
+    /// After how many parts the part size doubles. `0` keeps the part size the same for the
+    /// whole upload.
+    pub growth_interval: u32,
+    /// The maximum number of parts of a single upload.
+    pub max_parts: u32,
+    /// How many parts may be uploaded at the same time.
+    pub concurrency: usize,
+}
+
+impl MultipartStrategy {
+    /// Returns the size of the part with the given (zero-based) index, in bytes. The last part
+    /// of an upload may of course be smaller.
+    pub fn part_size(&self, index: u32) -> u64 {
+        let doublings = index.checked_div(self.growth_interval).unwrap_or(0);
+        let initial = self.initial_part_size.min(self.max_part_size);
+        if initial.leading_zeros() < doublings {
+            return self.max_part_size;
+        }
+        (initial << doublings).min(self.max_part_size)
+    }
+
+    /// Returns the size of the biggest upload that fits in `max_parts` parts, in bytes.
+    pub fn max_upload_size(&self) -> u64 {
+        let mut total = 0u64;
+        let mut index = 0;
+        // All parts between two doublings have the same size.
+        while index < self.max_parts {
+            let parts = match self.growth_interval {
+                0 => self.max_parts - index,
+                interval => interval.min(self.max_parts - index),
+            };
+            total = total.saturating_add(self.part_size(index).saturating_mul(u64::from(parts)));
+            index += parts;
+        }
+        total
+    }
+}
+
+impl Default for MultipartStrategy {
+    fn default() -> Self {
+        MultipartStrategy {
+            initial_part_size: 5 << 20,
+            max_part_size: 5 << 30,
+            growth_interval: 1000,
+            max_parts: 10_000,
+            concurrency: 4,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn grows_up_to_the_maximum() {
+        let strategy = MultipartStrategy::default();
+        assert_eq!(strategy.part_size(0), 5 << 20);
+        assert_eq!(strategy.part_size(999), 5 << 20);
+        assert_eq!(strategy.part_size(1000), 10 << 20);
+        assert_eq!(strategy.part_size(9999), 5 << 29);
+        assert_eq!(strategy.part_size(u32::MAX), 5 << 30);
+        assert_eq!(strategy.max_upload_size(), 1000 * (5 << 20) * 1023);
+    }
+
+    #[test]
+    fn fixed_part_size() {
+        let strategy = MultipartStrategy {
+            initial_part_size: 100,
+            growth_interval: 0,
+            max_parts: 10,
+            ..MultipartStrategy::default()
+        };
+        assert_eq!(strategy.part_size(0), 100);
+        assert_eq!(strategy.part_size(u32::MAX), 100);
+        assert_eq!(strategy.max_upload_size(), 1000);
+    }
+
+    #[test]
+    fn never_overflows() {
+        let strategy = MultipartStrategy {
+            initial_part_size: u64::MAX / 2,
+            max_part_size: u64::MAX,
+            growth_interval: 1,
+            max_parts: 100,
+            concurrency: 1,
+        };
+        assert_eq!(strategy.part_size(1), u64::MAX - 1);
+        assert_eq!(strategy.part_size(2), u64::MAX);
+        assert_eq!(strategy.max_upload_size(), u64::MAX);
+    }
+}
@@ -0,0 +1,727 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{stream, Future, Stream};
+use futures03::compat::Future01CompatExt;
+use futures03::future::join_all;
+use log::warn;
+
+use super::mounts::{erase, MountMetadata, Mounted, Upload};
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::compat::compat;
+use crate::events::NegotiatedOptions;
+
+type BoxStream<T> = Box<dyn Stream<Item = T, Error = Error> + Send>;
+type Reader = Box<dyn tokio::prelude::AsyncRead + Send>;
+
+// In the upper layer, `.wh.name` hides `name` of the lower layer.
+const WHITEOUT: &str = ".wh.";
+// In the upper layer, a directory with this file hides everything the lower layer has in it.
+const OPAQUE: &str = ".wh..wh..opq";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layer {
+    Lower,
+    Upper,
+}
+
+/// A [`StorageBackend`] that lays a writable upper backend over a lower one, which is only ever
+/// read, so a golden content tree can be customized per deployment without copying it. The
+/// clients see the files of both, those of the upper layer winning when both have one by the same
+/// name.
+///
+/// Whatever changes is changed in the upper layer: a file of the lower layer is copied up before
+/// it's appended to, renamed or touched, and the directories it's in are made there as needed.
+/// Deletions of what the lower layer has leave a whiteout in the upper layer, a `.wh.name` file
+/// next to where it was, and a directory that's made anew after it was removed hides what the
+/// lower layer has in it with a `.wh..wh..opq` file, the way Linux's overlayfs does. These files
+/// aren't shown to the clients, and can't be used in any way. Directories that the lower layer
+/// has can't be renamed.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, Overlay};
+///
+/// let server = Server::new(Box::new(|| {
+///     Overlay::new(Filesystem::new("/srv/golden"), Filesystem::new("/srv/site"))
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+pub struct Overlay {
+    lower: Arc<dyn Mounted>,
+    upper: Arc<dyn Mounted>,
+}
+
+// The path without `.`, `..` and the root, relative to the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn absolute(relative: &Path) -> PathBuf {
+    Path::new("/").join(relative)
+}
+
+fn is_whiteout(name: &std::ffi::OsStr) -> bool {
+    name.to_string_lossy().starts_with(WHITEOUT)
+}
+
+// Whether the path is, or is in, one of the files that make up the overlay.
+fn hidden(path: &Path) -> bool {
+    normalize(path)
+        .components()
+        .any(|component| is_whiteout(component.as_os_str()))
+}
+
+// The whiteout that hides the given path, unless it's the root.
+fn whiteout(relative: &Path) -> Option<PathBuf> {
+    let name = relative.file_name()?;
+    let dir = relative.parent()?;
+    Some(absolute(dir).join(format!("{}{}", WHITEOUT, name.to_string_lossy())))
+}
+
+fn not_found<T>() -> Result<T, Error> {
+    Err(Error::from(ErrorKind::NotFound))
+}
+
+fn denied<T>() -> Result<T, Error> {
+    Err(Error::from(ErrorKind::PermissionDenied))
+}
+
+impl Overlay {
+    /// Lay the given upper backend, where all changes go, over the given lower one.
+    pub fn new<L, U>(lower: L, upper: U) -> Self
+    where
+        L: StorageBackend + Send + Sync + 'static,
+        L::File: tokio::prelude::AsyncRead + Send + 'static,
+        L::Metadata: Metadata + Send + 'static,
+        L::Error: Send + 'static,
+        U: StorageBackend + Send + Sync + 'static,
+        U::File: tokio::prelude::AsyncRead + Send + 'static,
+        U::Metadata: Metadata + Send + 'static,
+        U::Error: Send + 'static,
+    {
+        Overlay {
+            lower: erase(lower),
+            upper: erase(upper),
+        }
+    }
+
+    // A copy that shares the layers, for use in listings.
+    fn clone_handle(&self) -> Self {
+        Overlay {
+            lower: Arc::clone(&self.lower),
+            upper: Arc::clone(&self.upper),
+        }
+    }
+
+    fn layer(&self, layer: Layer) -> &dyn Mounted {
+        match layer {
+            Layer::Lower => self.lower.as_ref(),
+            Layer::Upper => self.upper.as_ref(),
+        }
+    }
+
+    // Whether the lower layer shows through at the path: not if the path or a directory it's in
+    // was deleted, or a directory it's in was made anew.
+    async fn lower_visible(&self, relative: &Path) -> bool {
+        let mut markers = vec![];
+        for ancestor in relative.ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            markers.extend(whiteout(ancestor));
+            if ancestor != relative {
+                markers.push(absolute(ancestor).join(OPAQUE));
+            }
+        }
+        let found = join_all(markers.into_iter().map(|marker| self.upper.stat(marker))).await;
+        !found.iter().any(Result::is_ok)
+    }
+
+    // The layer that has what's at the path, and what it has there.
+    async fn lookup(&self, path: &Path) -> Option<(Layer, MountMetadata)> {
+        let relative = normalize(path);
+        if let Ok(metadata) = self.upper.stat(absolute(&relative)).await {
+            return Some((Layer::Upper, metadata));
+        }
+        if !self.lower_visible(&relative).await {
+            return None;
+        }
+        let metadata = self.lower.stat(absolute(&relative)).await.ok()?;
+        Some((Layer::Lower, metadata))
+    }
+
+    // Whether the lower layer has something at the path that the clients can see.
+    async fn in_lower(&self, relative: &Path) -> bool {
+        self.lower_visible(relative).await && self.lower.stat(absolute(relative)).await.is_ok()
+    }
+
+    // Makes the directories the path is in in the upper layer, where only the lower layer has
+    // them.
+    async fn copy_up_dirs(&self, relative: &Path) {
+        let mut dirs: Vec<PathBuf> = relative
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| !dir.as_os_str().is_empty())
+            .map(absolute)
+            .collect();
+        dirs.reverse();
+        // Most errors mean that the directory exists: if it doesn't, what comes next fails.
+        for dir in dirs {
+            let _ = self.upper.mkd(dir).await;
+        }
+    }
+
+    // Copies what the lower layer has at the path to the upper layer, so it can be changed there.
+    async fn copy_up(&self, path: &Path) -> Result<(), Error> {
+        let relative = normalize(path);
+        let is_dir = match self.lookup(path).await {
+            Some((Layer::Upper, _)) => return Ok(()),
+            Some((Layer::Lower, metadata)) => metadata.is_dir(),
+            None => return not_found(),
+        };
+        self.copy_up_dirs(&relative).await;
+        let path = absolute(&relative);
+        if is_dir {
+            return self.upper.mkd(path).await;
+        }
+        let file = self.lower.get(path.clone()).await?;
+        self.upper.upload(file, path, Upload::Put).await?;
+        Ok(())
+    }
+
+    // Hides what the lower layer has at the path, if anything.
+    async fn white_out(&self, relative: PathBuf) -> Result<(), Error> {
+        let found = self.in_lower(&relative).await;
+        let whiteout = match whiteout(&relative) {
+            Some(whiteout) if found => whiteout,
+            _ => return Ok(()),
+        };
+        self.copy_up_dirs(&relative).await;
+        self.upper
+            .upload(
+                Box::new(std::io::Cursor::new(vec![])),
+                whiteout,
+                Upload::Put,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Removes the whiteout of the path, if there is one. Resolves to whether there was.
+    async fn clear_whiteout(&self, relative: &Path) -> bool {
+        match whiteout(relative) {
+            Some(whiteout) => self.upper.del(whiteout).await.is_ok(),
+            None => false,
+        }
+    }
+
+    // Gets the path ready to be written in the upper layer: it must not be a directory, and the
+    // directory it's in must exist. With `copy`, a file of the lower layer is copied up first.
+    async fn prepare(&self, path: &Path, copy: bool) -> Result<(), Error> {
+        let relative = normalize(path);
+        let parent = match relative.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return denied(),
+        };
+        let found = self
+            .lookup(&relative)
+            .await
+            .map(|(_, found)| found.is_dir());
+        let parent = self
+            .lookup(&parent)
+            .await
+            .map(|(_, parent)| parent.is_dir());
+        match (found, parent) {
+            (Some(true), _) => return not_found(),
+            (_, Some(true)) => {}
+            _ => return not_found(),
+        }
+        if !copy || self.copy_up(&relative).await.is_err() {
+            self.copy_up_dirs(&relative).await;
+        }
+        Ok(())
+    }
+
+    async fn upload<P: AsRef<Path>, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+        how: Upload,
+    ) -> Result<u64, Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let relative = normalize(path.as_ref());
+        match how {
+            Upload::Put => self.prepare(&relative, false).await?,
+            Upload::Append => self.prepare(&relative, true).await?,
+            Upload::PutUnique => {
+                let found = self.lookup(&relative).await.is_some();
+                self.prepare(&relative, false).await?;
+                if found {
+                    return Err(Error::from(ErrorKind::Exists));
+                }
+            }
+        }
+        let written = self
+            .upper
+            .upload(Box::new(bytes), absolute(&relative), how)
+            .await?;
+        self.clear_whiteout(&relative).await;
+        Ok(written)
+    }
+
+    // The files in the directory at the path, as the clients see them.
+    async fn files(
+        &self,
+        relative: PathBuf,
+    ) -> Result<Vec<Fileinfo<PathBuf, MountMetadata>>, Error> {
+        let dir = absolute(&relative);
+        let upper = self.upper.list(dir.clone()).collect().compat().await.ok();
+        let opaque = self.upper.stat(dir.join(OPAQUE)).await.is_ok();
+        let lower = if self.lower_visible(&relative).await && !opaque {
+            self.lower.list(dir).collect().compat().await.ok()
+        } else {
+            None
+        };
+        if upper.is_none() && lower.is_none() {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+        let mut whiteouts = BTreeSet::new();
+        let mut files: BTreeMap<OsString, Fileinfo<PathBuf, MountMetadata>> = BTreeMap::new();
+        for file in upper.unwrap_or_default() {
+            let name = match file.path.file_name() {
+                Some(name) => name.to_os_string(),
+                None => continue,
+            };
+            if is_whiteout(&name) {
+                let name = name.to_string_lossy();
+                whiteouts.insert(OsString::from(&name[WHITEOUT.len()..]));
+                continue;
+            }
+            files.insert(name, file);
+        }
+        for file in lower.unwrap_or_default() {
+            if let Some(name) = file.path.file_name() {
+                if !whiteouts.contains(name) && !files.contains_key(name) {
+                    files.insert(name.to_os_string(), file);
+                }
+            }
+        }
+        Ok(files
+            .into_iter()
+            .map(|(name, file)| Fileinfo {
+                path: relative.join(name),
+                metadata: file.metadata,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for Overlay {
+    type File = Reader;
+    type Metadata = MountMetadata;
+    type Error = Error;
+
+    fn set_user(&mut self, user: &User) {
+        for layer in [&mut self.lower, &mut self.upper] {
+            match Arc::get_mut(layer) {
+                Some(backend) => backend.set_user(user),
+                None => warn!("Storage backend in use during login, not setting the user"),
+            }
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<MountMetadata, Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        match self.lookup(path.as_ref()).await {
+            Some((_, metadata)) => Ok(metadata),
+            None => not_found(),
+        }
+    }
+
+    fn list<P: AsRef<Path>>(&self, path: P) -> BoxStream<Fileinfo<PathBuf, MountMetadata>>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        if hidden(path.as_ref()) {
+            return Box::new(stream::once(Err(Error::from(ErrorKind::NotFound))));
+        }
+        let relative = normalize(path.as_ref());
+        let this = self.clone_handle();
+        Box::new(
+            compat(async move { this.files(relative).await })
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Reader, Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        match self.lookup(&path).await {
+            Some((layer, _)) => self.layer(layer).get(path).await,
+            None => not_found(),
+        }
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        match self.lookup(&path).await {
+            Some((layer, _)) => self.layer(layer).get_range(path, range).await,
+            None => not_found(),
+        }
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::Put).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::PutUnique).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Error> {
+        self.upload(bytes, path, Upload::Append).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Error> {
+        self.upper
+            .free_space(absolute(&normalize(path.as_ref())))
+            .await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        match self.lookup(&path).await {
+            Some((layer, _)) => self.layer(layer).presign(path, ttl).await,
+            None => not_found(),
+        }
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.lower.transfer_options(options);
+        self.upper.transfer_options(options);
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.lower.transfer_id(id);
+        self.upper.transfer_id(id);
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let relative = normalize(path.as_ref());
+        match self.lookup(&relative).await {
+            Some((_, metadata)) if metadata.is_dir() => not_found(),
+            Some((Layer::Upper, _)) => {
+                self.upper.del(absolute(&relative)).await?;
+                self.white_out(relative).await
+            }
+            Some((Layer::Lower, _)) => self.white_out(relative).await,
+            None => not_found(),
+        }
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        if hidden(path.as_ref()) {
+            return denied();
+        }
+        let relative = normalize(path.as_ref());
+        let parent = match relative.parent() {
+            Some(parent) => parent.to_path_buf(),
+            None => return denied(),
+        };
+        let found = self.lookup(&relative).await.is_some();
+        let parent = self
+            .lookup(&parent)
+            .await
+            .map(|(_, parent)| parent.is_dir());
+        match (found, parent) {
+            (false, Some(true)) => {}
+            (true, _) => return Err(Error::from(ErrorKind::Exists)),
+            _ => return not_found(),
+        }
+        let dir = absolute(&relative);
+        self.copy_up_dirs(&relative).await;
+        self.upper.mkd(dir.clone()).await?;
+        if self.clear_whiteout(&relative).await {
+            let marker = dir.join(OPAQUE);
+            self.upper
+                .upload(Box::new(std::io::Cursor::new(vec![])), marker, Upload::Put)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let relative = normalize(path.as_ref());
+        if relative.as_os_str().is_empty() {
+            return denied();
+        }
+        let dir = absolute(&relative);
+        let layer = match self.lookup(&relative).await {
+            Some((layer, metadata)) if metadata.is_dir() => layer,
+            _ => return not_found(),
+        };
+        if !self.files(relative.clone()).await?.is_empty() {
+            return Err(Error::from(ErrorKind::NotEmpty));
+        }
+        if layer == Layer::Upper {
+            // The directory looks empty, but there may be whiteouts in it.
+            let files = self.upper.list(dir.clone()).collect().compat().await?;
+            for file in files {
+                if let Some(name) = file.path.file_name().filter(|name| is_whiteout(name)) {
+                    self.upper.del(dir.join(name)).await?;
+                }
+            }
+            self.upper.rmd(dir).await?;
+        }
+        self.white_out(relative).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Error> {
+        if hidden(from.as_ref()) || hidden(to.as_ref()) {
+            return not_found();
+        }
+        let (from, to) = (normalize(from.as_ref()), normalize(to.as_ref()));
+        if from.as_os_str().is_empty() {
+            return denied();
+        }
+        let is_dir = match self.lookup(&from).await {
+            Some((_, metadata)) => metadata.is_dir(),
+            None => return not_found(),
+        };
+        if is_dir && self.in_lower(&from).await {
+            return denied();
+        }
+        self.prepare(&to, false).await?;
+        self.copy_up(&from).await?;
+        self.upper.rename(absolute(&from), absolute(&to)).await?;
+        if self.clear_whiteout(&to).await && is_dir {
+            self.upper
+                .upload(
+                    Box::new(std::io::Cursor::new(vec![])),
+                    absolute(&to).join(OPAQUE),
+                    Upload::Put,
+                )
+                .await?;
+        }
+        self.white_out(from).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        self.copy_up(&path).await?;
+        self.upper.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        self.copy_up(&path).await?;
+        self.upper.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Error> {
+        if hidden(path.as_ref()) {
+            return not_found();
+        }
+        let path = absolute(&normalize(path.as_ref()));
+        match self.lookup(&path).await {
+            Some((layer, _)) => self.layer(layer).checksum(path, algorithm, range).await,
+            None => not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+    use futures::Future;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::{Cursor, Read};
+
+    fn put<S: StorageBackend>(storage: &S, path: &str, contents: &str)
+    where
+        S::Error: std::fmt::Debug,
+    {
+        block_on(storage.put(Cursor::new(contents.as_bytes().to_vec()), path)).unwrap();
+    }
+
+    fn contents<S: StorageBackend>(storage: &S, path: &str) -> Option<String>
+    where
+        S::File: Read,
+    {
+        let mut contents = String::new();
+        let mut file = block_on(storage.get(path)).ok()?;
+        file.read_to_string(&mut contents).unwrap();
+        Some(contents)
+    }
+
+    fn names(storage: &Overlay, path: &str) -> Vec<String> {
+        storage
+            .list(path)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn golden() -> Memory {
+        let lower = Memory::new();
+        block_on(lower.mkd("/docs")).unwrap();
+        put(&lower, "/docs/a.txt", "golden a");
+        put(&lower, "/docs/b.txt", "golden b");
+        put(&lower, "/index.html", "golden index");
+        lower
+    }
+
+    #[test]
+    fn copies_up_on_write() {
+        let lower = golden();
+        let upper = Memory::new();
+        let storage = Overlay::new(lower.clone(), upper.clone());
+
+        assert_eq!(names(&storage, "/"), vec!["docs", "index.html"]);
+        assert_eq!(contents(&storage, "/docs/a.txt").unwrap(), "golden a");
+
+        put(&storage, "/docs/a.txt", "custom a");
+        assert_eq!(contents(&storage, "/docs/a.txt").unwrap(), "custom a");
+        assert_eq!(contents(&lower, "/docs/a.txt").unwrap(), "golden a");
+        assert_eq!(contents(&upper, "/docs/a.txt").unwrap(), "custom a");
+
+        block_on(storage.append(Cursor::new(b" and more".to_vec()), "/docs/b.txt")).unwrap();
+        assert_eq!(
+            contents(&storage, "/docs/b.txt").unwrap(),
+            "golden b and more"
+        );
+        assert_eq!(contents(&lower, "/docs/b.txt").unwrap(), "golden b");
+
+        block_on(storage.rename("/index.html", "/home.html")).unwrap();
+        assert_eq!(contents(&storage, "/home.html").unwrap(), "golden index");
+        assert_eq!(contents(&storage, "/index.html"), None);
+        assert_eq!(contents(&lower, "/index.html").unwrap(), "golden index");
+        assert_eq!(names(&storage, "/"), vec!["docs", "home.html"]);
+        assert!(block_on(storage.rename("/docs", "/papers")).is_err());
+
+        put(&storage, "/new.txt", "new");
+        assert!(block_on(storage.put_unique(Cursor::new(b"x".to_vec()), "/docs/a.txt")).is_err());
+        assert!(block_on(storage.put(Cursor::new(b"x".to_vec()), "/none/x.txt")).is_err());
+        assert_eq!(names(&storage, "/"), vec!["docs", "home.html", "new.txt"]);
+    }
+
+    #[test]
+    fn whites_out_deletions() {
+        let lower = golden();
+        let upper = Memory::new();
+        let storage = Overlay::new(lower.clone(), upper.clone());
+
+        put(&storage, "/docs/a.txt", "custom a");
+        block_on(storage.del("/docs/a.txt")).unwrap();
+        assert_eq!(contents(&storage, "/docs/a.txt"), None);
+        assert!(block_on(storage.stat("/docs/a.txt")).is_err());
+        assert!(block_on(storage.del("/docs/a.txt")).is_err());
+        assert_eq!(names(&storage, "/docs"), vec!["docs/b.txt"]);
+        assert_eq!(contents(&lower, "/docs/a.txt").unwrap(), "golden a");
+        assert!(block_on(upper.stat("/docs/.wh.a.txt")).is_ok());
+        assert!(block_on(storage.stat("/docs/.wh.a.txt")).is_err());
+
+        // A file can come back.
+        put(&storage, "/docs/a.txt", "back");
+        assert_eq!(names(&storage, "/docs"), vec!["docs/a.txt", "docs/b.txt"]);
+        block_on(storage.del("/docs/a.txt")).unwrap();
+
+        assert!(block_on(storage.rmd("/docs")).is_err());
+        block_on(storage.del("/docs/b.txt")).unwrap();
+        block_on(storage.rmd("/docs")).unwrap();
+        assert!(block_on(storage.stat("/docs")).is_err());
+        assert!(block_on(storage.stat("/docs/b.txt")).is_err());
+        assert_eq!(names(&storage, "/"), vec!["index.html"]);
+        assert_eq!(contents(&lower, "/docs/b.txt").unwrap(), "golden b");
+
+        // A directory made anew doesn't show what the lower layer has in it.
+        block_on(storage.mkd("/docs")).unwrap();
+        assert_eq!(names(&storage, "/docs"), Vec::<String>::new());
+        assert!(block_on(storage.stat("/docs/b.txt")).is_err());
+        put(&storage, "/docs/c.txt", "c");
+        assert_eq!(names(&storage, "/docs"), vec!["docs/c.txt"]);
+        assert!(block_on(storage.mkd("/docs")).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Directories for flat object stores, which only know objects with names like
+//! `reports/2019.csv`: the directories are made up from the prefixes of the names.
+//!
+//! A directory exists as long as there are objects under its prefix, or a marker object that
+//! `MKD` created for it. Listing a directory lists the objects directly under its prefix, and the
+//! prefixes one level down as subdirectories, leaving the markers out. So a client can change
+//! into a directory that some upload created, but not into one that never was, and a directory
+//! that `MKD` created stays around until `RMD` removes its marker.
+
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use super::cloud::ObjectMetadata;
+use super::{Error, ErrorKind, Fileinfo};
+
+// How an object store keeps a directory around while there's nothing in it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Marker {
+    // An empty object with the prefix of the directory as its name (`reports/`), like the S3 and
+    // Cloud Storage consoles create.
+    #[cfg_attr(not(any(feature = "s3", feature = "gcs")), allow(dead_code))]
+    TrailingSlash,
+    // An empty object with the given name in the directory (`reports/.bzEmpty`), for stores that
+    // don't take names with a trailing slash, like the B2 web UI creates.
+    #[cfg_attr(not(feature = "b2"), allow(dead_code))]
+    File(&'static str),
+}
+
+// The names of the objects of a backend: where its root is, and how it marks directories.
+#[derive(Clone, Debug)]
+pub(super) struct Namespace {
+    // The prefix that the root of the FTP server maps to: empty, or ending in a slash.
+    root: String,
+    marker: Marker,
+}
+
+// An object on a page of a listing.
+#[derive(Debug, PartialEq)]
+pub(super) struct Listed {
+    pub(super) name: String,
+    pub(super) metadata: ObjectMetadata,
+}
+
+// A page of the listing of a prefix: the objects under it, and with a delimiter the prefixes of
+// the subdirectories, which the objects in them are rolled up into.
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct Page {
+    pub(super) objects: Vec<Listed>,
+    pub(super) prefixes: Vec<String>,
+}
+
+impl Namespace {
+    pub(super) fn new(marker: Marker) -> Self {
+        Namespace {
+            root: String::new(),
+            marker,
+        }
+    }
+
+    // Maps the root of the FTP server to the given prefix, with or without slashes around it.
+    pub(super) fn set_root(&mut self, prefix: &str) {
+        let prefix = prefix.trim_matches('/');
+        self.root = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        };
+    }
+
+    pub(super) fn root(&self) -> &str {
+        &self.root
+    }
+
+    // The name of the object at the given path, without a trailing slash. `..` never leaves the
+    // root.
+    pub(super) fn name<P: AsRef<Path>>(&self, path: P) -> String {
+        let mut names: Vec<String> = vec![];
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                Component::ParentDir => {
+                    names.pop();
+                }
+                _ => {}
+            }
+        }
+        format!("{}{}", self.root, names.join("/"))
+    }
+
+    // The prefix of the names in the directory at the given path.
+    pub(super) fn prefix<P: AsRef<Path>>(&self, path: P) -> String {
+        let name = self.name(path);
+        if name.is_empty() || name.ends_with('/') {
+            name
+        } else {
+            format!("{}/", name)
+        }
+    }
+
+    // The name of the marker of the directory with the given prefix, that `MKD` creates.
+    pub(super) fn marker(&self, prefix: &str) -> String {
+        match self.marker {
+            Marker::TrailingSlash => prefix.to_string(),
+            Marker::File(marker) => format!("{}{}", prefix, marker),
+        }
+    }
+
+    // Whether the object with the given name is the marker of a directory.
+    pub(super) fn is_marker(&self, name: &str) -> bool {
+        match self.marker {
+            Marker::TrailingSlash => name.ends_with('/'),
+            Marker::File(marker) => name.rsplit('/').next() == Some(marker),
+        }
+    }
+
+    // The metadata of the directory with the given prefix, from the first page of its listing,
+    // or `None` if there's no such directory. It was modified when its marker was, if it has one.
+    pub(super) fn stat_dir(&self, prefix: &str, page: &Page) -> Option<ObjectMetadata> {
+        if prefix == self.root {
+            return Some(ObjectMetadata::dir(SystemTime::UNIX_EPOCH));
+        }
+        if page.objects.is_empty() && page.prefixes.is_empty() {
+            return None;
+        }
+        let marker = self.marker(prefix);
+        let modified = page
+            .objects
+            .iter()
+            .find(|object| object.name == marker)
+            .map_or(SystemTime::UNIX_EPOCH, |marker| marker.metadata.modified);
+        Some(ObjectMetadata::dir(modified))
+    }
+
+    // The name of the marker to delete to remove the directory with the given prefix, from the
+    // first page of its listing. The root can't be removed, and neither can a directory with
+    // anything but its marker in it. A directory without a marker goes away by itself with the
+    // last object in it.
+    pub(super) fn removable(&self, prefix: &str, page: &Page) -> Result<String, Error> {
+        let marker = self.marker(prefix);
+        if prefix == self.root {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        if page.objects.is_empty() && page.prefixes.is_empty() {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+        let only_marker =
+            page.prefixes.is_empty() && page.objects.len() == 1 && page.objects[0].name == marker;
+        if !only_marker {
+            return Err(Error::from(ErrorKind::NotEmpty));
+        }
+        Ok(marker)
+    }
+
+    // The entries of a page of the listing of a directory, with their paths relative to the
+    // root: the subdirectories, and then the files. Markers are left out.
+    pub(super) fn fileinfos(&self, page: Page) -> Vec<Fileinfo<PathBuf, ObjectMetadata>> {
+        let relative = |name: &str| {
+            PathBuf::from(
+                name.strip_prefix(self.root.as_str())
+                    .unwrap_or(name)
+                    .trim_end_matches('/'),
+            )
+        };
+        let mut fileinfos: Vec<_> = page
+            .prefixes
+            .iter()
+            .map(|prefix| Fileinfo {
+                path: relative(prefix),
+                metadata: ObjectMetadata::dir(SystemTime::UNIX_EPOCH),
+            })
+            .collect();
+        fileinfos.extend(
+            page.objects
+                .into_iter()
+                .filter(|object| !self.is_marker(&object.name))
+                .map(|object| Fileinfo {
+                    path: relative(&object.name),
+                    metadata: object.metadata,
+                }),
+        );
+        fileinfos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Metadata;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn listed(name: &str, len: u64) -> Listed {
+        Listed {
+            name: name.to_string(),
+            metadata: ObjectMetadata {
+                len,
+                dir: false,
+                modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_556_704_800),
+                etag: None,
+            },
+        }
+    }
+
+    #[test]
+    fn maps_paths_to_names() {
+        let mut namespace = Namespace::new(Marker::TrailingSlash);
+        assert_eq!(namespace.name("/"), "");
+        assert_eq!(namespace.name("/reports/2019.csv"), "reports/2019.csv");
+        assert_eq!(namespace.prefix("/reports"), "reports/");
+        assert_eq!(namespace.prefix("/"), "");
+
+        namespace.set_root("/incoming/");
+        assert_eq!(namespace.root(), "incoming/");
+        assert_eq!(namespace.name("/../../a.txt"), "incoming/a.txt");
+        assert_eq!(namespace.name("dir/../b.txt"), "incoming/b.txt");
+        assert_eq!(namespace.prefix("/"), "incoming/");
+        assert_eq!(namespace.marker("incoming/reports/"), "incoming/reports/");
+
+        let namespace = Namespace::new(Marker::File(".bzEmpty"));
+        assert_eq!(namespace.marker("reports/"), "reports/.bzEmpty");
+        assert!(namespace.is_marker("reports/.bzEmpty"));
+        assert!(!namespace.is_marker("reports/not.bzEmpty"));
+    }
+
+    #[test]
+    fn synthesizes_directories() {
+        let namespace = Namespace::new(Marker::TrailingSlash);
+        assert!(namespace.stat_dir("", &Page::default()).unwrap().is_dir());
+        assert_eq!(namespace.stat_dir("gone/", &Page::default()), None);
+
+        // Made with `MKD`
+        let made = Page {
+            objects: vec![listed("made/", 0)],
+            prefixes: vec![],
+        };
+        assert_eq!(
+            namespace
+                .stat_dir("made/", &made)
+                .unwrap()
+                .modified()
+                .unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_556_704_800)
+        );
+        assert_eq!(namespace.removable("made/", &made).as_deref(), Ok("made/"));
+
+        // Made by an upload, or by uploads further down
+        for page in [
+            Page {
+                objects: vec![listed("uploaded/a.txt", 1)],
+                prefixes: vec![],
+            },
+            Page {
+                objects: vec![],
+                prefixes: vec!["uploaded/deeper/".to_string()],
+            },
+        ] {
+            let metadata = namespace.stat_dir("uploaded/", &page).unwrap();
+            assert!(metadata.is_dir());
+            assert_eq!(metadata.modified().unwrap(), SystemTime::UNIX_EPOCH);
+            assert_eq!(
+                namespace.removable("uploaded/", &page),
+                Err(Error::from(ErrorKind::NotEmpty))
+            );
+        }
+        let full = Page {
+            objects: vec![listed("made/", 0), listed("made/a.txt", 1)],
+            prefixes: vec![],
+        };
+        assert_eq!(
+            namespace.removable("made/", &full),
+            Err(Error::from(ErrorKind::NotEmpty))
+        );
+        assert_eq!(
+            namespace.removable("", &made),
+            Err(Error::from(ErrorKind::PermissionDenied))
+        );
+        assert_eq!(
+            namespace.removable("gone/", &Page::default()),
+            Err(Error::from(ErrorKind::NotFound))
+        );
+    }
+
+    #[test]
+    fn lists_directories() {
+        let mut namespace = Namespace::new(Marker::File(".bzEmpty"));
+        namespace.set_root("incoming");
+        let page = Page {
+            objects: vec![
+                listed("incoming/.bzEmpty", 0),
+                listed("incoming/Tom & Jerry.txt", 43),
+            ],
+            prefixes: vec!["incoming/reports/".to_string()],
+        };
+        let fileinfos = namespace.fileinfos(page);
+        let paths: Vec<_> = fileinfos.iter().map(|fileinfo| &fileinfo.path).collect();
+        assert_eq!(
+            paths,
+            vec![&PathBuf::from("reports"), &PathBuf::from("Tom & Jerry.txt")]
+        );
+        assert!(fileinfos[0].metadata.is_dir());
+        assert_eq!(fileinfos[1].metadata.len(), 43);
+    }
+}
@@ -0,0 +1,649 @@
+//! Contains the [`Quota`] wrapper, which limits the bytes and the number of files and
+//! directories every account may have, and the [`UsageStore`] that keeps track of them.
+//!
+//! [`Quota`]: struct.Quota.html
+//! [`UsageStore`]: trait.UsageStore.html
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{future, Future, Stream};
+use futures03::compat::Future01CompatExt;
+use log::{info, warn};
+
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, ListOptions, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+/// The most an account may store. `None` means there's no limit, which is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QuotaLimits {
+    /// The number of bytes the files may take up together.
+    pub max_bytes: Option<u64>,
+    /// The number of files and directories.
+    pub max_files: Option<u64>,
+}
+
+/// What an account stores.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    /// The number of bytes the files take up together.
+    pub bytes: u64,
+    /// The number of files and directories.
+    pub files: u64,
+}
+
+/// Keeps track of the [`Usage`] of the accounts of a [`Quota`], e.g. in a database, so it
+/// survives restarts and can be shared by several servers. Changes are passed as differences,
+/// so stores can apply them atomically, whatever other sessions of the account do.
+///
+/// [`Usage`]: struct.Usage.html
+/// [`Quota`]: struct.Quota.html
+pub trait UsageStore {
+    /// Returns the usage of the given account, which is all zeroes for an unknown one.
+    fn usage(&self, account: &str) -> Box<dyn Future<Item = Usage, Error = io::Error> + Send>;
+
+    /// Adds the given (possibly negative) numbers of bytes and files to the usage of the given
+    /// account.
+    fn record(
+        &self,
+        account: &str,
+        bytes: i64,
+        files: i64,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send>;
+}
+
+/// [`UsageStore`] that keeps the usage in memory, so it's lost when the server stops. It can be
+/// seeded with what the accounts store when the server starts.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::quota::{InMemoryUsage, Usage};
+///
+/// let usage = InMemoryUsage::new();
+/// usage.set("alice", Usage { bytes: 4096, files: 3 });
+/// ```
+///
+/// [`UsageStore`]: trait.UsageStore.html
+#[derive(Debug, Default)]
+pub struct InMemoryUsage {
+    accounts: Mutex<HashMap<String, Usage>>,
+}
+
+impl InMemoryUsage {
+    /// Create a new `InMemoryUsage` that doesn't know any account yet.
+    pub fn new() -> Self {
+        InMemoryUsage::default()
+    }
+
+    /// Set the usage of the given account.
+    pub fn set<A: Into<String>>(&self, account: A, usage: Usage) {
+        self.accounts
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(account.into(), usage);
+    }
+}
+
+impl UsageStore for InMemoryUsage {
+    fn usage(&self, account: &str) -> Box<dyn Future<Item = Usage, Error = io::Error> + Send> {
+        let accounts = self
+            .accounts
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Box::new(future::ok(
+            accounts.get(account).cloned().unwrap_or_default(),
+        ))
+    }
+
+    fn record(
+        &self,
+        account: &str,
+        bytes: i64,
+        files: i64,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let mut accounts = self
+            .accounts
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let usage = accounts.entry(account.to_string()).or_default();
+        usage.bytes = usage.bytes.saturating_add_signed(bytes);
+        usage.files = usage.files.saturating_add_signed(files);
+        Box::new(future::ok(()))
+    }
+}
+
+/// The source of the [`Quota`] errors that operations fail with when they'd take an account over
+/// its quota. The server replies `552` to those.
+///
+/// [`Quota`]: ../enum.ErrorKind.html#variant.Quota
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The storage quota is exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+fn exceeded<E: From<Error>>() -> E {
+    Error::new(ErrorKind::Quota, QuotaExceeded).into()
+}
+
+/// A [`StorageBackend`] wrapper that limits what every account may store, in bytes and in
+/// files and directories, for hosting providers with per-account quotas. Uploads and `MKD`s that
+/// would go over the [`QuotaLimits`] fail with a `Quota` [`Error`], which the server replies `552`
+/// to; an upload that hits the limit halfway is stopped, and a new file it leaves behind is
+/// removed. A file that was overwritten or appended to is left as the backend's rollback left it.
+///
+/// The account is the user that logged in, unless one is set for all of them with [`account`],
+/// for when they share a root. The limits are the ones given to the wrapper, unless the [`User`]
+/// has limits of its own. The [`UsageStore`] is shared by the sessions, and only learns about
+/// changes made through the wrapper: it has to be seeded with what's already stored.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::Filesystem;
+/// use firetrap::storage::quota::{InMemoryUsage, Quota, QuotaLimits};
+/// use std::sync::Arc;
+///
+/// let usage = Arc::new(InMemoryUsage::new());
+/// let limits = QuotaLimits {
+///     max_bytes: Some(1 << 30),
+///     max_files: Some(10_000),
+/// };
+/// let server = Server::new(Box::new(move || {
+///     Quota::new(Filesystem::new("/srv/ftp"), usage.clone()).limits(limits)
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`QuotaLimits`]: struct.QuotaLimits.html
+/// [`Error`]: ../struct.Error.html
+/// [`account`]: #method.account
+/// [`User`]: ../../auth/struct.User.html
+/// [`UsageStore`]: trait.UsageStore.html
+pub struct Quota<S> {
+    inner: S,
+    usage: Arc<dyn UsageStore + Send + Sync>,
+    limits: QuotaLimits,
+    // The account for all users, if set with `account`.
+    account: Option<String>,
+    username: String,
+}
+
+impl<S> Quota<S> {
+    /// Wrap the given backend, keeping track of the usage in the given store, without limits.
+    pub fn new(inner: S, usage: Arc<dyn UsageStore + Send + Sync>) -> Self {
+        Quota {
+            inner,
+            usage,
+            limits: QuotaLimits::default(),
+            account: None,
+            username: String::new(),
+        }
+    }
+
+    /// Set the limits for the users that don't have limits of their own.
+    pub fn limits(mut self, limits: QuotaLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Count what all users store against the given account.
+    pub fn account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    fn account_name(&self) -> String {
+        self.account
+            .clone()
+            .unwrap_or_else(|| self.username.clone())
+    }
+}
+
+// How `Quota::upload` writes the file.
+#[derive(Clone, Copy, PartialEq)]
+enum Upload {
+    Put,
+    PutUnique,
+    Append,
+}
+
+// Fails once more than `remaining` bytes are read.
+struct Limited<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.remaining {
+            self.exceeded.store(true, Ordering::SeqCst);
+            return Err(io::Error::other(QuotaExceeded));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for Limited<R> {}
+
+impl<S> Quota<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata,
+    S::Error: From<io::Error> + From<Error> + Send + 'static,
+{
+    // The length of the file at the given path, or `None` if there's no file.
+    async fn file_len(&self, path: &Path) -> Option<u64> {
+        self.inner
+            .stat(path)
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    }
+
+    // The usage of the account, and its limits.
+    async fn usage(&self) -> Result<(Usage, QuotaLimits), S::Error> {
+        let usage = self.usage.usage(&self.account_name()).compat().await?;
+        Ok((usage, self.limits))
+    }
+
+    // Records a change of the usage of the account. Never fails, it only complains.
+    async fn record(&self, bytes: i64, files: i64) {
+        let account = self.account_name();
+        if let Err(e) = self.usage.record(&account, bytes, files).compat().await {
+            warn!("Failed to record the storage usage of {}: {}", account, e);
+        }
+    }
+
+    async fn upload<R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: &Path,
+        how: Upload,
+    ) -> Result<u64, S::Error> {
+        let before = self.file_len(path).await;
+        let (usage, limits) = self.usage().await?;
+        if before.is_none() && limits.max_files.is_some_and(|max| usage.files >= max) {
+            return Err(exceeded());
+        }
+        // An overwritten file doesn't count anymore.
+        let replaced = if how == Upload::Append {
+            0
+        } else {
+            before.unwrap_or(0)
+        };
+        let remaining = limits.max_bytes.map_or(u64::MAX, |max| {
+            max.saturating_add(replaced).saturating_sub(usage.bytes)
+        });
+        let over = Arc::new(AtomicBool::new(false));
+        let bytes = Limited {
+            inner: bytes,
+            remaining,
+            exceeded: Arc::clone(&over),
+        };
+        let written = match how {
+            Upload::Put => self.inner.put(bytes, path).await,
+            Upload::PutUnique => self.inner.put_unique(bytes, path).await,
+            Upload::Append => self.inner.append(bytes, path).await,
+        };
+        // Whatever the wrapped backend made of the error of the reader.
+        let over = over.load(Ordering::SeqCst);
+        if over {
+            info!("Stopped an upload to {}: over quota", path.display());
+            // A file that was there before is as the backend left it after the failed write:
+            // the old one, if it keeps its promises. Only a new file that's left behind goes.
+            if before.is_none() {
+                let _ = self.inner.del(path).await;
+            }
+        }
+        let after = self.file_len(path).await;
+        let bytes = after.unwrap_or(0) as i64 - before.unwrap_or(0) as i64;
+        let files = after.is_some() as i64 - before.is_some() as i64;
+        self.record(bytes, files).await;
+        if over {
+            Err(exceeded())
+        } else {
+            written
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Quota<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<io::Error> + From<Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.username = user.username.clone();
+        if let Some(limits) = user.quota {
+            self.limits = limits;
+        }
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        self.inner.list(path)
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.list_fmt(path, options)
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.nlst(path)
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::Put).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::PutUnique).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::Append).await
+    }
+
+    /// Returns what's left of the account's byte quota, if that's less than what the wrapped
+    /// backend has room for.
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        let free = self.inner.free_space(path).await?;
+        let (usage, limits) = self.usage().await?;
+        let left = limits.max_bytes.map(|max| max.saturating_sub(usage.bytes));
+        match (free, left) {
+            (Some(free), Some(left)) => Ok(Some(free.min(left))),
+            (free, left) => Ok(free.or(left)),
+        }
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let len = self.file_len(path.as_ref()).await;
+        self.inner.del(path).await?;
+        self.record(-(len.unwrap_or(0) as i64), -1).await;
+        Ok(())
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let (usage, limits) = self.usage().await?;
+        if limits.max_files.is_some_and(|max| usage.files >= max) {
+            return Err(exceeded());
+        }
+        self.inner.mkd(path).await?;
+        self.record(0, 1).await;
+        Ok(())
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.rmd(path).await?;
+        self.record(0, -1).await;
+        Ok(())
+    }
+
+    // `rmd_recursive`, `del_if` and `rename_if` aren't passed on, so that their default
+    // implementations go through the methods above, and every removed entry is counted.
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        // Only an entry that's replaced changes anything.
+        let replaced = self.inner.stat(to.as_ref()).await.ok().map(|metadata| {
+            if metadata.is_file() {
+                metadata.len()
+            } else {
+                0
+            }
+        });
+        self.inner.rename(from, to).await?;
+        if let Some(len) = replaced {
+            self.record(-(len as i64), -1).await;
+        }
+        Ok(())
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Error, ErrorKind, Memory};
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn quota(usage: &Arc<InMemoryUsage>, limits: QuotaLimits) -> Quota<Memory> {
+        let mut quota = Quota::new(Memory::new(), usage.clone()).limits(limits);
+        quota.set_user(&User::new("alice"));
+        quota
+    }
+
+    fn usage_of(usage: &InMemoryUsage, account: &str) -> Usage {
+        usage.usage(account).wait().unwrap()
+    }
+
+    #[test]
+    fn limits_bytes() {
+        let usage = Arc::new(InMemoryUsage::new());
+        let storage = quota(
+            &usage,
+            QuotaLimits {
+                max_bytes: Some(10),
+                max_files: None,
+            },
+        );
+
+        block_on(storage.put(Cursor::new(vec![0; 6]), "a")).unwrap();
+        assert_eq!(usage_of(&usage, "alice"), Usage { bytes: 6, files: 1 });
+        // Overwriting only counts the difference.
+        block_on(storage.put(Cursor::new(vec![0; 10]), "a")).unwrap();
+        assert_eq!(
+            usage_of(&usage, "alice"),
+            Usage {
+                bytes: 10,
+                files: 1
+            }
+        );
+
+        // Going over stops the upload and removes what it wrote.
+        let error = block_on(storage.put(Cursor::new(vec![0; 1]), "b")).unwrap_err();
+        assert_eq!(error, Error::from(ErrorKind::Quota));
+        assert!(std::error::Error::source(&error)
+            .unwrap()
+            .is::<QuotaExceeded>());
+        assert!(block_on(storage.stat("b")).is_err());
+        assert_eq!(
+            usage_of(&usage, "alice"),
+            Usage {
+                bytes: 10,
+                files: 1
+            }
+        );
+        assert_eq!(block_on(storage.free_space("/")).unwrap(), Some(0));
+
+        // Overwriting with too much keeps the file as it was.
+        let error = block_on(storage.put(Cursor::new(vec![1; 11]), "a")).unwrap_err();
+        assert_eq!(error, Error::from(ErrorKind::Quota));
+        assert_eq!(block_on(storage.stat("a")).unwrap().len(), 10);
+        assert_eq!(
+            usage_of(&usage, "alice"),
+            Usage {
+                bytes: 10,
+                files: 1
+            }
+        );
+
+        block_on(storage.del("a")).unwrap();
+        assert_eq!(usage_of(&usage, "alice"), Usage::default());
+        assert_eq!(block_on(storage.free_space("/")).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn limits_files() {
+        let usage = Arc::new(InMemoryUsage::new());
+        let storage = quota(
+            &usage,
+            QuotaLimits {
+                max_bytes: None,
+                max_files: Some(2),
+            },
+        );
+
+        block_on(storage.mkd("dir")).unwrap();
+        block_on(storage.put(Cursor::new(vec![0; 3]), "dir/a")).unwrap();
+        assert_eq!(
+            block_on(storage.mkd("other")).unwrap_err(),
+            Error::from(ErrorKind::Quota)
+        );
+        assert_eq!(
+            block_on(storage.put(Cursor::new(vec![0; 3]), "dir/b")).unwrap_err(),
+            Error::from(ErrorKind::Quota)
+        );
+        // Existing files may still be overwritten and appended to.
+        block_on(storage.put(Cursor::new(vec![0; 4]), "dir/a")).unwrap();
+        block_on(storage.append(Cursor::new(vec![0; 4]), "dir/a")).unwrap();
+        assert_eq!(usage_of(&usage, "alice"), Usage { bytes: 8, files: 2 });
+
+        block_on(storage.rmd_recursive(PathBuf::from("dir"))).unwrap();
+        assert_eq!(usage_of(&usage, "alice"), Usage::default());
+    }
+
+    #[test]
+    fn user_limits_and_shared_account() {
+        let usage = Arc::new(InMemoryUsage::new());
+        usage.set("shared", Usage { bytes: 5, files: 1 });
+        let mut storage = Quota::new(Memory::new(), usage.clone())
+            .account("shared")
+            .limits(QuotaLimits {
+                max_bytes: Some(100),
+                max_files: None,
+            });
+        storage.set_user(&User::new("bob").quota(QuotaLimits {
+            max_bytes: Some(8),
+            max_files: None,
+        }));
+
+        block_on(storage.put(Cursor::new(vec![0; 3]), "a")).unwrap();
+        assert_eq!(usage_of(&usage, "shared"), Usage { bytes: 8, files: 2 });
+        assert_eq!(usage_of(&usage, "bob"), Usage::default());
+        assert_eq!(
+            block_on(storage.put(Cursor::new(vec![0; 1]), "b")).unwrap_err(),
+            Error::from(ErrorKind::Quota)
+        );
+
+        // Replacing a file by renaming frees it.
+        block_on(storage.put(Cursor::new(vec![]), "c")).unwrap();
+        block_on(storage.rename("c", "a")).unwrap();
+        assert_eq!(usage_of(&usage, "shared"), Usage { bytes: 5, files: 2 });
+    }
+}
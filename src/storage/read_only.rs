@@ -0,0 +1,279 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::Stream;
+
+use super::{Fileinfo, HashAlgorithm, ListOptions, Metadata, Precondition, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+/// A [`StorageBackend`] wrapper that only lets clients read: downloads, listings and everything
+/// else that doesn't change anything are passed on to the wrapped backend, while uploads,
+/// deletes, renames, new and removed directories, and changed modification times or permissions
+/// fail with a permission error, which the server replies `550` to. This is what public mirrors
+/// want, whoever logs in.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, ReadOnly};
+///
+/// let server = Server::new(Box::new(|| ReadOnly::new(Filesystem::new("/srv/mirror"))));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+pub struct ReadOnly<S> {
+    inner: S,
+}
+
+impl<S> ReadOnly<S> {
+    /// Wrap the given backend.
+    pub fn new(inner: S) -> Self {
+        ReadOnly { inner }
+    }
+}
+
+// Fails the way every operation that would change something does.
+pub(super) fn denied<T, E>() -> Result<T, E>
+where
+    E: From<std::io::Error>,
+{
+    Err(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "The storage is read-only",
+    )
+    .into())
+}
+
+#[async_trait]
+impl<S> StorageBackend for ReadOnly<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        self.inner.list(path)
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.list_fmt(path, options)
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.nlst(path)
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64, Self::Error> {
+        denied()
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64, Self::Error> {
+        denied()
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        _bytes: R,
+        _path: P,
+    ) -> Result<u64, Self::Error> {
+        denied()
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, _path: P) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn del_if(&self, _path: PathBuf, _precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        denied()
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, _path: P) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, _path: P) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn rmd_recursive(&self, _path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        denied()
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, _from: P, _to: P) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn rename_if(
+        &self,
+        _from: PathBuf,
+        _to: PathBuf,
+        _precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        denied()
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+        _mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, _path: P, _mode: u32) -> Result<(), Self::Error> {
+        denied()
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Error, ErrorKind, Memory};
+    use futures::Future;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::{Cursor, Read};
+    use std::sync::Arc;
+
+    #[test]
+    fn only_reads() {
+        let memory = Memory::new();
+        block_on(memory.mkd("dir")).unwrap();
+        block_on(memory.put(Cursor::new(b"contents".to_vec()), "dir/file.txt")).unwrap();
+        let storage = Arc::new(ReadOnly::new(memory.clone()));
+
+        let mut contents = String::new();
+        block_on(storage.get("dir/file.txt"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "contents");
+        assert_eq!(storage.list("dir").collect().wait().unwrap().len(), 1);
+        assert!(block_on(storage.stat("dir/file.txt")).unwrap().is_file());
+
+        let errors = vec![
+            block_on(storage.put(Cursor::new(b"new".to_vec()), "dir/new.txt")).unwrap_err(),
+            block_on(storage.append(Cursor::new(b"more".to_vec()), "dir/file.txt")).unwrap_err(),
+            block_on(storage.del("dir/file.txt")).unwrap_err(),
+            block_on(storage.mkd("other")).unwrap_err(),
+            block_on(storage.rmd("dir")).unwrap_err(),
+            block_on(storage.rmd_recursive(PathBuf::from("dir"))).unwrap_err(),
+            block_on(storage.rename("dir", "moved")).unwrap_err(),
+            block_on(storage.set_mtime("dir/file.txt", SystemTime::UNIX_EPOCH)).unwrap_err(),
+            block_on(storage.chmod("dir/file.txt", 0o777)).unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!(error, Error::from(ErrorKind::PermissionDenied));
+        }
+
+        // Nothing changed underneath.
+        let names: Vec<_> = memory
+            .list("/")
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(names, vec![PathBuf::from("dir")]);
+        assert_eq!(block_on(memory.stat("dir/file.txt")).unwrap().len(), 8);
+    }
+}
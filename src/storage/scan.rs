@@ -0,0 +1,803 @@
+//! Contains the [`Scanned`] wrapper, which scans uploads for viruses before they get to the
+//! wrapped backend, the [`Scanner`] trait it scans with, and the scanners that ask [`Clamd`] or an
+//! [`Icap`] server.
+//!
+//! [`Scanned`]: struct.Scanned.html
+//! [`Scanner`]: trait.Scanner.html
+//! [`Clamd`]: struct.Clamd.html
+//! [`Icap`]: struct.Icap.html
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::{Future, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+
+use super::{
+    blocking, Fileinfo, HashAlgorithm, ListOptions, Metadata, Precondition, StorageBackend,
+};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+use crate::staging::{StagedFile, Staging, StagingArea};
+
+/// What a [`Scanner`] found.
+///
+/// [`Scanner`]: trait.Scanner.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Nothing.
+    Clean,
+    /// The malware with the given name, like `Eicar-Test-Signature`.
+    Infected(String),
+}
+
+/// Scans the contents of files for malware, for the [`Scanned`] wrapper.
+///
+/// Besides [`Clamd`] and [`Icap`], closures that take the path and the contents of an upload are
+/// scanners.
+///
+/// [`Scanned`]: struct.Scanned.html
+/// [`Clamd`]: struct.Clamd.html
+/// [`Icap`]: struct.Icap.html
+pub trait Scanner: Send + Sync {
+    /// Scans everything `contents` has, which is what would end up at `path`. This runs on the
+    /// blocking section of the tokio threadpool, so it may block.
+    fn scan(&self, path: &Path, contents: &mut dyn Read) -> io::Result<Verdict>;
+}
+
+impl<F> Scanner for F
+where
+    F: Fn(&Path, &mut dyn Read) -> io::Result<Verdict> + Send + Sync,
+{
+    fn scan(&self, path: &Path, contents: &mut dyn Read) -> io::Result<Verdict> {
+        self(path, contents)
+    }
+}
+
+// A connection to a scanner, over TCP or a Unix socket.
+trait Connection: Read + Write {}
+
+impl<T: Read + Write> Connection for T {}
+
+enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    fn connect(&self, timeout: Duration) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Address::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Ok(Box::new(stream))
+            }
+            Address::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+// How much of the contents goes to the scanner at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// A [`Scanner`] that streams the contents to the `clamd` daemon of ClamAV, with its `INSTREAM`
+/// command. Keep `StreamMaxLength` in `clamd.conf` above the biggest upload: `clamd` hangs up on
+/// bigger ones, which fails the upload.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::scan::Clamd;
+/// use std::time::Duration;
+///
+/// let clamd = Clamd::unix("/run/clamav/clamd.ctl").timeout(Duration::from_secs(60));
+/// ```
+///
+/// [`Scanner`]: trait.Scanner.html
+pub struct Clamd {
+    address: Address,
+    timeout: Duration,
+}
+
+impl Clamd {
+    /// Ask the `clamd` that listens at the given TCP address, like `127.0.0.1:3310`.
+    pub fn tcp<A: Into<String>>(address: A) -> Self {
+        Clamd {
+            address: Address::Tcp(address.into()),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Ask the `clamd` that listens at the Unix socket at the given path.
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        Clamd {
+            address: Address::Unix(path.into()),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set how long to wait for `clamd` to take more of the contents, or to answer. The default
+    /// is 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Scanner for Clamd {
+    fn scan(&self, _path: &Path, contents: &mut dyn Read) -> io::Result<Verdict> {
+        let mut connection = self.address.connect(self.timeout)?;
+        connection.write_all(b"zINSTREAM\0")?;
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            let n = match contents.read(&mut chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            connection.write_all(&(n as u32).to_be_bytes())?;
+            if n == 0 {
+                break;
+            }
+            connection.write_all(&chunk[..n])?;
+        }
+        connection.flush()?;
+
+        let mut reply = vec![];
+        BufReader::new(connection.take(4096)).read_until(0, &mut reply)?;
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches('\0').trim();
+        let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+        if result == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(name) = result.strip_suffix(" FOUND") {
+            Ok(Verdict::Infected(name.to_string()))
+        } else {
+            Err(io::Error::other(format!("clamd replied {:?}", reply)))
+        }
+    }
+}
+
+/// A [`Scanner`] that sends the contents to an ICAP server (RFC 3507), like c-icap or the ICAP
+/// service of a commercial scanner, as the body of an HTTP response in a `RESPMOD` request. The
+/// server answering `204 No Content` means the file is clean. Answering `200` with the contents
+/// changed is what servers do to block a file, so that means it's infected, with the name from
+/// the `X-Infection-Found` or `X-Virus-ID` header.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::storage::scan::Icap;
+///
+/// let icap = Icap::new("10.0.0.5:1344", "avscan");
+/// ```
+///
+/// [`Scanner`]: trait.Scanner.html
+pub struct Icap {
+    address: String,
+    service: String,
+    timeout: Duration,
+}
+
+impl Icap {
+    /// Ask the given service of the ICAP server at the given TCP address.
+    pub fn new<A: Into<String>, S: Into<String>>(address: A, service: S) -> Self {
+        Icap {
+            address: address.into(),
+            service: service.into(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set how long to wait for the server to take more of the contents, or to answer. The
+    /// default is 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+// The path, %-encoded for the request line of the HTTP request that ICAP servers get to see.
+fn url_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.to_string_lossy().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    if !encoded.starts_with('/') {
+        encoded.insert(0, '/');
+    }
+    encoded
+}
+
+// The name of the malware that the headers of an ICAP reply name, like
+// `X-Infection-Found: Type=0; Resolution=2; Threat=EICAR-Test-File;`.
+fn threat(headers: &[String]) -> Option<String> {
+    headers.iter().find_map(|header| {
+        let (name, value) = header.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "x-infection-found" => value
+                .split(';')
+                .find_map(|field| field.trim().strip_prefix("Threat="))
+                .map(str::to_string),
+            "x-virus-id" | "x-violations-found" if !value.is_empty() => Some(value.to_string()),
+            _ => None,
+        }
+    })
+}
+
+impl Scanner for Icap {
+    fn scan(&self, path: &Path, contents: &mut dyn Read) -> io::Result<Verdict> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut stream = io::BufWriter::new(stream);
+
+        let request = format!("GET {} HTTP/1.1\r\nHost: firetrap\r\n\r\n", url_path(path));
+        let response = "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\r\n";
+        write!(
+            stream,
+            "RESPMOD icap://{}/{} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\n\
+             Encapsulated: req-hdr=0, res-hdr={}, res-body={}\r\n\r\n{}{}",
+            self.address,
+            self.service,
+            self.address,
+            request.len(),
+            request.len() + response.len(),
+            request,
+            response
+        )?;
+        let mut chunk = vec![0; CHUNK];
+        loop {
+            let n = match contents.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            write!(stream, "{:x}\r\n", n)?;
+            stream.write_all(&chunk[..n])?;
+            stream.write_all(b"\r\n")?;
+        }
+        stream.write_all(b"0\r\n\r\n")?;
+        let stream = stream.into_inner().map_err(|e| e.into_error())?;
+
+        let mut reader = BufReader::new(stream.take(64 * 1024));
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        let mut headers = vec![];
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            headers.push(header.trim().to_string());
+        }
+        match status.split_whitespace().nth(1) {
+            Some("204") => Ok(Verdict::Clean),
+            Some("200") => Ok(Verdict::Infected(
+                threat(&headers).unwrap_or_else(|| "an unnamed threat".to_string()),
+            )),
+            _ => Err(io::Error::other(format!(
+                "The ICAP server replied {:?}",
+                status.trim()
+            ))),
+        }
+    }
+}
+
+/// A [`StorageBackend`] wrapper that scans every upload with a [`Scanner`] before it gets to the
+/// wrapped backend, so infected files are never visible there, whichever backend that is.
+///
+/// Uploads go to a [`Staging`] directory on the local disk first, are scanned there, and are only
+/// passed on when they're clean. An appended file is scanned as a whole, with what it had before.
+/// Infected uploads fail with a permission error, which the server replies `550` to, and can be
+/// kept in a quarantine directory on the local disk, out of reach of the clients, for a closer
+/// look. Uploads also fail when the scanner can't be reached, so nothing goes unscanned.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::Filesystem;
+/// use firetrap::storage::scan::{Clamd, Scanned};
+///
+/// let server = Server::new(Box::new(|| {
+///     Scanned::new(Filesystem::new("/srv/ftp"), Clamd::tcp("127.0.0.1:3310"))
+///         .quarantine("/var/lib/firetrap/quarantine")
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`Scanner`]: trait.Scanner.html
+/// [`Staging`]: ../../staging/struct.Staging.html
+pub struct Scanned<S> {
+    inner: S,
+    scanner: Arc<dyn Scanner>,
+    staging: Staging,
+    quarantine: Option<PathBuf>,
+}
+
+impl<S> Scanned<S> {
+    /// Wrap the given backend, scanning uploads with the given scanner. Uploads are staged in the
+    /// default [`StagingArea`], and infected ones thrown away.
+    ///
+    /// [`StagingArea`]: ../../staging/struct.StagingArea.html
+    pub fn new<T: Scanner + 'static>(inner: S, scanner: T) -> Self {
+        Scanned {
+            inner,
+            scanner: Arc::new(scanner),
+            staging: Self::session(&StagingArea::default()),
+            quarantine: None,
+        }
+    }
+
+    /// Stage the uploads in the given area, which has to be on the local disk.
+    pub fn staging(mut self, area: StagingArea) -> Self {
+        self.staging = Self::session(&area);
+        self
+    }
+
+    /// Keep infected uploads in the given directory on the local disk, instead of throwing them
+    /// away. They're named after the time of the upload and the name of the file. The directory is
+    /// created if it isn't there.
+    pub fn quarantine<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.quarantine = Some(dir.into());
+        self
+    }
+
+    fn session(area: &StagingArea) -> Staging {
+        area.session(&format!("scan-{:016x}", rand::random::<u64>()))
+    }
+}
+
+// How `Scanned::upload` writes the file.
+#[derive(Clone, Copy, PartialEq)]
+enum Upload {
+    Put,
+    PutUnique,
+    Append,
+}
+
+// Scans the upload, after what the file it's appended to had, if anything. Resolves to the upload
+// if it's clean.
+fn scan(
+    scanner: Arc<dyn Scanner>,
+    quarantine: Option<PathBuf>,
+    path: PathBuf,
+    original: Option<StagedFile>,
+    upload: StagedFile,
+) -> impl Future<Item = StagedFile, Error = io::Error> {
+    blocking(move || {
+        let contents = || -> io::Result<Box<dyn Read>> {
+            let upload = File::open(upload.path())?;
+            Ok(match &original {
+                Some(original) => Box::new(File::open(original.path())?.chain(upload)),
+                None => Box::new(upload),
+            })
+        };
+        let name = match scanner.scan(&path, &mut contents()?)? {
+            Verdict::Clean => return Ok(upload),
+            Verdict::Infected(name) => name,
+        };
+        match &quarantine {
+            Some(dir) => {
+                let since_epoch = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let file_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let kept = dir.join(format!(
+                    "{}-{:08x}-{}",
+                    since_epoch.as_secs(),
+                    rand::random::<u32>(),
+                    file_name
+                ));
+                let result = std::fs::create_dir_all(dir)
+                    .and_then(|()| File::create(&kept))
+                    .and_then(|mut file| io::copy(&mut contents()?, &mut file));
+                match result {
+                    Ok(_) => warn!(
+                        "Rejected the upload to {:?}, infected with {}, kept as {:?}",
+                        path, name, kept
+                    ),
+                    Err(e) => warn!(
+                        "Rejected the upload to {:?}, infected with {}, and failed to keep it: {}",
+                        path, name, e
+                    ),
+                }
+            }
+            None => warn!("Rejected the upload to {:?}, infected with {}", path, name),
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("The file is infected with {}", name),
+        ))
+    })
+}
+
+impl<S> Scanned<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Error: From<io::Error> + Send + 'static,
+{
+    // Stages and scans the upload, then passes the clean upload on to the wrapped backend.
+    async fn upload<R>(&self, bytes: R, path: &Path, how: Upload) -> Result<u64, S::Error>
+    where
+        R: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        let original = match how {
+            Upload::Append => match self.inner.get(path).await {
+                Ok(file) => Some(self.staging.stage(file).compat().await?),
+                // There's nothing to append to yet.
+                Err(_) => None,
+            },
+            Upload::Put | Upload::PutUnique => None,
+        };
+        let upload = self.staging.stage(bytes).compat().await?;
+        let scanner = Arc::clone(&self.scanner);
+        let quarantine = self.quarantine.clone();
+        let upload = scan(scanner, quarantine, path.to_path_buf(), original, upload)
+            .compat()
+            .await?;
+        match how {
+            Upload::Put => self.inner.put(upload, path).await,
+            Upload::PutUnique => self.inner.put_unique(upload, path).await,
+            Upload::Append => self.inner.append(upload, path).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Scanned<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        self.inner.list(path)
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.list_fmt(path, options)
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.nlst(path)
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::Put).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::PutUnique).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path.as_ref(), Upload::Append).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.del(path).await
+    }
+
+    async fn del_if(&self, path: PathBuf, precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.del_if(path, precondition).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.rmd(path).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.rmd_recursive(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.rename_if(from, to, precondition).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use crate::storage::{Error, ErrorKind, Memory};
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    fn infected(contents: &[u8]) -> bool {
+        contents.windows(EICAR.len()).any(|window| window == EICAR)
+    }
+
+    // Answers the given number of INSTREAM requests like clamd does.
+    fn fake_clamd(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut command = [0; 10];
+                stream.read_exact(&mut command).unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut contents = vec![];
+                loop {
+                    let mut len = [0; 4];
+                    stream.read_exact(&mut len).unwrap();
+                    let len = u32::from_be_bytes(len) as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; len];
+                    stream.read_exact(&mut chunk).unwrap();
+                    contents.extend(chunk);
+                }
+                let reply: &[u8] = if infected(&contents) {
+                    b"stream: Eicar-Test-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+        address
+    }
+
+    // Answers the given number of RESPMOD requests like an ICAP server does.
+    fn fake_icap(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut byte = [0; 1];
+                while !request.ends_with(b"\r\n0\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                assert!(request.starts_with("RESPMOD icap://"));
+                assert!(request.contains("/avscan ICAP/1.0\r\n"));
+                assert!(request.contains("GET /dir/new%20file.txt HTTP/1.1\r\n"));
+                let reply: &[u8] = if infected(request.as_bytes()) {
+                    b"ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=EICAR;\r\nEncapsulated: res-hdr=0, null-body=19\r\n\r\nHTTP/1.1 403 Forbidden\r\n\r\n"
+                } else {
+                    b"ICAP/1.0 204 No Content\r\nEncapsulated: null-body=0\r\n\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn asks_clamd() {
+        let clamd = Clamd::tcp(fake_clamd(2));
+        let big = vec![b'a'; 200_000];
+        assert_eq!(
+            clamd.scan(Path::new("big"), &mut Cursor::new(big)).unwrap(),
+            Verdict::Clean
+        );
+        let mut contents = b"junk".to_vec();
+        contents.extend(EICAR);
+        assert_eq!(
+            clamd
+                .scan(Path::new("eicar"), &mut Cursor::new(contents))
+                .unwrap(),
+            Verdict::Infected("Eicar-Test-Signature".to_string())
+        );
+        // Nothing listens anymore.
+        assert!(clamd
+            .scan(Path::new("eicar"), &mut Cursor::new(vec![]))
+            .is_err());
+    }
+
+    #[test]
+    fn asks_icap_servers() {
+        let icap = Icap::new(fake_icap(2), "avscan");
+        let path = Path::new("/dir/new file.txt");
+        assert_eq!(
+            icap.scan(path, &mut Cursor::new(b"harmless".to_vec()))
+                .unwrap(),
+            Verdict::Clean
+        );
+        assert_eq!(
+            icap.scan(path, &mut Cursor::new(EICAR.to_vec())).unwrap(),
+            Verdict::Infected("EICAR".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_infected_uploads() {
+        compat::on_runtime(move || {
+            let root = tempfile::TempDir::new().unwrap();
+            let quarantine = root.path().join("quarantine");
+            let scanner = |_: &Path, contents: &mut dyn Read| {
+                let mut data = vec![];
+                contents.read_to_end(&mut data)?;
+                Ok(if infected(&data) {
+                    Verdict::Infected("Eicar-Test-Signature".to_string())
+                } else {
+                    Verdict::Clean
+                })
+            };
+            let storage = Scanned::new(Memory::new(), scanner)
+                .staging(StagingArea::new(root.path().join("staging")))
+                .quarantine(&quarantine);
+
+            block_on(storage.put(Cursor::new(b"clean".to_vec()), "/clean.txt")).unwrap();
+            assert_eq!(block_on(storage.stat("/clean.txt")).unwrap().len(), 5);
+
+            let error =
+                block_on(storage.put(Cursor::new(EICAR.to_vec()), "/eicar.com")).unwrap_err();
+            assert_eq!(error, Error::from(ErrorKind::PermissionDenied));
+            assert!(block_on(storage.stat("/eicar.com")).is_err());
+
+            // What's appended only makes it infected with what the file had already.
+            block_on(storage.put(Cursor::new(EICAR[..20].to_vec()), "/parts")).unwrap();
+            assert!(block_on(storage.append(Cursor::new(EICAR[20..].to_vec()), "/parts")).is_err());
+            assert_eq!(block_on(storage.stat("/parts")).unwrap().len(), 20);
+            block_on(storage.append(Cursor::new(b"!".to_vec()), "/parts")).unwrap();
+            assert_eq!(block_on(storage.stat("/parts")).unwrap().len(), 21);
+
+            let mut kept: Vec<Vec<u8>> = std::fs::read_dir(&quarantine)
+                .unwrap()
+                .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+                .collect();
+            kept.sort();
+            assert_eq!(kept, vec![EICAR.to_vec(), EICAR.to_vec()]);
+        });
+    }
+}
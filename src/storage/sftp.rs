@@ -0,0 +1,1599 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::{future, stream, Async, Future, Poll, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+use tokio::prelude::AsyncRead;
+
+use super::{blocking, Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+
+// The version of the protocol we speak: the one of draft-ietf-secsh-filexfer-02, which is what
+// OpenSSH implements.
+const VERSION: u32 = 3;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+const SSH_FXP_EXTENDED: u8 = 200;
+const SSH_FXP_EXTENDED_REPLY: u8 = 201;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_APPEND: u32 = 0x04;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+const SSH_FXF_EXCL: u32 = 0x20;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+const STATVFS: &str = "statvfs@openssh.com";
+
+// How many bytes to ask for, or send, in one request. Servers have to accept packets of up to
+// 34000 bytes, so this leaves room for the rest of the packet.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+// Replies bigger than this are taken for a broken connection rather than allocated.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// The number of idle SFTP connections that a backend keeps around by default.
+pub const DEFAULT_MAX_IDLE: usize = 8;
+
+/// Opens the connections to an SFTP server for an [`SftpStorageBackend`]: the two halves of a
+/// byte stream that speaks the SFTP protocol, like the stdin and stdout of the `sftp` subsystem of
+/// an SSH session. [`Ssh`] runs the OpenSSH client for this; other implementations can use an SSH
+/// library, or connect to an SFTP server that needs no SSH at all.
+///
+/// [`SftpStorageBackend`]: ./struct.SftpStorageBackend.html
+/// [`Ssh`]: ./struct.Ssh.html
+pub trait Connector: Send + Sync {
+    /// Opens a new connection to the SFTP server, returning what to read the replies from and what
+    /// to write the requests to.
+    fn connect(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)>;
+}
+
+/// A [`Connector`] that runs the OpenSSH client, `ssh -s <destination> sftp`, for every
+/// connection, like `sshfs` does. That way the SSH configuration, keys, agent and known hosts of
+/// the user that runs the server all apply. The client runs in batch mode, so it has to be able to
+/// log in without asking for a password or passphrase, and to know the host key already.
+///
+/// [`Connector`]: ./trait.Connector.html
+#[derive(Clone, Debug)]
+pub struct Ssh {
+    program: OsString,
+    destination: String,
+    port: Option<u16>,
+    identity_file: Option<PathBuf>,
+    options: Vec<String>,
+}
+
+impl Ssh {
+    /// Connect to the given destination, like `backup@files.example.com`.
+    pub fn new<D: Into<String>>(destination: D) -> Self {
+        Ssh {
+            program: OsString::from("ssh"),
+            destination: destination.into(),
+            port: None,
+            identity_file: None,
+            options: vec![],
+        }
+    }
+
+    /// Run the given program instead of the `ssh` in the `PATH`.
+    pub fn program<P: AsRef<OsStr>>(mut self, program: P) -> Self {
+        self.program = program.as_ref().to_os_string();
+        self
+    }
+
+    /// Connect to the given port instead of the one from the SSH configuration (`-p`).
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Log in with the private key in the given file (`-i`).
+    pub fn identity_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.identity_file = Some(path.into());
+        self
+    }
+
+    /// Pass the given option to the client (`-o`), like `StrictHostKeyChecking=yes`.
+    pub fn option<O: Into<String>>(mut self, option: O) -> Self {
+        self.options.push(option.into());
+        self
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg("-o").arg("BatchMode=yes");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity_file) = &self.identity_file {
+            command.arg("-i").arg(identity_file);
+        }
+        for option in &self.options {
+            command.arg("-o").arg(option);
+        }
+        command.arg("-s").arg(&self.destination).arg("sftp");
+        command
+    }
+}
+
+// The output of the SSH client, which takes the client down with it.
+struct SshOutput {
+    stdout: ChildStdout,
+    child: Child,
+}
+
+impl Read for SshOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for SshOutput {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Connector for Ssh {
+    fn connect(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let mut child = self
+            .command()
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::other("No stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("No stdout"))?;
+        Ok((Box::new(SshOutput { stdout, child }), Box::new(stdin)))
+    }
+}
+
+/// The [`Metadata`] of a file on an SFTP server: the attributes that the server sends along.
+/// Servers may leave any of them out, so what's missing reads as zero.
+///
+/// [`Metadata`]: ../trait.Metadata.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SftpMetadata {
+    size: Option<u64>,
+    ids: Option<(u32, u32)>,
+    permissions: Option<u32>,
+    // The access and modification times, in seconds since the epoch.
+    times: Option<(u32, u32)>,
+}
+
+impl SftpMetadata {
+    /// Returns the permissions of the file, including its type, like `0o100644`.
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+
+    fn file_type(&self) -> u32 {
+        self.permissions.unwrap_or(0) & 0o170_000
+    }
+
+    fn encode(&self, packet: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+        }
+        if self.ids.is_some() {
+            flags |= SSH_FILEXFER_ATTR_UIDGID;
+        }
+        if self.permissions.is_some() {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+        }
+        if self.times.is_some() {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+        }
+        put_u32(packet, flags);
+        if let Some(size) = self.size {
+            put_u64(packet, size);
+        }
+        if let Some((uid, gid)) = self.ids {
+            put_u32(packet, uid);
+            put_u32(packet, gid);
+        }
+        if let Some(permissions) = self.permissions {
+            put_u32(packet, permissions);
+        }
+        if let Some((atime, mtime)) = self.times {
+            put_u32(packet, atime);
+            put_u32(packet, mtime);
+        }
+    }
+
+    fn decode(fields: &mut Fields) -> io::Result<Self> {
+        let flags = fields.u32()?;
+        let mut metadata = SftpMetadata::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            metadata.size = Some(fields.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            metadata.ids = Some((fields.u32()?, fields.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            metadata.permissions = Some(fields.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            metadata.times = Some((fields.u32()?, fields.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..fields.u32()? {
+                fields.string()?;
+                fields.string()?;
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+impl Metadata for SftpMetadata {
+    fn len(&self) -> u64 {
+        self.size.unwrap_or(0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file_type() == 0o040_000
+    }
+
+    fn is_file(&self) -> bool {
+        self.file_type() == 0o100_000
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.file_type() == 0o120_000
+    }
+
+    fn modified(&self) -> super::Result<SystemTime> {
+        self.times
+            .map(|(_, mtime)| UNIX_EPOCH + Duration::from_secs(u64::from(mtime)))
+            .ok_or_else(|| Error::backend("The modification time is unknown"))
+    }
+
+    fn gid(&self) -> u32 {
+        self.ids.map_or(0, |(_, gid)| gid)
+    }
+
+    fn uid(&self) -> u32 {
+        self.ids.map_or(0, |(uid, _)| uid)
+    }
+}
+
+fn put_u32(packet: &mut Vec<u8>, value: u32) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(packet: &mut Vec<u8>, value: u64) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(packet: &mut Vec<u8>, value: &[u8]) {
+    put_u32(packet, value.len() as u32);
+    packet.extend_from_slice(value);
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed SFTP packet")
+}
+
+// Reads the fields of a packet, front to back.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(malformed());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+// Turns an `SSH_FXP_STATUS` reply into an error, unless it's `SSH_FX_OK`.
+fn status(payload: &[u8]) -> io::Result<u32> {
+    let mut fields = Fields(payload);
+    let code = fields.u32()?;
+    let message = fields
+        .string()
+        .map(|message| String::from_utf8_lossy(message).into_owned())
+        .unwrap_or_default();
+    match code {
+        SSH_FX_OK | SSH_FX_EOF => Ok(code),
+        SSH_FX_NO_SUCH_FILE => Err(io::Error::new(io::ErrorKind::NotFound, message)),
+        SSH_FX_PERMISSION_DENIED => Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+        _ => Err(io::Error::other(format!(
+            "SFTP error {}: {}",
+            code, message
+        ))),
+    }
+}
+
+fn unexpected(kind: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected SFTP reply of type {}", kind),
+    )
+}
+
+// A connection to the SFTP server, which handles one request at a time.
+struct Session {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
+    next_id: u32,
+    extensions: Vec<String>,
+    // Set when the connection failed, so it doesn't go back into the pool.
+    broken: bool,
+}
+
+impl Session {
+    fn connect(connector: &dyn Connector) -> io::Result<Session> {
+        let (reader, writer) = connector.connect()?;
+        let mut session = Session {
+            reader: BufReader::new(reader),
+            writer,
+            next_id: 0,
+            extensions: vec![],
+            broken: false,
+        };
+        let mut init = vec![SSH_FXP_INIT];
+        put_u32(&mut init, VERSION);
+        session.send(&init)?;
+        let (kind, payload) = session.receive()?;
+        if kind != SSH_FXP_VERSION {
+            return Err(unexpected(kind));
+        }
+        let mut fields = Fields(&payload);
+        if fields.u32()? < VERSION {
+            return Err(io::Error::other("The SFTP server speaks an older version"));
+        }
+        while !fields.0.is_empty() {
+            let name = fields.string()?;
+            fields.string()?;
+            session
+                .extensions
+                .push(String::from_utf8_lossy(name).into_owned());
+        }
+        Ok(session)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        let sent = self
+            .writer
+            .write_all(&(packet.len() as u32).to_be_bytes())
+            .and_then(|_| self.writer.write_all(packet))
+            .and_then(|_| self.writer.flush());
+        self.broken |= sent.is_err();
+        sent
+    }
+
+    fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let received = (|| {
+            let mut len = [0; 4];
+            self.reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 || len > MAX_PACKET_SIZE {
+                return Err(malformed());
+            }
+            let mut packet = vec![0; len];
+            self.reader.read_exact(&mut packet)?;
+            let kind = packet.remove(0);
+            Ok((kind, packet))
+        })();
+        self.broken |= received.is_err();
+        received
+    }
+
+    // Sends a request with the given fields, and returns the type and fields of the reply.
+    fn request(&mut self, kind: u8, fields: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        let mut packet = Vec::with_capacity(5 + fields.len());
+        packet.push(kind);
+        put_u32(&mut packet, id);
+        packet.extend_from_slice(fields);
+        self.send(&packet)?;
+        let (kind, payload) = self.receive()?;
+        let mut reply = Fields(&payload);
+        if reply.u32()? != id {
+            self.broken = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SFTP reply to another request",
+            ));
+        }
+        Ok((kind, reply.0.to_vec()))
+    }
+
+    // Sends a request that the server answers with a status.
+    fn command(&mut self, kind: u8, fields: &[u8]) -> io::Result<()> {
+        match self.request(kind, fields)? {
+            (SSH_FXP_STATUS, payload) => status(&payload).map(|_| ()),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    fn path_request(&mut self, kind: u8, path: &str) -> io::Result<(u8, Vec<u8>)> {
+        let mut fields = vec![];
+        put_string(&mut fields, path.as_bytes());
+        self.request(kind, &fields)
+    }
+
+    fn path_command(&mut self, kind: u8, path: &str) -> io::Result<()> {
+        let mut fields = vec![];
+        put_string(&mut fields, path.as_bytes());
+        self.command(kind, &fields)
+    }
+
+    fn attrs(reply: (u8, Vec<u8>)) -> io::Result<SftpMetadata> {
+        match reply {
+            (SSH_FXP_ATTRS, payload) => SftpMetadata::decode(&mut Fields(&payload)),
+            (SSH_FXP_STATUS, payload) => status(&payload).and(Err(malformed())),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    fn handle(reply: (u8, Vec<u8>)) -> io::Result<Vec<u8>> {
+        match reply {
+            (SSH_FXP_HANDLE, payload) => Fields(&payload).string().map(<[u8]>::to_vec),
+            (SSH_FXP_STATUS, payload) => status(&payload).and(Err(malformed())),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    fn lstat(&mut self, path: &str) -> io::Result<SftpMetadata> {
+        Self::attrs(self.path_request(SSH_FXP_LSTAT, path)?)
+    }
+
+    fn open(&mut self, path: &str, flags: u32, attrs: &SftpMetadata) -> io::Result<Vec<u8>> {
+        let mut fields = vec![];
+        put_string(&mut fields, path.as_bytes());
+        put_u32(&mut fields, flags);
+        attrs.encode(&mut fields);
+        Self::handle(self.request(SSH_FXP_OPEN, &fields)?)
+    }
+
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let mut fields = vec![];
+        put_string(&mut fields, handle);
+        self.command(SSH_FXP_CLOSE, &fields)
+    }
+
+    // Returns `None` at the end of the file.
+    fn read(&mut self, handle: &[u8], offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut fields = vec![];
+        put_string(&mut fields, handle);
+        put_u64(&mut fields, offset);
+        put_u32(&mut fields, len.min(CHUNK_SIZE) as u32);
+        match self.request(SSH_FXP_READ, &fields)? {
+            (SSH_FXP_DATA, payload) => Fields(&payload).string().map(|data| Some(data.to_vec())),
+            (SSH_FXP_STATUS, payload) => status(&payload).map(|_| None),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+
+    fn write(&mut self, handle: &[u8], offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut fields = Vec::with_capacity(handle.len() + data.len() + 16);
+        put_string(&mut fields, handle);
+        put_u64(&mut fields, offset);
+        put_string(&mut fields, data);
+        self.command(SSH_FXP_WRITE, &fields)
+    }
+
+    fn setstat(&mut self, path: &str, attrs: &SftpMetadata) -> io::Result<()> {
+        let mut fields = vec![];
+        put_string(&mut fields, path.as_bytes());
+        attrs.encode(&mut fields);
+        self.command(SSH_FXP_SETSTAT, &fields)
+    }
+
+    fn fsetstat(&mut self, handle: &[u8], attrs: &SftpMetadata) -> io::Result<()> {
+        let mut fields = vec![];
+        put_string(&mut fields, handle);
+        attrs.encode(&mut fields);
+        self.command(SSH_FXP_FSETSTAT, &fields)
+    }
+
+    fn readdir(&mut self, path: &str) -> io::Result<Vec<(String, SftpMetadata)>> {
+        let handle = Self::handle(self.path_request(SSH_FXP_OPENDIR, path)?)?;
+        let mut entries = vec![];
+        let mut fields = vec![];
+        put_string(&mut fields, &handle);
+        let listed = loop {
+            match self.request(SSH_FXP_READDIR, &fields) {
+                Ok((SSH_FXP_NAME, payload)) => {
+                    let mut names = Fields(&payload);
+                    for _ in 0..names.u32()? {
+                        let name = String::from_utf8_lossy(names.string()?).into_owned();
+                        names.string()?;
+                        let metadata = SftpMetadata::decode(&mut names)?;
+                        if name != "." && name != ".." {
+                            entries.push((name, metadata));
+                        }
+                    }
+                }
+                Ok((SSH_FXP_STATUS, payload)) => break status(&payload).map(|_| ()),
+                Ok((kind, _)) => break Err(unexpected(kind)),
+                Err(e) => break Err(e),
+            }
+        };
+        let closed = self.close(&handle);
+        listed.and(closed).map(|_| entries)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let mut fields = vec![];
+        // Plain renames fail when the target exists, unlike RNTO on most servers.
+        let posix = self.extensions.iter().any(|name| name == POSIX_RENAME);
+        if posix {
+            put_string(&mut fields, POSIX_RENAME.as_bytes());
+        }
+        put_string(&mut fields, from.as_bytes());
+        put_string(&mut fields, to.as_bytes());
+        if posix {
+            self.command(SSH_FXP_EXTENDED, &fields)
+        } else {
+            self.command(SSH_FXP_RENAME, &fields)
+        }
+    }
+
+    // The bytes available to unprivileged users in the file system of the given path, if the
+    // server can tell.
+    fn free_space(&mut self, path: &str) -> io::Result<Option<u64>> {
+        if !self.extensions.iter().any(|name| name == STATVFS) {
+            return Ok(None);
+        }
+        let mut fields = vec![];
+        put_string(&mut fields, STATVFS.as_bytes());
+        put_string(&mut fields, path.as_bytes());
+        match self.request(SSH_FXP_EXTENDED, &fields)? {
+            (SSH_FXP_EXTENDED_REPLY, payload) => {
+                let mut stats = Fields(&payload);
+                let _block_size = stats.u64()?;
+                let fragment_size = stats.u64()?;
+                let _blocks = stats.u64()?;
+                let _free = stats.u64()?;
+                let available = stats.u64()?;
+                Ok(Some(available.saturating_mul(fragment_size)))
+            }
+            (SSH_FXP_STATUS, payload) => status(&payload).map(|_| None),
+            (kind, _) => Err(unexpected(kind)),
+        }
+    }
+}
+
+// The connections to the SFTP server that are not in use.
+struct Pool {
+    connector: Box<dyn Connector>,
+    idle: Mutex<Vec<Session>>,
+    max_idle: usize,
+}
+
+// A session taken from the pool, which goes back when dropped unless it broke.
+struct Pooled {
+    session: Option<Session>,
+    pool: Arc<Pool>,
+    // Whether the session was used before, so it may have gone stale while idle.
+    reused: bool,
+}
+
+impl std::ops::Deref for Pooled {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Session {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            if session.broken {
+                return;
+            }
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < self.pool.max_idle {
+                idle.push(session);
+            }
+        }
+    }
+}
+
+impl Pool {
+    fn connect(self: &Arc<Self>) -> io::Result<Pooled> {
+        Ok(Pooled {
+            session: Some(Session::connect(&*self.connector)?),
+            pool: Arc::clone(self),
+            reused: false,
+        })
+    }
+
+    fn take(self: &Arc<Self>) -> io::Result<Pooled> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(session) => Ok(Pooled {
+                session: Some(session),
+                pool: Arc::clone(self),
+                reused: true,
+            }),
+            None => self.connect(),
+        }
+    }
+
+    // Runs the given requests on a session from the pool, returning the session along with the
+    // result. When an idle session turns out to be dead, requests that can safely be sent twice
+    // are retried on a new connection.
+    fn checkout<T, F>(self: &Arc<Self>, idempotent: bool, f: F) -> io::Result<(Pooled, T)>
+    where
+        F: Fn(&mut Session) -> io::Result<T>,
+    {
+        let mut session = self.take()?;
+        match f(&mut session) {
+            Ok(result) => Ok((session, result)),
+            Err(e) if !(session.broken && session.reused && idempotent) => Err(e),
+            Err(e) => {
+                warn!("Reconnecting to the SFTP server after: {}", e);
+                drop(session);
+                let mut session = self.connect()?;
+                f(&mut session).map(|result| (session, result))
+            }
+        }
+    }
+}
+
+/// A [`StorageBackend`] that passes every operation on to a remote SFTP server, making the server
+/// a gateway from FTP(S) to SFTP. The connections to the SFTP server are opened by a
+/// [`Connector`], like [`Ssh`], and kept in a pool that's shared by the clones of the backend:
+/// connections go back to the pool after every operation, up to a maximum number of idle ones,
+/// and the ones that break are replaced. An operation that finds that an idle connection has died
+/// is retried on a new one if it's safe to send it twice.
+///
+/// Renames overwrite existing files if the server supports the `posix-rename@openssh.com`
+/// extension, as OpenSSH does. `presign` isn't supported.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::sftp::{SftpStorageBackend, Ssh};
+///
+/// let sftp = SftpStorageBackend::new(Ssh::new("ftp@files.example.com").port(2222)).root("/srv/ftp");
+/// let server = Server::new(Box::new(move || sftp.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`Connector`]: ./trait.Connector.html
+/// [`Ssh`]: ./struct.Ssh.html
+#[derive(Clone)]
+pub struct SftpStorageBackend {
+    pool: Arc<Pool>,
+    // The remote path that the root of the FTP server maps to. When empty, it's the directory
+    // that the SFTP server starts in, usually the home directory.
+    root: String,
+}
+
+impl SftpStorageBackend {
+    /// Create a backend that opens its connections with the given connector.
+    pub fn new<C: Connector + 'static>(connector: C) -> Self {
+        SftpStorageBackend {
+            pool: Arc::new(Pool {
+                connector: Box::new(connector),
+                idle: Mutex::new(vec![]),
+                max_idle: DEFAULT_MAX_IDLE,
+            }),
+            root: String::new(),
+        }
+    }
+
+    /// Map the root of the FTP server to the given path on the SFTP server, instead of the
+    /// directory that the SFTP server starts in.
+    pub fn root<P: Into<String>>(mut self, root: P) -> Self {
+        let root = root.into();
+        self.root = root.trim_end_matches('/').to_string();
+        if self.root.is_empty() && !root.is_empty() {
+            self.root.push('/');
+        }
+        self
+    }
+
+    /// Set the number of idle connections to keep around for later operations. The default is
+    /// [`DEFAULT_MAX_IDLE`]. Call this before cloning the backend, since the clones share the pool.
+    ///
+    /// [`DEFAULT_MAX_IDLE`]: ./constant.DEFAULT_MAX_IDLE.html
+    pub fn max_idle(mut self, max_idle: usize) -> Self {
+        if let Some(pool) = Arc::get_mut(&mut self.pool) {
+            pool.max_idle = max_idle;
+        }
+        self
+    }
+
+    // The path relative to the root of the FTP server, with sequences like '../' resolved.
+    fn key<P: AsRef<Path>>(path: P) -> super::Result<PathBuf> {
+        let mut key = PathBuf::new();
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => key.push(name),
+                Component::ParentDir => {
+                    if !key.pop() {
+                        return Err(Error::from(ErrorKind::PermissionDenied));
+                    }
+                }
+                Component::RootDir | Component::CurDir => {}
+                Component::Prefix(_) => return Err(Error::from(ErrorKind::PermissionDenied)),
+            }
+        }
+        Ok(key)
+    }
+
+    // The path on the SFTP server.
+    fn remote(&self, key: &Path) -> String {
+        let key = key.to_string_lossy();
+        match (self.root.as_str(), key.is_empty()) {
+            ("", true) => ".".to_string(),
+            ("", false) => key.into_owned(),
+            ("/", _) => format!("/{}", key),
+            (root, true) => root.to_string(),
+            (root, false) => format!("{}/{}", root, key),
+        }
+    }
+
+    fn remote_path<P: AsRef<Path>>(&self, path: P) -> super::Result<String> {
+        Self::key(path).map(|key| self.remote(&key))
+    }
+
+    // Runs the given requests on the blocking section of the tokio threadpool.
+    fn run<T, F>(
+        &self,
+        path: super::Result<String>,
+        idempotent: bool,
+        f: F,
+    ) -> Box<dyn Future<Item = T, Error = Error> + Send>
+    where
+        T: Send + 'static,
+        F: Fn(&mut Session, &str) -> io::Result<T> + Send + 'static,
+    {
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let pool = Arc::clone(&self.pool);
+        Box::new(
+            blocking(move || {
+                pool.checkout(idempotent, |session| f(session, &path))
+                    .map(|(_, result)| result)
+            })
+            .map_err(Error::from),
+        )
+    }
+
+    fn open(
+        &self,
+        path: super::Result<String>,
+        range: std::ops::Range<u64>,
+    ) -> Box<dyn Future<Item = SftpFile, Error = Error> + Send> {
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let pool = Arc::clone(&self.pool);
+        Box::new(
+            blocking(move || {
+                let (session, handle) = pool.checkout(true, |session| {
+                    session.open(&path, SSH_FXF_READ, &SftpMetadata::default())
+                })?;
+                Ok(SftpFile {
+                    session,
+                    handle,
+                    offset: range.start,
+                    end: range.end,
+                })
+            })
+            .map_err(Error::from),
+        )
+    }
+
+    fn upload<P: AsRef<Path>, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+        how: Upload,
+    ) -> Box<dyn Future<Item = u64, Error = Error> + Send> {
+        match self.remote_path(path) {
+            Ok(path) => Box::new(
+                Uploading {
+                    reader: bytes,
+                    pool: Arc::clone(&self.pool),
+                    path,
+                    how,
+                    file: None,
+                    offset: 0,
+                    written: 0,
+                    buffer: vec![0; CHUNK_SIZE],
+                    filled: 0,
+                    done: false,
+                }
+                .map_err(Error::from),
+            ),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+fn unix_time(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs().min(u64::from(u32::MAX)) as u32)
+        .unwrap_or(0)
+}
+
+// Runs blocking requests from within a future or reader, on the blocking section of the tokio
+// threadpool.
+fn block<T, F: FnOnce() -> io::Result<T>>(f: F) -> Poll<T, io::Error> {
+    match tokio_threadpool::blocking(f) {
+        Ok(Async::Ready(Ok(result))) => Ok(Async::Ready(result)),
+        Ok(Async::Ready(Err(e))) => Err(e),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+/// A file being read from an SFTP server, in the chunks that it's read into.
+pub struct SftpFile {
+    session: Pooled,
+    handle: Vec<u8>,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for SftpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.end - self.offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let (session, handle, offset) = (&mut self.session, &self.handle, self.offset);
+        match block(|| session.read(handle, offset, len))? {
+            Async::Ready(Some(data)) => {
+                let n = data.len().min(len);
+                buf[..n].copy_from_slice(&data[..n]);
+                self.offset += n as u64;
+                Ok(n)
+            }
+            Async::Ready(None) => {
+                self.end = self.offset;
+                Ok(0)
+            }
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl AsyncRead for SftpFile {}
+
+impl Drop for SftpFile {
+    fn drop(&mut self) {
+        if self.session.close(&self.handle).is_err() {
+            self.session.broken = true;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Upload {
+    Put,
+    PutUnique,
+    Append,
+}
+
+// A file being written to, and how to undo that if the upload is dropped before it finishes.
+struct Writing {
+    session: Pooled,
+    handle: Vec<u8>,
+    // The length of the file before an append.
+    appended_to: Option<u64>,
+}
+
+// Writes the bytes of the reader to the SFTP server as they arrive.
+struct Uploading<R> {
+    reader: R,
+    pool: Arc<Pool>,
+    path: String,
+    how: Upload,
+    file: Option<Writing>,
+    offset: u64,
+    written: u64,
+    buffer: Vec<u8>,
+    filled: usize,
+    done: bool,
+}
+
+impl<R> Uploading<R> {
+    fn open(&self) -> io::Result<Writing> {
+        let flags = match self.how {
+            Upload::Put => SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+            Upload::PutUnique => SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL,
+            Upload::Append => SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_APPEND,
+        };
+        let (session, (handle, appended_to)) = self.pool.checkout(true, |session| {
+            // Whether the file was there before an append, to put it back that way if needed.
+            let before = match self.how {
+                Upload::Append => match session.lstat(&self.path) {
+                    Ok(metadata) => Some(metadata.len()),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                },
+                _ => None,
+            };
+            let handle = session.open(&self.path, flags, &SftpMetadata::default())?;
+            Ok((handle, before))
+        })?;
+        Ok(Writing {
+            session,
+            handle,
+            appended_to,
+        })
+    }
+}
+
+impl<R: AsyncRead> Future for Uploading<R> {
+    type Item = u64;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<u64, io::Error> {
+        let polled = self.poll_upload();
+        // A failed upload is left as it is, like one to a `Filesystem`: only dropping it before
+        // it's done undoes it.
+        if polled.is_err() {
+            if let Some(mut file) = self.file.take() {
+                let _ = file.session.close(&file.handle);
+            }
+        }
+        polled
+    }
+}
+
+impl<R: AsyncRead> Uploading<R> {
+    fn poll_upload(&mut self) -> Poll<u64, io::Error> {
+        if self.file.is_none() {
+            let file = futures::try_ready!(block(|| self.open()));
+            // Servers ignore the offsets of writes to files opened for appending, but say where
+            // they start anyway.
+            self.offset = file.appended_to.unwrap_or(0);
+            self.file = Some(file);
+        }
+        loop {
+            if self.filled == 0 && !self.done {
+                match self.reader.poll_read(&mut self.buffer)? {
+                    Async::Ready(0) => self.done = true,
+                    Async::Ready(n) => self.filled = n,
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+            let file = self.file.as_mut().unwrap();
+            if self.filled > 0 {
+                let (handle, offset, data) =
+                    (&file.handle, self.offset, &self.buffer[..self.filled]);
+                let session = &mut file.session;
+                futures::try_ready!(block(|| session.write(handle, offset, data)));
+                self.offset += self.filled as u64;
+                self.written += self.filled as u64;
+                self.filled = 0;
+            } else if self.done {
+                let session = &mut file.session;
+                let handle = &file.handle;
+                futures::try_ready!(block(|| session.close(handle)));
+                self.file = None;
+                return Ok(Async::Ready(self.written));
+            }
+        }
+    }
+}
+
+impl<R> Drop for Uploading<R> {
+    // Undoes what an unfinished upload wrote, as the cancellation contract of `StorageBackend`
+    // asks.
+    fn drop(&mut self) {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return,
+        };
+        let handle = file.handle.clone();
+        let undone = match file.appended_to {
+            Some(len) => {
+                let truncated = SftpMetadata {
+                    size: Some(len),
+                    ..SftpMetadata::default()
+                };
+                file.session
+                    .fsetstat(&handle, &truncated)
+                    .and(file.session.close(&handle))
+            }
+            None => {
+                let closed = file.session.close(&handle);
+                closed.and_then(|_| file.session.path_command(SSH_FXP_REMOVE, &self.path))
+            }
+        };
+        if let Err(e) = undone {
+            warn!(
+                "Failed to undo the interrupted upload to {}: {}",
+                self.path, e
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SftpStorageBackend {
+    type File = SftpFile;
+    type Metadata = SftpMetadata;
+    type Error = Error;
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.run(self.remote_path(path), true, |session, path| {
+            session.lstat(path)
+        })
+        .compat()
+        .await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let key = match Self::key(path) {
+            Ok(key) => key,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let remote = Ok(self.remote(&key));
+        Box::new(
+            self.run(remote, true, |session, path| session.readdir(path))
+                .map(move |entries| {
+                    stream::iter_ok(entries.into_iter().map(move |(name, metadata)| Fileinfo {
+                        path: key.join(name),
+                        metadata,
+                    }))
+                })
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.open(self.remote_path(path), 0..u64::MAX)
+            .compat()
+            .await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error> {
+        let file = self.open(self.remote_path(path), range).compat().await?;
+        Ok(Box::new(file))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::Put).compat().await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::PutUnique).compat().await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, path, Upload::Append).compat().await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.run(self.remote_path(path), true, |session, path| {
+            session.free_space(path)
+        })
+        .compat()
+        .await
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            session.path_command(SSH_FXP_REMOVE, path)
+        })
+        .compat()
+        .await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            let mut fields = vec![];
+            put_string(&mut fields, path.as_bytes());
+            SftpMetadata::default().encode(&mut fields);
+            session.command(SSH_FXP_MKDIR, &fields)
+        })
+        .compat()
+        .await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), false, |session, path| {
+            session.path_command(SSH_FXP_RMDIR, path)
+        })
+        .compat()
+        .await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let to = self.remote_path(to)?;
+        self.run(self.remote_path(from), false, move |session, from| {
+            session.rename(from, &to)
+        })
+        .compat()
+        .await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let mtime = unix_time(mtime);
+        self.run(self.remote_path(path), true, move |session, path| {
+            // The access time can only be set along with it, so it's left as it was where the
+            // server tells what it was.
+            let atime = session.lstat(path)?.times.map_or(mtime, |(atime, _)| atime);
+            let times = SftpMetadata {
+                times: Some((atime, mtime)),
+                ..SftpMetadata::default()
+            };
+            session.setstat(path, &times)
+        })
+        .compat()
+        .await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.run(self.remote_path(path), true, move |session, path| {
+            let permissions = SftpMetadata {
+                permissions: Some(mode & 0o7777),
+                ..SftpMetadata::default()
+            };
+            session.setstat(path, &permissions)
+        })
+        .compat()
+        .await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let range = range.unwrap_or(0..u64::MAX);
+        self.run(self.remote_path(path), true, move |session, path| {
+            let handle = session.open(path, SSH_FXF_READ, &SftpMetadata::default())?;
+            let mut hasher = algorithm.hasher();
+            let mut offset = range.start;
+            let hashed = loop {
+                let len = (range.end - offset).min(CHUNK_SIZE as u64) as usize;
+                if len == 0 {
+                    break Ok(());
+                }
+                match session.read(&handle, offset, len) {
+                    Ok(Some(data)) => {
+                        hasher.update(&data);
+                        offset += data.len() as u64;
+                    }
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            let closed = session.close(&handle);
+            hashed.and(closed).map(|_| hasher.finish())
+        })
+        .compat()
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::collections::{BTreeSet, HashMap};
+    use std::io::Cursor;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // What's stored on the fake SFTP server.
+    #[derive(Default)]
+    struct Files {
+        files: HashMap<String, Vec<u8>>,
+        dirs: BTreeSet<String>,
+        handles: HashMap<Vec<u8>, String>,
+        next_handle: u32,
+    }
+
+    // An SFTP server in memory, for the connections of which the backend is its own connector.
+    // Bumping the generation makes it hang up on the connections opened before.
+    #[derive(Clone, Default)]
+    struct FakeServer {
+        files: Arc<Mutex<Files>>,
+        connections: Arc<AtomicUsize>,
+        generation: Arc<AtomicUsize>,
+    }
+
+    impl Connector for FakeServer {
+        fn connect(&self) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+            let (client, server) = UnixStream::pair()?;
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            let fake = self.clone();
+            let generation = self.generation.load(Ordering::SeqCst);
+            std::thread::spawn(move || fake.serve(server, generation));
+            Ok((Box::new(client.try_clone()?), Box::new(client)))
+        }
+    }
+
+    const OK: u32 = 0;
+    const EOF: u32 = 1;
+    const NO_SUCH_FILE: u32 = 2;
+    const FAILURE: u32 = 4;
+
+    fn reply_status(id: u32, code: u32) -> Vec<u8> {
+        let mut reply = vec![SSH_FXP_STATUS];
+        put_u32(&mut reply, id);
+        put_u32(&mut reply, code);
+        put_string(&mut reply, b"");
+        put_string(&mut reply, b"");
+        reply
+    }
+
+    fn attrs(size: u64, dir: bool) -> SftpMetadata {
+        SftpMetadata {
+            size: Some(size),
+            ids: Some((1000, 1000)),
+            permissions: Some(if dir { 0o040_755 } else { 0o100_644 }),
+            times: Some((0, 1_000_000)),
+        }
+    }
+
+    fn parent(path: &str) -> &str {
+        path.rsplit_once('/').map_or("", |(parent, _)| parent)
+    }
+
+    impl FakeServer {
+        fn serve(&self, mut stream: UnixStream, generation: usize) {
+            loop {
+                let mut len = [0; 4];
+                if stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                let mut packet = vec![0; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut packet).unwrap();
+                if self.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let reply = self.handle(packet[0], &mut Fields(&packet[1..])).unwrap();
+                stream
+                    .write_all(&(reply.len() as u32).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        }
+
+        fn handle(&self, kind: u8, fields: &mut Fields) -> io::Result<Vec<u8>> {
+            if kind == SSH_FXP_INIT {
+                let mut reply = vec![SSH_FXP_VERSION];
+                put_u32(&mut reply, VERSION);
+                put_string(&mut reply, POSIX_RENAME.as_bytes());
+                put_string(&mut reply, b"1");
+                return Ok(reply);
+            }
+            let id = fields.u32()?;
+            let mut fs = self.files.lock().unwrap();
+            let string = |fields: &mut Fields| -> io::Result<String> {
+                Ok(String::from_utf8_lossy(fields.string()?).into_owned())
+            };
+            let code = match kind {
+                SSH_FXP_LSTAT => {
+                    let path = string(fields)?;
+                    let metadata = match fs.files.get(&path) {
+                        Some(data) => attrs(data.len() as u64, false),
+                        None if fs.dirs.contains(&path) => attrs(0, true),
+                        None => return Ok(reply_status(id, NO_SUCH_FILE)),
+                    };
+                    let mut reply = vec![SSH_FXP_ATTRS];
+                    put_u32(&mut reply, id);
+                    metadata.encode(&mut reply);
+                    return Ok(reply);
+                }
+                SSH_FXP_OPEN | SSH_FXP_OPENDIR => {
+                    let path = string(fields)?;
+                    if kind == SSH_FXP_OPEN {
+                        let flags = fields.u32()?;
+                        let exists = fs.files.contains_key(&path);
+                        if !fs.dirs.contains(parent(&path))
+                            || (!exists && flags & SSH_FXF_CREAT == 0)
+                        {
+                            return Ok(reply_status(id, NO_SUCH_FILE));
+                        }
+                        if exists && flags & SSH_FXF_EXCL != 0 {
+                            return Ok(reply_status(id, FAILURE));
+                        }
+                        let file = fs.files.entry(path.clone()).or_default();
+                        if flags & SSH_FXF_TRUNC != 0 {
+                            file.clear();
+                        }
+                    } else if !fs.dirs.contains(&path) {
+                        return Ok(reply_status(id, NO_SUCH_FILE));
+                    }
+                    fs.next_handle += 1;
+                    let handle = fs.next_handle.to_be_bytes().to_vec();
+                    fs.handles.insert(handle.clone(), path);
+                    let mut reply = vec![SSH_FXP_HANDLE];
+                    put_u32(&mut reply, id);
+                    put_string(&mut reply, &handle);
+                    return Ok(reply);
+                }
+                SSH_FXP_CLOSE => {
+                    let handle = fields.string()?.to_vec();
+                    fs.handles.remove(&handle);
+                    OK
+                }
+                SSH_FXP_READ => {
+                    let handle = fields.string()?.to_vec();
+                    let (offset, len) = (fields.u64()? as usize, fields.u32()? as usize);
+                    let data = &fs.files[&fs.handles[&handle]];
+                    if offset >= data.len() {
+                        return Ok(reply_status(id, EOF));
+                    }
+                    let mut reply = vec![SSH_FXP_DATA];
+                    put_u32(&mut reply, id);
+                    put_string(&mut reply, &data[offset..data.len().min(offset + len)]);
+                    return Ok(reply);
+                }
+                SSH_FXP_WRITE => {
+                    let handle = fields.string()?.to_vec();
+                    let offset = fields.u64()? as usize;
+                    let data = fields.string()?;
+                    let path = fs.handles[&handle].clone();
+                    let file = fs.files.get_mut(&path).unwrap();
+                    if file.len() < offset + data.len() {
+                        file.resize(offset + data.len(), 0);
+                    }
+                    file[offset..offset + data.len()].copy_from_slice(data);
+                    OK
+                }
+                SSH_FXP_FSETSTAT => {
+                    let handle = fields.string()?.to_vec();
+                    let path = fs.handles[&handle].clone();
+                    if let Some(size) = SftpMetadata::decode(fields)?.size {
+                        fs.files.get_mut(&path).unwrap().truncate(size as usize);
+                    }
+                    OK
+                }
+                SSH_FXP_READDIR => {
+                    let handle = fields.string()?.to_vec();
+                    let dir = fs.handles.insert(handle, String::new()).unwrap();
+                    if dir.is_empty() {
+                        return Ok(reply_status(id, EOF));
+                    }
+                    let mut entries: Vec<(String, SftpMetadata)> = fs
+                        .files
+                        .iter()
+                        .map(|(path, data)| (path.clone(), attrs(data.len() as u64, false)))
+                        .chain(fs.dirs.iter().map(|path| (path.clone(), attrs(0, true))))
+                        .filter(|(path, _)| parent(path) == dir)
+                        .collect();
+                    entries.sort_by(|a, b| a.0.cmp(&b.0));
+                    let mut reply = vec![SSH_FXP_NAME];
+                    put_u32(&mut reply, id);
+                    put_u32(&mut reply, entries.len() as u32 + 1);
+                    entries.insert(0, (format!("{}/.", dir), attrs(0, true)));
+                    for (path, metadata) in entries {
+                        let name = path.rsplit('/').next().unwrap();
+                        put_string(&mut reply, name.as_bytes());
+                        put_string(&mut reply, name.as_bytes());
+                        metadata.encode(&mut reply);
+                    }
+                    return Ok(reply);
+                }
+                SSH_FXP_REMOVE => match fs.files.remove(&string(fields)?) {
+                    Some(_) => OK,
+                    None => NO_SUCH_FILE,
+                },
+                SSH_FXP_MKDIR => {
+                    let path = string(fields)?;
+                    fs.dirs.insert(path);
+                    OK
+                }
+                SSH_FXP_EXTENDED => {
+                    assert_eq!(string(fields)?, POSIX_RENAME);
+                    let (from, to) = (string(fields)?, string(fields)?);
+                    match fs.files.remove(&from) {
+                        Some(data) => {
+                            fs.files.insert(to, data);
+                            OK
+                        }
+                        None => NO_SUCH_FILE,
+                    }
+                }
+                _ => FAILURE,
+            };
+            Ok(reply_status(id, code))
+        }
+    }
+
+    fn backend() -> (FakeServer, SftpStorageBackend) {
+        let server = FakeServer::default();
+        server.files.lock().unwrap().dirs.insert("/srv".to_string());
+        let backend = SftpStorageBackend::new(server.clone()).root("/srv/");
+        (server, backend)
+    }
+
+    fn contents(backend: &SftpStorageBackend, path: &str) -> Vec<u8> {
+        let file = block_on(backend.get(path)).unwrap();
+        block_on(tokio_io::io::read_to_end(file, vec![]).compat())
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn transfers_files() {
+        compat::on_runtime(move || {
+            let (_, backend) = backend();
+            let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+            let written = block_on(backend.put(Cursor::new(data.clone()), "/big.bin")).unwrap();
+            assert_eq!(written, 100_000);
+            assert_eq!(contents(&backend, "big.bin"), data);
+            assert_eq!(block_on(backend.stat("/big.bin")).unwrap().len(), 100_000);
+
+            block_on(backend.append(Cursor::new(b"!".to_vec()), "/big.bin")).unwrap();
+            assert_eq!(block_on(backend.stat("/big.bin")).unwrap().len(), 100_001);
+
+            let file = block_on(backend.get_range("/big.bin", 99_999..100_001)).unwrap();
+            let range = block_on(tokio_io::io::read_to_end(file, vec![]).compat())
+                .unwrap()
+                .1;
+            assert_eq!(range, vec![data[99_999], b'!']);
+            assert!(block_on(backend.put_unique(Cursor::new(vec![]), "/big.bin")).is_err());
+        });
+    }
+
+    #[test]
+    fn manages_files() {
+        compat::on_runtime(move || {
+            let (server, backend) = backend();
+            block_on(backend.mkd("/dir")).unwrap();
+            block_on(backend.put(Cursor::new(b"a".to_vec()), "/dir/a.txt")).unwrap();
+            block_on(backend.rename("/dir/a.txt", "/dir/b.txt")).unwrap();
+            let listed: Vec<_> = block_on(backend.list("/dir").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|file| (file.path, file.metadata.len(), file.metadata.is_file()))
+                .collect();
+            assert_eq!(listed, vec![(PathBuf::from("dir/b.txt"), 1, true)]);
+            assert!(block_on(backend.stat("/dir")).unwrap().is_dir());
+            assert_eq!(
+                block_on(backend.stat("/dir/../..")).unwrap_err(),
+                Error::from(ErrorKind::PermissionDenied)
+            );
+            block_on(backend.del("/dir/b.txt")).unwrap();
+            assert_eq!(
+                block_on(backend.del("/dir/b.txt")).unwrap_err(),
+                Error::from(ErrorKind::NotFound)
+            );
+            assert!(server.files.lock().unwrap().files.is_empty());
+        });
+    }
+
+    #[test]
+    fn reuses_and_replaces_connections() {
+        compat::on_runtime(move || {
+            let (server, backend) = backend();
+            block_on(backend.mkd("/dir")).unwrap();
+            block_on(backend.stat("/dir")).unwrap();
+            assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+
+            // The server hangs up on the idle connection, so the next operation needs a new one.
+            server.generation.fetch_add(1, Ordering::SeqCst);
+            assert!(block_on(backend.stat("/dir")).unwrap().is_dir());
+            assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn cancellation() {
+        let (server, backend) = backend();
+        crate::storage::conformance::check_cancellation(backend);
+        let files = server.files.lock().unwrap();
+        let mut names: Vec<_> = files.files.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["/srv/appended.txt"]);
+        assert!(files.handles.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::Stream;
+
+use super::{Fileinfo, HashAlgorithm, ListOptions, Metadata, Precondition, StorageBackend};
+use crate::auth::User;
+use crate::bandwidth::{self, BandwidthLimiter, TransferPriority};
+use crate::events::NegotiatedOptions;
+
+/// A [`StorageBackend`] wrapper that limits how fast files are read from and written to the
+/// wrapped backend, whichever backend that is, with a limit for each direction. The bytes of
+/// downloads are taken from the backend, and the bytes of uploads handed to it, no faster than
+/// the limit allows, measured with a token bucket that holds a quarter of a second worth of bytes.
+///
+/// The limits are shared by the transfers of the instance, so of a session when the storage
+/// factory of the [`Server`] creates it, by the [`TransferPriority`] of the user. This is on top
+/// of [`Server::bandwidth_limit`], which limits all data connections together.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, Throttled};
+///
+/// // 1 MiB/s down, 256 KiB/s up
+/// let server = Server::new(Box::new(|| {
+///     Throttled::new(Filesystem::new("/srv/ftp"))
+///         .downloads(1024 * 1024)
+///         .uploads(256 * 1024)
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`Server`]: ../server/struct.Server.html
+/// [`TransferPriority`]: ../bandwidth/enum.TransferPriority.html
+/// [`Server::bandwidth_limit`]: ../server/struct.Server.html#method.bandwidth_limit
+pub struct Throttled<S> {
+    inner: S,
+    downloads: Option<Arc<BandwidthLimiter>>,
+    uploads: Option<Arc<BandwidthLimiter>>,
+    priority: TransferPriority,
+}
+
+impl<S> Throttled<S> {
+    /// Wrap the given backend, without limits.
+    pub fn new(inner: S) -> Self {
+        Throttled {
+            inner,
+            downloads: None,
+            uploads: None,
+            priority: TransferPriority::default(),
+        }
+    }
+
+    /// Limit the reads of files from the wrapped backend to the given number of bytes per second.
+    pub fn downloads(mut self, bytes_per_second: u64) -> Self {
+        self.downloads = Some(Arc::new(BandwidthLimiter::new(bytes_per_second)));
+        self
+    }
+
+    /// Limit the writes of files to the wrapped backend to the given number of bytes per second.
+    pub fn uploads(mut self, bytes_per_second: u64) -> Self {
+        self.uploads = Some(Arc::new(BandwidthLimiter::new(bytes_per_second)));
+        self
+    }
+}
+
+// Limits the reading speed of `reader` to its share of the `limiter`, if there is one.
+fn throttle<R>(
+    reader: R,
+    limiter: Option<&Arc<BandwidthLimiter>>,
+    priority: TransferPriority,
+) -> ThrottledFile<R> {
+    ThrottledFile(match limiter {
+        Some(limiter) => Reader::Throttled(bandwidth::Throttled::new(
+            reader,
+            BandwidthLimiter::share(limiter, priority),
+        )),
+        None => Reader::Plain(reader),
+    })
+}
+
+// A reader that's throttled, or not when there's no limit for its direction.
+enum Reader<R> {
+    Plain(R),
+    Throttled(bandwidth::Throttled<R>),
+}
+
+/// A file being read through a [`Throttled`] backend, no faster than its limit allows.
+///
+/// [`Throttled`]: ./struct.Throttled.html
+pub struct ThrottledFile<F>(Reader<F>);
+
+impl<F: Read> Read for ThrottledFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.0 {
+            Reader::Plain(reader) => reader.read(buf),
+            Reader::Throttled(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<F: tokio::prelude::AsyncRead> tokio::prelude::AsyncRead for ThrottledFile<F> {}
+
+#[async_trait]
+impl<S> StorageBackend for Throttled<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = ThrottledFile<S::File>;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.priority = user.priority;
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        self.inner.list(path)
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.list_fmt(path, options)
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.nlst(path)
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let file = self.inner.get(path).await?;
+        Ok(throttle(file, self.downloads.as_ref(), self.priority))
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        let reader = self.inner.get_range(path, range).await?;
+        Ok(Box::new(throttle(
+            reader,
+            self.downloads.as_ref(),
+            self.priority,
+        )))
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let bytes = throttle(bytes, self.uploads.as_ref(), self.priority);
+        self.inner.put(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let bytes = throttle(bytes, self.uploads.as_ref(), self.priority);
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let bytes = throttle(bytes, self.uploads.as_ref(), self.priority);
+        self.inner.append(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+        _ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        // Clients that download from a presigned URL would get around the limit.
+        Ok(None)
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.del(path).await
+    }
+
+    async fn del_if(&self, path: PathBuf, precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.del_if(path, precondition).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.rmd(path).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.rmd_recursive(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.rename_if(from, to, precondition).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat::compat;
+    use crate::storage::{Error, Memory};
+    use futures03::compat::Future01CompatExt;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn throttles_each_direction() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let storage = Arc::new(Throttled::new(Memory::new()).uploads(100_000));
+
+        let start = Instant::now();
+        let uploading = Arc::clone(&storage);
+        rt.block_on(compat(async move {
+            uploading
+                .put(std::io::Cursor::new(vec![1u8; 50_000]), "/data")
+                .await
+        }))
+        .unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+
+        // Downloads aren't limited.
+        let start = Instant::now();
+        let downloading = Arc::clone(&storage);
+        let data = rt
+            .block_on(compat(async move {
+                let file = downloading.get("/data").await?;
+                Ok::<_, Error>(tokio_io::io::read_to_end(file, vec![]).compat().await?.1)
+            }))
+            .unwrap();
+        assert_eq!(data.len(), 50_000);
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        let storage = Arc::new(Arc::try_unwrap(storage).ok().unwrap().downloads(200_000));
+        let start = Instant::now();
+        let data = rt
+            .block_on(compat(async move {
+                let file = storage.get_range("/data", 0..40_000).await?;
+                Ok::<_, Error>(tokio_io::io::read_to_end(file, vec![]).compat().await?.1)
+            }))
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(data, vec![1u8; 40_000]);
+        assert!(elapsed >= Duration::from_millis(150), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+    }
+}
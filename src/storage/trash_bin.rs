@@ -0,0 +1,551 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::{stream, Stream};
+use futures03::compat::{Future01CompatExt, Stream01CompatExt};
+use futures03::future::BoxFuture;
+use futures03::StreamExt;
+use log::warn;
+
+use super::{Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+// The directory of the trash bin, in the root of the wrapped backend.
+const TRASH: &str = ".trash";
+
+// The names of the directories of the deletions in the trash bin: when they happened, in UTC.
+const DELETED_AT: &str = "%Y%m%dT%H%M%S%.9fZ";
+
+// How often the trash bin is emptied of what it doesn't need to keep anymore, at most.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a [`TrashBin`] keeps what was deleted.
+///
+/// [`TrashBin`]: ./struct.TrashBin.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retention {
+    /// How long deleted files and directories are kept. The default is 30 days.
+    pub max_age: Duration,
+    /// The number of deletions to keep at most, after which the oldest go. `None` (the default)
+    /// means there's no limit.
+    pub max_deletions: Option<usize>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            max_deletions: None,
+        }
+    }
+}
+
+/// A [`StorageBackend`] wrapper that moves what clients delete into a trash bin, instead of
+/// destroying it, so an accidental `mdelete *` can be undone by the operator.
+///
+/// `DELE`, `RMD` and recursive removals move the file or directory to
+/// `/.trash/{time}/{path}` in the wrapped backend, where `{time}` is when it was deleted, like
+/// `20261014T093000.000000000Z`, and `{path}` where it was. The trash bin is hidden from the
+/// clients: it isn't listed, and can't be used in any way. What's older than the [`Retention`]
+/// allows is removed for good, when something is deleted, at most once a minute. The wrapped
+/// backend has to be able to rename files: directories are moved one file at a time.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, Retention, TrashBin};
+/// use std::time::Duration;
+///
+/// let server = Server::new(Box::new(|| {
+///     TrashBin::new(Filesystem::new("/srv/ftp")).retention(Retention {
+///         max_age: Duration::from_secs(7 * 24 * 60 * 60),
+///         ..Retention::default()
+///     })
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`Retention`]: ./struct.Retention.html
+pub struct TrashBin<S> {
+    inner: S,
+    retention: Retention,
+    // When the trash bin was last emptied of what it doesn't need to keep.
+    cleaned: Mutex<Option<Instant>>,
+}
+
+impl<S> TrashBin<S> {
+    /// Wrap the given backend, with the default [`Retention`].
+    ///
+    /// [`Retention`]: ./struct.Retention.html
+    pub fn new(inner: S) -> Self {
+        TrashBin {
+            inner,
+            retention: Retention::default(),
+            cleaned: Mutex::new(None),
+        }
+    }
+
+    /// Set how long deleted files and directories are kept.
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+}
+
+// Resolves `.` and `..`, relative to the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+// Whether the path is the trash bin, or in it.
+fn trashed(path: &Path) -> bool {
+    normalize(path).starts_with(TRASH)
+}
+
+fn io_error<E: From<std::io::Error>>(kind: std::io::ErrorKind, message: &str) -> E {
+    std::io::Error::new(kind, message.to_string()).into()
+}
+
+fn not_found<T, E: From<std::io::Error>>() -> Result<T, E> {
+    Err(io_error(
+        std::io::ErrorKind::NotFound,
+        "No such file or directory",
+    ))
+}
+
+// What a removal is allowed to remove.
+#[derive(Clone, Copy, PartialEq)]
+enum Removal {
+    File,
+    EmptyDir,
+    Dir,
+}
+
+// Moves the directory at `from`, and everything in it, to `to`, one file at a time, since not
+// every backend renames directories.
+fn move_dir<S>(inner: &S, from: PathBuf, to: PathBuf) -> BoxFuture<'_, Result<(), S::Error>>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: Send + 'static,
+{
+    Box::pin(async move {
+        let _ = inner.mkd(&to).await;
+        let mut entries = inner.list(&from).compat();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let name = match entry.path.file_name() {
+                Some(name) => name.to_os_string(),
+                None => continue,
+            };
+            let (from, to) = (from.join(&name), to.join(&name));
+            if entry.metadata.is_dir() && !entry.metadata.is_symlink() {
+                move_dir(inner, from, to).await?;
+            } else {
+                inner.rename(from, to).await?;
+            }
+        }
+        inner.rmd(from).await
+    })
+}
+
+impl<S> TrashBin<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    // Moves what's at `path` to a new deletion in the trash bin, if it's what the removal may
+    // remove.
+    async fn discard(&self, path: &Path, removal: Removal) -> Result<(), S::Error> {
+        let relative = normalize(path);
+        if trashed(&relative) {
+            return not_found();
+        }
+        if relative.as_os_str().is_empty() {
+            return Err(io_error(
+                std::io::ErrorKind::PermissionDenied,
+                "The root can't be removed",
+            ));
+        }
+        let from = Path::new("/").join(&relative);
+        let deletion = Path::new("/")
+            .join(TRASH)
+            .join(Utc::now().format(DELETED_AT).to_string());
+        let to = deletion.join(&relative);
+        let mut dirs: Vec<PathBuf> = to
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.parent().is_some())
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.reverse();
+
+        let is_dir = self.inner.stat(&from).await?.is_dir();
+        match removal {
+            Removal::File if is_dir => {
+                return Err(io_error(std::io::ErrorKind::Other, "Not a file"));
+            }
+            Removal::EmptyDir | Removal::Dir if !is_dir => {
+                return Err(io_error(std::io::ErrorKind::Other, "Not a directory"));
+            }
+            Removal::EmptyDir => {
+                if let Some(first) = self.inner.list(&from).compat().next().await {
+                    first?;
+                    return Err(io_error(
+                        std::io::ErrorKind::Other,
+                        "The directory isn't empty",
+                    ));
+                }
+            }
+            _ => {}
+        }
+        // Most errors mean that the directory exists: if it doesn't, the rename fails.
+        for dir in dirs {
+            let _ = self.inner.mkd(dir).await;
+        }
+        match removal {
+            Removal::File => self.inner.rename(from, to).await?,
+            Removal::EmptyDir | Removal::Dir => move_dir(&self.inner, from, to).await?,
+        }
+        self.cleanup().await;
+        Ok(())
+    }
+
+    // Removes the deletions that the retention doesn't keep, unless that was done recently.
+    async fn cleanup(&self) {
+        {
+            let mut cleaned = self.cleaned.lock().unwrap_or_else(PoisonError::into_inner);
+            if cleaned.is_some_and(|at| at.elapsed() < CLEANUP_INTERVAL) {
+                return;
+            }
+            *cleaned = Some(Instant::now());
+        }
+        let entries = self
+            .inner
+            .list(Path::new("/").join(TRASH))
+            .collect()
+            .compat()
+            .await;
+        let mut deletions: Vec<(DateTime<Utc>, PathBuf)> = entries
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|entry| {
+                let name = entry.path.file_name()?.to_str()?;
+                let at = NaiveDateTime::parse_from_str(name, DELETED_AT).ok()?;
+                Some((at.and_utc(), entry.path))
+            })
+            .collect();
+        deletions.sort();
+        let excess = self
+            .retention
+            .max_deletions
+            .map_or(0, |max| deletions.len().saturating_sub(max));
+        let oldest = DateTime::<Utc>::from(SystemTime::now() - self.retention.max_age);
+        let expired: Vec<PathBuf> = deletions
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (at, _))| *i < excess || *at < oldest)
+            .map(|(_, (_, path))| path)
+            .collect();
+        for path in expired {
+            if self.inner.rmd_recursive(path.clone()).await.is_err() {
+                warn!("Failed to remove {:?} from the trash bin", path);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for TrashBin<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        if trashed(path.as_ref()) {
+            return Box::new(stream::once(Err(io_error(
+                std::io::ErrorKind::NotFound,
+                "No such file or directory",
+            ))));
+        }
+        Box::new(self.inner.list(path).filter(|file| !trashed(&file.path)))
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.put(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.append(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.discard(path.as_ref(), Removal::File).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.discard(path.as_ref(), Removal::EmptyDir).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.discard(&path, Removal::Dir).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        if trashed(from.as_ref()) || trashed(to.as_ref()) {
+            return not_found();
+        }
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        if trashed(path.as_ref()) {
+            return not_found();
+        }
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use crate::storage::Filesystem;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    // The paths of the files in the directory, and its subdirectories, relative to it.
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut found = vec![];
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                let name = PathBuf::from(path.file_name().unwrap());
+                found.extend(files(&path).into_iter().map(|file| name.join(file)));
+            } else {
+                found.push(PathBuf::from(path.file_name().unwrap()));
+            }
+        }
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn moves_deletions_to_the_trash_bin() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(root.path().join("docs/old")).unwrap();
+            std::fs::write(root.path().join("docs/report.txt"), b"report").unwrap();
+            std::fs::write(root.path().join("docs/old/draft.txt"), b"draft").unwrap();
+            let storage = TrashBin::new(Filesystem::new(root.path()));
+
+            block_on(storage.del("/docs/report.txt")).unwrap();
+            assert!(!root.path().join("docs/report.txt").exists());
+            let trash = root.path().join(TRASH);
+            assert_eq!(files(&trash).len(), 1);
+            let deleted = &files(&trash)[0];
+            assert!(deleted.ends_with("docs/report.txt"));
+            assert_eq!(std::fs::read(trash.join(deleted)).unwrap(), b"report");
+
+            // What can't be removed stays where it is.
+            assert!(block_on(storage.del("/docs/old")).is_err());
+            assert!(block_on(storage.rmd("/docs/old")).is_err());
+            assert!(block_on(storage.rmd("/docs/old/draft.txt")).is_err());
+            assert!(block_on(storage.del("/")).is_err());
+            block_on(storage.rmd_recursive(PathBuf::from("/docs/old"))).unwrap();
+            assert!(!root.path().join("docs/old").exists());
+            assert_eq!(files(&trash).len(), 2);
+
+            // The trash bin is out of reach.
+            let names: Vec<PathBuf> = block_on(storage.list("/").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+            assert_eq!(names, vec![PathBuf::from("docs")]);
+            assert!(block_on(storage.stat("/.trash")).is_err());
+            assert!(block_on(storage.list("/docs/../.trash").collect().compat()).is_err());
+            assert!(block_on(storage.del(Path::new("/").join(TRASH).join(deleted))).is_err());
+            assert!(block_on(storage.put(Cursor::new(b"x".to_vec()), "/.trash/x")).is_err());
+            assert!(block_on(storage.rename("/docs", "/.trash/docs")).is_err());
+        });
+    }
+
+    #[test]
+    fn empties_the_trash_bin() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            let trash = root.path().join(TRASH);
+            std::fs::create_dir_all(trash.join("20000101T000000.000000000Z/old")).unwrap();
+            std::fs::create_dir_all(trash.join("unrelated")).unwrap();
+            for name in ["a", "b", "c"] {
+                std::fs::write(root.path().join(name), name).unwrap();
+            }
+
+            let storage = TrashBin::new(Filesystem::new(root.path()));
+            block_on(storage.del("/a")).unwrap();
+            assert!(!trash.join("20000101T000000.000000000Z").exists());
+            assert!(trash.join("unrelated").exists());
+            assert_eq!(files(&trash).len(), 1);
+
+            // Only once a minute.
+            std::fs::create_dir_all(trash.join("20000101T000000.000000000Z")).unwrap();
+            block_on(storage.del("/b")).unwrap();
+            assert!(trash.join("20000101T000000.000000000Z").exists());
+
+            let storage = TrashBin::new(Filesystem::new(root.path())).retention(Retention {
+                max_deletions: Some(1),
+                ..Retention::default()
+            });
+            block_on(storage.del("/c")).unwrap();
+            let kept: Vec<PathBuf> = files(&trash)
+                .into_iter()
+                .filter(|file| !file.starts_with("unrelated"))
+                .collect();
+            assert_eq!(kept.len(), 1);
+            assert!(kept[0].ends_with("c"));
+        });
+    }
+}
@@ -0,0 +1,450 @@
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{Future as _, Stream};
+use log::warn;
+
+use super::{Fileinfo, HashAlgorithm, ListOptions, Metadata, Precondition, StorageBackend};
+use crate::auth::User;
+use crate::compat::compat;
+use crate::events::NegotiatedOptions;
+
+/// A [`StorageBackend`] wrapper that gives every user a directory of their own, and keeps them in
+/// it: once the user has logged in, every path is taken to be within `{base}/{username}` of the
+/// wrapped backend, which is where `/` is for the client, and `..` doesn't go past it. Wrapping a
+/// [`Filesystem`] this way gives the classic jail of a home directory per user.
+///
+/// The directory is created when it isn't there yet, with the directories that lead to it, before
+/// the first operation after the login. Characters of the username that could take it outside the
+/// base directory (`/`, `\`, a leading `.`, control characters and `%` itself) are `%`-encoded in
+/// the name of the directory, so `../bob` gets `%2E.%2Fbob`, not the directory of `bob`. Nothing
+/// works before the login.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, UserRooted};
+///
+/// let server = Server::new(Box::new(|| {
+///     UserRooted::new(Filesystem::new("/srv/ftp")).base("/home")
+/// }));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`Filesystem`]: ./struct.Filesystem.html
+pub struct UserRooted<S> {
+    inner: Arc<S>,
+    base: PathBuf,
+    root: Option<PathBuf>,
+    // Whether the root of the user is known to exist, shared with the futures that create it.
+    created: Arc<AtomicBool>,
+}
+
+impl<S> UserRooted<S> {
+    /// Wrap the given backend, with the directories of the users right at its root.
+    pub fn new(inner: S) -> Self {
+        UserRooted {
+            inner: Arc::new(inner),
+            base: PathBuf::from("/"),
+            root: None,
+            created: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set the directory of the wrapped backend that has the directories of the users in it,
+    /// e.g. `/home`.
+    pub fn base<P: AsRef<Path>>(mut self, base: P) -> Self {
+        self.base = Path::new("/").join(normalize(base.as_ref()));
+        self
+    }
+
+    // The path within the wrapped backend, or `None` before the login.
+    fn within(&self, path: &Path) -> Option<PathBuf> {
+        self.root.as_ref().map(|root| root.join(normalize(path)))
+    }
+}
+
+// Resolves `.` and `..` without ever going above the root, the way `cd ..` does in `/`.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+// The name of the directory of the user with the given name, `%`-encoded so it can only ever be a
+// single, plain component.
+fn dir_name(username: &str) -> String {
+    let mut name = String::with_capacity(username.len());
+    for (i, c) in username.chars().enumerate() {
+        match c {
+            '/' | '\\' | '%' => name.push_str(&format!("%{:02X}", c as u32)),
+            '.' if i == 0 => name.push_str("%2E"),
+            c if c.is_control() => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    name.push_str(&format!("%{:02X}", byte));
+                }
+            }
+            c => name.push(c),
+        }
+    }
+    if name.is_empty() {
+        name.push('%');
+    }
+    name
+}
+
+fn logged_out<E: From<std::io::Error>>() -> E {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "There's no user logged in",
+    )
+    .into()
+}
+
+impl<S> UserRooted<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    // The path within the wrapped backend, once the root of the user exists. The root, and the
+    // directories that lead to it, are created unless that's done. Errors are ignored, since most
+    // mean that the directory exists: if it doesn't, the operation that follows fails anyway.
+    fn run(&self, path: &Path) -> impl Future<Output = Result<PathBuf, S::Error>> + Send + 'static {
+        let path = self.within(path);
+        let mut dirs: Vec<PathBuf> = match &self.root {
+            Some(root) if !self.created.load(Ordering::SeqCst) => root
+                .ancestors()
+                .filter(|dir| dir.parent().is_some())
+                .map(Path::to_path_buf)
+                .collect(),
+            _ => vec![],
+        };
+        dirs.reverse();
+        let inner = Arc::clone(&self.inner);
+        let created = Arc::clone(&self.created);
+        async move {
+            let path = path.ok_or_else(logged_out)?;
+            if !dirs.is_empty() {
+                for dir in dirs {
+                    let _ = inner.mkd(dir).await;
+                }
+                created.store(true, Ordering::SeqCst);
+            }
+            Ok(path)
+        }
+    }
+
+    // The path of an entry of the wrapped backend, as the client sees it.
+    fn outside(root: &Path, path: PathBuf) -> PathBuf {
+        let relative = root.strip_prefix("/").unwrap_or(root);
+        match path
+            .strip_prefix(root)
+            .or_else(|_| path.strip_prefix(relative))
+        {
+            Ok(rest) if path.has_root() => Path::new("/").join(rest),
+            Ok(rest) => rest.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for UserRooted<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.root = Some(self.base.join(dir_name(&user.username)));
+        self.created = Arc::new(AtomicBool::new(false));
+        match Arc::get_mut(&mut self.inner) {
+            Some(inner) => inner.set_user(user),
+            None => warn!("Storage backend in use during login, not setting the user"),
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let root = self.root.clone().unwrap_or_default();
+        let inner = Arc::clone(&self.inner);
+        Box::new(
+            compat(self.run(path.as_ref()))
+                .map(move |path| inner.list(path))
+                .flatten_stream()
+                .map(move |file| Fileinfo {
+                    path: Self::outside(&root, file.path),
+                    metadata: file.metadata,
+                }),
+        )
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        Box::new(
+            compat(self.run(path.as_ref()))
+                .map_err(|e| std::io::Error::from(e.into()))
+                .map(move |path| inner.list_fmt(path, options))
+                .flatten_stream(),
+        )
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        Box::new(
+            compat(self.run(path.as_ref()))
+                .map_err(|e| std::io::Error::from(e.into()))
+                .map(move |path| inner.nlst(path))
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.put(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.append(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.del(path).await
+    }
+
+    async fn del_if(&self, path: PathBuf, precondition: Precondition) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let path = self.run(&path).await?;
+        self.inner.del_if(path, precondition).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.rmd(path).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let path = self.run(&path).await?;
+        self.inner.rmd_recursive(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let to = self.within(to.as_ref()).ok_or_else(logged_out)?;
+        let from = self.run(from.as_ref()).await?;
+        self.inner.rename(from, to).await
+    }
+
+    async fn rename_if(
+        &self,
+        from: PathBuf,
+        to: PathBuf,
+        precondition: Precondition,
+    ) -> Result<bool, Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        let to = self.within(&to).ok_or_else(logged_out)?;
+        let from = self.run(&from).await?;
+        self.inner.rename_if(from, to, precondition).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let path = self.run(path.as_ref()).await?;
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use crate::storage::{Error, ErrorKind, Filesystem};
+    use futures03::compat::Future01CompatExt;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn encodes_usernames() {
+        assert_eq!(dir_name("alice"), "alice");
+        assert_eq!(
+            dir_name("alice.smith@example.com"),
+            "alice.smith@example.com"
+        );
+        assert_eq!(dir_name("../bob"), "%2E.%2Fbob");
+        assert_eq!(dir_name("a\\b%c\n"), "a%5Cb%25c%0A");
+        assert_eq!(dir_name(""), "%");
+    }
+
+    #[test]
+    fn keeps_users_in_their_directory() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::write(root.path().join("secret.txt"), b"secret").unwrap();
+
+            let mut storage = UserRooted::new(Filesystem::new(root.path())).base("/home/");
+            assert_eq!(
+                block_on(storage.stat("/")).unwrap_err(),
+                Error::from(ErrorKind::PermissionDenied)
+            );
+
+            storage.set_user(&User::new("alice"));
+            assert!(block_on(storage.stat("/")).unwrap().is_dir());
+            assert!(root.path().join("home/alice").is_dir());
+            block_on(storage.put(std::io::Cursor::new(b"hello".to_vec()), "/../../a.txt")).unwrap();
+            assert_eq!(
+                std::fs::read(root.path().join("home/alice/a.txt")).unwrap(),
+                b"hello"
+            );
+            assert!(block_on(storage.stat("/../secret.txt")).is_err());
+            block_on(storage.mkd("docs")).unwrap();
+            block_on(storage.rename("/a.txt", "/docs/b.txt")).unwrap();
+            let mut files: Vec<PathBuf> = block_on(storage.list("/docs").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+            files.sort();
+            assert_eq!(files, vec![PathBuf::from("docs/b.txt")]);
+
+            let mut storage = UserRooted::new(Filesystem::new(root.path())).base("/home");
+            storage.set_user(&User::new("../.."));
+            block_on(storage.put(std::io::Cursor::new(b"bye".to_vec()), "/b.txt")).unwrap();
+            assert!(root.path().join("home/%2E.%2F..").is_dir());
+            assert_eq!(
+                std::fs::read(root.path().join("secret.txt")).unwrap(),
+                b"secret"
+            );
+        });
+    }
+}
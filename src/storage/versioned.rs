@@ -0,0 +1,432 @@
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::{stream, Stream};
+use futures03::compat::Future01CompatExt;
+use log::warn;
+
+use super::{Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+// The directory of the previous versions, in the root of the wrapped backend.
+const VERSIONS: &str = ".versions";
+
+/// A [`StorageBackend`] wrapper that keeps the previous versions of files: when an upload
+/// replaces a file, or a file is deleted, what the file had is kept, so teams that share a
+/// drop-zone can get back what was overwritten by mistake.
+///
+/// The versions of `/docs/report.txt` are `/.versions/docs/report.txt.~1~`, `.~2~` and so on in
+/// the wrapped backend, the highest number being the newest, of which the given number is kept.
+/// Replaced files are copied there before the upload starts, so a failed upload doesn't lose
+/// anything, and deleted files are moved there. The versions are hidden from the clients: the
+/// directory isn't listed, and can't be used in any way.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, Versioned};
+///
+/// let server = Server::new(Box::new(|| Versioned::new(Filesystem::new("/srv/ftp")).keep(10)));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+pub struct Versioned<S> {
+    inner: S,
+    keep: usize,
+}
+
+impl<S> Versioned<S> {
+    /// Wrap the given backend, keeping 5 previous versions of every file.
+    pub fn new(inner: S) -> Self {
+        Versioned { inner, keep: 5 }
+    }
+
+    /// Set the number of previous versions of every file to keep. With `0`, nothing is kept.
+    pub fn keep(mut self, versions: usize) -> Self {
+        self.keep = versions;
+        self
+    }
+}
+
+// Resolves `.` and `..`, relative to the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+// Whether the path is the directory of the previous versions, or in it.
+fn hidden(path: &Path) -> bool {
+    normalize(path).starts_with(VERSIONS)
+}
+
+// The number of the version with the given name, of the file with the given name.
+fn version(name: &str, file: &str) -> Option<u64> {
+    name.strip_prefix(file)?
+        .strip_prefix(".~")?
+        .strip_suffix('~')?
+        .parse()
+        .ok()
+}
+
+fn not_found<E: From<std::io::Error>>() -> E {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "No such file or directory").into()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Snapshot {
+    // For uploads, that replace the file once they're done.
+    Copy,
+    // For deletions.
+    Move,
+}
+
+impl<S> Versioned<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    // Keeps the file at `path` as its newest version, if there is a file. Returns whether there
+    // was one.
+    async fn snapshot(&self, path: &Path, how: Snapshot) -> Result<bool, S::Error> {
+        let relative = normalize(path);
+        let name = match relative.file_name().and_then(|name| name.to_str()) {
+            Some(name) if self.keep > 0 => name.to_string(),
+            _ => return Ok(false),
+        };
+        let from = Path::new("/").join(&relative);
+        let dir = Path::new("/")
+            .join(VERSIONS)
+            .join(relative.parent().unwrap_or_else(|| Path::new("")));
+        let mut dirs: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|dir| dir.parent().is_some())
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.reverse();
+        let keep = self.keep as u64;
+
+        let is_file = match self.inner.stat(&from).await {
+            Ok(metadata) => metadata.is_file(),
+            Err(_) => false,
+        };
+        if !is_file {
+            return Ok(false);
+        }
+        // Most errors mean that the directory exists: if it doesn't, the copy or the move fails.
+        for dir in dirs {
+            let _ = self.inner.mkd(dir).await;
+        }
+        let versions: Vec<u64> = self
+            .inner
+            .list(&dir)
+            .collect()
+            .compat()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|entry| {
+                let file_name = entry.path.file_name()?.to_str()?;
+                version(file_name, &name)
+            })
+            .collect();
+        let newest = versions.iter().max().map_or(1, |max| max + 1);
+        let to = dir.join(format!("{}.~{}~", name, newest));
+        match how {
+            Snapshot::Copy => {
+                let file = self.inner.get(from).await?;
+                self.inner.put(file, to).await?;
+            }
+            Snapshot::Move => self.inner.rename(from, to).await?,
+        }
+        let old: Vec<PathBuf> = versions
+            .into_iter()
+            .filter(|version| version + keep <= newest)
+            .map(|version| dir.join(format!("{}.~{}~", name, version)))
+            .collect();
+        for path in old {
+            if self.inner.del(&path).await.is_err() {
+                warn!("Failed to remove the old version {:?}", path);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for Versioned<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: tokio::prelude::AsyncRead + Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<std::io::Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        if hidden(path.as_ref()) {
+            return Box::new(stream::once(Err(not_found())));
+        }
+        Box::new(self.inner.list(path).filter(|file| !hidden(&file.path)))
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.snapshot(path.as_ref(), Snapshot::Copy).await?;
+        self.inner.put(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.append(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        if self.snapshot(path.as_ref(), Snapshot::Move).await? {
+            return Ok(());
+        }
+        self.inner.del(path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.rmd(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        if hidden(from.as_ref()) || hidden(to.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        if hidden(path.as_ref()) {
+            return Err(not_found());
+        }
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat;
+    use crate::storage::Filesystem;
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn versions(dir: &Path) -> Vec<(String, String)> {
+        let mut versions: Vec<(String, String)> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (
+                    path.file_name().unwrap().to_string_lossy().into_owned(),
+                    std::fs::read_to_string(&path).unwrap_or_default(),
+                )
+            })
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    fn pair(name: &str, contents: &str) -> (String, String) {
+        (name.to_string(), contents.to_string())
+    }
+
+    #[test]
+    fn parses_version_names() {
+        assert_eq!(version("a.txt.~12~", "a.txt"), Some(12));
+        assert_eq!(version("a.txt.~x~", "a.txt"), None);
+        assert_eq!(version("b.txt.~1~", "a.txt"), None);
+        assert_eq!(version("a.txt", "a.txt"), None);
+    }
+
+    #[test]
+    fn keeps_previous_versions() {
+        compat::on_runtime(move || {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::create_dir(root.path().join("docs")).unwrap();
+            let storage = Versioned::new(Filesystem::new(root.path())).keep(2);
+            let put = |contents: &str| {
+                block_on(storage.put(Cursor::new(contents.as_bytes().to_vec()), "/docs/a.txt"))
+                    .unwrap();
+            };
+
+            put("one");
+            let dir = root.path().join(VERSIONS).join("docs");
+            assert!(!dir.exists());
+            put("two");
+            put("three");
+            assert_eq!(
+                versions(&dir),
+                vec![pair("a.txt.~1~", "one"), pair("a.txt.~2~", "two")]
+            );
+            put("four");
+            assert_eq!(
+                versions(&dir),
+                vec![pair("a.txt.~2~", "two"), pair("a.txt.~3~", "three")]
+            );
+            block_on(storage.del("/docs/a.txt")).unwrap();
+            assert!(!root.path().join("docs/a.txt").exists());
+            assert_eq!(
+                versions(&dir),
+                vec![pair("a.txt.~3~", "three"), pair("a.txt.~4~", "four")]
+            );
+            assert!(block_on(storage.del("/docs/a.txt")).is_err());
+
+            // The versions are out of reach.
+            let names: Vec<PathBuf> = block_on(storage.list("/").collect().compat())
+                .unwrap()
+                .into_iter()
+                .map(|file| file.path)
+                .collect();
+            assert_eq!(names, vec![PathBuf::from("docs")]);
+            assert!(block_on(storage.stat("/.versions/docs")).is_err());
+            assert!(block_on(storage.get("/docs/../.versions/docs/a.txt.~4~")).is_err());
+            assert!(block_on(storage.del("/.versions/docs/a.txt.~4~")).is_err());
+
+            let storage = Versioned::new(Filesystem::new(root.path())).keep(0);
+            block_on(storage.put(Cursor::new(b"x".to_vec()), "/b.txt")).unwrap();
+            block_on(storage.put(Cursor::new(b"y".to_vec()), "/b.txt")).unwrap();
+            block_on(storage.del("/b.txt")).unwrap();
+            assert!(!root.path().join(VERSIONS).join("b.txt.~1~").exists());
+        });
+    }
+}
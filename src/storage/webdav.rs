@@ -0,0 +1,1047 @@
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::{future, stream, Future, Stream};
+use futures03::compat::Future01CompatExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use log::warn;
+use tokio::prelude::AsyncRead;
+use tokio_codec::{BytesCodec, FramedRead};
+
+use super::cloud::{unescape, uri_encode, Chain};
+pub use super::cloud::{Object, ObjectMetadata};
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, Metadata, StorageBackend};
+use crate::auth::User;
+
+// The properties that `stat` and `list` ask for.
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><d:getetag/></d:prop></d:propfind>"#;
+
+/// The credentials that the requests to the WebDAV server are authenticated with.
+#[derive(Clone, PartialEq)]
+pub enum Credentials {
+    /// HTTP Basic authentication.
+    Basic {
+        /// The name of the account on the WebDAV server.
+        username: String,
+        /// Its password, or an app password like Nextcloud and SharePoint hand out.
+        password: String,
+    },
+    /// A bearer token, like an OAuth 2.0 access token.
+    Bearer(String),
+}
+
+impl Credentials {
+    /// Create credentials for HTTP Basic authentication.
+    pub fn basic<U: Into<String>, P: Into<String>>(username: U, password: P) -> Self {
+        Credentials::Basic {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Create credentials that send the given bearer token.
+    pub fn bearer<T: Into<String>>(token: T) -> Self {
+        Credentials::Bearer(token.into())
+    }
+
+    // The value of the `Authorization` header.
+    fn authorization(&self) -> String {
+        match self {
+            Credentials::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Leave the secrets out of the logs.
+        match self {
+            Credentials::Basic { username, .. } => {
+                f.debug_struct("Basic").field("username", username).finish()
+            }
+            Credentials::Bearer(_) => f.write_str("Bearer"),
+        }
+    }
+}
+
+type CredentialsFn = dyn Fn(&User) -> Option<Credentials> + Send + Sync;
+
+// Looks up the credentials of the FTP user that logged in.
+#[derive(Clone)]
+struct PerUser(Arc<CredentialsFn>);
+
+impl fmt::Debug for PerUser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PerUser")
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    // The scheme and authority of the server, like `https://cloud.example.com`.
+    endpoint: String,
+    // The percent-encoded path of the collection that the root of the FTP server maps to,
+    // without a trailing slash, like `/remote.php/dav/files/alice`.
+    base: String,
+    per_user: Option<PerUser>,
+}
+
+/// A [`StorageBackend`] that passes everything on to a WebDAV server, like Nextcloud, ownCloud,
+/// SharePoint or Apache with `mod_dav`, so that FTP clients can get at the files stored there.
+///
+/// The root of the FTP server maps to the collection at the URL the backend is created with.
+/// `stat` and `LIST` are `PROPFIND` requests, and the other commands map to `GET`, `PUT`,
+/// `DELETE`, `MKCOL` and `MOVE`. Uploads are streamed to the server while the client is still
+/// uploading, with chunked transfer encoding. A server that ran out of space or quota (`507`)
+/// makes uploads fail with `452`, and one that refuses the credentials (`401` or `403`) makes
+/// commands fail with `550 Permission denied`.
+///
+/// WebDAV can't append to a file or change its modification time, so `APPE` downloads the file
+/// and uploads it again with the new bytes after it, and `MFMT` fails. That relies on the server
+/// replacing the file only once the new one is completely uploaded, like the servers above do.
+/// File modes are ignored. What an interrupted upload leaves behind is up to the server.
+///
+/// The requests are authenticated with the [`Credentials`] of the backend, or with those of the
+/// FTP user that logged in (see [`credentials_for`]). Every backend has its own HTTP client with
+/// its own connection pool, so create it once and hand every session a clone.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use firetrap::Server;
+/// use firetrap::storage::webdav::{Credentials, WebDavStorageBackend};
+///
+/// let dav = WebDavStorageBackend::new("https://cloud.example.com/remote.php/dav/files/ftp")
+///     .credentials(Credentials::basic("ftp", "app-password"));
+/// let server = Server::new(Box::new(move || dav.clone()));
+/// ```
+///
+/// [`StorageBackend`]: ../trait.StorageBackend.html
+/// [`Credentials`]: ./enum.Credentials.html
+/// [`credentials_for`]: #method.credentials_for
+#[derive(Clone, Debug)]
+pub struct WebDavStorageBackend {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: Arc<Config>,
+    // The credentials of the session, which `set_user` may swap for those of the user.
+    credentials: Option<Credentials>,
+    // Set by `transfer_id`: sent along with the requests, from the start of a transfer until the
+    // start of the next one.
+    transfer_id: Arc<Mutex<Option<String>>>,
+}
+
+impl WebDavStorageBackend {
+    /// Create a backend for the collection at the given URL, like
+    /// `https://cloud.example.com/remote.php/dav/files/alice`, without credentials. The path of
+    /// the URL must be percent-encoded already.
+    pub fn new<U: AsRef<str>>(url: U) -> Self {
+        let url = url.as_ref().trim_end_matches('/');
+        let authority = url.find("://").map_or(0, |start| start + 3);
+        let (endpoint, base) = match url[authority..].find('/') {
+            Some(path) => url.split_at(authority + path),
+            None => (url, ""),
+        };
+        WebDavStorageBackend {
+            client: Client::builder().build(HttpsConnector::new(4)),
+            config: Arc::new(Config {
+                endpoint: endpoint.to_string(),
+                base: base.to_string(),
+                per_user: None,
+            }),
+            credentials: None,
+            transfer_id: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Authenticate the requests with the given credentials.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Authenticate the requests of every session with the credentials that the given function
+    /// returns for the FTP user that logged in, instead of with those of the backend. When it
+    /// returns `None`, the requests of the session go out without credentials. With a server
+    /// that serves the files of the account the requests are authenticated as, like the
+    /// `/remote.php/webdav` URL of Nextcloud does, every user then gets their own files.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use firetrap::storage::webdav::{Credentials, WebDavStorageBackend};
+    ///
+    /// let dav = WebDavStorageBackend::new("https://cloud.example.com/remote.php/webdav")
+    ///     .credentials_for(|user| {
+    ///         let password = std::env::var(format!("DAV_PASSWORD_{}", user.username)).ok()?;
+    ///         Some(Credentials::basic(user.username.as_str(), password))
+    ///     });
+    /// ```
+    pub fn credentials_for<F>(mut self, credentials: F) -> Self
+    where
+        F: Fn(&User) -> Option<Credentials> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).per_user = Some(PerUser(Arc::new(credentials)));
+        self
+    }
+
+    // The path of the given file relative to the base collection, without slashes at either
+    // end. `..` never leaves the base collection.
+    fn name<P: AsRef<Path>>(&self, path: P) -> String {
+        let mut names: Vec<String> = vec![];
+        for component in path.as_ref().components() {
+            match component {
+                Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                Component::ParentDir => {
+                    names.pop();
+                }
+                _ => {}
+            }
+        }
+        names.join("/")
+    }
+
+    // The URL of the file or collection with the given name. Collections get a trailing slash,
+    // as some servers insist on.
+    fn url(&self, name: &str, collection: bool) -> String {
+        let slash = if collection && !name.is_empty() {
+            "/"
+        } else {
+            ""
+        };
+        format!(
+            "{}{}/{}{}",
+            self.config.endpoint,
+            self.config.base,
+            uri_encode(name, false),
+            slash
+        )
+    }
+
+    // Authenticates and sends the given request.
+    fn send(
+        &self,
+        request: DavRequest,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+        let mut builder = Request::builder();
+        builder.method(request.method).uri(request.uri);
+        for (name, value) in &request.headers {
+            builder.header(name.as_str(), value.as_str());
+        }
+        if let Some(credentials) = &self.credentials {
+            builder.header(hyper::header::AUTHORIZATION, credentials.authorization());
+        }
+        let agent = format!("firetrap/{}", env!("CARGO_PKG_VERSION"));
+        match &*self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(id) => builder.header(
+                hyper::header::USER_AGENT,
+                format!("{} transfer/{}", agent, id),
+            ),
+            None => builder.header(hyper::header::USER_AGENT, agent),
+        };
+        let http_request = match builder.body(request.body) {
+            Ok(http_request) => http_request,
+            Err(e) => {
+                warn!("Invalid WebDAV request: {}", e);
+                return Box::new(future::err(Error::backend(e)));
+            }
+        };
+        Box::new(self.client.request(http_request).map_err(|e| {
+            warn!("WebDAV request failed: {}", e);
+            Error::backend(e)
+        }))
+    }
+
+    // Sends the given request, failing unless the server replies with a success code.
+    fn send_ok(
+        &self,
+        request: DavRequest,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+        Box::new(self.send(request).and_then(success))
+    }
+
+    // Resolves to the properties of the file or collection with the given name, and with a depth
+    // of 1 to those of the members of the collection as well.
+    fn propfind(
+        &self,
+        name: &str,
+        depth: u8,
+    ) -> Box<dyn Future<Item = Vec<Entry>, Error = Error> + Send> {
+        let mut request = DavRequest::new(dav_method("PROPFIND"), self.url(name, depth > 0));
+        request.headers = vec![
+            ("Depth".to_string(), depth.to_string()),
+            (
+                hyper::header::CONTENT_TYPE.to_string(),
+                "application/xml; charset=utf-8".to_string(),
+            ),
+        ];
+        request.body = Body::from(PROPFIND);
+        let config = Arc::clone(&self.config);
+        Box::new(
+            self.send_ok(request)
+                .and_then(|response| {
+                    response.into_body().concat2().map_err(|e| {
+                        warn!("Failed to read the WebDAV reply: {}", e);
+                        Error::backend(e)
+                    })
+                })
+                .map(move |body| multistatus(&String::from_utf8_lossy(&body), &config.base)),
+        )
+    }
+
+    // Uploads the bytes to the file with the given name, while they come in. With `unique`, the
+    // upload fails if the file exists already.
+    fn upload<R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        name: &str,
+        unique: bool,
+    ) -> Box<dyn Future<Item = u64, Error = Error> + Send> {
+        let sent = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&sent);
+        let chunks = FramedRead::new(bytes, BytesCodec::new()).map(move |chunk| {
+            counter.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            chunk.freeze()
+        });
+        let mut request = DavRequest::new(Method::PUT, self.url(name, false));
+        if unique {
+            request
+                .headers
+                .push((hyper::header::IF_NONE_MATCH.to_string(), "*".to_string()));
+        }
+        request.body = Body::wrap_stream(chunks);
+        Box::new(
+            self.send_ok(request)
+                .map(move |_| sent.load(Ordering::SeqCst)),
+        )
+    }
+
+    fn get_file(
+        &self,
+        name: &str,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Box<dyn Future<Item = Box<dyn AsyncRead + Send>, Error = Error> + Send> {
+        use std::io::Read;
+
+        let mut request = DavRequest::new(Method::GET, self.url(name, false));
+        if let Some(range) = &range {
+            if range.start >= range.end {
+                return Box::new(future::ok(
+                    Box::new(Object::new(Body::empty())) as Box<dyn AsyncRead + Send>
+                ));
+            }
+            request.headers.push((
+                hyper::header::RANGE.to_string(),
+                format!("bytes={}-{}", range.start, range.end - 1),
+            ));
+        }
+        Box::new(self.send(request).and_then(move |response| {
+            // The range starts after the end of the file.
+            if range.is_some() && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+                let empty: Box<dyn AsyncRead + Send> = Box::new(Object::new(Body::empty()));
+                return future::Either::A(future::ok(empty));
+            }
+            future::Either::B(success(response).map(move |response| {
+                let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+                let object = Object::new(response.into_body());
+                match range {
+                    // Servers that don't do ranges send the whole file.
+                    Some(range) if !partial => {
+                        let reader: Box<dyn AsyncRead + Send> = Box::new(
+                            super::Skip {
+                                inner: object,
+                                remaining: range.start,
+                            }
+                            .take(range.end - range.start),
+                        );
+                        reader
+                    }
+                    _ => Box::new(object),
+                }
+            }))
+        }))
+    }
+
+    fn delete(&self, url: String) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+        Box::new(
+            self.send_ok(DavRequest::new(Method::DELETE, url))
+                .map(|_| ()),
+        )
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavStorageBackend {
+    type File = Object;
+    type Metadata = ObjectMetadata;
+    type Error = Error;
+
+    fn set_user(&mut self, user: &User) {
+        if let Some(per_user) = &self.config.per_user {
+            self.credentials = (per_user.0)(user);
+        }
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        let name = self.name(path);
+        let entries = self.propfind(&name, 0).compat().await?;
+        entries
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.metadata)
+            .ok_or(Error::from(ErrorKind::NotFound))
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<
+        dyn Stream<Item = Fileinfo<std::path::PathBuf, Self::Metadata>, Error = Self::Error> + Send,
+    >
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        let name = self.name(path);
+        Box::new(
+            self.propfind(&name, 1)
+                .map(move |entries| {
+                    // The listed collection is among the entries as well.
+                    let members = entries
+                        .into_iter()
+                        .filter(move |entry| entry.name != name)
+                        .map(|entry| Fileinfo {
+                            path: PathBuf::from(entry.name),
+                            metadata: entry.metadata,
+                        });
+                    stream::iter_ok(members)
+                })
+                .flatten_stream(),
+        )
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        let request = DavRequest::new(Method::GET, self.url(&self.name(path), false));
+        let response = self.send_ok(request).compat().await?;
+        Ok(Object::new(response.into_body()))
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn AsyncRead + Send>, Self::Error> {
+        self.get_file(&self.name(path), Some(range)).compat().await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, &self.name(path), false).compat().await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.upload(bytes, &self.name(path), true).compat().await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        let name = self.name(path);
+        let request = DavRequest::new(Method::GET, self.url(&name, false));
+        let response = self.send(request).compat().await?;
+        // Appending to nothing creates the file.
+        let existing = if response.status() == StatusCode::NOT_FOUND {
+            Object::new(Body::empty())
+        } else {
+            Object::new(success(response).compat().await?.into_body())
+        };
+        let chained = Chain {
+            first: Some(existing),
+            second: bytes,
+        };
+        self.upload(chained, &name, false).compat().await
+    }
+
+    fn transfer_id(&self, id: &str) {
+        *self.transfer_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(id.to_string());
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        // A `DELETE` of a collection deletes everything in it.
+        let name = self.name(&path);
+        if self.stat(path).await?.is_dir() {
+            return Err(Error::backend("Not a file"));
+        }
+        self.delete(self.url(&name, false)).compat().await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let request = DavRequest::new(dav_method("MKCOL"), self.url(&self.name(path), true));
+        self.send_ok(request).compat().await?;
+        Ok(())
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        let name = self.name(path);
+        if name.is_empty() {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        let entries = self.propfind(&name, 1).compat().await?;
+        let dir = entries
+            .iter()
+            .any(|entry| entry.name == name && entry.metadata.is_dir());
+        if !dir {
+            return Err(Error::backend("Not a directory"));
+        }
+        if entries.iter().any(|entry| entry.name != name) {
+            return Err(Error::from(ErrorKind::NotEmpty));
+        }
+        self.delete(self.url(&name, true)).compat().await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        let mut request = DavRequest::new(dav_method("MOVE"), self.url(&self.name(from), false));
+        request.headers = vec![
+            ("Destination".to_string(), self.url(&self.name(to), false)),
+            ("Overwrite".to_string(), "T".to_string()),
+        ];
+        self.send_ok(request).compat().await?;
+        Ok(())
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        _path: P,
+        _mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        // `getlastmodified` is a live property that only the server itself sets.
+        Err(Error::backend(
+            "WebDAV servers don't change modification times",
+        ))
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, _path: P, _mode: u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        let file = self.get_file(&self.name(path), range).compat().await?;
+        let hasher = FramedRead::new(file, BytesCodec::new())
+            .fold(algorithm.hasher(), |mut hasher, chunk| {
+                hasher.update(&chunk);
+                Ok::<_, io::Error>(hasher)
+            })
+            .compat()
+            .await
+            .map_err(|e| {
+                warn!("Failed to read the file from the WebDAV server: {}", e);
+                Error::backend(e)
+            })?;
+        Ok(hasher.finish())
+    }
+}
+
+// A request to the WebDAV server, before it's authenticated.
+struct DavRequest {
+    method: Method,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl DavRequest {
+    fn new(method: Method, uri: String) -> Self {
+        DavRequest {
+            method,
+            uri,
+            headers: vec![],
+            body: Body::empty(),
+        }
+    }
+}
+
+// One of the methods that WebDAV adds to HTTP.
+fn dav_method(name: &'static str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("invalid method")
+}
+
+// The error for a status code that the WebDAV server replied with.
+fn status_error(status: StatusCode) -> Error {
+    let kind = match status.as_u16() {
+        401 | 403 => ErrorKind::PermissionDenied,
+        404 => ErrorKind::NotFound,
+        // A `MKCOL` of something that exists
+        405 => ErrorKind::Exists,
+        // Insufficient Storage, which servers like Nextcloud also reply when the quota is used up
+        507 => ErrorKind::StorageFull,
+        _ => ErrorKind::Backend,
+    };
+    Error::new(kind, format!("The WebDAV server replied {}", status))
+}
+
+// Resolves to the response if it has a success code, or logs the error the server replied with.
+fn success(
+    response: Response<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = Error> + Send> {
+    if response.status().is_success() {
+        return Box::new(future::ok(response));
+    }
+    let status = response.status();
+    Box::new(response.into_body().concat2().then(move |body| {
+        // SabreDAV based servers like Nextcloud explain the error in `<s:message>`.
+        let message = body
+            .ok()
+            .and_then(|body| element(&String::from_utf8_lossy(&body), "message"));
+        warn!(
+            "The WebDAV server replied with {}: {}",
+            status,
+            message.unwrap_or_else(|| "no error message".to_string())
+        );
+        Err(status_error(status))
+    }))
+}
+
+// A file or collection in a `PROPFIND` reply.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    // The path relative to the base collection, without slashes at either end.
+    name: String,
+    metadata: ObjectMetadata,
+}
+
+// Parses the `multistatus` reply to a `PROPFIND`, leaving out the entries that aren't in the
+// collection at the given base path.
+fn multistatus(xml: &str, base: &str) -> Vec<Entry> {
+    let base = uri_decode(base);
+    elements(xml, "response")
+        .into_iter()
+        .filter_map(|response| {
+            let href = element(response, "href")?;
+            // Hrefs may be absolute URLs as well as absolute paths.
+            let path = match href.find("://") {
+                Some(start) => {
+                    let authority = &href[start + 3..];
+                    authority.find('/').map_or("", |path| &authority[path..])
+                }
+                None => href.as_str(),
+            };
+            let path = uri_decode(path);
+            let name = path.strip_prefix(&base)?;
+            if !name.is_empty() && !name.starts_with('/') {
+                return None;
+            }
+            // Only the properties the server found
+            let props: Vec<&str> = elements(response, "propstat")
+                .into_iter()
+                .filter(|propstat| {
+                    element(propstat, "status").is_none_or(|status| status.contains(" 200 "))
+                })
+                .collect();
+            Some(Entry {
+                name: name.trim_matches('/').to_string(),
+                metadata: metadata(&props.concat()),
+            })
+        })
+        .collect()
+}
+
+fn metadata(props: &str) -> ObjectMetadata {
+    let modified = element(props, "getlastmodified")
+        .and_then(|modified| DateTime::parse_from_rfc2822(modified.trim()).ok())
+        .map_or(SystemTime::UNIX_EPOCH, SystemTime::from);
+    let etag = element(props, "getetag").filter(|etag| !etag.is_empty());
+    if !elements(props, "collection").is_empty() {
+        return ObjectMetadata {
+            etag,
+            ..ObjectMetadata::dir(modified)
+        };
+    }
+    ObjectMetadata {
+        len: element(props, "getcontentlength")
+            .and_then(|len| len.trim().parse().ok())
+            .unwrap_or(0),
+        dir: false,
+        modified,
+        etag,
+    }
+}
+
+// Returns the contents of every element with the given local name in the given XML, whatever
+// its namespace prefix (`<d:href>`, `<D:href>` and `<href xmlns="DAV:">` alike). Empty elements
+// like `<d:collection/>` have empty contents. That's enough for `multistatus` replies, where the
+// elements we're after don't nest in elements with the same name.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['/', '?', '!']) {
+            continue;
+        }
+        let qualified = tag
+            .trim_end_matches('/')
+            .split_whitespace()
+            .next()
+            .unwrap_or("");
+        if qualified.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        if tag.ends_with('/') {
+            found.push("");
+            continue;
+        }
+        let close = format!("</{}>", qualified);
+        match rest.find(&close) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+// Returns the text of the first element with the given local name in the given XML.
+fn element(xml: &str, name: &str) -> Option<String> {
+    elements(xml, name).first().map(|text| unescape(text))
+}
+
+// Decodes the percent-encoded bytes in the given path.
+fn uri_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compat::compat;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn parses_multistatus_replies() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getetag>"5c8"</d:getetag></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getcontentlength/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>https://cloud.example.com/remote.php/dav/files/alice/Q%26A%20notes.txt</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>1234</d:getcontentlength>
+        <d:getlastmodified>Tue, 15 Oct 2019 08:12:31 GMT</d:getlastmodified>
+        <d:getetag>&quot;f00&quot;</d:getetag>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <D:response xmlns:D="DAV:">
+    <D:href>/remote.php/dav/files/alice/Photos/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection /></D:resourcetype></D:prop></D:propstat>
+  </D:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice2/elsewhere.txt</d:href>
+  </d:response>
+</d:multistatus>"#;
+        let entries = multistatus(xml, "/remote.php/dav/files/alice");
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    name: "".to_string(),
+                    metadata: ObjectMetadata {
+                        len: 0,
+                        dir: true,
+                        modified: SystemTime::UNIX_EPOCH,
+                        etag: Some("\"5c8\"".to_string()),
+                    },
+                },
+                Entry {
+                    name: "Q&A notes.txt".to_string(),
+                    metadata: ObjectMetadata {
+                        len: 1234,
+                        dir: false,
+                        modified: SystemTime::from(
+                            Utc.with_ymd_and_hms(2019, 10, 15, 8, 12, 31).unwrap()
+                        ),
+                        etag: Some("\"f00\"".to_string()),
+                    },
+                },
+                Entry {
+                    name: "Photos".to_string(),
+                    metadata: ObjectMetadata::dir(SystemTime::UNIX_EPOCH),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_paths_to_urls() {
+        let dav = WebDavStorageBackend::new("https://cloud.example.com/dav/files/alice/");
+        assert_eq!(
+            dav.url(&dav.name("/../reports/Q&A 2019.csv"), false),
+            "https://cloud.example.com/dav/files/alice/reports/Q%26A%202019.csv"
+        );
+        assert_eq!(
+            dav.url(&dav.name("/reports"), true),
+            "https://cloud.example.com/dav/files/alice/reports/"
+        );
+        assert_eq!(
+            dav.url("", true),
+            "https://cloud.example.com/dav/files/alice/"
+        );
+        let root = WebDavStorageBackend::new("http://localhost:8080");
+        assert_eq!(root.url("a.txt", false), "http://localhost:8080/a.txt");
+    }
+
+    #[test]
+    fn hides_secrets() {
+        let basic = format!("{:?}", Credentials::basic("alice", "secret"));
+        assert_eq!(basic, r#"Basic { username: "alice" }"#);
+        assert_eq!(format!("{:?}", Credentials::bearer("secret")), "Bearer");
+        assert_eq!(
+            Credentials::basic("Aladdin", "open sesame").authorization(),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+    }
+
+    // The files of a minimal WebDAV server: `None` for collections.
+    type Files = Arc<Mutex<BTreeMap<String, Option<Vec<u8>>>>>;
+
+    // Serves the given files under `/dav`, for the requests that the backend makes, and only to
+    // clients with the given authorization.
+    fn serve(
+        files: Files,
+        authorization: &'static str,
+        request: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let reply = |status: u16, body: Vec<u8>| {
+            let mut response = Response::new(Body::from(body));
+            *response.status_mut() = StatusCode::from_u16(status).unwrap();
+            response
+        };
+        let authorized = request
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .is_some_and(|value| value == authorization);
+        if !authorized {
+            return Box::new(future::ok(reply(401, vec![])));
+        }
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        let path = |url: &str| uri_decode(url.trim_end_matches('/'));
+        let key = path(request.uri().path());
+        let depth = header("Depth");
+        let destination = header("Destination").map(|url| {
+            let url: hyper::Uri = url.parse().unwrap();
+            path(url.path())
+        });
+        let unique = header("If-None-Match").is_some();
+        let method = request.method().clone();
+        Box::new(request.into_body().concat2().map(move |body| {
+            let mut files = files.lock().unwrap();
+            match (method.as_str(), files.get(&key).cloned()) {
+                ("PROPFIND", Some(file)) => {
+                    let mut listed = vec![(key.clone(), file)];
+                    if depth.as_deref() == Some("1") {
+                        let prefix = format!("{}/", key);
+                        listed.extend(
+                            files
+                                .iter()
+                                .filter(|(name, _)| {
+                                    name.strip_prefix(&prefix)
+                                        .is_some_and(|name| !name.contains('/'))
+                                })
+                                .map(|(name, file)| (name.clone(), file.clone())),
+                        );
+                    }
+                    let responses: String = listed
+                        .iter()
+                        .map(|(name, file)| {
+                            let props = match file {
+                                Some(contents) => format!(
+                                    "<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>",
+                                    contents.len()
+                                ),
+                                None => {
+                                    "<d:resourcetype><d:collection/></d:resourcetype>".to_string()
+                                }
+                            };
+                            format!(
+                                "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop>\
+                                 <d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                                uri_encode(name, false),
+                                props
+                            )
+                        })
+                        .collect();
+                    let xml = format!(
+                        "<d:multistatus xmlns:d=\"DAV:\">{}</d:multistatus>",
+                        responses
+                    );
+                    reply(207, xml.into_bytes())
+                }
+                ("GET", Some(Some(contents))) => reply(200, contents),
+                ("PUT", Some(_)) if unique => reply(412, vec![]),
+                ("PUT", _) => {
+                    files.insert(key, Some(body.to_vec()));
+                    reply(201, vec![])
+                }
+                ("MKCOL", None) => {
+                    files.insert(key, None);
+                    reply(201, vec![])
+                }
+                ("DELETE", Some(_)) => {
+                    let prefix = format!("{}/", key);
+                    files.retain(|name, _| *name != key && !name.starts_with(&prefix));
+                    reply(204, vec![])
+                }
+                ("MOVE", Some(file)) => {
+                    files.remove(&key);
+                    files.insert(destination.unwrap(), file);
+                    reply(201, vec![])
+                }
+                (_, None) => reply(404, vec![]),
+                _ => reply(405, vec![]),
+            }
+        }))
+    }
+
+    #[test]
+    fn talks_webdav() {
+        use hyper::service::service_fn;
+
+        let files: Files = Arc::new(Mutex::new(BTreeMap::new()));
+        files.lock().unwrap().insert("/dav".to_string(), None);
+        let authorization = "Basic YWxpY2U6c2VjcmV0";
+        let server_files = Arc::clone(&files);
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+            let files = Arc::clone(&server_files);
+            service_fn(move |request| serve(Arc::clone(&files), authorization, request))
+        });
+        let url = format!("http://{}/dav", server.local_addr());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.spawn(server.map_err(|e| panic!("{}", e)));
+
+        // The client and the fake server both have to run on the runtime.
+        rt.block_on(compat(async move {
+            let anonymous = WebDavStorageBackend::new(&url);
+            assert_eq!(
+                anonymous.stat("/").await,
+                Err(Error::from(ErrorKind::PermissionDenied))
+            );
+            let mut dav = anonymous
+                .credentials_for(|user| Some(Credentials::basic(user.username.as_str(), "secret")));
+            dav.set_user(&User::new("alice"));
+
+            dav.mkd("/docs").await.unwrap();
+            let sent = dav
+                .put(io::Cursor::new(b"0123456789".to_vec()), "/docs/a b.txt")
+                .await
+                .unwrap();
+            assert_eq!(sent, 10);
+            let unique = dav.put_unique(io::Cursor::new(b"again".to_vec()), "/docs/a b.txt");
+            assert!(unique.await.is_err());
+            dav.append(io::Cursor::new(b"!".to_vec()), "/docs/a b.txt")
+                .await
+                .unwrap();
+
+            let metadata = dav.stat("/docs/a b.txt").await.unwrap();
+            assert_eq!((metadata.len(), metadata.is_file()), (11, true));
+            let listed = dav.list("/docs").collect().compat().await.unwrap();
+            let names: Vec<_> = listed.iter().map(|file| file.path.clone()).collect();
+            assert_eq!(names, vec![PathBuf::from("docs/a b.txt")]);
+
+            // The fake server doesn't do ranges, so the backend skips to the range itself.
+            let range = dav.get_range("/docs/a b.txt", 2..5).await.unwrap();
+            let (_, contents) = tokio_io::io::read_to_end(range, vec![])
+                .compat()
+                .await
+                .unwrap();
+            assert_eq!(contents, b"234");
+
+            assert!(dav.rmd("/docs").await.is_err());
+            assert!(dav.del("/docs").await.is_err());
+            dav.rename("/docs/a b.txt", "/b.txt").await.unwrap();
+            let file = dav.get("/b.txt").await.unwrap();
+            let (_, contents) = tokio_io::io::read_to_end(file, vec![])
+                .compat()
+                .await
+                .unwrap();
+            assert_eq!(contents, b"0123456789!");
+            dav.rmd("/docs").await.unwrap();
+            dav.del("/b.txt").await.unwrap();
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+        assert_eq!(
+            files.lock().unwrap().keys().collect::<Vec<_>>(),
+            vec!["/dav"]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures::Stream;
+
+use super::{Error, ErrorKind, Fileinfo, HashAlgorithm, ListOptions, Metadata, StorageBackend};
+use crate::auth::User;
+use crate::events::NegotiatedOptions;
+
+/// The source of the [`Exists`] errors that uploads and renames fail with when they'd overwrite a
+/// file of a [`WriteOnce`] storage. The server replies `553` to those.
+///
+/// [`Exists`]: ./enum.ErrorKind.html#variant.Exists
+/// [`WriteOnce`]: ./struct.WriteOnce.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileExists;
+
+impl fmt::Display for FileExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The file exists, and mustn't be overwritten")
+    }
+}
+
+impl std::error::Error for FileExists {}
+
+/// A [`StorageBackend`] wrapper that never lets a file be overwritten: uploads to a path that
+/// exists, appends included, and renames onto one fail with an `Exists` [`Error`], which the
+/// server replies `553` to. Everything else is passed on to the wrapped backend, so files can still be deleted, and
+/// then uploaded anew. This is what ingest pipelines want, to be sure that a file they picked up
+/// doesn't change underneath them.
+///
+/// Uploads go to the wrapped backend's [`put_unique`], so a file that another session uploads at
+/// the same time doesn't get overwritten either, as far as the backend can tell.
+///
+/// # Example
+///
+/// ```rust
+/// use firetrap::Server;
+/// use firetrap::storage::{Filesystem, WriteOnce};
+///
+/// let server = Server::new(Box::new(|| WriteOnce::new(Filesystem::new("/srv/ingest"))));
+/// ```
+///
+/// [`StorageBackend`]: ./trait.StorageBackend.html
+/// [`Error`]: ./struct.Error.html
+/// [`put_unique`]: ./trait.StorageBackend.html#tymethod.put_unique
+pub struct WriteOnce<S> {
+    inner: S,
+}
+
+impl<S> WriteOnce<S> {
+    /// Wrap the given backend.
+    pub fn new(inner: S) -> Self {
+        WriteOnce { inner }
+    }
+}
+
+impl<S> WriteOnce<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<Error> + Send + 'static,
+{
+    // Fails if there's something at the path, so that what would overwrite it doesn't run.
+    async fn unless_exists(&self, path: &Path) -> Result<(), S::Error> {
+        match self.inner.stat(path).await {
+            Ok(_) => Err(Error::new(ErrorKind::Exists, FileExists).into()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend for WriteOnce<S>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    S::File: Send + 'static,
+    S::Metadata: Metadata + Send + 'static,
+    S::Error: From<Error> + Send + 'static,
+{
+    type File = S::File;
+    type Metadata = S::Metadata;
+    type Error = S::Error;
+
+    fn set_user(&mut self, user: &User) {
+        self.inner.set_user(user)
+    }
+
+    async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Self::Error> {
+        self.inner.stat(path).await
+    }
+
+    fn list<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Self::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata,
+    {
+        self.inner.list(path)
+    }
+
+    fn list_fmt<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: ListOptions,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.list_fmt(path, options)
+    }
+
+    fn nlst<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = std::io::Error> + Send>
+    where
+        <Self as StorageBackend>::Metadata: Metadata + 'static,
+        <Self as StorageBackend>::Error: Send + 'static,
+    {
+        self.inner.nlst(path)
+    }
+
+    async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Self::Error> {
+        self.inner.get(path).await
+    }
+
+    async fn get_range<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        range: std::ops::Range<u64>,
+    ) -> Result<Box<dyn tokio::prelude::AsyncRead + Send>, Self::Error>
+    where
+        Self::File: tokio::prelude::AsyncRead + Send + 'static,
+    {
+        self.inner.get_range(path, range).await
+    }
+
+    async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.unless_exists(path.as_ref()).await?;
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn put_unique<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.unless_exists(path.as_ref()).await?;
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+        &self,
+        bytes: R,
+        path: P,
+    ) -> Result<u64, Self::Error> {
+        self.unless_exists(path.as_ref()).await?;
+        self.inner.put_unique(bytes, path).await
+    }
+
+    async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Self::Error> {
+        self.inner.free_space(path).await
+    }
+
+    async fn presign<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        ttl: std::time::Duration,
+    ) -> Result<Option<String>, Self::Error> {
+        self.inner.presign(path, ttl).await
+    }
+
+    fn transfer_options(&self, options: &NegotiatedOptions) {
+        self.inner.transfer_options(options)
+    }
+
+    fn transfer_id(&self, id: &str) {
+        self.inner.transfer_id(id)
+    }
+
+    async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.del(path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.mkd(path).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Self::Error> {
+        self.inner.rmd(path).await
+    }
+
+    async fn rmd_recursive(&self, path: PathBuf) -> Result<(), Self::Error>
+    where
+        Self::Metadata: Metadata,
+    {
+        self.inner.rmd_recursive(path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Self::Error> {
+        self.unless_exists(to.as_ref()).await?;
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_mtime<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        mtime: SystemTime,
+    ) -> Result<(), Self::Error> {
+        self.inner.set_mtime(path, mtime).await
+    }
+
+    async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).await
+    }
+
+    async fn checksum<P: AsRef<Path> + Send>(
+        &self,
+        path: P,
+        algorithm: HashAlgorithm,
+        range: Option<std::ops::Range<u64>>,
+    ) -> Result<String, Self::Error> {
+        self.inner.checksum(path, algorithm, range).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Error, ErrorKind, Memory};
+    use futures03::executor::block_on;
+    use pretty_assertions::assert_eq;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn never_overwrites() {
+        let memory = Memory::new();
+        let storage = WriteOnce::new(memory.clone());
+        block_on(storage.put(Cursor::new(b"first".to_vec()), "/a.txt")).unwrap();
+        block_on(storage.put(Cursor::new(b"other".to_vec()), "/b.txt")).unwrap();
+
+        let errors = vec![
+            block_on(storage.put(Cursor::new(b"second".to_vec()), "/a.txt")).unwrap_err(),
+            block_on(storage.put_unique(Cursor::new(b"second".to_vec()), "/a.txt")).unwrap_err(),
+            block_on(storage.append(Cursor::new(b" and more".to_vec()), "/a.txt")).unwrap_err(),
+            block_on(storage.rename("/b.txt", "/a.txt")).unwrap_err(),
+        ];
+        for error in errors {
+            assert_eq!(error, Error::from(ErrorKind::Exists));
+            assert!(std::error::Error::source(&error)
+                .unwrap()
+                .is::<FileExists>());
+        }
+        let mut contents = String::new();
+        block_on(memory.get("/a.txt"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first");
+
+        // What's gone can be written anew.
+        block_on(storage.rename("/b.txt", "/c.txt")).unwrap();
+        block_on(storage.del("/a.txt")).unwrap();
+        block_on(storage.append(Cursor::new(b"second".to_vec()), "/a.txt")).unwrap();
+        assert_eq!(block_on(memory.stat("/a.txt")).unwrap().len(), 6);
+        assert!(block_on(memory.stat("/c.txt")).is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt::{self, Write};
+
+// The features that firetrap was built with, by name.
+pub(crate) fn features() -> Vec<(&'static str, bool)> {
+    vec![
+        ("pam", cfg!(feature = "pam")),
+        ("s3", cfg!(feature = "s3")),
+        ("gcs", cfg!(feature = "gcs")),
+        ("b2", cfg!(feature = "b2")),
+        ("hdfs", cfg!(feature = "hdfs")),
+        ("webdav", cfg!(feature = "webdav")),
+        ("encryption", cfg!(feature = "encryption")),
+        ("sftp", cfg!(feature = "sftp")),
+        ("ftp", cfg!(feature = "ftp")),
+        ("fuzzing", cfg!(feature = "fuzzing")),
+        ("bench", cfg!(feature = "bench")),
+    ]
+}
+
+// Returns the name of the host, if the system tells.
+pub(crate) fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // Safe, because the buffer is as long as we say and outlives the call.
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+// Builds a JSON object, one member at a time, in the order they're added.
+#[derive(Default)]
+pub(crate) struct Json {
+    members: Vec<String>,
+}
+
+impl Json {
+    pub(crate) fn new() -> Self {
+        Json::default()
+    }
+
+    pub(crate) fn string<V: fmt::Display>(self, name: &str, value: V) -> Self {
+        let value = quote(&value.to_string());
+        self.raw(name, value)
+    }
+
+    pub(crate) fn number<V: fmt::Display>(self, name: &str, value: V) -> Self {
+        self.raw(name, value.to_string())
+    }
+
+    pub(crate) fn boolean(self, name: &str, value: bool) -> Self {
+        self.raw(name, value.to_string())
+    }
+
+    // A string, or `null`.
+    pub(crate) fn optional<V: fmt::Display>(self, name: &str, value: Option<V>) -> Self {
+        match value {
+            Some(value) => self.string(name, value),
+            None => self.raw(name, "null".to_string()),
+        }
+    }
+
+    // A number, or `null`.
+    pub(crate) fn optional_number<V: fmt::Display>(self, name: &str, value: Option<V>) -> Self {
+        match value {
+            Some(value) => self.number(name, value),
+            None => self.raw(name, "null".to_string()),
+        }
+    }
+
+    pub(crate) fn strings<I>(self, name: &str, values: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: fmt::Display,
+    {
+        let values: Vec<String> = values
+            .into_iter()
+            .map(|value| quote(&value.to_string()))
+            .collect();
+        self.raw(name, format!("[{}]", values.join(",")))
+    }
+
+    pub(crate) fn object(self, name: &str, value: Json) -> Self {
+        let value = value.to_string();
+        self.raw(name, value)
+    }
+
+    pub(crate) fn objects<I: IntoIterator<Item = Json>>(self, name: &str, values: I) -> Self {
+        let values: Vec<String> = values.into_iter().map(|value| value.to_string()).collect();
+        self.raw(name, format!("[{}]", values.join(",")))
+    }
+
+    // A value that's already valid JSON.
+    pub(crate) fn raw(mut self, name: &str, value: String) -> Self {
+        self.members.push(format!("{}:{}", quote(name), value));
+        self
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{{}}}", self.members.join(","))
+    }
+}
+
+// The given text as a JSON string, with everything that isn't printable ASCII escaped, so that it
+// fits on a single reply line.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            ' '..='~' => quoted.push(c),
+            _ => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(quoted, "\\u{:04x}", unit).unwrap();
+                }
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn writes_json() {
+        let json = Json::new()
+            .string("name", "a \"quoted\"\r\nline\\ ☃")
+            .number("count", 3)
+            .number("ratio", 0.5)
+            .boolean("enabled", true)
+            .optional::<u16>("port", None)
+            .optional_number("limit", Some(7))
+            .strings("codecs", &["deflate", "gzip"])
+            .objects("errors", vec![Json::new().number("code", 550)])
+            .object("empty", Json::new());
+        assert_eq!(
+            json.to_string(),
+            r#"{"name":"a \"quoted\"\u000d\u000aline\\ \u2603","count":3,"ratio":0.5,"enabled":true,"port":null,"limit":7,"codecs":["deflate","gzip"],"errors":[{"code":550}],"empty":{}}"#
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::os::unix::io::AsRawFd;
+
+use bytes::{BufMut, BytesMut};
+use tokio::net::TcpStream;
+
+// "Interpret As Command": the start of every Telnet command.
+pub(crate) const IAC: u8 = 255;
+// The option negotiation commands WILL, WONT, DO and DONT, which are followed by an option.
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+// The start and end of a subnegotiation, with the option data in between.
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Data,
+    // Right after an IAC
+    Command,
+    // Right after WILL, WONT, DO or DONT, before the option
+    Option,
+    Subnegotiation,
+    // Right after an IAC in a subnegotiation
+    SubnegotiationCommand,
+}
+
+// Removes the Telnet commands (RFC 854) from the bytes of the control connection. Clients send
+// them mostly as the "Interrupt Process" and "Synch" (Data Mark) that go before an `ABOR`, but
+// could send option negotiations as well. Those are dropped without an answer, so no option is
+// ever enabled, but nothing is refused with a `WONT` or `DONT` either: a client that waits for
+// one waits in vain. Only an escaped `IAC IAC` gets through, as a single 255 byte.
+//
+// This keeps its state between calls, so commands can be split over reads in any way.
+#[derive(Debug)]
+pub(crate) struct Filter {
+    state: State,
+}
+
+impl Filter {
+    pub(crate) fn new() -> Self {
+        Filter { state: State::Data }
+    }
+
+    // Moves the data in `input` to `output`, without the Telnet commands.
+    pub(crate) fn filter(&mut self, input: &mut BytesMut, output: &mut BytesMut) {
+        output.reserve(input.len());
+        for &b in input.iter() {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Command,
+                (State::Data, b) => {
+                    output.put_u8(b);
+                    State::Data
+                }
+                (State::Command, IAC) => {
+                    output.put_u8(IAC);
+                    State::Data
+                }
+                (State::Command, WILL..=DONT) => State::Option,
+                (State::Command, SB) => State::Subnegotiation,
+                // Interrupt Process, Data Mark and the like: nothing for us to do
+                (State::Command, _) | (State::Option, _) => State::Data,
+                (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationCommand, SE) => State::Data,
+                (State::SubnegotiationCommand, _) => State::Subnegotiation,
+            };
+        }
+        input.clear();
+    }
+}
+
+// Makes the urgent data of the connection arrive in line with the rest. Clients send the Data
+// Mark of the Telnet "Synch" as urgent data, which would otherwise be left out of the stream and
+// leave the `IAC` before it dangling.
+pub(crate) fn receive_urgent_inline(socket: &TcpStream) -> std::io::Result<()> {
+    let enabled: libc::c_int = 1;
+    // Safe, because the socket is open for as long as we borrow it and `enabled` outlives the
+    // call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_OOBINLINE,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn filter(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut telnet = Filter::new();
+        let mut output = BytesMut::new();
+        for chunk in chunks {
+            telnet.filter(&mut BytesMut::from(*chunk), &mut output);
+        }
+        output.to_vec()
+    }
+
+    #[test]
+    fn removes_commands() {
+        // Interrupt Process and Synch before ABOR, in one go or split up
+        let abor = b"\xff\xf4\xff\xf2ABOR\r\n";
+        assert_eq!(filter(&[abor]), b"ABOR\r\n");
+        assert_eq!(filter(&[&abor[..1], &abor[1..3], &abor[3..]]), b"ABOR\r\n");
+        // Option negotiations and subnegotiations
+        assert_eq!(filter(&[b"\xff\xfd\x01NOOP\r\n"]), b"NOOP\r\n");
+        assert_eq!(
+            filter(&[b"\xff\xfa\x18\x01", b"\xff\xff\xff\xf0NOOP\r\n"]),
+            b"NOOP\r\n"
+        );
+        assert_eq!(filter(&[b"DELE caf\xff", b"\xff\r\n"]), b"DELE caf\xff\r\n");
+    }
+}
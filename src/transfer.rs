@@ -0,0 +1,164 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::sync::oneshot;
+use futures::Future;
+use tokio_io::AsyncRead;
+
+// The states of a transfer, in the order they can follow each other. `QUIT` means a `QUIT`
+// arrived while it was running, so the session ends as soon as it's done.
+const WAITING: u8 = 0;
+const RUNNING: u8 = 1;
+const QUIT: u8 = 2;
+const DONE: u8 = 3;
+
+// The session's handle on the transfer of its data connection. Dropping it, because the control
+// connection went away or another data connection replaced this one, cancels the transfer.
+pub(crate) struct TransferHandle {
+    progress: Arc<Progress>,
+    _cancel: oneshot::Sender<()>,
+}
+
+// What a transfer is doing, shared by the data channel that runs it and the control channel.
+pub(crate) struct Progress {
+    state: AtomicU8,
+    what: Mutex<String>,
+    start: Mutex<Option<Instant>>,
+    bytes: AtomicU64,
+}
+
+// Creates the handle for the session, the progress for the data channel, and the future that
+// resolves when the transfer gets cancelled.
+pub(crate) fn new() -> (
+    TransferHandle,
+    Arc<Progress>,
+    Box<dyn Future<Item = (), Error = ()> + Send>,
+) {
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let progress = Arc::new(Progress {
+        state: AtomicU8::new(WAITING),
+        what: Mutex::new(String::new()),
+        start: Mutex::new(None),
+        bytes: AtomicU64::new(0),
+    });
+    let handle = TransferHandle {
+        progress: Arc::clone(&progress),
+        _cancel: cancel_tx,
+    };
+    // Nothing ever gets sent, the receiver resolves when the sender is dropped.
+    (handle, progress, Box::new(cancel_rx.then(|_| Ok(()))))
+}
+
+impl TransferHandle {
+    // Describes the running transfer for `STAT`, if there is one.
+    pub(crate) fn status(&self) -> Option<String> {
+        match self.progress.state.load(Ordering::SeqCst) {
+            RUNNING | QUIT => {}
+            _ => return None,
+        }
+        let what = self.progress.what.lock().ok()?.clone();
+        let seconds = (*self.progress.start.lock().ok()?)?.elapsed().as_secs();
+        Some(format!(
+            "{}: {} bytes in {} seconds",
+            what,
+            self.progress.bytes.load(Ordering::SeqCst),
+            seconds
+        ))
+    }
+
+    // Asks the running transfer to end the session when it's done. Returns `false` if no
+    // transfer is running, so the session can end right away.
+    pub(crate) fn quit_when_done(&self) -> bool {
+        self.progress
+            .state
+            .compare_exchange(RUNNING, QUIT, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+impl Progress {
+    // Marks the start of the transfer, described by `what` (e.g. `RETR /file.txt`).
+    pub(crate) fn started(&self, what: String) {
+        if let Ok(mut described) = self.what.lock() {
+            *described = what;
+        }
+        if let Ok(mut start) = self.start.lock() {
+            *start = Some(Instant::now());
+        }
+        let _ = self
+            .state
+            .compare_exchange(WAITING, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    // Marks the end of the transfer. Returns whether the session should end now, because the
+    // client sent a `QUIT` while it was running.
+    pub(crate) fn finished(&self) -> bool {
+        self.state.swap(DONE, Ordering::SeqCst) == QUIT
+    }
+
+    // Counts the bytes that the transfer reads from the given reader.
+    pub(crate) fn count<R>(self: &Arc<Self>, reader: R) -> Counted<R> {
+        Counted {
+            inner: reader,
+            progress: Arc::clone(self),
+        }
+    }
+}
+
+// A reader that adds the bytes read to the `Progress` of a transfer.
+pub(crate) struct Counted<R> {
+    inner: R,
+    progress: Arc<Progress>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.bytes.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Counted<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn reports_progress() {
+        let (handle, progress, _cancelled) = new();
+        assert_eq!(handle.status(), None);
+        assert!(!handle.quit_when_done());
+
+        progress.started("RETR /file.txt".to_string());
+        let mut counted = progress.count(std::io::Cursor::new(vec![0; 100]));
+        let mut buf = [0; 60];
+        counted.read_exact(&mut buf).unwrap();
+        assert_eq!(
+            handle.status(),
+            Some("RETR /file.txt: 60 bytes in 0 seconds".to_string())
+        );
+        assert!(!progress.finished());
+        assert_eq!(handle.status(), None);
+    }
+
+    #[test]
+    fn quits_when_done() {
+        let (handle, progress, _cancelled) = new();
+        progress.started("STOR /file.txt".to_string());
+        assert!(handle.quit_when_done());
+        assert!(progress.finished());
+        assert!(!handle.quit_when_done());
+    }
+
+    #[test]
+    fn cancels_on_drop() {
+        let (handle, _progress, cancelled) = new();
+        drop(handle);
+        assert_eq!(cancelled.wait(), Ok(()));
+    }
+}
@@ -18,6 +18,77 @@ macro_rules! start_server {
     };
 }
 
+// A minimal FTP client for the commands that the `ftp` crate doesn't support.
+struct RawClient {
+    reader: std::io::BufReader<std::net::TcpStream>,
+    writer: std::net::TcpStream,
+}
+
+impl RawClient {
+    fn connect(addr: &str) -> RawClient {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = RawClient {
+            reader: std::io::BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        let greeting = client.read_reply();
+        assert!(
+            greeting.starts_with("220"),
+            "unexpected greeting {:?}",
+            greeting
+        );
+        client
+    }
+
+    fn login(&mut self) {
+        self.cmd("USER hoi");
+        assert!(self.cmd("PASS jij").starts_with("230"));
+    }
+
+    // Enters passive mode and connects to the data port the server tells us.
+    fn pasv(&mut self) -> std::net::TcpStream {
+        let reply = self.cmd("PASV");
+        assert!(reply.starts_with("227 "), "unexpected reply {:?}", reply);
+        let fields: Vec<u16> = reply
+            .split(['(', ')'])
+            .nth(1)
+            .unwrap()
+            .split(',')
+            .map(|field| field.parse().unwrap())
+            .collect();
+        std::net::TcpStream::connect(("127.0.0.1", fields[4] << 8 | fields[5])).unwrap()
+    }
+
+    // Sends the given command and returns the complete (possibly multi-line) reply.
+    fn cmd(&mut self, cmd: &str) -> String {
+        use std::io::Write;
+
+        self.writer
+            .write_all(format!("{}\r\n", cmd).as_bytes())
+            .unwrap();
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> String {
+        use std::io::BufRead;
+
+        let mut reply = String::new();
+        self.reader.read_line(&mut reply).unwrap();
+        if reply.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{} ", &reply[..3]);
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).unwrap();
+                reply.push_str(&line);
+                if line.starts_with(&end) || line.is_empty() {
+                    break;
+                }
+            }
+        }
+        reply
+    }
+}
+
 #[test]
 fn connect() {
     let addr = "127.0.0.1:1234";
@@ -173,7 +244,7 @@ fn cwd() {
     let pwd = ftp_stream.pwd().unwrap();
     assert_eq!(
         std::path::Path::new(&pwd),
-        std::path::Path::new("/").join(&basename)
+        std::path::Path::new("/").join(basename)
     );
 }
 
@@ -196,7 +267,7 @@ fn cdup() {
     let pwd = ftp_stream.pwd().unwrap();
     assert_eq!(
         std::path::Path::new(&pwd),
-        std::path::Path::new("/").join(&basename)
+        std::path::Path::new("/").join(basename)
     );
 
     ftp_stream.cdup().unwrap();
@@ -242,7 +313,7 @@ fn quit() {
 #[test]
 fn nlst() {
     let addr = "127.0.0.1:1245";
-    let root = tempfile::TempDir::new().unwrap().into_path();
+    let root = tempfile::TempDir::new().unwrap().keep();
     let path = root.clone();
     start_server!(addr, root);
 
@@ -265,7 +336,7 @@ fn nlst() {
 #[test]
 fn mkdir() {
     let addr = "127.0.0.1:1246";
-    let root = tempfile::TempDir::new().unwrap().into_path();
+    let root = tempfile::TempDir::new().unwrap().keep();
     let server_root = root.clone();
     start_server!(addr, server_root);
 
@@ -285,7 +356,7 @@ fn mkdir() {
 #[test]
 fn rename() {
     let addr = "127.0.0.1:1247";
-    let root = tempfile::TempDir::new().unwrap().into_path();
+    let root = tempfile::TempDir::new().unwrap().keep();
     let server_root = root.clone();
     start_server!(addr, server_root);
 
@@ -302,13 +373,13 @@ fn rename() {
 
     // Make sure we fail if we're not logged in
     ftp_stream
-        .rename(&from_filename, &to_filename)
+        .rename(from_filename, to_filename)
         .expect_err("Rename accepted without logging in");
 
     // Do the renaming
     ftp_stream.login("some", "user").unwrap();
     ftp_stream
-        .rename(&from_filename, &to_filename)
+        .rename(from_filename, to_filename)
         .expect("Failed to rename");
 
     // Give the OS some time to actually rename the thingy.
@@ -321,3 +392,2923 @@ fn rename() {
     let metadata = std::fs::metadata(full_to).expect("New filename not created");
     assert!(metadata.is_file());
 }
+
+#[test]
+fn size() {
+    use std::io::Write;
+
+    let addr = "127.0.0.1:1248";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+
+    let mut f = std::fs::File::create(root.join("sized.txt")).unwrap();
+    f.write_all(b"0123456789").unwrap();
+    std::fs::create_dir(root.join("subdir")).unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+
+    // Make sure we fail if we're not logged in
+    ftp_stream.size("sized.txt").unwrap_err();
+
+    ftp_stream.login("hoi", "jij").unwrap();
+    assert_eq!(ftp_stream.size("sized.txt").unwrap(), Some(10));
+
+    // Directories and missing files don't have a size
+    ftp_stream.size("subdir").unwrap_err();
+    ftp_stream.size("missing.txt").unwrap_err();
+}
+
+#[test]
+fn authorizer() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+
+    struct NoDeletes;
+    #[async_trait::async_trait]
+    impl Authorizer for NoDeletes {
+        async fn authorize(
+            &self,
+            _username: &str,
+            _path: &std::path::Path,
+            operation: Operation,
+        ) -> Result<bool, ()> {
+            Ok(operation != Operation::Delete)
+        }
+    }
+    static NO_DELETES: NoDeletes = NoDeletes;
+
+    let addr = "127.0.0.1:1249";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).authorizer(&NO_DELETES);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let full_path = root.join("precious.txt");
+    std::fs::File::create(&full_path).unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream.rm("precious.txt").unwrap_err();
+    assert!(std::fs::metadata(&full_path).is_ok());
+
+    // Other operations are still allowed
+    ftp_stream.simple_retr("precious.txt").unwrap();
+}
+
+#[test]
+fn authorizer_denies_mfmt() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+
+    struct ReadOnly;
+    #[async_trait::async_trait]
+    impl Authorizer for ReadOnly {
+        async fn authorize(
+            &self,
+            _username: &str,
+            _path: &std::path::Path,
+            operation: Operation,
+        ) -> Result<bool, ()> {
+            Ok(operation != Operation::Write)
+        }
+    }
+    static READ_ONLY: ReadOnly = ReadOnly;
+
+    let addr = "127.0.0.1:1329";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).authorizer(&READ_ONLY);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let full_path = root.join("dated.txt");
+    std::fs::File::create(&full_path).unwrap();
+    let modified = std::fs::metadata(&full_path).unwrap().modified().unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(
+        client.cmd("MFMT 20000101000000 dated.txt"),
+        "550 Permission denied\r\n"
+    );
+    assert_eq!(
+        std::fs::metadata(&full_path).unwrap().modified().unwrap(),
+        modified
+    );
+}
+
+#[test]
+fn slow_authorizer() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+    use futures03::compat::Future01CompatExt;
+
+    // Takes its time, like one that asks a policy server.
+    struct Remote;
+    #[async_trait::async_trait]
+    impl Authorizer for Remote {
+        async fn authorize(
+            &self,
+            username: &str,
+            _path: &std::path::Path,
+            _operation: Operation,
+        ) -> Result<bool, ()> {
+            let until = time::Instant::now() + time::Duration::from_millis(500);
+            tokio::timer::Delay::new(until).compat().await.unwrap();
+            Ok(username != "guest")
+        }
+    }
+    static REMOTE: Remote = Remote;
+
+    let addr = "127.0.0.1:1325";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(root).authorizer(&REMOTE);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    // The sessions wait for their decisions at the same time, not one after the other.
+    let start = time::Instant::now();
+    let sessions: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = RawClient::connect(addr);
+                let username = if i % 2 == 0 { "guest" } else { "hoi" };
+                client.cmd(&format!("USER {}", username));
+                assert!(client.cmd("PASS jij").starts_with("230"));
+                let reply = client.cmd(&format!("MKD dir{}", i));
+                if username == "guest" {
+                    assert_eq!(reply, "550 Permission denied\r\n");
+                } else {
+                    assert!(reply.starts_with("257 "), "{:?}", reply);
+                }
+                assert!(client.cmd("NOOP").starts_with("200"));
+            })
+        })
+        .collect();
+    for session in sessions {
+        session.join().unwrap();
+    }
+    assert!(start.elapsed() < time::Duration::from_secs(3));
+}
+
+#[test]
+fn mdtm() {
+    use chrono::prelude::*;
+
+    let addr = "127.0.0.1:1250";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+
+    let full_path = root.join("dated.txt");
+    std::fs::File::create(&full_path).unwrap();
+    let modified: DateTime<Utc> = std::fs::metadata(&full_path)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .into();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+
+    // Make sure we fail if we're not logged in
+    ftp_stream.mdtm("dated.txt").unwrap_err();
+
+    ftp_stream.login("hoi", "jij").unwrap();
+    let remote_modified = ftp_stream.mdtm("dated.txt").unwrap().unwrap();
+    assert_eq!(remote_modified.timestamp(), modified.timestamp());
+
+    ftp_stream.mdtm("missing.txt").unwrap_err();
+}
+
+#[test]
+fn mfmt() {
+    let addr = "127.0.0.1:1251";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+
+    let full_path = root.join("synced.txt");
+    std::fs::File::create(&full_path).unwrap();
+
+    let mut client = RawClient::connect(addr);
+
+    // Make sure we fail if we're not logged in
+    assert!(client
+        .cmd("MFMT 20190401120000 synced.txt")
+        .starts_with("530"));
+
+    client.login();
+    assert_eq!(
+        client.cmd("MFMT 20190401120000 synced.txt"),
+        "213 Modify=20190401120000; synced.txt\r\n"
+    );
+    let modified = std::fs::metadata(&full_path).unwrap().modified().unwrap();
+    assert_eq!(
+        modified,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_554_120_000)
+    );
+
+    assert!(client
+        .cmd("MFMT 20190401120000 missing.txt")
+        .starts_with("550"));
+}
+
+#[test]
+fn session_ended() {
+    use firetrap::events::{SessionEnded, SessionListener};
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<SessionEnded>>);
+    impl SessionListener for Recorder {
+        fn session_ended(&self, event: &SessionEnded) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1252";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).session_listener(&*RECORDER);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    std::fs::write(root.join("secret.txt"), b"0123456789").unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    let client_addr = ftp_stream.get_ref().local_addr().unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream.simple_retr("secret.txt").unwrap();
+    ftp_stream.simple_retr("secret.txt").unwrap();
+    ftp_stream.simple_retr("missing.txt").unwrap_err();
+    ftp_stream.quit().unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+
+    let events = RECORDER.0.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event.connection.peer, client_addr);
+    assert_eq!(event.connection.local, addr.parse().unwrap());
+    assert!(event.connection.ttl.is_some());
+    assert_eq!(event.username, Some("hoi".to_string()));
+    assert_eq!(event.bytes_sent, 20);
+    assert_eq!(event.bytes_received, 0);
+    assert_eq!(event.distinct_paths, 2);
+    assert_eq!(event.failed_operations, 1);
+    assert!(event.commands >= 8);
+}
+
+#[test]
+fn epsv() {
+    use std::io::Read;
+
+    let addr = "127.0.0.1:1253";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("EPSV 2").starts_with("522"));
+
+    let reply = client.cmd("EPSV");
+    assert!(reply.starts_with("229 "), "unexpected reply {:?}", reply);
+    let port: u16 = reply
+        .split("(|||")
+        .nth(1)
+        .and_then(|rest| rest.split('|').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(client.cmd("RETR data.txt").starts_with("150"));
+    let mut contents = vec![];
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"0123456789");
+    assert!(client.read_reply().starts_with("226"));
+
+    assert!(client.cmd("EPSV ALL").starts_with("200"));
+    assert!(client.cmd("PASV").starts_with("503"));
+    assert!(client.cmd("EPSV").starts_with("229"));
+}
+
+#[test]
+fn eprt() {
+    use std::io::Read;
+
+    let addr = "127.0.0.1:1254";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+
+    for (protocol, ip) in &[(1, "127.0.0.1"), (2, "::1")] {
+        let listener = std::net::TcpListener::bind((*ip, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let reply = client.cmd(&format!("EPRT |{}|{}|{}|", protocol, ip, port));
+        assert!(reply.starts_with("200"), "unexpected reply {:?}", reply);
+        assert!(client.cmd("RETR data.txt").starts_with("150"));
+        let (mut data, _) = listener.accept().unwrap();
+        let mut contents = vec![];
+        data.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"0123456789");
+        assert!(client.read_reply().starts_with("226"));
+    }
+
+    assert!(client.cmd("EPRT |2|127.0.0.1|2000|").starts_with("501"));
+}
+
+#[test]
+fn port() {
+    use std::io::{Read, Write};
+
+    // Active connections are made from the port below the control port, so leave 1256 free.
+    let addr = "127.0.0.1:1257";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+
+    let port_cmd = |listener: &std::net::TcpListener| {
+        let port = listener.local_addr().unwrap().port();
+        format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff)
+    };
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(client.cmd(&port_cmd(&listener)).starts_with("200"));
+    assert!(client.cmd("RETR data.txt").starts_with("150"));
+    let (mut data, peer) = listener.accept().unwrap();
+    assert_eq!(peer.port(), 1256);
+    let mut contents = vec![];
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"0123456789");
+    assert!(client.read_reply().starts_with("226"));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(client.cmd(&port_cmd(&listener)).starts_with("200"));
+    assert!(client.cmd("STOR uploaded.txt").starts_with("150"));
+    let (mut data, _) = listener.accept().unwrap();
+    data.write_all(b"uploaded").unwrap();
+    drop(data);
+    assert!(client.read_reply().starts_with("226"));
+    assert_eq!(
+        std::fs::read(root.join("uploaded.txt")).unwrap(),
+        b"uploaded"
+    );
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert!(client.cmd(&port_cmd(&listener)).starts_with("200"));
+    assert!(client.cmd("LIST").starts_with("150"));
+    let (mut data, _) = listener.accept().unwrap();
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).unwrap();
+    assert!(listing.contains("data.txt"));
+    assert!(listing.contains("uploaded.txt"));
+    assert!(client.read_reply().starts_with("226"));
+}
+
+#[test]
+fn stou() {
+    use firetrap::random::{DeterministicRandom, RandomSource};
+    use std::io::Write;
+
+    static RANDOM: DeterministicRandom = DeterministicRandom::new(7);
+
+    let addr = "127.0.0.1:1258";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).random_source(&RANDOM);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    let reply = client.cmd("STOU");
+    assert!(reply.starts_with("150 "), "unexpected reply {:?}", reply);
+    data.write_all(b"unique").unwrap();
+    drop(data);
+
+    // The first name was used for the session ID
+    let expected = DeterministicRandom::new(7);
+    expected.unique_name();
+    let name = expected.unique_name();
+    assert_eq!(reply, format!("150 FILE: {}\r\n", name));
+    assert_eq!(
+        client.read_reply(),
+        format!("250 Transfer complete, FILE: {}\r\n", name)
+    );
+    assert_eq!(std::fs::read(root.join(&name)).unwrap(), b"unique");
+
+    // Never overwrites a file that happens to have the next name.
+    let next = expected.unique_name();
+    std::fs::write(root.join(&next), b"precious").unwrap();
+    let mut data = client.pasv();
+    assert_eq!(client.cmd("STOU"), format!("150 FILE: {}\r\n", next));
+    // The server may have closed the data connection already.
+    let _ = data.write_all(b"clobber");
+    drop(data);
+    assert!(client.read_reply().starts_with("553"));
+    assert_eq!(std::fs::read(root.join(&next)).unwrap(), b"precious");
+}
+
+// Keeps many sessions open and checks that accepting a new connection doesn't get slower as the
+// number of sessions grows. Run it with `cargo test -- --ignored accept_latency`, and set
+// `FIRETRAP_LOAD_SESSIONS` to change the number of sessions (mind `ulimit -n`).
+#[test]
+#[ignore]
+fn accept_latency_under_load() {
+    use std::io::BufRead;
+
+    let sessions: usize = std::env::var("FIRETRAP_LOAD_SESSIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(5000);
+    let addr = "127.0.0.1:1259";
+    start_server!(addr);
+
+    // Returns the time it took to connect and receive the greeting.
+    let connect = || {
+        let start = time::Instant::now();
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut reader = std::io::BufReader::new(stream);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+        (start.elapsed(), reader)
+    };
+    let median = |mut latencies: Vec<time::Duration>| {
+        latencies.sort();
+        latencies[latencies.len() / 2]
+    };
+
+    let mut open = Vec::with_capacity(sessions);
+    let mut first = vec![];
+    let mut last = vec![];
+    for i in 0..sessions {
+        let (latency, session) = connect();
+        if i < 100 {
+            first.push(latency);
+        } else if i >= sessions - 100 {
+            last.push(latency);
+        }
+        open.push(session);
+    }
+
+    let (first, last) = (median(first), median(last));
+    println!(
+        "median accept latency with {} sessions: {:?} at the start, {:?} at the end",
+        sessions, first, last
+    );
+    assert!(
+        last < first * 5 + time::Duration::from_millis(2),
+        "accept latency went from {:?} to {:?}",
+        first,
+        last
+    );
+}
+
+#[test]
+fn current_thread_runtime() {
+    use firetrap::server::RuntimeFlavor;
+
+    let addr = "127.0.0.1:1260";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .runtime_flavor(RuntimeFlavor::CurrentThread);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    // Two sessions at once, both served by the same thread
+    let mut first = RawClient::connect(addr);
+    let mut second = RawClient::connect(addr);
+    first.login();
+    second.login();
+    assert!(first.cmd("PWD").starts_with("257 \"/\""));
+    assert!(second.cmd("NOOP").starts_with("200"));
+}
+
+#[test]
+fn pinned_accept_loop() {
+    let addr = "127.0.0.1:1261";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .worker_threads(1)
+            .blocking_threads(1)
+            .pin_accept_loop(true);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let contents = ftp_stream.simple_retr("data.txt").unwrap();
+    assert_eq!(contents.into_inner(), b"0123456789");
+}
+
+#[test]
+fn passive_host() {
+    let addr = "127.0.0.1:1262";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .passive_host(std::net::Ipv4Addr::new(203, 0, 113, 7));
+        server.listen(addr);
+    });
+    let resolver_addr = "127.0.0.1:1263";
+    thread::spawn(move || {
+        let server =
+            firetrap::Server::with_root(std::env::temp_dir()).passive_host_resolver(|local| {
+                assert_eq!(local, "127.0.0.1:1263".parse().unwrap());
+                Some(std::net::Ipv4Addr::new(198, 51, 100, 1))
+            });
+        server.listen(resolver_addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let reply = client.cmd("PASV");
+    assert!(
+        reply.starts_with("227 Entering Passive Mode (203,0,113,7,"),
+        "unexpected reply {:?}",
+        reply
+    );
+
+    let mut client = RawClient::connect(resolver_addr);
+    client.login();
+    let reply = client.cmd("PASV");
+    assert!(
+        reply.starts_with("227 Entering Passive Mode (198,51,100,1,"),
+        "unexpected reply {:?}",
+        reply
+    );
+}
+
+#[test]
+fn mode_z() {
+    use flate2::read::{ZlibDecoder, ZlibEncoder};
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:1264";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    let data = b"Lorem ipsum dolor sit amet. ".repeat(1000);
+    std::fs::write(root.join("text.txt"), &data).unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("MODE Z").starts_with("200"));
+
+    let mut data_conn = client.pasv();
+    assert!(client.cmd("RETR text.txt").starts_with("150"));
+    let mut compressed = vec![];
+    data_conn.read_to_end(&mut compressed).unwrap();
+    assert!(client.read_reply().starts_with("226"));
+    assert!(compressed.len() < data.len() / 10);
+    let mut decompressed = vec![];
+    ZlibDecoder::new(compressed.as_slice())
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, data);
+
+    let mut data_conn = client.pasv();
+    assert!(client.cmd("STOR upload.txt").starts_with("150"));
+    let mut upload = vec![];
+    ZlibEncoder::new(data.as_slice(), flate2::Compression::default())
+        .read_to_end(&mut upload)
+        .unwrap();
+    data_conn.write_all(&upload).unwrap();
+    drop(data_conn);
+    assert!(client.read_reply().starts_with("226"));
+    assert_eq!(std::fs::read(root.join("upload.txt")).unwrap(), data);
+
+    // And back to uncompressed transfers
+    assert!(client.cmd("MODE S").starts_with("200"));
+    let mut data_conn = client.pasv();
+    assert!(client.cmd("RETR upload.txt").starts_with("150"));
+    let mut plain = vec![];
+    data_conn.read_to_end(&mut plain).unwrap();
+    assert!(client.read_reply().starts_with("226"));
+    assert_eq!(plain, data);
+}
+
+#[test]
+fn bandwidth_limit() {
+    let addr = "127.0.0.1:1265";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).bandwidth_limit(200_000);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("big.bin"), vec![0u8; 100_000]).unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let start = time::Instant::now();
+    let contents = ftp_stream.simple_retr("big.bin").unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(contents.into_inner().len(), 100_000);
+    assert!(
+        elapsed >= time::Duration::from_millis(400),
+        "took {:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn ascii_type() {
+    use ftp::types::{FileType, FormatControl};
+
+    let addr = "127.0.0.1:1266";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("unix.txt"), b"one\ntwo\n").unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream
+        .transfer_type(FileType::Ascii(FormatControl::Default))
+        .unwrap();
+
+    let contents = ftp_stream.simple_retr("unix.txt").unwrap();
+    assert_eq!(contents.into_inner(), b"one\r\ntwo\r\n");
+
+    let mut upload = std::io::Cursor::new(b"three\r\nfour\r\n".to_vec());
+    ftp_stream.put("dos.txt", &mut upload).unwrap();
+    assert_eq!(
+        std::fs::read(root.join("dos.txt")).unwrap(),
+        b"three\nfour\n"
+    );
+
+    // Binary transfers are left alone
+    ftp_stream.transfer_type(FileType::Binary).unwrap();
+    let contents = ftp_stream.simple_retr("unix.txt").unwrap();
+    assert_eq!(contents.into_inner(), b"one\ntwo\n");
+}
+
+#[test]
+fn abor() {
+    use std::io::Read;
+
+    let addr = "127.0.0.1:1267";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        // Slow down the transfer, so it's still running when we abort it
+        let server = firetrap::Server::with_root(server_root).bandwidth_limit(100_000);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("huge.bin"), vec![0u8; 1_000_000]).unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR huge.bin").starts_with("150"));
+    thread::sleep(time::Duration::from_millis(100));
+
+    assert!(client.cmd("ABOR").starts_with("426"));
+    assert!(client.read_reply().starts_with("226"));
+    let mut received = vec![];
+    data.read_to_end(&mut received).unwrap();
+    assert!(received.len() < 1_000_000);
+
+    // An idle data connection is just closed
+    let mut data = client.pasv();
+    assert_eq!(client.cmd("ABOR"), "226 Closed data channel\r\n");
+    assert_eq!(data.read(&mut [0u8; 16]).unwrap(), 0);
+
+    // The session is still usable
+    assert!(client.cmd("NOOP").starts_with("200"));
+}
+
+#[test]
+fn control_channel_during_transfer() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:1294";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).bandwidth_limit(100_000);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("huge.bin"), vec![0u8; 1_000_000]).unwrap();
+
+    // STAT reports on the running upload, and QUIT waits for it.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    assert!(client.cmd("STOR up.bin").starts_with("150"));
+    data.write_all(&[1u8; 1000]).unwrap();
+    thread::sleep(time::Duration::from_millis(200));
+    let status = client.cmd("STAT");
+    assert!(
+        status.contains(" Transferring STOR /up.bin: 1000 bytes in "),
+        "unexpected status {:?}",
+        status
+    );
+    client.writer.write_all(b"QUIT\r\n").unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+    data.write_all(&[1u8; 1000]).unwrap();
+    drop(data);
+    assert!(client.read_reply().starts_with("226"));
+    assert_eq!(client.read_reply(), "221 bye!\r\n");
+    assert_eq!(std::fs::read(root.join("up.bin")).unwrap().len(), 2000);
+
+    // Closing the control connection cancels the running download.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR huge.bin").starts_with("150"));
+    drop(client);
+    let mut received = vec![];
+    data.read_to_end(&mut received).unwrap();
+    assert!(received.len() < 1_000_000);
+}
+
+#[test]
+fn stat() {
+    let addr = "127.0.0.1:1268";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("hello.txt"), b"hello").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    assert!(client.cmd("STAT").starts_with("530"));
+    client.login();
+
+    let status = client.cmd("STAT");
+    assert!(status.starts_with("211-"), "unexpected status {:?}", status);
+    assert!(status.contains(" Logged in as hoi\r\n"));
+    assert!(status.contains(" TYPE: BINARY"));
+    assert!(status.ends_with("211 End of status\r\n"));
+
+    // The listing is sent over the control connection
+    let listing = client.cmd("STAT /");
+    assert!(
+        listing.starts_with("213-"),
+        "unexpected listing {:?}",
+        listing
+    );
+    assert!(listing.contains(" 5 "));
+    assert!(listing.contains(" hello.txt\r\n"));
+    assert!(listing.ends_with("213 End of status\r\n"));
+
+    assert!(client.cmd("STAT /nonexistent").starts_with("550"));
+}
+
+#[test]
+fn site_chmod() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let addr = "127.0.0.1:1269";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("deploy.sh"), b"#!/bin/sh\n").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(
+        client.cmd("SITE CHMOD 755 deploy.sh"),
+        "200 SITE CHMOD command successful\r\n"
+    );
+    let mode = std::fs::metadata(root.join("deploy.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+
+    assert!(client.cmd("SITE CHMOD 755 missing.sh").starts_with("550"));
+    assert!(client.cmd("SITE CHMOD u+x deploy.sh").starts_with("501"));
+    assert!(client.cmd("SITE HELP").contains(" CHMOD "));
+}
+
+#[test]
+fn huge_file_sizes() {
+    let addr = "127.0.0.1:1270";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    // A sparse file, so this doesn't actually take up 6GiB
+    let len: u64 = 6 << 30;
+    std::fs::File::create(root.join("huge.img"))
+        .unwrap()
+        .set_len(len)
+        .unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("SIZE huge.img"), format!("213 {}\r\n", len));
+    assert!(client.cmd("STAT /").contains(&format!(" {} ", len)));
+}
+
+#[test]
+fn async_site_command() {
+    use futures::Future;
+
+    let addr = "127.0.0.1:1271";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(root).site_command_async(
+            "WHOAMI",
+            "Show your username",
+            |context, _| {
+                // Reply from another thread, like a real asynchronous operation would
+                let (tx, rx) = futures::sync::oneshot::channel();
+                thread::spawn(move || {
+                    thread::sleep(time::Duration::from_millis(50));
+                    let username = context.username.unwrap_or_default();
+                    tx.send(format!("200 {} in {}\r\n", username, context.cwd.display()))
+                        .unwrap();
+                });
+                rx.map_err(|_| ())
+            },
+        );
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("SITE whoami"), "200 hoi in /\r\n");
+    assert!(client
+        .cmd("SITE HELP")
+        .contains(" WHOAMI   Show your username\r\n"));
+}
+
+#[test]
+fn command_timeout() {
+    use futures::Future;
+
+    let addr = "127.0.0.1:1299";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(root)
+            .command_timeout(time::Duration::from_millis(100))
+            .site_command_async("SLOW", "Take a while", |_, args| {
+                let delay = time::Duration::from_millis(args.parse().unwrap());
+                let (tx, rx) = futures::sync::oneshot::channel();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = tx.send("200 Finally\r\n".to_string());
+                });
+                rx.map_err(|_| ())
+            });
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("SITE SLOW 10"), "200 Finally\r\n");
+    assert_eq!(
+        client.cmd("SITE SLOW 300"),
+        "451 The command timed out, please try again later\r\n"
+    );
+    // The session goes on, without the reply that came too late.
+    thread::sleep(time::Duration::from_millis(300));
+    assert_eq!(client.cmd("NOOP"), "200 Successfully did nothing\r\n");
+}
+
+#[test]
+fn hash() {
+    let addr = "127.0.0.1:1272";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(
+        root.join("fox.txt"),
+        b"The quick brown fox jumps over the lazy dog",
+    )
+    .unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client
+        .cmd("FEAT")
+        .contains(" HASH SHA-256*;SHA-1;MD5;CRC32\r\n"));
+    assert_eq!(
+        client.cmd("HASH fox.txt"),
+        "213 SHA-256 0-43 d7a8fbb307d7809469ca9abcb0082e4f8d5651e46d3cdb762d02d0bf37c9e592 fox.txt\r\n"
+    );
+
+    assert_eq!(client.cmd("OPTS HASH md5"), "200 MD5\r\n");
+    assert_eq!(client.cmd("OPTS HASH"), "200 MD5\r\n");
+    assert!(client
+        .cmd("FEAT")
+        .contains(" HASH SHA-256;SHA-1;MD5*;CRC32\r\n"));
+    assert_eq!(
+        client.cmd("HASH fox.txt"),
+        "213 MD5 0-43 9e107d9d372bb6826bd81d3542a419d6 fox.txt\r\n"
+    );
+
+    assert!(client.cmd("OPTS HASH SHA-512").starts_with("501"));
+    assert!(client.cmd("HASH missing.txt").starts_with("550"));
+    assert!(client.cmd("HASH /").starts_with("550"));
+}
+
+#[test]
+fn concurrent_writes() {
+    use firetrap::server::ConcurrentWrites;
+    use std::io::Write;
+
+    let start = |addr: &'static str, policy| {
+        let root = tempfile::TempDir::new().unwrap().keep();
+        let server_root = root.clone();
+        thread::spawn(move || {
+            let server = firetrap::Server::with_root(server_root).concurrent_writes(policy);
+            server.listen(addr);
+        });
+        thread::sleep(time::Duration::from_millis(100));
+        root
+    };
+    let start_upload = |addr| {
+        let mut client = RawClient::connect(addr);
+        client.login();
+        let data = client.pasv();
+        assert!(client.cmd("STOR shared.txt").starts_with("150"));
+        (client, data)
+    };
+
+    // The second upload is rejected while the first one is running
+    let addr = "127.0.0.1:1273";
+    let root = start(addr, ConcurrentWrites::Reject);
+    let (mut first, mut first_data) = start_upload(addr);
+    first_data.write_all(b"first ").unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+    let (mut second, _second_data) = start_upload(addr);
+    assert!(second.read_reply().starts_with("450"));
+    first_data.write_all(b"upload").unwrap();
+    drop(first_data);
+    assert!(first.read_reply().starts_with("226"));
+    assert_eq!(
+        std::fs::read_to_string(root.join("shared.txt")).unwrap(),
+        "first upload"
+    );
+    // And the lock is released again
+    let (mut third, third_data) = start_upload(addr);
+    drop(third_data);
+    assert!(third.read_reply().starts_with("226"));
+
+    // The second upload waits for the first one
+    let addr = "127.0.0.1:1274";
+    let root = start(addr, ConcurrentWrites::Queue);
+    let (mut first, mut first_data) = start_upload(addr);
+    first_data.write_all(b"first ").unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+    let (mut second, mut second_data) = start_upload(addr);
+    second_data.write_all(b"second upload").unwrap();
+    drop(second_data);
+    thread::sleep(time::Duration::from_millis(100));
+    first_data.write_all(b"upload").unwrap();
+    drop(first_data);
+    assert!(first.read_reply().starts_with("226"));
+    assert!(second.read_reply().starts_with("226"));
+    assert_eq!(
+        std::fs::read_to_string(root.join("shared.txt")).unwrap(),
+        "second upload"
+    );
+}
+
+#[test]
+fn site_expect() {
+    let addr = "127.0.0.1:1275";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("report.csv"), b"a,b,c\n").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("SITE EXPECT size=lots").starts_with("501"));
+
+    // Somebody changed the file since we looked at it
+    assert!(client.cmd("SITE EXPECT size=3").starts_with("200"));
+    assert!(client.cmd("DELE report.csv").starts_with("450"));
+    assert!(root.join("report.csv").exists());
+    // The precondition only applies to a single command
+    assert!(client.cmd("RNFR report.csv").starts_with("350"));
+    assert!(client.cmd("SITE EXPECT size=3").starts_with("200"));
+    assert!(client.cmd("RNTO done.csv").starts_with("450"));
+    assert!(root.join("report.csv").exists());
+
+    assert!(client.cmd("RNFR report.csv").starts_with("350"));
+    assert!(client.cmd("SITE EXPECT size=6").starts_with("200"));
+    assert_eq!(
+        client.cmd("RNTO done.csv"),
+        "250 sure, it shall be known\r\n"
+    );
+    assert!(root.join("done.csv").exists());
+
+    assert!(client.cmd("SITE EXPECT size=6").starts_with("200"));
+    assert!(client.cmd("DELE done.csv").starts_with("250"));
+    assert!(!root.join("done.csv").exists());
+}
+
+#[test]
+fn xcrc_and_xmd5() {
+    let addr = "127.0.0.1:1276";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(
+        root.join("quick fox.txt"),
+        b"The quick brown fox jumps over the lazy dog",
+    )
+    .unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let feat = client.cmd("FEAT");
+    assert!(feat.contains(" XCRC\r\n"));
+    assert!(feat.contains(" XMD5\r\n"));
+    assert_eq!(client.cmd("XCRC \"quick fox.txt\""), "250 414FA339\r\n");
+    assert_eq!(client.cmd("XCRC quick fox.txt 16"), "250 959DAF95\r\n");
+    assert_eq!(
+        client.cmd("XMD5 \"quick fox.txt\" 4 9"),
+        "250 1DF3746A4728276AFDC24F828186F73A\r\n"
+    );
+    assert!(client.cmd("XMD5 missing.txt").starts_with("550"));
+    assert!(client.cmd("XCRC /").starts_with("550"));
+}
+
+#[test]
+fn rmd() {
+    let addr = "127.0.0.1:1277";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::create_dir(root.join("empty")).unwrap();
+    std::fs::create_dir(root.join("full")).unwrap();
+    std::fs::write(root.join("full/keep.txt"), b"keep me").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(
+        client.cmd("RMD empty"),
+        "250 Directory successfully removed\r\n"
+    );
+    assert!(!root.join("empty").exists());
+    assert!(client.cmd("XRMD full").starts_with("550"));
+    assert!(root.join("full/keep.txt").exists());
+    assert!(client.cmd("RMD missing").starts_with("550"));
+}
+
+#[test]
+fn retr_with_read_ahead() {
+    let addr = "127.0.0.1:1278";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).read_ahead(4096, 3);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+    std::fs::write(root.join("big.bin"), &data).unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let remote_data = ftp_stream.simple_retr("big.bin").unwrap().into_inner();
+    assert_eq!(remote_data, data);
+    ftp_stream.simple_retr("missing.bin").unwrap_err();
+}
+
+#[test]
+fn recursive_rmd() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+
+    // Only admins may remove whole trees.
+    struct AdminsOnly;
+    #[async_trait::async_trait]
+    impl Authorizer for AdminsOnly {
+        async fn authorize(
+            &self,
+            username: &str,
+            _path: &std::path::Path,
+            operation: Operation,
+        ) -> Result<bool, ()> {
+            Ok(operation != Operation::RemoveDirectoryRecursively || username == "admin")
+        }
+    }
+    static ADMINS_ONLY: AdminsOnly = AdminsOnly;
+
+    let addr = "127.0.0.1:1279";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .recursive_rmd(true)
+            .authorizer(&ADMINS_ONLY);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::create_dir_all(root.join("old/logs")).unwrap();
+    std::fs::write(root.join("old/logs/1.log"), b"...").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("RMD old").starts_with("550"));
+    assert!(root.join("old/logs/1.log").exists());
+
+    let mut admin = RawClient::connect(addr);
+    admin.cmd("USER admin");
+    assert!(admin.cmd("PASS secret").starts_with("230"));
+    assert_eq!(
+        admin.cmd("RMD old"),
+        "250 Directory successfully removed\r\n"
+    );
+    assert!(!root.join("old").exists());
+}
+
+#[test]
+fn allo() {
+    let addr = "127.0.0.1:1280";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("ALLO").starts_with("202"));
+    assert!(client.cmd("ALLO 1024").starts_with("200"));
+    // No disk is this big
+    assert!(client
+        .cmd(&format!("ALLO {}", u64::MAX))
+        .starts_with("452 Insufficient storage space"));
+}
+
+#[test]
+fn greeting_delay() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:1281";
+    let delay = time::Duration::from_millis(300);
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).greeting_delay(delay, delay);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let started = time::Instant::now();
+    let mut client = RawClient::connect(addr);
+    assert!(started.elapsed() >= delay);
+    client.login();
+
+    // Bots that don't wait for the greeting never get one.
+    let mut bot = std::net::TcpStream::connect(addr).unwrap();
+    bot.write_all(b"USER root\r\n").unwrap();
+    let mut reply = vec![];
+    let _ = bot.read_to_end(&mut reply);
+    assert_eq!(reply, b"");
+}
+
+#[test]
+fn lang() {
+    let addr = "127.0.0.1:1282";
+    thread::spawn(move || {
+        let mut catalog = firetrap::catalog::Catalog::new();
+        catalog.add(
+            "fr",
+            "Please authenticate with USER and PASS first",
+            "Veuillez d'abord vous authentifier avec USER et PASS",
+        );
+        catalog.add("fr", "Responses changed to FR", "Réponses changées en FR");
+        let server = firetrap::Server::with_root(std::env::temp_dir()).reply_catalog(catalog);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    assert_eq!(client.cmd("LANG de"), "504 Unsupported parameter\r\n");
+    assert_eq!(client.cmd("LANG fr-CA"), "200 Réponses changées en FR\r\n");
+    assert_eq!(
+        client.cmd("PWD"),
+        "530 Veuillez d'abord vous authentifier avec USER et PASS\r\n"
+    );
+
+    client.login();
+    assert!(client.cmd("FEAT").contains(" LANG EN;FR*\r\n"));
+    assert_eq!(client.cmd("LANG"), "200 Responses changed to EN\r\n");
+    assert!(client.cmd("FEAT").contains(" LANG EN*;FR\r\n"));
+    assert!(client.cmd("PWD").starts_with("257"));
+}
+
+#[test]
+fn transfer_ended() {
+    use firetrap::events::{NegotiatedOptions, SessionListener, TransferDirection, TransferEnded};
+    use ftp::types::{FileType, FormatControl};
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<TransferEnded>>);
+    impl SessionListener for Recorder {
+        fn transfer_ended(&self, event: &TransferEnded) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1283";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    let metrics = std::sync::Arc::new(firetrap::metrics::Metrics::new());
+    let server_metrics = std::sync::Arc::clone(&metrics);
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .session_listener(&*RECORDER)
+            .metrics(server_metrics);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    std::fs::write(root.join("unix.txt"), b"one\ntwo\n").unwrap();
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream.simple_retr("unix.txt").unwrap();
+    ftp_stream
+        .transfer_type(FileType::Ascii(FormatControl::Default))
+        .unwrap();
+    let mut upload = std::io::Cursor::new(b"three\r\nfour\r\n".to_vec());
+    ftp_stream.put("dos.txt", &mut upload).unwrap();
+    ftp_stream.simple_retr("missing.txt").unwrap_err();
+    ftp_stream.quit().unwrap();
+
+    let events = RECORDER.0.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].username, Some("hoi".to_string()));
+    assert_eq!(events[0].path, std::path::PathBuf::from("/unix.txt"));
+    assert_eq!(events[0].direction, TransferDirection::Download);
+    assert_eq!(events[0].bytes, 8);
+    assert_eq!(events[0].options, NegotiatedOptions::default());
+    assert_eq!(events[0].transfer_id, format!("{}.1", events[0].session_id));
+    assert_eq!(events[1].path, std::path::PathBuf::from("/dos.txt"));
+    assert_eq!(events[1].direction, TransferDirection::Upload);
+    assert_eq!(events[1].bytes, 11);
+    assert_eq!(events[1].transfer_id, format!("{}.2", events[1].session_id));
+    assert_eq!(
+        events[1].options,
+        NegotiatedOptions {
+            ascii: true,
+            ..NegotiatedOptions::default()
+        }
+    );
+
+    // The throughput of both transfers got recorded, by direction.
+    let throughput = metrics.throughput();
+    assert_eq!(throughput.len(), 2);
+    assert_eq!(throughput[0].0.direction, TransferDirection::Download);
+    assert_eq!(throughput[0].0.backend, "Filesystem");
+    assert_eq!(throughput[0].1.count(), 1);
+    assert_eq!(throughput[1].0.direction, TransferDirection::Upload);
+    assert!(events[1].duration > time::Duration::from_secs(0));
+}
+
+#[test]
+fn mlst() {
+    let addr = "127.0.0.1:1284";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::create_dir(root.join("dir")).unwrap();
+    std::fs::write(root.join("dir").join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client
+        .cmd("FEAT")
+        .contains(" MLST type*;size*;modify*;UNIX.uid*;UNIX.gid*;\r\n"));
+
+    assert!(client
+        .cmd("MFMT 20200102030405 dir/data.txt")
+        .starts_with("213"));
+    let reply = client.cmd("MLST dir/data.txt");
+    let lines: Vec<&str> = reply.split("\r\n").collect();
+    assert_eq!(lines.len(), 4, "unexpected reply {:?}", reply);
+    assert_eq!(lines[0], "250-Listing");
+    assert!(lines[1].starts_with(" type=file;size=10;modify=20200102030405;UNIX.uid="));
+    assert!(lines[1].ends_with("; /dir/data.txt"));
+    assert_eq!(lines[2], "250 End");
+
+    // Only the directory itself, not its contents
+    let reply = client.cmd("MLST dir");
+    assert_eq!(reply.lines().count(), 3, "unexpected reply {:?}", reply);
+    assert!(reply.contains(" type=dir;"));
+    assert!(reply.contains("; /dir\r\n"));
+
+    assert!(client.cmd("MLST missing.txt").starts_with("550"));
+}
+
+#[test]
+fn rang() {
+    use std::io::Read;
+
+    let addr = "127.0.0.1:1285";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("FEAT").contains(" RANG STREAM\r\n"));
+    assert!(client.cmd("RANG 5 4").starts_with("501"));
+
+    let retr = |client: &mut RawClient| {
+        let mut data = client.pasv();
+        assert!(client.cmd("RETR data.txt").starts_with("150"));
+        let mut contents = vec![];
+        data.read_to_end(&mut contents).unwrap();
+        assert!(client.read_reply().starts_with("226"));
+        contents
+    };
+
+    assert_eq!(
+        client.cmd("RANG 3 6"),
+        "350 Restarting at 3. Ending byte range at 6\r\n"
+    );
+    assert_eq!(retr(&mut client), b"3456");
+    // The range only applies to a single transfer.
+    assert_eq!(retr(&mut client), b"0123456789");
+
+    assert!(client.cmd("RANG 3 6").starts_with("350"));
+    assert_eq!(
+        client.cmd("RANG 1 0"),
+        "350 Restarting at 0. Ending byte range at EOF\r\n"
+    );
+    assert_eq!(retr(&mut client), b"0123456789");
+}
+
+#[test]
+fn file_mutated() {
+    use firetrap::events::{FileMutated, Mutation, SessionListener};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<FileMutated>>);
+    impl SessionListener for Recorder {
+        fn file_mutated(&self, event: &FileMutated) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1286";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).session_listener(&*RECORDER);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    std::fs::write(root.join("old.txt"), b"0123456789").unwrap();
+    std::fs::write(root.join("target.txt"), b"01234").unwrap();
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream
+        .put("old.txt", &mut std::io::Cursor::new(b"abc".to_vec()))
+        .unwrap();
+    ftp_stream.rename("old.txt", "target.txt").unwrap();
+    thread::sleep(time::Duration::from_millis(100));
+    ftp_stream.rm("target.txt").unwrap();
+    ftp_stream.rm("missing.txt").unwrap_err();
+    ftp_stream.quit().unwrap();
+
+    let events = RECORDER.0.lock().unwrap();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|e| {
+            (
+                e.mutation.clone(),
+                e.path.clone(),
+                e.before.map(|state| state.size),
+                e.after.map(|state| state.size),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                Mutation::Write,
+                PathBuf::from("/old.txt"),
+                Some(10),
+                Some(3)
+            ),
+            (
+                Mutation::Rename {
+                    from: PathBuf::from("/old.txt")
+                },
+                PathBuf::from("/target.txt"),
+                Some(5),
+                Some(3)
+            ),
+            (
+                Mutation::Delete,
+                PathBuf::from("/target.txt"),
+                Some(3),
+                None
+            ),
+        ]
+    );
+    assert!(events.iter().all(|e| e.username == Some("hoi".to_string())));
+    assert!(events[0].before.unwrap().modified.is_some());
+}
+
+#[test]
+fn help() {
+    let addr = "127.0.0.1:1287";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).site_command(
+            "PING",
+            "Check that the server is alive",
+            |_| "200 PONG\r\n".to_string(),
+        );
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    // No need to log in first
+    let mut client = RawClient::connect(addr);
+    let reply = client.cmd("HELP");
+    assert!(reply.starts_with("214-"));
+    assert!(reply.contains(" RETR "));
+    assert!(reply.ends_with("214 Use HELP <command> to learn more about one\r\n"));
+
+    assert_eq!(
+        client.cmd("HELP retr"),
+        "214 Syntax: RETR <path>: Download a file\r\n"
+    );
+    assert_eq!(client.cmd("HELP XYZZ"), "502 Unknown command XYZZ\r\n");
+    assert!(client.cmd("HELP SITE").contains(" PING "));
+    assert_eq!(
+        client.cmd("HELP SITE ping"),
+        "214 SITE PING: Check that the server is alive\r\n"
+    );
+    assert!(client
+        .cmd("HELP SITE CHMOD")
+        .starts_with("214 SITE CHMOD: "));
+    assert_eq!(
+        client.cmd("HELP SITE XYZZ"),
+        "502 Unknown SITE command XYZZ\r\n"
+    );
+}
+
+#[test]
+fn max_session_lifetime() {
+    use firetrap::events::{SessionExpired, SessionListener};
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<SessionExpired>>);
+    impl SessionListener for Recorder {
+        fn session_expired(&self, event: &SessionExpired) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1288";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .max_session_lifetime(time::Duration::from_millis(300))
+            .session_sweep_interval(time::Duration::from_millis(50))
+            .session_listener(&*RECORDER);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let started = time::Instant::now();
+    let mut client = RawClient::connect(addr);
+    client.login();
+    // Staying busy doesn't help.
+    loop {
+        let reply = client.cmd("NOOP");
+        if reply.starts_with("421") {
+            break;
+        }
+        assert!(reply.starts_with("200"), "unexpected reply {:?}", reply);
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+        thread::sleep(time::Duration::from_millis(20));
+    }
+    assert!(started.elapsed() >= time::Duration::from_millis(300));
+
+    let events = RECORDER.0.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].username, Some("hoi".to_string()));
+    assert!(events[0].lifetime >= time::Duration::from_millis(300));
+}
+
+#[test]
+fn proxy_protocol() {
+    use firetrap::events::{SessionListener, SessionStarted};
+    use firetrap::server::ProxyProtocol;
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+
+    struct Recorder(Mutex<Vec<SessionStarted>>);
+    impl SessionListener for Recorder {
+        fn session_started(&self, event: &SessionStarted) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1289";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .proxy_protocol(ProxyProtocol::Required)
+            .load_balancer("127.0.0.1/32".parse().unwrap())
+            // The client is elsewhere, according to the header.
+            .active_mode_policy(firetrap::server::ActiveModePolicy {
+                allow_server_addresses: true,
+                ..Default::default()
+            })
+            .session_listener(&*RECORDER);
+        server.listen(addr);
+    });
+    // Loopback isn't a load balancer of this one.
+    let untrusted = "127.0.0.1:1328";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .proxy_protocol(ProxyProtocol::Required)
+            .load_balancer("10.0.0.0/8".parse().unwrap());
+        server.listen(untrusted);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 21\r\n")
+        .unwrap();
+    let mut client = RawClient {
+        reader: std::io::BufReader::new(stream.try_clone().unwrap()),
+        writer: stream,
+    };
+    assert!(client.read_reply().starts_with("220"));
+    client.login();
+    {
+        let events = RECORDER.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let connection = events[0].connection;
+        assert_eq!(connection.peer, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(connection.local, "198.51.100.1:21".parse().unwrap());
+        assert_eq!(
+            connection.proxy.map(|proxy| proxy.ip()),
+            Some("127.0.0.1".parse().unwrap())
+        );
+    }
+
+    // Active data connections come from our real address, not the one in the header.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let reply = client.cmd(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff));
+    assert!(reply.starts_with("200"), "{:?}", reply);
+    assert!(client.cmd("NLST").starts_with("150"));
+    let (mut data, _) = listener.accept().unwrap();
+    data.read_to_end(&mut vec![]).unwrap();
+    assert!(client.read_reply().starts_with("226"));
+
+    // Anyone else's header isn't read, so it's just a command we don't know.
+    let mut stream = std::net::TcpStream::connect(untrusted).unwrap();
+    stream
+        .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 21\r\n")
+        .unwrap();
+    let mut client = RawClient {
+        reader: std::io::BufReader::new(stream.try_clone().unwrap()),
+        writer: stream,
+    };
+    assert!(client.read_reply().starts_with("220"));
+    assert!(client.read_reply().starts_with("500"));
+
+    // Connections that don't start with a header are closed without a greeting.
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.write_all(b"USER hoi\r\n").unwrap();
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).unwrap();
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn utf8_paths() {
+    use std::io::{BufRead, Write};
+
+    let addr = "127.0.0.1:1290";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let path = root.clone();
+    start_server!(addr, root);
+
+    let mut client = RawClient::connect(addr);
+    // Before logging in, like many clients do
+    assert_eq!(client.cmd("OPTS UTF8 ON"), "200 UTF8 mode enabled\r\n");
+    client.login();
+    assert!(client.cmd("MKD café").starts_with("257 "));
+    assert!(path.join("café").is_dir());
+
+    // Not UTF-8, so rejected rather than guessed at
+    client.writer.write_all(b"MKD caf\xe9\r\n").unwrap();
+    assert_eq!(client.read_reply(), "500 Invalid UTF8 in command\r\n");
+
+    // Without UTF-8, commands and replies are in ISO-8859-1.
+    assert!(client.cmd("OPTS UTF8 OFF").starts_with("200 "));
+    client.writer.write_all(b"MKD na\xefve\r\n").unwrap();
+    let mut reply = vec![];
+    client.reader.read_until(b'\n', &mut reply).unwrap();
+    assert!(reply.starts_with(b"257 "));
+    assert!(
+        reply.ends_with(b"na\xefve\r\n"),
+        "unexpected reply {:?}",
+        reply
+    );
+    assert!(path.join("naïve").is_dir());
+}
+
+#[test]
+fn greeting() {
+    // Returns the greeting a new connection gets.
+    fn greeting_of(addr: &str) -> String {
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = RawClient {
+            reader: std::io::BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        client.read_reply()
+    }
+
+    let addr = "127.0.0.1:1291";
+    thread::spawn(move || {
+        let server =
+            firetrap::Server::with_root(std::env::temp_dir()).greeting_generator(|connection| {
+                format!("Welcome {}\nAuthorized use only\n", connection.peer.ip())
+            });
+        server.listen(addr);
+    });
+    let banner = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(banner.path(), "Hello\r\nfrom a file\r\n").unwrap();
+    let banner_path = banner.path().to_path_buf();
+    let file_addr = "127.0.0.1:1292";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).greeting_file(banner_path);
+        server.listen(file_addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    assert_eq!(
+        greeting_of(addr),
+        "220-Welcome 127.0.0.1\r\n220 Authorized use only\r\n"
+    );
+    assert_eq!(greeting_of(file_addr), "220-Hello\r\n220 from a file\r\n");
+    // The file is read again for every connection.
+    std::fs::write(banner.path(), "Changed").unwrap();
+    assert_eq!(greeting_of(file_addr), "220 Changed\r\n");
+}
+
+#[test]
+fn case_insensitive_storage() {
+    use firetrap::storage::{CaseCollision, CaseInsensitive, Filesystem};
+
+    let addr = "127.0.0.1:1293";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    std::fs::write(root.join("README.TXT"), "Hello").unwrap();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::new(Box::new(move || {
+            CaseInsensitive::new(Filesystem::new(server_root.clone()))
+                .collisions(CaseCollision::Reject)
+        }));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let data = ftp_stream.simple_retr("readme.txt").unwrap().into_inner();
+    assert_eq!(data, b"Hello");
+    ftp_stream.put("Readme.txt", &mut &b"Bye"[..]).unwrap_err();
+    assert_eq!(std::fs::read(root.join("README.TXT")).unwrap(), b"Hello");
+}
+
+#[test]
+fn quirks() {
+    let addr = "127.0.0.1:1295";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir());
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    assert_eq!(client.cmd("CLNT WS_FTP Pro 12.9"), "200 Noted\r\n");
+    client.login();
+    assert_eq!(client.cmd("FEAT"), "211 no-features\r\n");
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("CLNT Trumpet FTP 2.0"), "200 Noted\r\n");
+    let features = client.cmd("FEAT");
+    assert!(features.starts_with("211- Extensions supported:\r\n211- EPRT\r\n"));
+    assert!(features.ends_with("\r\n211- XMD5\r\n211 End\r\n"));
+
+    // Clients without a profile get the usual replies.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("CLNT lftp 4.9.2"), "200 Noted\r\n");
+    assert!(client
+        .cmd("FEAT")
+        .starts_with("211-Extensions supported:\r\n EPRT\r\n"));
+}
+
+#[test]
+fn telnet_synch_before_abor() {
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+
+    let addr = "127.0.0.1:1296";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).bandwidth_limit(100_000);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("huge.bin"), vec![0u8; 1_000_000]).unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR huge.bin").starts_with("150"));
+    thread::sleep(time::Duration::from_millis(100));
+
+    // Like RFC 959 asks: Interrupt Process, then a Synch with its Data Mark as urgent data.
+    client.writer.write_all(b"\xff\xf4\xff").unwrap();
+    let sent = unsafe {
+        libc::send(
+            client.writer.as_raw_fd(),
+            b"\xf2".as_ptr() as *const libc::c_void,
+            1,
+            libc::MSG_OOB,
+        )
+    };
+    assert_eq!(sent, 1);
+    assert!(client.cmd("ABOR").starts_with("426"));
+    assert!(client.read_reply().starts_with("226"));
+    let mut received = vec![];
+    data.read_to_end(&mut received).unwrap();
+    assert!(received.len() < 1_000_000);
+
+    // Option negotiations are ignored.
+    client.writer.write_all(b"\xff\xfd\x01").unwrap();
+    assert!(client.cmd("NOOP").starts_with("200"));
+}
+
+#[test]
+fn site_presign() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+
+    // Only admins may hand out URLs.
+    struct AdminsOnly;
+    #[async_trait::async_trait]
+    impl Authorizer for AdminsOnly {
+        async fn authorize(
+            &self,
+            username: &str,
+            _path: &std::path::Path,
+            operation: Operation,
+        ) -> Result<bool, ()> {
+            Ok(operation != Operation::Presign || username == "admin")
+        }
+    }
+    static ADMINS_ONLY: AdminsOnly = AdminsOnly;
+
+    let addr = "127.0.0.1:1297";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).authorizer(&ADMINS_ONLY);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("big.iso"), b"...").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("SITE PRESIGN big.iso 3600").starts_with("550"));
+
+    let mut admin = RawClient::connect(addr);
+    admin.cmd("USER admin");
+    assert!(admin.cmd("PASS secret").starts_with("230"));
+    assert!(admin.cmd("SITE PRESIGN big.iso").starts_with("501"));
+    assert!(admin
+        .cmd("SITE PRESIGN missing.iso 3600")
+        .starts_with("550"));
+    // The local filesystem has no URLs to hand out.
+    assert_eq!(
+        admin.cmd("SITE PRESIGN big.iso 3600"),
+        "502 The storage backend can't presign URLs\r\n"
+    );
+}
+
+#[test]
+fn rein() {
+    let addr = "127.0.0.1:1298";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::create_dir(root.join("reports")).unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("CWD reports").starts_with("250"));
+    assert!(client.cmd("TYPE A").starts_with("200"));
+    assert!(client.cmd("USER other").starts_with("503"));
+
+    assert_eq!(client.cmd("REIN"), "220 Service ready for new user\r\n");
+    assert!(client.cmd("PWD").starts_with("530"));
+    assert!(client.cmd("USER other").starts_with("331"));
+    assert!(client.cmd("PASS secret").starts_with("230"));
+    assert_eq!(client.cmd("PWD"), "257 \"/\"\r\n");
+    assert!(client.cmd("STAT").contains(" TYPE: BINARY,"));
+}
+
+#[test]
+fn replaced_data_connections() {
+    use std::io::Read;
+
+    // Active connections are made from the port below the control port, so leave 1326 free.
+    let addr = "127.0.0.1:1327";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    start_server!(addr, server_root);
+    std::fs::write(root.join("data.txt"), b"0123456789").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+
+    // The data connection of the first PORT is given up, the second one gets the transfer.
+    let first = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let second = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    for listener in &[&first, &second] {
+        let port = listener.local_addr().unwrap().port();
+        let reply = client.cmd(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff));
+        assert!(reply.starts_with("200"), "{:?}", reply);
+    }
+    assert!(client.cmd("RETR data.txt").starts_with("150"));
+    let (mut data, _) = second.accept().unwrap();
+    let mut contents = vec![];
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"0123456789");
+    assert!(client.read_reply().starts_with("226"));
+
+    // The same for PASV.
+    let reply = client.cmd("PASV");
+    assert!(reply.starts_with("227 "), "{:?}", reply);
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR data.txt").starts_with("150"));
+    let mut contents = vec![];
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"0123456789");
+    assert!(client.read_reply().starts_with("226"));
+
+    // A data connection that arrives after a REIN is closed, and the session goes on.
+    let data = client.pasv();
+    assert!(client.cmd("REIN").starts_with("220"));
+    drop(data);
+    client.login();
+    assert!(client.cmd("NOOP").starts_with("200"));
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR data.txt").starts_with("150"));
+    let mut contents = vec![];
+    data.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"0123456789");
+    assert!(client.read_reply().starts_with("226"));
+}
+
+#[test]
+fn max_new_entries() {
+    use firetrap::auth::{Authenticator, User};
+    use firetrap::events::{EntryLimitReached, SessionListener};
+    use std::sync::Mutex;
+
+    struct Partners;
+    #[async_trait::async_trait]
+    impl Authenticator for Partners {
+        async fn authenticate(&self, _username: &str, _password: &str) -> Result<bool, ()> {
+            Ok(true)
+        }
+
+        fn user(&self, username: &str) -> User {
+            User::new(username).max_new_entries(2)
+        }
+    }
+    static PARTNERS: Partners = Partners;
+
+    struct Recorder(Mutex<Vec<EntryLimitReached>>);
+    impl SessionListener for Recorder {
+        fn entry_limit_reached(&self, event: &EntryLimitReached) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+    }
+
+    let addr = "127.0.0.1:1300";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .authenticator(&PARTNERS)
+            .session_listener(&*RECORDER);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("existing.txt"), b"old").unwrap();
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("partner", "secret").unwrap();
+    ftp_stream.mkdir("one").unwrap();
+    // Failing to create a directory doesn't count.
+    ftp_stream.mkdir("one").unwrap_err();
+    ftp_stream
+        .put("new.txt", &mut std::io::Cursor::new(b"new".to_vec()))
+        .unwrap();
+
+    // That's all the user may create, but existing files may still change.
+    assert!(ftp_stream
+        .mkdir("two")
+        .unwrap_err()
+        .to_string()
+        .contains("550 Too many new files and directories in this session"));
+    ftp_stream
+        .put("another.txt", &mut std::io::Cursor::new(b"no".to_vec()))
+        .unwrap_err();
+    ftp_stream
+        .put("existing.txt", &mut std::io::Cursor::new(b"new".to_vec()))
+        .unwrap();
+    ftp_stream.quit().unwrap();
+    assert!(!root.join("two").exists());
+    assert!(!root.join("another.txt").exists());
+    assert_eq!(std::fs::read(root.join("existing.txt")).unwrap(), b"new");
+
+    let events = RECORDER.0.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].username, Some("partner".to_string()));
+    assert_eq!(events[0].path, std::path::PathBuf::from("/two"));
+    assert_eq!(events[0].limit, 2);
+    assert_eq!(events[1].path, std::path::PathBuf::from("/another.txt"));
+}
+
+#[test]
+fn storage_full() {
+    use firetrap::events::{SessionListener, StorageFull, StorageRecovered};
+    use firetrap::server::StorageFullPolicy;
+    use firetrap::storage::{
+        Error, ErrorKind, Fileinfo, Filesystem, HashAlgorithm, StorageBackend,
+    };
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        full: Mutex<Vec<StorageFull>>,
+        recovered: Mutex<Vec<StorageRecovered>>,
+    }
+    impl SessionListener for Recorder {
+        fn storage_full(&self, event: &StorageFull) {
+            self.full.lock().unwrap().push(event.clone());
+        }
+
+        fn storage_recovered(&self, event: &StorageRecovered) {
+            self.recovered.lock().unwrap().push(event.clone());
+        }
+    }
+    lazy_static::lazy_static! {
+        static ref STRICT: Recorder = Recorder::default();
+        static ref LENIENT: Recorder = Recorder::default();
+    }
+
+    // Writes uploads named `full.bin`, and then fails them the way a full disk does.
+    struct FullDisk(Filesystem);
+    #[async_trait::async_trait]
+    impl StorageBackend for FullDisk {
+        type File = <Filesystem as StorageBackend>::File;
+        type Metadata = std::fs::Metadata;
+        type Error = Error;
+
+        async fn stat<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata, Error> {
+            self.0.stat(path).await
+        }
+
+        fn list<P: AsRef<Path>>(
+            &self,
+            path: P,
+        ) -> Box<dyn futures::Stream<Item = Fileinfo<PathBuf, Self::Metadata>, Error = Error> + Send>
+        {
+            self.0.list(path)
+        }
+
+        async fn get<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::File, Error> {
+            self.0.get(path).await
+        }
+
+        async fn put<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+            &self,
+            bytes: R,
+            path: P,
+        ) -> Result<u64, Error> {
+            let full = path.as_ref().file_name() == Some("full.bin".as_ref());
+            let written = self.0.put(bytes, path).await?;
+            if full {
+                Err(ErrorKind::StorageFull.into())
+            } else {
+                Ok(written)
+            }
+        }
+
+        async fn put_unique<
+            P: AsRef<Path> + Send,
+            R: tokio::prelude::AsyncRead + Send + 'static,
+        >(
+            &self,
+            bytes: R,
+            path: P,
+        ) -> Result<u64, Error> {
+            self.0.put_unique(bytes, path).await
+        }
+
+        async fn append<P: AsRef<Path> + Send, R: tokio::prelude::AsyncRead + Send + 'static>(
+            &self,
+            bytes: R,
+            path: P,
+        ) -> Result<u64, Error> {
+            self.0.append(bytes, path).await
+        }
+
+        async fn free_space<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>, Error> {
+            self.0.free_space(path).await
+        }
+
+        async fn del<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+            self.0.del(path).await
+        }
+
+        async fn mkd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+            self.0.mkd(path).await
+        }
+
+        async fn rmd<P: AsRef<Path> + Send>(&self, path: P) -> Result<(), Error> {
+            self.0.rmd(path).await
+        }
+
+        async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<(), Error> {
+            self.0.rename(from, to).await
+        }
+
+        async fn set_mtime<P: AsRef<Path> + Send>(
+            &self,
+            path: P,
+            mtime: std::time::SystemTime,
+        ) -> Result<(), Error> {
+            self.0.set_mtime(path, mtime).await
+        }
+
+        async fn chmod<P: AsRef<Path> + Send>(&self, path: P, mode: u32) -> Result<(), Error> {
+            self.0.chmod(path, mode).await
+        }
+
+        async fn checksum<P: AsRef<Path> + Send>(
+            &self,
+            path: P,
+            algorithm: HashAlgorithm,
+            range: Option<std::ops::Range<u64>>,
+        ) -> Result<String, Error> {
+            self.0.checksum(path, algorithm, range).await
+        }
+    }
+
+    let serve = |addr: &'static str, policy, recorder: &'static Recorder| {
+        let root = tempfile::TempDir::new().unwrap().keep();
+        let server_root = root.clone();
+        thread::spawn(move || {
+            let server = firetrap::Server::new(Box::new(move || {
+                FullDisk(Filesystem::new(server_root.clone()))
+            }))
+            .storage_full_policy(policy)
+            .session_listener(recorder);
+            server.listen(addr);
+        });
+        root
+    };
+    let strict = serve(
+        "127.0.0.1:1301",
+        StorageFullPolicy {
+            delete_partial: true,
+            // More than there'll ever be
+            read_only_until_free: Some(u64::MAX),
+        },
+        &STRICT,
+    );
+    let lenient = serve("127.0.0.1:1302", StorageFullPolicy::default(), &LENIENT);
+    thread::sleep(time::Duration::from_millis(100));
+
+    let upload = |ftp_stream: &mut FtpStream, name| {
+        ftp_stream.put(name, &mut std::io::Cursor::new(vec![b'x'; 64 * 1024]))
+    };
+
+    // The partial file goes, and uploads are refused from then on.
+    let mut ftp_stream = FtpStream::connect("127.0.0.1:1301").unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    let e = upload(&mut ftp_stream, "full.bin").unwrap_err();
+    assert!(
+        e.to_string().contains("452 Insufficient storage space"),
+        "{}",
+        e
+    );
+    assert!(!strict.join("full.bin").exists());
+    let e = upload(&mut ftp_stream, "other.bin").unwrap_err();
+    assert!(e.to_string().contains("452"), "{}", e);
+    assert!(!strict.join("other.bin").exists());
+    ftp_stream.quit().unwrap();
+    {
+        let full = STRICT.full.lock().unwrap();
+        assert_eq!(full.len(), 1);
+        assert_eq!(full[0].path, std::path::PathBuf::from("/full.bin"));
+        assert!(full[0].partial_deleted);
+        assert!(full[0].read_only);
+    }
+
+    // There's plenty of space elsewhere, so the next upload goes through.
+    let mut ftp_stream = FtpStream::connect("127.0.0.1:1302").unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    let e = upload(&mut ftp_stream, "full.bin").unwrap_err();
+    assert!(e.to_string().contains("452"), "{}", e);
+    assert!(lenient.join("full.bin").exists());
+    upload(&mut ftp_stream, "other.bin").unwrap();
+    ftp_stream.quit().unwrap();
+    assert!(!LENIENT.full.lock().unwrap()[0].partial_deleted);
+    assert_eq!(LENIENT.recovered.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn memory_storage() {
+    use firetrap::storage::{Memory, StorageBackend};
+
+    let addr = "127.0.0.1:1303";
+    let storage = Memory::new();
+    let server_storage = storage.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::new(Box::new(move || server_storage.clone()));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    ftp_stream.mkdir("inbox").unwrap();
+    ftp_stream.cwd("inbox").unwrap();
+    ftp_stream
+        .put("hello.txt", &mut std::io::Cursor::new(b"hello".to_vec()))
+        .unwrap();
+    ftp_stream.quit().unwrap();
+
+    // Every session sees the same files.
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    ftp_stream.rename("inbox", "done").unwrap();
+    assert_eq!(ftp_stream.nlst(None).unwrap(), vec!["done"]);
+    assert_eq!(ftp_stream.nlst(Some("done")).unwrap(), vec!["hello.txt"]);
+    let contents = ftp_stream.simple_retr("done/hello.txt").unwrap();
+    assert_eq!(contents.into_inner(), b"hello");
+    ftp_stream.quit().unwrap();
+
+    let mut contents = vec![];
+    let mut file = futures03::executor::block_on(storage.get("done/hello.txt")).unwrap();
+    std::io::Read::read_to_end(&mut file, &mut contents).unwrap();
+    assert_eq!(contents, b"hello");
+}
+
+#[test]
+fn allowlist() {
+    use firetrap::allowlist::Allowlist;
+    use std::io::BufRead;
+
+    let serve = |addr: &'static str, allowlist| {
+        thread::spawn(move || {
+            let server = firetrap::Server::with_root(std::env::temp_dir()).allowlist(allowlist);
+            server.listen(addr);
+        });
+    };
+    serve(
+        "127.0.0.1:1304",
+        Allowlist::new().network("192.0.2.0/24".parse().unwrap()),
+    );
+    serve(
+        "127.0.0.1:1305",
+        Allowlist::new()
+            .network("192.0.2.0/24".parse().unwrap())
+            .hostname("localhost"),
+    );
+    thread::sleep(time::Duration::from_millis(200));
+
+    let stream = std::net::TcpStream::connect("127.0.0.1:1304").unwrap();
+    let mut reader = std::io::BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("421 "), "{:?}", reply);
+    // And then the connection is closed.
+    reply.clear();
+    assert_eq!(reader.read_line(&mut reply).unwrap(), 0);
+
+    // Allowed by its hostname.
+    let mut ftp_stream = FtpStream::connect("127.0.0.1:1305").unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    ftp_stream.quit().unwrap();
+}
+
+#[test]
+fn read_only() {
+    use firetrap::storage::{Filesystem, ReadOnly};
+
+    let addr = "127.0.0.1:1306";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    std::fs::write(root.join("mirror.txt"), b"mirrored").unwrap();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::new(Box::new(move || {
+            ReadOnly::new(Filesystem::new(server_root.clone()))
+        }));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("anonymous", "").unwrap();
+    assert_eq!(ftp_stream.nlst(None).unwrap(), vec!["mirror.txt"]);
+    let contents = ftp_stream.simple_retr("mirror.txt").unwrap();
+    assert_eq!(contents.into_inner(), b"mirrored");
+
+    let denied = |result: ftp::types::Result<()>| {
+        let e = result.unwrap_err().to_string();
+        assert!(e.contains("550"), "{}", e);
+    };
+    denied(ftp_stream.put("new.txt", &mut std::io::Cursor::new(b"new".to_vec())));
+    denied(ftp_stream.rm("mirror.txt"));
+    denied(ftp_stream.mkdir("dir"));
+    denied(ftp_stream.rename("mirror.txt", "renamed.txt"));
+    ftp_stream.quit().unwrap();
+
+    // The reply tells why the storage refused, also for the commands that don't go over a data
+    // connection.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(
+        client.cmd("MFMT 20190401120000 mirror.txt"),
+        "550 Permision denied\r\n"
+    );
+    assert_eq!(
+        client.cmd("SITE CHMOD 600 mirror.txt"),
+        "550 Permision denied\r\n"
+    );
+    assert_eq!(client.cmd("HASH missing.txt"), "550 File not found\r\n");
+
+    let mut names: Vec<_> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["mirror.txt"]);
+}
+
+#[test]
+fn quota() {
+    use firetrap::storage::quota::{InMemoryUsage, Quota, QuotaLimits};
+    use firetrap::storage::Memory;
+    use std::sync::Arc;
+
+    let addr = "127.0.0.1:1307";
+    let usage = Arc::new(InMemoryUsage::new());
+    thread::spawn(move || {
+        let limits = QuotaLimits {
+            max_bytes: Some(8),
+            max_files: Some(2),
+        };
+        let memory = Memory::new();
+        let server = firetrap::Server::new(Box::new(move || {
+            Quota::new(memory.clone(), usage.clone()).limits(limits)
+        }));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream
+        .put("small.txt", &mut std::io::Cursor::new(b"small".to_vec()))
+        .unwrap();
+    let e = ftp_stream
+        .put("big.txt", &mut std::io::Cursor::new(b"too big".to_vec()))
+        .unwrap_err();
+    assert!(
+        e.to_string().contains("552 Exceeded storage allocation"),
+        "{}",
+        e
+    );
+    assert_eq!(ftp_stream.nlst(None).unwrap(), vec!["small.txt"]);
+    ftp_stream.mkdir("dir").unwrap();
+    ftp_stream.quit().unwrap();
+
+    // The usage is kept across sessions.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let reply = client.cmd("MKD other");
+    assert!(reply.starts_with("552 "), "unexpected reply {:?}", reply);
+}
+
+#[test]
+fn site_idnt() {
+    use firetrap::allowlist::Allowlist;
+
+    thread::spawn(|| {
+        let allowlist = Allowlist::new()
+            .network("127.0.0.0/8".parse().unwrap())
+            .network("192.0.2.0/24".parse().unwrap());
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .trusted_proxy("127.0.0.1/32".parse().unwrap())
+            .allowlist(allowlist);
+        server.listen("127.0.0.1:1308");
+    });
+    thread::spawn(|| {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .trusted_proxy("10.0.0.0/8".parse().unwrap());
+        server.listen("127.0.0.1:1309");
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect("127.0.0.1:1308");
+    assert!(client.cmd("SITE IDNT nonsense").starts_with("501 "));
+    let reply = client.cmd("SITE IDNT alice@192.0.2.7:50123");
+    assert!(reply.starts_with("200 "), "unexpected reply {:?}", reply);
+    assert!(client.cmd("SITE IDNT 192.0.2.8").starts_with("503 "));
+    client.login();
+    let status = client.cmd("STAT");
+    assert!(
+        status.contains(" Connected from 192.0.2.7:50123\r\n"),
+        "{}",
+        status
+    );
+    assert!(status.contains(" Identified as alice\r\n"), "{}", status);
+
+    // The allowlist applies to the client, not to the proxy.
+    let mut client = RawClient::connect("127.0.0.1:1308");
+    let reply = client.cmd("SITE IDNT 198.51.100.1");
+    assert!(reply.starts_with("421 "), "unexpected reply {:?}", reply);
+
+    let mut client = RawClient::connect("127.0.0.1:1308");
+    client.login();
+    assert!(client.cmd("SITE IDNT 192.0.2.7").starts_with("503 "));
+
+    let mut client = RawClient::connect("127.0.0.1:1309");
+    assert!(client.cmd("SITE IDNT 192.0.2.7").starts_with("550 "));
+}
+
+// The CRC32C of `MODE X` blocks.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn integrity_block(seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut block = seq.to_be_bytes().to_vec();
+    block.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    block.extend_from_slice(payload);
+    let crc = crc32c(&block);
+    block.extend_from_slice(&crc.to_be_bytes());
+    block
+}
+
+#[test]
+fn mode_x() {
+    use std::io::{Read, Write};
+
+    let addr = "127.0.0.1:1310";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).data_integrity(true);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("FEAT").contains(" MODE X\r\n"));
+    assert!(client.cmd("MODE X").starts_with("200 "));
+
+    // A corrupted block is asked for again.
+    let mut data = client.pasv();
+    assert!(client.cmd("STOR checked.txt").starts_with("150 "));
+    let mut answer = [0; 1];
+    let mut corrupted = integrity_block(0, b"hello ");
+    corrupted[9] ^= 1;
+    for block in [
+        corrupted,
+        integrity_block(0, b"hello "),
+        integrity_block(1, b"world"),
+        integrity_block(2, b""),
+    ] {
+        data.write_all(&block).unwrap();
+        data.read_exact(&mut answer).unwrap();
+    }
+    drop(data);
+    assert!(client.read_reply().starts_with("226 "));
+    assert_eq!(
+        std::fs::read(root.join("checked.txt")).unwrap(),
+        b"hello world"
+    );
+
+    // And so is one that we say is.
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR checked.txt").starts_with("150 "));
+    let mut received = vec![];
+    let mut naks = 0;
+    loop {
+        let mut header = [0; 8];
+        data.read_exact(&mut header).unwrap();
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let mut body = vec![0; len + 4];
+        data.read_exact(&mut body).unwrap();
+        let seq = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        assert_eq!(
+            integrity_block(seq, &body[..len]),
+            [&header[..], &body].concat()
+        );
+        if naks == 0 {
+            naks += 1;
+            data.write_all(&[0x15]).unwrap();
+            continue;
+        }
+        data.write_all(&[0x06]).unwrap();
+        if len == 0 {
+            break;
+        }
+        received.extend_from_slice(&body[..len]);
+    }
+    assert_eq!(received, b"hello world");
+    assert!(client.read_reply().starts_with("226 "));
+
+    assert!(client.cmd("MODE S").starts_with("200 "));
+}
+
+#[test]
+fn stalled_reader() {
+    use std::io::Write;
+
+    let addr = "127.0.0.1:1311";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir())
+            .reply_buffer(1024)
+            .reply_timeout(time::Duration::from_millis(200));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+
+    // Keep asking for help without ever reading it, until the server gives up on us.
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut writer = client.writer.try_clone().unwrap();
+    thread::spawn(move || loop {
+        if writer.write_all(b"HELP\r\n").is_err() {
+            tx.send(()).unwrap();
+            return;
+        }
+    });
+    rx.recv_timeout(time::Duration::from_secs(30))
+        .expect("the server kept a stalled client connected");
+    drop(client);
+}
+
+#[test]
+fn stat_listing_beyond_reply_buffer() {
+    let addr = "127.0.0.1:1324";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root).reply_buffer(1024);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    for i in 0..500 {
+        std::fs::write(root.join(format!("report-{:04}.csv", i)), b"...").unwrap();
+    }
+
+    // The listing is sent a line at a time, so it gets through however small the buffer is.
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let listing = client.cmd("STAT /");
+    assert!(listing.starts_with("213-Status follows:\r\n"));
+    assert!(listing.ends_with("213 End of status\r\n"));
+    assert_eq!(listing.matches(" report-").count(), 500);
+    assert!(client.cmd("NOOP").starts_with("200"));
+}
+
+// A `MODE Z` codec that's easy to check: it flips bits instead of compressing.
+struct Flip;
+
+struct Flipped(firetrap::compression::Reader);
+
+impl std::io::Read for Flipped {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.0.read(buf)?;
+        for b in &mut buf[..n] {
+            *b ^= 0x5a;
+        }
+        Ok(n)
+    }
+}
+
+impl tokio_io::AsyncRead for Flipped {}
+
+impl firetrap::compression::Codec for Flip {
+    fn name(&self) -> &str {
+        "flip"
+    }
+
+    fn compress(&self, reader: firetrap::compression::Reader) -> firetrap::compression::Reader {
+        Box::new(Flipped(reader))
+    }
+
+    fn decompress(&self, reader: firetrap::compression::Reader) -> firetrap::compression::Reader {
+        Box::new(Flipped(reader))
+    }
+}
+
+#[test]
+fn mode_z_engines() {
+    use std::io::Read;
+
+    let addr = "127.0.0.1:1312";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    let metrics = std::sync::Arc::new(firetrap::metrics::Metrics::new());
+    let server_metrics = std::sync::Arc::clone(&metrics);
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .compression_codec(std::sync::Arc::new(Flip))
+            .metrics(server_metrics);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::write(root.join("file.txt"), b"contents").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(client.cmd("FEAT").contains(" MODE Z ENGINE zlib;flip\r\n"));
+    assert_eq!(client.cmd("OPTS MODE Z"), "200 MODE Z ENGINE zlib\r\n");
+    assert!(client.cmd("OPTS MODE Z ENGINE zstd").starts_with("501 "));
+    assert_eq!(
+        client.cmd("OPTS MODE Z ENGINE FLIP"),
+        "200 MODE Z ENGINE flip\r\n"
+    );
+    assert!(client.cmd("MODE Z").starts_with("200 "));
+
+    let mut data = client.pasv();
+    assert!(client.cmd("RETR file.txt").starts_with("150 "));
+    let mut flipped = vec![];
+    data.read_to_end(&mut flipped).unwrap();
+    assert!(client.read_reply().starts_with("226 "));
+    let contents: Vec<u8> = flipped.iter().map(|b| b ^ 0x5a).collect();
+    assert_eq!(contents, b"contents");
+    let compression = metrics.compression();
+    assert_eq!(compression.len(), 1);
+    assert_eq!(compression[0].0, "flip");
+    assert_eq!(compression[0].1.mean(), Some(100));
+
+    // No codecs, no `MODE Z`
+    let addr = "127.0.0.1:1313";
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).allowed_codecs(&[]);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert!(!client.cmd("FEAT").contains("MODE Z"));
+    assert!(client.cmd("MODE Z").starts_with("504 "));
+    assert!(client.cmd("OPTS MODE Z").starts_with("504 "));
+}
+
+#[test]
+fn atomic_uploads() {
+    use firetrap::staging::StagingArea;
+    use std::io::Write;
+
+    let addr = "127.0.0.1:1314";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let staging = tempfile::TempDir::new().unwrap().keep();
+    let (server_root, server_staging) = (root.clone(), staging.clone());
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root)
+            .staging(StagingArea::new(server_staging).limit(1000))
+            .atomic_uploads(true);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    let staged = || std::fs::read_dir(&staging).unwrap().count();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    let mut data = client.pasv();
+    assert!(client.cmd("STOR up.txt").starts_with("150"));
+    data.write_all(b"not there yet").unwrap();
+    thread::sleep(time::Duration::from_millis(200));
+    assert!(!root.join("up.txt").exists());
+    assert_eq!(staged(), 1);
+    drop(data);
+    assert!(client.read_reply().starts_with("226"));
+    assert_eq!(
+        std::fs::read(root.join("up.txt")).unwrap(),
+        b"not there yet"
+    );
+
+    // Uploads that don't fit in the staging area fail without a trace.
+    let mut data = client.pasv();
+    assert!(client.cmd("STOR big.bin").starts_with("150"));
+    let _ = data.write_all(&[0u8; 2000]);
+    drop(data);
+    assert!(client.read_reply().starts_with("452"));
+    assert!(!root.join("big.bin").exists());
+
+    assert_eq!(client.cmd("QUIT"), "221 bye!\r\n");
+    thread::sleep(time::Duration::from_millis(200));
+    assert_eq!(staged(), 0);
+}
+
+#[test]
+fn port_bounce_protection() {
+    use firetrap::metrics::{Metrics, RejectedPort};
+    use firetrap::server::ActiveModePolicy;
+    use std::sync::Arc;
+
+    let metrics = Arc::new(Metrics::new());
+    let server_metrics = Arc::clone(&metrics);
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).metrics(server_metrics);
+        server.listen("127.0.0.1:1315");
+    });
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(std::env::temp_dir()).active_mode_policy(
+            ActiveModePolicy {
+                allow_privileged_ports: true,
+                ..ActiveModePolicy::default()
+            },
+        );
+        server.listen("127.0.0.1:1316");
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect("127.0.0.1:1315");
+    client.login();
+    assert!(client.cmd("PORT 127,0,0,1,0,21").starts_with("504"));
+    assert!(client.cmd("EPRT |1|127.0.0.1|25|").starts_with("504"));
+    assert_eq!(
+        metrics.rejected_ports(),
+        vec![(RejectedPort::Privileged, 2)]
+    );
+    assert_eq!(client.cmd("QUIT"), "221 bye!\r\n");
+
+    let mut client = RawClient::connect("127.0.0.1:1316");
+    client.login();
+    assert!(client.cmd("PORT 127,0,0,1,0,21").starts_with("200"));
+    assert_eq!(client.cmd("QUIT"), "221 bye!\r\n");
+}
+
+#[test]
+fn site_debug() {
+    use firetrap::auth::authorization::{Authorizer, Operation};
+
+    struct AdminsOnly;
+    #[async_trait::async_trait]
+    impl Authorizer for AdminsOnly {
+        async fn authorize(
+            &self,
+            username: &str,
+            _path: &std::path::Path,
+            operation: Operation,
+        ) -> Result<bool, ()> {
+            Ok(operation != Operation::Debug || username == "admin")
+        }
+    }
+    static ADMINS_ONLY: AdminsOnly = AdminsOnly;
+
+    let server = firetrap::Server::with_root(std::env::temp_dir())
+        .authorizer(&ADMINS_ONLY)
+        .site_debug(true);
+    let handle = server.handle();
+    assert!(handle.support_bundle().contains("\"listening\":null"));
+    thread::spawn(move || {
+        server.listen("127.0.0.1:1317");
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut client = RawClient::connect("127.0.0.1:1317");
+    client.login();
+    assert!(client.cmd("SITE DEBUG").starts_with("550"));
+    assert!(client.cmd("SIZE missing").starts_with("550"));
+
+    let mut admin = RawClient::connect("127.0.0.1:1317");
+    admin.cmd("USER admin");
+    assert!(admin.cmd("PASS secret").starts_with("230"));
+    let reply = admin.cmd("SITE DEBUG");
+    assert!(reply.starts_with("200 {\"version\":"));
+    assert!(reply.ends_with("}\r\n"));
+    assert!(reply.contains("\"features\":{"));
+    assert!(reply.contains("\"storage\":\"Filesystem\""));
+    assert!(reply.contains("\"listening\":\"127.0.0.1:1317\""));
+    assert!(reply.contains("\"code\":550,\"count\":2"));
+    // The same, from the handle.
+    assert!(handle
+        .support_bundle()
+        .contains("\"listening\":\"127.0.0.1:1317\""));
+
+    // It's off unless turned on, even for users the authorizer allows.
+    thread::spawn(move || {
+        firetrap::Server::with_root(std::env::temp_dir()).listen("127.0.0.1:1330");
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    let mut client = RawClient::connect("127.0.0.1:1330");
+    client.login();
+    assert_eq!(
+        client.cmd("SITE DEBUG"),
+        "502 SITE DEBUG is not available\r\n"
+    );
+}
+
+#[test]
+fn write_once() {
+    use firetrap::storage::{Memory, WriteOnce};
+
+    let addr = "127.0.0.1:1321";
+    thread::spawn(move || {
+        let memory = Memory::new();
+        let server = firetrap::Server::new(Box::new(move || WriteOnce::new(memory.clone())));
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream
+        .put("a.txt", &mut std::io::Cursor::new(b"first".to_vec()))
+        .unwrap();
+    ftp_stream
+        .put("b.txt", &mut std::io::Cursor::new(b"other".to_vec()))
+        .unwrap();
+    let e = ftp_stream
+        .put("a.txt", &mut std::io::Cursor::new(b"second".to_vec()))
+        .unwrap_err();
+    assert!(e.to_string().contains("553 "), "{}", e);
+    let e = ftp_stream.rename("b.txt", "a.txt").unwrap_err();
+    assert!(e.to_string().contains("553 "), "{}", e);
+    ftp_stream.rename("b.txt", "c.txt").unwrap();
+    let mut names = ftp_stream.nlst(None).unwrap();
+    names.sort();
+    assert_eq!(names, vec!["a.txt", "c.txt"]);
+    ftp_stream.quit().unwrap();
+}
+
+#[test]
+fn cwd_needs_a_directory() {
+    let addr = "127.0.0.1:1322";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::create_dir(root.join("reports")).unwrap();
+    std::fs::write(root.join("reports").join("2019.csv"), b"...").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("CWD missing"), "550 File not found\r\n");
+    assert_eq!(
+        client.cmd("CWD reports/2019.csv"),
+        "550 Not a directory\r\n"
+    );
+    assert_eq!(client.cmd("PWD"), "257 \"/\"\r\n");
+    assert_eq!(client.cmd("CWD reports"), "250 Okay.\r\n");
+    assert_eq!(client.cmd("PWD"), "257 \"/reports\"\r\n");
+}
+
+#[test]
+fn storage_error_replies() {
+    let addr = "127.0.0.1:1323";
+    let root = tempfile::TempDir::new().unwrap().keep();
+    let server_root = root.clone();
+    thread::spawn(move || {
+        let server = firetrap::Server::with_root(server_root);
+        server.listen(addr);
+    });
+    thread::sleep(time::Duration::from_millis(100));
+    std::fs::create_dir(root.join("full")).unwrap();
+    std::fs::write(root.join("full/keep.txt"), b"keep me").unwrap();
+
+    let mut client = RawClient::connect(addr);
+    client.login();
+    assert_eq!(client.cmd("DELE missing.txt"), "550 File not found\r\n");
+    assert_eq!(client.cmd("RMD full"), "550 Directory not empty\r\n");
+    assert_eq!(client.cmd("MKD full"), "550 Directory already exists\r\n");
+    assert!(client.cmd("RNFR missing.txt").starts_with("350"));
+    assert_eq!(client.cmd("RNTO found.txt"), "550 File not found\r\n");
+    let _data = client.pasv();
+    assert_eq!(client.cmd("RETR missing.txt"), "550 File not found\r\n");
+    let _data = client.pasv();
+    assert!(client.cmd("LIST missing").starts_with("150"));
+    assert_eq!(client.read_reply(), "550 File not found\r\n");
+    assert!(root.join("full/keep.txt").exists());
+}